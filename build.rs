@@ -1,9 +1,22 @@
 use canonicalize_json_micheline::{canonicalize_json_micheline, CanonicalizeError};
+use std::path::Path;
 
 const CANONICAL_CONTRACT_PATH: &str = "src/escrow/zkchannels_contract_canonical.json";
 
 fn main() -> Result<(), CanonicalizeError> {
     println!("cargo:rerun-if-changed=src/database/migrations/merchant");
+    rerun_if_commit_changes();
+
+    // Embed the git commit being built, if it can be found
+    let git_commit = std::process::Command::new("git")
+        .args(&["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=ZEEKOE_GIT_COMMIT={}", git_commit);
     println!("cargo:rerun-if-changed=src/escrow/zkchannels_contract.json");
 
     let contract_json = include_str!("src/escrow/zkchannels_contract.json");
@@ -17,3 +30,23 @@ fn main() -> Result<(), CanonicalizeError> {
 
     Ok(())
 }
+
+/// Rebuild when the commit being built changes: when another branch is checked out, which
+/// changes HEAD, or when the checked-out branch moves, which changes its ref, or the packed refs
+/// if its ref has been packed.
+fn rerun_if_commit_changes() {
+    let git_dir = Path::new(".git");
+    let head = git_dir.join("HEAD");
+    println!("cargo:rerun-if-changed={}", head.display());
+
+    let mut watched = vec![git_dir.join("packed-refs")];
+    if let Ok(head) = std::fs::read_to_string(&head) {
+        if let Some(reference) = head.trim().strip_prefix("ref: ") {
+            watched.push(git_dir.join(reference));
+        }
+    }
+    // Cargo reruns on every build for a path which doesn't exist, so only existing ones are given
+    for path in watched.into_iter().filter(|path| path.exists()) {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+}
@@ -20,6 +20,11 @@
 # List the channels
 # $: python3 test-zeekoe.py list
 #
+# To proxy payments through the chain watcher, which keeps connections to the merchant open, pass
+# '--proxy-through-daemon' to 'cust-setup'. Scenarios should leave the customer database in the
+# same state (compare 'list') whether or not payments are proxied.
+# $: python3 test-zeekoe.py cust-setup --url "http://localhost:20000" --proxy-through-daemon -v
+#
 
 import argparse
 import glob
@@ -102,7 +107,7 @@ certificate = "localhost.crt"
         print("============")
     return
     
-def create_customer_config(cust_db, cust_config, cust_account_keys, self_delay, confirmation_depth, url_path, verbose=False):
+def create_customer_config(cust_db, cust_config, cust_account_keys, self_delay, confirmation_depth, url_path, proxy_through_daemon=False, verbose=False):
     config_contents = """
 database = {{ sqlite = "{customer_db}" }}
 trust_certificate = "localhost.crt"
@@ -110,7 +115,8 @@ trust_certificate = "localhost.crt"
 tezos_uri = "{url}"
 self_delay = {self_delay}
 confirmation_depth = {confirmation_depth}
-    """.format(customer_db=cust_db, tezos_account=cust_account_keys, self_delay=self_delay, confirmation_depth=confirmation_depth, url=url_path)
+proxy_through_daemon = {proxy_through_daemon}
+    """.format(customer_db=cust_db, tezos_account=cust_account_keys, self_delay=self_delay, confirmation_depth=confirmation_depth, url=url_path, proxy_through_daemon=str(proxy_through_daemon).lower())
     f = open(cust_config, "w")
     f.write(config_contents)
     f.close()
@@ -320,6 +326,7 @@ def main():
     parser.add_argument("--amount", "-a", help="starting balance for each channel", default="10")
     parser.add_argument("--verbose", "-v", help="increase output verbosity", action="store_true")
     parser.add_argument("--channel", type=int, help="desired starting channel counter", default="1")
+    parser.add_argument("--proxy-through-daemon", help="make payments through the customer watcher", action="store_true")
     parser.add_argument('--command-list','-c', nargs='+', help='''
         Commands to be tested. The list of valid commands and their descriptions are:
         establish - creates a new zkChannel
@@ -373,7 +380,7 @@ def main():
         zkchannel_merchant("run", config=merch_config, verbose=verbose)
 
     elif args.command == CUST_SETUP:
-        create_customer_config(cust_db, cust_config, cust_keys, self_delay, confirmation_depth, url)
+        create_customer_config(cust_db, cust_config, cust_keys, self_delay, confirmation_depth, url, args.proxy_through_daemon)
         info("Starting the customer watcher...")
         zkchannel_customer("watch", config=cust_config, verbose=verbose)
 
@@ -1,10 +1,11 @@
 use {
     rust_decimal::Decimal,
-    rusty_money::{define_currency_set, FormattableCurrency, Money, MoneyError},
+    rusty_money::{define_currency_set, FormattableCurrency, Money},
     std::{
         convert::TryInto,
         fmt::{self, Display},
         num::TryFromIntError,
+        ops::Range,
         str::FromStr,
     },
     thiserror::Error,
@@ -17,38 +18,216 @@ use zkabacus_crypto::{
 
 use crate::protocol::Party;
 
+/// The largest balance, in mutez, that a channel may hold in total.
+///
+/// The contract stores balances as mutez naturals, which pytezos and the contract's arithmetic
+/// handle as 64-bit signed integers. This bound is the total supply of XTZ (about a billion tez),
+/// which leaves plenty of headroom below `i64::MAX` for sums of balances and fees.
+pub const MAX_CHANNEL_BALANCE: u64 = 1_000_000_000 * 1_000_000;
+
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct Amount {
     pub(crate) money: Money<'static, supported::Currency>,
 }
 
 impl FromStr for Amount {
-    type Err = MoneyError;
-
-    /// Parse an amount specified like "100.00 XTZ"
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if let Some((amount, currency)) = s.split_once(' ') {
-            let currency = supported::find(currency).ok_or(MoneyError::InvalidCurrency)?;
-            let money = Money::from_str(amount, currency)?;
-            if money.is_positive() {
-                Ok(Amount { money })
+    type Err = AmountParseError;
+
+    /// Parse an amount specified like "100.00 XTZ".
+    ///
+    /// The accepted grammar is a positive decimal number, then whitespace, then the code of a
+    /// supported currency. Surrounding whitespace is ignored, the currency code is
+    /// case-insensitive, and digits may be grouped with single underscores (e.g. "1_000 XTZ").
+    ///
+    /// The following are deliberately rejected, with a suggestion of what was probably meant:
+    /// - a number without a currency code, or run together with it (e.g. "10XTZ");
+    /// - a decimal point without digits on both sides of it (e.g. ".5 XTZ" or "5. XTZ");
+    /// - commas, either as digit grouping or as a decimal separator (e.g. "1,000 XTZ");
+    /// - signs, underscores not between two digits, and anything after the currency code;
+    /// - amounts which are zero or negative.
+    ///
+    /// An amount with more decimal places than its currency has minor units parses successfully,
+    /// but will fail to convert into a balance or payment amount.
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        use AmountParseErrorKind::*;
+
+        let fail = |kind, span: Range<usize>, suggestion: Option<String>| AmountParseError {
+            input: input.to_string(),
+            kind,
+            span,
+            suggestion,
+        };
+
+        // Ignore surrounding whitespace, but keep offsets relative to the original input
+        let start = input.len() - input.trim_start().len();
+        let end = input.trim_end().len();
+        if start >= end {
+            return Err(fail(Empty, 0..input.len(), None));
+        }
+
+        // Split the input into the number, the currency code, and anything trailing after that
+        let number_end = input[start..end]
+            .find(|c: char| !is_number_char(c))
+            .map_or(end, |i| start + i);
+        let number = &input[start..number_end];
+        let code_start = input[number_end..end]
+            .find(|c: char| !c.is_whitespace())
+            .map_or(end, |i| number_end + i);
+        let code_end = input[code_start..end]
+            .find(char::is_whitespace)
+            .map_or(end, |i| code_start + i);
+        let code = &input[code_start..code_end];
+        let trailing_start = input[code_end..end]
+            .find(|c: char| !c.is_whitespace())
+            .map_or(end, |i| code_end + i);
+
+        // The best guess at a corrected version of the input, given a corrected number
+        let try_instead = |number: &str| {
+            let code = if code.is_empty() {
+                XTZ.code().to_string()
             } else {
-                Err(MoneyError::InvalidAmount)
+                code.to_uppercase()
+            };
+            Some(format!("try \"{} {}\"", number, code))
+        };
+
+        if number.is_empty() {
+            // Perhaps the currency code was written first, as in "XTZ 10"
+            let rest = input[code_end..end].trim();
+            let suggestion = if code.chars().all(char::is_alphabetic)
+                && rest.starts_with(|c: char| c.is_ascii_digit())
+                && !rest.contains(char::is_whitespace)
+            {
+                Some(format!("try \"{} {}\"", rest, code.to_uppercase()))
+            } else {
+                None
+            };
+            return Err(fail(InvalidNumber, code_start..code_end, suggestion));
+        }
+
+        // Validate the number, in order of how informative each complaint is
+        let position = |pattern: &[char]| number.find(pattern).map(|i| start + i);
+        if number.starts_with('-') {
+            return Err(fail(NotPositive, start..number_end, None));
+        }
+        if let Some(i) = position(&['+', '-']) {
+            let suggestion = if i == start {
+                try_instead(&number[1..])
+            } else {
+                None
+            };
+            return Err(fail(Sign, i..i + 1, suggestion));
+        }
+        if !number.contains(|c: char| c.is_ascii_digit()) {
+            return Err(fail(InvalidNumber, start..number_end, None));
+        }
+        if let Some(i) = position(&[',']) {
+            let (integer, fraction) = number.split_once('.').unwrap_or((number, ""));
+            let suggestion =
+                if !fraction.contains(',') && integer.split(',').skip(1).all(|g| g.len() == 3) {
+                    // Commas used to group digits, as in "1,000,000"
+                    try_instead(&number.replace(',', "_"))
+                } else if !number.contains('.') && number.matches(',').count() == 1 {
+                    // A comma used as the decimal separator, as in "0,5"
+                    try_instead(&number.replace(',', "."))
+                } else {
+                    None
+                };
+            return Err(fail(Comma, i..i + 1, suggestion));
+        }
+        if number.matches('.').count() > 1 {
+            let i = start + number.rfind('.').unwrap();
+            return Err(fail(MultipleDecimalPoints, i..i + 1, None));
+        }
+        if number.starts_with('.') {
+            return Err(fail(
+                LeadingDecimalPoint,
+                start..start + 1,
+                try_instead(&format!("0{}", number)),
+            ));
+        }
+        if number.ends_with('.') {
+            return Err(fail(
+                TrailingDecimalPoint,
+                number_end - 1..number_end,
+                try_instead(number.trim_end_matches('.')),
+            ));
+        }
+        let bytes = number.as_bytes();
+        for (i, &byte) in bytes.iter().enumerate() {
+            let between_digits = i > 0
+                && i + 1 < bytes.len()
+                && bytes[i - 1].is_ascii_digit()
+                && bytes[i + 1].is_ascii_digit();
+            if byte == b'_' && !between_digits {
+                return Err(fail(
+                    MisplacedUnderscore,
+                    start + i..start + i + 1,
+                    try_instead(&number.replace('_', "")),
+                ));
             }
-        } else {
-            Err(MoneyError::InvalidAmount)
         }
+
+        // Validate the currency code and what follows it
+        if code.is_empty() {
+            return Err(fail(MissingCurrency, end..end, try_instead(number)));
+        }
+        let currency = match supported::find(&code.to_uppercase()) {
+            Some(currency) => currency,
+            None => {
+                let supported_codes: Vec<&str> =
+                    supported_currencies().iter().map(|c| c.code()).collect();
+                return Err(fail(
+                    UnsupportedCurrency,
+                    code_start..code_end,
+                    Some(format!(
+                        "supported currencies are: {}",
+                        supported_codes.join(", ")
+                    )),
+                ));
+            }
+        };
+        if code_start == number_end {
+            return Err(fail(
+                MissingSpace,
+                code_start..code_end,
+                try_instead(number),
+            ));
+        }
+        if trailing_start < end {
+            return Err(fail(
+                TrailingInput,
+                trailing_start..end,
+                try_instead(number),
+            ));
+        }
+
+        // Finally, construct the amount itself
+        let amount = Decimal::from_str(&number.replace('_', ""))
+            .map_err(|_| fail(OutOfRange, start..number_end, None))?;
+        if amount.is_zero() {
+            return Err(fail(NotPositive, start..number_end, None));
+        }
+        Ok(Amount {
+            money: Money::from_decimal(amount, currency),
+        })
     }
 }
 
+/// Whether a character is treated as part of the number when splitting up an amount; this is
+/// more permissive than the accepted grammar, so that mistakes can be pointed out precisely.
+fn is_number_char(c: char) -> bool {
+    c.is_ascii_digit() || matches!(c, '.' | '_' | ',' | '+' | '-')
+}
+
 impl TryInto<PaymentAmount> for Amount {
-    type Error = AmountParseError;
+    type Error = PaymentAmountConversionError;
 
     fn try_into(self) -> Result<PaymentAmount, Self::Error> {
         // Convert the payment amount appropriately
         let minor_units: i64 = self
             .try_into_minor_units()
-            .ok_or(AmountParseError::InvalidValue)?;
+            .ok_or(PaymentAmountConversionError::InvalidValue)?;
 
         // Squash into PaymentAmount
         Ok(if minor_units < 0 {
@@ -65,12 +244,15 @@ macro_rules! try_into_balance {
             type Error = BalanceConversionError;
 
             fn try_into(self) -> Result<$balance_type, Self::Error> {
-                $balance_type::try_new(
-                    self.try_into_minor_units()
-                        .ok_or_else(|| Self::Error::InvalidDeposit(Party::Customer))?
-                        .try_into()?,
-                )
-                .map_err(|_| Self::Error::InvalidDeposit(Party::$party))
+                let minor_units: u64 = self
+                    .try_into_minor_units()
+                    .ok_or_else(|| Self::Error::InvalidDeposit(Party::$party))?
+                    .try_into()?;
+                if minor_units > MAX_CHANNEL_BALANCE {
+                    return Err(Self::Error::ExceedsMaximum(Party::$party));
+                }
+                $balance_type::try_new(minor_units)
+                    .map_err(|_| Self::Error::InvalidDeposit(Party::$party))
             }
         }
     };
@@ -85,6 +267,56 @@ pub enum BalanceConversionError {
     InvalidDeposit(Party),
     #[error(transparent)]
     BalanceTooLarge(#[from] TryFromIntError),
+    #[error(
+        "{0} deposit exceeds the maximum channel balance of {}",
+        max_channel_balance()
+    )]
+    ExceedsMaximum(Party),
+}
+
+/// The initial balances of both parties to a channel.
+#[derive(Debug, Clone, Copy)]
+pub struct Balances {
+    pub customer: CustomerBalance,
+    pub merchant: MerchantBalance,
+}
+
+impl Balances {
+    pub fn new(customer: CustomerBalance, merchant: MerchantBalance) -> Self {
+        Self { customer, merchant }
+    }
+
+    /// The total balance of the channel, in mutez, or an error if it exceeds
+    /// [`MAX_CHANNEL_BALANCE`] or is zero.
+    ///
+    /// Either party's balance may be zero, but not both.
+    pub fn total(&self) -> Result<u64, ChannelBalanceError> {
+        match self
+            .customer
+            .into_inner()
+            .checked_add(self.merchant.into_inner())
+        {
+            Some(0) => Err(ChannelBalanceError::Empty),
+            Some(total) if total <= MAX_CHANNEL_BALANCE => Ok(total),
+            _ => Err(ChannelBalanceError::ExceedsMaximum),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Error, PartialEq, Eq)]
+pub enum ChannelBalanceError {
+    #[error(
+        "Total channel balance exceeds the maximum of {}",
+        max_channel_balance()
+    )]
+    ExceedsMaximum,
+    #[error("At least one party must deposit a non-zero balance")]
+    Empty,
+}
+
+/// [`MAX_CHANNEL_BALANCE`] as an [`Amount`], for display.
+pub fn max_channel_balance() -> Amount {
+    Amount::from_minor_units_of_currency(MAX_CHANNEL_BALANCE as i64, XTZ)
 }
 
 impl Display for Amount {
@@ -144,17 +376,98 @@ impl Amount {
 }
 
 #[derive(Debug, Error)]
-pub enum AmountParseError {
-    #[error("Unknown currency: {0}")]
-    UnknownCurrency(String),
-    #[error("Invalid format for currency amount")]
-    InvalidFormat,
+pub enum PaymentAmountConversionError {
     #[error("Payment amount invalid for currency or out of range for channel")]
     InvalidValue,
     #[error(transparent)]
     InvalidPaymentAmount(#[from] PaymentAmountError),
 }
 
+/// An error from parsing an [`Amount`], which points out where in the input the problem was and,
+/// when there's an obvious fix, suggests it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AmountParseError {
+    input: String,
+    kind: AmountParseErrorKind,
+    span: Range<usize>,
+    suggestion: Option<String>,
+}
+
+impl AmountParseError {
+    /// The kind of problem with the input.
+    pub fn kind(&self) -> AmountParseErrorKind {
+        self.kind
+    }
+
+    /// The byte range of the input at which the problem occurs.
+    pub fn span(&self) -> Range<usize> {
+        self.span.clone()
+    }
+
+    /// A hint as to how the input could be fixed, if one is available.
+    pub fn suggestion(&self) -> Option<&str> {
+        self.suggestion.as_deref()
+    }
+}
+
+impl Display for AmountParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if self.kind != AmountParseErrorKind::Empty {
+            // Underline the offending part of the input, measuring in characters, not bytes
+            let offset = self.input[..self.span.start].chars().count();
+            let width = self.input[self.span.clone()].chars().count().max(1);
+            write!(
+                f,
+                "\n    {}\n    {}{}",
+                self.input,
+                " ".repeat(offset),
+                "^".repeat(width)
+            )?;
+        }
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, "\nhelp: {}", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AmountParseError {}
+
+/// The kinds of problem which can prevent an [`Amount`] from being parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[non_exhaustive]
+pub enum AmountParseErrorKind {
+    #[error("Missing amount: expected a number followed by a currency, like \"1.5 XTZ\"")]
+    Empty,
+    #[error("Invalid number in amount")]
+    InvalidNumber,
+    #[error("Amounts must not include a sign")]
+    Sign,
+    #[error("Amounts must be greater than zero")]
+    NotPositive,
+    #[error("Commas are not accepted in amounts")]
+    Comma,
+    #[error("Amounts must not have more than one decimal point")]
+    MultipleDecimalPoints,
+    #[error("Amounts must not start with a decimal point")]
+    LeadingDecimalPoint,
+    #[error("Amounts must not end with a decimal point")]
+    TrailingDecimalPoint,
+    #[error("Underscores in amounts are only accepted between two digits")]
+    MisplacedUnderscore,
+    #[error("Missing currency code after amount")]
+    MissingCurrency,
+    #[error("Missing space between amount and currency code")]
+    MissingSpace,
+    #[error("Unsupported currency")]
+    UnsupportedCurrency,
+    #[error("Unexpected input after currency code")]
+    TrailingInput,
+    #[error("Amount is too large")]
+    OutOfRange,
+}
+
 // Define only the currencies supported by this application
 define_currency_set!(
     supported {
@@ -171,6 +484,11 @@ define_currency_set!(
     }
 );
 
+/// All the currencies in the [`supported`] set, for listing to the user.
+fn supported_currencies() -> [&'static supported::Currency; 1] {
+    [XTZ]
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -215,4 +533,228 @@ mod test {
                 || TryInto::<MerchantBalance>::try_into(bad_amount.unwrap()).is_err()
         );
     }
+
+    #[test]
+    fn invalid_deposit_names_its_party() {
+        let too_many_decimals_amount = Amount::from_str("1.55555555 XTZ").unwrap();
+
+        assert!(matches!(
+            TryInto::<CustomerBalance>::try_into(too_many_decimals_amount.clone()),
+            Err(BalanceConversionError::InvalidDeposit(Party::Customer))
+        ));
+        assert!(matches!(
+            TryInto::<MerchantBalance>::try_into(too_many_decimals_amount),
+            Err(BalanceConversionError::InvalidDeposit(Party::Merchant))
+        ));
+    }
+
+    #[test]
+    fn deposit_at_maximum_channel_balance() {
+        let max = Amount::from_minor_units_of_currency(MAX_CHANNEL_BALANCE as i64, XTZ);
+        let over = Amount::from_minor_units_of_currency(MAX_CHANNEL_BALANCE as i64 + 1, XTZ);
+
+        let customer_balance: CustomerBalance = max.clone().try_into().unwrap();
+        assert_eq!(customer_balance.into_inner(), MAX_CHANNEL_BALANCE);
+        let merchant_balance: MerchantBalance = max.try_into().unwrap();
+        assert_eq!(merchant_balance.into_inner(), MAX_CHANNEL_BALANCE);
+
+        assert!(matches!(
+            TryInto::<CustomerBalance>::try_into(over.clone()),
+            Err(BalanceConversionError::ExceedsMaximum(Party::Customer))
+        ));
+        assert!(matches!(
+            TryInto::<MerchantBalance>::try_into(over),
+            Err(BalanceConversionError::ExceedsMaximum(Party::Merchant))
+        ));
+    }
+
+    #[test]
+    fn total_at_maximum_channel_balance() {
+        let balances = |customer, merchant| {
+            Balances::new(
+                CustomerBalance::try_new(customer).unwrap(),
+                MerchantBalance::try_new(merchant).unwrap(),
+            )
+        };
+
+        assert_eq!(
+            balances(MAX_CHANNEL_BALANCE, 0).total(),
+            Ok(MAX_CHANNEL_BALANCE)
+        );
+        assert_eq!(
+            balances(MAX_CHANNEL_BALANCE - 1, 1).total(),
+            Ok(MAX_CHANNEL_BALANCE)
+        );
+        assert_eq!(
+            balances(MAX_CHANNEL_BALANCE, 1).total(),
+            Err(ChannelBalanceError::ExceedsMaximum)
+        );
+        assert_eq!(
+            balances(MAX_CHANNEL_BALANCE, MAX_CHANNEL_BALANCE).total(),
+            Err(ChannelBalanceError::ExceedsMaximum)
+        );
+    }
+
+    #[test]
+    fn either_party_may_deposit_nothing() {
+        let balances = |customer, merchant| {
+            Balances::new(
+                CustomerBalance::try_new(customer).unwrap(),
+                MerchantBalance::try_new(merchant).unwrap(),
+            )
+        };
+
+        assert_eq!(balances(5, 0).total(), Ok(5));
+        assert_eq!(balances(0, 5).total(), Ok(5));
+        assert_eq!(balances(5, 5).total(), Ok(10));
+        assert_eq!(balances(0, 0).total(), Err(ChannelBalanceError::Empty));
+    }
+
+    #[test]
+    fn accepted_amount_grammar() {
+        let accepted = [
+            ("1 XTZ", 1_000_000),
+            ("12.34 XTZ", 12_340_000),
+            ("0.5 XTZ", 500_000),
+            ("0.000001 XTZ", 1),
+            ("007 XTZ", 7_000_000),
+            ("1.000000 XTZ", 1_000_000),
+            ("1_000 XTZ", 1_000_000_000),
+            ("1_000_000 XTZ", 1_000_000_000_000),
+            ("0.000_001 XTZ", 1),
+            ("10 xtz", 10_000_000),
+            ("10 Xtz", 10_000_000),
+            ("10   XTZ", 10_000_000),
+            ("10\tXTZ", 10_000_000),
+            ("  10 XTZ  ", 10_000_000),
+        ];
+
+        for (input, minor_units) in accepted.iter() {
+            let amount = Amount::from_str(input)
+                .unwrap_or_else(|e| panic!("failed to parse {:?}: {}", input, e));
+            assert_eq!(
+                Some(*minor_units),
+                amount.try_into_minor_units(),
+                "wrong value for {:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn rejected_amount_grammar() {
+        use AmountParseErrorKind::*;
+
+        let rejected = [
+            ("", Empty, 0..0, None),
+            ("   ", Empty, 0..3, None),
+            ("XTZ", InvalidNumber, 0..3, None),
+            ("ten XTZ", InvalidNumber, 0..3, None),
+            ("XTZ 10", InvalidNumber, 0..3, Some("try \"10 XTZ\"")),
+            ("xtz 1.5", InvalidNumber, 0..3, Some("try \"1.5 XTZ\"")),
+            ("_ XTZ", InvalidNumber, 0..1, None),
+            ("+5 XTZ", Sign, 0..1, Some("try \"5 XTZ\"")),
+            ("5-3 XTZ", Sign, 1..2, None),
+            ("-5 XTZ", NotPositive, 0..2, None),
+            ("0 XTZ", NotPositive, 0..1, None),
+            ("0.000 XTZ", NotPositive, 0..5, None),
+            ("1,000 XTZ", Comma, 1..2, Some("try \"1_000 XTZ\"")),
+            (
+                "1,000,000.5 XTZ",
+                Comma,
+                1..2,
+                Some("try \"1_000_000.5 XTZ\""),
+            ),
+            ("0,5 XTZ", Comma, 1..2, Some("try \"0.5 XTZ\"")),
+            ("1,5,0 XTZ", Comma, 1..2, None),
+            ("1.2.3 XTZ", MultipleDecimalPoints, 3..4, None),
+            (".5 XTZ", LeadingDecimalPoint, 0..1, Some("try \"0.5 XTZ\"")),
+            (".5", LeadingDecimalPoint, 0..1, Some("try \"0.5 XTZ\"")),
+            ("5. XTZ", TrailingDecimalPoint, 1..2, Some("try \"5 XTZ\"")),
+            ("_1 XTZ", MisplacedUnderscore, 0..1, Some("try \"1 XTZ\"")),
+            ("1_ XTZ", MisplacedUnderscore, 1..2, Some("try \"1 XTZ\"")),
+            (
+                "1__000 XTZ",
+                MisplacedUnderscore,
+                1..2,
+                Some("try \"1000 XTZ\""),
+            ),
+            (
+                "1_.5 XTZ",
+                MisplacedUnderscore,
+                1..2,
+                Some("try \"1.5 XTZ\""),
+            ),
+            ("10", MissingCurrency, 2..2, Some("try \"10 XTZ\"")),
+            ("  10  ", MissingCurrency, 4..4, Some("try \"10 XTZ\"")),
+            ("10XTZ", MissingSpace, 2..5, Some("try \"10 XTZ\"")),
+            ("10xtz", MissingSpace, 2..5, Some("try \"10 XTZ\"")),
+            (
+                "10 BTC",
+                UnsupportedCurrency,
+                3..6,
+                Some("supported currencies are: XTZ"),
+            ),
+            (
+                "10BTC",
+                UnsupportedCurrency,
+                2..5,
+                Some("supported currencies are: XTZ"),
+            ),
+            (
+                "10 ꜩ",
+                UnsupportedCurrency,
+                3..6,
+                Some("supported currencies are: XTZ"),
+            ),
+            ("10 XTZ XTZ", TrailingInput, 7..10, Some("try \"10 XTZ\"")),
+            (
+                "10 XTZ please",
+                TrailingInput,
+                7..13,
+                Some("try \"10 XTZ\""),
+            ),
+            (
+                "1000000000000000000000000000000 XTZ",
+                OutOfRange,
+                0..31,
+                None,
+            ),
+        ];
+
+        for (input, kind, span, suggestion) in rejected.iter().cloned() {
+            let error = Amount::from_str(input)
+                .expect_err(&format!("parsing {:?} should have failed", input));
+            assert_eq!(kind, error.kind(), "wrong kind of error for {:?}", input);
+            assert_eq!(span, error.span(), "wrong span for {:?}", input);
+            assert_eq!(
+                suggestion,
+                error.suggestion(),
+                "wrong suggestion for {:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn amount_parse_error_messages() {
+        let message = |input| Amount::from_str(input).unwrap_err().to_string();
+
+        assert_eq!(
+            "Missing space between amount and currency code\n    10XTZ\n      ^^^\nhelp: try \"10 XTZ\"",
+            message("10XTZ")
+        );
+        assert_eq!(
+            "Amounts must not start with a decimal point\n    .5 XTZ\n    ^\nhelp: try \"0.5 XTZ\"",
+            message(".5 XTZ")
+        );
+        assert_eq!(
+            "Missing currency code after amount\n    10\n      ^\nhelp: try \"10 XTZ\"",
+            message("10")
+        );
+        assert_eq!(
+            "Unsupported currency\n    10 ꜩ\n       ^\nhelp: supported currencies are: XTZ",
+            message("10 ꜩ")
+        );
+    }
 }
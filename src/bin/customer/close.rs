@@ -7,58 +7,348 @@
 //* `mutual_close()`).
 use {
     async_trait::async_trait,
-    rand::rngs::StdRng,
+    comfy_table::{Cell, Table},
+    futures::stream::{self, StreamExt},
+    rand::{rngs::StdRng, SeedableRng},
     serde::Serialize,
-    std::{convert::Infallible, fs::File, path::PathBuf},
+    std::{
+        convert::Infallible,
+        path::{Path, PathBuf},
+    },
 };
 
 use zeekoe::{
     abort,
     customer::{
-        cli::Close,
+        cli::{Close, ConfirmClose},
         client::ZkChannelAddress,
-        database::{zkchannels_state, QueryCustomer, QueryCustomerExt, State},
+        daemon::{self, Notify},
+        database::{
+            zkchannels_state::{self, ZkChannelState},
+            ClosingPath, OperationGuard, QueryCustomer, QueryCustomerExt, State, StateName,
+        },
+        finalize::finalize_expiry,
+        mutual_close::{self, MutualCloseChain, MutualCloseOutcome},
+        output::{CloseSummary, CloseType, JsonOutput},
         Chan, ChannelName, Config,
     },
+    escrow::{
+        tezos::{MutualCloseAuthorizationSignature, OperationStatus, TezosClient},
+        types::{ContractId, ContractStatus, Entrypoint},
+    },
     offer_abort, proceed,
-    protocol::{close, Party::Customer},
+    protocol::{
+        close::{self, unilateral_close_plan, CloseReceipt, UnilateralClosePlan},
+        version::Features,
+        Party::Customer,
+    },
+};
+
+pub use zeekoe::{
+    customer::finalize::{finalize_closed_customer_close, finalize_customer_claim},
+    protocol::close::UnilateralCloseKind,
 };
 use zkabacus_crypto::{
     customer::ClosingMessage, ChannelId, CloseState, CloseStateSignature, CustomerBalance,
     MerchantBalance, RevocationLock,
 };
 
-use super::{connect, connect_daemon, database, load_tezos_client, Command};
+use super::{connect, database, load_tezos_client, resolve_label, Command, OutputFormat};
 use anyhow::Context;
 
 #[async_trait]
 impl Command for Close {
-    async fn run(self, mut rng: StdRng, config: self::Config) -> Result<(), anyhow::Error> {
+    async fn run(
+        self,
+        rng: StdRng,
+        config: self::Config,
+        format: OutputFormat,
+    ) -> Result<(), anyhow::Error> {
         let database = database(&config)
             .await
             .context("Failed to connect to local database")?;
 
-        if self.force {
-            unilateral_close(
-                &self.label,
-                &config,
-                self.off_chain,
-                &mut rng,
-                database.as_ref(),
-                UnilateralCloseKind::CustomerInitiated,
-            )
-            .await
-            .context("Unilateral close failed")?;
+        let output = CloseOutput {
+            path: self.close_output.clone(),
+            overwrite: self.force_overwrite,
+        };
+
+        if self.all {
+            return close_all(&self, &output, rng, &config, database.as_ref(), format).await;
+        }
+        let label = resolve_label(
+            database.as_ref(),
+            self.label.as_ref(),
+            self.channel_id.as_deref(),
+            self.address.as_ref(),
+        )
+        .await?;
+
+        let summary =
+            close_channel(&self, &label, &output, rng, &config, database.as_ref()).await?;
+        format.print(&summary)?;
+        if let (OutputFormat::Human, Some(close_json)) = (format, &summary.close_json) {
+            report_close_json(close_json);
+        }
+        Ok(())
+    }
+}
+
+/// The most channels to close at once with `close --all`, since each close holds its own
+/// connections to the merchant and the Tezos node.
+const MAX_CONCURRENT_CLOSES: usize = 3;
+
+/// The outcome of closing one of the channels closed by `close --all`.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum CloseAllOutcome {
+    Closed(CloseSummary),
+    Skipped { label: ChannelName, skipped: String },
+    Failed { label: ChannelName, error: String },
+}
+
+/// Close every open channel, continuing past any that fail to close, and report the outcome for
+/// each.
+///
+/// Without `--force`, an open channel which can't be closed mutually, such as one in the middle of
+/// a payment, is skipped and reported as such rather than closed unilaterally.
+async fn close_all(
+    close: &Close,
+    output: &CloseOutput,
+    mut rng: StdRng,
+    config: &Config,
+    database: &dyn QueryCustomer,
+    format: OutputFormat,
+) -> Result<(), anyhow::Error> {
+    // Only close channels that are funded and not already closing
+    let channels = database
+        .get_channels()
+        .await
+        .context("Failed to get channels")?
+        .into_iter()
+        .filter(|details| details.state.state_name().is_open());
+
+    // Give each close its own randomness, since they run concurrently
+    let mut closes = Vec::new();
+    let mut skipped = Vec::new();
+    for details in channels {
+        let state = details.state.state_name();
+        if close.force || state.can_close_mutually() {
+            closes.push((details.label, StdRng::from_rng(&mut rng)?));
         } else {
-            mutual_close(&self, rng, config)
-                .await
-                .context("Mutual close failed")?;
+            skipped.push(CloseAllOutcome::Skipped {
+                label: details.label,
+                skipped: format!(
+                    "the channel is {}, and only a ready channel can be closed mutually; pass \
+                    `--force` to close it unilaterally",
+                    state
+                ),
+            });
         }
+    }
+
+    let mut outcomes: Vec<CloseAllOutcome> = stream::iter(closes)
+        .map(|(label, rng)| async move {
+            match close_channel(close, &label, output, rng, config, database).await {
+                Ok(summary) => CloseAllOutcome::Closed(summary),
+                Err(e) => CloseAllOutcome::Failed {
+                    label,
+                    error: format!("{:#}", e),
+                },
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_CLOSES)
+        .collect()
+        .await;
+    outcomes.extend(skipped);
+
+    let failures = outcomes
+        .iter()
+        .filter(|outcome| matches!(outcome, CloseAllOutcome::Failed { .. }))
+        .count();
+
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string(&outcomes)?),
+        OutputFormat::Human => {
+            let mut table = Table::new();
+            table.load_preset(comfy_table::presets::UTF8_FULL);
+            table.set_header(vec!["Label", "Outcome"]);
+            for outcome in &outcomes {
+                match outcome {
+                    CloseAllOutcome::Closed(summary) => table.add_row(vec![
+                        Cell::new(&summary.label),
+                        Cell::new(format!(
+                            "{} close; now {}",
+                            summary.close_type, summary.state
+                        )),
+                    ]),
+                    CloseAllOutcome::Skipped { label, skipped } => table.add_row(vec![
+                        Cell::new(label),
+                        Cell::new(format!("Skipped: {}", skipped)),
+                    ]),
+                    CloseAllOutcome::Failed { label, error } => table.add_row(vec![
+                        Cell::new(label),
+                        Cell::new(format!("Failed: {}", error)),
+                    ]),
+                };
+            }
+            println!("{}", table);
+
+            for outcome in &outcomes {
+                if let CloseAllOutcome::Closed(CloseSummary {
+                    close_json: Some(close_json),
+                    ..
+                }) = outcome
+                {
+                    report_close_json(close_json);
+                }
+            }
+        }
+    }
+
+    if failures > 0 {
+        return Err(anyhow::anyhow!("Failed to close {} channel(s)", failures));
+    }
+    Ok(())
+}
+
+/// Close a single channel, unilaterally if `--force` was given and mutually otherwise, and
+/// summarize the result.
+async fn close_channel(
+    close: &Close,
+    label: &ChannelName,
+    output: &CloseOutput,
+    mut rng: StdRng,
+    config: &Config,
+    database: &dyn QueryCustomer,
+) -> Result<CloseSummary, anyhow::Error> {
+    let (close_type, result) = if close.force {
+        let result = unilateral_close(
+            label,
+            config,
+            close.off_chain,
+            output,
+            &mut rng,
+            database,
+            UnilateralCloseKind::CustomerInitiated,
+        )
+        .await
+        .context("Unilateral close failed")?;
+        (UnilateralCloseKind::CustomerInitiated.into(), result)
+    } else {
+        mutual_close(label, close.off_chain, output, rng, config, database)
+            .await
+            .context("Mutual close failed")?
+    };
+
+    let channel = database.get_channel(label).await?;
+    Ok(CloseSummary {
+        label: channel.label,
+        channel_id: *channel.state.channel_id(),
+        close_type,
+        contract_id: channel.contract_details.contract_id,
+        operation_hashes: result.operation_hash.into_iter().collect(),
+        close_json: result.close_json,
+        customer_balance: *channel.state.customer_balance(),
+        merchant_balance: *channel.state.merchant_balance(),
+        state: channel.state.state_name(),
+    })
+}
+
+#[async_trait]
+impl Command for ConfirmClose {
+    async fn run(
+        self,
+        _rng: StdRng,
+        config: self::Config,
+        _format: OutputFormat,
+    ) -> Result<(), anyhow::Error> {
+        let database = database(&config)
+            .await
+            .context("Failed to connect to local database")?;
+
+        let receipt = tokio::fs::read_to_string(&self.receipt)
+            .await
+            .with_context(|| format!("Could not read receipt {:?}", &self.receipt))?;
+        let receipt: CloseReceipt = serde_json::from_str(&receipt)
+            .with_context(|| format!("Could not parse receipt {:?}", &self.receipt))?;
+
+        let label = resolve_label(
+            database.as_ref(),
+            self.label.as_ref(),
+            self.channel_id.as_deref(),
+            None,
+        )
+        .await?;
+        confirm_off_chain_close(database.as_ref(), &config, &label, &receipt)
+            .await
+            .context("Failed to confirm off-chain close")?;
 
         Ok(())
     }
 }
 
+/// Finalize a unilateral close whose custClose operation the customer broadcast themselves.
+///
+/// **Usage**: this function is called once the customer has broadcast the custClose operation
+/// produced by an off-chain close, and it is confirmed on chain.
+async fn confirm_off_chain_close(
+    database: &dyn QueryCustomer,
+    config: &Config,
+    channel_name: &ChannelName,
+    receipt: &CloseReceipt,
+) -> Result<(), anyhow::Error> {
+    let channel = database.get_channel(channel_name).await?;
+    if !channel.awaiting_broadcast {
+        return Err(anyhow::anyhow!(
+            "{} is not waiting for a custClose operation to be broadcast",
+            channel_name
+        ));
+    }
+    let close_message = match channel.state {
+        State::PendingClose(close_message) => close_message,
+        state => {
+            return Err(anyhow::anyhow!(
+                "Unexpected channel state: expected PendingClose; got {}",
+                state.state_name()
+            ))
+        }
+    };
+
+    // Make sure the contract reflects the operation before relying on it
+    let tezos_client = load_tezos_client(config, channel_name, database).await?;
+    let contract_state = tezos_client.get_contract_state().await?;
+    close::check_posted_close(
+        contract_state.status()?,
+        (
+            contract_state.customer_balance()?,
+            contract_state.merchant_balance()?,
+        ),
+        (
+            *close_message.customer_balance(),
+            *close_message.merchant_balance(),
+        ),
+    )
+    .with_context(|| {
+        format!(
+            "Operation {} at level {} is not a custClose on the stored balances",
+            receipt.operation_hash, receipt.level
+        )
+    })?;
+
+    finalize_customer_close(database, channel_name, *close_message.merchant_balance()).await?;
+    database
+        .set_awaiting_broadcast(channel_name, false)
+        .await
+        .context(format!(
+            "Failed to clear broadcast marker for {} after successful close",
+            channel_name
+        ))?;
+
+    // Notify the on-chain monitoring daemon this channel has started to close.
+    daemon::refresh(config, Notify::BestEffort).await
+}
+
 #[derive(Debug, Clone, Serialize)]
 struct Closing {
     channel_id: ChannelId,
@@ -66,12 +356,32 @@ struct Closing {
     merchant_balance: MerchantBalance,
     closing_signature: CloseStateSignature,
     revocation_lock: RevocationLock,
+    contract_id: Option<ContractId>,
+    tezos_uri: String,
+}
+
+/// Where to write the closing data when closing off chain.
+#[derive(Debug, Clone, Default)]
+pub struct CloseOutput {
+    /// The file to write, if not the default for the channel.
+    pub path: Option<PathBuf>,
+    /// Whether to replace the file if it already exists.
+    pub overwrite: bool,
 }
 
-#[derive(PartialEq)]
-pub enum UnilateralCloseKind {
-    MerchantInitiated,
-    CustomerInitiated,
+/// What a close did, beyond updating the channel state.
+#[derive(Debug, Clone, Default)]
+pub struct CloseResult {
+    /// The hash of the custClose or mutualClose operation, if one was posted.
+    pub operation_hash: Option<String>,
+    /// Where the closing data was written, if closing off chain.
+    pub close_json: Option<PathBuf>,
+}
+
+/// Report where the closing data for an off-chain close was written, on its own line on stdout,
+/// so that scripts can find it.
+pub fn report_close_json(path: &Path) {
+    println!("close-json: {}", path.display());
 }
 
 /// Initiate channel closure on the current balances as part of a unilateral customer or a
@@ -85,18 +395,69 @@ pub async fn unilateral_close(
     channel_name: &ChannelName,
     config: &Config,
     off_chain: bool,
+    output: &CloseOutput,
     rng: &mut StdRng,
     database: &dyn QueryCustomer,
     close_kind: UnilateralCloseKind,
-) -> Result<(), anyhow::Error> {
+) -> Result<CloseResult, anyhow::Error> {
+    // Make sure the CLI and the daemon don't both try to close at once
+    let guard = OperationGuard::acquire(
+        database,
+        channel_name,
+        Entrypoint::CustomerClose,
+        config.transaction_timeout,
+    )
+    .await?;
+    let result = unilateral_close_guarded(
+        channel_name,
+        config,
+        off_chain,
+        output,
+        rng,
+        database,
+        close_kind,
+    )
+    .await;
+    guard.release_after(database, result).await
+}
+
+/// Close unilaterally while holding the guard for the custClose entrypoint.
+async fn unilateral_close_guarded(
+    channel_name: &ChannelName,
+    config: &Config,
+    off_chain: bool,
+    output: &CloseOutput,
+    rng: &mut StdRng,
+    database: &dyn QueryCustomer,
+    close_kind: UnilateralCloseKind,
+) -> Result<CloseResult, anyhow::Error> {
+    // Refuse to produce a second custClose operation for a channel that already has one
+    let channel = database.get_channel(channel_name).await?;
+    close::check_not_closing(channel.state.state_name(), channel.awaiting_broadcast)
+        .with_context(|| format!("Cannot close {} again", channel_name))?;
+
+    // When closing off chain, open the file to write the closing data to, and gather what goes
+    // in it, before the channel starts closing, so that a file which can't be written doesn't
+    // leave the channel stuck closing without it
+    let off_chain_output = if off_chain {
+        Some((
+            open_close_json(config, output, channel.state.channel_id())?,
+            channel.contract_details.contract_id,
+            config.tezos_network()?.uri().to_string(),
+        ))
+    } else {
+        None
+    };
+
     // Read the closing message and set the channel state to PendingClose
     let close_message = get_close_message(rng, database, channel_name)
         .await
         .context("Failed to fetch closing message from database")?;
 
-    // If the customer has no money to claim in expiry, just update the status
-    if close_kind == UnilateralCloseKind::MerchantInitiated
-        && close_message.customer_balance().into_inner() == 0
+    // If the customer has no money to claim in expiry, just update the status: the chain watcher
+    // finalizes the channel once the merchant claims the whole balance
+    if unilateral_close_plan(close_kind, *close_message.customer_balance())
+        == UnilateralClosePlan::AwaitMerchantClaim
     {
         database
             .with_channel_state(
@@ -111,32 +472,66 @@ pub async fn unilateral_close(
                 "Failed to update channel status to PendingExpiry for {}",
                 channel_name
             ))??;
-        return Ok(());
+        return Ok(CloseResult::default());
     }
 
-    if !off_chain {
-        // Call the custClose entrypoint and wait for it to be confirmed on chain
-        let tezos_client = load_tezos_client(config, channel_name, database).await?;
-        tezos_client.cust_close(&close_message).await?;
-    } else {
-        // TODO: Print out information necessary to produce custClose transaction
-        // Wait for customer confirmation that it posted
+    let operation_hash = if let Some((close_json, contract_id, tezos_uri)) = off_chain_output {
+        // Write out the information necessary to produce the custClose operation, and leave the
+        // channel in PendingClose until the customer confirms that they broadcast it
         let closing = Closing {
             merchant_balance: *close_message.merchant_balance(),
             customer_balance: *close_message.customer_balance(),
             closing_signature: close_message.closing_signature().clone(),
             revocation_lock: *close_message.revocation_lock(),
             channel_id: *close_message.channel_id(),
+            contract_id,
+            tezos_uri,
         };
-        write_close_json(&closing)?;
-    }
-
-    // React to a successfully posted custClose: update final merchant balance
+        let close_json_path = close_json.path().to_owned();
+        close_json.write(&closing)?;
+        database
+            .set_awaiting_broadcast(channel_name, true)
+            .await
+            .context(format!(
+                "Failed to mark {} as awaiting broadcast of custClose",
+                channel_name
+            ))?;
+        tracing::info!(
+            label = %channel_name,
+            "Once the custClose operation is confirmed on chain, run `confirm-close {} --receipt \
+            <file>`",
+            channel_name
+        );
+        return Ok(CloseResult {
+            operation_hash: None,
+            close_json: Some(close_json_path),
+        });
+    } else {
+        // Call the custClose entrypoint and wait for it to be confirmed on chain
+        let tezos_client = load_tezos_client(config, channel_name, database).await?;
+        let operation_hash = tezos_client.cust_close(&close_message).await?.hash;
+        tracing::info!(
+            label = %channel_name,
+            contract_id = %tezos_client.contract_id,
+            entrypoint = %Entrypoint::CustomerClose,
+            %operation_hash,
+            "Posted custClose"
+        );
+        operation_hash
+    };
+
+    // React to a successfully posted custClose: update final merchant balance. If the customer
+    // balance is zero, the chain watcher still finalizes the channel after the claim delay,
+    // without posting custClaim
     finalize_customer_close(database, channel_name, *close_message.merchant_balance()).await?;
 
     // Notify the on-chain monitoring daemon this channel has started to close.
-    //refresh_daemon(&config).await
-    Ok(())
+    daemon::refresh(config, Notify::BestEffort).await?;
+
+    Ok(CloseResult {
+        operation_hash: Some(operation_hash),
+        close_json: None,
+    })
 }
 
 /// Update channel balances when merchant receives payout in unilateral close flows.
@@ -148,11 +543,23 @@ async fn finalize_customer_close(
     channel_name: &ChannelName,
     merchant_balance: MerchantBalance,
 ) -> anyhow::Result<()> {
-    // TODO: assert that the db status is PendingClose,
+    let state = database.get_channel(channel_name).await?.state;
+    if !zkchannels_state::PendingClose.matches(&state) {
+        return Err(anyhow::anyhow!(
+            "Failed to finalize close for {}. Unexpected channel state: expected PendingClose; got {}",
+            channel_name,
+            state.state_name(),
+        ));
+    }
 
     // Indicate that the merchant balance has been paid out to the merchant
     database
-        .update_closing_balances(channel_name, merchant_balance, None)
+        .update_closing_balances(
+            channel_name,
+            ClosingPath::Unilateral,
+            merchant_balance,
+            None,
+        )
         .await
         .context(format!(
             "Failed to save channel balances for {} after successful close",
@@ -167,11 +574,29 @@ async fn finalize_customer_close(
 /// **Usage**: this function is called when
 /// the contract's customer claim delay has passed *and* the custClose entrypoint call/operation
 /// is confirmed on chain at any depth.
+pub async fn claim_funds(
+    database: &dyn QueryCustomer,
+    config: &Config,
+    channel_name: &ChannelName,
+) -> Result<(), anyhow::Error> {
+    // Make sure the CLI and the daemon don't both try to claim at once
+    let guard = OperationGuard::acquire(
+        database,
+        channel_name,
+        Entrypoint::CustomerClaim,
+        config.transaction_timeout,
+    )
+    .await?;
+    let result = claim_funds_guarded(database, config, channel_name).await;
+    guard.release_after(database, result).await
+}
+
+/// Claim funds while holding the guard for the custClaim entrypoint.
 //
 // Note to developers: This function reverts the status update if the `cust_claim` entrypoint call
 // fails. This revert is only valid if no other state changes in this function!
 // DO NOT ADD STATE CHANGES without first removing the status update.
-pub async fn claim_funds(
+async fn claim_funds_guarded(
     database: &dyn QueryCustomer,
     config: &Config,
     channel_name: &ChannelName,
@@ -225,7 +650,16 @@ pub async fn claim_funds(
         .await
         .with_context(|| format!("Failed to claim customer funds for {}", channel_name))
     {
-        Ok(_) => Ok(()),
+        Ok(status) => {
+            tracing::info!(
+                label = %channel_name,
+                contract_id = %tezos_client.contract_id,
+                entrypoint = %Entrypoint::CustomerClaim,
+                status = status.as_str(),
+                "Posted custClaim"
+            );
+            Ok(())
+        }
         Err(e) => {
             // If `custClaim` didn't post correctly, revert state back to PendingClose
             database
@@ -242,177 +676,59 @@ pub async fn claim_funds(
     }
 }
 
-/// Update channel to indicate a dispute.
-///
-/// **Usage**: this function is called in response to a merchDispute entrypoint call/operation that is
-/// confirmed on chain at any depth.
-pub async fn process_dispute(
-    database: &dyn QueryCustomer,
-    channel_name: &ChannelName,
-) -> Result<(), anyhow::Error> {
-    // Update channel status to Dispute
-    database
-        .with_channel_state(
-            channel_name,
-            zkchannels_state::PendingClose,
-            |closing_message| -> Result<_, Infallible> {
-                Ok((State::Dispute(closing_message), ()))
-            },
-        )
-        .await
-        .context(format!(
-            "Failed to update channel status to Dispute for {}",
-            channel_name
-        ))??;
-
-    Ok(())
-}
-
-/// Update channel state once a disputed unilateral close flow is finalized.
-///
-/// **Usage**: this function is called when a merchDispute entrypoint call/operation is confirmed
-/// on chain to the required confirmation depth.
-pub async fn finalize_dispute(
-    database: &dyn QueryCustomer,
-    channel_name: &ChannelName,
-) -> Result<(), anyhow::Error> {
-    // Update channel status from Dispute to Closed
-    let (customer_balance, merchant_balance) = database
-        .with_channel_state(
-            channel_name,
-            zkchannels_state::Dispute,
-            |closing_message| -> Result<_, anyhow::Error> {
-                let balances = transfer_balances_to_merchant(
-                    *closing_message.customer_balance(),
-                    *closing_message.merchant_balance(),
-                )?;
-                Ok((State::Closed(closing_message), balances))
-            },
-        )
-        .await
-        .context(format!(
-            "Failed to update channel status to Closed for {}",
-            channel_name
-        ))??;
-
-    // Indicate that all balances are paid out to the merchant
-    database
-        .update_closing_balances(channel_name, merchant_balance, Some(customer_balance))
-        .await
-        .context(format!(
-            "Failed to save final channel balances for {} after successful dispute",
-            channel_name
-        ))?;
-
-    Ok(())
-}
-
-/// Update channel state once an undisputed unilateral close flow is complete.
-/// This is either a customer unilateral close or an expiry close flow.
-///
-/// **Usage**: this function is called as response to an on-chain event:
-/// - a custClaim entrypoint call operation is confirmed on chain at the required confirmation depth
-pub async fn finalize_customer_claim(
-    database: &dyn QueryCustomer,
-    channel_name: &ChannelName,
-) -> Result<(), anyhow::Error> {
-    // Update status from PendingCustomerClaim to Closed
-    let (merchant_balance, customer_balance) = database
-        .with_channel_state(
-            channel_name,
-            zkchannels_state::PendingCustomerClaim,
-            |closing_message| -> Result<_, Infallible> {
-                let balances = (
-                    *closing_message.merchant_balance(),
-                    *closing_message.customer_balance(),
-                );
-                Ok((State::Closed(closing_message), balances))
-            },
-        )
-        .await
-        .context(format!(
-            "Failed to update channel status to Closed for {}",
-            channel_name
-        ))??;
-
-    // Update final balances to indicate that the customer balance is paid out to the customer
-    database
-        .update_closing_balances(channel_name, merchant_balance, Some(customer_balance))
-        .await
-        .context(format!(
-            "Failed to save final channel balances for {} after successful close",
-            channel_name
-        ))?;
-
-    Ok(())
-}
-
-/// Update channel state after the merchant claims the full channel balances; this happens in the
-/// expiry close flow if the customer _does not_ post corrected channel balances via custCluse.
+/// Update the channel state from PendingExpiry or PendingClose to Closed after the merchant
+/// claimed the whole channel balance with merchClaim.
 ///
-/// **Usage**: this function is called as response to an on-chain event:
-/// - a merchClaim entrypoint call operation is confirmed on chain at the required confirmation depth
-pub async fn finalize_expiry(
+/// A channel is left in PendingClose if its custClose operation never landed before the merchant
+/// claimed, and is still open (or mutually closing) if the whole expiry went by unanswered, such
+/// as while the chain watcher was stopped.
+pub async fn finalize_merchant_claim(
+    rng: &mut StdRng,
     database: &dyn QueryCustomer,
     channel_name: &ChannelName,
 ) -> Result<(), anyhow::Error> {
-    // Update status from PendingExpiry to Closed
-    // Calculate updated balances (all money going to the merchant)
-    let (customer_balance, merchant_balance) = database
-        .with_channel_state(
-            channel_name,
-            zkchannels_state::PendingExpiry,
-            |closing_message| -> Result<_, anyhow::Error> {
-                let balances = transfer_balances_to_merchant(
-                    *closing_message.customer_balance(),
-                    *closing_message.merchant_balance(),
-                )?;
-                Ok((State::Closed(closing_message), balances))
-            },
-        )
-        .await
-        .context(format!(
-            "Failed to update channel status to Closed for {}",
-            channel_name
-        ))??;
-
-    // Save final balances (with all money going to the merchant)
-    database
-        .update_closing_balances(channel_name, merchant_balance, Some(customer_balance))
-        .await
-        .context(format!(
-            "Failed to save final channel balances for {} after successful close",
-            channel_name
-        ))?;
-
-    Ok(())
+    let mut channel = database.get_channel(channel_name).await?;
+    let state = channel.state.state_name();
+    if state.is_open() || state == StateName::PendingMutualClose {
+        // The closing message is only needed for its balances, which all go to the merchant
+        get_close_message(rng, database, channel_name).await?;
+        channel = database.get_channel(channel_name).await?;
+    }
+    if zkchannels_state::PendingClose.matches(&channel.state) {
+        database
+            .with_channel_state(
+                channel_name,
+                zkchannels_state::PendingClose,
+                |closing_message| Ok::<_, Infallible>((State::PendingExpiry(closing_message), ())),
+            )
+            .await
+            .context(format!(
+                "Failed to update channel status to PendingExpiry for {}",
+                channel_name
+            ))??;
+    }
+    finalize_expiry(database, channel_name).await
 }
 
 async fn mutual_close(
-    close: &Close,
-    rng: StdRng,
-    config: self::Config,
-) -> Result<(), anyhow::Error> {
-    let database = database(&config)
+    label: &ChannelName,
+    off_chain: bool,
+    output: &CloseOutput,
+    mut rng: StdRng,
+    config: &Config,
+    database: &dyn QueryCustomer,
+) -> Result<(CloseType, CloseResult), anyhow::Error> {
+    let channel_details = database
+        .get_channel(label)
         .await
-        .context("Failed to connect to local database")?;
-
-    let channel_details = database.get_channel(&close.label).await.context(format!(
-        "Failed to get channel details for {}",
-        close.label.clone()
-    ))?;
+        .context(format!("Failed to get channel details for {}", label))?;
 
     // Run zkAbacus mutual close, which sets the channel status to PendingClose and gives the
     // customer authorization to call the mutual close entrypoint
-    let (close_state, chan) = zkabacus_close(
-        rng,
-        database.as_ref(),
-        &close.label,
-        &config,
-        &channel_details.address,
-    )
-    .await
-    .context("zkAbacus close failed.")?;
+    let (close_state, chan) =
+        zkabacus_close(&mut rng, database, label, config, &channel_details.address)
+            .await
+            .context("zkAbacus close failed.")?;
 
     // Receive an authorization signature from merchant under the merchant's EdDSA Tezos key
     let (authorization_signature, chan) = chan
@@ -421,7 +737,7 @@ async fn mutual_close(
         .context("Failed to receive authorization signature from the merchant.")?;
 
     // Verify the authorization siganture under the merchant's EdDSA Tezos key
-    let tezos_client = load_tezos_client(&config, &close.label, database.as_ref()).await?;
+    let tezos_client = load_tezos_client(config, label, database).await?;
     let merchant_tezos_public_key = channel_details.contract_details.merchant_tezos_public_key;
     let verification_result = tezos_client
         .verify_authorization_signature(
@@ -443,24 +759,90 @@ async fn mutual_close(
         Err(_) => abort!(in chan return close::Error::InvalidMerchantAuthorizationSignature),
     }
 
-    // Call the mutual close entrypoint and raise the appropriate error if one exists.
-    // The customer has the option to retry or initiate a unilateral close.
-    // We should consider having the customer automatically initiate a unilateral close after a
-    // random delay.
-    tezos_client
-        .mutual_close(
-            close_state.customer_balance(),
-            close_state.merchant_balance(),
-            &authorization_signature,
-        )
-        .await
-        .context(format!(
-            "Failed to call mutual close for {}",
-            close.label.clone()
-        ))?;
+    // Call the mutual close entrypoint. If that fails and a fallback is configured, close
+    // unilaterally on the closing message already generated for the mutual close
+    let outcome = mutual_close::post_with_fallback(
+        &PostMutualClose {
+            tezos_client: &tezos_client,
+            close_state: &close_state,
+            authorization_signature: &authorization_signature,
+        },
+        &mut rng,
+        config.mutual_close_fallback_timeout,
+    )
+    .await
+    .context(format!("Failed to call mutual close for {}", label))?;
+
+    match outcome {
+        // Finalize the result of the mutual close entrypoint call
+        MutualCloseOutcome::Closed { operation_hash } => {
+            tracing::info!(
+                %label,
+                entrypoint = %Entrypoint::MutualClose,
+                operation_hash = ?operation_hash,
+                "Mutual close confirmed"
+            );
+            finalize_mutual_close(config, database, label).await?;
+            Ok((
+                CloseType::Mutual,
+                CloseResult {
+                    operation_hash,
+                    close_json: None,
+                },
+            ))
+        }
+        MutualCloseOutcome::CloseUnilaterally => {
+            tracing::warn!(%label, "Mutual close failed; closing unilaterally instead");
+            let result = unilateral_close(
+                label,
+                config,
+                off_chain,
+                output,
+                &mut rng,
+                database,
+                UnilateralCloseKind::CustomerInitiated,
+            )
+            .await
+            .context("Unilateral close after failed mutual close failed")?;
+            Ok((UnilateralCloseKind::CustomerInitiated.into(), result))
+        }
+        MutualCloseOutcome::Unexpected(status) => Err(anyhow::anyhow!(
+            "Mutual close of {} failed, and the contract has status {:?}",
+            label,
+            status
+        )),
+    }
+}
 
-    // Finalize the result of the mutual close entrypoint call
-    finalize_mutual_close(database.as_ref(), &close.label).await
+/// The mutualClose operation for a channel, and the status of its contract.
+struct PostMutualClose<'a> {
+    tezos_client: &'a TezosClient,
+    close_state: &'a CloseState,
+    authorization_signature: &'a MutualCloseAuthorizationSignature,
+}
+
+#[async_trait]
+impl MutualCloseChain for PostMutualClose<'_> {
+    type Error = anyhow::Error;
+
+    async fn mutual_close(&self) -> Result<String, Self::Error> {
+        let operation = self
+            .tezos_client
+            .mutual_close(
+                self.close_state.customer_balance(),
+                self.close_state.merchant_balance(),
+                self.authorization_signature,
+            )
+            .await?;
+        match operation.status {
+            OperationStatus::Applied => Ok(operation.hash),
+            _ => Err(anyhow::anyhow!("mutualClose operation was not applied")),
+        }
+    }
+
+    async fn contract_status(&self) -> Result<ContractStatus, Self::Error> {
+        Ok(self.tezos_client.get_contract_state().await?.status()?)
+    }
 }
 
 /// Update the channel state from PendingClose to Closed at completion of mutual close.
@@ -468,7 +850,8 @@ async fn mutual_close(
 /// **Usage**: This should be called when the customer receives a confirmation from the blockchain
 /// that the mutual close operation has been applied and has reached required confirmation depth.
 /// It will only be called after a successful execution of [`mutual_close()`].
-async fn finalize_mutual_close(
+pub async fn finalize_mutual_close(
+    config: &Config,
     database: &dyn QueryCustomer,
     channel_name: &ChannelName,
 ) -> Result<(), anyhow::Error> {
@@ -493,7 +876,12 @@ async fn finalize_mutual_close(
 
     // Update final balances to indicate that the customer balance is paid out to the customer
     database
-        .update_closing_balances(channel_name, merchant_balance, Some(customer_balance))
+        .update_closing_balances(
+            channel_name,
+            ClosingPath::Mutual,
+            merchant_balance,
+            Some(customer_balance),
+        )
         .await
         .context(format!(
             "Failed to save final channel balances for {} after successful close",
@@ -501,12 +889,11 @@ async fn finalize_mutual_close(
         ))?;
 
     // Notify the on-chain monitoring daemon this channel is closed
-    // refresh_daemon(config).await
-    Ok(())
+    daemon::refresh(config, Notify::BestEffort).await
 }
 
 async fn zkabacus_close(
-    mut rng: StdRng,
+    rng: &mut StdRng,
     database: &dyn QueryCustomer,
     channel_name: &ChannelName,
     config: &self::Config,
@@ -518,7 +905,7 @@ async fn zkabacus_close(
             channel_name,
             zkchannels_state::Ready,
             |ready| -> Result<_, Infallible> {
-                let closing_message = ready.close(&mut rng);
+                let closing_message = ready.close(rng);
                 Ok((
                     State::PendingMutualClose(closing_message.clone()),
                     closing_message,
@@ -528,7 +915,7 @@ async fn zkabacus_close(
         .await??;
 
     // Connect communication channel to the merchant
-    let (_session_key, chan) = connect(config, address)
+    let (_session_key, chan) = connect(config, address, Features::NONE)
         .await
         .context("Failed to connect to merchant")?;
 
@@ -577,7 +964,8 @@ async fn get_close_message(
                 | State::PendingExpiry(_)
                 | State::PendingCustomerClaim(_)
                 | State::Dispute(_)
-                | State::Closed(_) => {
+                | State::Closed(_)
+                | State::FundingReclaimed(_) => {
                     return Err(close::Error::UncloseableState(state.state_name()))
                 }
             };
@@ -592,40 +980,18 @@ async fn get_close_message(
     Ok(closing_message)
 }
 
-fn transfer_balances_to_merchant(
-    customer_balance: CustomerBalance,
-    merchant_balance: MerchantBalance,
-) -> Result<(CustomerBalance, MerchantBalance), anyhow::Error> {
-    Ok((
-        CustomerBalance::try_new(0)?,
-        MerchantBalance::try_new(customer_balance.into_inner() + merchant_balance.into_inner())?,
-    ))
-}
-
-fn write_close_json(closing: &Closing) -> Result<(), anyhow::Error> {
-    let close_json_path = PathBuf::from(format!(
-        "{}.close.json",
-        hex::encode(closing.channel_id.to_bytes())
-    ));
-    let mut close_file = File::create(&close_json_path)
-        .with_context(|| format!("Could not open file for writing: {:?}", &close_json_path))?;
-    serde_json::to_writer(&mut close_file, &closing)
-        .with_context(|| format!("Could not write close data to file: {:?}", &close_json_path))?;
-
-    eprintln!("Closing data written to {:?}", &close_json_path);
-    Ok(())
-}
-
-#[allow(unused)]
-async fn refresh_daemon(config: &Config) -> anyhow::Result<()> {
-    let (_session_key, chan) = connect_daemon(config)
-        .await
-        .context("Failed to connect to daemon")?;
-
-    chan.choose::<0>()
-        .await
-        .context("Failed to select daemon Refresh")?
-        .close();
-
-    Ok(())
+fn open_close_json(
+    config: &Config,
+    output: &CloseOutput,
+    channel_id: &ChannelId,
+) -> Result<JsonOutput, anyhow::Error> {
+    let close_json_path = match &output.path {
+        Some(path) => path.clone(),
+        None => config
+            .close_output_dir
+            .clone()
+            .unwrap_or_default()
+            .join(format!("{}.close.json", hex::encode(channel_id.to_bytes()))),
+    };
+    Ok(JsonOutput::open(&close_json_path, output.overwrite)?)
 }
@@ -16,24 +16,36 @@ use zkabacus_crypto::{
 
 use zeekoe::{
     abort,
+    amount::{Amount, Balances, XTZ},
     customer::{
         cli::Establish,
         client::ZkChannelAddress,
-        database::{zkchannels_state, QueryCustomer, QueryCustomerExt, State},
-        Chan, ChannelName, Config,
+        daemon::{self, Notify},
+        database::{
+            self,
+            zkchannels_state::{self, ZkChannelState},
+            ChannelDetails, OperationGuard, QueryCustomer, QueryCustomerExt, State, StateName,
+        },
+        merchant_funding, Chan, ChannelName, Config,
     },
     escrow::{
         tezos,
-        types::{ContractDetails, KeyHash},
+        types::{ContractDetails, ContractStatus, Entrypoint, KeyHash},
     },
     offer_abort, proceed,
-    protocol::{establish, Party::Customer},
+    protocol::{
+        establish,
+        parameters::{self, ChainParameters, MerchantPolicy},
+        version::Features,
+        Party::{Customer, Merchant},
+        Transcript,
+    },
     timeout::WithTimeout,
 };
 
 use tezedge::crypto::Prefix;
 
-use super::{connect, database, load_tezos_client, Command};
+use super::{connect, database, load_tezos_client, session, Command, OutputFormat};
 
 #[derive(Debug, Clone, Serialize)]
 struct Establishment {
@@ -46,7 +58,12 @@ struct Establishment {
 
 #[async_trait]
 impl Command for Establish {
-    async fn run(self, mut rng: StdRng, config: self::Config) -> Result<(), anyhow::Error> {
+    async fn run(
+        self,
+        mut rng: StdRng,
+        config: self::Config,
+        _format: OutputFormat,
+    ) -> Result<(), anyhow::Error> {
         let Self {
             label,
             merchant: address,
@@ -54,6 +71,9 @@ impl Command for Establish {
             merchant_deposit,
             note,
             off_chain,
+            skip_merchant_balance_check,
+            merchant_key_hash,
+            auto_reclaim,
             ..
         } = self;
 
@@ -62,6 +82,26 @@ impl Command for Establish {
             .await
             .context("Failed to connect to local database")?;
 
+        // Make sure the configured Tezos node is on the network we think it is
+        tezos::check_network(&config.tezos_network()?).await?;
+
+        let auto_reclaim = auto_reclaim || config.auto_reclaim;
+
+        // If a channel with this label was interrupted partway through establishment, resume its
+        // on-chain steps rather than originating a second contract, or reclaim its funding
+        if let Some(label) = &label {
+            match database.get_channel(label).await {
+                Ok(channel) if auto_reclaim && stuck_before_merchant_funding(&channel) => {
+                    reclaim_funding(&config, database.as_ref(), label).await?;
+                    tracing::info!(%label, "Reclaimed customer funding");
+                    return Ok(());
+                }
+                Ok(channel) => return resume_establish(&config, database.as_ref(), channel).await,
+                Err(database::Error::NoSuchChannel(_)) => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+
         // Format deposit amounts as the correct types
         let customer_balance = deposit.try_into()?;
 
@@ -70,12 +110,80 @@ impl Command for Establish {
             Some(deposit) => deposit.try_into()?,
         };
 
+        // Make sure the channel as a whole isn't larger than the contract can represent
+        Balances::new(customer_balance, merchant_balance).total()?;
+
+        // Load the customer's Tezos account details
+        let tezos_signer = config.load_tezos_signer().await?;
+
+        // Make sure the funding address can cover the deposit and fees before starting, so that a
+        // shortfall doesn't leave a half-established channel behind
+        if !off_chain {
+            tezos::verify_funding_balance(
+                &config.tezos_network()?,
+                &tezos_signer.funding_address(),
+                Customer,
+                customer_balance.into_inner(),
+            )
+            .await?;
+        }
+
         // Run a **separate** session to get the merchant's public parameters
-        let (zkabacus_customer_config, contract_details) =
+        let (zkabacus_customer_config, contract_details, required_chain_parameters, policy) =
             get_parameters(&config, &address).await?;
 
+        // Read the contents of the channel establishment note, if any: this is the justification,
+        // if any is needed, for why the channel should be allowed to be established (format
+        // unspecified, specific to merchant)
+        let note = note.unwrap_or_default().read(config.max_note_length)?;
+
+        // Check the request against the merchant's advertised limits, so that a channel the
+        // merchant would refuse is caught before any session is opened for it
+        policy.check_currency()?;
+        policy.check_deposits(customer_balance.into_inner(), merchant_balance.into_inner())?;
+        policy.check_note(&note)?;
+
+        // Make sure the merchant's keys are the ones we expect, before committing any funds
+        let key_hash = KeyHash::new(
+            zkabacus_customer_config.merchant_public_key(),
+            contract_details.merchant_funding_address(),
+            &contract_details.merchant_tezos_public_key,
+        );
+        let pinned = database.trusted_merchant_key_hash(&address).await?;
+        match establish::check_merchant_key_hash(key_hash, merchant_key_hash, pinned)? {
+            establish::KeyHashPin::Trusted => {}
+            establish::KeyHashPin::Pin => {
+                database
+                    .trust_merchant_key_hash(&address, &key_hash)
+                    .await
+                    .context("Failed to pin merchant's key hash")?;
+                tracing::info!(%address, %key_hash, "Pinned hash of merchant's keys");
+            }
+        }
+
+        // Agree on the contract's parameters with the merchant, so that the merchant doesn't
+        // reject the contract we originate
+        let chain_parameters = negotiate_chain_parameters(&config, required_chain_parameters)?;
+
+        // Make sure the merchant can fund their side of the channel, so that the customer doesn't
+        // pay to originate and fund a contract which will never be funded
+        if establish::should_check_merchant_balance(
+            off_chain,
+            skip_merchant_balance_check,
+            merchant_balance,
+        ) {
+            tezos::verify_funding_balance(
+                &config.tezos_network()?,
+                &contract_details.merchant_funding_address(),
+                Merchant,
+                merchant_balance.into_inner(),
+            )
+            .await
+            .context("Use --skip-merchant-balance-check to establish the channel anyway")?;
+        }
+
         // Connect with the merchant...
-        let (session_key, chan) = connect(&config, &address)
+        let (session_key, chan) = connect(&config, &address, Features::NONE)
             .await
             .context("Failed to connect to merchant")?;
 
@@ -85,32 +193,27 @@ impl Command for Establish {
             .await
             .context("Failed to select channel establishment session")?;
 
-        // Load the customer's Tezos account details
-        let tezos_key_material = config.load_tezos_key_material()?;
-
         // Format the customer and merchant funding information
-        let merchant_funding_info = tezos::MerchantFundingInformation {
+        let mut merchant_funding_info = tezos::MerchantFundingInformation {
             balance: merchant_balance,
             address: contract_details.merchant_funding_address(),
             public_key: contract_details.merchant_tezos_public_key.clone(),
         };
         let customer_funding_info = tezos::CustomerFundingInformation {
             balance: customer_balance,
-            address: tezos_key_material.funding_address(),
-            public_key: tezos_key_material.public_key().clone(),
+            address: tezos_signer.funding_address(),
+            public_key: tezos_signer.public_key().clone(),
         };
 
+        // Keep a transcript of the session, to bind the establish proof to what was negotiated
+        let mut transcript = Transcript::new(&session_key.to_bytes());
+
         // Send initial request for a new channel with the specified funding information
-        // Timeout accounts for 8 messages sent and received, plus extra time to get approval
-        let (channel_id, chan) = async {
+        // Timeout accounts for 9 messages sent and received, plus extra time to get approval
+        let (channel_id, merchant_balance, chan) = async {
             // Generate randomness for the channel ID
             let customer_randomness = CustomerRandomness::new(&mut rng);
 
-            // Read the contents of the channel establishment note, if any: this is the justification,
-            // if any is needed, for why the channel should be allowed to be established (format
-            // unspecified, specific to merchant)
-            let note = note.unwrap_or_default().read(config.max_note_length)?;
-
             // Compute a hash of the merchant's public key material.
             let key_hash = KeyHash::new(
                 zkabacus_customer_config.merchant_public_key(),
@@ -118,6 +221,15 @@ impl Command for Establish {
                 &merchant_funding_info.public_key,
             );
 
+            // Record the request in the transcript, in the order it is sent
+            transcript.append(&customer_randomness);
+            transcript.append(&customer_funding_info.balance);
+            transcript.append(&merchant_funding_info.balance);
+            transcript.append(&note);
+            transcript.append(&customer_funding_info.public_key);
+            transcript.append(&customer_funding_info.address);
+            transcript.append(&key_hash);
+
             // Send the request for the funding of the channel
             let chan = chan
                 .send(customer_randomness)
@@ -145,34 +257,52 @@ impl Command for Establish {
             // Allow the merchant to reject the funding of the channel, else continue
             offer_abort!(in chan as Customer);
 
+            // Receive the merchant's contribution, which it may have reduced from what was asked
+            let (merchant_contribution, chan) = chan
+                .recv()
+                .await
+                .context("Failed to receive merchant's contribution to the channel")?;
+            transcript.append(&merchant_contribution);
+            let merchant_balance = establish::check_merchant_contribution(
+                merchant_funding_info.balance,
+                merchant_contribution,
+            )?;
+            if merchant_balance.into_inner() < merchant_funding_info.balance.into_inner() {
+                tracing::warn!(
+                    deposit = %Amount::from_minor_units_of_currency(
+                        merchant_balance.into_inner() as i64,
+                        XTZ
+                    ),
+                    "Merchant reduced its deposit into the channel"
+                );
+            }
+
             // Receive merchant randomness contribution to the channel ID formation
             let (merchant_randomness, chan) = chan
                 .recv()
                 .await
                 .context("Failed to receive merchant randomness for channel ID")?;
+            transcript.append(&merchant_randomness);
 
             // Generate channel ID (merchant will share this same value since they use the same inputs)
-            let channel_id = ChannelId::new(
+            let channel_id = establish::channel_id(
                 merchant_randomness,
                 customer_randomness,
-                // Merchant's Pointcheval-Sanders public key:
                 zkabacus_customer_config.merchant_public_key(),
-                // Merchant's Tezos public key
-                merchant_funding_info.public_key.as_ref(),
-                // Customer's Tezos public key
-                customer_funding_info.public_key.as_ref(),
+                &merchant_funding_info.public_key,
+                &customer_funding_info.public_key,
             );
 
-            Ok((channel_id, chan))
+            Ok((channel_id, merchant_balance, chan))
         }
-        .with_timeout(8 * config.message_timeout + config.approval_timeout)
+        .with_timeout(9 * config.message_timeout + config.approval_timeout)
         .await
         .context("Establish timed out while waiting for channel approval")?
         .context("Channel was not approved by merchant")?;
+        merchant_funding_info.balance = merchant_balance;
 
-        // Generate the proof context for the establish proof
-        // TODO: the context should actually be formed from a session transcript up to this point
-        let context = ProofContext::new(&session_key.to_bytes());
+        // Generate the proof context for the establish proof from the transcript so far
+        let context = transcript.context();
 
         let zkabacus_request_parameters = ZkAbacusRequestParameters {
             channel_id,
@@ -192,6 +322,7 @@ impl Command for Establish {
             &address,
             chan,
             label,
+            config.max_label_suffix,
         )
         .with_timeout(4 * config.message_timeout)
         .await
@@ -210,169 +341,152 @@ impl Command for Establish {
             };
             write_establish_json(&establishment)?;
         }
-        let (contract_id, origination_status) = if off_chain {
+        if off_chain {
             // TODO: prompt user to submit the origination of the contract
             todo!("prompt user to submit contract origination details")
-        } else {
-            let tezos_key_material = config.load_tezos_key_material()?;
-            // Originate the contract on-chain
-            tezos::originate(
-                Some(&config.tezos_uri),
-                &merchant_funding_info,
+        }
+        originate_contract(
+            &config,
+            database.as_ref(),
+            &channel_name,
+            &merchant_funding_info,
+            &customer_funding_info,
+            zkabacus_customer_config.merchant_public_key(),
+            &channel_id,
+            chain_parameters,
+        )
+        .await?;
+
+        // If anything goes wrong between origination and the merchant's funding, the customer's
+        // deposit may be left behind in the contract
+        let chan = match async {
+            // Notify merchant that the contract successfully originated and wait for them to verify
+            let chan = async {
+                let contract_details = database.contract_details(&channel_name).await?;
+                let contract_id = contract_details
+                    .contract_id
+                    .context("Contract ID not set")?;
+
+                // Send the contract id to the merchant.
+                let chan = chan
+                    .send(contract_id)
+                    .await
+                    .context("Failed to send contract id to merchant")?;
+                offer_abort!(in chan as Customer);
+
+                Ok(chan)
+            }
+            .with_timeout(config.message_timeout + config.verification_timeout)
+            .await
+            .context(
+                "Establish timed out while waiting for merchant to verify originated contract",
+            )?
+            .context("Merchant failed to verify originated contract")?;
+
+            // Fund the channel
+            if off_chain {
+                // TODO: prompt user to fund the contract on chain
+                todo!("prompt user to fund contract on chain and submit details")
+            }
+            fund_contract(
+                &config,
+                database.as_ref(),
+                &channel_name,
                 &customer_funding_info,
-                zkabacus_customer_config.merchant_public_key(),
-                &tezos_key_material,
-                &channel_id,
-                config.confirmation_depth,
-                config.self_delay,
             )
-            .await
-            .context("Failed to originate contract on-chain")?
-        };
+            .await?;
+
+            // Allow the merchant to confirm customer funding, then confirm merchant funding
+            // Timeout is set to allow each party to receive notification of funding and to verify
+            // it on chain, plus time for the merchant to post funding on chain.
+            let chan = async {
+                let chan = chan
+                    .send(establish::ContractFunded)
+                    .await
+                    .context("Failed to notify merchant contract was funded")?;
+
+                // Wait for merchant to confirm funding
+                offer_abort!(in chan as Customer);
+
+                // Allow the merchant to indicate whether it funded the channel
+                let (_contract_funded, chan) = chan
+                    .recv()
+                    .await
+                    .context("Failed to receive merchant funding confirmation")?;
+
+                let merchant_funding_successful: bool = if off_chain {
+                    // TODO: prompt user to check that the merchant funding was provided
+                    true
+                } else {
+                    let tezos_client =
+                        load_tezos_client(&config, &channel_name, database.as_ref()).await?;
+                    merchant_funding::await_merchant_funding(
+                        &tezos_client,
+                        merchant_balance,
+                        merchant_funding::POLL_INTERVAL,
+                        config.merchant_funding_timeout,
+                    )
+                    .await
+                    .map_or_else(
+                        |err| {
+                            tracing::warn!(error = %err, "Could not verify merchant funding");
+                            false
+                        },
+                        |_| true,
+                    )
+                };
+
+                // Abort if merchant funding was not successful, leaving the channel customer-funded
+                if !merchant_funding_successful {
+                    abort!(in chan return establish::Error::FailedMerchantFunding);
+                }
+
+                // Update database to indicate successful merchant funding.
+                database
+                    .with_channel_state(
+                        &channel_name,
+                        zkchannels_state::CustomerFunded,
+                        |inactive| -> Result<_, Infallible> {
+                            Ok((State::MerchantFunded(inactive), ()))
+                        },
+                    )
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "Failed to update channel {} to MerchantFunded status",
+                            channel_name
+                        )
+                    })??;
 
-        // Check to make sure origination succeeded
-        if !matches!(origination_status, tezos::OperationStatus::Applied) {
-            todo!("Abort protocol because origination failed?")
-        }
+                proceed!(in chan);
 
-        // Update database to indicate successful contract origination.
-        database
-            .with_channel_state(
-                &channel_name,
-                zkchannels_state::Inactive,
-                |inactive| -> Result<_, Infallible> { Ok((State::Originated(inactive), ())) },
+                Ok(chan)
+            }
+            .with_timeout(
+                2 * (config.message_timeout + config.verification_timeout)
+                    + config.transaction_timeout
+                    + config.merchant_funding_timeout,
             )
             .await
-            .context(format!(
-                "Failed to update channel {} to Originated status",
-                &channel_name
-            ))??;
-
-        database
-            .initialize_contract_details(&channel_name, &contract_id)
-            .await
-            .context(format!(
-                "Failed to store contract details for {}",
-                &channel_name
-            ))?;
-
-        // Notify merchant that the contract successfully originated and wait for them to verify
-        let chan = async {
-            let contract_details = database.contract_details(&channel_name).await?;
-            let contract_id = contract_details
-                .contract_id
-                .context("Contract ID not set")?;
-
-            // Send the contract id to the merchant.
-            let chan = chan
-                .send(contract_id)
-                .await
-                .context("Failed to send contract id to merchant")?;
-            offer_abort!(in chan as Customer);
+            .context("Establish timed out waiting for funding confirmation")?
+            .context("Failed to confirm that both parties funded the channel")?;
 
-            Ok(chan)
+            Ok::<_, anyhow::Error>(chan)
         }
-        .with_timeout(config.message_timeout + config.verification_timeout)
         .await
-        .context("Establish timed out while waiting for merchant to verify originated contract")?
-        .context("Merchant failed to verify originated contract")?;
-
-        // Fund the channel
-        let customer_funding_status = if off_chain {
-            // TODO: prompt user to fund the contract on chain
-            todo!("prompt user to fund contract on chain and submit details")
-        } else {
-            let tezos_client = load_tezos_client(&config, &channel_name, database.as_ref()).await?;
-            tezos_client
-                .add_customer_funding(&customer_funding_info)
-                .await?
-        };
-
-        // Check to make sure funding succeeded
-        if !matches!(customer_funding_status, tezos::OperationStatus::Applied) {
-            todo!("Abort protocol because funding failed?")
-        }
-
-        // Update database to indicate successful customer funding.
-        database
-            .with_channel_state(
-                &channel_name,
-                zkchannels_state::Originated,
-                |inactive| -> Result<_, Infallible> { Ok((State::CustomerFunded(inactive), ())) },
-            )
-            .await
-            .with_context(|| {
-                format!(
-                    "Failed to update channel {} to CustomerFunded status",
-                    channel_name
-                )
-            })??;
-
-        // Allow the merchant to confirm customer funding, then confirm merchant funding
-        // Timeout is set to allow each party to receive notification of funding and to verify it
-        // on chain, plus time for the merchant to post funding on chain.
-        let chan = async {
-            let chan = chan
-                .send(establish::ContractFunded)
-                .await
-                .context("Failed to notify merchant contract was funded")?;
-
-            // Wait for merchant to confirm funding
-            offer_abort!(in chan as Customer);
-
-            // Allow the merchant to indicate whether it funded the channel
-            let (_contract_funded, chan) = chan
-                .recv()
-                .await
-                .context("Failed to receive merchant funding confirmation")?;
-
-            let merchant_funding_successful: bool = if off_chain {
-                // TODO: prompt user to check that the merchant funding was provided
-                true
-            } else {
-                let tezos_client =
-                    load_tezos_client(&config, &channel_name, database.as_ref()).await?;
-                tezos_client.verify_merchant_funding().await.map_or_else(
-                    |err| {
-                        eprintln!("Could not verify merchant funding: {}", err);
-                        false
-                    },
-                    |_| true,
-                )
-            };
-
-            // Abort if merchant funding was not successful
-            if !merchant_funding_successful {
-                abort!(in chan return establish::Error::FailedMerchantFunding);
-            }
-
-            // Update database to indicate successful merchant funding.
-            database
-                .with_channel_state(
+        {
+            Ok(chan) => chan,
+            Err(error) => {
+                return Err(abandon_establish(
+                    &config,
+                    database.as_ref(),
                     &channel_name,
-                    zkchannels_state::CustomerFunded,
-                    |inactive| -> Result<_, Infallible> {
-                        Ok((State::MerchantFunded(inactive), ()))
-                    },
+                    auto_reclaim,
+                    error,
                 )
-                .await
-                .with_context(|| {
-                    format!(
-                        "Failed to update channel {} to MerchantFunded status",
-                        channel_name
-                    )
-                })??;
-
-            proceed!(in chan);
-
-            Ok(chan)
-        }
-        .with_timeout(
-            2 * (config.message_timeout + config.verification_timeout) + config.transaction_timeout,
-        )
-        .await
-        .context("Establish timed out waiting for funding confirmation")?
-        .context("Failed to confirm that both parties funded the channel")?;
+                .await)
+            }
+        };
 
         // Run zkAbacus.Activate
         // Timeout accounts for one message sent and reacted to
@@ -388,62 +502,450 @@ impl Command for Establish {
         .context("Establish timed out while activating channel")?
         .context("Failed to activate channel")?;
 
-        // Print success
-        eprintln!(
-            "Successfully established new channel with label \"{}\"",
+        // Report success
+        tracing::info!(label = %channel_name, "Successfully established new channel");
+
+        Ok(())
+    }
+}
+
+/// Resume the on-chain steps of establishing a channel whose establishment was interrupted, from
+/// its stored state.
+///
+/// The merchant's side of the establish session has ended by now, so the channel can't be
+/// activated; this only makes sure the customer's deposit ends up in exactly one contract.
+async fn resume_establish(
+    config: &Config,
+    database: &dyn QueryCustomer,
+    channel: ChannelDetails,
+) -> Result<(), anyhow::Error> {
+    let channel_name = channel.label;
+    let state_name = channel.state.state_name();
+    let step = establish::next_chain_step(state_name).with_context(|| {
+        format!(
+            "Channel {} is already established (status: {})",
+            channel_name, state_name
+        )
+    })?;
+
+    let tezos_signer = config.load_tezos_signer().await?;
+    let zkabacus_customer_config = database.channel_zkabacus_config(&channel_name).await?;
+    let merchant_funding_info = tezos::MerchantFundingInformation {
+        balance: channel.merchant_deposit,
+        address: channel.contract_details.merchant_funding_address(),
+        public_key: channel.contract_details.merchant_tezos_public_key.clone(),
+    };
+    let customer_funding_info = tezos::CustomerFundingInformation {
+        balance: channel.customer_deposit,
+        address: tezos_signer.funding_address(),
+        public_key: tezos_signer.public_key().clone(),
+    };
+
+    if step == establish::ChainStep::Originate {
+        tracing::info!(
+            label = %channel_name,
+            "Resuming establishment: originating contract"
+        );
+        let (_, _, required_chain_parameters, _) = get_parameters(config, &channel.address).await?;
+        let chain_parameters = negotiate_chain_parameters(config, required_chain_parameters)?;
+        originate_contract(
+            config,
+            database,
+            &channel_name,
+            &merchant_funding_info,
+            &customer_funding_info,
+            zkabacus_customer_config.merchant_public_key(),
+            channel.state.channel_id(),
+            chain_parameters,
+        )
+        .await?;
+    }
+
+    if step != establish::ChainStep::AwaitMerchantFunding {
+        tracing::info!(label = %channel_name, "Resuming establishment: funding contract");
+        fund_contract(config, database, &channel_name, &customer_funding_info).await?;
+    }
+
+    tracing::warn!(
+        label = %channel_name,
+        "The channel's contract is funded, but it can't be activated because the merchant is no \
+        longer waiting to establish it"
+    );
+    Ok(())
+}
+
+/// Whether a channel is stuck partway through establishment, with an originated contract that the
+/// merchant hasn't funded.
+fn stuck_before_merchant_funding(channel: &ChannelDetails) -> bool {
+    matches!(
+        channel.state.state_name(),
+        StateName::Originated | StateName::CustomerFunded
+    )
+}
+
+/// Handle establishment failing after the channel's contract was originated, but before the
+/// merchant funded it, by reclaiming the customer's deposit from the contract if `auto_reclaim` is
+/// set, or else telling the customer how to.
+///
+/// Returns the error that establishment failed with, with context if reclaiming failed too.
+async fn abandon_establish(
+    config: &Config,
+    database: &dyn QueryCustomer,
+    channel_name: &ChannelName,
+    auto_reclaim: bool,
+    error: anyhow::Error,
+) -> anyhow::Error {
+    match database.get_channel(channel_name).await {
+        Ok(channel) if stuck_before_merchant_funding(&channel) => {}
+        _ => return error,
+    }
+
+    if !auto_reclaim {
+        tracing::warn!(
+            label = %channel_name,
+            "The channel's contract may still hold your deposit. To reclaim it, run `establish` \
+            again with `--label \"{}\" --auto-reclaim`",
             channel_name
         );
+        return error;
+    }
 
-        Ok(())
+    tracing::warn!(
+        label = %channel_name,
+        error = %format!("{:#}", error),
+        "Establishment failed; reclaiming customer funding..."
+    );
+    match reclaim_funding(config, database, channel_name).await {
+        Ok(()) => {
+            tracing::info!(label = %channel_name, "Reclaimed customer funding");
+            error
+        }
+        Err(reclaim_error) => error.context(format!(
+            "Failed to reclaim customer funding too: {:#}",
+            reclaim_error
+        )),
     }
 }
 
-/// Fetch the merchant's public parameters.
-async fn get_parameters(
+/// Reclaim the customer's deposit from the contract of a channel whose establishment was abandoned
+/// before the merchant funded it, then record that the channel's funding was reclaimed.
+///
+/// **Usage**: this is called when establishment fails, or by the chain watcher for channels whose
+/// establishment stalled.
+pub async fn reclaim_funding(
     config: &Config,
-    address: &ZkChannelAddress,
-) -> Result<(zkabacus_crypto::customer::Config, ContractDetails), anyhow::Error> {
-    // Connect to the merchant
-    let (_session_key, chan) = connect(config, address).await?;
+    database: &dyn QueryCustomer,
+    channel_name: &ChannelName,
+) -> Result<(), anyhow::Error> {
+    // Make sure the CLI and the daemon don't both try to reclaim at once
+    let guard = OperationGuard::acquire(
+        database,
+        channel_name,
+        Entrypoint::ReclaimCustomerFunding,
+        config.transaction_timeout,
+    )
+    .await?;
+    let result = reclaim_funding_guarded(config, database, channel_name).await;
+    guard.release_after(database, result).await
+}
+
+/// Reclaim funding while holding the guard for the reclaimFunding entrypoint.
+async fn reclaim_funding_guarded(
+    config: &Config,
+    database: &dyn QueryCustomer,
+    channel_name: &ChannelName,
+) -> Result<(), anyhow::Error> {
+    let state_name = database.get_channel(channel_name).await?.state.state_name();
+    let tezos_client = load_tezos_client(config, channel_name, database).await?;
+    let status = tezos_client.get_contract_state().await?.status()?;
 
-    // Select the get-parameters session
-    let chan = chan.choose::<0>().await?;
+    match establish::reclaim_step(state_name, status)
+        .with_context(|| format!("Can't reclaim funding for {}", channel_name))?
+    {
+        establish::Reclaim::CallReclaimFunding => {
+            let reclaim_status = tezos_client
+                .reclaim_customer_funding()
+                .await
+                .with_context(|| format!("Failed to reclaim funding for {}", channel_name))?;
+            if !matches!(reclaim_status, tezos::OperationStatus::Applied) {
+                return Err(anyhow::anyhow!(
+                    "Failed to reclaim funding for {}: operation was not applied",
+                    channel_name
+                ));
+            }
+            tracing::info!(
+                label = %channel_name,
+                contract_id = %tezos_client.contract_id,
+                entrypoint = "reclaimFunding",
+                "Reclaimed funding from contract"
+            );
+        }
+        establish::Reclaim::NothingToReclaim => {}
+    }
 
-    // Get the merchant's Pointcheval-Sanders public key
-    let (merchant_public_key, chan) = chan
-        .recv()
-        .await
-        .context("Failed to receive merchant's Pointcheval-Sanders public key")?;
+    // Update database to indicate that the funding was reclaimed
+    if state_name == StateName::Originated {
+        set_funding_reclaimed(database, channel_name, zkchannels_state::Originated).await
+    } else {
+        set_funding_reclaimed(database, channel_name, zkchannels_state::CustomerFunded).await
+    }
+}
 
-    // Get the merchant's commitment parameters
-    let (revocation_commitment_parameters, chan) = chan
-        .recv()
+/// Move a channel from the given state to `FundingReclaimed`.
+async fn set_funding_reclaimed<S: ZkChannelState<ZkAbacusState = Inactive> + Send + 'static>(
+    database: &dyn QueryCustomer,
+    channel_name: &ChannelName,
+    expected_state: S,
+) -> Result<(), anyhow::Error> {
+    database
+        .with_channel_state(
+            channel_name,
+            expected_state,
+            |inactive| -> Result<_, Infallible> { Ok((State::FundingReclaimed(inactive), ())) },
+        )
         .await
-        .context("Failed to receive merchant's revocation commitment parameters")?;
+        .with_context(|| {
+            format!(
+                "Failed to update channel {} to FundingReclaimed status",
+                channel_name
+            )
+        })??;
+    Ok(())
+}
 
-    // Get the merchant's range proof parameters
-    let (range_constraint_parameters, chan) = chan
-        .recv()
+/// Agree on the contract's parameters with the merchant, given those they require, within the
+/// bounds of the customer's configuration.
+fn negotiate_chain_parameters(
+    config: &Config,
+    required: ChainParameters,
+) -> Result<ChainParameters, anyhow::Error> {
+    establish::negotiate_chain_parameters(
+        required,
+        config.self_delay,
+        config.max_accepted_self_delay,
+        config.confirmation_depth,
+    )
+    .context("Refusing to establish a channel with the merchant's required self-delay")
+}
+
+/// Originate the contract for a channel and record it, unless a contract has already been
+/// recorded for the channel, then move the channel to `Originated`.
+///
+/// The contract ID is stored before the channel moves to `Originated`, so that an interruption
+/// between the two never leads to a second origination.
+async fn originate_contract(
+    config: &Config,
+    database: &dyn QueryCustomer,
+    channel_name: &ChannelName,
+    merchant_funding_info: &tezos::MerchantFundingInformation,
+    customer_funding_info: &tezos::CustomerFundingInformation,
+    merchant_public_key: &PublicKey,
+    channel_id: &ChannelId,
+    chain_parameters: ChainParameters,
+) -> Result<(), anyhow::Error> {
+    if database
+        .contract_details(channel_name)
+        .await?
+        .contract_id
+        .is_none()
+    {
+        let tezos_signer = config.load_tezos_signer().await?;
+        let (contract_id, contract_level, origination_status) = tezos::originate(
+            Some(&config.tezos_network()?.uri()),
+            merchant_funding_info,
+            customer_funding_info,
+            merchant_public_key,
+            &tezos_signer,
+            channel_id,
+            chain_parameters.confirmation_depth,
+            chain_parameters.self_delay,
+        )
         .await
-        .context("Failed to receive merchant's range proof parameters")?;
+        .context("Failed to originate contract on-chain")?;
 
-    if range_constraint_parameters.validate().is_err() {
-        return Err(establish::Error::InvalidParameters.into());
+        // Check to make sure origination succeeded
+        if !matches!(origination_status, tezos::OperationStatus::Applied) {
+            return Err(establish::Error::FailedVerifyOrigination.into());
+        }
+
+        database
+            .set_contract_id(channel_name, &contract_id, contract_level)
+            .await
+            .with_context(|| format!("Failed to store contract details for {}", channel_name))?;
+        tracing::info!(
+            label = %channel_name,
+            %contract_id,
+            level = contract_level,
+            "Originated contract"
+        );
     }
 
-    // Get the merchant's tz1 address
-    let (merchant_funding_address, chan) = chan
-        .recv()
+    // Update database to indicate successful contract origination.
+    database
+        .with_channel_state(
+            channel_name,
+            zkchannels_state::Inactive,
+            |inactive| -> Result<_, Infallible> { Ok((State::Originated(inactive), ())) },
+        )
         .await
-        .context("Failed to receive merchant's funding address")?;
+        .with_context(|| {
+            format!(
+                "Failed to update channel {} to Originated status",
+                channel_name
+            )
+        })??;
 
-    // Get the merchant's Tezos public key
-    let (merchant_tezos_public_key, chan) = chan
-        .recv()
+    Ok(())
+}
+
+/// Add the customer's deposit to the channel's originated contract, unless the contract shows it
+/// has already been added.
+async fn fund_contract(
+    config: &Config,
+    database: &dyn QueryCustomer,
+    channel_name: &ChannelName,
+    customer_funding_info: &tezos::CustomerFundingInformation,
+) -> Result<(), anyhow::Error> {
+    let tezos_client = load_tezos_client(config, channel_name, database).await?;
+    let status = tezos_client.get_contract_state().await?.status()?;
+    match status {
+        // With a zero deposit, this is a zero-amount call, if the contract still needs one
+        ContractStatus::AwaitingCustomerFunding => {
+            let customer_funding_status = tezos_client
+                .add_customer_funding(customer_funding_info)
+                .await?;
+
+            // Check to make sure funding succeeded
+            if !matches!(customer_funding_status, tezos::OperationStatus::Applied) {
+                return Err(establish::Error::FailedVerifyCustomerFunding.into());
+            }
+            tracing::info!(
+                label = %channel_name,
+                contract_id = %tezos_client.contract_id,
+                entrypoint = "addFunding",
+                "Funded contract"
+            );
+        }
+        ContractStatus::AwaitingMerchantFunding | ContractStatus::Open => {}
+        status => {
+            return Err(anyhow::anyhow!(
+                "Contract for {} can't be funded with status {:?}",
+                channel_name,
+                status
+            ))
+        }
+    }
+
+    // Update database to indicate successful customer funding.
+    database
+        .with_channel_state(
+            channel_name,
+            zkchannels_state::Originated,
+            |inactive| -> Result<_, Infallible> { Ok((State::CustomerFunded(inactive), ())) },
+        )
         .await
-        .context("Failed to receive merchant's Tezos public key")?;
+        .with_context(|| {
+            format!(
+                "Failed to update channel {} to CustomerFunded status",
+                channel_name
+            )
+        })??;
 
-    chan.close();
+    Ok(())
+}
+
+/// Fetch the merchant's public parameters.
+pub(crate) async fn get_parameters(
+    config: &Config,
+    address: &ZkChannelAddress,
+) -> Result<
+    (
+        zkabacus_crypto::customer::Config,
+        ContractDetails,
+        ChainParameters,
+        MerchantPolicy,
+    ),
+    anyhow::Error,
+> {
+    // Run the session to completion, leaving the connection open for the establish session
+    let (
+        merchant_public_key,
+        revocation_commitment_parameters,
+        range_constraint_parameters,
+        merchant_funding_address,
+        merchant_tezos_public_key,
+        chain_parameters,
+        policy,
+        parameters_signature,
+    ) = session(config, address, |_session_key, chan| async move {
+        // Select the get-parameters session
+        let chan = chan.choose::<0>().await?;
+
+        // Get the merchant's Pointcheval-Sanders public key
+        let (merchant_public_key, chan) = chan
+            .recv()
+            .await
+            .context("Failed to receive merchant's Pointcheval-Sanders public key")?;
+
+        // Get the merchant's commitment parameters
+        let (revocation_commitment_parameters, chan) = chan
+            .recv()
+            .await
+            .context("Failed to receive merchant's revocation commitment parameters")?;
+
+        // Get the merchant's range proof parameters
+        let (range_constraint_parameters, chan) = chan
+            .recv()
+            .await
+            .context("Failed to receive merchant's range proof parameters")?;
+
+        if range_constraint_parameters.validate().is_err() {
+            return Err(establish::Error::InvalidParameters.into());
+        }
+
+        // Get the merchant's tz1 address
+        let (merchant_funding_address, chan) = chan
+            .recv()
+            .await
+            .context("Failed to receive merchant's funding address")?;
+
+        // Get the merchant's Tezos public key
+        let (merchant_tezos_public_key, chan) = chan
+            .recv()
+            .await
+            .context("Failed to receive merchant's Tezos public key")?;
+
+        // Get the merchant's requirements for the channel's contract
+        let (chain_parameters, chan) = chan
+            .recv()
+            .await
+            .context("Failed to receive merchant's contract parameters")?;
+
+        // Get the merchant's limits and policies for channels and payments
+        let (policy, chan) = chan
+            .recv()
+            .await
+            .context("Failed to receive merchant's policy")?;
+
+        // Get the merchant's signature over all of its parameters
+        let (parameters_signature, chan) = chan
+            .recv()
+            .await
+            .context("Failed to receive merchant's signature on its parameters")?;
+
+        chan.close();
+        Ok((
+            merchant_public_key,
+            revocation_commitment_parameters,
+            range_constraint_parameters,
+            merchant_funding_address,
+            merchant_tezos_public_key,
+            chain_parameters,
+            policy,
+            parameters_signature,
+        ))
+    })
+    .await?;
 
     // Check that merchant's tezos public key corresponds to the tezos account that they specified
     let merchant_account_matches = merchant_tezos_public_key.hash() == merchant_funding_address;
@@ -455,6 +957,25 @@ async fn get_parameters(
         return Err(establish::Error::InvalidParameters.into());
     }
 
+    // Check that the merchant signed exactly these parameters with the key checked above, so that
+    // none of them was substituted in transit
+    let signed_parameters = parameters::signed_message(
+        &merchant_public_key,
+        &revocation_commitment_parameters,
+        &range_constraint_parameters,
+        &merchant_funding_address,
+        &merchant_tezos_public_key,
+        &chain_parameters,
+        &policy,
+    );
+    tezos::verify_parameters_signature(
+        &merchant_tezos_public_key,
+        &signed_parameters,
+        &parameters_signature,
+    )
+    .await
+    .context(establish::Error::InvalidParametersSignature)?;
+
     Ok((
         zkabacus_crypto::customer::Config::from_parts(
             merchant_public_key,
@@ -464,7 +985,10 @@ async fn get_parameters(
         ContractDetails {
             merchant_tezos_public_key,
             contract_id: None,
+            contract_level: None,
         },
+        chain_parameters,
+        policy,
     ))
 }
 
@@ -490,6 +1014,7 @@ async fn zkabacus_initialize(
     address: &ZkChannelAddress,
     chan: Chan<establish::Initialize>,
     channel_name: Option<ChannelName>,
+    max_label_suffix: u32,
 ) -> Result<(ChannelName, Chan<establish::CustomerSupplyContractInfo>), anyhow::Error> {
     let (requested, proof) = Requested::new(
         &mut rng,
@@ -532,6 +1057,7 @@ async fn zkabacus_initialize(
         inactive,
         contract_details,
         channel_name,
+        max_label_suffix,
     )
     .await
     .context("Failed to store inactive channel state in local database")?;
@@ -540,27 +1066,54 @@ async fn zkabacus_initialize(
 }
 
 /// Store an [`Inactive`] channel state in the database with a given label and address. If the label
-/// is already in use, find another label that is not and return that.
+/// is already in use, find another label that is not and return that, by adding a number in
+/// parentheses to it, up to `max_label_suffix`.
 async fn store_inactive_local(
     database: &dyn QueryCustomer,
     zkabacus_config: &zkabacus_crypto::customer::Config,
     address: &ZkChannelAddress,
-    inactive: Inactive,
+    mut inactive: Inactive,
     contract_details: &ContractDetails,
     channel_name: Option<ChannelName>,
+    max_label_suffix: u32,
 ) -> Result<ChannelName, anyhow::Error> {
     // Use the specified label, or else use the `ZkChannelAddress` as a string
     let label = channel_name.unwrap_or_else(|| ChannelName::new(address.to_string()));
 
-    // Try inserting the inactive state with this label
-    match database
-        .new_channel(&label, address, inactive, contract_details, zkabacus_config)
-        .await
-    {
-        Ok(()) => Ok(label),
-        Err((_returned_inactive, error)) => {
-            // TODO: what to do with the `Inactive` state here when the database has failed to allow us to persist it?
-            Err(error.into())
+    // Try inserting the inactive state with this label, then with each suffix in turn
+    let mut suffix = 0;
+    let mut candidate = label.clone();
+    loop {
+        match database
+            .new_channel(
+                &candidate,
+                address,
+                inactive,
+                contract_details,
+                zkabacus_config,
+            )
+            .await
+        {
+            Ok(()) => return Ok(candidate),
+            Err((returned_inactive, database::Error::ChannelExists(_)))
+                if suffix < max_label_suffix =>
+            {
+                inactive = returned_inactive;
+                suffix += 1;
+                candidate = label.suffixed(suffix);
+            }
+            Err((_returned_inactive, database::Error::ChannelExists(_))) => {
+                return Err(anyhow::anyhow!(
+                    "Label \"{}\" and the next {} suffixed labels are all taken; choose another \
+                    with --label",
+                    label,
+                    max_label_suffix
+                ));
+            }
+            Err((_returned_inactive, error)) => {
+                // TODO: what to do with the `Inactive` state here when the database has failed to allow us to persist it?
+                return Err(error.into());
+            }
         }
     }
 }
@@ -598,7 +1151,7 @@ async fn zkabacus_activate(
         .with_context(|| format!("Failed to update channel {} to Ready status", &label))??;
 
     // Notify the on-chain monitoring daemon that there's a new channel.
-    refresh_daemon(config).await
+    daemon::refresh(config, Notify::BestEffort).await
 }
 
 /// Write the establish_json if performing operations off-chain.
@@ -621,21 +1174,6 @@ fn write_establish_json(establishment: &Establishment) -> Result<(), anyhow::Err
         )
     })?;
 
-    eprintln!("Establishment data written to {:?}", &establish_json_path);
-    Ok(())
-}
-
-/// Invoke `Refresh` on the customer daemon.
-async fn refresh_daemon(_config: &Config) -> anyhow::Result<()> {
-    // TODO: if daemon becomes relevant as a server, uncomment this
-    // let (_session_key, chan) = connect_daemon(config)
-    //     .await
-    //     .context("Failed to connect to daemon")?;
-
-    // chan.choose::<0>()
-    //     .await
-    //     .context("Failed to select daemon Refresh")?
-    //     .close();
-
+    tracing::info!(path = ?establish_json_path, "Establishment data written");
     Ok(())
 }
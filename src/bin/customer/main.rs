@@ -3,31 +3,41 @@ use {
     async_trait::async_trait,
     futures::FutureExt,
     rand::{rngs::StdRng, SeedableRng},
+    serde::Serialize,
     sqlx::SqlitePool,
-    std::{convert::identity, sync::Arc, time::Duration},
+    std::{convert::identity, fmt::Display, future::Future, sync::Arc, time::Duration},
     structopt::StructOpt,
     thiserror::Error,
-    webpki::DNSNameRef,
 };
 
 use zeekoe::{
+    build_info::BuildInfo,
     customer::{
         cli::{self, Customer::*},
-        client::{Backoff, SessionKey, ZkChannelAddress},
-        database::{self, connect_sqlite, QueryCustomer},
+        client::{ConnectionCache, SessionKey, ZkChannelAddress},
+        config::TezosNetworkError,
+        database::{self, connect_postgres, connect_sqlite, QueryCustomer},
         defaults::config_path,
-        Chan, ChannelName, Cli, Client, Config,
+        message_length, Chan, ChannelName, Cli, Client, Config,
     },
     escrow::tezos::TezosClient,
-    protocol,
+    logging,
+    protocol::{self, version::Features},
 };
 
 pub(crate) mod close;
 mod establish;
 mod manage;
 mod pay;
+mod proxy;
 mod watch;
 
+lazy_static::lazy_static! {
+    /// Connections to merchants kept open between the sessions of a command, so that a command
+    /// which runs several sessions with a merchant connects to it only once.
+    static ref MERCHANT_CONNECTIONS: ConnectionCache = ConnectionCache::default();
+}
+
 /// A single customer-side command, parameterized by the currently loaded configuration.
 ///
 /// All subcommands of [`cli::Customer`] should implement this, except [`Configure`], which does not need
@@ -35,59 +45,216 @@ mod watch;
 #[async_trait]
 pub trait Command {
     /// Run the command to completion using the given random number generator for all randomness and
-    /// the given customer configuration.
-    async fn run(self, rng: StdRng, config: Config) -> Result<(), anyhow::Error>;
+    /// the given customer configuration, reporting its result in the given format.
+    async fn run(
+        self,
+        rng: StdRng,
+        config: Config,
+        format: OutputFormat,
+    ) -> Result<(), anyhow::Error>;
+}
+
+/// How a command reports its result on stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human-readable text.
+    Human,
+    /// A single JSON value, for scripts.
+    Json,
+}
+
+impl OutputFormat {
+    /// Print the result of a command to stdout in this format.
+    pub fn print(self, result: &(impl Serialize + Display)) -> Result<(), serde_json::Error> {
+        match self {
+            OutputFormat::Human => println!("{}", result),
+            OutputFormat::Json => println!("{}", serde_json::to_string(result)?),
+        }
+        Ok(())
+    }
 }
 
 pub async fn main_with_cli(cli: Cli) -> Result<(), anyhow::Error> {
+    logging::init(cli.log_level.as_deref())?;
+
+    let allow_invalid_certificates = cli.insecure_allow_invalid_certs;
+    if allow_invalid_certificates && !cfg!(feature = "dev-insecure") {
+        return Err(anyhow::anyhow!(
+            "`--insecure-allow-invalid-certs` only works in builds with the `dev-insecure` feature"
+        ));
+    }
+
     let config_path = cli.config.ok_or_else(config_path).or_else(identity)?;
     let config = Config::load(&config_path).map(|result| {
-        result.with_context(|| {
-            format!(
-                "Could not load customer configuration from {:?}",
-                config_path
-            )
-        })
+        result
+            .map(|mut config| {
+                config.allow_invalid_certificates = allow_invalid_certificates;
+                config
+            })
+            .with_context(|| {
+                format!(
+                    "Could not load customer configuration from {:?}",
+                    config_path
+                )
+            })
     });
 
+    if cli.version {
+        let database = config
+            .await
+            .ok()
+            .and_then(|config| config.database_location().ok());
+        BuildInfo::collect(database.as_ref())
+            .await
+            .print(cli.json)?;
+        return Ok(());
+    }
+    let customer = cli
+        .customer
+        .ok_or_else(|| anyhow::anyhow!("No command given; see --help for usage"))?;
+
     // TODO: let this be made deterministic during testing
     let rng = StdRng::from_entropy();
+    let format = if cli.json {
+        OutputFormat::Json
+    } else {
+        OutputFormat::Human
+    };
 
-    match cli.customer {
+    match customer {
         Configure(cli::Configure { .. }) => {
             drop(config);
             tokio::task::spawn_blocking(|| Ok(edit::edit_file(config_path)?)).await?
         }
-        List(list) => list.run(rng, config.await?).await,
-        // Show(show) => show.run(rng, config.await?).await,
-        Rename(rename) => rename.run(rng, config.await?).await,
-        Establish(establish) => establish.run(rng, config.await?).await,
-        Pay(pay) => pay.run(rng, config.await?).await,
-        Refund(refund) => refund.run(rng, config.await?).await,
-        Close(close) => close.run(rng, config.await?).await,
-        Watch(watch) => watch.run(rng, config.await?).await,
+        List(list) => run(list, rng, config.await?, format).await,
+        Show(show) => run(show, rng, config.await?, format).await,
+        Rename(rename) => run(rename, rng, config.await?, format).await,
+        Export(export) => run(export, rng, config.await?, format).await,
+        Import(import) => run(import, rng, config.await?, format).await,
+        Archive(archive) => run(archive, rng, config.await?, format).await,
+        History(history) => run(history, rng, config.await?, format).await,
+        Establish(establish) => run(establish, rng, config.await?, format).await,
+        Pay(pay) => run(pay, rng, config.await?, format).await,
+        Refund(refund) => run(refund, rng, config.await?, format).await,
+        Repair(repair) => run(repair, rng, config.await?, format).await,
+        Close(close) => run(close, rng, config.await?, format).await,
+        ConfirmClose(confirm_close) => run(confirm_close, rng, config.await?, format).await,
+        Watch(watch) => run(watch, rng, config.await?, format).await,
+        Daemon(daemon) => run(daemon, rng, config.await?, format).await,
     }
 }
 
-/// Connect to a given [`ZkChannelAddress`], configured using the parameters in the [`Config`].
+/// Run a command with the loaded configuration, explaining a failure caused by a message larger
+/// than the configured `max_message_length`.
+async fn run(
+    command: impl Command,
+    rng: StdRng,
+    config: Config,
+    format: OutputFormat,
+) -> Result<(), anyhow::Error> {
+    let max_message_length = config.max_message_length;
+    command
+        .run(rng, config, format)
+        .await
+        .map_err(|e| message_length::explain(e, max_message_length))
+}
+
+/// Connect to a given [`ZkChannelAddress`], configured using the parameters in the [`Config`],
+/// and agree on the protocol version with the merchant, which must support the `required`
+/// features.
+///
+/// This uses a connection kept open after an earlier [`session`] with the merchant, if there is
+/// one.
 pub async fn connect(
     config: &Config,
     address: &ZkChannelAddress,
-) -> Result<(SessionKey, Chan<protocol::ZkChannels>), anyhow::Error> {
+    required: Features,
+) -> Result<(SessionKey, Chan<protocol::Sessions>), anyhow::Error> {
+    let (session_key, chan) = client(config, address)?.connect_zkchannel(address).await?;
+    Ok((
+        session_key,
+        negotiate_version(address, chan, required).await?,
+    ))
+}
+
+/// Run a session with the merchant at the given [`ZkChannelAddress`], connecting as [`connect`]
+/// does, and keeping the connection open afterwards for the command's next session with the
+/// merchant.
+///
+/// The session may not need any optional features of the protocol.
+pub async fn session<T, F, Fut>(
+    config: &Config,
+    address: &ZkChannelAddress,
+    run_session: F,
+) -> Result<T, anyhow::Error>
+where
+    F: FnOnce(SessionKey, Chan<protocol::Sessions>) -> Fut,
+    Fut: Future<Output = Result<T, anyhow::Error>>,
+{
+    kept_session(
+        config,
+        address,
+        &MERCHANT_CONNECTIONS,
+        config.reconnect.idle_timeout,
+        Features::NONE,
+        |session_key, chan, _| run_session(session_key, chan),
+    )
+    .await
+}
+
+/// Run a session with the merchant at the given [`ZkChannelAddress`] as [`session`] does, but
+/// keeping the connection in the given cache for as long as the given idle timeout, requiring the
+/// given features of the merchant, and telling the session whether it runs over a connection
+/// which was kept open from an earlier session.
+pub async fn kept_session<T, F, Fut>(
+    config: &Config,
+    address: &ZkChannelAddress,
+    kept: &ConnectionCache,
+    idle_timeout: Duration,
+    required: Features,
+    run_session: F,
+) -> Result<T, anyhow::Error>
+where
+    F: FnOnce(SessionKey, Chan<protocol::Sessions>, bool) -> Fut,
+    Fut: Future<Output = Result<T, anyhow::Error>>,
+{
+    let mut client = client(config, address)?;
+    client.reuse_connections(kept.clone(), idle_timeout, config.reconnect.max_idle);
+    client
+        .session_zkchannel_noting_reuse(address, |session_key, chan, reused| async move {
+            let chan = negotiate_version(address, chan, required).await?;
+            run_session(session_key, chan, reused).await
+        })
+        .await
+}
+
+/// A client for the merchant at the given [`ZkChannelAddress`], configured using the parameters
+/// in the [`Config`].
+#[cfg_attr(not(feature = "dev-insecure"), allow(unused_variables))]
+fn client(
+    config: &Config,
+    address: &ZkChannelAddress,
+) -> Result<Client<protocol::ZkChannels>, anyhow::Error> {
     let Config {
-        backoff,
-        connection_timeout,
+        reconnect,
         max_pending_connection_retries,
         max_message_length,
         trust_certificate,
+        client_certificate,
+        client_key,
         ..
     } = config;
 
-    let mut client: Client<protocol::ZkChannels> = Client::new(*backoff);
+    let mut client: Client<protocol::ZkChannels> = Client::new(reconnect.backoff());
     client
         .max_length(*max_message_length)
-        .timeout(*connection_timeout)
-        .max_pending_retries(*max_pending_connection_retries);
+        .timeout(reconnect.timeout)
+        .max_pending_retries(*max_pending_connection_retries)
+        .reuse_connections(
+            MERCHANT_CONNECTIONS.clone(),
+            reconnect.idle_timeout,
+            reconnect.max_idle,
+        );
 
     if let Some(path) = trust_certificate {
         #[cfg(feature = "allow_explicit_certificate_trust")]
@@ -99,37 +266,56 @@ pub async fn connect(
         })?;
 
         #[cfg(not(feature = "allow_explicit_certificate_trust"))]
-        eprintln!(
-            "Ignoring explicitly trusted certificate at {:?} because \
-            this binary was built to only trust webpki roots of trust",
-            path
+        tracing::warn!(
+            path = ?path,
+            "Ignoring explicitly trusted certificate because this binary was built to only trust \
+            webpki roots of trust"
         );
     }
 
-    Ok(client.connect_zkchannel(address).await?)
-}
+    if let (Some(certificate), Some(key)) = (client_certificate, client_key) {
+        client
+            .client_certificate(certificate, key)
+            .with_context(|| format!("Failed to load client certificate at {:?}", certificate))?;
+    }
 
-pub async fn connect_daemon(
-    config: &Config,
-) -> anyhow::Result<(SessionKey, Chan<protocol::daemon::Daemon>)> {
-    // Always error immediately. We don't need retry/reconnect for the daemon.
-    let mut backoff = Backoff::with_delay(Duration::ZERO);
-    backoff.max_retries(0);
+    #[cfg(feature = "dev-insecure")]
+    if config.allow_invalid_certificates {
+        tracing::warn!(
+            %address,
+            "INSECURE: not checking the merchant's certificate, so this connection is not \
+            authenticated (--insecure-allow-invalid-certs)"
+        );
+        client.allow_invalid_certificates();
+    }
+
+    Ok(client)
+}
 
-    let address = DNSNameRef::try_from_ascii_str("localhost").unwrap();
-    let client: Client<protocol::daemon::Daemon> = Client::new(backoff);
-    Ok(client.connect(&address.into(), config.daemon_port).await?)
+/// Agree on the protocol version with the merchant at the given [`ZkChannelAddress`], at the start
+/// of a session which needs the `required` features.
+async fn negotiate_version(
+    address: &ZkChannelAddress,
+    chan: Chan<protocol::ZkChannels>,
+    required: Features,
+) -> Result<Chan<protocol::Sessions>, anyhow::Error> {
+    let (merchant, features, chan) =
+        protocol::version::customer(chan, protocol::version::Hello::current(), required)
+            .await
+            .with_context(|| format!("Failed to agree on protocol version with {}", address))?;
+    tracing::debug!(
+        %address,
+        version = %merchant.version,
+        features = %features,
+        "Merchant speaks protocol version"
+    );
+    Ok(chan)
 }
 
 /// Connect to the database specified by the configuration.
 pub async fn database(config: &Config) -> Result<Arc<dyn QueryCustomer>, anyhow::Error> {
-    let location = match config.database.clone() {
-        None => zeekoe::customer::defaults::database_location()?,
-        Some(l) => l,
-    };
-
     use zeekoe::customer::config::DatabaseLocation;
-    let database = match location {
+    let database: Arc<dyn QueryCustomer> = match config.database_location()? {
         DatabaseLocation::Ephemeral => Arc::new(
             SqlitePool::connect("file::memory:")
                 .await
@@ -140,22 +326,69 @@ pub async fn database(config: &Config) -> Result<Arc<dyn QueryCustomer>, anyhow:
             conn.migrate().await?;
             conn
         }
-        DatabaseLocation::Postgres(_) => {
-            return Err(anyhow::anyhow!(
-                "Postgres database support is not yet implemented"
-            ))
+        DatabaseLocation::Postgres(ref uri) => {
+            let conn = connect_postgres(uri).await?;
+            conn.migrate().await?;
+            conn
         }
     };
     Ok(database)
 }
 
+/// Find the label of the channel given on the command line, either by its label, by the start of
+/// its ID, or by its merchant's address.
+///
+/// An address only identifies a channel if it is the only one with that merchant which isn't
+/// closed; otherwise, the channels to choose from are listed.
+pub async fn resolve_label(
+    database: &dyn QueryCustomer,
+    label: Option<&ChannelName>,
+    channel_id_prefix: Option<&str>,
+    address: Option<&ZkChannelAddress>,
+) -> Result<ChannelName, anyhow::Error> {
+    match (label, channel_id_prefix, address) {
+        (Some(label), _, _) => Ok(label.clone()),
+        (None, Some(prefix), _) => database
+            .channel_label_for_prefix(prefix)
+            .await
+            .context("Failed to find channel by ID"),
+        (None, None, Some(address)) => {
+            let mut labels = database
+                .channels_for_address(address)
+                .await
+                .context("Failed to find channel by merchant address")?;
+            match labels.len() {
+                0 => Err(anyhow::anyhow!(
+                    "There is no channel with {} which isn't closed",
+                    address
+                )),
+                1 => Ok(labels.remove(0)),
+                _ => Err(anyhow::anyhow!(
+                    "More than one channel with {} isn't closed; choose one by label: {}",
+                    address,
+                    labels
+                        .iter()
+                        .map(|label| format!("\"{}\"", label))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )),
+            }
+        }
+        (None, None, None) => Err(anyhow::anyhow!(
+            "No channel label, ID, or address was given"
+        )),
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum TezosClientError {
     #[error("Contract details for {0} are not set")]
     ContractDetailsNotSet(ChannelName),
-    #[error("Failed to  load key material: {0}")]
+    #[error("Failed to load key material: {0}")]
     InvalidKeyMaterial(#[from] anyhow::Error),
     #[error(transparent)]
+    InvalidNetwork(#[from] TezosNetworkError),
+    #[error(transparent)]
     DatabaseError(#[from] database::Error),
 }
 
@@ -173,13 +406,7 @@ pub async fn load_tezos_client(
         }
     };
 
-    Ok(TezosClient {
-        uri: Some(config.tezos_uri.clone()),
-        contract_id,
-        client_key_pair: config.load_tezos_key_material()?,
-        confirmation_depth: config.confirmation_depth,
-        self_delay: config.self_delay,
-    })
+    Ok(config.tezos_client(contract_id, config.load_tezos_signer().await?)?)
 }
 
 #[allow(unused)]
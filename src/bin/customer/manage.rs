@@ -1,73 +1,135 @@
 use {
     async_trait::async_trait,
     comfy_table::{Cell, Table},
+    futures::stream::StreamExt,
     rand::rngs::StdRng,
-    std::convert::TryInto,
+    std::{
+        collections::HashMap,
+        fs::OpenOptions,
+        io::{self, BufRead, BufWriter, Write},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+    zeroize::Zeroizing,
 };
 
-use zeekoe::{
-    amount::{Amount, XTZ},
-    customer::{
-        cli::{List, Rename},
-        Config,
+use zeekoe::customer::{
+    backup,
+    cli::{Archive, Export, History, Import, List, Rename, Show},
+    database::{ChannelDetails, QueryCustomer},
+    output::{
+        write_csv_row, ChannelDescription, ChannelListing, HistoryFormat, HistoryRecord,
+        PaymentSummary, StateChange,
     },
+    ChannelName, Config,
 };
 
-use super::{database, Command};
+use super::{database, resolve_label, Command, OutputFormat};
 use anyhow::Context;
-use serde_json::json;
 
 #[async_trait]
 impl Command for List {
-    async fn run(self, _rng: StdRng, config: self::Config) -> Result<(), anyhow::Error> {
+    async fn run(
+        self,
+        _rng: StdRng,
+        config: self::Config,
+        format: OutputFormat,
+    ) -> Result<(), anyhow::Error> {
         let database = database(&config)
             .await
             .context("Failed to connect to local database")?;
-        let channels = database.get_channels().await?;
-
-        // TODO: don't hard-code XTZ here, instead store currency in database
-        let amount = |b: u64| Amount::from_minor_units_of_currency(b.try_into().unwrap(), XTZ);
-
-        if self.json {
-            let mut output = Vec::new();
-            for details in channels {
-                output.push(json!({
-                    "label": details.label,
-                    "state": details.state.state_name(),
-                    "balance": format!("{}", amount(details.state.customer_balance().into_inner())),
-                    "max_refund": format!("{}", amount(details.state.merchant_balance().into_inner())),
-                    "channel_id": format!("{}", details.state.channel_id()),
-                    "contract_id": details.contract_details.contract_id.map_or_else(|| "N/A".to_string(), |contract_id| format!("{}", contract_id))
-                }));
-            }
-            println!("{}", json!(output).to_string());
+        let channels: Vec<ChannelListing> = database
+            .get_channels()
+            .await?
+            .iter()
+            .map(ChannelListing::new)
+            .collect();
+
+        if self.json || format == OutputFormat::Json {
+            println!("{}", serde_json::to_string(&channels)?);
         } else {
             let mut table = Table::new();
             table.load_preset(comfy_table::presets::UTF8_FULL);
-            table.set_header(vec![
-                "Label",
-                "State",
-                "Balance",
-                "Max Refund",
-                "Channel ID",
-                "Contract ID",
-            ]);
-
-            for details in channels {
-                table.add_row(vec![
-                    Cell::new(details.label),
-                    Cell::new(details.state.state_name()),
-                    Cell::new(amount(details.state.customer_balance().into_inner())),
-                    Cell::new(amount(details.state.merchant_balance().into_inner())),
-                    Cell::new(details.state.channel_id()),
-                    Cell::new(details.contract_details.contract_id.map_or_else(
-                        || "N/A".to_string(),
-                        |contract_id| format!("{}", contract_id),
-                    )),
-                ]);
+            table.set_header(ChannelListing::HEADER.to_vec());
+            for channel in channels {
+                table.add_row(channel.row().iter().map(Cell::new).collect::<Vec<_>>());
             }
+            println!("{}", table);
+        }
+        Ok(())
+    }
+}
 
+#[async_trait]
+impl Command for Show {
+    async fn run(
+        self,
+        _rng: StdRng,
+        config: self::Config,
+        format: OutputFormat,
+    ) -> Result<(), anyhow::Error> {
+        let database = database(&config)
+            .await
+            .context("Failed to connect to local database")?;
+        let label = resolve_label(
+            database.as_ref(),
+            self.label.as_ref(),
+            self.channel_id.as_deref(),
+            None,
+        )
+        .await?;
+        let channel = database
+            .get_channel(&label)
+            .await
+            .with_context(|| format!("Failed to get channel details for {}", label))?;
+        let mut description = ChannelDescription::new(
+            &channel,
+            database.operations_in_flight(&label).await?,
+            database.contract_observation(&label).await?,
+        );
+        if self.history {
+            let history = database
+                .channel_history(&label)
+                .await
+                .with_context(|| format!("Failed to get channel history for {}", label))?;
+            description = description.with_history(&history);
+        }
+        if self.payments {
+            let payments = database
+                .channel_payments(&label)
+                .await
+                .with_context(|| format!("Failed to get payments for {}", label))?;
+            description = description.with_payments(&payments);
+        }
+
+        if self.json || format == OutputFormat::Json {
+            println!("{}", serde_json::to_string(&description)?);
+        } else {
+            let mut table = Table::new();
+            table.load_preset(comfy_table::presets::UTF8_FULL);
+            for (name, value) in description.rows() {
+                table.add_row(vec![Cell::new(name), Cell::new(value)]);
+            }
             println!("{}", table);
+
+            if let Some(history) = &description.history {
+                let mut table = Table::new();
+                table.load_preset(comfy_table::presets::UTF8_FULL);
+                table.set_header(StateChange::HEADER.to_vec());
+                for change in history {
+                    table.add_row(change.row().iter().map(Cell::new).collect::<Vec<_>>());
+                }
+                println!("{}", table);
+            }
+
+            if let Some(payments) = &description.payments {
+                let mut table = Table::new();
+                table.load_preset(comfy_table::presets::UTF8_FULL);
+                table.set_header(PaymentSummary::HEADER.to_vec());
+                for payment in payments {
+                    table.add_row(payment.row().iter().map(Cell::new).collect::<Vec<_>>());
+                }
+                println!("{}", table);
+            }
         }
         Ok(())
     }
@@ -76,7 +138,12 @@ impl Command for List {
 #[async_trait]
 impl Command for Rename {
     #[allow(unused)]
-    async fn run(self, rng: StdRng, config: self::Config) -> Result<(), anyhow::Error> {
+    async fn run(
+        self,
+        rng: StdRng,
+        config: self::Config,
+        _format: OutputFormat,
+    ) -> Result<(), anyhow::Error> {
         database(&config)
             .await
             .context("Failed to connect to local database")?
@@ -85,3 +152,287 @@ impl Command for Rename {
             .context("Failed to rename channel")
     }
 }
+
+/// Ask for the passphrase of a backup on the terminal, twice if it's being chosen.
+fn read_passphrase(confirm: bool) -> Result<Zeroizing<String>, anyhow::Error> {
+    let passphrase = Zeroizing::new(rpassword::read_password_from_tty(Some(
+        "Backup passphrase: ",
+    ))?);
+    if confirm {
+        let again = Zeroizing::new(rpassword::read_password_from_tty(Some(
+            "Repeat passphrase: ",
+        ))?);
+        if passphrase != again {
+            return Err(anyhow::anyhow!("Passphrases don't match"));
+        }
+        if passphrase.is_empty() {
+            return Err(anyhow::anyhow!("Passphrase must not be empty"));
+        }
+    }
+    Ok(passphrase)
+}
+
+#[async_trait]
+impl Command for Export {
+    async fn run(
+        self,
+        mut rng: StdRng,
+        config: self::Config,
+        _format: OutputFormat,
+    ) -> Result<(), anyhow::Error> {
+        let database = database(&config)
+            .await
+            .context("Failed to connect to local database")?;
+        let channels = database.export_channels().await?;
+        let passphrase = read_passphrase(true)?;
+
+        // Refuse to replace an existing file, which may be an earlier backup
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&self.out)
+            .with_context(|| format!("Could not create backup file {:?}", self.out))?;
+
+        let sealed = backup::seal(&mut rng, &channels, &passphrase)?;
+        file.write_all(&sealed)
+            .and_then(|()| file.sync_all())
+            .with_context(|| format!("Could not write backup file {:?}", self.out))?;
+
+        eprintln!("Exported {} channels to {:?}", channels.len(), self.out);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Command for Import {
+    async fn run(
+        self,
+        _rng: StdRng,
+        config: self::Config,
+        format: OutputFormat,
+    ) -> Result<(), anyhow::Error> {
+        let sealed = std::fs::read(&self.file)
+            .with_context(|| format!("Could not read backup file {:?}", self.file))?;
+
+        // Check the backup is intact before touching the database
+        let passphrase = read_passphrase(false)?;
+        let channels = backup::open(&sealed, &passphrase)?;
+
+        let database = database(&config)
+            .await
+            .context("Failed to connect to local database")?;
+        let summary = database
+            .import_channels(channels, self.merge_strategy)
+            .await
+            .context("Failed to import channels; nothing was imported")?;
+
+        match format {
+            OutputFormat::Json => println!("{}", serde_json::to_string(&summary)?),
+            OutputFormat::Human => {
+                for (channels, outcome) in [
+                    (&summary.imported, "Imported"),
+                    (&summary.replaced, "Replaced"),
+                    (&summary.skipped, "Skipped existing"),
+                ] {
+                    for label in channels {
+                        println!("{} {}", outcome, label);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Ask a question on the terminal, returning the line typed in answer.
+fn ask(question: &str) -> Result<String, anyhow::Error> {
+    eprint!("{}", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(answer.trim().to_string())
+}
+
+#[async_trait]
+impl Command for Archive {
+    async fn run(
+        self,
+        _rng: StdRng,
+        config: self::Config,
+        format: OutputFormat,
+    ) -> Result<(), anyhow::Error> {
+        let database = database(&config)
+            .await
+            .context("Failed to connect to local database")?;
+        let labels = if self.all_closed {
+            database
+                .get_channels()
+                .await?
+                .into_iter()
+                .filter(|channel| channel.state.state_name().is_terminal())
+                .map(|channel| channel.label)
+                .collect()
+        } else {
+            vec![
+                resolve_label(
+                    database.as_ref(),
+                    self.label.as_ref(),
+                    self.channel_id.as_deref(),
+                    None,
+                )
+                .await?,
+            ]
+        };
+
+        if self.purge && !labels.is_empty() {
+            let names = labels
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            let answer = ask(&format!(
+                "Permanently delete {}? No record of it will be kept. [y/N] ",
+                names
+            ))?;
+            if !answer.eq_ignore_ascii_case("y") {
+                return Err(anyhow::anyhow!("Purge cancelled; nothing was deleted"));
+            }
+            let expected = match labels.as_slice() {
+                [label] => label.to_string(),
+                _ => labels.len().to_string(),
+            };
+            let answer = ask(&format!("Type \"{}\" to confirm: ", expected))?;
+            if answer != expected {
+                return Err(anyhow::anyhow!("Purge cancelled; nothing was deleted"));
+            }
+        }
+
+        let mut removed = Vec::new();
+        for label in labels {
+            if self.purge {
+                database
+                    .purge_channel(&label)
+                    .await
+                    .with_context(|| format!("Failed to purge {}", label))?;
+            } else {
+                database
+                    .archive_channel(&label)
+                    .await
+                    .with_context(|| format!("Failed to archive {}", label))?;
+            }
+            if format == OutputFormat::Human {
+                let outcome = if self.purge { "Purged" } else { "Archived" };
+                println!("{} {}", outcome, label);
+            }
+            removed.push(label);
+        }
+
+        if format == OutputFormat::Json {
+            let outcome = if self.purge { "purged" } else { "archived" };
+            println!("{}", serde_json::json!({ outcome: removed }));
+        }
+        Ok(())
+    }
+}
+
+/// When a channel was closed, if that was recorded.
+async fn closed_at(
+    database: &dyn QueryCustomer,
+    channel: &ChannelDetails,
+) -> Result<Option<SystemTime>, anyhow::Error> {
+    Ok(database
+        .channel_history(&channel.label)
+        .await?
+        .iter()
+        .rev()
+        .find(|event| event.new_state.is_terminal())
+        .map(|event| event.recorded_at))
+}
+
+#[async_trait]
+impl Command for History {
+    async fn run(
+        self,
+        _rng: StdRng,
+        config: self::Config,
+        format: OutputFormat,
+    ) -> Result<(), anyhow::Error> {
+        let database = database(&config)
+            .await
+            .context("Failed to connect to local database")?;
+        let since = self.since.map_or(UNIX_EPOCH, |since| since.0);
+        let format = match format {
+            OutputFormat::Json => HistoryFormat::Json,
+            OutputFormat::Human => self.format,
+        };
+
+        let mut out = BufWriter::new(io::stdout());
+        if format == HistoryFormat::Csv {
+            write_csv_row(&mut out, HistoryRecord::CSV_HEADER)?;
+        }
+
+        // A single payment is looked up on its own, whatever became of it
+        if let Some(payment_id) = &self.payment_id {
+            for payment in database
+                .payments_with_id(payment_id)
+                .await
+                .context("Failed to look up payment")?
+            {
+                HistoryRecord::payment(&payment).write(format, &mut out)?;
+            }
+            out.flush()?;
+            return Ok(());
+        }
+
+        // Closed channels get a summary after their payments
+        let channels = match &self.label {
+            Some(label) => vec![database
+                .get_channel(label)
+                .await
+                .with_context(|| format!("Failed to get channel details for {}", label))?],
+            None => database.get_channels().await?,
+        };
+        let mut closed: HashMap<_, _> = channels
+            .into_iter()
+            .filter(|channel| channel.closing_path.is_some())
+            .map(|channel| (channel.label.clone(), channel))
+            .collect();
+
+        // Payments come one channel at a time, so a channel's summary is written once its
+        // payments are done
+        let mut payments = database.payment_history(self.label.as_ref(), since);
+        let mut previous: Option<ChannelName> = None;
+        loop {
+            let payment = match payments.next().await {
+                Some(payment) => Some(payment.context("Failed to read payment history")?),
+                None => None,
+            };
+            let label = payment.as_ref().map(|payment| &payment.label);
+            if previous.as_ref() != label {
+                if let Some(channel) = previous.take().and_then(|label| closed.remove(&label)) {
+                    let closed_at = closed_at(database.as_ref(), &channel).await?;
+                    HistoryRecord::closed(&channel, closed_at).write(format, &mut out)?;
+                }
+                previous = label.cloned();
+            }
+            match payment {
+                Some(payment) => HistoryRecord::payment(&payment).write(format, &mut out)?,
+                None => break,
+            }
+        }
+        drop(payments);
+
+        // Closed channels with no payments in the period still get a summary, if they closed in it
+        let mut rest: Vec<_> = closed.into_iter().map(|(_, channel)| channel).collect();
+        rest.sort_by_key(|channel| channel.label.to_string());
+        for channel in rest {
+            let closed_at = closed_at(database.as_ref(), &channel).await?;
+            if closed_at.map_or(true, |closed_at| closed_at >= since) {
+                HistoryRecord::closed(&channel, closed_at).write(format, &mut out)?;
+            }
+        }
+
+        out.flush()?;
+        Ok(())
+    }
+}
@@ -1,288 +1,477 @@
-use {anyhow::Context, async_trait::async_trait, rand::rngs::StdRng, std::convert::TryInto};
-
-use zkabacus_crypto::{
-    customer::{LockMessage, StartMessage},
-    ClosingSignature, Context as ProofContext, PayToken, PaymentAmount,
+use {
+    anyhow::Context,
+    async_trait::async_trait,
+    rand::{rngs::StdRng, SeedableRng},
+    std::{
+        convert::TryInto,
+        time::{Duration, Instant},
+    },
 };
 
+use zkabacus_crypto::PaymentAmount;
+
 use zeekoe::{
-    abort,
     customer::{
-        cli::{Note, Pay, Refund},
+        self,
+        cli::{Pay, Refund, Repair},
         client::SessionKey,
-        database::{zkchannels_state, QueryCustomer, QueryCustomerExt, State},
-        Chan, ChannelName, Config,
+        daemon,
+        database::{InterruptedPayment, QueryCustomer, StateName},
+        output::{RepeatedPayment, RepeatedPaymentsSummary},
+        pay::{make_payment, receive_service, record_outcome, send_payment},
+        Chan, ChannelName, Config, RepeatedPayments,
+    },
+    protocol::{
+        daemon::{request_proxy_pay, PayRequest},
+        pay,
+        version::Features,
+        Transcript,
     },
-    offer_abort, proceed,
-    protocol::{pay, Party::Customer},
     timeout::WithTimeout,
 };
 
-use super::{connect, database, Command};
+use super::{connect, database, establish::get_parameters, resolve_label, Command, OutputFormat};
 
 #[async_trait]
 impl Command for Pay {
-    async fn run(self, rng: StdRng, config: self::Config) -> Result<(), anyhow::Error> {
+    async fn run(
+        self,
+        rng: StdRng,
+        config: self::Config,
+        _format: OutputFormat,
+    ) -> Result<(), anyhow::Error> {
         let payment_amount = self.pay.try_into()?;
 
+        // Read the contents of the note, if any
+        let note = self
+            .note
+            .unwrap_or_default()
+            .read(config.max_note_length)
+            .context("Failed to read payment note from standard input or command line")?;
+
         let database = database(&config)
             .await
             .context("Failed to connect to local database")?;
-
-        let (session_key, chan) = open_session(database.as_ref(), &config, &self.label).await?;
-
-        let chan = request_payment(&config, chan, payment_amount, self.note)
-            .with_timeout(config.approval_timeout)
-            .await
-            .context("Payment timed out while awaiting approval")?
-            .context("Payment was not approved by the merchant")?;
-
-        // Run the core zkAbacus.Pay protocol
-        // Timeout is set to 10 messages, which includes all sent & received messages and aborts
-        let chan = zkabacus_pay(
-            rng,
+        let label = resolve_label(
             database.as_ref(),
-            &self.label,
-            session_key,
-            chan,
-            payment_amount,
+            self.label.as_ref(),
+            None,
+            self.address.as_ref(),
         )
-        .with_timeout(10 * config.message_timeout)
-        .await
-        .context("Payment timed out while updating channel status")?
-        .context("Failed to complete pay protocol")?;
+        .await?;
+
+        // Don't start a payment the merchant has said it will refuse
+        check_policy(&config, database.as_ref(), &label, payment_amount, &note).await?;
+
+        if self.repeat.is_some() || self.until_total.is_some() {
+            // Each repeated payment gets a fresh ID, so there's no one payment a given ID could be
+            // recorded against
+            if self.payment_id.is_some() {
+                return Err(anyhow::anyhow!(
+                    "`--payment-id` names a single payment, and can't be used with `--repeat` or \
+                    `--until-total`"
+                ));
+            }
+            if config.proxy_through_daemon {
+                return Err(anyhow::anyhow!(
+                    "Repeated payments are made over a connection of their own, not through the \
+                    daemon; set `proxy_through_daemon = false` to make them"
+                ));
+            }
+            let until_total: Option<PaymentAmount> =
+                self.until_total.map(TryInto::try_into).transpose()?;
+            return pay_repeatedly(
+                &config,
+                database.as_ref(),
+                &label,
+                payment_amount,
+                note,
+                RepeatedPayments::new(payment_amount, self.repeat, until_total),
+                self.interval.unwrap_or_default(),
+            )
+            .await;
+        }
+
+        let started = Instant::now();
+        let payment_id = self.payment_id.unwrap_or_else(pay::PaymentId::generate);
+        tracing::info!(%label, %payment_id, "Making payment");
+
+        let response_note = if config.proxy_through_daemon {
+            proxy_payment(&config, label.clone(), payment_amount, note, payment_id).await?
+        } else {
+            // Finish any payment an interrupted session left in the middle first
+            if resume_payment(&config, database.as_ref(), &label).await? {
+                tracing::info!(%label, "Resumed interrupted payment");
+            }
 
-        receive_service(chan)
-            .with_timeout(config.approval_timeout)
-            .await
-            .context("Payment timed out when receiving service")??;
+            // Don't bother the merchant with a payment the channel can't cover
+            check_balance(database.as_ref(), &label, payment_amount).await?;
+
+            let (session_key, chan) = open_session(database.as_ref(), &config, &label).await?;
+
+            make_payment(
+                rng,
+                &config,
+                database.as_ref(),
+                &label,
+                Transcript::new(&session_key.to_bytes()),
+                chan,
+                payment_amount,
+                note,
+                payment_id,
+                |_| {},
+            )
+            .await?
+        };
+
+        // Report the response note, if any
+        if let Some(response_note) = response_note {
+            tracing::info!(
+                %label,
+                %response_note,
+                "Payment succeeded with response from merchant"
+            );
+        } else {
+            tracing::info!(
+                %label,
+                "Payment succeeded with no concluding response from merchant"
+            );
+        }
+        tracing::info!(
+            %label,
+            elapsed = ?started.elapsed(),
+            "Payment completed"
+        );
 
         Ok(())
     }
 }
 
-/// Set up the communication channel with the merchant.
-async fn open_session(
-    database: &dyn QueryCustomer,
-    config: &Config,
-    channel_name: &ChannelName,
-) -> Result<(SessionKey, Chan<pay::Pay>), anyhow::Error> {
-    // Look up the address and current local customer state for this merchant in the database
-    let address = database
-        .channel_address(channel_name)
-        .await
-        .context("Failed to look up channel address in local database")?;
-
-    // Connect and select the Pay session
-    let (session_key, chan) = connect(config, &address).await?;
-    let chan = chan
-        .choose::<2>()
-        .await
-        .context("Failed selecting pay session with merchant")?;
-
-    Ok((session_key, chan))
-}
-
-/// Request approval for the payment request from the merchant, aborting the session if it is not
-/// granted.
-async fn request_payment(
+/// Make a series of payments of the same amount over one session with the merchant, writing the
+/// outcome of each to stdout as a line of JSON as it completes, followed by a summary of the
+/// payments completed.
+///
+/// A payment which fails stops the series.
+async fn pay_repeatedly(
     config: &Config,
-    chan: Chan<pay::Pay>,
+    database: &dyn QueryCustomer,
+    label: &ChannelName,
     payment_amount: PaymentAmount,
-    payment_note: Option<Note>,
-) -> Result<Chan<pay::CustomerStartPayment>, anyhow::Error> {
-    // Read the contents of the note, if any
-    let note = payment_note
-        .unwrap_or_default()
-        .read(config.max_note_length)
-        .context("Failed to read payment note from standard input or command line")?;
-
-    // Send the payment amount and note to the merchant
-    let chan = chan
-        .send(payment_amount)
-        .await
-        .context("Failed to send payment amount")?
-        .send(note)
-        .await
-        .context("Failed to send payment note")?;
-
-    // Allow the merchant to accept or reject the payment and note
-    offer_abort!(in chan as Customer);
-
-    Ok(chan)
-}
-
-/// Receive the paid-for service from the merchant, printing the outcome if there is one and
-/// closing the communication channel.
-async fn receive_service(chan: Chan<pay::MerchantProvideService>) -> Result<(), anyhow::Error> {
-    // Receive the response note (i.e. the fulfillment of the service)
-    let (response_note, chan) = chan
-        .recv()
-        .await
-        .context("Failed to receive response note")?;
+    note: String,
+    payments: RepeatedPayments,
+    interval: Duration,
+) -> Result<(), anyhow::Error> {
+    let mut completed = 0;
+    let result = async {
+        // Finish any payment an interrupted session left in the middle first
+        if resume_payment(config, database, label).await? {
+            tracing::info!(%label, "Resumed interrupted payment");
+        }
+
+        let address = database
+            .channel_address(label)
+            .await
+            .context("Failed to look up channel address in local database")?;
+        let (session_key, chan) = connect(
+            config,
+            &address,
+            Features::REPEATED_PAYMENTS.union(Features::PAYMENT_IDS),
+        )
+        .await?;
+        let mut chan = chan
+            .choose::<5>()
+            .await
+            .context("Failed selecting repeated pay session with merchant")?;
 
-    // Close the communication channel: we are done communicating with the merchant
-    chan.close();
+        for iteration in payments {
+            if iteration > 1 {
+                tokio::time::sleep(interval).await;
+            }
 
-    // Print the response note on standard out
-    if let Some(response_note) = response_note {
-        eprintln!(
-            "Payment succeeded with response from merchant: \"{}\"",
-            response_note
-        );
-    } else {
-        eprintln!("Payment succeeded with no concluding response from merchant");
+            // Don't bother the merchant with a payment the channel can't cover
+            check_balance(database, label, payment_amount).await?;
+
+            let started = Instant::now();
+            let (response_note, next) = chan
+                .choose::<1>()
+                .await
+                .context("Failed selecting next payment with merchant")?
+                .call(|chan| {
+                    make_payment(
+                        StdRng::from_entropy(),
+                        config,
+                        database,
+                        label,
+                        Transcript::new(&session_key.to_bytes()),
+                        chan,
+                        payment_amount,
+                        note.clone(),
+                        pay::PaymentId::generate(),
+                        |_| {},
+                    )
+                })
+                .await?;
+            chan = next.map_err(|_| anyhow::anyhow!("Pay session ended before it was complete"))?;
+            completed += 1;
+
+            println!(
+                "{}",
+                serde_json::to_string(&RepeatedPayment::new(
+                    iteration,
+                    label,
+                    payment_amount.to_i64(),
+                    response_note,
+                    started.elapsed(),
+                ))?
+            );
+        }
+
+        chan.choose::<0>()
+            .await
+            .context("Failed to end repeated pay session with merchant")?
+            .close();
+        Ok::<_, anyhow::Error>(())
     }
-
-    Ok(())
+    .await;
+
+    let summary = RepeatedPaymentsSummary::new(
+        label,
+        completed,
+        payment_amount.to_i64(),
+        result.as_ref().err().map(|e| format!("{:#}", e)),
+    );
+    println!("{}", serde_json::to_string(&summary)?);
+    result
 }
 
-/// The core zkAbacus.Pay protocol: receive a valid, updated channel state.
-async fn zkabacus_pay(
-    mut rng: StdRng,
+/// Resume the payment on the channel if its session was interrupted after it started, so that the
+/// channel is ready for the next payment, returning whether there was a payment to resume.
+///
+/// If the payment can't be resumed, the channel can only be closed.
+pub(crate) async fn resume_payment(
+    config: &Config,
     database: &dyn QueryCustomer,
     label: &ChannelName,
-    session_key: SessionKey,
-    chan: Chan<pay::CustomerStartPayment>,
-    payment_amount: PaymentAmount,
-) -> Result<Chan<pay::MerchantProvideService>, anyhow::Error> {
-    // Generate the shared context for proofs
-    let context = ProofContext::new(&session_key.to_bytes());
+) -> Result<bool, anyhow::Error> {
+    let channel = database
+        .get_channel(label)
+        .await
+        .with_context(|| format!("Failed to get channel details for {}", label))?;
+    let state = channel.state.state_name();
+    if !matches!(state, StateName::Started | StateName::Locked) {
+        return Ok(false);
+    }
+    let close_instead = || {
+        format!(
+            "the remaining option is to close the channel with `zkchannel customer close \"{}\"`",
+            label
+        )
+    };
 
-    // Start the zkAbacus core payment and get fresh proofs and commitments
-    let start_message = start_payment(&mut rng, database, label, payment_amount, context).await?;
+    // A payment which locked the channel can only be resumed with the revocation revealed then
+    let interrupted = match database.interrupted_payment(label).await? {
+        Some(InterruptedPayment { lock: None, .. }) if state == StateName::Locked => None,
+        interrupted => interrupted,
+    };
+    let InterruptedPayment {
+        payment_id,
+        start,
+        lock,
+    } = interrupted.ok_or_else(|| {
+        anyhow::anyhow!(
+            "Channel {} is in the middle of a payment which was not recorded fully enough to \
+            resume; {}",
+            label,
+            close_instead()
+        )
+    })?;
 
-    // Send the initial proofs and commitments to the merchant
-    let chan = chan
-        .send(start_message.nonce)
+    let (_, chan) = connect(config, &channel.address, Features::RESUME_PAYMENT)
         .await
-        .context("Failed to send nonce")?
-        .send(start_message.pay_proof)
+        .with_context(|| {
+            format!(
+                "Could not reach the merchant to resume the payment on channel {}; if the \
+                merchant stays unreachable, {}",
+                label,
+                close_instead()
+            )
+        })?;
+    let chan = chan
+        .choose::<4>()
         .await
-        .context("Failed to send payment proof")?;
+        .context("Failed selecting resume session with merchant")?;
 
-    // Allow the merchant to cancel the session at this point, and throw an error if so
-    offer_abort!(in chan as Customer);
-
-    // Receive a closing signature from the merchant
-    let (closing_signature, chan) = chan
-        .recv()
+    let result = async {
+        let chan = send_payment(
+            database,
+            label,
+            chan,
+            payment_id,
+            start,
+            lock,
+            config.payment_response_timeout,
+        )
         .await
-        .context("Failed to receive closing signature")?;
-
-    // Verify the closing signature and transition into a locked state
-    let chan = if let Some(lock_message) = lock_payment(database, label, closing_signature).await? {
-        proceed!(in chan);
+        .context("Failed to complete resumed pay protocol")?;
 
-        // If the closing signature verifies, reveal our lock, secret, and blinding factor
-        let chan = chan
-            .send(lock_message.revocation_pair)
-            .await
-            .context("Failed to send revocation pair")?
-            .send(lock_message.revocation_lock_blinding_factor)
+        // The merchant echoes the payment ID it recorded, which the resumed payment agrees with
+        receive_service(chan, None)
+            .with_timeout(config.approval_timeout)
             .await
-            .context("Failed to send revocation lock blinding factor")?;
-
-        // Allow the merchant to cancel the session at this point, and throw an error if so
-        offer_abort!(in chan as Customer);
-        chan
-    } else {
-        // If the closing signature does not verify, inform the merchant we are aborting
-        abort!(in chan return pay::Error::InvalidPayToken);
-    };
-
-    // Receive a pay token from the merchant, which allows us to pay again
-    let (pay_token, chan) = chan
-        .recv()
-        .await
-        .context("Failed to receive payment token")?;
-
-    // Unlock the payment channel using the pay token
-    unlock_payment(database, label, pay_token).await?;
-
-    Ok(chan)
+            .context("Resumed payment timed out when receiving service")?
+    }
+    .await;
+
+    record_outcome(database, label, payment_id, &result).await;
+    result.with_context(|| {
+        format!(
+            "Failed to resume the payment on channel {}; if it can't be resumed, {}",
+            label,
+            close_instead()
+        )
+    })?;
+    Ok(true)
 }
 
-/// Attempt to start the payment for the channel of the given label, using the given
-/// [`PaymentAmount`] and [`ProofContext`].
+/// Check that the channel's balance covers the payment before contacting the merchant.
 ///
-/// Returns the [`StartMessage`] for broadcast to the merchant if successful.
-async fn start_payment(
-    rng: &mut StdRng,
+/// zkAbacus still checks the payment when it is started, so a payment the balance no longer covers
+/// by then fails as it would without this check.
+pub(crate) async fn check_balance(
     database: &dyn QueryCustomer,
     label: &ChannelName,
     payment_amount: PaymentAmount,
-    context: ProofContext,
-) -> Result<StartMessage, anyhow::Error> {
-    let zkabacus_config = database.channel_zkabacus_config(label).await?;
-    // Try to start the payment. If successful, update channel status to `Started`.
-    database
-        .with_channel_state(label, zkchannels_state::Ready, |ready| {
-            // Try to start the payment using the payment amount and proof context
-            match ready.start(rng, payment_amount, &context, &zkabacus_config) {
-                Ok((started, start_message)) => Ok((State::Started(started), start_message)),
-                Err((_, e)) => Err(pay::Error::StartFailed(e)),
-            }
-        })
+) -> Result<(), anyhow::Error> {
+    let channel = database
+        .get_channel(label)
         .await
-        .with_context(|| format!("Failed to update channel {} to Started status", &label))?
-        .map_err(|e| e.into())
+        .with_context(|| format!("Failed to get channel details for {}", label))?;
+    customer::check_balance(
+        label,
+        payment_amount,
+        channel.state.customer_balance(),
+        channel.state.merchant_balance(),
+    )?;
+    Ok(())
 }
 
-/// Attempt to lock a started payment for the channel of the given label, using the given
-/// [`ClosingSignature`].
-///
-/// Returns the [`LockMessage`] for broadcast to the merchant if successful, or `None` if the
-/// database operations succeeded but the closing signature was invalid.
-async fn lock_payment(
+/// Check a payment against the limits the merchant advertises, so that a payment the merchant would
+/// refuse is caught before any session is opened for it.
+pub(crate) async fn check_policy(
+    config: &Config,
     database: &dyn QueryCustomer,
     label: &ChannelName,
-    closing_signature: ClosingSignature,
-) -> Result<Option<LockMessage>, anyhow::Error> {
-    let zkabacus_config = database.channel_zkabacus_config(label).await?;
-    // Try to continue (lock) the payment. If successful, update channel status to `Locked`.
-    database
-        .with_channel_state(label, zkchannels_state::Started, |started| {
-            // Attempt to lock the state using the closing signature. If it fails, raise a `pay::Error`.
-            match started.lock(closing_signature, &zkabacus_config) {
-                Ok((locked, lock_message)) => Ok((State::Locked(locked), lock_message)),
-                Err(_) => Err(pay::Error::InvalidClosingSignature),
-            }
-        })
+    payment_amount: PaymentAmount,
+    note: &str,
+) -> Result<(), anyhow::Error> {
+    let channel = database
+        .get_channel(label)
         .await
-        .map(Result::ok)
-        .with_context(|| format!("Failed to update channel {} to Locked status", &label))
+        .with_context(|| format!("Failed to get channel details for {}", label))?;
+    let (_, _, _, policy) = get_parameters(config, &channel.address)
+        .await
+        .context("Failed to get merchant's policy")?;
+    policy.check_payment(payment_amount.to_i64())?;
+    policy.check_note(note)?;
+    Ok(())
 }
 
-/// Attempt to unlock a locked payment for a channel of the given label, using the given
-/// [`PayToken`].
-///
-/// If successful, this updates the state in the database for the channel so that it is ready for
-/// the next payment.
-async fn unlock_payment(
+/// Ask the daemon to make a payment, printing its progress as it goes, and return the merchant's
+/// response note, if any.
+async fn proxy_payment(
+    config: &Config,
+    label: ChannelName,
+    payment_amount: PaymentAmount,
+    note: String,
+    payment_id: pay::PaymentId,
+) -> Result<Option<String>, anyhow::Error> {
+    let (_session_key, chan) = daemon::connect(config)
+        .await
+        .context("Failed to connect to daemon")?;
+    let chan = chan
+        .choose::<1>()
+        .await
+        .context("Failed to select daemon Pay")?;
+
+    let request = PayRequest {
+        label: label.clone(),
+        payment_amount,
+        note,
+        payment_id,
+    };
+    request_proxy_pay(
+        chan,
+        request,
+        |progress| tracing::info!(%label, "{}", progress),
+    )
+    .await
+}
+
+/// Set up the communication channel with the merchant.
+async fn open_session(
     database: &dyn QueryCustomer,
-    label: &ChannelName,
-    pay_token: PayToken,
-) -> Result<(), anyhow::Error> {
-    let zkabacus_config = database.channel_zkabacus_config(label).await?;
-    // Try to finish (unlock) the payment. If successful, update channel status to `Ready`.
-    database
-        .with_channel_state(label, zkchannels_state::Locked, |locked| {
-            // Attempt to unlock the state using the pay token
-            match locked.unlock(pay_token, &zkabacus_config) {
-                Ok(ready) => Ok((State::Ready(ready), ())),
-                Err(_) => Err(pay::Error::InvalidPayToken),
-            }
-        })
+    config: &Config,
+    channel_name: &ChannelName,
+) -> Result<(SessionKey, Chan<pay::Pay>), anyhow::Error> {
+    // Look up the address and current local customer state for this merchant in the database
+    let address = database
+        .channel_address(channel_name)
         .await
-        .with_context(|| format!("Failed to update channel {} to Ready status", &label))?
-        .map_err(|e| anyhow::anyhow!(e))
+        .context("Failed to look up channel address in local database")?;
+
+    // Connect and select the Pay session
+    let (session_key, chan) = connect(config, &address, Features::PAYMENT_IDS).await?;
+    let chan = chan
+        .choose::<2>()
+        .await
+        .context("Failed selecting pay session with merchant")?;
+
+    Ok((session_key, chan))
 }
 
 #[async_trait]
 impl Command for Refund {
-    async fn run(self, rng: StdRng, config: Config) -> Result<(), anyhow::Error> {
+    async fn run(
+        self,
+        rng: StdRng,
+        config: Config,
+        format: OutputFormat,
+    ) -> Result<(), anyhow::Error> {
         // A refund is merely a negative payment
-        self.into_negative_pay().run(rng, config).await
+        self.into_negative_pay().run(rng, config, format).await
+    }
+}
+
+#[async_trait]
+impl Command for Repair {
+    async fn run(
+        self,
+        _rng: StdRng,
+        config: Config,
+        format: OutputFormat,
+    ) -> Result<(), anyhow::Error> {
+        let database = database(&config)
+            .await
+            .context("Failed to connect to local database")?;
+        let label = resolve_label(
+            database.as_ref(),
+            self.label.as_ref(),
+            self.channel_id.as_deref(),
+            None,
+        )
+        .await?;
+
+        let resumed = resume_payment(&config, database.as_ref(), &label).await?;
+        match format {
+            OutputFormat::Json => println!(
+                "{}",
+                serde_json::json!({ "label": label, "resumed": resumed })
+            ),
+            OutputFormat::Human if resumed => {
+                println!("Resumed the interrupted payment on {}", label)
+            }
+            OutputFormat::Human => println!("{} is not in the middle of a payment", label),
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,152 @@
+//! Payments proxied through the customer daemon, which keeps connections to merchants warm so that
+//! interactive payments don't wait on the TCP, TLS, and session handshakes.
+
+use {
+    anyhow::Context,
+    rand::rngs::StdRng,
+    std::{collections::HashMap, future::Future, sync::Arc, time::Duration},
+    tokio::sync::Mutex,
+};
+
+use zeekoe::{
+    customer::{
+        client::{ConnectionCache, SessionKey, ZkChannelAddress},
+        database::QueryCustomer,
+        pay::make_payment,
+        server, Chan, ChannelName, Config,
+    },
+    protocol::{
+        self,
+        daemon::{PayProgress, PayRequest, ProxyPay},
+        pay,
+        version::Features,
+        Transcript,
+    },
+};
+
+use super::{
+    kept_session,
+    pay::{check_balance, resume_payment},
+};
+
+/// The daemon's connections to merchants, and the locks which keep payments on each channel from
+/// running concurrently.
+pub struct MerchantConnections {
+    config: Arc<Config>,
+    /// At most one idle connection per merchant, kept open after each payment for the next.
+    kept: ConnectionCache,
+    /// A lock per channel, keyed by the channel's name.
+    channels: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl MerchantConnections {
+    pub fn new(config: Arc<Config>) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            kept: ConnectionCache::default(),
+            channels: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn idle_timeout(&self) -> Duration {
+        self.config.daemon.idle_timeout
+    }
+
+    /// The lock which must be held while making a payment on the given channel.
+    async fn channel_lock(&self, label: &ChannelName) -> Arc<Mutex<()>> {
+        self.channels
+            .lock()
+            .await
+            .entry(label.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Run a Pay session with the merchant at the given address, over the connection kept open
+    /// from the last payment to it if there's a live one, or otherwise over a new connection,
+    /// telling the session which.
+    ///
+    /// If the session runs to completion, its connection is kept open for the next payment.
+    async fn pay_session<T, F, Fut>(
+        &self,
+        address: &ZkChannelAddress,
+        run_session: F,
+    ) -> Result<T, anyhow::Error>
+    where
+        F: FnOnce((SessionKey, Chan<pay::Pay>), bool) -> Fut,
+        Fut: Future<Output = Result<T, anyhow::Error>>,
+    {
+        kept_session(
+            &self.config,
+            address,
+            &self.kept,
+            self.idle_timeout(),
+            Features::PAYMENT_IDS,
+            |session_key, chan, reused| async move {
+                let chan = chan
+                    .choose::<2>()
+                    .await
+                    .context("Failed selecting pay session with merchant")?;
+                run_session((session_key, chan), reused).await
+            },
+        )
+        .await
+    }
+
+    /// Close any connections which have been idle longer than the idle timeout.
+    pub fn expire(&self) {
+        self.kept.expire(self.idle_timeout());
+    }
+}
+
+/// Make a payment requested by a client of the daemon, reporting progress back to the client.
+///
+/// Payments on the same channel are made one at a time, in the order they are requested.
+pub async fn serve_proxy_pay(
+    rng: StdRng,
+    config: &Config,
+    database: &dyn QueryCustomer,
+    connections: &MerchantConnections,
+    chan: server::Chan<ProxyPay>,
+) -> Result<(), anyhow::Error> {
+    protocol::daemon::serve_proxy_pay(chan, |request, progress| async move {
+        let PayRequest {
+            label,
+            payment_amount,
+            note,
+            payment_id,
+        } = request;
+        let channel_lock = connections.channel_lock(&label).await;
+        let _channel_guard = channel_lock.lock().await;
+
+        if resume_payment(config, database, &label).await? {
+            tracing::info!(%label, "Resumed interrupted payment");
+        }
+        check_balance(database, &label, payment_amount).await?;
+        let address = database
+            .channel_address(&label)
+            .await
+            .context("Failed to look up channel address in local database")?;
+
+        connections
+            .pay_session(&address, |(session_key, chan), reused| {
+                let _ = progress.send(PayProgress::Connected { reused });
+                make_payment(
+                    rng,
+                    config,
+                    database,
+                    &label,
+                    Transcript::new(&session_key.to_bytes()),
+                    chan,
+                    payment_amount,
+                    note,
+                    payment_id,
+                    |paid| {
+                        let _ = progress.send(paid);
+                    },
+                )
+            })
+            .await
+    })
+    .await
+}
@@ -1,220 +1,932 @@
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 use {
-    anyhow::Context, async_trait::async_trait, rand::rngs::StdRng, std::sync::Arc, tokio::signal,
+    anyhow::Context,
+    async_trait::async_trait,
+    comfy_table::{Cell, Table},
+    dialectic::offer,
+    futures::future,
+    rand::rngs::StdRng,
+    std::{
+        collections::BTreeMap,
+        convert::TryInto,
+        net::{IpAddr, Ipv4Addr},
+        sync::Arc,
+    },
+    tokio::{
+        signal,
+        sync::{broadcast::error::RecvError, mpsc, Semaphore},
+    },
 };
 
 use zeekoe::{
-    customer::database::zkchannels_state::{self, ZkChannelState},
+    amount::{Amount, XTZ},
+    build_info::BuildInfo,
     customer::{
-        cli::Watch,
-        database::{ChannelDetails, QueryCustomer},
-        Config,
+        cli::{self, Watch},
+        config::DaemonAddress,
+        daemon::{self, channel_summaries, serve_trigger_close, DaemonLock},
+        database::{ChannelDetails, QueryCustomer, QueryCustomerExt, StateName},
+        notify::{NotificationEvent, NotificationEventType, Notifications},
+        server,
+        watch::{
+            dispatch_all, key_for_action, plan_reaction, ChannelDispatcher, KeyAccess,
+            KeyUnavailable, Reaction,
+        },
+        Config, Server,
+    },
+    escrow::{
+        notify::{ContractEvent, ContractNotifier},
+        tezos,
+        types::{ContractId, ContractStatus, Entrypoint},
+    },
+    protocol::{
+        close::{
+            closed_contract_ending, customer_close_ending, ClosedContractEnding,
+            CustomerCloseEnding,
+        },
+        daemon::{Daemon, DaemonToken},
     },
-    escrow::types::ContractStatus,
 };
 
-use super::{close, database, load_tezos_client, Command, TezosClientError};
+use super::{
+    close, database, establish,
+    proxy::{serve_proxy_pay, MerchantConnections},
+    Command, OutputFormat,
+};
 
-const MAX_INTERVAL_SECONDS: u64 = 60;
+/// How often to check the Tezos node for new blocks.
+const BLOCK_POLL_SECONDS: u64 = 5;
 
 #[async_trait]
 impl Command for Watch {
-    async fn run(self, rng: StdRng, config: Config) -> Result<(), anyhow::Error> {
+    async fn run(
+        self,
+        mut rng: StdRng,
+        config: Config,
+        _format: OutputFormat,
+    ) -> Result<(), anyhow::Error> {
+        tracing::info!(
+            "{}",
+            BuildInfo::collect(Some(&config.database_location()?)).await
+        );
+
+        // Only one daemon may react to the channels in a database, so that operations aren't
+        // posted twice; the lock is released when the daemon stops
+        let _lock = config
+            .daemon_lock_file()?
+            .map(|path| DaemonLock::acquire(&path))
+            .transpose()?;
+
         let database = database(&config)
             .await
             .context("Customer chain-watching daemon failed to connect to local database")?;
 
         let config = Arc::new(config);
 
-        // Make sure Tezos keys are accessible from disk
-        let key_load_test = config.load_tezos_key_material()?;
-        drop(key_load_test);
+        // The Tezos key is only loaded when an operation must be posted, so that it needn't live on
+        // the machine running the daemon, and never if the daemon is only watching
+        let key_access = if self.watch_only {
+            KeyAccess::WatchOnly
+        } else {
+            KeyAccess::OnDemand
+        };
 
-        /*
-        // Note: commenting out the server setup because we will not use it with the polling
-        // architecture; we don't expect any incoming requests.
+        // Make sure the configured Tezos node is on the network we think it is
+        tezos::check_network(&config.tezos_network()?).await?;
 
-        // Sender and receiver to indicate graceful shutdown should occur
-        let (terminate, _) = broadcast::channel(1);
-        let mut wait_terminate = terminate.subscribe();
+        // Issue a fresh token which clients must present to control this daemon
+        let token_file = config.daemon_token_file()?;
+        let token = DaemonToken::new(&mut rng);
+        token
+            .write(&token_file)
+            .with_context(|| format!("Could not write daemon token to {:?}", token_file))?;
 
-        // Initialize a new `Server` with parameters taken from the configuration
-        let server: Server<Daemon> = Server::new();
+        // Serve the control protocol, through which clients can query the daemon, ask it to act on
+        // a channel right away, and proxy payments through it
+        let control_service = serve_control(
+            rng.clone(),
+            config.clone(),
+            database.clone(),
+            token,
+            self.off_chain,
+            key_access,
+        );
 
-        // Serve on this address
-        let localhost_v4 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
-        let address = (localhost_v4, config.daemon_port);
+        let mut interval = tokio::time::interval(config.chain_polling_interval());
 
-        // There is no meaningful initialization necessary per request
-        let initialize = || async { Some(()) };
+        // Only one sweep over the channels runs at a time, so that a slow node doesn't cause
+        // sweeps to pile up
+        let sweep_permit = Arc::new(Semaphore::new(1));
 
-        // For each request, dispatch to the appropriate method, defined elsewhere
-        let interact = move |_session_key, (), _chan: Chan<Daemon>| {
-            // Clone `Arc`s for the various resources we need in this request
-            //let _database = database.clone();
+        // Channels report back the next time they will need attention, if that is known, so
+        // that the daemon can wake up right then rather than waiting for the next interval
+        let (next_action_sender, mut next_action_receiver) = mpsc::unbounded_channel();
 
-            async move {
-                offer!(in _chan {
-                    // Refresh
-                    0 => {
-                        println!("refreshed");
-                        Ok::<_, anyhow::Error>(())
-                    }
-                })?
-            }
+        // Channels which couldn't be dispatched are retried on the next pass, even if their
+        // contracts haven't changed
+        let (retry_sender, mut retry_receiver) = mpsc::unbounded_channel();
+
+        // Watch the chain for changes to channels' contracts, so that each channel only needs to be
+        // checked when its contract changes, rather than at every interval
+        let notifier = Arc::new(ContractNotifier::tezos(
+            config.tezos_network()?.uri(),
+            Vec::new(),
+        ));
+        let mut contract_events = notifier.subscribe();
+        let mut notifier_join_handle = {
+            let notifier = notifier.clone();
+            tokio::spawn(async move { notifier.run(Duration::from_secs(BLOCK_POLL_SECONDS)).await })
         };
-        */
 
-        // In production, the self_delay should be long (at least 48h) so this will always end up
-        // being 60s. In development, you may see lower values to allow for quicker testing.
-        let interval_seconds = std::cmp::min(config.self_delay / 2, MAX_INTERVAL_SECONDS);
-        let mut interval = tokio::time::interval(Duration::from_secs(interval_seconds));
+        // Everything needed to dispatch on a channel, shared by every pass
+        let dispatcher = Arc::new(Dispatcher {
+            rng: rng.clone(),
+            config: config.clone(),
+            database: database.clone(),
+            off_chain: self.off_chain,
+            key_access,
+            notifications: Notifications::new(&config),
+        });
 
         // Run the polling service
         let polling_service_join_handle = tokio::spawn(async move {
+            let mut next_action: Option<SystemTime> = None;
+
+            // Whether every channel should be checked on the next pass, rather than only those
+            // whose contracts have changed or haven't been checked yet
+            let mut check_all = true;
+
+            // If the notifier fails, go back to checking every channel at every interval
+            let mut notifier_failed = false;
+
+            let mut checked: Vec<ContractId> = Vec::new();
+            let mut changed: Vec<ContractId> = Vec::new();
+
+            // The entrypoints whose calls closed contracts, as seen by the notifier, so that a
+            // dispute can be told apart from a claim
+            let mut closed_by: Vec<(ContractId, Entrypoint)> = Vec::new();
+
+            // Catch up on whatever happened on chain while the daemon was stopped before reacting
+            // to anything new, checking every channel once; the loop then only checks channels
+            // again when their contracts change
+            match database
+                .get_channels()
+                .await
+                .context("Failed to retrieve channels to catch up on")
+            {
+                Ok(channels) => {
+                    let contract_ids = channels
+                        .iter()
+                        .filter_map(|channel| channel.contract_details.contract_id.clone());
+                    notifier.set_contracts(contract_ids).await;
+
+                    let started = Instant::now();
+                    let selected: Vec<_> = channels
+                        .into_iter()
+                        .map(|channel| (channel, None))
+                        .collect();
+                    let results =
+                        dispatch_all(dispatcher.as_ref(), &selected, config.max_concurrent_polls)
+                            .await;
+
+                    let total = selected.len();
+                    let mut failures = 0;
+                    for ((channel, _), result) in selected.into_iter().zip(results) {
+                        match result {
+                            Ok(Some(channel_next_action)) => {
+                                let _ = next_action_sender.send(channel_next_action);
+                            }
+                            Ok(None) => {}
+                            Err(e) => {
+                                failures += 1;
+                                tracing::error!(
+                                    label = %channel.label,
+                                    contract_id = ?channel.contract_details.contract_id,
+                                    error = %e,
+                                    "Error catching up"
+                                );
+                                if let Some(contract_id) = &channel.contract_details.contract_id {
+                                    let _ = retry_sender.send(contract_id.clone());
+                                }
+                            }
+                        }
+                        if let Some(contract_id) = channel.contract_details.contract_id {
+                            checked.push(contract_id);
+                        }
+                    }
+                    tracing::info!(
+                        elapsed = ?started.elapsed(),
+                        channels = total,
+                        failures,
+                        "Caught up on chain events"
+                    );
+                    check_all = false;
+                }
+                Err(e) => tracing::error!(
+                    error = %format!("{:#}", e),
+                    "Catching up failed; checking every channel on the first pass instead"
+                ),
+            }
+
             loop {
-                // Retrieve list of channels from database
-                let channels = match database
-                    .get_channels()
-                    .await
-                    .context("Failed to retrieve contract IDs")
-                {
-                    Ok(channels) => channels,
-                    Err(e) => return Err::<(), anyhow::Error>(e),
-                };
-
-                // Query each contract ID and dispatch on the result
-                for channel in channels {
-                    let database = database.clone();
-                    let config = config.clone();
-                    let mut rng = rng.clone();
-                    let off_chain = self.off_chain;
-                    tokio::spawn(async move {
-                        match dispatch_channel(
-                            &mut rng,
-                            &config,
-                            database.as_ref(),
-                            &channel,
-                            off_chain,
-                        )
+                while let Ok(contract_id) = retry_receiver.try_recv() {
+                    changed.push(contract_id);
+                }
+
+                // Skip this pass if the previous sweep is still running, rather than stacking
+                // sweeps; the contracts this pass would have checked are checked on a later one
+                match sweep_permit.clone().try_acquire_owned() {
+                    Err(_) => tracing::warn!("Previous sweep is still running; skipping this pass"),
+                    // Retrieve list of channels from database; if that fails, try again on the
+                    // next pass, remembering which contracts still need to be checked
+                    Ok(permit) => match database
+                        .get_channels()
                         .await
-                        {
-                            Ok(()) => eprintln!("Successfully dispatched {}", &channel.label),
-                            Err(e) => eprintln!("Error dispatching on {}: {}", &channel.label, e),
+                        .context("Failed to retrieve contract IDs")
+                    {
+                        Ok(channels) => {
+                            let contract_ids = channels
+                                .iter()
+                                .filter_map(|channel| channel.contract_details.contract_id.clone());
+                            notifier.set_contracts(contract_ids).await;
+
+                            // Forget the entrypoint which closed a contract once its channel is
+                            // closed, or gone, since there is nothing left to finalize
+                            closed_by.retain(|(contract_id, _)| {
+                                channels.iter().any(|channel| {
+                                    channel.contract_details.contract_id.as_ref()
+                                        == Some(contract_id)
+                                        && channel.state.state_name() != StateName::Closed
+                                })
+                            });
+
+                            // Select each channel whose contract needs checking; channels without a
+                            // contract are always dispatched on, since that doesn't touch the chain
+                            let mut selected = Vec::new();
+                            for channel in channels {
+                                if let Some(contract_id) = &channel.contract_details.contract_id {
+                                    let needs_check = check_all
+                                        || notifier_failed
+                                        || !checked.contains(contract_id)
+                                        || changed.contains(contract_id);
+                                    if !needs_check {
+                                        continue;
+                                    }
+                                    if !checked.contains(contract_id) {
+                                        checked.push(contract_id.clone());
+                                    }
+                                }
+
+                                let channel_closed_by = closed_by
+                                    .iter()
+                                    .find(|(contract_id, _)| {
+                                        channel.contract_details.contract_id.as_ref()
+                                            == Some(contract_id)
+                                    })
+                                    .map(|(_, entrypoint)| *entrypoint);
+                                selected.push((channel, channel_closed_by));
+                            }
+
+                            // Dispatch on the selected channels in the background, so that contract
+                            // events keep being received meanwhile
+                            let dispatcher = dispatcher.clone();
+                            let next_action_sender = next_action_sender.clone();
+                            let retry_sender = retry_sender.clone();
+                            let max_concurrent_polls = config.max_concurrent_polls;
+                            tokio::spawn(async move {
+                                let started = Instant::now();
+                                let results = dispatch_all(
+                                    dispatcher.as_ref(),
+                                    &selected,
+                                    max_concurrent_polls,
+                                )
+                                .await;
+
+                                // Count the channels checked in this sweep by status, for the log
+                                let total = selected.len();
+                                let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+                                let mut failures = 0;
+                                for ((channel, _), result) in selected.into_iter().zip(results) {
+                                    *counts
+                                        .entry(channel.state.state_name().to_string())
+                                        .or_default() += 1;
+                                    match result {
+                                        Ok(channel_next_action) => {
+                                            tracing::debug!(
+                                                label = %channel.label,
+                                                "Successfully dispatched"
+                                            );
+                                            if let Some(channel_next_action) = channel_next_action {
+                                                // The receiver only goes away when the daemon stops
+                                                let _ =
+                                                    next_action_sender.send(channel_next_action);
+                                            }
+                                        }
+                                        Err(e) => {
+                                            failures += 1;
+                                            tracing::error!(
+                                                label = %channel.label,
+                                                contract_id = ?channel.contract_details.contract_id,
+                                                error = %e,
+                                                "Error dispatching"
+                                            );
+                                            if let Some(contract_id) =
+                                                channel.contract_details.contract_id
+                                            {
+                                                let _ = retry_sender.send(contract_id);
+                                            }
+                                        }
+                                    }
+                                }
+
+                                let counts = counts
+                                    .iter()
+                                    .map(|(status, count)| format!("{} {}", count, status))
+                                    .collect::<Vec<_>>();
+                                tracing::info!(
+                                    elapsed = ?started.elapsed(),
+                                    channels = total,
+                                    states = %counts.join(", "),
+                                    failures,
+                                    "Sweep finished"
+                                );
+
+                                // The next sweep can start now
+                                drop(permit);
+                            });
+
+                            check_all = false;
+                            changed.clear();
                         }
+                        Err(e) => tracing::error!(
+                            error = %format!("{:#}", e),
+                            "Sweep failed; retrying on the next pass"
+                        ),
+                    },
+                }
+
+                // Reclaim customer funds from channels whose establishment stalled before the
+                // merchant funded them
+                if let Some(timeout) = config.stalled_establish_timeout {
+                    let stalled_channels = match database
+                        .get_stalled_establishments(SystemTime::now() - timeout)
+                        .await
+                        .context("Failed to retrieve stalled channels")
+                    {
+                        Ok(stalled_channels) => stalled_channels,
+                        Err(e) => {
+                            tracing::error!(
+                                error = %format!("{:#}", e),
+                                "Reclaiming stalled channels failed; retrying on the next pass"
+                            );
+                            Vec::new()
+                        }
+                    };
+
+                    for channel in stalled_channels {
+                        let database = database.clone();
+                        let config = config.clone();
+                        tokio::spawn(async move {
+                            tracing::warn!(
+                                label = %channel.label,
+                                "Establishment of channel stalled before the merchant funded it; \
+                                reclaiming customer funds"
+                            );
+                            match establish::reclaim_funding(
+                                &config,
+                                database.as_ref(),
+                                &channel.label,
+                            )
+                            .await
+                            {
+                                Ok(()) => tracing::info!(label = %channel.label, "Reclaimed funds"),
+                                Err(e) => tracing::error!(
+                                    label = %channel.label,
+                                    error = %format!("{:#}", e),
+                                    "Error reclaiming funds"
+                                ),
+                            }
+                        });
+                    }
+                }
+
+                // Wait for the next interval, for a contract to change, or for the earliest channel
+                // action, if it's sooner
+                loop {
+                    let until_next_action = next_action.map(|next_action| {
+                        next_action
+                            .duration_since(SystemTime::now())
+                            .unwrap_or_default()
                     });
+
+                    tokio::select! {
+                        _ = interval.tick() => break,
+                        Some(channel_next_action) = next_action_receiver.recv() => {
+                            next_action = Some(match next_action {
+                                Some(next_action) => next_action.min(channel_next_action),
+                                None => channel_next_action,
+                            });
+                        }
+                        _ = tokio::time::sleep(until_next_action.unwrap_or_default()),
+                            if until_next_action.is_some() =>
+                        {
+                            next_action = None;
+                            check_all = true;
+                            break;
+                        }
+                        event = contract_events.recv(), if !notifier_failed => {
+                            match event {
+                                Ok(ContractEvent { contract_id, new_status, entrypoint, .. }) => {
+                                    tracing::info!(
+                                        %contract_id,
+                                        status = ?new_status,
+                                        entrypoint = ?entrypoint,
+                                        "Contract changed status"
+                                    );
+                                    if let (ContractStatus::Closed, Some(entrypoint)) =
+                                        (new_status, entrypoint)
+                                    {
+                                        closed_by.push((contract_id.clone(), entrypoint));
+                                    }
+                                    changed.push(contract_id);
+                                }
+                                // Some changes were missed, so they could be to any contract
+                                Err(RecvError::Lagged(_)) => check_all = true,
+                                Err(RecvError::Closed) => notifier_failed = true,
+                            }
+                            break;
+                        }
+                        result = &mut notifier_join_handle, if !notifier_failed => {
+                            let error = match result {
+                                Ok(result) => result.err().map(anyhow::Error::from),
+                                Err(e) => Some(e.into()),
+                            };
+                            if let Some(error) = error {
+                                tracing::warn!(
+                                    %error,
+                                    "Contract notifier failed, falling back to polling"
+                                );
+                            }
+                            notifier_failed = true;
+                            break;
+                        }
+                    }
                 }
-                interval.tick().await;
             }
         });
 
         tokio::select! {
             _ = signal::ctrl_c() => {
-                eprintln!("Terminated by user");
+                tracing::info!("Terminated by user");
                 Ok(())
             },
-            result = polling_service_join_handle => result?,
+            // The polling service never stops on its own, unless it panics
+            result = polling_service_join_handle => result.context("Polling service failed"),
+            result = control_service => result,
         }
+    }
+}
 
-        /*
-        // Note: We do not run the server in the polling architecture because we do not expect any
-        // incoming requests.
+/// Serve the daemon's control protocol on localhost or a Unix socket, through which clients can ask
+/// the daemon for the status of every channel, to close a channel right away, or to make payments
+/// on their behalf.
+async fn serve_control(
+    rng: StdRng,
+    config: Arc<Config>,
+    database: Arc<dyn QueryCustomer>,
+    token: DaemonToken,
+    off_chain: bool,
+    key_access: KeyAccess,
+) -> Result<(), anyhow::Error> {
+    // Serve on localhost or a Unix socket only: the daemon is controlled by clients on this
+    // machine
+    let address = match config.daemon_address()? {
+        DaemonAddress::Port(port) => {
+            server::ListenAddress::from((IpAddr::V4(Ipv4Addr::LOCALHOST), port))
+        }
+        DaemonAddress::Socket(path) => server::ListenAddress::Unix(path),
+    };
+    let identity = config.daemon_identity()?;
+    let connections = MerchantConnections::new(config.clone());
+
+    // Periodically close connections to merchants that have been idle too long
+    let expire_connections = connections.clone();
+    let idle_timeout = config.daemon.idle_timeout.max(Duration::from_secs(1));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(idle_timeout);
+        loop {
+            interval.tick().await;
+            expire_connections.expire();
+        }
+    });
 
-        // Future that completes on graceful shutdown
-        let wait_terminate = async move { wait_terminate.recv().await.unwrap_or(()) };
+    // Initialize a new `Server` with parameters taken from the configuration
+    let mut server: Server<Daemon> = Server::new();
+    server.max_length(config.max_message_length);
 
-        server
-            .serve_while(address, None, initialize, interact, wait_terminate)
-            .await?;
-        */
+    // There is no meaningful initialization necessary per request
+    let initialize = || async { Some(()) };
+
+    // For each request, dispatch to the appropriate method, defined elsewhere
+    let interact = move |_session_key, (), chan: server::Chan<Daemon>| {
+        // Clone the various resources we need in this request
+        let rng = rng.clone();
+        let config = config.clone();
+        let database = database.clone();
+        let connections = connections.clone();
+        let token = token.clone();
+
+        async move {
+            // Identify which database we're serving, then check the client's token
+            let chan = chan
+                .send(identity)
+                .await
+                .context("Failed to send daemon identity")?;
+            let (client_token, chan) = chan
+                .recv()
+                .await
+                .context("Failed to receive daemon token")?;
+            if client_token != token {
+                return Err(anyhow::anyhow!("Client presented an invalid daemon token"));
+            }
+
+            offer!(in chan {
+                // Refresh: the polling service finds new channels on its own
+                0 => chan.close(),
+                // Pay
+                1 => serve_proxy_pay(
+                    rng,
+                    &config,
+                    database.as_ref(),
+                    &connections,
+                    chan,
+                ).await?,
+                // Status
+                2 => chan
+                    .send(channel_summaries(database.as_ref()).await?)
+                    .await
+                    .context("Failed to send channel summaries")?
+                    .close(),
+                // Trigger close: dispatch on the channel now, as the polling service would
+                3 => serve_trigger_close(chan, |label| async move {
+                    let mut rng = rng;
+                    let channel = database.get_channel(&label).await?;
+                    dispatch_channel(
+                        &mut rng,
+                        &config,
+                        database.as_ref(),
+                        &channel,
+                        None,
+                        off_chain,
+                        key_access,
+                        &Notifications::new(&config),
+                    )
+                    .await
+                }).await?,
+            })?;
+            Ok::<_, anyhow::Error>(())
+        }
+    };
+
+    // The control server runs for as long as the daemon does
+    server
+        .serve_while(address, None, initialize, interact, future::pending())
+        .await?;
+
+    Ok(())
+}
+
+#[async_trait]
+impl Command for cli::Daemon {
+    async fn run(
+        self,
+        _rng: StdRng,
+        config: Config,
+        format: OutputFormat,
+    ) -> Result<(), anyhow::Error> {
+        match self.command {
+            cli::DaemonCommand::Status => {
+                let summaries = daemon::status(&config).await?;
+                match format {
+                    OutputFormat::Json => println!("{}", serde_json::to_string(&summaries)?),
+                    OutputFormat::Human => {
+                        // TODO: don't hard-code XTZ here, instead store currency in database
+                        let amount = |b: u64| {
+                            Amount::from_minor_units_of_currency(b.try_into().unwrap(), XTZ)
+                        };
+
+                        let mut table = Table::new();
+                        table.load_preset(comfy_table::presets::UTF8_FULL);
+                        table.set_header(vec![
+                            "Label",
+                            "State",
+                            "Balance",
+                            "Max Refund",
+                            "Contract ID",
+                            "Contract Status",
+                            "Last Checked",
+                            "In Flight",
+                        ]);
+
+                        for summary in summaries {
+                            let (contract_status, last_checked) = match summary.last_observation {
+                                Some(observation) => (
+                                    match (observation.reaction, observation.required_action) {
+                                        (Some(reaction), _) => format!(
+                                            "{:?} since level {} (posted {})",
+                                            observation.status, observation.level, reaction
+                                        ),
+                                        (None, Some(action)) => format!(
+                                            "{:?} since level {} (needs {})",
+                                            observation.status, observation.level, action
+                                        ),
+                                        (None, None) => format!(
+                                            "{:?} since level {}",
+                                            observation.status, observation.level
+                                        ),
+                                    },
+                                    humantime::format_rfc3339_seconds(observation.observed_at)
+                                        .to_string(),
+                                ),
+                                None => ("N/A".to_string(), "never".to_string()),
+                            };
+                            let in_flight = summary
+                                .operations_in_flight
+                                .iter()
+                                .map(|entrypoint| entrypoint.to_string())
+                                .collect::<Vec<_>>()
+                                .join(", ");
+
+                            table.add_row(vec![
+                                Cell::new(summary.label),
+                                Cell::new(summary.state),
+                                Cell::new(amount(summary.customer_balance.into_inner())),
+                                Cell::new(amount(summary.merchant_balance.into_inner())),
+                                Cell::new(summary.contract_id.map_or_else(
+                                    || "N/A".to_string(),
+                                    |contract_id| format!("{}", contract_id),
+                                )),
+                                Cell::new(contract_status),
+                                Cell::new(last_checked),
+                                Cell::new(in_flight),
+                            ]);
+                        }
+
+                        println!("{}", table);
+                    }
+                }
+            }
+            cli::DaemonCommand::TriggerClose { label } => {
+                match daemon::trigger_close(&config, label.clone()).await? {
+                    Some(next_action) => eprintln!(
+                        "Checked {}; it next needs attention at {}",
+                        label,
+                        humantime::format_rfc3339_seconds(next_action)
+                    ),
+                    None => eprintln!("Checked {}", label),
+                }
+            }
+        }
+        Ok(())
     }
 }
 
+/// Dispatches on channels from the polling service, each along with the entrypoint whose call
+/// closed its contract, if that was seen.
+struct Dispatcher {
+    rng: StdRng,
+    config: Arc<Config>,
+    database: Arc<dyn QueryCustomer>,
+    off_chain: bool,
+    key_access: KeyAccess,
+    notifications: Notifications,
+}
+
+#[async_trait]
+impl ChannelDispatcher for Dispatcher {
+    type Channel = (ChannelDetails, Option<Entrypoint>);
+
+    async fn dispatch(&self, channel: &Self::Channel) -> Result<Option<SystemTime>, anyhow::Error> {
+        let (channel, closed_by) = channel;
+        dispatch_channel(
+            &mut self.rng.clone(),
+            &self.config,
+            self.database.as_ref(),
+            channel,
+            *closed_by,
+            self.off_chain,
+            self.key_access,
+            &self.notifications,
+        )
+        .await
+    }
+}
+
+/// Check the on-chain state of a channel and take any action required by it. If the channel's
+/// contract was seen to be closed, `closed_by` is the entrypoint whose call closed it.
+///
+/// Returns the time at which the channel will next need attention, if it is waiting on a known
+/// deadline.
+///
+/// The Tezos key is only loaded, if `key_access` allows it, when an operation must be posted.
+/// Each change to the channel is passed on to the customer's notification command, if any.
+#[allow(clippy::too_many_arguments)]
 async fn dispatch_channel(
     rng: &mut StdRng,
     config: &Config,
     database: &dyn QueryCustomer,
     channel: &ChannelDetails,
+    closed_by: Option<Entrypoint>,
     off_chain: bool,
-) -> Result<(), anyhow::Error> {
-    let tezos_client = match load_tezos_client(config, &channel.label, database).await {
-        Ok(tezos_client) => tezos_client,
-        Err(TezosClientError::ContractDetailsNotSet(_)) => return Ok(()),
-        error => error?,
+    key_access: KeyAccess,
+    notifications: &Notifications,
+) -> Result<Option<SystemTime>, anyhow::Error> {
+    let contract_id = match &channel.contract_details.contract_id {
+        Some(contract_id) => contract_id,
+        None => return Ok(None),
     };
-    let contract_state = tezos_client.get_contract_state().await?;
-
-    // The channel has not reacted to an expiry transaction being posted
-    // The condition is
-    // - the contract is in Expiry state
-    // - the local state is neither PendingClose nor PendingExpiry
-    if contract_state.status()? == ContractStatus::Expiry
-        && !(zkchannels_state::PendingClose.matches(&channel.state)
-            || zkchannels_state::PendingExpiry.matches(&channel.state))
-    {
-        // TODO: this should wait for any payments to complete.
-
-        close::unilateral_close(
-            &channel.label,
-            config,
-            off_chain,
-            rng,
-            database,
-            close::UnilateralCloseKind::MerchantInitiated,
-        )
-        .await
-        .context("Chain watcher failed to process contract in expiry state")?;
-    }
+    let contract_state = tezos::get_contract_state(
+        config.tezos_network()?.uri(),
+        contract_id,
+        config.confirmation_depth,
+    )
+    .await?;
 
-    // The channel has not claimed funds after custClose timeout expired
-    // The condition is:
-    // - the contract is in the CustomerClose state
-    // - the timeout has been set and expired
-    // - the local state is PendingClose (customer did not yet try to claim funds)
-    if contract_state.status()? == ContractStatus::CustomerClose
-        && contract_state.timeout_expired().unwrap_or(false)
-        && zkchannels_state::PendingClose.matches(&channel.state)
-    {
-        close::claim_funds(database, config, &channel.label)
-            .await
-            .context("Chain watcher failed to claim funds")?;
+    // Read the contract's status and timeout once, so that every check below sees the same answer,
+    // even if the timeout expires while the channel is being dispatched on
+    let status = contract_state.status()?;
+    let timeout_expired = contract_state.timeout_expired();
 
-        // Developer note: if we separate the logic so that this is not always called immediately
-        // after `close::claim_funds()`, make sure it is still called in the case where the
-        // customer has 0 funds and does not actually post a claim operation
-        close::finalize_customer_claim(database, &channel.label)
-            .await
-            .context("Chain watcher failed to finalized claimed funds")?;
-    }
+    // Record what was seen, so that each status is only reacted to once, even if a reaction
+    // partially succeeds and the same status is seen again on later passes
+    let level = tezos::head_level(config.tezos_network()?.uri()).await?;
+    let previous_status = database
+        .contract_observation(&channel.label)
+        .await?
+        .map(|observation| observation.status);
+    let observation = database
+        .observe_contract(&channel.label, status, level)
+        .await?;
 
-    // The channel has not reacted to a merchDispute transaction being posted
-    // The condition is:
-    // - the contract is Closed
-    // - the local state is PendingClose
-    if contract_state.status()? == ContractStatus::Closed
-        && zkchannels_state::PendingClose.matches(&channel.state)
-    {
-        close::process_dispute(database, &channel.label)
-            .await
-            .context("Chain watcher failed to process disputed contract")?;
-        close::finalize_dispute(database, &channel.label)
-            .await
-            .context("Chain watcher failed to process finalized disputed contract")?;
+    // Decide what the channel needs from its state and the contract's status alone, so that a
+    // contract which moved through several statuses since it was last checked is still handled
+    let reaction = plan_reaction(channel.state.state_name(), status, timeout_expired);
+
+    // The operation the channel needs posted, if any: custClose in reaction to an expiry, or
+    // custClaim once the custClose timeout has expired. Only now is the Tezos key needed.
+    let action = reaction.operation(channel.awaiting_broadcast);
+    if let Some(action) = action {
+        if observation.reaction.is_none() {
+            let key = key_for_action(key_access, &channel.label, action, || {
+                config.load_tezos_signer()
+            })
+            .await;
+            match key {
+                Ok(_) => {}
+                Err(unavailable @ KeyUnavailable::WatchOnly { .. }) => {
+                    // Leave the operation for the user, and only say so once per status
+                    if observation.required_action != Some(action) {
+                        database
+                            .record_required_action(&channel.label, status, action)
+                            .await?;
+                        tracing::warn!(
+                            label = %channel.label,
+                            %action,
+                            "{}",
+                            unavailable
+                        );
+                        notifications.notify(NotificationEvent::action_required(
+                            &channel.label,
+                            Some(contract_id),
+                            action,
+                        ));
+                    }
+                    return Ok(None);
+                }
+                Err(unavailable) => return Err(unavailable.into()),
+            }
+        }
     }
 
-    // The channel has not reacted to a merchClaim transaction being posted
-    // The condition is:
-    // - the contract is Closed
-    // - the local state is PendingExpiry (the customer did not post corrected balances after
-    //   the merchant posted expiry)
-    if contract_state.status()? == ContractStatus::Closed
-        && zkchannels_state::PendingExpiry.matches(&channel.state)
-    {
-        close::finalize_expiry(database, &channel.label)
-            .await
-            .context("Chain watcher failed to process expired contract")?;
+    let notify = |event| {
+        notifications.notify(NotificationEvent::new(
+            event,
+            &channel.label,
+            Some(contract_id),
+        ));
+    };
+
+    match reaction {
+        Reaction::Nothing => {}
+
+        // The channel has not reacted to an expiry transaction being posted
+        Reaction::AnswerExpiry => {
+            // TODO: this should wait for any payments to complete.
+
+            let output = close::CloseOutput::default();
+            let result = database
+                .react_once(
+                    &channel.label,
+                    &observation,
+                    Entrypoint::CustomerClose,
+                    || {
+                        close::unilateral_close(
+                            &channel.label,
+                            config,
+                            off_chain,
+                            &output,
+                            rng,
+                            database,
+                            close::UnilateralCloseKind::MerchantInitiated,
+                        )
+                    },
+                )
+                .await?
+                .context("Chain watcher failed to process contract in expiry state")?;
+            if let Some(result) = result {
+                notify(NotificationEventType::ExpiryPosted);
+                if let Some(close_json) = result.close_json {
+                    close::report_close_json(&close_json);
+                }
+            }
+        }
+
+        // The channel is waiting for the custClose timeout to expire before it can claim funds
+        Reaction::AwaitTimeout => return Ok(contract_state.delay_expiry()),
+
+        // The channel has not claimed funds after custClose timeout expired, and the custClose
+        // operation, if produced off chain, has been confirmed by the customer
+        Reaction::Claim if action.is_some() => {
+            let claimed = database
+                .react_once(
+                    &channel.label,
+                    &observation,
+                    Entrypoint::CustomerClaim,
+                    || close::claim_funds(database, config, &channel.label),
+                )
+                .await?
+                .context("Chain watcher failed to claim funds")?
+                .is_some();
+
+            // Developer note: if we separate the logic so that this is not always called
+            // immediately after `close::claim_funds()`, make sure it is still called in the case
+            // where the customer has 0 funds and does not actually post a claim operation
+            close::finalize_customer_claim(database, &channel.label)
+                .await
+                .context("Chain watcher failed to finalized claimed funds")?;
+            if claimed {
+                notify(NotificationEventType::FundsClaimed);
+            }
+        }
+        Reaction::Claim => {}
+
+        // The channel has not reacted to its contract being closed, by a merchDispute or custClaim
+        // after a customer close, or by a merchClaim or mutualClose
+        Reaction::FinalizeClose => {
+            let state = channel.state.state_name();
+            if let Some(ending) = customer_close_ending(state, closed_by) {
+                close::finalize_closed_customer_close(database, &channel.label, ending)
+                    .await
+                    .context("Chain watcher failed to finalize closed contract")?;
+                notify(match ending {
+                    CustomerCloseEnding::Disputed => NotificationEventType::DisputeDetected,
+                    CustomerCloseEnding::Claimed => NotificationEventType::FundsClaimed,
+                });
+            } else {
+                match closed_contract_ending(state, closed_by, previous_status) {
+                    Some(ClosedContractEnding::MerchantClaimed) => {
+                        close::finalize_merchant_claim(rng, database, &channel.label)
+                            .await
+                            .context("Chain watcher failed to process expired contract")?;
+                        notify(NotificationEventType::MerchantClaimed);
+                    }
+                    Some(ClosedContractEnding::MutuallyClosed) => {
+                        close::finalize_mutual_close(config, database, &channel.label)
+                            .await
+                            .context("Chain watcher failed to finalize mutual close")?;
+                        notify(NotificationEventType::MutuallyClosed);
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        // Say so once per status, rather than at every check
+        Reaction::Inconsistent => {
+            if previous_status != Some(status) {
+                tracing::warn!(
+                    label = %channel.label,
+                    %contract_id,
+                    state = %channel.state.state_name(),
+                    status = ?status,
+                    "Contract status doesn't follow from the channel's state; check the channel \
+                    with `customer show` and close it manually if needed"
+                );
+                notify(NotificationEventType::ActionRequired);
+            }
+        }
     }
 
-    Ok(())
+    Ok(None)
 }
@@ -1,17 +1,24 @@
 //* Close functionalities for a merchant.
-use {anyhow::Context, async_trait::async_trait};
+use {anyhow::Context, async_trait::async_trait, std::time::SystemTime};
 
 use super::{database, load_tezos_client, Command};
 
 use zeekoe::{
     abort,
+    escrow::tezos::{ContractState, OperationStatus},
     merchant::{
         cli,
         database::{Error, QueryMerchant, QueryMerchantExt},
+        webhook::{WebhookEvent, Webhooks},
         Chan, Config,
     },
     offer_abort, proceed,
-    protocol::{self, close, ChannelStatus, Party::Merchant},
+    protocol::{
+        self,
+        close::{self, ManualCloseAction},
+        ChannelStatus,
+        Party::Merchant,
+    },
 };
 
 use zkabacus_crypto::{
@@ -24,6 +31,7 @@ pub struct Close;
 impl Close {
     pub async fn run(
         &self,
+        webhooks: &Webhooks,
         config: &Config,
         merchant_config: &MerchantConfig,
         chan: Chan<protocol::Close>,
@@ -34,6 +42,11 @@ impl Close {
         let (chan, close_state) = zkabacus_close(merchant_config, database.as_ref(), chan)
             .await
             .context("Mutual close failed")?;
+        webhooks.notify(WebhookEvent::close_started(
+            close_state.channel_id(),
+            *close_state.customer_balance(),
+            *close_state.merchant_balance(),
+        ));
 
         // Get contract ID for this channel
         let contract_id = database
@@ -47,6 +60,21 @@ impl Close {
         // Generate an authorization signature under the merchant's EdDSA Tezos key
         let tezos_client =
             load_tezos_client(config, close_state.channel_id(), database.as_ref()).await?;
+
+        // Don't authorize paying out to a customer other than the one the channel was established
+        // with
+        let contract_state = tezos_client
+            .get_contract_state()
+            .await
+            .context("Failed to fetch contract state")?;
+        if !verify_customer_address(database.as_ref(), close_state.channel_id(), &contract_state)
+            .await?
+        {
+            return Err(anyhow::anyhow!(
+                "Refusing mutual close: contract's customer address does not match the channel's (id: {})",
+                close_state.channel_id()
+            ));
+        }
         let authorization_signature = tezos_client
             .authorize_mutual_close(&close_state)
             .await
@@ -86,6 +114,38 @@ impl Close {
     }
 }
 
+/// Check that the contract pays the customer's balance to the address the customer established
+/// the channel with, returning whether it does.
+///
+/// A mismatch means the merchant's records and the chain disagree about who the customer is, which
+/// should never happen, so it is logged and the channel flagged for attention. Channels established
+/// before the address was recorded can't be checked, and are assumed to match.
+pub async fn verify_customer_address(
+    database: &dyn QueryMerchant,
+    channel_id: &ChannelId,
+    contract_state: &ContractState,
+) -> Result<bool, anyhow::Error> {
+    let recorded_address = match database.customer_funding_address(channel_id).await? {
+        Some(recorded_address) => recorded_address,
+        None => return Ok(true),
+    };
+
+    match contract_state.verify_customer_address(&recorded_address) {
+        Ok(()) => Ok(true),
+        Err(e) => {
+            tracing::error!(%channel_id, error = %e, "Customer address does not match the contract");
+            database
+                .set_attention_reason(channel_id, Some(&e.to_string()))
+                .await
+                .context(format!(
+                    "Failed to flag mismatched customer address (id: {})",
+                    channel_id
+                ))?;
+            Ok(false)
+        }
+    }
+}
+
 /// Process a customer close event.
 ///
 /// **Usage**: this should be called after receiving a notification that a custClose entrypoint
@@ -93,31 +153,35 @@ impl Close {
 pub async fn process_customer_close(
     config: &Config,
     database: &dyn QueryMerchant,
+    webhooks: &Webhooks,
     channel_id: &ChannelId,
     revocation_lock: &RevocationLock,
 ) -> Result<(), anyhow::Error> {
-    // Set status to PendingClose if possible
-    database
-        .update_status_to_pending_close(channel_id)
-        .await
-        .context(format!(
-            "Failed to update channel to PendingClose status (id: {})",
-            channel_id
-        ))?;
+    // Set status to PendingClose if possible, unless an earlier attempt to process the close
+    // already did so
+    if database.channel_status(channel_id).await? != ChannelStatus::PendingClose {
+        database
+            .update_status_to_pending_close(channel_id)
+            .await
+            .context(format!(
+                "Failed to update channel to PendingClose status (id: {})",
+                channel_id
+            ))?;
+    }
 
-    // Save the provided revocation lock (from the entrypoint call) and retrieve any existing
-    // revocation secrets associated with it.
-    let possible_secrets = database
-        .insert_revocation_lock(revocation_lock)
+    // Look up the secret for the provided revocation lock (from the entrypoint call), which the
+    // merchant only knows if the customer revoked the state they closed on.
+    let revocation_secret = database
+        .revocation_secret_for(revocation_lock)
         .await
         .context(format!(
             "Failed to look up revocation lock (id: {})",
             channel_id
         ))?;
 
-    // Get the first secret, if it exists.
-    match possible_secrets.iter().flatten().next() {
-        // If the lock *does not* have a revocation secret, do nothing else.
+    match revocation_secret {
+        // If the lock *does not* have a revocation secret, this is an honest close: do nothing
+        // else, and let the posted balances be finalized.
         None => Ok(()),
         // If the lock already has a revocation secret, start the dispute process.
         Some(revocation_secret) => {
@@ -138,13 +202,38 @@ pub async fn process_customer_close(
 
             // Call the merchDispute entrypoint and wait for it to be confirmed
             let tezos_client = load_tezos_client(config, channel_id, database).await?;
-            let _status = tezos_client
-                .merch_dispute(revocation_secret)
+            let dispute = tezos_client
+                .merch_dispute(&revocation_secret)
                 .await
                 .context(format!(
                     "Failed to post merchDispute entrypoint (id: {})",
                     &channel_id
                 ))?;
+            database
+                .record_dispute_operation(channel_id, &dispute.hash)
+                .await
+                .context(format!(
+                    "Failed to record merchDispute operation {} (id: {})",
+                    dispute.hash, &channel_id
+                ))?;
+            webhooks.notify(WebhookEvent::dispute_filed(channel_id, &dispute.hash));
+
+            // If the dispute wasn't applied, revert state back to PendingClose, so that the chain
+            // watcher tries again while the contract is still in CustomerClose
+            if !matches!(dispute.status, OperationStatus::Applied) {
+                database
+                    .compare_and_swap_channel_status(
+                        channel_id,
+                        &ChannelStatus::Dispute,
+                        &ChannelStatus::PendingClose,
+                    )
+                    .await?;
+                return Err(anyhow::anyhow!(
+                    "merchDispute operation {} was not applied (id: {})",
+                    dispute.hash,
+                    &channel_id
+                ));
+            }
 
             // React to successfully confirmed dispute
             finalize_dispute(database, channel_id)
@@ -216,11 +305,32 @@ pub async fn finalize_customer_close(
     }
 }
 
+/// Finalize a customer close whose contract was closed before the channel was updated to match.
+///
+/// The balances the customer posted are no longer in the contract's storage once the customer has
+/// claimed their funds, so the channel's closing balances are left as they are.
+pub async fn finalize_closed_customer_close(
+    database: &dyn QueryMerchant,
+    channel_id: &ChannelId,
+) -> Result<(), anyhow::Error> {
+    database
+        .compare_and_swap_channel_status(
+            channel_id,
+            &ChannelStatus::PendingClose,
+            &ChannelStatus::Closed,
+        )
+        .await
+        .context(format!(
+            "Failed to update channel to Closed status (id: {})",
+            &channel_id
+        ))
+}
+
 /// Process a confirmed merchant dispute event.
 ///
 /// **Usage**: this should be called after receiving a notification that a merchDispute
 /// entrypoint call/operation is confirmed at the required confirmation depth.
-async fn finalize_dispute(
+pub async fn finalize_dispute(
     database: &dyn QueryMerchant,
     channel_id: &ChannelId,
 ) -> Result<(), anyhow::Error> {
@@ -359,23 +469,126 @@ impl Command for cli::Close {
     async fn run(self, config: Config) -> Result<(), anyhow::Error> {
         // Retrieve zkAbacus config from the database
         let database = database(&config).await?;
+        let (webhooks, webhook_delivery) = Webhooks::start(
+            config.webhooks.as_ref(),
+            database.clone(),
+            reqwest::Client::new(),
+        )
+        .await
+        .context("Failed to start webhooks")?;
 
         // Make sure exactly one correct command line option is satisfied
-        match (self.channel, self.all) {
-            (Some(channel_id), false) => expiry(&config, database.as_ref(), &channel_id).await,
+        let result = match (self.channel, self.all) {
+            (Some(channel_id), false) => {
+                manual_close(
+                    &config,
+                    database.as_ref(),
+                    &webhooks,
+                    &channel_id,
+                    self.dry_run,
+                )
+                .await
+            }
             // TODO: iterate through database; call expiry for every channel
             (None, true) => Err(anyhow::anyhow!(
                 "Closing all channels is not yet implemented."
             )),
             _ => unreachable!(),
+        };
+
+        // Deliver any events from the close before exiting
+        drop(webhooks);
+        webhook_delivery.finish().await;
+        result
+    }
+}
+
+/// Force the close of a channel right now, deciding from the state of its contract whether to
+/// initiate expiry, claim the balance after an unanswered expiry, or dispute a customer's close on
+/// a revoked balance, and printing the decision. With `dry_run`, nothing is posted.
+///
+/// **Usage**: this is called directly from the command line.
+async fn manual_close(
+    config: &Config,
+    database: &dyn QueryMerchant,
+    webhooks: &Webhooks,
+    channel_id: &ChannelId,
+    dry_run: bool,
+) -> Result<(), anyhow::Error> {
+    let channel_status = database
+        .channel_status(channel_id)
+        .await
+        .context("Failed to retrieve current channel status")?;
+    let tezos_client = load_tezos_client(config, channel_id, database).await?;
+    let contract_state = tezos_client.get_contract_state().await.context(format!(
+        "Failed to fetch contract state (id: {})",
+        channel_id
+    ))?;
+
+    // A customer's close can be disputed if the merchant knows the secret for its revocation lock
+    let revocation_lock = contract_state.revocation_lock()?;
+    let revoked = match &revocation_lock {
+        Some(revocation_lock) => database
+            .revocation_secret_for(revocation_lock)
+            .await
+            .context(format!(
+                "Failed to look up revocation lock (id: {})",
+                channel_id
+            ))?
+            .is_some(),
+        None => false,
+    };
+
+    let action = close::manual_close_action(
+        contract_state.status()?,
+        contract_state.delay_expiry(),
+        SystemTime::now(),
+        revoked,
+    )?;
+
+    if dry_run {
+        println!("Would {} (id: {})", action, channel_id);
+        return Ok(());
+    }
+    println!("Going to {} (id: {})", action, channel_id);
+
+    match action {
+        ManualCloseAction::Expiry => expiry(config, database, channel_id).await?,
+        ManualCloseAction::MerchantClaim => {
+            // The channel may not have caught up with the expiry on chain
+            if let ChannelStatus::MerchantFunded | ChannelStatus::Active = channel_status {
+                database
+                    .compare_and_swap_channel_status(
+                        channel_id,
+                        &channel_status,
+                        &ChannelStatus::PendingExpiry,
+                    )
+                    .await?;
+            }
+            claim_expiry_funds(config, database, channel_id).await?;
+            finalize_expiry_close(database, channel_id).await?;
+        }
+        ManualCloseAction::Dispute => {
+            let revocation_lock =
+                revocation_lock.expect("A revoked close has a revocation lock on chain");
+            process_customer_close(config, database, webhooks, channel_id, &revocation_lock)
+                .await?;
         }
     }
+
+    println!("Done (id: {})", channel_id);
+    Ok(())
 }
 
 /// Initiate close procedures with an expiry transaction.
 ///
-/// **Usage**: this is called directly from the command line.
-async fn expiry(
+/// **Usage**: this is called directly from the command line, or by the chain watcher to reclaim
+/// funds from channels that were funded but never activated, or that have been active too long.
+//
+// Note to developers: This function reverts the status update if the `expiry` entrypoint call
+// fails. This revert is only valid if no other state changes in this function!
+// DO NOT ADD STATE CHANGES without first removing the status update.
+pub async fn expiry(
     config: &Config,
     database: &dyn QueryMerchant,
     channel_id: &ChannelId,
@@ -408,12 +621,25 @@ async fn expiry(
 
     // Call expiry entrypoint
     let tezos_client = load_tezos_client(config, channel_id, database).await?;
-    tezos_client.expiry().await.context(format!(
+    match tezos_client.expiry().await.context(format!(
         "Failed to initiate expiry close flow (id: {})",
         &channel_id
-    ))?;
-
-    Ok(())
+    )) {
+        Ok(_status) => Ok(()),
+        Err(e) => {
+            // If `expiry` didn't post correctly (for instance, because the customer closed the
+            // contract first), revert state back to what it was, so that the chain watcher
+            // responds to whatever is on chain instead
+            database
+                .compare_and_swap_channel_status(
+                    channel_id,
+                    &ChannelStatus::PendingExpiry,
+                    &current_status,
+                )
+                .await?;
+            Err(e)
+        }
+    }
 }
 
 /// Claim the channel balances.
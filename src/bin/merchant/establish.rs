@@ -1,4 +1,4 @@
-use {anyhow::Context, rand::rngs::StdRng};
+use {anyhow::Context, rand::rngs::StdRng, std::sync::Arc};
 
 use zkabacus_crypto::{
     merchant::Config as ZkAbacusConfig, ChannelId, Context as ProofContext, CustomerBalance,
@@ -7,11 +7,20 @@ use zkabacus_crypto::{
 
 use zeekoe::{
     abort,
+    amount::Balances,
     escrow::{
+        notify::{ChainSource, TezosChain},
         tezos::{self, TezosClient},
-        types::{KeyHash, TezosKeyMaterial, TezosPublicKey},
+        types::{KeyHash, TezosFundingAddress, TezosKeyMaterial, TezosPublicKey},
+    },
+    merchant::{
+        approve::{self, ApproverClient},
+        config::Service,
+        database::QueryMerchant,
+        server::SessionKey,
+        webhook::{WebhookEvent, Webhooks},
+        Chan, Config,
     },
-    merchant::{config::Service, database::QueryMerchant, server::SessionKey, Chan, Config},
     offer_abort, proceed,
     protocol::{self, establish, ChannelStatus, Party::Merchant},
     timeout::WithTimeout,
@@ -19,7 +28,7 @@ use zeekoe::{
 
 use tezedge::crypto::Prefix;
 
-use super::{approve, database, load_tezos_client};
+use super::database;
 
 pub struct Establish;
 
@@ -28,7 +37,8 @@ impl Establish {
     pub async fn run(
         &self,
         mut rng: StdRng,
-        client: &reqwest::Client,
+        client: &ApproverClient,
+        webhooks: &Webhooks,
         config: &Config,
         service: &Service,
         zkabacus_merchant_config: &ZkAbacusConfig,
@@ -43,6 +53,9 @@ impl Establish {
                        .context("Establish timed out while receiving channel request")?
                        .context("Failed to receive valid channel request")?;
         */
+        // Keep a transcript of the session, to bind the establish proof to what was negotiated
+        let mut transcript = protocol::Transcript::new(&session_key.to_bytes());
+
         let (
             customer_randomness,
             customer_deposit,
@@ -58,39 +71,46 @@ impl Establish {
                 .recv()
                 .await
                 .context("Failed to receive customer randomness")?;
+            transcript.append(&customer_randomness);
 
             // Receive the customer's desired deposit into the channel
             let (customer_deposit, chan) = chan
                 .recv()
                 .await
                 .context("Failed to receive customer balance")?;
+            transcript.append(&customer_deposit);
 
             // Receive the customer's desired merchant contribution to the channel
             let (merchant_deposit, chan) = chan
                 .recv()
                 .await
                 .context("Failed to receive merchant balance")?;
+            transcript.append(&merchant_deposit);
 
             // Receive the channel establishment justification note from the customer
             let (note, chan) = chan
                 .recv()
                 .await
                 .context("Failed to receive establish note")?;
+            transcript.append(&note);
 
             // Receive the customer's Tezos public key (EdDSA public key)
             let (customer_tezos_public_key, chan) = chan
                 .recv()
                 .await
                 .context("Failed to receive customer Tezos public key")?;
+            transcript.append(&customer_tezos_public_key);
 
             // Receive the customer's Tezos account (tz1) address corresponding to that public key
             let (customer_funding_address, chan) = chan
                 .recv()
                 .await
                 .context("Failed to receive customer Tezos funding address")?;
+            transcript.append(&customer_funding_address);
 
             // Recieve the key hash, computed over the merchant's public keys
             let (key_hash, chan) = chan.recv().await.context("Failed to receive key hash")?;
+            transcript.append(&key_hash);
 
             Ok::<_, anyhow::Error>((
                 customer_randomness,
@@ -108,6 +128,11 @@ impl Establish {
         .context("Establish timed out while receiving channel request")?
         .context("Failed to receive valid channel request")?;
 
+        // Refuse an over-long note before doing anything with it
+        if let Err(too_long) = protocol::check_note_length(&note, service.max_note_length) {
+            abort!(in chan return establish::Error::NoteTooLong(too_long))
+        }
+
         // TODO: verify customer's tezos public key is valid
 
         // Check that the customer's Tezos public key corresponds to their Tezos account
@@ -116,7 +141,13 @@ impl Establish {
         // Check that the customer's account is actually a tz1 address
         let funding_address_is_tz1 = matches!(customer_funding_address.get_prefix(), Prefix::tz1);
 
-        // Check that the key hash matches the merchant's expected key hash
+        // TODO: Add "valid tezos public key" check to this
+        if !(customer_keys_match && funding_address_is_tz1) {
+            abort!(in chan return establish::Error::Rejected("invalid inputs".into()))
+        }
+
+        // Check that the key hash matches the merchant's expected key hash, so that the customer
+        // is establishing a channel with the keys they think they are
         let tezos_key_material = config.load_tezos_key_material()?;
         let merchant_keys_match = key_hash
             == KeyHash::new(
@@ -124,10 +155,37 @@ impl Establish {
                 tezos_key_material.funding_address(),
                 tezos_key_material.public_key(),
             );
+        if !merchant_keys_match {
+            abort!(in chan return establish::Error::KeyHashMismatch)
+        }
 
-        // TODO: Add "valid tezos public key" check to this
-        if !(customer_keys_match && funding_address_is_tz1 && merchant_keys_match) {
-            abort!(in chan return establish::Error::Rejected("invalid inputs".into()))
+        // Refuse channels larger than the contract can represent
+        if let Err(e) = Balances::new(customer_deposit, merchant_deposit).total() {
+            abort!(in chan return establish::Error::Rejected(e.to_string()))
+        }
+
+        // Only fund channels on our own if this service allows it
+        if customer_deposit.into_inner() == 0 && !service.allow_merchant_funded_channels {
+            abort!(in chan return establish::Error::Rejected(
+                "merchant does not accept channels without a customer deposit".into()
+            ))
+        }
+
+        // Make sure we can cover our own deposit and fees, before agreeing to anything
+        if merchant_deposit.into_inner() > 0 {
+            if let Err(e) = tezos::verify_funding_balance(
+                &config.tezos_network()?,
+                &tezos_key_material.funding_address(),
+                Merchant,
+                merchant_deposit.into_inner(),
+            )
+            .await
+            {
+                tracing::warn!(error = %e, "Refusing to establish channel");
+                abort!(in chan return establish::Error::Rejected(
+                    "merchant cannot fund the channel".into()
+                ))
+            }
         }
 
         // Store items only used to generate channel ID in a struct
@@ -137,35 +195,42 @@ impl Establish {
         };
 
         // Request approval from the approval service
-        let response_url = match approve::establish(
-            client,
-            &service.approve,
-            &customer_deposit,
-            &merchant_deposit,
-            note,
-        )
-        .await
-        {
-            Ok(confirm_url) => confirm_url,
-            Err(approval_error) => {
-                let error = establish::Error::Rejected(
-                    approval_error.unwrap_or_else(|| "internal error".into()),
-                );
-                abort!(in chan return error);
-            }
-        };
+        let approval =
+            match approve::establish(client, service, &customer_deposit, &merchant_deposit, note)
+                .await
+            {
+                Ok(approval) => approval,
+                Err(approval_error) => {
+                    let error = establish::Error::Rejected(
+                        approval_error.unwrap_or_else(|| "internal error".into()),
+                    );
+                    abort!(in chan return error);
+                }
+            };
         // The approval service has approved
         proceed!(in chan);
+        let response_url = approval.response_url;
+
+        // Tell the customer what the merchant will actually contribute, which the approval service
+        // may have reduced
+        let merchant_deposit = approval.merchant_deposit;
+        let chan = chan
+            .send(merchant_deposit)
+            .await
+            .context("Failed to send merchant contribution")?;
+        transcript.append(&merchant_deposit);
 
         let establish_result = establish_channel(
             &mut rng,
             channel_id_contribution,
             zkabacus_merchant_config,
-            session_key,
             config,
             service,
             merchant_deposit,
             customer_deposit,
+            &customer_funding_address,
+            webhooks,
+            transcript,
             chan,
         )
         .await;
@@ -188,15 +253,18 @@ async fn establish_channel(
     mut rng: &mut StdRng,
     channel_id_contribution: CustomerChannelIdContribution,
     zkabacus_merchant_config: &ZkAbacusConfig,
-    session_key: SessionKey,
     config: &Config,
     service: &Service,
     merchant_deposit: MerchantBalance,
     customer_deposit: CustomerBalance,
+    customer_funding_address: &TezosFundingAddress,
+    webhooks: &Webhooks,
+    mut transcript: protocol::Transcript,
     chan: Chan<establish::MerchantSupplyInfo>,
 ) -> Result<(), anyhow::Error> {
     let database = database(config).await?;
     let tezos_key_material = config.load_tezos_key_material()?;
+    let customer_public_key = channel_id_contribution.customer_tezos_public_key.clone();
 
     // Form channel ID, incorporating randomness and key material from both parties.
     let (channel_id, chan) = form_channel_id(
@@ -205,12 +273,12 @@ async fn establish_channel(
         zkabacus_merchant_config,
         &tezos_key_material,
         channel_id_contribution,
+        &mut transcript,
     )
     .await?;
 
-    // Generate the proof context for the establish proof
-    // TODO: the context should actually be formed from a session transcript up to this point
-    let context = ProofContext::new(&session_key.to_bytes());
+    // Generate the proof context for the establish proof from the transcript so far
+    let context = transcript.context();
 
     // Receive the establish proof from the customer and validate it
     let (blinded_state, chan) = zkabacus_initialize(
@@ -227,26 +295,25 @@ async fn establish_channel(
     .context("Establish timed out while initializing channel")?
     .context("Failed to initialize channel")?;
 
-    // Load tezos client to use in upcoming on-chain operations
-    let tezos_client = load_tezos_client(config, &channel_id, database.as_ref()).await?;
-
     // Verify that the customer originated and funded the channel correctly
     // Timeout accounts for posting and verification of two Tezos operations
-    let chan = async {
+    let (chan, tezos_client) = async {
         // Receive contract id from customer (possibly also send block height, check spec)
         let (contract_id, chan) = chan
             .recv()
             .await
             .context("Failed to receive contract ID from customer")?;
 
-        let proposed_tezos_client = TezosClient {
-            uri: Some(config.tezos_uri.clone()),
+        // The channel isn't in the database until its contract is verified, so the client for
+        // the contract can't be loaded from there
+        let tezos_client = TezosClient {
+            uri: Some(config.tezos_network()?.uri()),
             contract_id: contract_id.clone(),
-            client_key_pair: config.load_tezos_key_material()?,
+            client_signer: Arc::new(config.load_tezos_key_material()?),
             confirmation_depth: config.confirmation_depth,
             self_delay: config.self_delay,
         };
-        match proposed_tezos_client
+        match tezos_client
             .verify_origination(
                 merchant_deposit,
                 customer_deposit,
@@ -256,21 +323,24 @@ async fn establish_channel(
         {
             Ok(()) => {}
             Err(err) => {
-                eprintln!("Warning: {}", err);
-                abort!(in chan return establish::Error::FailedVerifyOrigination);
+                tracing::warn!(%channel_id, %contract_id, error = %err, "Rejected contract");
+                abort!(in chan return establish::Error::ContractRejected((&err).into()));
             }
         };
 
-        // Store the channel information in the database
+        // Store the channel information in the database, with the Tezos account the customer
+        // established it with
         database
-            .new_channel(
+            .record_verified_contract(
                 &channel_id,
                 &contract_id,
                 &merchant_deposit,
                 &customer_deposit,
+                customer_funding_address,
+                &customer_public_key,
             )
             .await
-            .context("Failed to insert new channel_id, contract_id in database")?;
+            .context("Failed to insert new channel in database")?;
 
         // Move forward in the protocol
         proceed!(in chan);
@@ -280,17 +350,30 @@ async fn establish_channel(
             .await
             .context("Failed to receive notification that the customer funded the contract")?;
 
-        match tezos_client
-            .verify_customer_funding(&merchant_deposit)
+        let funding_operation = match tezos_client
+            .verify_customer_funding(
+                &customer_deposit,
+                &merchant_deposit,
+                customer_funding_address,
+                config.funding_search_depth,
+            )
             .await
         {
-            Ok(()) => {}
+            Ok(funding_operation) => funding_operation,
             Err(err) => {
-                eprintln!("Warning: {}", err);
-                abort!(in chan return establish::Error::FailedVerifyCustomerFunding);
+                tracing::warn!(%channel_id, %contract_id, error = %err, "Rejected contract");
+                abort!(in chan return establish::Error::ContractRejected((&err).into()));
             }
         };
 
+        // Keep a record of the operation that funded the contract, if the customer needed one
+        if let Some(funding_operation) = funding_operation {
+            database
+                .record_customer_funding(&channel_id, &funding_operation)
+                .await
+                .context("Failed to record customer funding operation in database")?;
+        }
+
         // Transition the contract state in the database from originated to customer-funded
         database
             .compare_and_swap_channel_status(
@@ -309,7 +392,7 @@ async fn establish_channel(
         // Move forward in the protocol
         proceed!(in chan);
 
-        Ok(chan)
+        Ok((chan, tezos_client))
     }
     .with_timeout(2 * (service.transaction_timeout + service.verification_timeout))
     .await
@@ -319,11 +402,42 @@ async fn establish_channel(
     // If the merchant contribution was greater than zero, fund the channel on chain, and await
     // confirmation that the funding has gone through to the required confirmation depth
     if merchant_deposit.into_inner() > 0 {
+        // The customer may have reclaimed their funding since it was verified at depth, in which
+        // case funding the contract would only leave the merchant's deposit in a dead contract.
+        // Check the head, rather than waiting for confirmations, to keep the window small
+        let head_status = TezosChain::new(config.tezos_network()?.uri())
+            .head_contract_status(&tezos_client.contract_id)
+            .await
+            .context("Failed to check contract status before merchant funding")?;
+        if let Err(error) = establish::check_not_reclaimed(head_status) {
+            tracing::warn!(
+                %channel_id,
+                contract_id = %tezos_client.contract_id,
+                %error,
+                "Customer reclaimed funding before merchant funding"
+            );
+            database
+                .compare_and_swap_channel_status(
+                    &channel_id,
+                    &ChannelStatus::CustomerFunded,
+                    &ChannelStatus::Closed,
+                )
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to update channel to Closed status after funding was reclaimed \
+                        (id: {})",
+                        &channel_id
+                    )
+                })?;
+            return Err(error.into());
+        }
+
         match tezos_client
             .add_merchant_funding(&tezos::MerchantFundingInformation {
                 balance: merchant_deposit,
-                public_key: tezos_client.client_key_pair.public_key().clone(),
-                address: tezos_client.client_key_pair.funding_address(),
+                public_key: tezos_client.client_signer.public_key().clone(),
+                address: tezos_client.client_signer.funding_address(),
             })
             .await
         {
@@ -376,6 +490,12 @@ async fn establish_channel(
     .await
     .context("Failed to activate channel")?;
 
+    webhooks.notify(WebhookEvent::channel_opened(
+        &channel_id,
+        customer_deposit,
+        merchant_deposit,
+    ));
+
     Ok(())
 }
 
@@ -391,6 +511,7 @@ async fn form_channel_id(
     zkabacus_merchant_config: &ZkAbacusConfig,
     tezos_key_material: &TezosKeyMaterial,
     channel_id_contribution: CustomerChannelIdContribution,
+    transcript: &mut protocol::Transcript,
 ) -> Result<(ChannelId, Chan<establish::Initialize>), anyhow::Error> {
     // Generate the merchant's random contribution to the channel ID
     let merchant_randomness = MerchantRandomness::new(rng);
@@ -400,15 +521,15 @@ async fn form_channel_id(
         .send(merchant_randomness)
         .await
         .context("Failed to send merchant randomness for channel ID")?;
+    transcript.append(&merchant_randomness);
 
     // Generate channel ID (customer will share this same value since they use the same inputs)
-    let channel_id = ChannelId::new(
+    let channel_id = establish::channel_id(
         merchant_randomness,
         channel_id_contribution.customer_randomness,
-        // Merchant's Pointcheval-Sanders public key:
         zkabacus_merchant_config.signing_keypair().public_key(),
-        tezos_key_material.public_key().as_ref(),
-        channel_id_contribution.customer_tezos_public_key.as_ref(),
+        tezos_key_material.public_key(),
+        &channel_id_contribution.customer_tezos_public_key,
     );
 
     Ok((channel_id, chan))
@@ -4,48 +4,56 @@ use {
     dialectic::offer,
     futures::{
         stream::{FuturesUnordered, StreamExt},
-        FutureExt,
+        FutureExt, TryFutureExt,
     },
     rand::{rngs::StdRng, SeedableRng},
     sqlx::SqlitePool,
-    std::{convert::identity, sync::Arc},
+    std::{convert::identity, net::SocketAddr, sync::Arc},
     structopt::StructOpt,
     tokio::signal,
-    tokio::sync::broadcast,
+    tokio::sync::{broadcast, oneshot, Semaphore},
 };
 
-use std::time::Duration;
+use std::time::SystemTime;
 
 use zeekoe::{
+    build_info::BuildInfo,
     escrow::{
-        tezos::TezosClient,
+        tezos::{self, TezosClient},
         types::{ContractStatus, TezosKeyMaterial},
     },
+    logging,
     merchant::{
+        approve::ApproverClient,
         cli::{self, Run},
         config::DatabaseLocation,
-        database::{connect_sqlite, ChannelDetails, QueryMerchant},
+        database::{connect_postgres, connect_sqlite, ChannelDetails, QueryMerchant},
         defaults::config_path,
-        Chan, Cli, Config, Server,
+        message_length,
+        webhook::Webhooks,
+        Chan, Cli, ClientAuth, Config, Server,
+    },
+    metrics,
+    protocol::{
+        establish::{stalled_establish_action, StalledEstablishAction},
+        version::{self, Features},
+        ChannelStatus, ZkChannels,
     },
-    protocol::{ChannelStatus, ZkChannels},
 };
 
-mod approve;
 mod close;
 mod establish;
 mod manage;
 mod parameters;
 mod pay;
+mod reconcile;
 
 use close::Close;
 use establish::Establish;
 use parameters::Parameters;
-use pay::Pay;
+use pay::{Pay, Repeated, Resume};
 use zkabacus_crypto::ChannelId;
 
-const MAX_INTERVAL_SECONDS: u64 = 60;
-
 /// A single merchant-side command, parameterized by the currently loaded configuration.
 ///
 /// All subcommands of [`cli::Merchant`] should implement this, except [`cli::Merchant::Configure`], which does not need
@@ -58,6 +66,11 @@ pub trait Command {
 #[async_trait]
 impl Command for Run {
     async fn run(self, config: Config) -> Result<(), anyhow::Error> {
+        tracing::info!("{}", BuildInfo::collect(Some(&config.database)).await);
+
+        // Make sure the configured Tezos node is on the network we think it is
+        tezos::check_network(&config.tezos_network()?).await?;
+
         // Either initialize the merchant's config afresh, or get existing config if it exists
         let zkabacus_config = database(&config)
             .await
@@ -71,6 +84,15 @@ impl Command for Run {
         let client = reqwest::Client::new();
         let config = config.clone();
 
+        // Start notifying the webhook of channel lifecycle events, if there is one
+        let (webhooks, webhook_delivery) = Webhooks::start(
+            config.webhooks.as_ref(),
+            database(&config).await?,
+            client.clone(),
+        )
+        .await
+        .context("Failed to start webhooks")?;
+
         // Sender and receiver to indicate graceful shutdown should occur
         let (terminate, _) = broadcast::channel(1);
 
@@ -81,18 +103,30 @@ impl Command for Run {
             .map(|service| {
                 // Clone `Arc`s for the various resources we need in this server
                 let client = client.clone();
+                let webhooks = webhooks.clone();
                 let config = config.clone();
                 let zkabacus_config = zkabacus_config.clone();
                 let service = Arc::new(service.clone());
                 let mut wait_terminate = terminate.subscribe();
 
                 async move {
+                    // Every request to this service's approver goes through the same client
+                    let client = ApproverClient::for_service(client, &service).await?;
+
                     // Initialize a new `Server` with parameters taken from the configuration
                     let mut server: Server<ZkChannels> = Server::new();
                     server
-                        .timeout(service.connection_timeout)
+                        .timeout(service.max_resume_window)
+                        .idle_timeout(service.idle_connection_timeout)
                         .max_pending_retries(Some(service.max_pending_connection_retries))
-                        .max_length(service.max_message_length);
+                        .max_length(service.max_message_length)
+                        .max_concurrent_sessions(service.max_concurrent_sessions)
+                        .max_sessions_per_ip(service.max_sessions_per_ip)
+                        .client_auth(service.client_ca.clone().map(|ca_path| ClientAuth {
+                            ca_path,
+                            required: service.require_client_auth,
+                        }))
+                        .shutdown_grace_period(Some(config.shutdown_grace_period));
 
                     // Serve on this address
                     let address = (service.address, service.port);
@@ -106,6 +140,7 @@ impl Command for Run {
                     let interact = move |session_key, (), chan: Chan<ZkChannels>| {
                         // Clone `Arc`s for the various resources we need in this request
                         let client = client.clone();
+                        let webhooks = webhooks.clone();
                         let zkabacus_config = zkabacus_config.clone();
                         let service = service.clone();
                         let config = config.clone();
@@ -113,39 +148,84 @@ impl Command for Run {
                         // TODO: permit configuration option to make this deterministic for testing
                         let rng = StdRng::from_entropy();
 
+                        let max_message_length = service.max_message_length;
                         async move {
+                            let (customer, features, chan) = version::merchant(
+                                chan,
+                                version::Hello::current(),
+                                version::SupportedVersions::CURRENT,
+                            )
+                            .await?;
+                            tracing::debug!(
+                                version = %customer.version,
+                                features = %features,
+                                "Customer speaks protocol version"
+                            );
+
                             offer!(in chan {
-                                0 => Parameters.run(
-                                    &config,
-                                    &zkabacus_config,
-                                    chan,
-                                ).await?,
-                                1 => Establish.run(
-                                    rng,
-                                    &client,
+                                0 => metrics::session("parameters", Parameters.run(
                                     &config,
                                     &service,
                                     &zkabacus_config,
-                                    session_key,
                                     chan,
-                                ).await?,
-                                2 => Pay.run(
+                                )).await?,
+                                1 => metrics::session("establish", Establish.run(
                                     rng,
                                     &client,
+                                    &webhooks,
                                     &config,
                                     &service,
+                                    &zkabacus_config,
                                     session_key,
                                     chan,
-                                ).await?,
-                                3 => Close.run(
+                                )).await?,
+                                2 => {
+                                    features.require(Features::PAYMENT_IDS)?;
+                                    metrics::session("pay", Pay.run(
+                                        rng,
+                                        &client,
+                                        &webhooks,
+                                        &config,
+                                        &service,
+                                        session_key,
+                                        chan,
+                                    )).await?
+                                }
+                                3 => metrics::session("close", Close.run(
+                                    &webhooks,
                                     &config,
                                     &zkabacus_config,
                                     chan,
-                                ).await?,
+                                )).await?,
+                                4 => {
+                                    features.require(Features::RESUME_PAYMENT)?;
+                                    metrics::session("resume", Resume.run(
+                                        rng,
+                                        &client,
+                                        &webhooks,
+                                        &config,
+                                        &service,
+                                        chan,
+                                    )).await?
+                                }
+                                5 => {
+                                    features.require(
+                                        Features::REPEATED_PAYMENTS.union(Features::PAYMENT_IDS),
+                                    )?;
+                                    metrics::session("pay_repeated", Repeated.run(
+                                        &client,
+                                        &webhooks,
+                                        &config,
+                                        &service,
+                                        session_key,
+                                        chan,
+                                    )).await?
+                                }
 
                             })?;
                             Ok::<_, anyhow::Error>(())
                         }
+                        .map_err(move |e| message_length::explain(e, max_message_length))
                     };
 
                     // Future that completes on graceful shutdown
@@ -166,16 +246,33 @@ impl Command for Run {
             })
             .collect();
 
-        // In production, the self_delay should be long (at least 48h) so this will always end up
-        // being 60s. In development, you may see lower values to allow for quicker testing.
-        let interval_seconds = std::cmp::min(config.self_delay / 2, MAX_INTERVAL_SECONDS);
-        let mut polling_interval = tokio::time::interval(Duration::from_secs(interval_seconds));
+        // Serve metrics for scraping, if asked to
+        if let Some(port) = config.metrics_port {
+            let address = SocketAddr::new(config.metrics_address, port);
+            tokio::spawn(async move {
+                if let Err(e) = metrics::serve(address).await {
+                    tracing::error!(%address, error = %e, "Error serving metrics");
+                }
+            });
+        }
+
+        let mut polling_interval = tokio::time::interval(config.chain_polling_interval());
+
+        // Limit the number of channels being dispatched on at once
+        let max_dispatches = config.max_concurrent_dispatches.max(1);
+        let dispatch_permits = Arc::new(Semaphore::new(max_dispatches));
+
+        // Sender and receiver to stop the polling service, once the servers have shut down
+        let (stop_polling, mut recv_stop_polling) = oneshot::channel::<()>();
 
         // Get a join handle for the polling service
-        let polling_service_join_handle = tokio::spawn(async move {
+        let watcher_webhooks = webhooks.clone();
+        let mut polling_service_join_handle = tokio::spawn(async move {
             // Clone resources
             let config = config.clone();
+            let webhooks = watcher_webhooks;
             let database = database(&config).await?;
+            let tezos_network = config.tezos_network()?;
 
             loop {
                 // Retrieve list of channels from database
@@ -187,43 +284,211 @@ impl Command for Run {
                     Ok(channels) => channels,
                     Err(e) => return Err::<(), anyhow::Error>(e),
                 };
+                metrics::channels(channels.iter().map(|channel| channel.status));
+
+                // Reconcile each channel with its contract, unless the node can't be reached, in
+                // which case every contract would seem to be missing
+                match tezos::check_network(&tezos_network).await {
+                    Ok(()) => {
+                        for channel in channels {
+                            let database = database.clone();
+                            let webhooks = webhooks.clone();
+                            let config = config.clone();
+                            let dispatch_permits = dispatch_permits.clone();
+                            tokio::spawn(async move {
+                                let _permit = dispatch_permits.acquire().await;
+                                match reconcile::reconcile_channel(
+                                    database.as_ref(),
+                                    &webhooks,
+                                    &channel,
+                                    &config,
+                                )
+                                .await
+                                {
+                                    Ok(None) => tracing::debug!(
+                                        channel_id = %channel.channel_id,
+                                        "Successfully dispatched"
+                                    ),
+                                    Ok(Some(discrepancy)) => match discrepancy.outcome {
+                                        Ok(()) => tracing::info!(
+                                            channel_id = %channel.channel_id,
+                                            reconciliation = %discrepancy.reconciliation,
+                                            "Reconciled channel with the chain"
+                                        ),
+                                        Err(e) => tracing::error!(
+                                            channel_id = %channel.channel_id,
+                                            reconciliation = %discrepancy.reconciliation,
+                                            error = %format!("{:#}", e),
+                                            "Error reconciling channel with the chain"
+                                        ),
+                                    },
+                                    Err(e) => tracing::error!(
+                                        channel_id = %channel.channel_id,
+                                        error = %e,
+                                        "Error dispatching"
+                                    ),
+                                }
+                            });
+                        }
+                    }
+                    Err(e) => tracing::warn!(error = %e, "Skipping reconciliation with the chain"),
+                }
 
-                // Query each contract ID and dispatch on the result
-                for channel in channels {
-                    let database = database.clone();
-                    let config = config.clone();
-                    tokio::spawn(async move {
-                        match dispatch_channel(database.as_ref(), &channel, &config).await {
-                            Ok(()) => eprintln!("Successfully dispatched {}", &channel.channel_id),
-                            Err(e) => {
-                                eprintln!("Error dispatching on {}: {}", &channel.channel_id, e)
+                // Reclaim merchant funds from channels whose establishment stalled after funding
+                if let Some(timeout) = config.stalled_establish_timeout() {
+                    let stalled_channels = match database
+                        .get_stalled_establishments(SystemTime::now() - timeout)
+                        .await
+                    {
+                        Ok(stalled_channels) => stalled_channels,
+                        Err(e) => {
+                            tracing::warn!(
+                                error = %e,
+                                "Skipping reclaiming stalled channels: failed to retrieve them"
+                            );
+                            Vec::new()
+                        }
+                    };
+
+                    for channel in stalled_channels {
+                        let database = database.clone();
+                        let config = config.clone();
+                        let dispatch_permits = dispatch_permits.clone();
+                        tokio::spawn(async move {
+                            let _permit = dispatch_permits.acquire().await;
+                            if let Err(e) =
+                                reclaim_stalled_establishment(database.as_ref(), &channel, &config)
+                                    .await
+                            {
+                                tracing::error!(
+                                    channel_id = %channel.channel_id,
+                                    error = %e,
+                                    "Error reclaiming funds"
+                                )
                             }
+                        });
+                    }
+                }
+
+                // Expire channels which have been active too long, to recover the merchant's funds
+                if let Some(expiry_after) = config.channel_expiry_after {
+                    let long_active_channels = match database
+                        .get_long_active_channels(SystemTime::now() - expiry_after)
+                        .await
+                    {
+                        Ok(long_active_channels) => long_active_channels,
+                        Err(e) => {
+                            tracing::warn!(
+                                error = %e,
+                                "Skipping expiring long-active channels: failed to retrieve them"
+                            );
+                            Vec::new()
                         }
-                    });
+                    };
+
+                    for channel in long_active_channels {
+                        let database = database.clone();
+                        let config = config.clone();
+                        let dispatch_permits = dispatch_permits.clone();
+                        tokio::spawn(async move {
+                            let _permit = dispatch_permits.acquire().await;
+                            if let Err(e) =
+                                expire_long_active_channel(database.as_ref(), &channel, &config)
+                                    .await
+                            {
+                                tracing::error!(
+                                    channel_id = %channel.channel_id,
+                                    error = %e,
+                                    "Error expiring channel"
+                                )
+                            }
+                        });
+                    }
+                }
+
+                // Wait for the next round, unless it's time to stop
+                tokio::select! {
+                    _ = polling_interval.tick() => {},
+                    _ = &mut recv_stop_polling => {
+                        // Let the dispatches in progress finish, so their updates aren't lost
+                        let _permits = dispatch_permits.acquire_many(max_dispatches as u32).await;
+                        return Ok(());
+                    }
                 }
-                polling_interval.tick().await;
             }
         });
 
-        // Wait for either the servers or the polling service to finish
+        // Wait for a shutdown signal, or for either the servers or the polling service to fail
+        let mut polling_service_stopped = false;
         tokio::select! {
-            _ = signal::ctrl_c() => eprintln!("Terminated by user"),
+            result = shutdown_signal() => match result {
+                Ok(()) => tracing::info!("Shutting down, once sessions in progress finish..."),
+                Err(e) => tracing::error!(error = %e, "Error waiting for shutdown signal"),
+            },
             Some(Err(e)) = server_futures.next() => {
-                eprintln!("Error: {}", e);
+                tracing::error!(error = %e, "Server failed");
             },
-            Err(e) = polling_service_join_handle => {
-                eprintln!("Error: {}", e);
+            result = &mut polling_service_join_handle => {
+                polling_service_stopped = true;
+                report_polling_service_result(result);
             }
             else => {
-                eprintln!("Shutting down...")
+                tracing::info!("Shutting down...")
             }
         }
 
+        // Stop accepting connections, and wait for the sessions in progress to finish
+        terminate.send(()).unwrap_or(0);
+        while let Some(result) = server_futures.next().await {
+            if let Err(e) = result {
+                tracing::error!(error = %e, "Server failed");
+            }
+        }
+
+        // Only stop the polling service once no session can be relying on it to finish a close
+        if !polling_service_stopped {
+            stop_polling.send(()).unwrap_or(());
+            report_polling_service_result(polling_service_join_handle.await);
+        }
+
+        // Deliver the events from the sessions and dispatches which have now finished
+        drop(webhooks);
+        webhook_delivery.finish().await;
+
         Ok(())
     }
 }
 
-async fn dispatch_channel(
+/// Wait for the merchant to be asked to shut down, by Ctrl-C or, on Unix, by SIGTERM.
+async fn shutdown_signal() -> Result<(), std::io::Error> {
+    #[cfg(unix)]
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    #[cfg(unix)]
+    let terminated = terminate.recv();
+    #[cfg(not(unix))]
+    let terminated = futures::future::pending::<Option<()>>();
+
+    tokio::select! {
+        result = signal::ctrl_c() => result,
+        _ = terminated => Ok(()),
+    }
+}
+
+/// Report any error which stopped the polling service.
+fn report_polling_service_result(
+    result: Result<Result<(), anyhow::Error>, tokio::task::JoinError>,
+) {
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => tracing::error!(error = %format!("{:#}", e), "Polling service failed"),
+        Err(e) => tracing::error!(error = %e, "Polling service failed"),
+    }
+}
+
+/// Recover the merchant's funds from a channel which was funded, but whose establishment stalled
+/// before the channel was activated, by initiating the expiry close flow. The customer can still
+/// respond to the expiry with their initial balance to recover their own funds.
+async fn reclaim_stalled_establishment(
     database: &dyn QueryMerchant,
     channel: &ChannelDetails,
     config: &Config,
@@ -231,55 +496,60 @@ async fn dispatch_channel(
     let tezos_client = load_tezos_client(config, &channel.channel_id, database).await?;
     let contract_state = tezos_client.get_contract_state().await?;
 
-    // The channel has not claimed funds after the expiry timeout expired
-    // The condition is
-    // - the contract is in expiry state
-    // - the contract timeout is expired
-    // - the channel status is PendingExpiry, indicating it has not yet claimed funds
-    if contract_state.status()? == ContractStatus::Expiry
-        && contract_state.timeout_expired().unwrap_or(false)
-        && channel.status == ChannelStatus::PendingExpiry
-    {
-        close::claim_expiry_funds(config, database, &channel.channel_id).await?;
-        close::finalize_expiry_close(database, &channel.channel_id).await?;
-    }
+    let action = match stalled_establish_action(contract_state.status()?, channel.status) {
+        Some(action) => action,
+        None => return Ok(()),
+    };
 
-    // The channel has not reacted to a customer posting close balances on chain
-    // The condition is
-    // - the contract is in customer close state
-    // - the channel status is either Active (if the customer initiated the close flow)
-    //   or PendingExpiry (if the merchant initiated the close flow)
-    if contract_state.status()? == ContractStatus::CustomerClose
-        && (channel.status == ChannelStatus::Active
-            || channel.status == ChannelStatus::PendingExpiry)
-    {
-        let revocation_lock = contract_state.revocation_lock()?.ok_or_else(|| {
-            anyhow::anyhow!(
-                "Failed to retrieve revocation lock from contract storage for {}",
-                channel.channel_id
-            )
-        })?;
-        let final_balances = contract_state.final_balances()?.ok_or_else(|| {
-            anyhow::anyhow!(
-                "Failed to retrieve final balances from contract storage for {}",
-                channel.channel_id
+    tracing::warn!(
+        channel_id = %channel.channel_id,
+        "Establishment of channel stalled after it was funded; initiating expiry to reclaim \
+        merchant funds"
+    );
+
+    // The merchant funding may have been confirmed without the status being updated to match
+    if action == StalledEstablishAction::RecordFundingAndExpire {
+        database
+            .compare_and_swap_channel_status(
+                &channel.channel_id,
+                &ChannelStatus::CustomerFunded,
+                &ChannelStatus::MerchantFunded,
             )
-        })?;
-        close::process_customer_close(config, database, &channel.channel_id, &revocation_lock)
             .await?;
-        close::finalize_customer_close(
-            database,
-            &channel.channel_id,
-            final_balances.customer_balance(),
-            final_balances.merchant_balance(),
-        )
-        .await?;
     }
 
-    Ok(())
+    close::expiry(config, database, &channel.channel_id).await
+}
+
+/// Recover the merchant's funds from a channel which has been active for longer than the configured
+/// `channel_expiry_after`, by initiating the expiry close flow. The customer can respond to the
+/// expiry with their latest balance to close the channel as usual.
+async fn expire_long_active_channel(
+    database: &dyn QueryMerchant,
+    channel: &ChannelDetails,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    let tezos_client = load_tezos_client(config, &channel.channel_id, database).await?;
+    let contract_state = tezos_client.get_contract_state().await?;
+
+    // If the customer has already started closing the channel, let the chain watcher respond to
+    // that instead. If they do so after this check, the expiry fails and is reverted.
+    if contract_state.status()? != ContractStatus::Open {
+        return Ok(());
+    }
+
+    tracing::warn!(
+        channel_id = %channel.channel_id,
+        "Channel has been active for longer than the configured `channel_expiry_after`; \
+        initiating expiry to reclaim merchant funds"
+    );
+
+    close::expiry(config, database, &channel.channel_id).await
 }
 
 pub async fn main_with_cli(cli: Cli) -> Result<(), anyhow::Error> {
+    logging::init(cli.log_level.as_deref())?;
+
     let config_path = cli.config.ok_or_else(config_path).or_else(identity)?;
     let config = Config::load(&config_path).map(|result| {
         result.with_context(|| {
@@ -290,8 +560,19 @@ pub async fn main_with_cli(cli: Cli) -> Result<(), anyhow::Error> {
         })
     });
 
+    if cli.version {
+        let database = config.await.ok().map(|config| config.database);
+        BuildInfo::collect(database.as_ref())
+            .await
+            .print(cli.json)?;
+        return Ok(());
+    }
+    let merchant = cli
+        .merchant
+        .ok_or_else(|| anyhow::anyhow!("No command given; see --help for usage"))?;
+
     use cli::Merchant::*;
-    match cli.merchant {
+    match merchant {
         Configure(cli::Configure { .. }) => {
             drop(config);
             tokio::task::spawn_blocking(|| Ok(edit::edit_file(config_path)?)).await?
@@ -300,12 +581,15 @@ pub async fn main_with_cli(cli: Cli) -> Result<(), anyhow::Error> {
         Show(show) => show.run(config.await?).await,
         Run(run) => run.run(config.await?).await,
         Close(close) => close.run(config.await?).await,
+        BlockChannel(block_channel) => block_channel.run(config.await?).await,
+        History(history) => history.run(config.await?).await,
+        Reconcile(reconcile) => reconcile.run(config.await?).await,
     }
 }
 
 /// Connect to the database specified by the configuration.
 pub async fn database(config: &Config) -> Result<Arc<dyn QueryMerchant>, anyhow::Error> {
-    let database = match config.database {
+    let database: Arc<dyn QueryMerchant> = match config.database {
         DatabaseLocation::Ephemeral => Arc::new(
             SqlitePool::connect("file::memory:")
                 .await
@@ -316,10 +600,10 @@ pub async fn database(config: &Config) -> Result<Arc<dyn QueryMerchant>, anyhow:
             conn.migrate().await?;
             conn
         }
-        DatabaseLocation::Postgres(_) => {
-            return Err(anyhow::anyhow!(
-                "Postgres database support is not yet implemented"
-            ))
+        DatabaseLocation::Postgres(ref uri) => {
+            let conn = connect_postgres(uri).await?;
+            conn.migrate().await?;
+            conn
         }
     };
     Ok(database)
@@ -333,9 +617,9 @@ pub async fn load_tezos_client(
     let contract_id = database.contract_details(channel_id).await?;
 
     Ok(TezosClient {
-        uri: Some(config.tezos_uri.clone()),
+        uri: Some(config.tezos_network()?.uri()),
         contract_id,
-        client_key_pair: TezosKeyMaterial::read_key_pair(&config.tezos_account)?,
+        client_signer: Arc::new(TezosKeyMaterial::read_key_pair(&config.tezos_account)?),
         confirmation_depth: config.confirmation_depth,
         self_delay: config.self_delay,
     })
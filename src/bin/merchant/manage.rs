@@ -1,9 +1,9 @@
-use super::{database, Command};
+use super::{close::expiry, database, load_tezos_client, Command};
 use serde_json::json;
 use zeekoe::{
     amount::{Amount, XTZ},
     merchant::{
-        cli::{List, Show},
+        cli::{BlockChannel, History, List, Show},
         Config,
     },
 };
@@ -11,7 +11,9 @@ use {
     anyhow::Context,
     async_trait::async_trait,
     comfy_table::{Cell, Table},
-    std::convert::TryInto,
+    std::{convert::TryInto, time::SystemTime},
+    tezedge::crypto::ToBase58Check,
+    zkabacus_crypto::ChannelId,
 };
 
 #[async_trait]
@@ -20,7 +22,13 @@ impl Command for List {
         let database = database(&config)
             .await
             .context("Failed to connect to local database")?;
-        let channels = database.get_channels().await?;
+        let channels = match &self.status {
+            Some(status) => database.channels_with_status(status).await?,
+            None => database.get_channels().await?,
+        };
+
+        // TODO: don't hard-code XTZ here, instead store currency in database
+        let amount = |b: u64| Amount::from_minor_units_of_currency(b.try_into().unwrap(), XTZ);
 
         if self.json {
             let mut output = Vec::new();
@@ -29,19 +37,35 @@ impl Command for List {
                     "channel_id": format!("{}", channel.channel_id),
                     "contract_id": format!("{}", channel.contract_id),
                     "status": format!("{}", channel.status),
+                    "merchant_deposit": format!("{}", amount(channel.merchant_deposit.into_inner())),
+                    "customer_deposit": format!("{}", amount(channel.customer_deposit.into_inner())),
+                    "last_activity": time(channel.status_updated_at),
+                    "blocked": channel.blocked,
                 }));
             }
             println!("{}", json!(output).to_string());
         } else {
             let mut table = Table::new();
             table.load_preset(comfy_table::presets::UTF8_FULL);
-            table.set_header(vec!["Channel ID", "Contract ID", "Status"]);
+            table.set_header(vec![
+                "Channel ID",
+                "Contract ID",
+                "Status",
+                "Merchant Deposit",
+                "Customer Deposit",
+                "Last Activity",
+                "Blocked",
+            ]);
 
             for channel in channels {
                 table.add_row(vec![
-                    Cell::new(channel.channel_id),
+                    Cell::new(abbreviate(&channel.channel_id)),
                     Cell::new(channel.contract_id),
                     Cell::new(channel.status),
+                    Cell::new(amount(channel.merchant_deposit.into_inner())),
+                    Cell::new(amount(channel.customer_deposit.into_inner())),
+                    Cell::new(time(channel.status_updated_at)),
+                    Cell::new(if channel.blocked { "yes" } else { "no" }),
                 ]);
             }
 
@@ -58,17 +82,62 @@ impl Command for Show {
             .await
             .context("Failed to connect to local database")?;
         let details = database.get_channel_details_by_prefix(&self.prefix).await?;
+        let channel_id = &details.channel_id;
+        let funding_address = database
+            .customer_funding_address(channel_id)
+            .await?
+            .map(|address| address.to_base58check());
+        let public_key = database
+            .customer_public_key(channel_id)
+            .await?
+            .map(|public_key| public_key.to_base58check());
+        let funding_operation = database.customer_funding(channel_id).await?;
+        let dispute_operation = database.dispute_operation(channel_id).await?;
+        let attention_reason = database.attention_reason(channel_id).await?;
+
+        // Only touch the chain if asked to
+        let contract_state = if self.verify_chain {
+            let tezos_client = load_tezos_client(&config, channel_id, database.as_ref()).await?;
+            Some(
+                tezos_client
+                    .get_contract_state()
+                    .await
+                    .context("Failed to fetch contract state")?,
+            )
+        } else {
+            None
+        };
 
         // TODO: don't hard-code XTZ here, instead store currency in database
         let amount = |b: u64| Amount::from_minor_units_of_currency(b.try_into().unwrap(), XTZ);
 
         if self.json {
+            let contract_state = match &contract_state {
+                None => None,
+                Some(state) => Some(json!({
+                    "status": format!("{:?}", state.status()?),
+                    "merchant_balance": format!("{}", amount(state.merchant_balance()?.into_inner())),
+                    "customer_balance": format!("{}", amount(state.customer_balance()?.into_inner())),
+                    "delay_expiry": state.delay_expiry().map(time),
+                })),
+            };
             println!("{}", json!({
                 "channel_id": format!("{}", details.channel_id),
                 "status": format!("{}", details.status),
                 "contract_id": format!("{}", details.contract_id),
                 "merchant_deposit": format!("{}", amount(details.merchant_deposit.into_inner())),
                 "customer_deposit": format!("{}", amount(details.customer_deposit.into_inner())),
+                "last_activity": time(details.status_updated_at),
+                "blocked": details.blocked,
+                "customer_funding_address": funding_address,
+                "customer_public_key": public_key,
+                "customer_funding_operation": funding_operation.as_ref().map(|operation| json!({
+                    "hash": operation.hash(),
+                    "level": operation.level(),
+                })),
+                "dispute_operation": dispute_operation,
+                "needs_attention": attention_reason,
+                "contract_state": contract_state,
             }).to_string());
         } else {
             let mut table = Table::new();
@@ -88,9 +157,151 @@ impl Command for Show {
                 Cell::new("Customer Deposit"),
                 Cell::new(amount(details.customer_deposit.into_inner())),
             ]);
+            table.add_row(vec![
+                Cell::new("Last Activity"),
+                Cell::new(time(details.status_updated_at)),
+            ]);
+            table.add_row(vec![
+                Cell::new("Blocked"),
+                Cell::new(if details.blocked { "yes" } else { "no" }),
+            ]);
+            table.add_row(vec![
+                Cell::new("Customer Funding Address"),
+                Cell::new(funding_address.unwrap_or_default()),
+            ]);
+            table.add_row(vec![
+                Cell::new("Customer Public Key"),
+                Cell::new(public_key.unwrap_or_default()),
+            ]);
+            table.add_row(vec![
+                Cell::new("Customer Funding Operation"),
+                Cell::new(funding_operation.map_or_else(String::new, |operation| {
+                    format!("{} (level {})", operation.hash(), operation.level())
+                })),
+            ]);
+            table.add_row(vec![
+                Cell::new("Dispute Operation"),
+                Cell::new(dispute_operation.unwrap_or_default()),
+            ]);
+            table.add_row(vec![
+                Cell::new("Needs Attention"),
+                Cell::new(attention_reason.unwrap_or_default()),
+            ]);
+
+            if let Some(state) = contract_state {
+                table.add_row(vec![
+                    Cell::new("On-Chain Status"),
+                    Cell::new(format!("{:?}", state.status()?)),
+                ]);
+                table.add_row(vec![
+                    Cell::new("On-Chain Merchant Balance"),
+                    Cell::new(amount(state.merchant_balance()?.into_inner())),
+                ]);
+                table.add_row(vec![
+                    Cell::new("On-Chain Customer Balance"),
+                    Cell::new(amount(state.customer_balance()?.into_inner())),
+                ]);
+                table.add_row(vec![
+                    Cell::new("On-Chain Delay Expiry"),
+                    Cell::new(state.delay_expiry().map(time).unwrap_or_default()),
+                ]);
+            }
+
+            println!("{}", table);
+        }
+        Ok(())
+    }
+}
+
+/// Abbreviate a channel ID for display in a table; it can still be given in full to `show`.
+pub(crate) fn abbreviate(channel_id: &ChannelId) -> String {
+    let channel_id = channel_id.to_string();
+    match channel_id.char_indices().nth(12) {
+        Some((end, _)) => format!("{}…", &channel_id[..end]),
+        None => channel_id,
+    }
+}
+
+/// Format a time for display.
+fn time(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+#[async_trait]
+impl Command for History {
+    async fn run(self, config: Config) -> Result<(), anyhow::Error> {
+        let database = database(&config)
+            .await
+            .context("Failed to connect to local database")?;
+        let payments = database.payment_history(self.limit, self.offset).await?;
+
+        // TODO: don't hard-code XTZ here, instead store currency in database
+        let amount = |a: i64| Amount::from_minor_units_of_currency(a, XTZ);
+
+        if self.json {
+            let mut output = Vec::new();
+            for payment in payments {
+                output.push(json!({
+                    "amount": format!("{}", amount(payment.amount)),
+                    "note": payment.note,
+                    "approver": payment.approver,
+                    "response_note": payment.response_note,
+                    "response_url": payment.response_url,
+                    "outcome": format!("{}", payment.outcome),
+                    "failure_reason": payment.failure_reason,
+                    "created_at": time(payment.created_at),
+                }));
+            }
+            println!("{}", json!(output).to_string());
+        } else {
+            let mut table = Table::new();
+            table.load_preset(comfy_table::presets::UTF8_FULL);
+            table.set_header(vec![
+                "Time", "Amount", "Note", "Approver", "Response", "Outcome", "Reason",
+            ]);
+
+            for payment in payments {
+                table.add_row(vec![
+                    Cell::new(time(payment.created_at)),
+                    Cell::new(amount(payment.amount)),
+                    Cell::new(payment.note),
+                    Cell::new(payment.approver),
+                    Cell::new(
+                        payment
+                            .response_note
+                            .or(payment.response_url)
+                            .unwrap_or_default(),
+                    ),
+                    Cell::new(payment.outcome),
+                    Cell::new(payment.failure_reason.unwrap_or_default()),
+                ]);
+            }
 
             println!("{}", table);
         }
         Ok(())
     }
 }
+
+#[async_trait]
+impl Command for BlockChannel {
+    async fn run(self, config: Config) -> Result<(), anyhow::Error> {
+        let database = database(&config)
+            .await
+            .context("Failed to connect to local database")?;
+
+        database
+            .set_channel_blocked(&self.channel, true)
+            .await
+            .context("Failed to block channel")?;
+        println!("Blocked channel {}", self.channel);
+
+        if self.close {
+            // The chain watcher claims the channel's funds once the expiry timeout has passed
+            expiry(&config, database.as_ref(), &self.channel).await?;
+            println!("Initiated expiry for channel {}", self.channel);
+        }
+
+        Ok(())
+    }
+}
@@ -1,6 +1,10 @@
 use zeekoe::{
-    merchant::{Chan, Config},
-    protocol,
+    escrow::tezos,
+    merchant::{config::Service, Chan, Config},
+    protocol::{
+        self,
+        parameters::{self, ChainParameters},
+    },
 };
 
 pub struct Parameters;
@@ -9,6 +13,7 @@ impl Parameters {
     pub async fn run(
         &self,
         config: &Config,
+        service: &Service,
         merchant_config: &zkabacus_crypto::merchant::Config,
         chan: Chan<protocol::Parameters>,
     ) -> Result<(), anyhow::Error> {
@@ -18,9 +23,32 @@ impl Parameters {
 
         // Extract public parts of the tezos parameters
         let tezos_key_material = config.load_tezos_key_material()?;
-        let tezos_public_key = tezos_key_material.into_keypair().0;
+        let tezos_public_key = tezos_key_material.public_key().clone();
         let tezos_address = tezos_public_key.hash();
 
+        // The contract parameters that every channel must use
+        let chain_parameters = ChainParameters {
+            self_delay: config.self_delay,
+            confirmation_depth: config.confirmation_depth,
+        };
+
+        // The limits and policies the customer's requests will be held to
+        let policy = config.merchant_policy(service);
+
+        // Sign all of the parameters together, so the customer can check that none of them was
+        // substituted in transit
+        let signed_parameters = parameters::signed_message(
+            &public_key,
+            &commitment_parameters,
+            &range_constraint_parameters,
+            &tezos_address,
+            &tezos_public_key,
+            &chain_parameters,
+            &policy,
+        );
+        let parameters_signature =
+            tezos::sign_parameters(&tezos_key_material, &signed_parameters).await?;
+
         // Send those parameters to the customer
         chan.send(public_key)
             .await?
@@ -32,6 +60,12 @@ impl Parameters {
             .await?
             .send(tezos_public_key)
             .await?
+            .send(chain_parameters)
+            .await?
+            .send(policy)
+            .await?
+            .send(parameters_signature)
+            .await?
             .close();
         Ok(())
     }
@@ -1,21 +1,30 @@
-use {anyhow::Context, rand::rngs::StdRng, url::Url};
+use {
+    anyhow::Context,
+    rand::{rngs::StdRng, SeedableRng},
+    std::time::SystemTime,
+};
 
 use zeekoe::{
     abort,
     merchant::{
+        approve::{self, ApproverClient, PaymentApproval},
         config::Service,
-        database::{QueryMerchant, QueryMerchantExt},
+        database::{
+            self as merchant_database, PaySession, PaymentOutcome, PaymentRecord, QueryMerchant,
+            QueryMerchantExt,
+        },
         server::SessionKey,
+        webhook::{WebhookEvent, Webhooks},
         Chan, Config,
     },
-    offer_abort, proceed,
+    metrics, offer_abort, proceed,
     protocol::{self, pay, Party::Merchant},
     timeout::WithTimeout,
 };
 
-use zkabacus_crypto::{Context as ProofContext, PaymentAmount};
+use zkabacus_crypto::PaymentAmount;
 
-use super::{approve, database};
+use super::database;
 
 pub struct Pay;
 
@@ -23,7 +32,8 @@ impl Pay {
     pub async fn run(
         &self,
         rng: StdRng,
-        client: &reqwest::Client,
+        client: &ApproverClient,
+        webhooks: &Webhooks,
         config: &Config,
         service: &Service,
         session_key: SessionKey,
@@ -31,85 +41,291 @@ impl Pay {
     ) -> Result<(), anyhow::Error> {
         let database = database(config).await?;
 
+        // Keep a transcript of the session, to bind the payment proof to what was negotiated
+        let mut transcript = protocol::Transcript::new(&session_key.to_bytes());
+
         // Get the payment amount and context note from the customer
         let (payment_amount, chan) = chan
             .recv()
             .with_timeout(service.message_timeout)
             .await
             .context("Payment timed out while receiving payment amount")??;
+        transcript.append(&payment_amount);
         let (payment_note, chan) = chan
             .recv()
             .with_timeout(service.message_timeout)
             .await
             .context("Payment timed out while receiving payment note")??;
+        transcript.append(&payment_note);
+        let (payment_id, chan) = chan
+            .recv()
+            .with_timeout(service.message_timeout)
+            .await
+            .context("Payment timed out while receiving payment ID")??;
+        transcript.append(&payment_id);
+
+        // Keep a record of the payment for the payment history, which is updated once it
+        // completes
+        let mut record = PaymentRecord {
+            amount: payment_amount.to_i64(),
+            note: payment_note.clone(),
+            approver: service.approve.to_string(),
+            response_note: None,
+            response_url: None,
+            outcome: PaymentOutcome::Failed,
+            failure_reason: None,
+            created_at: SystemTime::now(),
+            payment_id: Some(payment_id),
+        };
 
-        // Query approver service to determine whether to allow the payment
-        let (response_url, chan) =
-            approve_payment(payment_amount, payment_note, chan, client, service).await?;
+        // Refuse an over-long note before doing anything with it, then refuse a payment the
+        // customer already made, then check it against the merchant's limits, then query the
+        // approver service to determine whether to allow it
+        let chan = match check_note(service, &payment_note, chan).await {
+            Ok(chan) => chan,
+            Err(err) => return Err(record_incomplete(database.as_ref(), record, err).await),
+        };
+        let chan = match check_duplicate(database.as_ref(), &payment_id, chan).await {
+            Ok(chan) => chan,
+            Err(err) => return Err(record_incomplete(database.as_ref(), record, err).await),
+        };
+        let chan = match check_limits(database.as_ref(), config, &payment_amount, chan).await {
+            Ok(chan) => chan,
+            Err(err) => return Err(record_incomplete(database.as_ref(), record, err).await),
+        };
+        let (approval, chan) =
+            match approve_payment(payment_amount, payment_note, chan, client, service).await {
+                Ok(approved) => approved,
+                Err(err) => return Err(record_incomplete(database.as_ref(), record, err).await),
+            };
+        record.response_note = approval.response_note.clone();
+        record.response_url = approval.response_url.as_ref().map(|url| url.to_string());
 
         // Run the zkAbacus.Pay protocol
         // Timeout is set to 10 messages, which includes all sent & received messages and aborts
-        let maybe_chan = zkabacus_pay(rng, database.as_ref(), session_key, chan, payment_amount)
-            .with_timeout(10 * service.message_timeout)
-            .await
-            .context("Payment timed out while updating channel status")?;
+        let maybe_chan = zkabacus_pay(
+            rng,
+            database.as_ref(),
+            transcript,
+            chan,
+            payment_amount,
+            &mut record,
+        )
+        .with_timeout(10 * service.message_timeout)
+        .await
+        .context("Payment timed out while updating channel status");
+
+        // A completed payment was already recorded along with its revocation, so only record
+        // payments which didn't complete
+        let incomplete = record.outcome != PaymentOutcome::Completed;
+        if !incomplete {
+            webhooks.notify(WebhookEvent::payment_received(record.amount));
+        }
+        let maybe_chan = match maybe_chan {
+            Err(err) if incomplete => {
+                return Err(record_incomplete(database.as_ref(), record, err).await)
+            }
+            Err(err) => return Err(err),
+            Ok(Err(err)) if incomplete => {
+                Err(record_incomplete(database.as_ref(), record, err).await)
+            }
+            Ok(maybe_chan) => maybe_chan,
+        };
 
-        provide_service(response_url, maybe_chan, client).await?;
+        provide_service(approval, payment_id, maybe_chan, client).await?;
 
         Ok(())
     }
 }
 
+/// Pay sessions, one after another over the same connection, for as long as the customer chooses
+/// to make another payment.
+///
+/// Each payment is approved on its own, and counts towards the merchant's limits like any other.
+pub struct Repeated;
+
+impl Repeated {
+    pub async fn run(
+        &self,
+        client: &ApproverClient,
+        webhooks: &Webhooks,
+        config: &Config,
+        service: &Service,
+        session_key: SessionKey,
+        chan: Chan<pay::Repeated>,
+    ) -> Result<(), anyhow::Error> {
+        // The customer may wait between payments, but only for so long
+        let max_wait = service.message_timeout + service.max_repeat_interval;
+        pay::serve_repeated(chan, max_wait, move |chan| {
+            metrics::session(
+                "pay",
+                Pay.run(
+                    StdRng::from_entropy(),
+                    client,
+                    webhooks,
+                    config,
+                    service,
+                    session_key.clone(),
+                    chan,
+                ),
+            )
+        })
+        .await
+    }
+}
+
+/// Record a payment which didn't complete in the payment history, along with the error which
+/// stopped it, returning the error.
+///
+/// The payment is recorded as rejected if it was a duplicate or the merchant's limits or approver
+/// refused it, and as failed otherwise. Failing to record it doesn't stop the session from ending as it would have.
+async fn record_incomplete(
+    database: &dyn QueryMerchant,
+    mut record: PaymentRecord,
+    error: anyhow::Error,
+) -> anyhow::Error {
+    record.outcome = match error.downcast_ref::<pay::Error>() {
+        Some(pay::Error::Rejected(_))
+        | Some(pay::Error::LimitExceeded(_))
+        | Some(pay::Error::NoteTooLong(_))
+        | Some(pay::Error::DuplicatePayment(_)) => PaymentOutcome::Rejected,
+        _ => PaymentOutcome::Failed,
+    };
+    record.failure_reason = Some(format!("{:#}", error));
+
+    metrics::payment(&record);
+    if let Err(err) = database.record_payment(&record).await {
+        tracing::warn!(error = %err, "Failed to record payment in history");
+    }
+    error
+}
+
+/// Check the payment note against the service's limit, so that an over-long note is never passed
+/// on to the approver. If it is too long, terminate the pay session.
+async fn check_note(
+    service: &Service,
+    payment_note: &str,
+    chan: Chan<pay::GetPaymentApproval>,
+) -> Result<Chan<pay::GetPaymentApproval>, anyhow::Error> {
+    if let Err(too_long) = protocol::check_note_length(payment_note, service.max_note_length) {
+        abort!(in chan return pay::Error::NoteTooLong(too_long));
+    }
+    Ok(chan)
+}
+
+/// Check that the merchant hasn't already completed a payment with the same payment ID, which the
+/// customer may be retrying without knowing that it completed. If it has, terminate the pay
+/// session.
+///
+/// Payments with the same ID which didn't complete don't stop the customer retrying it.
+async fn check_duplicate(
+    database: &dyn QueryMerchant,
+    payment_id: &pay::PaymentId,
+    chan: Chan<pay::GetPaymentApproval>,
+) -> Result<Chan<pay::GetPaymentApproval>, anyhow::Error> {
+    let completed = database
+        .payments_with_id(payment_id)
+        .await
+        .context("Failed to look up payment ID in database")?
+        .iter()
+        .any(|payment| payment.outcome == PaymentOutcome::Completed);
+    if completed {
+        abort!(in chan return pay::Error::DuplicatePayment(*payment_id));
+    }
+    Ok(chan)
+}
+
+/// Check the payment amount against the merchant's caps, then record the payment, only if it fits
+/// within the merchant's rate and volume limits. If it doesn't fit, terminate the pay session.
+///
+/// The payment is recorded whether or not it goes on to succeed, so that failed attempts count
+/// towards the rate limit too.
+async fn check_limits(
+    database: &dyn QueryMerchant,
+    config: &Config,
+    payment_amount: &PaymentAmount,
+    chan: Chan<pay::GetPaymentApproval>,
+) -> Result<Chan<pay::GetPaymentApproval>, anyhow::Error> {
+    let limits = config.payment_limits();
+    let amount = payment_amount.to_i64();
+
+    if let Err(exceeded) = limits.check_amount(amount) {
+        abort!(in chan return pay::Error::LimitExceeded(exceeded));
+    }
+
+    match database
+        .insert_payment_event(amount, SystemTime::now(), &limits)
+        .await
+    {
+        Ok(()) => Ok(chan),
+        Err(merchant_database::Error::PaymentLimitExceeded(exceeded)) => {
+            abort!(in chan return pay::Error::LimitExceeded(exceeded));
+        }
+        Err(err) => Err(err).context("Failed to record payment event in database"),
+    }
+}
+
 /// Query the approver service using payment details provided by the customer to determine whether
 /// to allow the payment. If not, terminate the pay session.
 async fn approve_payment(
     payment_amount: PaymentAmount,
     payment_note: String,
     chan: Chan<pay::GetPaymentApproval>,
-    client: &reqwest::Client,
+    client: &ApproverClient,
     service: &Service,
-) -> Result<(Option<Url>, Chan<pay::CustomerStartPayment>), anyhow::Error> {
+) -> Result<(PaymentApproval, Chan<pay::CustomerStartPayment>), anyhow::Error> {
     // Determine whether to accept the payment
-    let response_url =
-        match approve::payment(client, &service.approve, &payment_amount, payment_note).await {
-            Ok(response_url) => response_url,
-            Err(approval_error) => {
-                // If the payment was not approved, indicate to the client why
-                let error =
-                    pay::Error::Rejected(approval_error.unwrap_or_else(|| "internal error".into()));
-                abort!(in chan return error);
-            }
-        };
+    let approval = match approve::payment(
+        client,
+        &service.approve,
+        service.legacy_approver_requests,
+        &payment_amount,
+        payment_note,
+    )
+    .await
+    {
+        Ok(approval) => approval,
+        Err(approval_error) => {
+            // If the payment was not approved, indicate to the client why
+            let error =
+                pay::Error::Rejected(approval_error.unwrap_or_else(|| "internal error".into()));
+            abort!(in chan return error);
+        }
+    };
 
     proceed!(in chan);
 
-    Ok((response_url, chan))
+    Ok((approval, chan))
 }
 
 /// Inform the approver service whether the payment succeeded and pass the resulting fulfillment
 /// to the customer.
 async fn provide_service(
-    response_url: Option<Url>,
+    approval: PaymentApproval,
+    payment_id: pay::PaymentId,
     maybe_chan: Result<Chan<pay::MerchantProvideService>, anyhow::Error>,
-    client: &reqwest::Client,
+    client: &ApproverClient,
 ) -> Result<(), anyhow::Error> {
     match maybe_chan {
         Ok(chan) => {
-            // Send the response note (i.e. the fulfillment of the service) and close the
-            // connection to the customer
-            let response_note = approve::payment_success(client, response_url).await;
+            // Echo the payment ID, send the response note (i.e. the fulfillment of the service),
+            // and close the connection to the customer
+            let response_note = approve::payment_success(client, approval).await;
             let (note, result) = match response_note {
                 Err(err) => (None, Err(err)),
                 Ok(o) => (o, Ok(())),
             };
-            chan.send(note)
+            chan.send(Some(payment_id))
+                .await
+                .context("Failed to send payment ID")?
+                .send(note)
                 .await
                 .context("Failed to send response note")?
                 .close();
             result
         }
         Err(err) => {
-            approve::failure(client, response_url).await;
+            approve::failure(client, approval.response_url).await;
             Err(err)
         }
     }
@@ -119,26 +335,30 @@ async fn provide_service(
 async fn zkabacus_pay(
     mut rng: StdRng,
     database: &dyn QueryMerchant,
-    session_key: SessionKey,
+    transcript: protocol::Transcript,
     chan: Chan<pay::CustomerStartPayment>,
     payment_amount: PaymentAmount,
+    record: &mut PaymentRecord,
 ) -> Result<Chan<pay::MerchantProvideService>, anyhow::Error> {
     // Retrieve zkAbacus merchant config
     let merchant_config = database.fetch_or_create_config(&mut rng).await?;
 
-    // Generate the shared context for the proof
-    let context = ProofContext::new(&session_key.to_bytes());
+    // Generate the shared context for the proof from the transcript so far
+    let context = transcript.context();
 
     // Get the nonce and pay proof (this is the start of zkAbacus.Pay)
     let (nonce, chan) = chan.recv().await.context("Failed to receive nonce")?;
     let (pay_proof, chan) = chan.recv().await.context("Failed to receive pay proof")?;
 
+    // Keep what's needed to resume the session along with the nonce, in case it is interrupted
+    let session = PaySession::new(&transcript, payment_amount, record, &pay_proof);
+
     if let Some((unrevoked, closing_signature)) =
         merchant_config.allow_payment(&mut rng, payment_amount, &nonce, pay_proof, &context)
     {
         // Proof verified, so check the nonce
         if !database
-            .insert_nonce(&nonce)
+            .insert_nonce_for_session(&nonce, &session)
             .await
             .context("Failed to insert nonce in database")?
         {
@@ -159,20 +379,25 @@ async fn zkabacus_pay(
             let (revocation_pair, chan) = chan
                 .recv()
                 .await
-                .context("Failed to send revocation pair")?;
+                .context("Failed to receive revocation pair")?;
 
             let (revocation_blinding_factor, chan) = chan
                 .recv()
                 .await
-                .context("Failed to send revocation blinding factor")?;
+                .context("Failed to receive revocation blinding factor")?;
 
             // Validate the received information
             if let Ok(pay_token) =
                 unrevoked.complete_payment(&mut rng, &revocation_pair, &revocation_blinding_factor)
             {
-                // Check to see if the revocation lock was already present in the database
+                // Check to see if the revocation lock was already present in the database,
+                // recording the payment as completed along with the revocation if it wasn't
+                let completed = PaymentRecord {
+                    outcome: PaymentOutcome::Completed,
+                    ..record.clone()
+                };
                 let prior_revocations = database
-                    .insert_revocation_pair(&revocation_pair)
+                    .insert_revocation_pair_for_payment(&revocation_pair, &completed)
                     .await
                     .context("Failed to insert revocation lock/secret pair in database")?;
 
@@ -180,6 +405,8 @@ async fn zkabacus_pay(
                 if !prior_revocations.is_empty() {
                     abort!(in chan return pay::Error::ReusedRevocationLock);
                 }
+                *record = completed;
+                metrics::payment(record);
 
                 // The revealed information was correct; issue the pay token
                 proceed!(in chan);
@@ -202,3 +429,143 @@ async fn zkabacus_pay(
         abort!(in chan return pay::Error::InvalidPayProof);
     }
 }
+
+pub struct Resume;
+
+impl Resume {
+    pub async fn run(
+        &self,
+        rng: StdRng,
+        client: &ApproverClient,
+        webhooks: &Webhooks,
+        config: &Config,
+        service: &Service,
+        chan: Chan<pay::Resume>,
+    ) -> Result<(), anyhow::Error> {
+        let database = database(config).await?;
+
+        // Timeout is set to 10 messages, as for the zkAbacus.Pay protocol it replays
+        resume_payment(rng, database.as_ref(), client, webhooks, service, chan)
+            .with_timeout(10 * service.message_timeout)
+            .await
+            .context("Resumed payment timed out")?
+    }
+}
+
+/// Resume a payment whose session was interrupted after the customer started it, replaying the
+/// closing signature and pay token for the nonce and pay proof of the original session.
+///
+/// The approver isn't asked again, but having been told that the original session failed, is told
+/// that the payment succeeded after all, using the approval the original session received.
+async fn resume_payment(
+    mut rng: StdRng,
+    database: &dyn QueryMerchant,
+    client: &ApproverClient,
+    webhooks: &Webhooks,
+    service: &Service,
+    chan: Chan<pay::Resume>,
+) -> Result<(), anyhow::Error> {
+    let merchant_config = database.fetch_or_create_config(&mut rng).await?;
+
+    // Get the nonce and pay proof the customer sent in the original session
+    let (nonce, chan) = chan.recv().await.context("Failed to receive nonce")?;
+    let (pay_proof, chan) = chan.recv().await.context("Failed to receive pay proof")?;
+
+    // Only the exact payment the nonce was spent on can be resumed, so that resuming it can't
+    // produce any state but the one the customer was originally paying towards
+    let session = match database
+        .pay_session(&nonce, SystemTime::now())
+        .await
+        .context("Failed to look up pay session in database")?
+    {
+        Some(session) if session.is_for(&pay_proof) => session,
+        _ => abort!(in chan return pay::Error::NoPaymentToResume),
+    };
+    let payment_amount = match session.payment_amount() {
+        Some(payment_amount) => payment_amount,
+        None => abort!(in chan return pay::Error::NoPaymentToResume),
+    };
+    let approval = PaymentApproval {
+        response_url: session
+            .response_url
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .context("Failed to parse response URL of pay session")?,
+        response_note: session.response_note.clone(),
+    };
+    let (unrevoked, closing_signature) = match merchant_config.allow_payment(
+        &mut rng,
+        payment_amount,
+        &nonce,
+        pay_proof,
+        &session.context(),
+    ) {
+        Some(allowed) => allowed,
+        None => abort!(in chan return pay::Error::InvalidPayProof),
+    };
+
+    // Replay the rest of the protocol, as in the original session
+    proceed!(in chan);
+    let chan = chan
+        .send(closing_signature)
+        .await
+        .context("Failed to send closing signature")?;
+    offer_abort!(in chan as Merchant);
+    let (revocation_pair, chan) = chan
+        .recv()
+        .await
+        .context("Failed to receive revocation pair")?;
+    let (revocation_blinding_factor, chan) = chan
+        .recv()
+        .await
+        .context("Failed to receive revocation blinding factor")?;
+    let pay_token =
+        match unrevoked.complete_payment(&mut rng, &revocation_pair, &revocation_blinding_factor) {
+            Ok(pay_token) => pay_token,
+            Err(_) => abort!(in chan return pay::Error::InvalidRevocationOpening),
+        };
+
+    // The revocation pair opens the lock committed to by this very pay proof, so it can only have
+    // been revealed before by the original session; if it wasn't, the payment completes now, and
+    // its record from the original session is updated to say so
+    let record = PaymentRecord {
+        amount: session.amount,
+        note: session.note,
+        approver: service.approve.to_string(),
+        response_note: session.response_note,
+        response_url: session.response_url,
+        outcome: PaymentOutcome::Completed,
+        failure_reason: None,
+        created_at: session.created_at,
+        payment_id: session.payment_id,
+    };
+    let prior_revocations = database
+        .insert_revocation_pair_for_payment(&revocation_pair, &record)
+        .await
+        .context("Failed to insert revocation lock/secret pair in database")?;
+    if prior_revocations.is_empty() {
+        metrics::payment(&record);
+        webhooks.notify(WebhookEvent::payment_received(record.amount));
+    }
+
+    // Tell the approver the payment succeeded, and provide the service as the original session
+    // would have
+    let (note, result) = match approve::payment_success(client, approval).await {
+        Err(err) => (None, Err(err)),
+        Ok(note) => (note, Ok(())),
+    };
+    proceed!(in chan);
+    chan.send(pay_token)
+        .await
+        .context("Failed to send pay token")?
+        .send(record.payment_id)
+        .await
+        .context("Failed to send payment ID")?
+        .send(note)
+        .await
+        .context("Failed to send response note")?
+        .close();
+
+    result
+}
@@ -0,0 +1,290 @@
+//! Reconciling the merchant's record of each channel with the state of its contract on chain.
+use {
+    anyhow::Context,
+    async_trait::async_trait,
+    comfy_table::{Cell, Table},
+    serde_json::json,
+};
+
+use super::{close, database, load_tezos_client, manage::abbreviate, Command};
+
+use zeekoe::{
+    escrow::{
+        tezos::{self, ContractState},
+        types::ContractStatus,
+    },
+    merchant::{
+        cli::Reconcile,
+        database::{ChannelDetails, QueryMerchant},
+        webhook::{WebhookEvent, Webhooks},
+        Config,
+    },
+    protocol::{
+        close::{reconcile, MerchantChainAction, Reconciliation},
+        ChannelStatus,
+    },
+};
+
+use zkabacus_crypto::ChannelId;
+
+/// The reason a channel is flagged for attention when its contract can't be found on chain.
+const CONTRACT_NOT_FOUND: &str = "contract not found on chain";
+
+/// A channel whose stored status didn't match its contract, and what was done to correct it.
+pub struct Discrepancy {
+    pub channel_id: ChannelId,
+    pub channel_status: ChannelStatus,
+    /// The status of the contract, or `None` if it couldn't be found.
+    pub contract_status: Option<ContractStatus>,
+    pub reconciliation: Reconciliation,
+    pub outcome: Result<(), anyhow::Error>,
+}
+
+impl Discrepancy {
+    fn contract_status(&self) -> String {
+        self.contract_status
+            .map_or_else(|| "not found".to_string(), |status| format!("{:?}", status))
+    }
+
+    fn outcome(&self) -> String {
+        match &self.outcome {
+            Ok(()) => "done".to_string(),
+            Err(e) => format!("failed: {:#}", e),
+        }
+    }
+}
+
+#[async_trait]
+impl Command for Reconcile {
+    async fn run(self, config: Config) -> Result<(), anyhow::Error> {
+        // Make sure the node can be reached, so contracts aren't flagged for being missing
+        // when the chain is merely unavailable
+        tezos::check_network(&config.tezos_network()?).await?;
+
+        let database = database(&config)
+            .await
+            .context("Failed to connect to local database")?;
+        let (webhooks, webhook_delivery) = Webhooks::start(
+            config.webhooks.as_ref(),
+            database.clone(),
+            reqwest::Client::new(),
+        )
+        .await
+        .context("Failed to start webhooks")?;
+
+        let mut discrepancies = Vec::new();
+        for channel in database.get_channels().await? {
+            if let Some(discrepancy) =
+                reconcile_channel(database.as_ref(), &webhooks, &channel, &config)
+                    .await
+                    .with_context(|| format!("Failed to reconcile {}", channel.channel_id))?
+            {
+                discrepancies.push(discrepancy);
+            }
+        }
+
+        // Deliver any events from the corrections before reporting them
+        drop(webhooks);
+        webhook_delivery.finish().await;
+
+        if self.json {
+            let output: Vec<_> = discrepancies
+                .iter()
+                .map(|discrepancy| {
+                    json!({
+                        "channel_id": format!("{}", discrepancy.channel_id),
+                        "status": format!("{}", discrepancy.channel_status),
+                        "contract_status": discrepancy.contract_status.map(|status| format!("{:?}", status)),
+                        "action": format!("{}", discrepancy.reconciliation),
+                        "succeeded": discrepancy.outcome.is_ok(),
+                        "error": discrepancy.outcome.as_ref().err().map(|e| format!("{:#}", e)),
+                    })
+                })
+                .collect();
+            println!("{}", json!(output).to_string());
+        } else if discrepancies.is_empty() {
+            println!("All channels match their contracts");
+        } else {
+            let mut table = Table::new();
+            table.load_preset(comfy_table::presets::UTF8_FULL);
+            table.set_header(vec![
+                "Channel ID",
+                "Status",
+                "Contract Status",
+                "Action",
+                "Outcome",
+            ]);
+
+            for discrepancy in &discrepancies {
+                table.add_row(vec![
+                    Cell::new(abbreviate(&discrepancy.channel_id)),
+                    Cell::new(discrepancy.channel_status),
+                    Cell::new(discrepancy.contract_status()),
+                    Cell::new(discrepancy.reconciliation),
+                    Cell::new(discrepancy.outcome()),
+                ]);
+            }
+
+            println!("{}", table);
+        }
+        Ok(())
+    }
+}
+
+/// Compare a channel's stored status with the state of its contract, and correct any discrepancy.
+///
+/// Returns `None` if the channel already matches its contract. An error is only returned if the
+/// comparison itself fails; a failed correction is reported in the [`Discrepancy`].
+pub async fn reconcile_channel(
+    database: &dyn QueryMerchant,
+    webhooks: &Webhooks,
+    channel: &ChannelDetails,
+    config: &Config,
+) -> Result<Option<Discrepancy>, anyhow::Error> {
+    // There's nothing left to reconcile on a closed channel, so don't fetch its contract
+    if channel.status == ChannelStatus::Closed {
+        return Ok(None);
+    }
+
+    let tezos_client = load_tezos_client(config, &channel.channel_id, database).await?;
+    let contract_state = match tezos_client.get_contract_state().await {
+        Ok(contract_state) => Some(contract_state),
+        Err(e) => {
+            tracing::warn!(
+                channel_id = %channel.channel_id,
+                contract_id = %channel.contract_id,
+                error = %e,
+                "Could not fetch contract"
+            );
+            None
+        }
+    };
+
+    let contract = match &contract_state {
+        Some(contract_state) => {
+            // A contract which was missing before has been found
+            let attention_reason = database.attention_reason(&channel.channel_id).await?;
+            if attention_reason.as_deref() == Some(CONTRACT_NOT_FOUND) {
+                database
+                    .set_attention_reason(&channel.channel_id, None)
+                    .await?;
+            }
+            Some((contract_state.status()?, contract_state.timeout_expired()))
+        }
+        None => None,
+    };
+
+    let reconciliation = match reconcile(contract, channel.status) {
+        Some(reconciliation) => reconciliation,
+        None => return Ok(None),
+    };
+
+    let outcome = match (reconciliation, &contract_state) {
+        (Reconciliation::Act(action), Some(contract_state)) => {
+            correct(database, webhooks, channel, config, action, contract_state).await
+        }
+        _ => database
+            .set_attention_reason(&channel.channel_id, Some(CONTRACT_NOT_FOUND))
+            .await
+            .map_err(Into::into),
+    };
+
+    Ok(Some(Discrepancy {
+        channel_id: channel.channel_id,
+        channel_status: channel.status,
+        contract_status: contract.map(|(status, _)| status),
+        reconciliation,
+        outcome,
+    }))
+}
+
+/// Take the action needed to bring a channel in line with its contract.
+async fn correct(
+    database: &dyn QueryMerchant,
+    webhooks: &Webhooks,
+    channel: &ChannelDetails,
+    config: &Config,
+    action: MerchantChainAction,
+    contract_state: &ContractState,
+) -> Result<(), anyhow::Error> {
+    match action {
+        // The channel has not reacted to an expiry reaching the chain
+        MerchantChainAction::RecordExpiry => {
+            database
+                .compare_and_swap_channel_status(
+                    &channel.channel_id,
+                    &channel.status,
+                    &ChannelStatus::PendingExpiry,
+                )
+                .await?;
+        }
+
+        // The channel has not claimed funds after the expiry timeout expired
+        MerchantChainAction::ClaimExpiry => {
+            close::claim_expiry_funds(config, database, &channel.channel_id).await?;
+            close::finalize_expiry_close(database, &channel.channel_id).await?;
+        }
+
+        // The channel has not reacted to a merchClaim closing the contract
+        MerchantChainAction::FinalizeExpiry => {
+            close::finalize_expiry_close(database, &channel.channel_id).await?;
+        }
+
+        // The channel has not reacted to a merchDispute closing the contract
+        MerchantChainAction::FinalizeDispute => {
+            close::finalize_dispute(database, &channel.channel_id).await?;
+        }
+
+        // The channel processed a customer close, but not the contract closing afterwards
+        MerchantChainAction::FinalizeCustomerClose => {
+            close::finalize_closed_customer_close(database, &channel.channel_id).await?;
+        }
+
+        // The channel has not reacted to a customer posting close balances on chain, either
+        // because the customer initiated the close flow, or in response to the merchant's expiry
+        MerchantChainAction::ProcessCustomerClose => {
+            let revocation_lock = contract_state.revocation_lock()?.ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Failed to retrieve revocation lock from contract storage for {}",
+                    channel.channel_id
+                )
+            })?;
+            let final_balances = contract_state.final_balances()?.ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Failed to retrieve final balances from contract storage for {}",
+                    channel.channel_id
+                )
+            })?;
+
+            // A mismatched customer address is flagged, but the close is still processed, since
+            // disputing a revoked balance only protects the merchant's funds
+            close::verify_customer_address(database, &channel.channel_id, contract_state).await?;
+
+            // The close only starts here if an earlier attempt to process it didn't get this far
+            if channel.status != ChannelStatus::PendingClose {
+                webhooks.notify(WebhookEvent::close_started(
+                    &channel.channel_id,
+                    final_balances.customer_balance(),
+                    final_balances.merchant_balance(),
+                ));
+            }
+            close::process_customer_close(
+                config,
+                database,
+                webhooks,
+                &channel.channel_id,
+                &revocation_lock,
+            )
+            .await?;
+            close::finalize_customer_close(
+                database,
+                &channel.channel_id,
+                final_balances.customer_balance(),
+                final_balances.merchant_balance(),
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
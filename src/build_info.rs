@@ -0,0 +1,215 @@
+//! What exactly a user is running: the build, and the versions of everything it speaks.
+
+use {
+    serde::Serialize,
+    sqlx::{
+        postgres::PgPoolOptions,
+        sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    },
+    std::{
+        fmt::{self, Display, Formatter},
+        time::Duration,
+    },
+};
+
+use crate::{config::DatabaseLocation, escrow::tezos, protocol::version::SupportedVersions};
+
+/// The version of this crate.
+pub const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The git commit this was built from, or `unknown` if it wasn't built from a git checkout.
+pub const GIT_COMMIT: &str = env!("ZEEKOE_GIT_COMMIT");
+
+/// The escrow backend channels are held on.
+pub const ESCROW_BACKEND: &str = "tezos";
+
+/// Details of this build and of the database it is configured to use.
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub crate_version: &'static str,
+    pub git_commit: &'static str,
+    /// The versions of the zkChannels protocol this build serves as a merchant; as a customer, it
+    /// speaks the newest of them.
+    pub protocol_versions: SupportedVersions,
+    /// The latest migration applied to the configured database, or `None` if the database
+    /// couldn't be read.
+    pub schema_version: Option<i64>,
+    pub contract_hash: String,
+    pub escrow_backend: &'static str,
+    pub features: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    /// Collect the build information, along with the schema version of the database at the
+    /// location, if there is one and it can be read.
+    pub async fn collect(database: Option<&DatabaseLocation>) -> Self {
+        let schema_version = match database {
+            Some(database) => schema_version(database).await,
+            None => None,
+        };
+
+        let mut features = Vec::new();
+        if cfg!(feature = "allow_explicit_certificate_trust") {
+            features.push("allow_explicit_certificate_trust");
+        }
+        if cfg!(feature = "dev-insecure") {
+            features.push("dev-insecure");
+        }
+
+        Self {
+            crate_version: CRATE_VERSION,
+            git_commit: GIT_COMMIT,
+            protocol_versions: SupportedVersions::CURRENT,
+            schema_version,
+            contract_hash: hex::encode(tezos::contract_hash().as_bytes()),
+            escrow_backend: ESCROW_BACKEND,
+            features,
+        }
+    }
+
+    /// Print the build information to stdout, either as JSON or as a one-line summary.
+    pub fn print(&self, json: bool) -> Result<(), serde_json::Error> {
+        if json {
+            println!("{}", serde_json::to_string_pretty(self)?);
+        } else {
+            println!("{}", self);
+        }
+        Ok(())
+    }
+}
+
+/// A one-line summary, to print at startup.
+impl Display for BuildInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "zeekoe {} ({}), protocol {}, schema {}, {} contract {}",
+            self.crate_version,
+            self.git_commit,
+            self.protocol_versions,
+            self.schema_version
+                .map_or_else(|| "unknown".to_string(), |version| version.to_string()),
+            self.escrow_backend,
+            self.contract_hash,
+        )?;
+        if !self.features.is_empty() {
+            write!(f, ", features {}", self.features.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+/// How long to wait on a Postgres database for its schema version.
+const SCHEMA_VERSION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The query for the version of the latest migration applied to a database.
+const SCHEMA_VERSION_QUERY: &str = "SELECT MAX(version) FROM _sqlx_migrations";
+
+/// Read the version of the latest migration applied to the database, without creating or
+/// migrating it.
+async fn schema_version(database: &DatabaseLocation) -> Option<i64> {
+    match database {
+        DatabaseLocation::Ephemeral => None,
+        DatabaseLocation::Sqlite(path) => {
+            let options = SqliteConnectOptions::new()
+                .filename(path)
+                .create_if_missing(false)
+                .read_only(true);
+            let pool = SqlitePoolOptions::new()
+                .max_connections(1)
+                .connect_with(options)
+                .await
+                .ok()?;
+            sqlx::query_scalar::<_, Option<i64>>(SCHEMA_VERSION_QUERY)
+                .fetch_one(&pool)
+                .await
+                .ok()?
+        }
+        DatabaseLocation::Postgres(uri) => {
+            let pool = PgPoolOptions::new()
+                .max_connections(1)
+                .connect_timeout(SCHEMA_VERSION_TIMEOUT)
+                .connect(&uri.to_string())
+                .await
+                .ok()?;
+            sqlx::query_scalar::<_, Option<i64>>(SCHEMA_VERSION_QUERY)
+                .fetch_one(&pool)
+                .await
+                .ok()?
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn build_info_json() {
+        let missing = std::env::temp_dir().join(format!("no-such-{}.db", std::process::id()));
+        let info = BuildInfo::collect(Some(&DatabaseLocation::Sqlite(missing.clone()))).await;
+
+        // An inaccessible database is reported as having no schema version, and isn't created
+        assert_eq!(info.schema_version, None);
+        assert!(!missing.exists());
+
+        let json = serde_json::to_value(&info).unwrap();
+        for field in &[
+            "crate_version",
+            "git_commit",
+            "protocol_versions",
+            "schema_version",
+            "contract_hash",
+            "escrow_backend",
+            "features",
+        ] {
+            assert!(json.get(field).is_some(), "missing field {}", field);
+        }
+        assert!(json["schema_version"].is_null());
+    }
+
+    #[tokio::test]
+    async fn schema_version_of_migrated_database() {
+        use crate::database::{connect_sqlite, customer::QueryCustomer};
+
+        let path = std::env::temp_dir().join(format!("schema-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let conn = connect_sqlite(&path).await.unwrap();
+        conn.migrate().await.unwrap();
+        conn.close().await;
+
+        let info = BuildInfo::collect(Some(&DatabaseLocation::Sqlite(path.clone()))).await;
+        assert!(info.schema_version.is_some());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Postgres is only tested against the server given by `ZEEKOE_TEST_POSTGRES_URL`, whose user
+    /// must be able to create databases.
+    #[tokio::test]
+    async fn schema_version_of_migrated_postgres_database() {
+        use crate::database::{customer::QueryCustomer, PgPool};
+
+        let url = match std::env::var("ZEEKOE_TEST_POSTGRES_URL") {
+            Ok(url) => url,
+            Err(_) => return,
+        };
+        let name = format!("zeekoe_test_{}", uuid::Uuid::new_v4().to_simple());
+        let server = PgPool::connect(&url).await.unwrap();
+        sqlx::query(&format!("CREATE DATABASE {}", name))
+            .execute(&server)
+            .await
+            .unwrap();
+        server.close().await;
+
+        let mut url = url::Url::parse(&url).expect("invalid ZEEKOE_TEST_POSTGRES_URL");
+        url.set_path(&name);
+        let location = DatabaseLocation::Postgres(url.as_str().parse().unwrap());
+        assert_eq!(schema_version(&location).await, None);
+
+        let conn = PgPool::connect(url.as_str()).await.unwrap();
+        conn.migrate().await.unwrap();
+        conn.close().await;
+        assert!(schema_version(&location).await.is_some());
+    }
+}
@@ -4,36 +4,72 @@ use {
         io::{self, Read},
         path::PathBuf,
         str::FromStr,
+        time::{Duration, SystemTime},
     },
-    structopt::StructOpt,
+    structopt::{clap::AppSettings, StructOpt},
 };
 
-use crate::{amount::Amount, customer::ChannelName, transport::client::ZkChannelAddress};
+use crate::{
+    amount::Amount,
+    customer::{database::MergeStrategy, output::HistoryFormat, ChannelName},
+    escrow::types::KeyHash,
+    protocol::pay::PaymentId,
+    transport::client::ZkChannelAddress,
+};
 
 /// The customer zkChannels command-line interface.
 #[derive(Debug, StructOpt)]
+#[structopt(global_setting = AppSettings::DisableVersion)]
 #[non_exhaustive]
 pub struct Cli {
     /// Path to a configuration file.
     #[structopt(long)]
     pub config: Option<PathBuf>,
 
+    /// Print version information.
+    #[structopt(short = "V", long)]
+    pub version: bool,
+
+    /// Print output as JSON, for `--version` and for commands that support it (currently
+    /// `close` and `daemon status`).
+    #[structopt(long)]
+    pub json: bool,
+
+    /// Which events to log, such as `debug` or `zeekoe=debug,sqlx=warn`, overriding the
+    /// `ZEEKOE_LOG` environment variable. By default, events at the `info` level and above are
+    /// logged.
+    #[structopt(long)]
+    pub log_level: Option<String>,
+
+    /// Accept any merchant certificate, without checking it. This leaves connections to merchants
+    /// unauthenticated, so it is only for development, and only works in builds with the
+    /// `dev-insecure` feature.
+    #[structopt(long)]
+    pub insecure_allow_invalid_certs: bool,
+
     /// Run customer commands.
     #[structopt(subcommand)]
-    pub customer: Customer,
+    pub customer: Option<Customer>,
 }
 
 #[derive(Debug, StructOpt)]
 pub enum Customer {
     List(List),
-    // Show(Show),
+    Show(Show),
     Configure(Configure),
     Rename(Rename),
+    Export(Export),
+    Import(Import),
+    Archive(Archive),
+    History(History),
     Establish(Establish),
     Pay(Pay),
     Refund(Refund),
+    Repair(Repair),
     Close(Close),
+    ConfirmClose(ConfirmClose),
     Watch(Watch),
+    Daemon(Daemon),
 }
 
 /// List all the zkChannels you've established with merchants.
@@ -45,12 +81,25 @@ pub struct List {
     pub json: bool,
 }
 
-/// Show details for a single zkChannel.
+/// Show everything known about a single zkChannel.
 #[derive(Debug, StructOpt)]
 #[non_exhaustive]
 pub struct Show {
-    /// The channel ID, or a unique prefix of it.
-    pub prefix: String,
+    /// The label of the zkChannel. Incompatible with `--channel-id`.
+    #[structopt(required_unless = "channel-id")]
+    pub label: Option<ChannelName>,
+
+    /// Identify the zkChannel by the start of its ID, as shown by `list`, instead of its label.
+    #[structopt(long, conflicts_with = "label", empty_values(false))]
+    pub channel_id: Option<String>,
+
+    /// Also show every change in the state of the channel.
+    #[structopt(long)]
+    pub history: bool,
+
+    /// Also show every payment made on the channel, including those which failed.
+    #[structopt(long)]
+    pub payments: bool,
 
     /// Get json output.
     #[structopt(long)]
@@ -71,7 +120,8 @@ pub struct Establish {
     /// The `zkchannel://` address for the zkChannel.
     pub merchant: ZkChannelAddress,
 
-    /// The amount to be deposited (e.g. 123.45 XTZ).
+    /// The amount to be deposited (e.g. 123.45 XTZ). This may be zero if the merchant deposits
+    /// something. The customer and merchant deposits together may total at most 1_000_000_000 XTZ.
     #[structopt(long)]
     pub deposit: Amount,
 
@@ -80,7 +130,7 @@ pub struct Establish {
     pub merchant_deposit: Option<Amount>,
 
     /// A text description to identify a zkChannel.
-    #[structopt(long)]
+    #[structopt(long, parse(try_from_str = ChannelName::parse_new))]
     pub label: Option<ChannelName>,
 
     /// A note for the merchant as to why the zkChannel should be established. If you pass `-`, the
@@ -91,6 +141,22 @@ pub struct Establish {
     /// Enable off-chain transactions.
     #[structopt(long)]
     pub off_chain: bool,
+
+    /// Establish the channel even if the merchant's funding address doesn't seem to hold enough to
+    /// cover their deposit (e.g. because of their pending operations).
+    #[structopt(long)]
+    pub skip_merchant_balance_check: bool,
+
+    /// The expected hash of the merchant's keys, in hex. If this isn't given, the hash pinned on
+    /// first contact with the merchant is expected instead.
+    #[structopt(long)]
+    pub merchant_key_hash: Option<KeyHash>,
+
+    /// If establishment fails after the contract is funded, but before the merchant funds it,
+    /// reclaim the deposit from the contract. If a channel with this label is already stuck that
+    /// way, reclaim its deposit instead of resuming its establishment.
+    #[structopt(long)]
+    pub auto_reclaim: bool,
 }
 
 /// Rename an existing zkChannel.
@@ -101,15 +167,112 @@ pub struct Rename {
     pub old_label: ChannelName,
 
     /// An updated label for the channel.
+    #[structopt(parse(try_from_str = ChannelName::parse_new))]
     pub new_label: ChannelName,
 }
 
+/// Export every zkChannel to an encrypted backup, from which it can be restored with `import`.
+///
+/// You will be asked for a passphrase, which is needed to import the backup.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct Export {
+    /// The file to write the backup to, which must not already exist.
+    #[structopt(long)]
+    pub out: PathBuf,
+}
+
+/// Restore zkChannels from a backup written by `export`.
+///
+/// Nothing is restored if the backup is damaged or the passphrase is wrong.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct Import {
+    /// The backup file to restore from.
+    pub file: PathBuf,
+
+    /// What to do with a zkChannel whose label is already taken: `refuse` to restore anything,
+    /// `skip` it, or `replace` the existing zkChannel with it.
+    #[structopt(long, default_value = "refuse")]
+    pub merge_strategy: MergeStrategy,
+}
+
+/// Archive a closed zkChannel, keeping a record of its final balances but not its channel state.
+///
+/// Archived zkChannels no longer appear in `list`, and are ignored by the daemon.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct Archive {
+    /// A text description to identify a zkChannel. Incompatible with `--all-closed` and
+    /// `--channel-id`.
+    #[structopt(required_unless_one = &["all-closed", "channel-id"])]
+    pub label: Option<ChannelName>,
+    /// Identify the zkChannel by the start of its ID, as shown by `list`, instead of its label.
+    #[structopt(long, conflicts_with = "label", empty_values(false))]
+    pub channel_id: Option<String>,
+    /// Archive every zkChannel which is closed.
+    #[structopt(long, conflicts_with_all = &["label", "channel-id"])]
+    pub all_closed: bool,
+    /// Delete the zkChannel entirely instead, keeping no record of it. You will be asked to
+    /// confirm this twice.
+    #[structopt(long)]
+    pub purge: bool,
+}
+
+/// Write the payments made on a zkChannel, or on every zkChannel, for your records.
+///
+/// Each closed zkChannel also gets a row with its final balances and how it was closed.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct History {
+    /// A text description to identify a zkChannel. Incompatible with `--all`.
+    #[structopt(required_unless_one = &["all", "payment-id"])]
+    pub label: Option<ChannelName>,
+    /// Write the history of every zkChannel.
+    #[structopt(long, conflicts_with = "label")]
+    pub all: bool,
+    /// Only include what happened since this date (e.g. 2021-10-01, in UTC) or time (e.g.
+    /// 2021-10-01T12:00:00Z).
+    #[structopt(long)]
+    pub since: Option<Since>,
+    /// Write only the attempts at the payment with this ID, on whichever zkChannel it was made,
+    /// whether or not they completed. Use this to find out what became of a payment whose outcome
+    /// is unclear.
+    #[structopt(long, conflicts_with_all = &["label", "all", "since"])]
+    pub payment_id: Option<PaymentId>,
+    /// Write `csv`, or `json` with one object on each line.
+    #[structopt(long, default_value = "csv")]
+    pub format: HistoryFormat,
+}
+
+/// A point in time given on the command line, as a date or an RFC 3339 time.
+#[derive(Debug, Clone, Copy)]
+pub struct Since(pub SystemTime);
+
+impl FromStr for Since {
+    type Err = humantime::TimestampError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // A date on its own means the start of that day
+        humantime::parse_rfc3339_weak(s)
+            .or_else(|_| humantime::parse_rfc3339_weak(&format!("{} 00:00:00", s)))
+            .map(Since)
+    }
+}
+
 /// Initiate a payment on a zkChannel.
 #[derive(Debug, StructOpt)]
+#[structopt(setting = AppSettings::AllowMissingPositional)]
 #[non_exhaustive]
 pub struct Pay {
-    /// A text description to identify a zkChannel.
-    pub label: ChannelName,
+    /// A text description to identify a zkChannel. Incompatible with `--address`.
+    #[structopt(required_unless = "address")]
+    pub label: Option<ChannelName>,
+
+    /// Identify the zkChannel by its merchant's address instead of its label, as long as it is
+    /// the only zkChannel with that merchant which isn't closed.
+    #[structopt(long, conflicts_with = "label")]
+    pub address: Option<ZkChannelAddress>,
 
     /// The amount you wish to pay the merchant (e.g. 123.45 XTZ).
     pub pay: Amount,
@@ -118,27 +281,63 @@ pub struct Pay {
     /// read from stdin.
     #[structopt(long)]
     pub note: Option<Note>,
+
+    /// Make this many payments of the amount, one after another over the same connection to the
+    /// merchant. The outcome of each is written to stdout as a line of JSON.
+    #[structopt(long)]
+    pub repeat: Option<u64>,
+
+    /// Make payments of the amount, one after another over the same connection to the merchant,
+    /// until they add up to this total (e.g. 1 XTZ), never going past it. With `--repeat`, the
+    /// payments stop at whichever limit comes first.
+    #[structopt(long)]
+    pub until_total: Option<Amount>,
+
+    /// How long to wait between repeated payments (e.g. 1s).
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    pub interval: Option<Duration>,
+
+    /// An ID for the payment, which the merchant records and won't complete twice. Reuse the ID
+    /// of a payment whose outcome is unclear to retry it safely. Defaults to a fresh, random ID.
+    #[structopt(long, conflicts_with_all = &["repeat", "until-total"])]
+    pub payment_id: Option<PaymentId>,
 }
 
 impl Pay {
     pub fn into_negative_refund(self) -> Refund {
-        let Self { label, pay, note } = self;
+        let Self {
+            label,
+            address,
+            pay,
+            note,
+            payment_id,
+            ..
+        } = self;
         Refund {
             label,
+            address,
             refund: Amount {
                 money: -1 * pay.money,
             },
             note,
+            payment_id,
         }
     }
 }
 
 /// Request a refund from a merchant.
 #[derive(Debug, StructOpt)]
+#[structopt(setting = AppSettings::AllowMissingPositional)]
 #[non_exhaustive]
 pub struct Refund {
-    /// A text description to identify a zkChannel.
-    pub label: ChannelName,
+    /// A text description to identify a zkChannel. Incompatible with `--address`.
+    #[structopt(required_unless = "address")]
+    pub label: Option<ChannelName>,
+
+    /// Identify the zkChannel by its merchant's address instead of its label, as long as it is
+    /// the only zkChannel with that merchant which isn't closed.
+    #[structopt(long, conflicts_with = "label")]
+    pub address: Option<ZkChannelAddress>,
 
     /// The amount you wish the merchant to refund (e.g. 123.45 XTZ).
     pub refund: Amount,
@@ -147,37 +346,103 @@ pub struct Refund {
     /// read from stdin.
     #[structopt(long)]
     pub note: Option<Note>,
+
+    /// An ID for the refund, which the merchant records and won't complete twice. Reuse the ID
+    /// of a refund whose outcome is unclear to retry it safely. Defaults to a fresh, random ID.
+    #[structopt(long)]
+    pub payment_id: Option<PaymentId>,
 }
 
 impl Refund {
     pub fn into_negative_pay(self) -> Pay {
         let Self {
             label,
+            address,
             refund,
             note,
+            payment_id,
         } = self;
         Pay {
             label,
+            address,
             pay: Amount {
                 money: -1 * refund.money,
             },
             note,
+            repeat: None,
+            until_total: None,
+            interval: None,
+            payment_id,
         }
     }
 }
 
+/// Finish a payment on a zkChannel whose session with the merchant was interrupted, leaving the
+/// zkChannel in the middle of the payment.
+///
+/// `pay` and `refund` try this themselves before making a new payment.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct Repair {
+    /// The label of the zkChannel. Incompatible with `--channel-id`.
+    #[structopt(required_unless = "channel-id")]
+    pub label: Option<ChannelName>,
+
+    /// Identify the zkChannel by the start of its ID, as shown by `list`, instead of its label.
+    #[structopt(long, conflicts_with = "label", empty_values(false))]
+    pub channel_id: Option<String>,
+}
+
 /// Close an existing zkChannel.
 #[derive(Debug, StructOpt)]
 #[non_exhaustive]
 pub struct Close {
-    /// A text description to identify a zkChannel.
-    pub label: ChannelName,
+    /// A text description to identify a zkChannel. Incompatible with `--all`, `--channel-id`, and
+    /// `--address`.
+    #[structopt(required_unless_one = &["all", "channel-id", "address"])]
+    pub label: Option<ChannelName>,
+    /// Identify the zkChannel by the start of its ID, as shown by `list`, instead of its label.
+    #[structopt(long, conflicts_with = "label", empty_values(false))]
+    pub channel_id: Option<String>,
+    /// Identify the zkChannel by its merchant's address instead of its label, as long as it is
+    /// the only zkChannel with that merchant which isn't closed.
+    #[structopt(long, conflicts_with_all = &["label", "channel-id"])]
+    pub address: Option<ZkChannelAddress>,
+    /// Close every open zkChannel, continuing past any that fail to close.
+    #[structopt(
+        long,
+        conflicts_with_all = &["label", "channel-id", "address", "close-output"]
+    )]
+    pub all: bool,
     /// Perform a unilateral close without waiting for the merchant to respond.
     #[structopt(long)]
     pub force: bool,
     /// Enable off-chain transactions.
     #[structopt(long)]
     pub off_chain: bool,
+    /// Where to write the closing data when closing off chain. By default, this is
+    /// `<channel id>.close.json` in the configured `close_output_dir`, or else in the current
+    /// directory.
+    #[structopt(long)]
+    pub close_output: Option<PathBuf>,
+    /// Replace the closing data file if it already exists.
+    #[structopt(long)]
+    pub force_overwrite: bool,
+}
+
+/// Confirm that the custClose operation produced by an off-chain close has been broadcast.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct ConfirmClose {
+    /// A text description to identify a zkChannel. Incompatible with `--channel-id`.
+    #[structopt(required_unless = "channel-id")]
+    pub label: Option<ChannelName>,
+    /// Identify the zkChannel by the start of its ID, as shown by `list`, instead of its label.
+    #[structopt(long, conflicts_with = "label", empty_values(false))]
+    pub channel_id: Option<String>,
+    /// A JSON file with the `operation_hash` and `level` of the broadcast custClose operation.
+    #[structopt(long)]
+    pub receipt: PathBuf,
 }
 
 /// Run the chain-watching server
@@ -187,6 +452,31 @@ pub struct Watch {
     /// Enable off-chain transactions.
     #[structopt(long)]
     pub off_chain: bool,
+    /// Never load the Tezos key: only watch each zkChannel's contract, and record any operation it
+    /// needs for you to post with `customer close`.
+    #[structopt(long)]
+    pub watch_only: bool,
+}
+
+/// Query or control the running chain-watching server.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct Daemon {
+    #[structopt(subcommand)]
+    pub command: DaemonCommand,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum DaemonCommand {
+    /// Show what the chain-watching server knows about each zkChannel, including what it last saw
+    /// of the zkChannel's contract.
+    Status,
+    /// Have the chain-watching server check a zkChannel's contract now, rather than at its next
+    /// pass, and take whatever action closing the zkChannel requires.
+    TriggerClose {
+        /// The label of the zkChannel.
+        label: ChannelName,
+    },
 }
 
 /// An argument specified on the command line which may be a string literal, or the special string
@@ -239,3 +529,33 @@ impl Note {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payment_id_is_for_a_single_payment() {
+        const PAYMENT_ID: &str = "5b9c5f4e-58a5-4a2e-9d3b-1b6c0ab0b3d1";
+        let pay = |args: &[&str]| {
+            Pay::from_iter_safe(
+                ["pay", "my-zkchannel", "1 XTZ", "--payment-id", PAYMENT_ID]
+                    .iter()
+                    .chain(args),
+            )
+        };
+
+        assert_eq!(
+            pay(&[]).unwrap().payment_id,
+            Some(PAYMENT_ID.parse().unwrap())
+        );
+
+        // Repeated payments each need an ID of their own, so one ID can't be given for them all
+        for args in &[&["--repeat", "3"][..], &["--until-total", "5 XTZ"]] {
+            assert_eq!(
+                pay(args).unwrap_err().kind,
+                structopt::clap::ErrorKind::ArgumentConflict
+            );
+        }
+    }
+}
@@ -1,20 +1,39 @@
-use {std::path::PathBuf, structopt::StructOpt};
+use {
+    std::path::PathBuf,
+    structopt::{clap::AppSettings, StructOpt},
+};
 
 use zkabacus_crypto::ChannelId;
 
 pub use crate::merchant;
+use crate::protocol::ChannelStatus;
 
 /// The merchant zkChannels command-line interface.
 #[derive(Debug, StructOpt)]
+#[structopt(global_setting = AppSettings::DisableVersion)]
 #[non_exhaustive]
 pub struct Cli {
     /// Path to a configuration file.
     #[structopt(long)]
     pub config: Option<PathBuf>,
 
+    /// Print version information.
+    #[structopt(short = "V", long)]
+    pub version: bool,
+
+    /// Print version information as JSON.
+    #[structopt(long, requires = "version")]
+    pub json: bool,
+
+    /// Which events to log, such as `debug` or `zeekoe=debug,sqlx=warn`, overriding the
+    /// `ZEEKOE_LOG` environment variable. By default, events at the `info` level and above are
+    /// logged.
+    #[structopt(long)]
+    pub log_level: Option<String>,
+
     /// Run merchant commands.
     #[structopt(subcommand)]
-    pub merchant: Merchant,
+    pub merchant: Option<Merchant>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -24,12 +43,19 @@ pub enum Merchant {
     Configure(Configure),
     Run(Run),
     Close(Close),
+    BlockChannel(BlockChannel),
+    History(History),
+    Reconcile(Reconcile),
 }
 
 /// List all the zkChannels you've established with customers.
 #[derive(Debug, StructOpt)]
 #[non_exhaustive]
 pub struct List {
+    /// Only list zkChannels with this status, e.g. `active` or `pending_close`.
+    #[structopt(long)]
+    pub status: Option<ChannelStatus>,
+
     /// Get json output.
     #[structopt(long)]
     pub json: bool,
@@ -42,6 +68,10 @@ pub struct Show {
     #[structopt(empty_values(false))]
     pub prefix: String,
 
+    /// Also fetch the current state of the zkChannel's contract from the chain.
+    #[structopt(long)]
+    pub verify_chain: bool,
+
     /// Get json output.
     #[structopt(long)]
     pub json: bool,
@@ -68,6 +98,55 @@ pub struct Close {
     pub all: bool,
 
     /// Close a single zkChannel by ID. Incompatible with `--all`.
+    ///
+    /// Depending on the zkChannel's contract, this initiates expiry, claims the balance once an
+    /// expiry has gone unanswered, or disputes a customer's close on a revoked balance.
     #[structopt(long, required_unless = "all")]
     pub channel: Option<ChannelId>,
+
+    /// Print what closing the zkChannel would do, and why, without posting anything.
+    #[structopt(long, requires = "channel")]
+    pub dry_run: bool,
+}
+
+/// Show the payments customers have made or attempted, most recent first.
+///
+/// Payments can't be linked to zkChannels, so this covers all of them.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct History {
+    /// The number of payments to show.
+    #[structopt(long, default_value = "20")]
+    pub limit: u32,
+
+    /// The number of most recent payments to skip.
+    #[structopt(long, default_value = "0")]
+    pub offset: u32,
+
+    /// Get json output.
+    #[structopt(long)]
+    pub json: bool,
+}
+
+/// Block a zkChannel, e.g. because its customer has been abusive.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct BlockChannel {
+    /// The ID of the zkChannel to block.
+    pub channel: ChannelId,
+
+    /// Also close the zkChannel by initiating expiry.
+    #[structopt(long)]
+    pub close: bool,
+}
+
+/// Check every zkChannel against its contract on chain, and correct any discrepancies.
+///
+/// The chain watcher started by `run` does the same on every poll.
+#[derive(Debug, StructOpt)]
+#[non_exhaustive]
+pub struct Reconcile {
+    /// Get json output.
+    #[structopt(long)]
+    pub json: bool,
 }
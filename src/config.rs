@@ -2,8 +2,11 @@ use {
     http::Uri,
     serde::{de, Deserialize, Deserializer, Serialize},
     std::path::{Path, PathBuf},
+    thiserror::Error,
 };
 
+use crate::escrow::types::TezosNetwork;
+
 pub mod customer;
 pub mod merchant;
 
@@ -53,3 +56,183 @@ pub fn deserialize_confirmation_depth<'de, D: Deserializer<'de>>(
 
     Ok(num)
 }
+
+/// An error in how the Tezos network is specified in a configuration.
+#[derive(Debug, Error)]
+pub enum TezosNetworkError {
+    #[error("Only one of `tezos_network` and `tezos_uri` may be specified")]
+    Conflicting,
+    #[error("One of `tezos_network` or `tezos_uri` must be specified")]
+    Missing,
+}
+
+/// Resolve the Tezos network from a configuration, in which it may be specified either by name or
+/// by the URI of a node, but not both.
+pub fn resolve_tezos_network(
+    network: Option<TezosNetwork>,
+    uri: Option<Uri>,
+) -> Result<TezosNetwork, TezosNetworkError> {
+    match (network, uri) {
+        (Some(network), None) => Ok(network),
+        (None, Some(uri)) => Ok(TezosNetwork::Custom(uri)),
+        (Some(_), Some(_)) => Err(TezosNetworkError::Conflicting),
+        (None, None) => Err(TezosNetworkError::Missing),
+    }
+}
+
+/// Serialization for an optional [`Uri`], for use with `#[serde(with = "optional_uri")]`.
+pub mod optional_uri {
+    use {
+        http::Uri,
+        serde::{de, Deserialize, Deserializer, Serializer},
+    };
+
+    pub fn serialize<S: Serializer>(uri: &Option<Uri>, serializer: S) -> Result<S::Ok, S::Error> {
+        match uri {
+            Some(uri) => serializer.serialize_some(&uri.to_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Uri>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|uri| uri.parse().map_err(de::Error::custom))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tezos_network_by_name_or_uri() {
+        let uri: Uri = "http://localhost:8732".parse().unwrap();
+
+        assert_eq!(
+            resolve_tezos_network(Some(TezosNetwork::Granadanet), None).unwrap(),
+            TezosNetwork::Granadanet
+        );
+        assert_eq!(
+            resolve_tezos_network(None, Some(uri.clone())).unwrap(),
+            TezosNetwork::Custom(uri.clone())
+        );
+        assert!(matches!(
+            resolve_tezos_network(Some(TezosNetwork::Sandbox), Some(uri)),
+            Err(TezosNetworkError::Conflicting)
+        ));
+        assert!(matches!(
+            resolve_tezos_network(None, None),
+            Err(TezosNetworkError::Missing)
+        ));
+    }
+
+    #[test]
+    fn tezos_network_of_unloaded_config() {
+        let parse = |s: &str| toml::from_str::<customer::Config>(s).unwrap();
+
+        let config = parse(
+            r#"
+            tezos_account = "tezos_key.json"
+            tezos_uri = "http://localhost:8732"
+            "#,
+        );
+        assert_eq!(
+            config.tezos_network().unwrap(),
+            TezosNetwork::Custom("http://localhost:8732".parse().unwrap())
+        );
+
+        let config = parse(r#"tezos_account = "tezos_key.json""#);
+        assert!(matches!(
+            config.tezos_network(),
+            Err(TezosNetworkError::Missing)
+        ));
+    }
+
+    #[test]
+    fn tezos_client_uses_configured_node() {
+        use crate::escrow::types::{ContractId, KeySpecifier, TezosKeyMaterial};
+        use std::sync::Arc;
+        use tezedge::OriginatedAddress;
+
+        let config = toml::from_str::<customer::Config>(
+            r#"
+            tezos_account = "tezos_key.json"
+            tezos_uri = "http://tezos.example.com:8732"
+            confirmation_depth = 3
+            "#,
+        )
+        .unwrap();
+        let key_material = TezosKeyMaterial::read_key_pair(&KeySpecifier::Alias {
+            alias: "edsk2pfUZ7NAbo7ekr5RHW6Dni2GYKS935mqXXcrbXtTn8dCfTfViZ".into(),
+        })
+        .unwrap();
+        let contract_id = ContractId::new(
+            OriginatedAddress::from_base58check("KT1Mjjcb6tmSsLm7Cb3DSQszePjfchPM4Uxm").unwrap(),
+        );
+
+        let client = config
+            .tezos_client(contract_id, Arc::new(key_material))
+            .unwrap();
+        assert_eq!(
+            client.uri,
+            Some(Uri::from_static("http://tezos.example.com:8732"))
+        );
+        assert_eq!(client.confirmation_depth, 3);
+        assert_eq!(client.self_delay, config.self_delay);
+    }
+
+    #[test]
+    fn daemon_lock_is_per_database() {
+        let lock_file = |database: &str| {
+            toml::from_str::<customer::Config>(&format!(
+                "tezos_account = \"tezos_key.json\"\ndatabase = {}",
+                database
+            ))
+            .unwrap()
+            .daemon_lock_file()
+            .unwrap()
+        };
+
+        // Databases in the same directory have locks of their own
+        assert_eq!(
+            lock_file(r#"{ sqlite = "data/customer.db" }"#),
+            Some(PathBuf::from("data/customer.db.lock"))
+        );
+        assert_eq!(
+            lock_file(r#"{ sqlite = "data/other.db" }"#),
+            Some(PathBuf::from("data/other.db.lock"))
+        );
+
+        // So do Postgres databases, and an ephemeral database, which nothing else shares, has none
+        assert_ne!(
+            lock_file(r#"{ postgres = "postgres://localhost/one" }"#),
+            lock_file(r#"{ postgres = "postgres://localhost/two" }"#)
+        );
+        assert_eq!(lock_file(r#""ephemeral""#), None);
+    }
+
+    #[test]
+    fn parse_tezos_network() {
+        #[derive(Deserialize)]
+        struct Network {
+            tezos_network: TezosNetwork,
+        }
+
+        let parse = |s: &str| toml::from_str::<Network>(s).unwrap().tezos_network;
+
+        assert_eq!(parse(r#"tezos_network = "mainnet""#), TezosNetwork::Mainnet);
+        assert_eq!(parse(r#"tezos_network = "sandbox""#), TezosNetwork::Sandbox);
+        assert_eq!(
+            parse(r#"tezos_network = { custom = "http://localhost:8732" }"#),
+            TezosNetwork::Custom("http://localhost:8732".parse().unwrap())
+        );
+        assert_eq!(
+            TezosNetwork::Sandbox.uri(),
+            Uri::from_static("http://localhost:20000")
+        );
+        assert!(toml::from_str::<Network>(r#"tezos_network = "testnet""#).is_err());
+    }
+}
@@ -1,19 +1,27 @@
 use {
-    dialectic_reconnect::Backoff,
     serde::{Deserialize, Serialize},
     std::{
         path::{Path, PathBuf},
+        sync::Arc,
         time::Duration,
     },
 };
 
 use http::Uri;
 
-pub use super::{deserialize_confirmation_depth, deserialize_self_delay, DatabaseLocation};
+pub use super::{
+    deserialize_confirmation_depth, deserialize_self_delay, DatabaseLocation, TezosNetworkError,
+};
+use super::{optional_uri, resolve_tezos_network};
 
 use crate::{
-    customer::defaults,
-    escrow::types::{KeySpecifier, TezosKeyMaterial},
+    customer::{client::Backoff, defaults, notify::NotificationEventType},
+    escrow::{
+        signer::RemoteSigner,
+        tezos::TezosClient,
+        types::{ContractId, KeySpecifier, TezosKeyMaterial, TezosNetwork, TezosSigner},
+    },
+    protocol::daemon::DaemonIdentity,
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,41 +29,229 @@ use crate::{
 #[non_exhaustive]
 pub struct Config {
     pub database: Option<DatabaseLocation>,
-    #[serde(default = "defaults::backoff")]
-    pub backoff: Backoff,
-    #[serde(with = "humantime_serde", default = "defaults::connection_timeout")]
-    pub connection_timeout: Option<Duration>,
-    #[serde(default = "defaults::daemon_port")]
-    pub daemon_port: u16,
+    /// How to reconnect to merchants after losing the connection.
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+    #[serde(default)]
+    pub daemon: DaemonConfig,
+    #[serde(default)]
+    pub proxy_through_daemon: bool,
     #[serde(default = "defaults::max_pending_connection_retries")]
     pub max_pending_connection_retries: usize,
     #[serde(with = "humantime_serde", default = "defaults::message_timeout")]
     pub message_timeout: Duration,
     #[serde(with = "humantime_serde", default = "defaults::approval_timeout")]
     pub approval_timeout: Duration,
+    /// How long to wait for each of the merchant's responses once a payment has started, after
+    /// which the payment is left for `repair` to finish.
+    #[serde(
+        with = "humantime_serde",
+        default = "defaults::payment_response_timeout"
+    )]
+    pub payment_response_timeout: Duration,
     #[serde(with = "humantime_serde", default = "defaults::verification_timeout")]
     pub verification_timeout: Duration,
     #[serde(with = "humantime_serde", default = "defaults::transaction_timeout")]
     pub transaction_timeout: Duration,
+    /// How long to wait, at most, after a failed mutual close before falling back to a unilateral
+    /// close. If this isn't set, a failed mutual close is left for the customer to retry.
+    #[serde(with = "humantime_serde", default)]
+    pub mutual_close_fallback_timeout: Option<Duration>,
+    /// How long to wait, at most, for the merchant's funding to appear on chain when establishing
+    /// a channel.
+    #[serde(
+        with = "humantime_serde",
+        default = "defaults::merchant_funding_timeout"
+    )]
+    pub merchant_funding_timeout: Duration,
+    /// Whether to reclaim the customer's deposit when establishing a channel fails after the
+    /// customer funded the contract, but before the merchant did, as with `establish
+    /// --auto-reclaim`.
+    #[serde(default)]
+    pub auto_reclaim: bool,
+    /// How long a channel may stay stuck partway through establishment, with its contract
+    /// originated but not funded by the merchant, before the daemon reclaims the customer's
+    /// deposit from it. This should be much longer than establishment itself takes. If this isn't
+    /// set, the daemon leaves such channels alone.
+    #[serde(with = "humantime_serde", default)]
+    pub stalled_establish_timeout: Option<Duration>,
+    /// How often the daemon checks the contract of every channel for on-chain activity that needs
+    /// a response. This is shortened if necessary so that every response is made well within the
+    /// self-delay.
+    #[serde(with = "humantime_serde", default = "defaults::polling_interval")]
+    pub polling_interval: Duration,
+    /// The maximum number of channels whose contracts the daemon checks and responds to at once.
+    #[serde(default = "defaults::max_concurrent_polls")]
+    pub max_concurrent_polls: usize,
     #[serde(default = "defaults::max_message_length")]
     pub max_message_length: usize,
     #[serde(default = "defaults::max_note_length")]
     pub max_note_length: u64,
-    #[serde(with = "http_serde::uri")]
-    pub tezos_uri: Uri,
+    /// How many numbered labels, like `my-channel (1)`, establishing a channel tries if its label
+    /// is already taken, before giving up.
+    #[serde(default = "defaults::max_label_suffix")]
+    pub max_label_suffix: u32,
+    #[serde(default)]
+    pub tezos_network: Option<TezosNetwork>,
+    #[serde(default, with = "optional_uri")]
+    pub tezos_uri: Option<Uri>,
     pub tezos_account: KeySpecifier,
+    /// The shortest self-delay, in seconds, to accept from a merchant.
     #[serde(
         default = "defaults::self_delay",
         deserialize_with = "deserialize_self_delay"
     )]
     pub self_delay: u64,
+    /// The longest self-delay, in seconds, to accept from a merchant.
+    #[serde(
+        default = "defaults::max_accepted_self_delay",
+        deserialize_with = "deserialize_self_delay"
+    )]
+    pub max_accepted_self_delay: u64,
     #[serde(
         default = "defaults::confirmation_depth",
         deserialize_with = "deserialize_confirmation_depth"
     )]
     pub confirmation_depth: u64,
+    /// A certificate to trust for merchant connections, besides the webpki roots of trust, such as
+    /// a self-signed certificate for a merchant in development. This is only honored by builds with
+    /// the `allow_explicit_certificate_trust` feature.
     #[serde(default)]
     pub trust_certificate: Option<PathBuf>,
+    /// Whether to accept any merchant certificate without checking it, as set by
+    /// `--insecure-allow-invalid-certs`. This is never read from the configuration file.
+    #[serde(skip)]
+    pub allow_invalid_certificates: bool,
+    /// A certificate chain to present to merchants which authenticate their customers by TLS
+    /// client certificates. This requires `client_key`.
+    #[serde(default)]
+    pub client_certificate: Option<PathBuf>,
+    /// The private key for `client_certificate`.
+    #[serde(default)]
+    pub client_key: Option<PathBuf>,
+    /// The directory in which to write data for closing off chain, if not the current directory.
+    #[serde(default)]
+    pub close_output_dir: Option<PathBuf>,
+    /// A program which the daemon runs whenever a channel changes because of something that
+    /// happened on chain, with the event as JSON on its standard input.
+    #[serde(default)]
+    pub notification_command: Option<PathBuf>,
+    /// The kinds of event to run the notification command for, or `None` for every kind.
+    #[serde(default)]
+    pub notification_events: Option<Vec<NotificationEventType>>,
+    /// How long the notification command may run for each event before it is killed.
+    #[serde(with = "humantime_serde", default = "defaults::notification_timeout")]
+    pub notification_timeout: Duration,
+}
+
+/// How the customer reconnects to a merchant after losing the connection, or failing to make it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct ReconnectConfig {
+    /// How long to wait before the first attempt to reconnect.
+    #[serde(with = "humantime_serde", default = "defaults::reconnect_delay")]
+    pub initial_delay: Duration,
+    /// How much to multiply the delay by after each attempt.
+    #[serde(default = "defaults::reconnect_multiplier")]
+    pub multiplier: f64,
+    /// The longest to wait between attempts, however many there have been.
+    #[serde(with = "humantime_serde", default)]
+    pub max_delay: Option<Duration>,
+    /// How many attempts to make before giving up, if not as many as fit in the `timeout`.
+    #[serde(default)]
+    pub max_retries: Option<usize>,
+    /// How long to keep trying to reconnect, in total, before giving up.
+    #[serde(with = "humantime_serde", default = "defaults::connection_timeout")]
+    pub timeout: Option<Duration>,
+    /// How long to keep a connection to a merchant open after a session, for the next session
+    /// with the same merchant.
+    #[serde(
+        with = "humantime_serde",
+        default = "defaults::idle_connection_timeout"
+    )]
+    pub idle_timeout: Duration,
+    /// The most connections to keep open between sessions.
+    #[serde(default = "defaults::max_idle_connections")]
+    pub max_idle: usize,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay: defaults::reconnect_delay(),
+            multiplier: defaults::reconnect_multiplier(),
+            max_delay: None,
+            max_retries: None,
+            timeout: defaults::connection_timeout(),
+            idle_timeout: defaults::idle_connection_timeout(),
+            max_idle: defaults::max_idle_connections(),
+        }
+    }
+}
+
+impl ReconnectConfig {
+    /// The backoff strategy for connections to merchants.
+    pub fn backoff(&self) -> Backoff {
+        let mut backoff = Backoff::with_delay(self.initial_delay);
+        backoff
+            .multiplier(self.multiplier)
+            .max_delay(self.max_delay);
+        if let Some(max_retries) = self.max_retries {
+            backoff.max_retries(max_retries);
+        }
+        backoff
+    }
+}
+
+/// How clients reach the customer's chain-watching daemon.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct DaemonConfig {
+    /// The port on localhost on which the daemon listens. If neither this nor a socket is given,
+    /// a port is derived from the location of the database.
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// A Unix socket on which the daemon listens, instead of a port: either `true`, for a socket
+    /// next to the database, or the path of the socket.
+    #[serde(default)]
+    pub socket: Option<DaemonSocket>,
+    /// The file in which the daemon stores the token that clients must present to it. By default,
+    /// this is next to the database.
+    #[serde(default)]
+    pub token_file: Option<PathBuf>,
+    /// How long the daemon keeps an idle connection to a merchant open for later payments.
+    #[serde(with = "humantime_serde", default = "defaults::daemon_idle_timeout")]
+    pub idle_timeout: Duration,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            port: None,
+            socket: None,
+            token_file: None,
+            idle_timeout: defaults::daemon_idle_timeout(),
+        }
+    }
+}
+
+/// Whether the customer's chain-watching daemon listens on a Unix socket, and where.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DaemonSocket {
+    /// Whether to listen on a socket in the default location.
+    Default(bool),
+    /// The path of the socket.
+    Path(PathBuf),
+}
+
+/// Where the customer's chain-watching daemon listens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DaemonAddress {
+    Port(u16),
+    Socket(PathBuf),
 }
 
 impl Config {
@@ -69,11 +265,31 @@ impl Config {
             .expect("Merchant configuration path must exist in some parent directory");
 
         if config.self_delay < 120 {
-            eprintln!("Warning: `self_delay` should not be less than 120 outside of");
-            eprintln!("testing. If this is an error, please update the customer");
-            eprintln!("configuration.");
+            tracing::warn!(
+                self_delay = config.self_delay,
+                "`self_delay` should not be less than 120 outside of testing. If this is an error, \
+                please update the customer configuration."
+            );
+        }
+
+        if config.reconnect.multiplier.is_nan() || config.reconnect.multiplier < 1.0 {
+            return Err(anyhow::anyhow!(
+                "`reconnect.multiplier` ({}) must be at least 1",
+                config.reconnect.multiplier
+            ));
         }
 
+        if config.max_accepted_self_delay < config.self_delay {
+            return Err(anyhow::anyhow!(
+                "`max_accepted_self_delay` ({}) must not be less than `self_delay` ({})",
+                config.max_accepted_self_delay,
+                config.self_delay
+            ));
+        }
+
+        // Make sure the Tezos network is given either by name or by URI, but not both
+        config.tezos_network()?;
+
         // Adjust contained paths to be relative to the config path
         config.database = config
             .database
@@ -81,7 +297,48 @@ impl Config {
         config.trust_certificate = config
             .trust_certificate
             .map(|ref cert_path| config_dir.join(cert_path));
+        config.client_certificate = config
+            .client_certificate
+            .map(|ref cert_path| config_dir.join(cert_path));
+        config.client_key = config
+            .client_key
+            .map(|ref key_path| config_dir.join(key_path));
+        config.close_output_dir = config
+            .close_output_dir
+            .map(|ref output_dir| config_dir.join(output_dir));
+        config.notification_command = config
+            .notification_command
+            .map(|ref command| config_dir.join(command));
         config.tezos_account.set_relative_path(config_dir);
+        config.daemon.socket = config.daemon.socket.map(|socket| match socket {
+            DaemonSocket::Path(ref socket_path) => DaemonSocket::Path(config_dir.join(socket_path)),
+            DaemonSocket::Default(enabled) => DaemonSocket::Default(enabled),
+        });
+        config.daemon.token_file = config
+            .daemon
+            .token_file
+            .map(|ref token_path| config_dir.join(token_path));
+
+        if config.client_certificate.is_some() != config.client_key.is_some() {
+            return Err(anyhow::anyhow!(
+                "`client_certificate` and `client_key` must be specified together"
+            ));
+        }
+
+        let socket = match config.daemon.socket {
+            None | Some(DaemonSocket::Default(false)) => false,
+            Some(DaemonSocket::Default(true)) | Some(DaemonSocket::Path(_)) => true,
+        };
+        if socket && !cfg!(unix) {
+            return Err(anyhow::anyhow!(
+                "`daemon.socket` is only supported on Unix; use `daemon.port` instead"
+            ));
+        }
+        if config.daemon.port.is_some() && socket {
+            return Err(anyhow::anyhow!(
+                "Only one of `daemon.port` and `daemon.socket` may be specified"
+            ));
+        }
 
         Ok(config)
     }
@@ -89,4 +346,105 @@ impl Config {
     pub fn load_tezos_key_material(&self) -> anyhow::Result<TezosKeyMaterial> {
         Ok(TezosKeyMaterial::read_key_pair(&self.tezos_account)?)
     }
+
+    /// Load a signer for the Tezos account: its key material, or if the key is held by a remote
+    /// signer, a connection to that signer.
+    pub async fn load_tezos_signer(&self) -> anyhow::Result<Arc<dyn TezosSigner>> {
+        Ok(match &self.tezos_account {
+            KeySpecifier::Remote {
+                signer_url,
+                address,
+            } => Arc::new(RemoteSigner::connect(signer_url, address).await?),
+            _ => Arc::new(self.load_tezos_key_material()?),
+        })
+    }
+
+    /// A client for posting operations on the given contract with the given signer, on the
+    /// configured Tezos network and with the configured confirmation depth and self-delay.
+    pub fn tezos_client(
+        &self,
+        contract_id: ContractId,
+        client_signer: Arc<dyn TezosSigner>,
+    ) -> Result<TezosClient, TezosNetworkError> {
+        Ok(TezosClient {
+            uri: Some(self.tezos_network()?.uri()),
+            contract_id,
+            client_signer,
+            confirmation_depth: self.confirmation_depth,
+            self_delay: self.self_delay,
+        })
+    }
+
+    /// The Tezos network to use, given either by name or by the URI of a node.
+    pub fn tezos_network(&self) -> Result<TezosNetwork, TezosNetworkError> {
+        resolve_tezos_network(self.tezos_network.clone(), self.tezos_uri.clone())
+    }
+
+    /// How often the daemon checks on-chain activity: the configured polling interval, but at most
+    /// half the self-delay, so that the customer can always respond to a close in time.
+    pub fn chain_polling_interval(&self) -> Duration {
+        self.polling_interval
+            .min(Duration::from_secs(self.self_delay / 2))
+            .max(Duration::from_secs(1))
+    }
+
+    /// The location of the database, falling back to the default location if none is configured.
+    pub fn database_location(&self) -> Result<DatabaseLocation, anyhow::Error> {
+        match &self.database {
+            Some(location) => Ok(location.clone()),
+            None => defaults::database_location(),
+        }
+    }
+
+    /// The identity of the daemon serving this configuration's database.
+    pub fn daemon_identity(&self) -> Result<DaemonIdentity, anyhow::Error> {
+        Ok(DaemonIdentity::new(&self.database_location()?))
+    }
+
+    /// Where the daemon for this configuration listens.
+    pub fn daemon_address(&self) -> Result<DaemonAddress, anyhow::Error> {
+        Ok(match (&self.daemon.socket, self.daemon.port) {
+            (Some(DaemonSocket::Path(socket)), _) => DaemonAddress::Socket(socket.clone()),
+            (Some(DaemonSocket::Default(true)), _) => {
+                DaemonAddress::Socket(self.daemon_file(defaults::DAEMON_SOCKET_FILE)?)
+            }
+            (_, Some(port)) => DaemonAddress::Port(port),
+            (_, None) => DaemonAddress::Port(self.daemon_identity()?.default_port()),
+        })
+    }
+
+    /// The file containing the token that clients present to the daemon.
+    pub fn daemon_token_file(&self) -> Result<PathBuf, anyhow::Error> {
+        if let Some(token_file) = &self.daemon.token_file {
+            return Ok(token_file.clone());
+        }
+        self.daemon_file(defaults::DAEMON_TOKEN_FILE)
+    }
+
+    /// Where the daemon keeps the lock which stops a second daemon from running against the same
+    /// database: `<database file>.lock`, if it's a SQLite file, or a file in the data directory
+    /// named for the database, if it's a Postgres database. An ephemeral database is never shared,
+    /// so it has no lock.
+    pub fn daemon_lock_file(&self) -> Result<Option<PathBuf>, anyhow::Error> {
+        match self.database_location()? {
+            DatabaseLocation::Ephemeral => Ok(None),
+            DatabaseLocation::Sqlite(path) => {
+                let mut lock_file = path.into_os_string();
+                lock_file.push(".lock");
+                Ok(Some(lock_file.into()))
+            }
+            DatabaseLocation::Postgres(_) => Ok(Some(
+                defaults::data_dir()?.join(format!("daemon-{}.lock", self.daemon_identity()?)),
+            )),
+        }
+    }
+
+    /// The path of a file the daemon keeps for this configuration's database: next to the
+    /// database, if it's a SQLite file, or otherwise in the data directory.
+    fn daemon_file(&self, file_name: &str) -> Result<PathBuf, anyhow::Error> {
+        match self.database_location()? {
+            DatabaseLocation::Sqlite(path) => Ok(path.with_file_name(file_name)),
+            _ => Ok(defaults::data_dir()?.join(file_name)),
+        }
+    }
 }
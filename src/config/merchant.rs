@@ -1,15 +1,26 @@
 use {
     http::Uri,
+    rusty_money::FormattableCurrency,
     serde::{Deserialize, Serialize},
-    std::{net::IpAddr, path::Path, path::PathBuf, time::Duration},
+    std::{
+        fmt::{self, Display, Formatter},
+        net::IpAddr,
+        path::Path,
+        path::PathBuf,
+        time::Duration,
+    },
     url::Url,
 };
 
 pub use super::{deserialize_confirmation_depth, deserialize_self_delay, DatabaseLocation};
 
+use super::{optional_uri, resolve_tezos_network, TezosNetworkError};
+
 use crate::{
-    escrow::types::{KeySpecifier, TezosKeyMaterial},
-    merchant::defaults,
+    amount::XTZ,
+    escrow::types::{KeySpecifier, TezosKeyMaterial, TezosNetwork},
+    merchant::{defaults, webhook::WebhookEventType},
+    protocol::{parameters::MerchantPolicy, pay::PaymentLimits},
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -18,8 +29,11 @@ use crate::{
 pub struct Config {
     pub database: DatabaseLocation,
     pub tezos_account: KeySpecifier,
-    #[serde(with = "http_serde::uri")]
-    pub tezos_uri: Uri,
+    #[serde(default)]
+    pub tezos_network: Option<TezosNetwork>,
+    #[serde(default, with = "optional_uri")]
+    pub tezos_uri: Option<Uri>,
+    /// The self-delay, in seconds, required of every channel's contract.
     #[serde(
         default = "defaults::self_delay",
         deserialize_with = "deserialize_self_delay"
@@ -30,6 +44,52 @@ pub struct Config {
         deserialize_with = "deserialize_confirmation_depth"
     )]
     pub confirmation_depth: u64,
+    /// How many blocks back from the head to search for the customer's funding operation when
+    /// verifying a new channel. This bounds how long a customer may take to report their funding
+    /// after it is included in a block.
+    #[serde(default = "defaults::funding_search_depth")]
+    pub funding_search_depth: u64,
+    /// How often to check the contract of every channel for on-chain activity that needs a
+    /// response. This is shortened if necessary so that every response is made well within the
+    /// self-delay.
+    #[serde(with = "humantime_serde", default = "defaults::polling_interval")]
+    pub polling_interval: Duration,
+    /// The maximum number of channels whose contracts are checked and responded to at once.
+    #[serde(default = "defaults::max_concurrent_dispatches")]
+    pub max_concurrent_dispatches: usize,
+    /// How long a channel may stay active before the merchant initiates expiry to reclaim its
+    /// funds, or `None` to leave channels open until the customer closes them.
+    ///
+    /// Payments are unlinkable, so this is measured from when the channel was activated.
+    #[serde(with = "humantime_serde", default)]
+    pub channel_expiry_after: Option<Duration>,
+    /// The largest payment, in mutez, to accept.
+    #[serde(default)]
+    pub max_payment_amount: Option<u64>,
+    /// The largest refund, in mutez, to issue.
+    #[serde(default)]
+    pub max_refund_amount: Option<u64>,
+    /// The most payments and refunds to accept in any minute, across all channels.
+    #[serde(default)]
+    pub max_payments_per_minute: Option<u32>,
+    /// The most mutez of payments, not counting refunds, to accept in any day, across all
+    /// channels.
+    #[serde(default)]
+    pub max_volume_per_day: Option<u64>,
+    /// The address on which to serve metrics, if `metrics_port` is set.
+    #[serde(default = "defaults::address")]
+    pub metrics_address: IpAddr,
+    /// The port on which to serve Prometheus metrics over plain HTTP, or `None` not to serve them.
+    /// Metrics are only available if the merchant was built with the `metrics` feature.
+    #[serde(default)]
+    pub metrics_port: Option<u16>,
+    /// How long to wait, on shutdown, for sessions in progress to finish before cutting them off.
+    /// No new connections are accepted in the meantime.
+    #[serde(with = "humantime_serde", default = "defaults::shutdown_grace_period")]
+    pub shutdown_grace_period: Duration,
+    /// Where to send notifications of channel lifecycle events, if anywhere.
+    #[serde(default)]
+    pub webhooks: Option<Webhooks>,
     #[serde(rename = "service")]
     pub services: Vec<Service>,
 }
@@ -42,22 +102,80 @@ pub struct Service {
     pub address: IpAddr,
     #[serde(default = "defaults::port")]
     pub port: u16,
-    #[serde(with = "humantime_serde", default)]
-    pub connection_timeout: Option<Duration>,
+    /// How long to keep a broken session waiting for its customer to reconnect and resume it,
+    /// before giving up on it. By default, sessions wait indefinitely, up to
+    /// `max_pending_connection_retries` reconnections.
+    #[serde(with = "humantime_serde", default, alias = "connection_timeout")]
+    pub max_resume_window: Option<Duration>,
+    /// How long to keep a connection open after its session finishes, for the customer to start
+    /// another session over it.
+    #[serde(
+        with = "humantime_serde",
+        default = "defaults::idle_connection_timeout"
+    )]
+    pub idle_connection_timeout: Option<Duration>,
     #[serde(default = "defaults::max_pending_connection_retries")]
     pub max_pending_connection_retries: usize,
+    /// The most sessions to serve at once. Further connections wait until a session finishes.
+    #[serde(default)]
+    pub max_concurrent_sessions: Option<usize>,
+    /// The most sessions to serve at once for any one client IP address. Further connections from
+    /// that address are refused.
+    #[serde(default)]
+    pub max_sessions_per_ip: Option<usize>,
     #[serde(with = "humantime_serde", default = "defaults::message_timeout")]
     pub message_timeout: Duration,
+    /// How long a customer making repeated payments may wait between them, beyond the
+    /// `message_timeout`, before the merchant gives up on the session.
+    #[serde(with = "humantime_serde", default = "defaults::max_repeat_interval")]
+    pub max_repeat_interval: Duration,
     #[serde(with = "humantime_serde", default = "defaults::transaction_timeout")]
     pub transaction_timeout: Duration,
     #[serde(with = "humantime_serde", default = "defaults::verification_timeout")]
     pub verification_timeout: Duration,
     #[serde(default = "defaults::max_message_length")]
     pub max_message_length: usize,
+    /// The longest note, in bytes, to accept with a channel or payment. Longer notes are refused
+    /// before they reach the approver.
+    #[serde(default = "defaults::max_note_length")]
+    pub max_note_length: u64,
     #[serde(default)]
     pub approve: Approver,
+    /// Whether to describe payments to a URL-based approver the way older approvers expect: with
+    /// the amount in the query string and the note as the body, rather than as a JSON document.
+    #[serde(default)]
+    pub legacy_approver_requests: bool,
+    /// How to prove to the approver that requests come from the merchant, if at all.
+    #[serde(default)]
+    pub approver_auth: Option<ApproverAuth>,
+    /// How long to wait for the approver to respond, retrying if it can't be reached, before
+    /// rejecting the payment or channel.
+    #[serde(with = "humantime_serde", default = "defaults::approver_timeout")]
+    pub approver_timeout: Duration,
+    /// How to approve new channels. If this isn't given, new channels are approved by `approve`.
+    #[serde(default)]
+    pub establish_approver: Option<EstablishApprover>,
+    /// Whether to accept channels which the merchant funds entirely, with a zero customer
+    /// deposit. Such channels are still subject to the approver.
+    #[serde(default)]
+    pub allow_merchant_funded_channels: bool,
+    #[serde(default = "defaults::reclaim_stalled_establish")]
+    pub reclaim_stalled_establish: bool,
+    #[serde(
+        with = "humantime_serde",
+        default = "defaults::stalled_establish_timeout"
+    )]
+    pub stalled_establish_timeout: Duration,
     pub private_key: PathBuf,
     pub certificate: PathBuf,
+    /// Certificate authorities, in PEM format, whose client certificates customers may present to
+    /// authenticate themselves. Customers presenting any other certificate are refused.
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+    /// Whether to refuse customers which present no client certificate, rather than serving them
+    /// anonymously. This requires `client_ca`.
+    #[serde(default)]
+    pub require_client_auth: bool,
 }
 
 impl Config {
@@ -71,17 +189,47 @@ impl Config {
             .expect("Merchant configuration path must exist in some parent directory");
 
         if config.self_delay < 120 {
-            eprintln!("Warning: `self_delay` should not be less than 120 outside of");
-            eprintln!("testing. If this is an error, please update the merchant");
-            eprintln!("configuration.");
+            tracing::warn!(
+                self_delay = config.self_delay,
+                "`self_delay` should not be less than 120 outside of testing. If this is an error, \
+                please update the merchant configuration."
+            );
         }
 
+        if config.funding_search_depth < config.confirmation_depth {
+            return Err(anyhow::anyhow!(
+                "`funding_search_depth` ({}) must not be less than `confirmation_depth` ({})",
+                config.funding_search_depth,
+                config.confirmation_depth
+            ));
+        }
+
+        // Make sure the Tezos network is given either by name or by URI, but not both
+        config.tezos_network()?;
+
         // Adjust contained paths to be relative to the config path
         config.database = config.database.relative_to(config_dir);
         config.tezos_account.set_relative_path(config_dir);
         for service in config.services.as_mut_slice() {
             service.private_key = config_dir.join(&service.private_key);
             service.certificate = config_dir.join(&service.certificate);
+            service.client_ca = service
+                .client_ca
+                .as_ref()
+                .map(|client_ca| config_dir.join(client_ca));
+            if service.require_client_auth && service.client_ca.is_none() {
+                return Err(anyhow::anyhow!(
+                    "`require_client_auth` requires a `client_ca` for the service at {}:{}",
+                    service.address,
+                    service.port
+                ));
+            }
+            if let Some(auth) = service.approver_auth.as_mut() {
+                auth.set_relative_path(config_dir);
+            }
+        }
+        if let Some(webhooks) = config.webhooks.as_mut() {
+            webhooks.secret_file = config_dir.join(&webhooks.secret_file);
         }
 
         Ok(config)
@@ -90,6 +238,76 @@ impl Config {
     pub fn load_tezos_key_material(&self) -> Result<TezosKeyMaterial, anyhow::Error> {
         Ok(TezosKeyMaterial::read_key_pair(&self.tezos_account)?)
     }
+
+    /// The Tezos network to use, given either by name or by the URI of a node.
+    pub fn tezos_network(&self) -> Result<TezosNetwork, TezosNetworkError> {
+        resolve_tezos_network(self.tezos_network.clone(), self.tezos_uri.clone())
+    }
+
+    /// How often to check on-chain activity: the configured polling interval, but at most half the
+    /// self-delay, so that a customer's close can always be disputed in time.
+    pub fn chain_polling_interval(&self) -> Duration {
+        self.polling_interval
+            .min(Duration::from_secs(self.self_delay / 2))
+            .max(Duration::from_secs(1))
+    }
+
+    /// The limits to place on payments, which are checked before each payment is approved.
+    pub fn payment_limits(&self) -> PaymentLimits {
+        PaymentLimits {
+            max_payment_amount: self.max_payment_amount,
+            max_refund_amount: self.max_refund_amount,
+            max_payments_per_minute: self.max_payments_per_minute,
+            max_volume_per_day: self.max_volume_per_day,
+        }
+    }
+
+    /// The limits and policies to advertise to customers of the service, so that they can check
+    /// their requests against them before making them.
+    ///
+    /// Deposit bounds are only advertised for a service which approves channels automatically;
+    /// otherwise its approver decides. The note length is the smaller of the service's note limit
+    /// and what fits in a message.
+    pub fn merchant_policy(&self, service: &Service) -> MerchantPolicy {
+        let bounds = match &service.establish_approver {
+            Some(EstablishApprover::Automatic(bounds)) => Some(bounds),
+            _ => None,
+        };
+        let min_customer_deposit = bounds.and_then(|bounds| bounds.min_deposit);
+
+        MerchantPolicy {
+            // A service which doesn't fund channels on its own requires some customer deposit
+            min_customer_deposit: if service.allow_merchant_funded_channels {
+                min_customer_deposit
+            } else {
+                Some(min_customer_deposit.unwrap_or_default().max(1))
+            },
+            max_customer_deposit: bounds.and_then(|bounds| bounds.max_deposit),
+            max_merchant_contribution: bounds.map(|bounds| bounds.max_merchant_contribution),
+            max_payment_amount: self.max_payment_amount,
+            max_note_length: Some(
+                service
+                    .max_note_length
+                    .min(service.max_message_length as u64),
+            ),
+            self_delay: self.self_delay,
+            confirmation_depth: self.confirmation_depth,
+            currency: XTZ.code().to_string(),
+        }
+    }
+
+    /// The length of time after which a funded but inactive channel should have its merchant
+    /// funds reclaimed, or `None` if no service enables reclamation.
+    ///
+    /// Channels aren't associated with the service that established them, so this is the longest
+    /// timeout of any service that enables reclamation.
+    pub fn stalled_establish_timeout(&self) -> Option<Duration> {
+        self.services
+            .iter()
+            .filter(|service| service.reclaim_stalled_establish)
+            .map(|service| service.stalled_establish_timeout)
+            .max()
+    }
 }
 
 /// A description of how to approve payments.
@@ -98,12 +316,14 @@ impl Config {
 pub enum Approver {
     /// Approve all non-negative payments.
     Automatic,
-    /// Request approval from an external service at the URL, via a `GET` request containing the
-    /// transaction amount in the query string and the transaction note in the body of the request.
+    /// Request approval from an external service at the URL, via a `POST` request to `pay` or
+    /// `refund` whose body describes the payment as JSON (see
+    /// [`PaymentRequest`](crate::merchant::approve::PaymentRequest)).
     ///
-    /// An external approver is considered to approve a transaction if it returns an "Ok 200" code,
+    /// An external approver is considered to approve a transaction if it returns a success code,
     /// and otherwise to disapprove it. The body of the approver's response is forwarded to the
-    /// customer.
+    /// customer: as the response note once the payment completes, or as the reason for rejecting
+    /// it.
     Url(Url),
 }
 
@@ -112,3 +332,83 @@ impl Default for Approver {
         Approver::Automatic
     }
 }
+
+impl Display for Approver {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Approver::Automatic => write!(f, "automatic"),
+            Approver::Url(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// How to authenticate the merchant's requests to its approver, with a secret shared with the
+/// approver and kept in a file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApproverAuth {
+    /// Send the token in the file as an `Authorization: Bearer` header.
+    Bearer { token_file: PathBuf },
+    /// Sign the body of each request with HMAC-SHA256, keyed by the secret in the file, sending
+    /// the hex-encoded signature in an `X-Zeekoe-Signature` header.
+    Hmac { secret_file: PathBuf },
+}
+
+impl ApproverAuth {
+    fn set_relative_path(&mut self, config_dir: &Path) {
+        match self {
+            Self::Bearer { token_file: path } | Self::Hmac { secret_file: path } => {
+                *path = config_dir.join(&*path)
+            }
+        }
+    }
+}
+
+/// A webhook to notify of channel lifecycle events, via a `POST` request for each event whose
+/// body describes it as JSON (see [`WebhookEvent`](crate::merchant::webhook::WebhookEvent)).
+///
+/// The body of each request is signed with HMAC-SHA256, keyed by the secret in `secret_file`, and
+/// the hex-encoded signature sent in an `X-Zeekoe-Signature` header. An event may be delivered more
+/// than once, so the webhook should ignore events whose `id` it has already seen.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+#[non_exhaustive]
+pub struct Webhooks {
+    pub url: Url,
+    pub secret_file: PathBuf,
+    /// The kinds of event to send, or `None` to send every kind.
+    #[serde(default)]
+    pub events: Option<Vec<WebhookEventType>>,
+}
+
+/// A description of how to approve new channels.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EstablishApprover {
+    /// Approve all channels within the given bounds.
+    Automatic(DepositBounds),
+    /// Request approval from an external service at the URL, via a `POST` request to `establish`
+    /// whose body describes the channel as JSON (see
+    /// [`EstablishRequest`](crate::merchant::approve::EstablishRequest)).
+    ///
+    /// The approver responds with a JSON
+    /// [`EstablishDecision`](crate::merchant::approve::EstablishDecision), which may reduce the
+    /// merchant's contribution to the channel. A non-success response is a rejection, whose body
+    /// is forwarded to the customer.
+    Url(Url),
+}
+
+/// Bounds, in mutez, on the channels an automatic approver accepts.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub struct DepositBounds {
+    /// The smallest customer deposit to accept, if any.
+    #[serde(default)]
+    pub min_deposit: Option<u64>,
+    /// The largest customer deposit to accept, if any.
+    #[serde(default)]
+    pub max_deposit: Option<u64>,
+    /// The most the merchant contributes to a channel; any channel asking for more is rejected.
+    #[serde(default)]
+    pub max_merchant_contribution: u64,
+}
@@ -4,13 +4,28 @@ use {
         fmt::{Display, Formatter},
         str::FromStr,
     },
+    thiserror::Error,
+    zkabacus_crypto::{CustomerBalance, MerchantBalance, PaymentAmount},
 };
 
+use crate::amount::{Amount, XTZ};
+
+pub mod backup;
+pub mod daemon;
+pub mod finalize;
+pub mod merchant_funding;
+pub mod mutual_close;
+pub mod notify;
+pub mod output;
+pub mod pay;
+pub mod watch;
+
 pub use crate::cli::{customer as cli, customer::Cli};
 pub use crate::config::{customer as config, customer::Config};
 pub use crate::database::customer as database;
 pub use crate::defaults::customer as defaults;
 pub use crate::transport::client::{self as client, Chan, Client};
+pub use crate::transport::message_length;
 pub use crate::transport::server::{self as server, Server};
 
 #[derive(Debug, Clone, sqlx::Type, Serialize, Deserialize)]
@@ -37,15 +52,41 @@ impl Display for AccountName {
     }
 }
 
-#[derive(Debug, Clone, sqlx::Type, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, sqlx::Type, Serialize, Deserialize)]
 #[sqlx(transparent)]
 pub struct ChannelName(String);
 
+/// The maximum length of a channel label, in characters.
+pub const MAX_CHANNEL_NAME_LENGTH: usize = 128;
+
+/// A reason a channel label can't be used.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum InvalidChannelName {
+    #[error("Channel label must not be empty")]
+    Empty,
+    #[error(
+        "Channel label must be at most {} characters long, but is {0}",
+        MAX_CHANNEL_NAME_LENGTH
+    )]
+    TooLong(usize),
+    #[error("Channel label must not contain control characters, but contains {0:?}")]
+    ControlCharacter(char),
+    #[error(
+        "Channel label must not end with a number in parentheses, like \"{0}\", which is added to \
+        labels that are already taken"
+    )]
+    Suffixed(String),
+}
+
+/// Parse the label of a channel, which must be valid. The label may have been suffixed because it
+/// was already taken; see [`ChannelName::parse_new`] for labels which are being chosen.
 impl FromStr for ChannelName {
-    type Err = std::convert::Infallible;
+    type Err = InvalidChannelName;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(ChannelName(s.to_string()))
+        let name = ChannelName(s.to_string());
+        name.validate()?;
+        Ok(name)
     }
 }
 
@@ -59,4 +100,262 @@ impl ChannelName {
     pub fn new(name: String) -> Self {
         Self(name)
     }
+
+    /// Check that the label can be stored: it must not be empty, nor longer than
+    /// [`MAX_CHANNEL_NAME_LENGTH`], nor contain control characters.
+    pub fn validate(&self) -> Result<(), InvalidChannelName> {
+        if self.0.is_empty() {
+            return Err(InvalidChannelName::Empty);
+        }
+        let length = self.0.chars().count();
+        if length > MAX_CHANNEL_NAME_LENGTH {
+            return Err(InvalidChannelName::TooLong(length));
+        }
+        match self.0.chars().find(|c| c.is_control()) {
+            Some(c) => Err(InvalidChannelName::ControlCharacter(c)),
+            None => Ok(()),
+        }
+    }
+
+    /// Parse a label newly chosen by the customer, which must be valid and must not look like one
+    /// suffixed because it was already taken.
+    pub fn parse_new(s: &str) -> Result<Self, InvalidChannelName> {
+        let name: ChannelName = s.parse()?;
+        match name.suffix() {
+            Some(suffix) => Err(InvalidChannelName::Suffixed(suffix.to_string())),
+            None => Ok(name),
+        }
+    }
+
+    /// This label with a number in parentheses added, to tell it apart from the same label
+    /// already taken by another channel.
+    pub fn suffixed(&self, number: u32) -> Self {
+        Self(format!("{} ({})", self.0, number))
+    }
+
+    /// The suffix added by [`ChannelName::suffixed`], if the label ends with one.
+    fn suffix(&self) -> Option<&str> {
+        let start = self.0.rfind(" (")?;
+        let suffix = &self.0[start + 1..];
+        let number = suffix.strip_prefix('(')?.strip_suffix(')')?;
+        if !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()) {
+            Some(suffix)
+        } else {
+            None
+        }
+    }
+}
+
+/// A payment or refund which is more than the balance of the party paying it.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum InsufficientBalance {
+    #[error("Cannot pay {requested} on channel {label}: only {available} is available to pay")]
+    Payment {
+        label: ChannelName,
+        requested: Amount,
+        available: Amount,
+    },
+    #[error(
+        "Cannot refund {requested} on channel {label}: only {available} is available to refund"
+    )]
+    Refund {
+        label: ChannelName,
+        requested: Amount,
+        available: Amount,
+    },
+}
+
+/// Check that the paying party's balance covers a payment: the customer's for a payment, or the
+/// merchant's for a refund.
+///
+/// This only catches a payment which can't succeed before the merchant is contacted; zkAbacus
+/// checks the payment again when it is started, against the balance at that point.
+pub fn check_balance(
+    label: &ChannelName,
+    payment_amount: PaymentAmount,
+    customer_balance: CustomerBalance,
+    merchant_balance: MerchantBalance,
+) -> Result<(), InsufficientBalance> {
+    let amount = payment_amount.to_i64();
+    let xtz = |mutez: u64| Amount::from_minor_units_of_currency(mutez as i64, XTZ);
+    if amount >= 0 && amount as u64 > customer_balance.into_inner() {
+        Err(InsufficientBalance::Payment {
+            label: label.clone(),
+            requested: xtz(amount as u64),
+            available: xtz(customer_balance.into_inner()),
+        })
+    } else if amount < 0 && amount.unsigned_abs() > merchant_balance.into_inner() {
+        Err(InsufficientBalance::Refund {
+            label: label.clone(),
+            requested: xtz(amount.unsigned_abs()),
+            available: xtz(merchant_balance.into_inner()),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// A series of payments of the same amount, made one after another by `pay --repeat` or
+/// `--until-total`, which yields the number of each payment to make, counting from 1.
+///
+/// The series ends after `repeat` payments, or before the payment which would take the total paid
+/// past `until_total`, whichever comes first. Refunds count towards the total by their size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RepeatedPayments {
+    amount: u64,
+    repeat: Option<u64>,
+    until_total: Option<u64>,
+    made: u64,
+}
+
+impl RepeatedPayments {
+    pub fn new(
+        payment_amount: PaymentAmount,
+        repeat: Option<u64>,
+        until_total: Option<PaymentAmount>,
+    ) -> Self {
+        Self {
+            amount: payment_amount.to_i64().unsigned_abs(),
+            repeat,
+            until_total: until_total.map(|total| total.to_i64().unsigned_abs()),
+            made: 0,
+        }
+    }
+}
+
+impl Iterator for RepeatedPayments {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let within_count = self.repeat.map_or(true, |repeat| self.made < repeat);
+        let within_total = self.until_total.map_or(true, |total| {
+            (self.made + 1).saturating_mul(self.amount) <= total
+        });
+        if within_count && within_total {
+            self.made += 1;
+            Some(self.made)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_payments_stop_at_either_limit() {
+        let pay = |mutez| PaymentAmount::pay_merchant(mutez).unwrap();
+        let series = |amount, repeat, until_total| {
+            RepeatedPayments::new(amount, repeat, until_total).collect::<Vec<_>>()
+        };
+
+        // Three payments, limited by count, by total, or by both
+        assert_eq!(series(pay(2), Some(3), None), [1, 2, 3]);
+        assert_eq!(series(pay(2), None, Some(pay(7))), [1, 2, 3]);
+        assert_eq!(series(pay(2), Some(3), Some(pay(100))), [1, 2, 3]);
+        assert_eq!(series(pay(2), Some(100), Some(pay(6))), [1, 2, 3]);
+
+        // Refunds count towards the total by their size
+        let refund = PaymentAmount::pay_customer(2).unwrap();
+        assert_eq!(series(refund, None, Some(pay(6))), [1, 2, 3]);
+
+        // A total smaller than a single payment allows none
+        assert!(series(pay(2), Some(3), Some(pay(1))).is_empty());
+    }
+
+    #[test]
+    fn balance_must_cover_payment() {
+        let label = ChannelName::new("coffee".to_string());
+        let customer_balance = CustomerBalance::try_new(5_000_000).unwrap();
+        let merchant_balance = MerchantBalance::try_new(1_000_000).unwrap();
+        let check = |payment_amount| {
+            check_balance(&label, payment_amount, customer_balance, merchant_balance)
+        };
+
+        // Paying or refunding exactly the balance is fine
+        check(PaymentAmount::pay_merchant(5_000_000).unwrap()).unwrap();
+        check(PaymentAmount::pay_customer(1_000_000).unwrap()).unwrap();
+
+        // Paying more than the customer's balance is not
+        let xtz = |mutez| Amount::from_minor_units_of_currency(mutez, XTZ);
+        let error = check(PaymentAmount::pay_merchant(5_000_001).unwrap()).unwrap_err();
+        assert_eq!(
+            error,
+            InsufficientBalance::Payment {
+                label: label.clone(),
+                requested: xtz(5_000_001),
+                available: xtz(5_000_000),
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Cannot pay {} on channel coffee: only {} is available to pay",
+                xtz(5_000_001),
+                xtz(5_000_000)
+            )
+        );
+
+        // Nor is refunding more than the merchant's balance, even if the customer's is enough
+        assert_eq!(
+            check(PaymentAmount::pay_customer(2_000_000).unwrap()),
+            Err(InsufficientBalance::Refund {
+                label: label.clone(),
+                requested: xtz(2_000_000),
+                available: xtz(1_000_000),
+            })
+        );
+    }
+
+    #[test]
+    fn channel_names_are_validated() {
+        assert!("my channel".parse::<ChannelName>().is_ok());
+        assert!("tezos (main)".parse::<ChannelName>().is_ok());
+        assert!("(1)".parse::<ChannelName>().is_ok());
+
+        assert_eq!("".parse::<ChannelName>(), Err(InvalidChannelName::Empty));
+        let long = "x".repeat(MAX_CHANNEL_NAME_LENGTH + 1);
+        assert_eq!(
+            long.parse::<ChannelName>(),
+            Err(InvalidChannelName::TooLong(MAX_CHANNEL_NAME_LENGTH + 1))
+        );
+        // Length is counted in characters, not bytes
+        assert!("é"
+            .repeat(MAX_CHANNEL_NAME_LENGTH)
+            .parse::<ChannelName>()
+            .is_ok());
+        assert_eq!(
+            "two\nlines".parse::<ChannelName>(),
+            Err(InvalidChannelName::ControlCharacter('\n'))
+        );
+        assert_eq!(
+            "bell\u{7}".parse::<ChannelName>(),
+            Err(InvalidChannelName::ControlCharacter('\u{7}'))
+        );
+        assert_eq!(
+            ChannelName::parse_new("my channel (2)"),
+            Err(InvalidChannelName::Suffixed("(2)".to_string()))
+        );
+        assert!(ChannelName::parse_new("tezos (main)").is_ok());
+        assert_eq!(
+            ChannelName::parse_new("two\nlines"),
+            Err(InvalidChannelName::ControlCharacter('\n'))
+        );
+    }
+
+    #[test]
+    fn existing_suffixed_names_parse() {
+        let existing = ChannelName::new("foo".to_string()).suffixed(1);
+        assert_eq!("foo (1)".parse::<ChannelName>(), Ok(existing));
+    }
+
+    #[test]
+    fn suffixed_names_are_valid_when_stored() {
+        let suffixed = ChannelName::new("my channel".to_string()).suffixed(12);
+        assert_eq!(suffixed.to_string(), "my channel (12)");
+        assert_eq!(suffixed.suffix(), Some("(12)"));
+        assert!(suffixed.validate().is_ok());
+    }
 }
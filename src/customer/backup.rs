@@ -0,0 +1,226 @@
+//! Encrypted backups of the customer's channels, from which they can be restored if the database is
+//! lost.
+//!
+//! A backup holds every channel serialized with bincode, sealed with ChaCha20-Poly1305 under a key
+//! derived from a passphrase with Argon2id. The header (magic bytes, format version, salt, and
+//! nonce) is authenticated along with the channels, so nothing is read from a backup which was
+//! tampered with, or opened with the wrong passphrase.
+
+use {
+    argon2::Argon2,
+    rand::{CryptoRng, RngCore},
+    ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    serde::Deserialize,
+    std::convert::TryInto,
+    thiserror::Error,
+    zeroize::Zeroizing,
+    zkabacus_crypto::{CustomerBalance, MerchantBalance},
+};
+
+use crate::{
+    customer::{
+        client::ZkChannelAddress,
+        database::{ChannelBackup, ClosingBalances, State},
+        ChannelName,
+    },
+    escrow::types::ContractId,
+};
+
+/// The bytes with which every backup starts.
+const MAGIC: &[u8; 8] = b"ZKCHBKUP";
+
+/// The version of the backup format written by this version of zeekoe.
+///
+/// Version 2 records how each channel was closed. Version 1 backups, which don't, can still be
+/// restored.
+pub const FORMAT_VERSION: u8 = 2;
+
+/// The earliest version of the backup format this version of zeekoe can restore.
+const OLDEST_FORMAT_VERSION: u8 = 1;
+
+/// The length of the salt for deriving the key from the passphrase.
+const SALT_LEN: usize = 16;
+
+/// The length of the header, which precedes the sealed channels.
+const HEADER_LEN: usize = MAGIC.len() + 1 + SALT_LEN + NONCE_LEN;
+
+/// An error when sealing or opening a backup.
+#[derive(Debug, Error)]
+pub enum BackupError {
+    #[error("This is not a zkChannels backup")]
+    NotABackup,
+    #[error(
+        "Unsupported backup format version {0} (this version of zeekoe reads versions {} to {})",
+        OLDEST_FORMAT_VERSION,
+        FORMAT_VERSION
+    )]
+    UnsupportedVersion(u8),
+    #[error("Could not decrypt the backup: the passphrase is wrong, or the backup was modified")]
+    Decrypt,
+    #[error("Could not encrypt the backup")]
+    Encrypt,
+    #[error("Could not derive a key from the passphrase: {0}")]
+    KeyDerivation(argon2::Error),
+    #[error("Invalid channel data in backup: {0}")]
+    Channels(#[from] bincode::Error),
+}
+
+/// A channel as recorded in a version 1 backup, before backups recorded how the channel was closed.
+#[derive(Deserialize)]
+struct ChannelBackupV1 {
+    label: ChannelName,
+    address: ZkChannelAddress,
+    merchant_deposit: MerchantBalance,
+    customer_deposit: CustomerBalance,
+    state: State,
+    closing_balances: ClosingBalances,
+    merchant_tezos_public_key: String,
+    contract_id: Option<ContractId>,
+    contract_level: Option<u32>,
+    awaiting_broadcast: bool,
+    zkabacus_config: zkabacus_crypto::customer::Config,
+}
+
+impl From<ChannelBackupV1> for ChannelBackup {
+    fn from(channel: ChannelBackupV1) -> Self {
+        ChannelBackup {
+            label: channel.label,
+            address: channel.address,
+            merchant_deposit: channel.merchant_deposit,
+            customer_deposit: channel.customer_deposit,
+            state: channel.state,
+            closing_balances: channel.closing_balances,
+            closing_path: None,
+            merchant_tezos_public_key: channel.merchant_tezos_public_key,
+            contract_id: channel.contract_id,
+            contract_level: channel.contract_level,
+            awaiting_broadcast: channel.awaiting_broadcast,
+            zkabacus_config: channel.zkabacus_config,
+        }
+    }
+}
+
+/// Derive the key with which to seal a backup from the passphrase and the backup's salt.
+fn key(passphrase: &str, salt: &[u8]) -> Result<LessSafeKey, BackupError> {
+    let mut key = Zeroizing::new([0; 32]);
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key[..])
+        .map_err(BackupError::KeyDerivation)?;
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key[..]).map_err(|_| BackupError::Encrypt)?;
+    Ok(LessSafeKey::new(key))
+}
+
+/// Seal the channels into a backup, encrypted with a key derived from the passphrase.
+pub fn seal(
+    rng: &mut (impl RngCore + CryptoRng),
+    channels: &[ChannelBackup],
+    passphrase: &str,
+) -> Result<Vec<u8>, BackupError> {
+    let mut salt = [0; SALT_LEN];
+    rng.fill_bytes(&mut salt);
+    let mut nonce = [0; NONCE_LEN];
+    rng.fill_bytes(&mut nonce);
+
+    let mut backup = Vec::with_capacity(HEADER_LEN);
+    backup.extend_from_slice(MAGIC);
+    backup.push(FORMAT_VERSION);
+    backup.extend_from_slice(&salt);
+    backup.extend_from_slice(&nonce);
+
+    let mut sealed = Zeroizing::new(bincode::serialize(channels)?);
+    key(passphrase, &salt)?
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(&backup[..]),
+            &mut *sealed,
+        )
+        .map_err(|_| BackupError::Encrypt)?;
+    backup.extend_from_slice(&sealed);
+    Ok(backup)
+}
+
+/// Open a backup with the passphrase it was sealed with, checking its format version and that it
+/// is intact before reading any channels from it.
+pub fn open(backup: &[u8], passphrase: &str) -> Result<Vec<ChannelBackup>, BackupError> {
+    if backup.len() < HEADER_LEN || !backup.starts_with(MAGIC) {
+        return Err(BackupError::NotABackup);
+    }
+    let (header, sealed) = backup.split_at(HEADER_LEN);
+    let version = header[MAGIC.len()];
+    if !(OLDEST_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+        return Err(BackupError::UnsupportedVersion(version));
+    }
+    let salt = &header[MAGIC.len() + 1..MAGIC.len() + 1 + SALT_LEN];
+    let nonce: [u8; NONCE_LEN] = header[HEADER_LEN - NONCE_LEN..]
+        .try_into()
+        .expect("Nonce must be the end of the header");
+
+    let mut sealed = Zeroizing::new(sealed.to_vec());
+    let channels = key(passphrase, salt)?
+        .open_in_place(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(header),
+            &mut *sealed,
+        )
+        .map_err(|_| BackupError::Decrypt)?;
+    Ok(match version {
+        1 => bincode::deserialize::<Vec<ChannelBackupV1>>(channels)?
+            .into_iter()
+            .map(ChannelBackup::from)
+            .collect(),
+        _ => bincode::deserialize(channels)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let backup = seal(&mut rng, &[], "correct horse battery staple").unwrap();
+        assert!(open(&backup, "correct horse battery staple")
+            .unwrap()
+            .is_empty());
+
+        assert!(matches!(
+            open(&backup, "incorrect horse battery staple"),
+            Err(BackupError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn damaged_backup_is_rejected() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let backup = seal(&mut rng, &[], "passphrase").unwrap();
+
+        // Any change to the header or the channels is detected
+        for i in [0, MAGIC.len() + 1, HEADER_LEN - 1, backup.len() - 1] {
+            let mut damaged = backup.clone();
+            damaged[i] ^= 1;
+            assert!(open(&damaged, "passphrase").is_err(), "byte {}", i);
+        }
+        assert!(matches!(
+            open(&backup[..HEADER_LEN - 1], "passphrase"),
+            Err(BackupError::NotABackup)
+        ));
+
+        // A backup from a later version isn't read at all
+        let mut later = backup.clone();
+        later[MAGIC.len()] = FORMAT_VERSION + 1;
+        assert!(matches!(
+            open(&later, "passphrase"),
+            Err(BackupError::UnsupportedVersion(v)) if v == FORMAT_VERSION + 1
+        ));
+
+        // The version is authenticated, so changing it to one which is read is detected too
+        let mut earlier = backup;
+        earlier[MAGIC.len()] = OLDEST_FORMAT_VERSION;
+        assert!(matches!(
+            open(&earlier, "passphrase"),
+            Err(BackupError::Decrypt)
+        ));
+    }
+}
@@ -0,0 +1,553 @@
+//! Clients of the customer's chain-watching daemon, the parts of its control protocol that are
+//! served the same way by any daemon, and the lock which keeps a second daemon from running
+//! against the same database.
+
+use {
+    anyhow::Context,
+    fs2::FileExt,
+    futures::Future,
+    std::{
+        fs::{self, File, OpenOptions},
+        io::{self, Read, Write},
+        path::{Path, PathBuf},
+        time::{Duration, SystemTime},
+    },
+    thiserror::Error,
+    webpki::DNSNameRef,
+};
+
+use crate::{
+    customer::{
+        client::{Backoff, SessionKey},
+        config::DaemonAddress,
+        database::{self, QueryCustomer},
+        server, Chan, ChannelName, Client, Config,
+    },
+    protocol::daemon::{ChannelSummary, Daemon, DaemonCommand, DaemonToken, TriggerClose},
+    timeout::WithTimeout,
+};
+
+/// How long to wait on the daemon when telling it about a change is only a courtesy.
+const BEST_EFFORT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Connect to the customer daemon, check that it is serving the same database as this client, and
+/// authenticate to it.
+pub async fn connect(config: &Config) -> anyhow::Result<(SessionKey, Chan<DaemonCommand>)> {
+    // Always error immediately. We don't need retry/reconnect for the daemon.
+    let mut backoff = Backoff::with_delay(Duration::ZERO);
+    backoff.max_retries(0);
+
+    // The daemon only listens on localhost or a Unix socket, and authenticates clients by their
+    // token
+    let mut client: Client<Daemon> = Client::new(backoff);
+    client.disable_tls().max_length(config.max_message_length);
+    let (session_key, chan) = match config.daemon_address()? {
+        DaemonAddress::Port(port) => {
+            let address = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+            client.connect(&address.into(), port).await?
+        }
+        DaemonAddress::Socket(path) => client
+            .connect_unix(&path)
+            .await
+            .with_context(|| format!("Could not connect to daemon socket {:?}", path))?,
+    };
+
+    // Refuse to talk to a daemon serving some other database, before revealing our token to it
+    let (identity, chan) = chan
+        .recv()
+        .await
+        .context("Failed to receive daemon identity")?;
+    identity.verify(&config.daemon_identity()?)?;
+
+    let token_file = config.daemon_token_file()?;
+    let token = DaemonToken::read(&token_file)
+        .with_context(|| format!("Could not read daemon token from {:?}", token_file))?;
+    let chan = chan
+        .send(token)
+        .await
+        .context("Failed to send daemon token")?;
+
+    Ok((session_key, chan))
+}
+
+/// How much a command depends on the daemon hearing about a change it made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Notify {
+    /// The daemon finds out about the change by itself eventually, so failing to tell it only
+    /// warrants a warning.
+    BestEffort,
+    /// The command isn't complete unless the daemon has been told.
+    Strict,
+}
+
+/// Tell the daemon that the channels in the database have changed.
+///
+/// With [`Notify::BestEffort`], this waits only briefly for the daemon, and warns rather than
+/// failing if it can't be reached. It doesn't try at all unless payments are proxied through the
+/// daemon, since otherwise the daemon isn't expected to be running.
+pub async fn refresh(config: &Config, notify: Notify) -> anyhow::Result<()> {
+    match notify {
+        Notify::Strict => send_refresh(config).await,
+        Notify::BestEffort if !config.proxy_through_daemon => Ok(()),
+        Notify::BestEffort => {
+            match send_refresh(config).with_timeout(BEST_EFFORT_TIMEOUT).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    tracing::warn!(error = %format!("{:#}", e), "Could not notify the daemon")
+                }
+                Err(_) => tracing::warn!("Timed out notifying the daemon"),
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn send_refresh(config: &Config) -> anyhow::Result<()> {
+    let (_session_key, chan) = connect(config)
+        .await
+        .context("Failed to connect to daemon")?;
+
+    chan.choose::<0>()
+        .await
+        .context("Failed to select daemon Refresh")?
+        .close();
+
+    Ok(())
+}
+
+/// Get a summary of every channel from the daemon.
+pub async fn status(config: &Config) -> anyhow::Result<Vec<ChannelSummary>> {
+    let (_session_key, chan) = connect(config)
+        .await
+        .context("Failed to connect to daemon")?;
+
+    let (summaries, chan) = chan
+        .choose::<2>()
+        .await
+        .context("Failed to select daemon Status")?
+        .recv()
+        .await
+        .context("Failed to receive channel summaries from daemon")?;
+    chan.close();
+
+    Ok(summaries)
+}
+
+/// Ask the daemon to check a channel's contract right away and take whatever action closing the
+/// channel requires, returning the time at which the channel next needs attention, if it is
+/// waiting on a deadline.
+pub async fn trigger_close(
+    config: &Config,
+    label: ChannelName,
+) -> anyhow::Result<Option<SystemTime>> {
+    let (_session_key, chan) = connect(config)
+        .await
+        .context("Failed to connect to daemon")?;
+
+    let (result, chan) = chan
+        .choose::<3>()
+        .await
+        .context("Failed to select daemon TriggerClose")?
+        .send(label)
+        .await
+        .context("Failed to send channel label to daemon")?
+        .recv()
+        .await
+        .context("Failed to receive result of close from daemon")?;
+    chan.close();
+
+    result
+        .map_err(anyhow::Error::msg)
+        .context("Daemon failed to close the channel")
+}
+
+/// Summarize every channel in the database, for a client asking the daemon for its status.
+pub async fn channel_summaries(
+    database: &dyn QueryCustomer,
+) -> Result<Vec<ChannelSummary>, database::Error> {
+    let mut summaries = Vec::new();
+    for channel in database.get_channels().await? {
+        summaries.push(ChannelSummary {
+            last_observation: database.contract_observation(&channel.label).await?,
+            operations_in_flight: database.operations_in_flight(&channel.label).await?,
+            state: channel.state.state_name(),
+            customer_balance: channel.state.customer_balance(),
+            merchant_balance: channel.state.merchant_balance(),
+            contract_id: channel.contract_details.contract_id,
+            label: channel.label,
+        });
+    }
+    Ok(summaries)
+}
+
+/// Receive the label of a channel to close from a client, run `trigger` on it, and send back
+/// either the time at which the channel next needs attention or a description of what went wrong.
+pub async fn serve_trigger_close<F, Fut>(
+    chan: server::Chan<TriggerClose>,
+    trigger: F,
+) -> anyhow::Result<()>
+where
+    F: FnOnce(ChannelName) -> Fut,
+    Fut: Future<Output = anyhow::Result<Option<SystemTime>>>,
+{
+    let (label, chan) = chan
+        .recv()
+        .await
+        .context("Failed to receive channel label")?;
+
+    let result = trigger(label).await.map_err(|e| format!("{:#}", e));
+
+    chan.send(result)
+        .await
+        .context("Failed to send result of close")?
+        .close();
+    Ok(())
+}
+
+/// An error when taking the daemon lock.
+#[derive(Debug, Error)]
+pub enum DaemonLockError {
+    #[error(
+        "Another daemon{} is already running against this database, holding {path:?}",
+        .pid.map_or_else(String::new, |pid| format!(" (PID {})", pid))
+    )]
+    Held { path: PathBuf, pid: Option<u32> },
+    #[error("Could not take the daemon lock: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// A lock held by the running daemon, so that no other daemon reacts to the same channels, which
+/// would post operations twice and race on the database.
+///
+/// It is an advisory lock on a file, which is released when dropped, or by the operating system
+/// when the daemon exits, however it exits.
+#[derive(Debug)]
+pub struct DaemonLock {
+    _file: File,
+}
+
+impl DaemonLock {
+    /// Take the lock on the file at the given path, creating the file if need be, and write the
+    /// ID of this process to it, so that a second daemon can say which process holds the lock.
+    pub fn acquire(path: &Path) -> Result<Self, DaemonLockError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+        if let Err(e) = file.try_lock_exclusive() {
+            return Err(if e.kind() == fs2::lock_contended_error().kind() {
+                DaemonLockError::Held {
+                    path: path.to_path_buf(),
+                    pid: Self::holder(&mut file),
+                }
+            } else {
+                e.into()
+            });
+        }
+
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        Ok(Self { _file: file })
+    }
+
+    /// The ID of the process named in the lock file, if it can be read.
+    fn holder(file: &mut File) -> Option<u32> {
+        let mut holder = String::new();
+        file.read_to_string(&mut holder).ok()?;
+        holder.trim().parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn refresh_without_daemon() {
+        // Find a port on which nothing is listening
+        let port = std::net::TcpListener::bind(("127.0.0.1", 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config: Config = toml::from_str(&format!(
+            r#"
+            database = "ephemeral"
+            tezos_account = {{ alias = "alice" }}
+            proxy_through_daemon = true
+
+            [daemon]
+            port = {}
+            "#,
+            port
+        ))
+        .unwrap();
+
+        assert!(refresh(&config, Notify::BestEffort).await.is_ok());
+        assert!(refresh(&config, Notify::Strict).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn status_and_trigger_close() {
+        use crate::{
+            customer::{
+                database::{ContractObservation, StateName},
+                Server,
+            },
+            escrow::types::{ContractStatus, Entrypoint},
+        };
+        use dialectic::offer;
+        use rand::{rngs::StdRng, SeedableRng};
+        use std::sync::{Arc, Mutex};
+        use zkabacus_crypto::{CustomerBalance, MerchantBalance};
+
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let token_file =
+            std::env::temp_dir().join(format!("daemon-control-token-{}", std::process::id()));
+        let config: Config = toml::from_str(&format!(
+            r#"
+            database = "ephemeral"
+            tezos_account = {{ alias = "alice" }}
+
+            [daemon]
+            port = {}
+            token_file = {:?}
+            "#,
+            address.port(),
+            token_file,
+        ))
+        .unwrap();
+
+        let token = DaemonToken::new(&mut StdRng::seed_from_u64(0));
+        token.write(&token_file).unwrap();
+        let identity = config.daemon_identity().unwrap();
+
+        // A daemon with one channel, which the merchant has started to expire
+        let deadline = SystemTime::UNIX_EPOCH + Duration::from_secs(1_630_454_400);
+        let summary = ChannelSummary {
+            label: ChannelName::new("expiring".to_string()),
+            state: StateName::PendingClose,
+            customer_balance: CustomerBalance::try_new(5).unwrap(),
+            merchant_balance: MerchantBalance::try_new(10).unwrap(),
+            contract_id: None,
+            last_observation: Some(ContractObservation {
+                status: ContractStatus::Expiry,
+                level: 100,
+                observed_at: deadline,
+                reaction: Some(Entrypoint::CustomerClose),
+                required_action: None,
+            }),
+            operations_in_flight: vec![Entrypoint::CustomerClaim],
+        };
+        let triggered = Arc::new(Mutex::new(Vec::new()));
+
+        let interact = {
+            let triggered = triggered.clone();
+            move |_session_key, (), chan: server::Chan<Daemon>| {
+                let (token, summary, triggered) =
+                    (token.clone(), summary.clone(), triggered.clone());
+                async move {
+                    let chan = chan.send(identity).await?;
+                    let (client_token, chan) = chan.recv().await?;
+                    assert!(client_token == token);
+                    offer!(in chan {
+                        0 => chan.close(),
+                        1 => drop(chan),
+                        2 => chan.send(vec![summary]).await?.close(),
+                        3 => serve_trigger_close(chan, |label| async move {
+                            if label.to_string() != "expiring" {
+                                return Err(anyhow::anyhow!("There is no channel by that name"));
+                            }
+                            triggered.lock().unwrap().push(label);
+                            Ok(Some(deadline))
+                        })
+                        .await?,
+                    })?;
+                    Ok::<_, anyhow::Error>(())
+                }
+            }
+        };
+        tokio::spawn(async move {
+            let server: Server<Daemon> = Server::new();
+            server
+                .serve_while(
+                    address,
+                    None,
+                    || async { Some(()) },
+                    interact,
+                    futures::future::pending(),
+                )
+                .await
+        });
+
+        // Wait for the daemon to start listening
+        for _ in 0..100 {
+            if tokio::net::TcpStream::connect(address).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let summaries = status(&config).await.unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].label.to_string(), "expiring");
+        assert_eq!(summaries[0].state, StateName::PendingClose);
+        assert_eq!(summaries[0].customer_balance.into_inner(), 5);
+        assert_eq!(summaries[0].merchant_balance.into_inner(), 10);
+        assert_eq!(
+            summaries[0]
+                .last_observation
+                .map(|observation| observation.reaction),
+            Some(Some(Entrypoint::CustomerClose))
+        );
+        assert_eq!(
+            summaries[0].operations_in_flight,
+            vec![Entrypoint::CustomerClaim]
+        );
+
+        let next_action = trigger_close(&config, ChannelName::new("expiring".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(next_action, Some(deadline));
+        assert_eq!(triggered.lock().unwrap().len(), 1);
+
+        // A failure to close is reported to the client
+        let err = trigger_close(&config, ChannelName::new("unknown".to_string()))
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("There is no channel by that name"));
+        assert_eq!(triggered.lock().unwrap().len(), 1);
+
+        std::fs::remove_file(&token_file).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn refresh_over_socket() {
+        use crate::customer::{server::ListenAddress, Server};
+        use dialectic::offer;
+        use rand::{rngs::StdRng, SeedableRng};
+        use std::{
+            os::unix::fs::PermissionsExt,
+            sync::{
+                atomic::{AtomicBool, Ordering},
+                Arc,
+            },
+        };
+
+        let dir = std::env::temp_dir().join(format!("daemon-socket-{}", std::process::id()));
+        let socket = dir.join("daemon.sock");
+        let token_file = dir.join("daemon.token");
+        let config: Config = toml::from_str(&format!(
+            r#"
+            database = "ephemeral"
+            tezos_account = {{ alias = "alice" }}
+
+            [daemon]
+            socket = {:?}
+            token_file = {:?}
+            "#,
+            socket, token_file,
+        ))
+        .unwrap();
+        assert_eq!(
+            config.daemon_address().unwrap(),
+            DaemonAddress::Socket(socket.clone())
+        );
+
+        std::fs::create_dir_all(&dir).unwrap();
+        let token = DaemonToken::new(&mut StdRng::seed_from_u64(0));
+        token.write(&token_file).unwrap();
+        let identity = config.daemon_identity().unwrap();
+
+        let refreshed = Arc::new(AtomicBool::new(false));
+        let interact = {
+            let refreshed = refreshed.clone();
+            move |_session_key, (), chan: server::Chan<Daemon>| {
+                let (token, refreshed) = (token.clone(), refreshed.clone());
+                async move {
+                    let chan = chan.send(identity).await?;
+                    let (client_token, chan) = chan.recv().await?;
+                    assert!(client_token == token);
+                    offer!(in chan {
+                        0 => {
+                            refreshed.store(true, Ordering::SeqCst);
+                            chan.close()
+                        },
+                        1 => drop(chan),
+                        2 => drop(chan),
+                        3 => drop(chan),
+                    })?;
+                    Ok::<_, anyhow::Error>(())
+                }
+            }
+        };
+        let address = ListenAddress::Unix(socket.clone());
+        tokio::spawn(async move {
+            let server: Server<Daemon> = Server::new();
+            server
+                .serve_while(
+                    address,
+                    None,
+                    || async { Some(()) },
+                    interact,
+                    futures::future::pending(),
+                )
+                .await
+        });
+
+        // Wait for the daemon to start listening
+        for _ in 0..100 {
+            if socket.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Only the user running the daemon can connect to it
+        let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        refresh(&config, Notify::Strict).await.unwrap();
+        assert!(refreshed.load(Ordering::SeqCst));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn second_daemon_is_locked_out() {
+        let dir = std::env::temp_dir().join(format!("daemon-lock-{}", std::process::id()));
+        let path = dir.join("customer.db.lock");
+
+        // The first daemon takes the lock, and the second can't while the first is running
+        let first = DaemonLock::acquire(&path).unwrap();
+        match DaemonLock::acquire(&path) {
+            Err(DaemonLockError::Held { pid, .. }) => assert_eq!(pid, Some(std::process::id())),
+            other => panic!("second daemon took the lock: {:?}", other),
+        }
+
+        // Once the first daemon stops, another can start
+        drop(first);
+        let second = DaemonLock::acquire(&path).unwrap();
+        drop(second);
+
+        // A lock file left naming a daemon which is no longer running doesn't hold the lock
+        std::fs::write(&path, u32::MAX.to_string()).unwrap();
+        let third = DaemonLock::acquire(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            std::process::id().to_string()
+        );
+        drop(third);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
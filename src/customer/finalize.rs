@@ -0,0 +1,228 @@
+//! Finalizing a channel once its contract is closed on chain after a unilateral close, shared by
+//! the close command and the chain watcher.
+
+use {anyhow::Context, std::convert::Infallible};
+
+use zkabacus_crypto::{CustomerBalance, MerchantBalance};
+
+use crate::{
+    customer::{
+        database::{
+            zkchannels_state::{self, ZkChannelState},
+            ClosingPath, QueryCustomer, QueryCustomerExt, State,
+        },
+        ChannelName,
+    },
+    protocol::close::CustomerCloseEnding,
+};
+
+/// Update channel to indicate a dispute.
+///
+/// **Usage**: this function is called in response to a merchDispute entrypoint call/operation that is
+/// confirmed on chain at any depth.
+pub async fn process_dispute(
+    database: &dyn QueryCustomer,
+    channel_name: &ChannelName,
+) -> Result<(), anyhow::Error> {
+    // Update channel status to Dispute
+    database
+        .with_channel_state(
+            channel_name,
+            zkchannels_state::PendingClose,
+            |closing_message| -> Result<_, Infallible> {
+                Ok((State::Dispute(closing_message), ()))
+            },
+        )
+        .await
+        .context(format!(
+            "Failed to update channel status to Dispute for {}",
+            channel_name
+        ))??;
+
+    Ok(())
+}
+
+/// Update channel state once a disputed unilateral close flow is finalized.
+///
+/// **Usage**: this function is called when a merchDispute entrypoint call/operation is confirmed
+/// on chain to the required confirmation depth.
+pub async fn finalize_dispute(
+    database: &dyn QueryCustomer,
+    channel_name: &ChannelName,
+) -> Result<(), anyhow::Error> {
+    // Update channel status from Dispute to Closed
+    let (customer_balance, merchant_balance) = database
+        .with_channel_state(
+            channel_name,
+            zkchannels_state::Dispute,
+            |closing_message| -> Result<_, anyhow::Error> {
+                let balances = transfer_balances_to_merchant(
+                    *closing_message.customer_balance(),
+                    *closing_message.merchant_balance(),
+                )?;
+                Ok((State::Closed(closing_message), balances))
+            },
+        )
+        .await
+        .context(format!(
+            "Failed to update channel status to Closed for {}",
+            channel_name
+        ))??;
+
+    // Indicate that all balances are paid out to the merchant
+    database
+        .update_closing_balances(
+            channel_name,
+            ClosingPath::Dispute,
+            merchant_balance,
+            Some(customer_balance),
+        )
+        .await
+        .context(format!(
+            "Failed to save final channel balances for {} after successful dispute",
+            channel_name
+        ))?;
+
+    Ok(())
+}
+
+/// Finalize a channel whose contract was closed after custClose was posted, either by the
+/// merchant disputing the posted balances or by the customer claiming their balance.
+///
+/// **Usage**: this function is called when the contract is closed on chain to the required
+/// confirmation depth while the channel is waiting on the end of a customer close.
+pub async fn finalize_closed_customer_close(
+    database: &dyn QueryCustomer,
+    channel_name: &ChannelName,
+    ending: CustomerCloseEnding,
+) -> Result<(), anyhow::Error> {
+    let state = database.get_channel(channel_name).await?.state;
+    match ending {
+        CustomerCloseEnding::Disputed => {
+            if zkchannels_state::PendingClose.matches(&state) {
+                process_dispute(database, channel_name).await?;
+            }
+            finalize_dispute(database, channel_name).await
+        }
+        CustomerCloseEnding::Claimed => {
+            // The custClaim operation was applied even though posting it appeared to fail
+            if zkchannels_state::PendingClose.matches(&state) {
+                database
+                    .with_channel_state(
+                        channel_name,
+                        zkchannels_state::PendingClose,
+                        |closing_message| -> Result<_, Infallible> {
+                            Ok((State::PendingCustomerClaim(closing_message), ()))
+                        },
+                    )
+                    .await
+                    .context(format!(
+                        "Failed to update channel status to PendingCustomerClaim for {}",
+                        channel_name
+                    ))??;
+            }
+            finalize_customer_claim(database, channel_name).await
+        }
+    }
+}
+
+/// Update channel state once an undisputed unilateral close flow is complete.
+/// This is either a customer unilateral close or an expiry close flow.
+///
+/// **Usage**: this function is called as response to an on-chain event:
+/// - a custClaim entrypoint call operation is confirmed on chain at the required confirmation depth
+pub async fn finalize_customer_claim(
+    database: &dyn QueryCustomer,
+    channel_name: &ChannelName,
+) -> Result<(), anyhow::Error> {
+    // Update status from PendingCustomerClaim to Closed
+    let (merchant_balance, customer_balance) = database
+        .with_channel_state(
+            channel_name,
+            zkchannels_state::PendingCustomerClaim,
+            |closing_message| -> Result<_, Infallible> {
+                let balances = (
+                    *closing_message.merchant_balance(),
+                    *closing_message.customer_balance(),
+                );
+                Ok((State::Closed(closing_message), balances))
+            },
+        )
+        .await
+        .context(format!(
+            "Failed to update channel status to Closed for {}",
+            channel_name
+        ))??;
+
+    // Update final balances to indicate that the customer balance is paid out to the customer
+    database
+        .update_closing_balances(
+            channel_name,
+            ClosingPath::Unilateral,
+            merchant_balance,
+            Some(customer_balance),
+        )
+        .await
+        .context(format!(
+            "Failed to save final channel balances for {} after successful close",
+            channel_name
+        ))?;
+
+    Ok(())
+}
+
+/// Update channel state after the merchant claims the full channel balances; this happens in the
+/// expiry close flow if the customer _does not_ post corrected channel balances via custCluse.
+///
+/// **Usage**: this function is called as response to an on-chain event:
+/// - a merchClaim entrypoint call operation is confirmed on chain at the required confirmation depth
+pub async fn finalize_expiry(
+    database: &dyn QueryCustomer,
+    channel_name: &ChannelName,
+) -> Result<(), anyhow::Error> {
+    // Update status from PendingExpiry to Closed
+    // Calculate updated balances (all money going to the merchant)
+    let (customer_balance, merchant_balance) = database
+        .with_channel_state(
+            channel_name,
+            zkchannels_state::PendingExpiry,
+            |closing_message| -> Result<_, anyhow::Error> {
+                let balances = transfer_balances_to_merchant(
+                    *closing_message.customer_balance(),
+                    *closing_message.merchant_balance(),
+                )?;
+                Ok((State::Closed(closing_message), balances))
+            },
+        )
+        .await
+        .context(format!(
+            "Failed to update channel status to Closed for {}",
+            channel_name
+        ))??;
+
+    // Save final balances (with all money going to the merchant)
+    database
+        .update_closing_balances(
+            channel_name,
+            ClosingPath::Expiry,
+            merchant_balance,
+            Some(customer_balance),
+        )
+        .await
+        .context(format!(
+            "Failed to save final channel balances for {} after successful close",
+            channel_name
+        ))?;
+
+    Ok(())
+}
+
+fn transfer_balances_to_merchant(
+    customer_balance: CustomerBalance,
+    merchant_balance: MerchantBalance,
+) -> Result<(CustomerBalance, MerchantBalance), anyhow::Error> {
+    Ok((
+        CustomerBalance::try_new(0)?,
+        MerchantBalance::try_new(customer_balance.into_inner() + merchant_balance.into_inner())?,
+    ))
+}
@@ -0,0 +1,168 @@
+//! Waiting for the merchant to fund their side of a newly established channel.
+
+use {
+    async_trait::async_trait,
+    std::{fmt::Display, time::Duration},
+    thiserror::Error,
+    zkabacus_crypto::MerchantBalance,
+};
+
+use crate::escrow::{
+    tezos::{ContractStateError, TezosClient},
+    types::ContractStatus,
+};
+
+/// How often to check the contract while waiting for the merchant's funding.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The status of a channel's contract, abstracted so that waiting for merchant funding can be
+/// exercised without a Tezos node.
+#[async_trait]
+pub trait ContractStatusSource {
+    type Error: Display + Send;
+
+    /// Get the current status of the channel's contract, at the configured confirmation depth.
+    async fn contract_status(&self) -> Result<ContractStatus, Self::Error>;
+}
+
+#[async_trait]
+impl ContractStatusSource for TezosClient {
+    type Error = ContractStateError;
+
+    async fn contract_status(&self) -> Result<ContractStatus, Self::Error> {
+        self.get_contract_state().await?.status()
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum MerchantFundingError<E: Display> {
+    #[error("Merchant did not fund the contract within {}", humantime::format_duration(*.0))]
+    Timeout(Duration),
+    #[error("Expected contract to be open, but it was {0:?}")]
+    UnexpectedContractStatus(ContractStatus),
+    #[error("Could not get contract status: {0}")]
+    Chain(E),
+}
+
+/// Wait for the contract to be open, which it is once the merchant has funded it.
+///
+/// If the merchant deposit is zero, the contract opens as soon as the customer funds it, so it is
+/// checked just once. Otherwise, the contract is checked every `poll_interval` until it opens, or
+/// until `timeout` has elapsed.
+pub async fn await_merchant_funding<C: ContractStatusSource + Sync>(
+    chain: &C,
+    merchant_deposit: MerchantBalance,
+    poll_interval: Duration,
+    timeout: Duration,
+) -> Result<(), MerchantFundingError<C::Error>> {
+    let poll = async {
+        loop {
+            match chain
+                .contract_status()
+                .await
+                .map_err(MerchantFundingError::Chain)?
+            {
+                ContractStatus::Open => return Ok(()),
+                ContractStatus::AwaitingMerchantFunding if merchant_deposit.into_inner() > 0 => {
+                    tokio::time::sleep(poll_interval).await
+                }
+                status => return Err(MerchantFundingError::UnexpectedContractStatus(status)),
+            }
+        }
+    };
+
+    tokio::time::timeout(timeout, poll)
+        .await
+        .unwrap_or(Err(MerchantFundingError::Timeout(timeout)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// A contract which reports each of the given statuses in turn, then the last one forever.
+    struct Contract {
+        statuses: Mutex<Vec<ContractStatus>>,
+        polls: Mutex<usize>,
+    }
+
+    impl Contract {
+        fn new(mut statuses: Vec<ContractStatus>) -> Self {
+            statuses.reverse();
+            Self {
+                statuses: Mutex::new(statuses),
+                polls: Mutex::new(0),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ContractStatusSource for Contract {
+        type Error = String;
+
+        async fn contract_status(&self) -> Result<ContractStatus, Self::Error> {
+            *self.polls.lock().unwrap() += 1;
+            let mut statuses = self.statuses.lock().unwrap();
+            Ok(if statuses.len() > 1 {
+                statuses.pop().unwrap()
+            } else {
+                statuses[0]
+            })
+        }
+    }
+
+    const INTERVAL: Duration = Duration::from_millis(5);
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    fn deposit(amount: u64) -> MerchantBalance {
+        MerchantBalance::try_new(amount).unwrap()
+    }
+
+    #[tokio::test]
+    async fn merchant_funds_on_third_poll() {
+        let contract = Contract::new(vec![
+            ContractStatus::AwaitingMerchantFunding,
+            ContractStatus::AwaitingMerchantFunding,
+            ContractStatus::Open,
+        ]);
+        assert_eq!(
+            await_merchant_funding(&contract, deposit(5), INTERVAL, TIMEOUT).await,
+            Ok(())
+        );
+        assert_eq!(*contract.polls.lock().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn merchant_never_funds() {
+        let contract = Contract::new(vec![ContractStatus::AwaitingMerchantFunding]);
+        assert_eq!(
+            await_merchant_funding(&contract, deposit(5), INTERVAL, TIMEOUT).await,
+            Err(MerchantFundingError::Timeout(TIMEOUT))
+        );
+        assert!(*contract.polls.lock().unwrap() > 1);
+    }
+
+    #[tokio::test]
+    async fn zero_merchant_deposit() {
+        // With nothing for the merchant to add, the contract should already be open
+        let contract = Contract::new(vec![ContractStatus::Open]);
+        assert_eq!(
+            await_merchant_funding(&contract, deposit(0), INTERVAL, TIMEOUT).await,
+            Ok(())
+        );
+
+        // ...and isn't waited for if it isn't
+        let contract = Contract::new(vec![
+            ContractStatus::AwaitingMerchantFunding,
+            ContractStatus::Open,
+        ]);
+        assert_eq!(
+            await_merchant_funding(&contract, deposit(0), INTERVAL, TIMEOUT).await,
+            Err(MerchantFundingError::UnexpectedContractStatus(
+                ContractStatus::AwaitingMerchantFunding
+            ))
+        );
+        assert_eq!(*contract.polls.lock().unwrap(), 1);
+    }
+}
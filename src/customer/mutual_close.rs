@@ -0,0 +1,208 @@
+//! Falling back from a mutual close to a unilateral close when the mutualClose operation fails.
+
+use {
+    async_trait::async_trait,
+    rand::Rng,
+    std::{fmt::Display, time::Duration},
+};
+
+use crate::escrow::types::ContractStatus;
+
+/// The chain operations needed to post a mutual close, abstracted so that the fallback can be
+/// exercised without a Tezos node.
+#[async_trait]
+pub trait MutualCloseChain {
+    type Error: Display + Send;
+
+    /// Post the mutualClose operation and wait for it to be confirmed, returning its hash.
+    async fn mutual_close(&self) -> Result<String, Self::Error>;
+
+    /// Get the current status of the channel's contract.
+    async fn contract_status(&self) -> Result<ContractStatus, Self::Error>;
+}
+
+/// How a mutual close ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MutualCloseOutcome {
+    /// The mutualClose operation was confirmed, and the channel can be finalized as closed.
+    ///
+    /// The hash of the operation is only known if posting it didn't appear to fail.
+    Closed { operation_hash: Option<String> },
+    /// The mutualClose operation failed and the contract is still open, so the customer should
+    /// close unilaterally.
+    CloseUnilaterally,
+    /// The mutualClose operation failed and the contract has moved on to some other status, so
+    /// neither finalizing nor closing unilaterally is safe.
+    Unexpected(ContractStatus),
+}
+
+/// Post the mutualClose operation, falling back to a unilateral close if it fails.
+///
+/// Without a `fallback_timeout`, a failure is returned as is. Otherwise, the customer waits a
+/// random delay up to the timeout, so that a close which is merely slow has a chance to land, and
+/// then decides what to do from the status of the contract.
+pub async fn post_with_fallback<C: MutualCloseChain + Sync>(
+    chain: &C,
+    rng: &mut (impl Rng + Send),
+    fallback_timeout: Option<Duration>,
+) -> Result<MutualCloseOutcome, C::Error> {
+    let error = match chain.mutual_close().await {
+        Ok(operation_hash) => {
+            return Ok(MutualCloseOutcome::Closed {
+                operation_hash: Some(operation_hash),
+            })
+        }
+        Err(error) => error,
+    };
+    let fallback_timeout = match fallback_timeout {
+        Some(fallback_timeout) => fallback_timeout,
+        None => return Err(error),
+    };
+
+    let delay = Duration::from_millis(rng.gen_range(0..=fallback_timeout.as_millis() as u64));
+    tracing::warn!(
+        %error,
+        delay = %humantime::format_duration(delay),
+        "Mutual close failed; checking the contract again after a delay"
+    );
+    tokio::time::sleep(delay).await;
+
+    Ok(match chain.contract_status().await? {
+        ContractStatus::Closed => MutualCloseOutcome::Closed {
+            operation_hash: None,
+        },
+        ContractStatus::Open => MutualCloseOutcome::CloseUnilaterally,
+        status => MutualCloseOutcome::Unexpected(status),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::{
+        collections::BTreeMap,
+        fmt,
+        sync::{Arc, Mutex},
+    };
+    use tracing::{
+        field::{Field, Visit},
+        Event, Subscriber,
+    };
+    use tracing_subscriber::{layer, prelude::*, Layer};
+
+    /// A chain on which the mutualClose operation fails, and the contract afterwards has the
+    /// given status.
+    struct FailingChain {
+        status_after: ContractStatus,
+        mutual_close_calls: Mutex<usize>,
+    }
+
+    impl FailingChain {
+        fn new(status_after: ContractStatus) -> Self {
+            Self {
+                status_after,
+                mutual_close_calls: Mutex::new(0),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl MutualCloseChain for FailingChain {
+        type Error = String;
+
+        async fn mutual_close(&self) -> Result<String, Self::Error> {
+            *self.mutual_close_calls.lock().unwrap() += 1;
+            Err("operation failed to confirm".to_string())
+        }
+
+        async fn contract_status(&self) -> Result<ContractStatus, Self::Error> {
+            Ok(self.status_after)
+        }
+    }
+
+    const TIMEOUT: Option<Duration> = Some(Duration::from_millis(10));
+
+    #[tokio::test]
+    async fn mutual_close_lands_late() {
+        let chain = FailingChain::new(ContractStatus::Closed);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        // The operation reported failure but was confirmed anyway
+        assert_eq!(
+            post_with_fallback(&chain, &mut rng, TIMEOUT).await,
+            Ok(MutualCloseOutcome::Closed {
+                operation_hash: None
+            })
+        );
+        assert_eq!(*chain.mutual_close_calls.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn mutual_close_falls_back() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let chain = FailingChain::new(ContractStatus::Open);
+        assert_eq!(
+            post_with_fallback(&chain, &mut rng, TIMEOUT).await,
+            Ok(MutualCloseOutcome::CloseUnilaterally)
+        );
+
+        // If the merchant has meanwhile started closing, don't fall back
+        let chain = FailingChain::new(ContractStatus::Expiry);
+        assert_eq!(
+            post_with_fallback(&chain, &mut rng, TIMEOUT).await,
+            Ok(MutualCloseOutcome::Unexpected(ContractStatus::Expiry))
+        );
+
+        // Without a timeout there's no fallback
+        let chain = FailingChain::new(ContractStatus::Open);
+        assert!(post_with_fallback(&chain, &mut rng, None).await.is_err());
+    }
+
+    /// Records the fields of every event logged while it is the default subscriber.
+    #[derive(Clone, Default)]
+    struct CapturedEvents(Arc<Mutex<Vec<BTreeMap<String, String>>>>);
+
+    impl<S: Subscriber> Layer<S> for CapturedEvents {
+        fn on_event(&self, event: &Event<'_>, _context: layer::Context<'_, S>) {
+            struct Fields<'a>(&'a mut BTreeMap<String, String>);
+
+            impl Visit for Fields<'_> {
+                fn record_str(&mut self, field: &Field, value: &str) {
+                    self.0.insert(field.name().to_string(), value.to_string());
+                }
+
+                fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                    self.0
+                        .insert(field.name().to_string(), format!("{:?}", value));
+                }
+            }
+
+            let mut fields = BTreeMap::new();
+            event.record(&mut Fields(&mut fields));
+            self.0.lock().unwrap().push(fields);
+        }
+    }
+
+    #[tokio::test]
+    async fn fallback_is_logged() {
+        let events = CapturedEvents::default();
+        let _subscriber =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(events.clone()));
+
+        let chain = FailingChain::new(ContractStatus::Open);
+        let mut rng = StdRng::seed_from_u64(0);
+        post_with_fallback(&chain, &mut rng, TIMEOUT).await.unwrap();
+
+        // The failure is logged as a structured event, with the error as a field of its own
+        let events = events.0.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0]["message"],
+            "Mutual close failed; checking the contract again after a delay"
+        );
+        assert_eq!(events[0]["error"], "operation failed to confirm");
+        assert!(events[0].contains_key("delay"));
+    }
+}
@@ -0,0 +1,310 @@
+//! Telling the customer about important events on their channels, by running a command of their
+//! choosing with each event as JSON on its standard input.
+//!
+//! The command is run in the background, and killed if it runs for too long, so that a hung
+//! command never holds up the daemon. Each event is only tried once.
+
+use {
+    serde::{Deserialize, Serialize},
+    std::{
+        path::{Path, PathBuf},
+        process::Stdio,
+        time::{Duration, SystemTime},
+    },
+    thiserror::Error,
+    tokio::{io::AsyncWriteExt, process::Command, task::JoinHandle},
+};
+
+use crate::{
+    customer::{ChannelName, Config},
+    escrow::types::{ContractId, Entrypoint},
+};
+
+/// The kinds of event the notification command can be run for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationEventType {
+    /// The merchant posted an expiry, to which the daemon is answering.
+    ExpiryPosted,
+    /// The merchant disputed the customer's close, and was paid the whole channel balance.
+    DisputeDetected,
+    /// The customer's balance was claimed after their close.
+    FundsClaimed,
+    /// The merchant claimed the whole channel balance after an expiry.
+    MerchantClaimed,
+    /// The customer's mutual close was applied.
+    MutuallyClosed,
+    /// The channel needs an operation posted or other attention that the daemon can't give it.
+    ActionRequired,
+}
+
+/// An event passed to the notification command, as JSON on its standard input.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotificationEvent {
+    pub event: NotificationEventType,
+    pub label: ChannelName,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contract_id: Option<String>,
+    /// The operation the channel needs posted, for an `action_required` event.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+    /// When the event happened, in RFC 3339 format.
+    pub timestamp: String,
+}
+
+impl NotificationEvent {
+    pub fn new(
+        event: NotificationEventType,
+        label: &ChannelName,
+        contract_id: Option<&ContractId>,
+    ) -> Self {
+        Self {
+            event,
+            label: label.clone(),
+            contract_id: contract_id.map(ToString::to_string),
+            action: None,
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        }
+    }
+
+    /// The channel needs the given operation posted, which the daemon can't post itself.
+    pub fn action_required(
+        label: &ChannelName,
+        contract_id: Option<&ContractId>,
+        action: Entrypoint,
+    ) -> Self {
+        Self {
+            action: Some(action.to_string()),
+            ..Self::new(NotificationEventType::ActionRequired, label, contract_id)
+        }
+    }
+}
+
+/// An error running the notification command for an event.
+#[derive(Debug, Error)]
+pub enum NotificationError {
+    #[error("Could not run notification command {0:?}: {1}")]
+    Spawn(PathBuf, #[source] std::io::Error),
+    #[error("Notification command {0:?} failed: {1}")]
+    Io(PathBuf, #[source] std::io::Error),
+    #[error("Notification command {0:?} exited with {1}")]
+    Failed(PathBuf, std::process::ExitStatus),
+    #[error("Notification command {0:?} was killed after running for {1:?}")]
+    TimedOut(PathBuf, Duration),
+}
+
+/// A handle for running the customer's notification command, which does nothing if no command is
+/// configured.
+#[derive(Debug, Clone, Default)]
+pub struct Notifications {
+    command: Option<PathBuf>,
+    /// The events to run the command for, or `None` to run it for all of them.
+    events: Option<Vec<NotificationEventType>>,
+    timeout: Duration,
+}
+
+impl Notifications {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            command: config.notification_command.clone(),
+            events: config.notification_events.clone(),
+            timeout: config.notification_timeout,
+        }
+    }
+
+    /// Run the notification command for an event in the background, if the command wants events
+    /// of its type, logging it if the command fails. This never waits for the command.
+    ///
+    /// The returned handle finishes once the command has finished or been killed.
+    pub fn notify(&self, event: NotificationEvent) -> Option<JoinHandle<()>> {
+        let wanted = match &self.events {
+            None => true,
+            Some(events) => events.contains(&event.event),
+        };
+        let command = match (&self.command, wanted) {
+            (Some(command), true) => command.clone(),
+            _ => return None,
+        };
+        let timeout = self.timeout;
+        Some(tokio::spawn(async move {
+            if let Err(e) = run(&command, timeout, &event).await {
+                tracing::warn!(
+                    label = %event.label,
+                    event = ?event.event,
+                    error = %e,
+                    "Notification command failed"
+                );
+            }
+        }))
+    }
+}
+
+/// Run the command once with the event on its standard input, killing it if it doesn't finish
+/// within the timeout.
+async fn run(
+    command: &Path,
+    timeout: Duration,
+    event: &NotificationEvent,
+) -> Result<(), NotificationError> {
+    let payload = serde_json::to_vec(event).expect("Notification events must be serializable");
+    let mut child = Command::new(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| NotificationError::Spawn(command.to_owned(), e))?;
+
+    let finished = async {
+        if let Some(mut stdin) = child.stdin.take() {
+            // A command needn't read the event, and may exit before it is written
+            match stdin.write_all(&payload).await {
+                Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => return Err(e),
+                _ => {}
+            }
+            // Closing standard input lets the command know the event is complete
+            drop(stdin);
+        }
+        child.wait().await
+    };
+
+    match tokio::time::timeout(timeout, finished).await {
+        Err(_) => Err(NotificationError::TimedOut(command.to_owned(), timeout)),
+        Ok(Err(e)) => Err(NotificationError::Io(command.to_owned(), e)),
+        Ok(Ok(status)) if !status.success() => {
+            Err(NotificationError::Failed(command.to_owned(), status))
+        }
+        Ok(Ok(_)) => Ok(()),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::fs::PermissionsExt, time::Instant};
+
+    /// Write an executable shell script with the given body into a fresh directory, returning its
+    /// path and the path of a file it may write to.
+    fn script(name: &str, body: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("notify-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let received = dir.join("received");
+        let path = dir.join("hook");
+        fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\n{}\n",
+                body.replace("$RECEIVED", &received.display().to_string())
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        (path, received)
+    }
+
+    fn notifications(
+        command: PathBuf,
+        events: Option<Vec<NotificationEventType>>,
+        timeout: Duration,
+    ) -> Notifications {
+        Notifications {
+            command: Some(command),
+            events,
+            timeout,
+        }
+    }
+
+    fn label() -> ChannelName {
+        ChannelName::new("my-channel".to_string())
+    }
+
+    #[tokio::test]
+    async fn command_receives_wanted_events() {
+        let (command, received) = script("receives", "cat >> \"$RECEIVED\"; echo >> \"$RECEIVED\"");
+        let notifications = notifications(
+            command,
+            Some(vec![
+                NotificationEventType::ExpiryPosted,
+                NotificationEventType::ActionRequired,
+            ]),
+            Duration::from_secs(10),
+        );
+
+        // Only the events the command asked for are passed to it
+        assert!(notifications
+            .notify(NotificationEvent::new(
+                NotificationEventType::FundsClaimed,
+                &label(),
+                None
+            ))
+            .is_none());
+        notifications
+            .notify(NotificationEvent::new(
+                NotificationEventType::ExpiryPosted,
+                &label(),
+                None,
+            ))
+            .unwrap()
+            .await
+            .unwrap();
+        notifications
+            .notify(NotificationEvent::action_required(
+                &label(),
+                None,
+                Entrypoint::CustomerClaim,
+            ))
+            .unwrap()
+            .await
+            .unwrap();
+
+        let received = fs::read_to_string(received).unwrap();
+        let events: Vec<NotificationEvent> = received
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event, NotificationEventType::ExpiryPosted);
+        assert_eq!(events[0].label, label());
+        assert_eq!(events[0].action, None);
+        assert_eq!(events[1].event, NotificationEventType::ActionRequired);
+        assert_eq!(events[1].action.as_deref(), Some("custClaim"));
+    }
+
+    #[tokio::test]
+    async fn hung_command_is_killed() {
+        let (command, received) = script("hung", "sleep 30; touch \"$RECEIVED\"");
+        let event = NotificationEvent::new(NotificationEventType::MerchantClaimed, &label(), None);
+
+        let started = Instant::now();
+        let result = run(&command, Duration::from_millis(100), &event).await;
+        assert!(matches!(result, Err(NotificationError::TimedOut(..))));
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        // The command doesn't keep running in the background
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!received.exists());
+    }
+
+    #[tokio::test]
+    async fn failing_command_is_reported() {
+        let (command, _) = script("failing", "exit 3");
+        let event = NotificationEvent::new(NotificationEventType::DisputeDetected, &label(), None);
+        assert!(matches!(
+            run(&command, Duration::from_secs(10), &event).await,
+            Err(NotificationError::Failed(..))
+        ));
+
+        let missing = command.with_file_name("missing");
+        assert!(matches!(
+            run(&missing, Duration::from_secs(10), &event).await,
+            Err(NotificationError::Spawn(..))
+        ));
+
+        // Failures are only logged, not returned to the daemon
+        notifications(command, None, Duration::from_secs(10))
+            .notify(event)
+            .unwrap()
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,945 @@
+//! Output for external tools: files written by the customer for off-chain flows, and
+//! machine-readable summaries of what a command did or of the customer's channels.
+
+use {
+    serde::Serialize,
+    std::{
+        borrow::Cow,
+        fmt::{self, Display, Formatter},
+        fs::{File, OpenOptions},
+        io::{self, Write},
+        path::{Path, PathBuf},
+        str::FromStr,
+        time::{Duration, SystemTime},
+    },
+    thiserror::Error,
+    zkabacus_crypto::{ChannelId, CustomerBalance, MerchantBalance},
+};
+
+use crate::{
+    amount::{Amount, XTZ},
+    customer::{
+        database::{
+            ChannelDetails, ChannelEvent, ClosingPath, ContractObservation, PaymentOutcome,
+            PaymentRecord, StateName,
+        },
+        ChannelName,
+    },
+    escrow::types::{ContractId, Entrypoint},
+    protocol::close::UnilateralCloseKind,
+};
+
+/// How many characters of a channel ID to show in a table of channels, which is enough to tell
+/// them apart.
+const CHANNEL_ID_PREFIX_LENGTH: usize = 12;
+
+/// An error when writing an output file.
+#[derive(Debug, Error)]
+pub enum OutputError {
+    #[error("Refusing to overwrite existing file {0:?}")]
+    AlreadyExists(PathBuf),
+    #[error("Could not open file for writing {0:?}: {1}")]
+    Open(PathBuf, #[source] io::Error),
+    #[error("Could not write to file {0:?}: {1}")]
+    Write(PathBuf, #[source] serde_json::Error),
+}
+
+/// Write the value as JSON to the file at the path, refusing to replace an existing file unless
+/// `overwrite` is set.
+pub fn write_json(path: &Path, value: &impl Serialize, overwrite: bool) -> Result<(), OutputError> {
+    JsonOutput::open(path, overwrite)?.write(value)
+}
+
+/// A file opened to write JSON to later, so that a file which can't be written is found out
+/// before doing whatever produces its contents.
+///
+/// A file this created is removed again if it is dropped without being written; a file which
+/// already existed is left as it was.
+#[derive(Debug)]
+pub struct JsonOutput {
+    path: PathBuf,
+    file: Option<File>,
+    created: bool,
+}
+
+impl JsonOutput {
+    /// Open the file at the path for writing, refusing to open an existing file unless
+    /// `overwrite` is set. An existing file isn't truncated until it is written.
+    pub fn open(path: &Path, overwrite: bool) -> Result<Self, OutputError> {
+        let opened = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map(|file| (file, true));
+        let opened = match opened {
+            Err(e) if overwrite && e.kind() == io::ErrorKind::AlreadyExists => OpenOptions::new()
+                .write(true)
+                .open(path)
+                .map(|file| (file, false)),
+            opened => opened,
+        };
+        let (file, created) = opened.map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => OutputError::AlreadyExists(path.to_owned()),
+            _ => OutputError::Open(path.to_owned(), e),
+        })?;
+        Ok(Self {
+            path: path.to_owned(),
+            file: Some(file),
+            created,
+        })
+    }
+
+    /// The path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write the value as JSON to the file, replacing anything already in it.
+    pub fn write(mut self, value: &impl Serialize) -> Result<(), OutputError> {
+        let mut file = self.file.take().expect("output is written only once");
+        file.set_len(0)
+            .map_err(|e| OutputError::Open(self.path.clone(), e))?;
+        serde_json::to_writer(&mut file, value)
+            .map_err(|e| OutputError::Write(self.path.clone(), e))
+    }
+}
+
+impl Drop for JsonOutput {
+    fn drop(&mut self) {
+        if let (Some(file), true) = (self.file.take(), self.created) {
+            drop(file);
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                tracing::warn!(path = ?self.path, error = %e, "Failed to remove unwritten output file");
+            }
+        }
+    }
+}
+
+/// How a channel was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CloseType {
+    /// Both parties agreed on the balances, which were paid out at once.
+    Mutual,
+    /// The customer posted the balances of their own accord.
+    Unilateral,
+    /// The customer posted the balances in response to the merchant initiating expiry.
+    ExpiryResponse,
+}
+
+impl From<UnilateralCloseKind> for CloseType {
+    fn from(kind: UnilateralCloseKind) -> Self {
+        match kind {
+            UnilateralCloseKind::CustomerInitiated => CloseType::Unilateral,
+            UnilateralCloseKind::MerchantInitiated => CloseType::ExpiryResponse,
+        }
+    }
+}
+
+impl Display for CloseType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CloseType::Mutual => "mutual",
+            CloseType::Unilateral => "unilateral",
+            CloseType::ExpiryResponse => "expiry response",
+        })
+    }
+}
+
+/// A summary of closing a channel.
+#[derive(Debug, Clone, Serialize)]
+pub struct CloseSummary {
+    pub label: ChannelName,
+    pub channel_id: ChannelId,
+    pub close_type: CloseType,
+    pub contract_id: Option<ContractId>,
+    /// The hashes of the operations posted to close the channel, in the order they were posted.
+    pub operation_hashes: Vec<String>,
+    /// Where the closing data was written, if the channel was closed off chain.
+    pub close_json: Option<PathBuf>,
+    pub customer_balance: CustomerBalance,
+    pub merchant_balance: MerchantBalance,
+    /// The state of the channel in the database once the command finished.
+    pub state: StateName,
+}
+
+impl Display for CloseSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({} close): {}",
+            self.label, self.close_type, self.state
+        )
+    }
+}
+
+/// Format a balance in mutez as an amount of tez.
+// TODO: don't hard-code XTZ here, instead store currency in database
+fn xtz(mutez: u64) -> String {
+    Amount::from_minor_units_of_currency(mutez as i64, XTZ).to_string()
+}
+
+/// What a channel in each state is doing, in a few words.
+pub fn describe_state(state: StateName) -> &'static str {
+    match state {
+        StateName::Inactive => "being established; the contract is not yet originated",
+        StateName::Originated => "being established; waiting for the customer's funding",
+        StateName::CustomerFunded => "being established; waiting for the merchant's funding",
+        StateName::MerchantFunded => "being established; waiting for the merchant to activate it",
+        StateName::Ready => "open for payments",
+        StateName::Started => "in the middle of a payment",
+        StateName::Locked => "in the middle of a payment, which the merchant must complete",
+        StateName::PendingMutualClose => "closing mutually; waiting for the contract to close",
+        StateName::PendingExpiry => "closing; waiting for the merchant to claim after expiry",
+        StateName::PendingClose => "closing; waiting to claim the customer's balance",
+        StateName::PendingCustomerClaim => "closing; waiting for the customer's claim to land",
+        StateName::Dispute => "closing; the merchant disputed the customer's balance",
+        StateName::Closed => "closed",
+        StateName::FundingReclaimed => "abandoned; the customer's funding was reclaimed",
+    }
+}
+
+/// A channel, as listed by `list`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelListing {
+    pub label: ChannelName,
+    pub state: StateName,
+    /// The customer's balance, such as `1.5 XTZ`.
+    pub balance: String,
+    /// The merchant's balance, which is the most the merchant can refund.
+    pub max_refund: String,
+    pub channel_id: String,
+    /// The ID of the channel's contract, or `N/A` if it has none yet.
+    pub contract_id: String,
+}
+
+impl ChannelListing {
+    /// The column headers of a table of channels.
+    pub const HEADER: [&'static str; 6] = [
+        "Label",
+        "State",
+        "Balance",
+        "Max Refund",
+        "Channel ID",
+        "Contract ID",
+    ];
+
+    pub fn new(channel: &ChannelDetails) -> Self {
+        Self {
+            label: channel.label.clone(),
+            state: channel.state.state_name(),
+            balance: xtz(channel.state.customer_balance().into_inner()),
+            max_refund: xtz(channel.state.merchant_balance().into_inner()),
+            channel_id: channel.state.channel_id().to_string(),
+            contract_id: channel
+                .contract_details
+                .contract_id
+                .as_ref()
+                .map_or_else(|| "N/A".to_string(), ToString::to_string),
+        }
+    }
+
+    /// The row of a table of channels for this channel, in the order of [`Self::HEADER`], with the
+    /// channel ID shortened.
+    pub fn row(&self) -> [String; 6] {
+        [
+            self.label.to_string(),
+            self.state.to_string(),
+            self.balance.clone(),
+            self.max_refund.clone(),
+            self.channel_id
+                .chars()
+                .take(CHANNEL_ID_PREFIX_LENGTH)
+                .collect(),
+            self.contract_id.clone(),
+        ]
+    }
+}
+
+/// A change in the kind of state of a channel, as shown by `show --history`.
+#[derive(Debug, Clone, Serialize)]
+pub struct StateChange {
+    pub from: StateName,
+    pub to: StateName,
+    /// When the change was made, in RFC 3339 format.
+    pub timestamp: String,
+}
+
+impl StateChange {
+    /// The column headers of a table of state changes.
+    pub const HEADER: [&'static str; 3] = ["Time", "From", "To"];
+
+    pub fn new(event: &ChannelEvent) -> Self {
+        Self {
+            from: event.old_state,
+            to: event.new_state,
+            timestamp: rfc3339(event.recorded_at),
+        }
+    }
+
+    /// The row of a table of state changes for this change, in the order of [`Self::HEADER`].
+    pub fn row(&self) -> [String; 3] {
+        [
+            self.timestamp.clone(),
+            self.from.to_string(),
+            self.to.to_string(),
+        ]
+    }
+}
+
+/// A payment begun on a channel, as shown by `show --payments`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PaymentSummary {
+    /// When the payment began, in RFC 3339 format.
+    pub started_at: String,
+    /// The amount paid, which is negative for a refund.
+    pub amount: String,
+    pub note: String,
+    pub outcome: PaymentOutcome,
+    /// Why the payment failed, if it did.
+    pub failure_reason: Option<String>,
+    pub response_note: Option<String>,
+    /// The customer's balance when the payment began, if it was recorded.
+    pub customer_balance_before: Option<String>,
+    /// The customer's balance once the payment completed, or when it began if it didn't.
+    pub customer_balance_after: String,
+}
+
+impl PaymentSummary {
+    /// The column headers of a table of payments.
+    pub const HEADER: [&'static str; 7] = [
+        "Started",
+        "Amount",
+        "Note",
+        "Outcome",
+        "Response",
+        "Balance before",
+        "Balance after",
+    ];
+
+    pub fn new(payment: &PaymentRecord) -> Self {
+        Self {
+            started_at: rfc3339(payment.started_at),
+            amount: signed_xtz(payment.amount),
+            note: payment.note.clone(),
+            outcome: payment.outcome,
+            failure_reason: payment.failure_reason.clone(),
+            response_note: payment.response_note.clone(),
+            customer_balance_before: payment
+                .customer_balance_before
+                .map(|balance| xtz(balance as u64)),
+            customer_balance_after: xtz(payment.customer_balance as u64),
+        }
+    }
+
+    /// The row of a table of payments for this payment, in the order of [`Self::HEADER`].
+    pub fn row(&self) -> [String; 7] {
+        let outcome = match &self.failure_reason {
+            Some(reason) => format!("{}: {}", self.outcome, reason),
+            None => self.outcome.to_string(),
+        };
+        [
+            self.started_at.clone(),
+            self.amount.clone(),
+            self.note.clone(),
+            outcome,
+            self.response_note.clone().unwrap_or_default(),
+            self.customer_balance_before
+                .clone()
+                .unwrap_or_else(|| "N/A".to_string()),
+            self.customer_balance_after.clone(),
+        ]
+    }
+}
+
+/// One of a series of payments made by `pay --repeat` or `--until-total`, which are written as
+/// they complete, one JSON object per line.
+#[derive(Debug, Clone, Serialize)]
+pub struct RepeatedPayment {
+    /// Which payment of the series this is, counting from 1.
+    pub iteration: u64,
+    pub label: ChannelName,
+    /// The amount paid, which is negative for a refund.
+    pub amount: String,
+    pub response_note: Option<String>,
+    /// How long the payment took, in milliseconds.
+    pub elapsed_ms: u64,
+}
+
+impl RepeatedPayment {
+    pub fn new(
+        iteration: u64,
+        label: &ChannelName,
+        amount: i64,
+        response_note: Option<String>,
+        elapsed: Duration,
+    ) -> Self {
+        Self {
+            iteration,
+            label: label.clone(),
+            amount: signed_xtz(amount),
+            response_note,
+            elapsed_ms: elapsed.as_millis() as u64,
+        }
+    }
+}
+
+/// The end of a series of payments made by `pay --repeat` or `--until-total`, written after the
+/// last of them.
+#[derive(Debug, Clone, Serialize)]
+pub struct RepeatedPaymentsSummary {
+    pub label: ChannelName,
+    /// How many payments completed.
+    pub completed: u64,
+    /// The total of the completed payments, which is negative for refunds.
+    pub total: String,
+    /// Why the series stopped early, if it did.
+    pub error: Option<String>,
+}
+
+impl RepeatedPaymentsSummary {
+    pub fn new(label: &ChannelName, completed: u64, amount: i64, error: Option<String>) -> Self {
+        Self {
+            label: label.clone(),
+            completed,
+            total: signed_xtz(amount.saturating_mul(completed as i64)),
+            error,
+        }
+    }
+}
+
+/// Format an amount in mutez as XTZ, which is negative for a refund.
+fn signed_xtz(mutez: i64) -> String {
+    let amount = xtz(mutez.unsigned_abs());
+    if mutez < 0 {
+        format!("-{}", amount)
+    } else {
+        amount
+    }
+}
+
+/// Format a time in RFC 3339 format, to the second.
+fn rfc3339(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+/// The formats in which `history` can write a channel's payment history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryFormat {
+    /// Comma-separated values, with a header row.
+    Csv,
+    /// JSON lines: one object per record, each on its own line.
+    Json,
+}
+
+/// An error parsing a [`HistoryFormat`].
+#[derive(Debug, Error)]
+#[error("Unknown history format \"{0}\": expected \"csv\" or \"json\"")]
+pub struct ParseHistoryFormatError(String);
+
+impl FromStr for HistoryFormat {
+    type Err = ParseHistoryFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(HistoryFormat::Csv),
+            "json" => Ok(HistoryFormat::Json),
+            _ => Err(ParseHistoryFormatError(s.to_string())),
+        }
+    }
+}
+
+/// What a row of a channel's payment history records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryKind {
+    Payment,
+    Refund,
+    /// The channel was closed, with the final balances of the row.
+    Closed,
+}
+
+impl Display for HistoryKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HistoryKind::Payment => "payment",
+            HistoryKind::Refund => "refund",
+            HistoryKind::Closed => "closed",
+        })
+    }
+}
+
+/// A row of a channel's payment history, as written by `history`: a completed payment or refund,
+/// or a summary of how a closed channel ended.
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryRecord {
+    /// When the payment completed or the channel closed, in RFC 3339 format, if known.
+    pub timestamp: Option<String>,
+    pub label: ChannelName,
+    pub kind: HistoryKind,
+    /// The amount paid, or refunded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /// The note the merchant sent in response to the payment, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_note: Option<String>,
+    /// The balances once the payment completed, or the final balances of a closed channel.
+    pub customer_balance: Option<String>,
+    pub merchant_balance: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub closing_path: Option<ClosingPath>,
+    /// The ID the customer chose for the payment, if it had one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payment_id: Option<String>,
+    /// What became of the payment, which is only not `completed` when looking up a payment by
+    /// its ID.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<String>,
+}
+
+impl HistoryRecord {
+    /// The header row of the history in CSV format.
+    pub const CSV_HEADER: [&'static str; 11] = [
+        "timestamp",
+        "label",
+        "kind",
+        "amount",
+        "note",
+        "response_note",
+        "customer_balance",
+        "merchant_balance",
+        "closing_path",
+        "payment_id",
+        "outcome",
+    ];
+
+    pub fn payment(payment: &PaymentRecord) -> Self {
+        let (kind, amount) = if payment.amount < 0 {
+            (HistoryKind::Refund, payment.amount.unsigned_abs())
+        } else {
+            (HistoryKind::Payment, payment.amount as u64)
+        };
+        Self {
+            timestamp: Some(rfc3339(payment.paid_at)),
+            label: payment.label.clone(),
+            kind,
+            amount: Some(xtz(amount)),
+            note: Some(payment.note.clone()),
+            response_note: payment.response_note.clone(),
+            customer_balance: Some(xtz(payment.customer_balance as u64)),
+            merchant_balance: Some(xtz(payment.merchant_balance as u64)),
+            closing_path: None,
+            payment_id: payment.payment_id.map(|id| id.to_string()),
+            outcome: Some(payment.outcome.to_string()),
+        }
+    }
+
+    /// The summary of a closed channel, which closed at `closed_at` if that is known.
+    pub fn closed(channel: &ChannelDetails, closed_at: Option<SystemTime>) -> Self {
+        Self {
+            timestamp: closed_at.map(rfc3339),
+            label: channel.label.clone(),
+            kind: HistoryKind::Closed,
+            amount: None,
+            note: None,
+            response_note: None,
+            customer_balance: channel
+                .closing_balances
+                .customer_balance
+                .map(|balance| xtz(balance.into_inner())),
+            merchant_balance: channel
+                .closing_balances
+                .merchant_balance
+                .map(|balance| xtz(balance.into_inner())),
+            closing_path: channel.closing_path,
+            payment_id: None,
+            outcome: None,
+        }
+    }
+
+    /// Write the record in the given format, followed by a newline.
+    pub fn write(&self, format: HistoryFormat, mut out: impl Write) -> io::Result<()> {
+        match format {
+            HistoryFormat::Json => {
+                serde_json::to_writer(&mut out, self)?;
+                writeln!(out)
+            }
+            HistoryFormat::Csv => {
+                let fields = [
+                    self.timestamp.clone().unwrap_or_default(),
+                    self.label.to_string(),
+                    self.kind.to_string(),
+                    self.amount.clone().unwrap_or_default(),
+                    self.note.clone().unwrap_or_default(),
+                    self.response_note.clone().unwrap_or_default(),
+                    self.customer_balance.clone().unwrap_or_default(),
+                    self.merchant_balance.clone().unwrap_or_default(),
+                    self.closing_path
+                        .map(|path| path.to_string())
+                        .unwrap_or_default(),
+                    self.payment_id.clone().unwrap_or_default(),
+                    self.outcome.clone().unwrap_or_default(),
+                ];
+                write_csv_row(out, fields.iter().map(String::as_str))
+            }
+        }
+    }
+}
+
+/// Write a row of comma-separated values, followed by a newline.
+pub fn write_csv_row<'a>(
+    mut out: impl Write,
+    fields: impl IntoIterator<Item = &'a str>,
+) -> io::Result<()> {
+    let row: Vec<_> = fields.into_iter().map(csv_field).collect();
+    writeln!(out, "{}", row.join(","))
+}
+
+/// Quote a field of comma-separated values if it holds anything which would otherwise end it
+/// early, doubling any quotes within it (as in RFC 4180).
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Everything known about a channel, as shown by `show`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelDescription {
+    #[serde(flatten)]
+    pub listing: ChannelListing,
+    /// What the channel is doing, in a few words.
+    pub description: &'static str,
+    pub address: String,
+    pub customer_deposit: String,
+    pub merchant_deposit: String,
+    pub merchant_tezos_public_key: String,
+    /// The level of the block in which the contract was originated, if it has been.
+    pub contract_level: Option<u32>,
+    /// The customer's balance when the channel was closed, if it has been.
+    pub closing_customer_balance: Option<String>,
+    /// The merchant's balance when the channel was closed, if it has been.
+    pub closing_merchant_balance: Option<String>,
+    /// How the closing balances were paid out, if they have been.
+    pub closing_path: Option<ClosingPath>,
+    /// Whether a custClose operation produced off chain is waiting to be broadcast.
+    pub awaiting_broadcast: bool,
+    /// The operations being posted on the channel's contract right now.
+    pub operations_in_flight: Vec<Entrypoint>,
+    /// What the chain-watching daemon last saw of the channel's contract, if anything.
+    pub last_observation: Option<ContractObservation>,
+    /// Every change in the kind of state of the channel, oldest first, if it was asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history: Option<Vec<StateChange>>,
+    /// Every payment begun on the channel, oldest first, if they were asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payments: Option<Vec<PaymentSummary>>,
+}
+
+impl ChannelDescription {
+    pub fn new(
+        channel: &ChannelDetails,
+        operations_in_flight: Vec<Entrypoint>,
+        last_observation: Option<ContractObservation>,
+    ) -> Self {
+        Self {
+            listing: ChannelListing::new(channel),
+            description: describe_state(channel.state.state_name()),
+            address: channel.address.to_string(),
+            customer_deposit: xtz(channel.customer_deposit.into_inner()),
+            merchant_deposit: xtz(channel.merchant_deposit.into_inner()),
+            merchant_tezos_public_key: channel
+                .contract_details
+                .merchant_tezos_public_key
+                .to_base58check(),
+            contract_level: channel.contract_details.contract_level,
+            closing_customer_balance: channel
+                .closing_balances
+                .customer_balance
+                .map(|balance| xtz(balance.into_inner())),
+            closing_merchant_balance: channel
+                .closing_balances
+                .merchant_balance
+                .map(|balance| xtz(balance.into_inner())),
+            closing_path: channel.closing_path,
+            awaiting_broadcast: channel.awaiting_broadcast,
+            operations_in_flight,
+            last_observation,
+            history: None,
+            payments: None,
+        }
+    }
+
+    /// Include the channel's history of state changes.
+    pub fn with_history(self, history: &[ChannelEvent]) -> Self {
+        Self {
+            history: Some(history.iter().map(StateChange::new).collect()),
+            ..self
+        }
+    }
+
+    /// Include the channel's payments, whatever became of them.
+    pub fn with_payments(self, payments: &[PaymentRecord]) -> Self {
+        Self {
+            payments: Some(payments.iter().map(PaymentSummary::new).collect()),
+            ..self
+        }
+    }
+
+    /// The name and value of each detail, in the order to show them.
+    pub fn rows(&self) -> Vec<(&'static str, String)> {
+        let or_none = |value: &Option<String>| value.clone().unwrap_or_else(|| "N/A".to_string());
+        let operations = if self.operations_in_flight.is_empty() {
+            "none".to_string()
+        } else {
+            self.operations_in_flight
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let last_observation = match &self.last_observation {
+            None => "never checked".to_string(),
+            Some(observation) => format!(
+                "{:?} at level {}{}",
+                observation.status,
+                observation.level,
+                match (observation.reaction, observation.required_action) {
+                    (Some(reaction), _) => format!(" (posted {})", reaction),
+                    (None, Some(action)) => format!(" (needs {})", action),
+                    (None, None) => String::new(),
+                }
+            ),
+        };
+
+        vec![
+            ("Label", self.listing.label.to_string()),
+            (
+                "State",
+                format!("{} ({})", self.listing.state, self.description),
+            ),
+            ("Address", self.address.clone()),
+            ("Channel ID", self.listing.channel_id.clone()),
+            ("Contract ID", self.listing.contract_id.clone()),
+            (
+                "Contract level",
+                self.contract_level
+                    .map_or_else(|| "N/A".to_string(), |level| level.to_string()),
+            ),
+            ("Merchant key", self.merchant_tezos_public_key.clone()),
+            ("Customer deposit", self.customer_deposit.clone()),
+            ("Merchant deposit", self.merchant_deposit.clone()),
+            ("Balance", self.listing.balance.clone()),
+            ("Max refund", self.listing.max_refund.clone()),
+            (
+                "Closing customer balance",
+                or_none(&self.closing_customer_balance),
+            ),
+            (
+                "Closing merchant balance",
+                or_none(&self.closing_merchant_balance),
+            ),
+            (
+                "Closed by",
+                or_none(&self.closing_path.map(|path| path.to_string())),
+            ),
+            (
+                "Awaiting broadcast",
+                if self.awaiting_broadcast { "yes" } else { "no" }.to_string(),
+            ),
+            ("Operations in flight", operations),
+            ("Last checked", last_observation),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_fields_are_escaped() {
+        let mut out = Vec::new();
+        write_csv_row(
+            &mut out,
+            [
+                "plain",
+                "coffee, with milk",
+                "two\nlines",
+                "a \"quoted\" word",
+                "",
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "plain,\"coffee, with milk\",\"two\nlines\",\"a \"\"quoted\"\"\" word\",\n"
+        );
+    }
+
+    #[test]
+    fn payment_history_as_csv() {
+        let payment = PaymentRecord {
+            label: ChannelName::new("café, downtown".to_string()),
+            amount: -1_500_000,
+            note: "refund for\r\nlatte".to_string(),
+            outcome: PaymentOutcome::Completed,
+            failure_reason: None,
+            response_note: Some("refunded".to_string()),
+            customer_balance_before: Some(2_000_000),
+            customer_balance: 3_500_000,
+            merchant_balance: 1_500_000,
+            started_at: SystemTime::UNIX_EPOCH,
+            paid_at: SystemTime::UNIX_EPOCH,
+            payment_id: Some("67e55044-10b1-426f-9247-bb680e5fe0c8".parse().unwrap()),
+        };
+        let mut out = Vec::new();
+        HistoryRecord::payment(&payment)
+            .write(HistoryFormat::Csv, &mut out)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "1970-01-01T00:00:00Z,\"café, downtown\",refund,{},\"refund for\r\nlatte\",refunded,{},{},,67e55044-10b1-426f-9247-bb680e5fe0c8,completed\n",
+                xtz(1_500_000),
+                xtz(3_500_000),
+                xtz(1_500_000),
+            )
+        );
+    }
+
+    #[test]
+    fn refuse_to_overwrite() {
+        let path = std::env::temp_dir().join(format!("output-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        write_json(&path, &1, false).unwrap();
+        assert!(matches!(
+            write_json(&path, &2, false),
+            Err(OutputError::AlreadyExists(_))
+        ));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "1");
+
+        write_json(&path, &3, true).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "3");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn output_is_opened_before_it_is_written() {
+        let path = std::env::temp_dir().join(format!("opened-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // An output dropped unwritten leaves nothing behind
+        let output = JsonOutput::open(&path, false).unwrap();
+        assert!(path.exists());
+        drop(output);
+        assert!(!path.exists());
+
+        // An existing file is refused up front, and left as it was
+        std::fs::write(&path, "existing contents").unwrap();
+        assert!(matches!(
+            JsonOutput::open(&path, false),
+            Err(OutputError::AlreadyExists(_))
+        ));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "existing contents");
+
+        // When overwriting, the existing file is kept until it is written, and then replaced
+        drop(JsonOutput::open(&path, true).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "existing contents");
+        JsonOutput::open(&path, true).unwrap().write(&1).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "1");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn close_summary_json() {
+        use rand::{rngs::StdRng, SeedableRng};
+        use zkabacus_crypto::{CustomerRandomness, KeyPair, MerchantRandomness};
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let public_key = KeyPair::new(&mut rng).public_key().clone();
+        let channel_id = ChannelId::new(
+            MerchantRandomness::new(&mut rng),
+            CustomerRandomness::new(&mut rng),
+            &public_key,
+            &[],
+            &[],
+        );
+
+        let summary = CloseSummary {
+            label: "my-channel".parse().unwrap(),
+            channel_id,
+            close_type: UnilateralCloseKind::MerchantInitiated.into(),
+            contract_id: None,
+            operation_hashes: vec!["ooFakeOperationHash".to_string()],
+            close_json: None,
+            customer_balance: CustomerBalance::try_new(5).unwrap(),
+            merchant_balance: MerchantBalance::try_new(7).unwrap(),
+            state: StateName::PendingClose,
+        };
+
+        let json = serde_json::to_value(&summary).unwrap();
+        let mut fields: Vec<&str> = json
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        fields.sort_unstable();
+        assert_eq!(
+            fields,
+            vec![
+                "channel_id",
+                "close_json",
+                "close_type",
+                "contract_id",
+                "customer_balance",
+                "label",
+                "merchant_balance",
+                "operation_hashes",
+                "state",
+            ]
+        );
+        assert_eq!(json["label"], "my-channel");
+        assert_eq!(json["close_type"], "expiry-response");
+        assert_eq!(json["operation_hashes"][0], "ooFakeOperationHash");
+        assert!(json["contract_id"].is_null());
+    }
+
+    #[test]
+    fn every_state_is_formatted() {
+        use strum::IntoEnumIterator;
+
+        let mut descriptions = Vec::new();
+        for state in StateName::iter() {
+            let listing = ChannelListing {
+                label: "my-channel".parse().unwrap(),
+                state,
+                balance: xtz(1_500_000),
+                max_refund: xtz(0),
+                channel_id: "abcdefghijklmnopqrstuvwxyz".to_string(),
+                contract_id: "N/A".to_string(),
+            };
+            let row = listing.row();
+            assert_eq!(row.len(), ChannelListing::HEADER.len());
+            assert_eq!(row[1], state.to_string());
+            assert_eq!(row[4], "abcdefghijkl");
+
+            // Each state is serialized by its name, and described differently
+            let json = serde_json::to_value(&listing).unwrap();
+            assert_eq!(json["state"], format!("{:?}", state));
+            let description = describe_state(state);
+            assert!(!description.is_empty());
+            assert!(!descriptions.contains(&description), "{:?}", state);
+            descriptions.push(description);
+        }
+        assert_eq!(descriptions.len(), 14);
+    }
+}
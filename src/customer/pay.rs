@@ -0,0 +1,702 @@
+//! The customer's side of a payment, shared between payments made directly by the customer CLI
+//! and those proxied through the daemon.
+
+use {anyhow::Context, dialectic::prelude::*, rand::rngs::StdRng, std::time::Duration};
+
+use zkabacus_crypto::{
+    customer::{LockMessage, StartMessage},
+    revlock::{RevocationLockBlindingFactor, RevocationPair},
+    ClosingSignature, Context as ProofContext, Nonce, PayProof, PayToken, PaymentAmount,
+};
+
+use crate::{
+    abort,
+    customer::{
+        database::{
+            zkchannels_state, NewPayment, PaymentLock, PaymentStart, QueryCustomer,
+            QueryCustomerExt, State, StateName,
+        },
+        ChannelName, Config,
+    },
+    offer_abort, proceed,
+    protocol::{daemon::PayProgress, pay, AbortReason, Party::Customer, Transcript},
+    timeout::{await_response, WithTimeout},
+};
+
+/// Make a payment over a session with the merchant, reporting progress as it is made, and return
+/// the merchant's response note, if any.
+///
+/// The `transcript` is that of the session so far, which is started from its session key.
+#[allow(clippy::too_many_arguments)]
+#[Transmitter(Tx for PaymentAmount, String, pay::PaymentId, Nonce, PayProof, RevocationPair,
+    RevocationLockBlindingFactor, AbortReason)]
+#[Receiver(Rx for AbortReason, ClosingSignature, PayToken, Option<pay::PaymentId>, Option<String>)]
+pub async fn make_payment<Tx, Rx>(
+    rng: StdRng,
+    config: &Config,
+    database: &dyn QueryCustomer,
+    label: &ChannelName,
+    mut transcript: Transcript,
+    chan: Chan<pay::Pay, Tx, Rx>,
+    payment_amount: PaymentAmount,
+    note: String,
+    payment_id: pay::PaymentId,
+    progress: impl Fn(PayProgress),
+) -> Result<Option<String>, anyhow::Error>
+where
+    Tx::Error: std::error::Error + Send + Sync + 'static,
+    Rx::Error: std::error::Error + Send + Sync + 'static,
+{
+    // Record the payment in the channel's history as pending: it is completed along with the
+    // channel's new state, or marked as failed if the session ends without one and can't be
+    // resumed
+    let record_id = database
+        .begin_payment(
+            label,
+            &NewPayment {
+                amount: payment_amount.to_i64(),
+                note: note.clone(),
+                payment_id,
+            },
+        )
+        .await
+        .with_context(|| format!("Failed to record payment on channel {}", label))?;
+
+    let result = async {
+        let chan = request_payment(chan, &mut transcript, payment_amount, note, payment_id)
+            .with_timeout(config.approval_timeout)
+            .await
+            .context("Payment timed out while awaiting approval")?
+            .context("Payment was not approved by the merchant")?;
+        progress(PayProgress::Approved);
+
+        // Run the core zkAbacus.Pay protocol, in which each of the merchant's responses is timed
+        // separately so that our own proofs never count against the timeout
+        let chan = zkabacus_pay(
+            rng,
+            database,
+            label,
+            transcript,
+            chan,
+            payment_amount,
+            record_id,
+            config.payment_response_timeout,
+        )
+        .await
+        .context("Failed to complete pay protocol")?;
+        progress(PayProgress::Paid);
+
+        receive_service(chan, Some(payment_id))
+            .with_timeout(config.approval_timeout)
+            .await
+            .context("Payment timed out when receiving service")?
+    }
+    .await;
+
+    record_outcome(database, label, record_id, &result).await;
+    result
+}
+
+/// Record how a payment session ended in the channel's payment history, logging any failure to
+/// do so.
+///
+/// A payment which completed stays completed, even if the service was not received. A payment
+/// which was interrupted after it started, leaving the channel in the middle of the payment, stays
+/// pending so that it can be resumed.
+pub async fn record_outcome(
+    database: &dyn QueryCustomer,
+    label: &ChannelName,
+    payment_id: i64,
+    result: &Result<Option<String>, anyhow::Error>,
+) {
+    let recorded = match result {
+        Err(e) => match database.get_channel(label).await {
+            Ok(channel)
+                if matches!(
+                    channel.state.state_name(),
+                    StateName::Started | StateName::Locked
+                ) =>
+            {
+                tracing::warn!(
+                    %label,
+                    error = %e,
+                    "Payment was interrupted partway; run `zkchannel customer repair` on the \
+                    channel to finish it, or close the channel if it can't be finished"
+                );
+                Ok(())
+            }
+            _ => database.fail_payment(payment_id, &format!("{:#}", e)).await,
+        },
+        Ok(Some(response_note)) => {
+            database
+                .set_payment_response_note(payment_id, response_note)
+                .await
+        }
+        Ok(None) => Ok(()),
+    };
+    if let Err(db_error) = recorded {
+        tracing::error!(%label, payment_id, error = %db_error, "Failed to record payment outcome");
+    }
+}
+
+/// Request approval for the payment request from the merchant, aborting the session if it is not
+/// granted.
+#[Transmitter(Tx for PaymentAmount, String, pay::PaymentId)]
+#[Receiver(Rx for AbortReason)]
+async fn request_payment<Tx, Rx>(
+    chan: Chan<pay::Pay, Tx, Rx>,
+    transcript: &mut Transcript,
+    payment_amount: PaymentAmount,
+    note: String,
+    payment_id: pay::PaymentId,
+) -> Result<Chan<pay::CustomerStartPayment, Tx, Rx>, anyhow::Error>
+where
+    Tx::Error: std::error::Error + Send + Sync + 'static,
+    Rx::Error: std::error::Error + Send + Sync + 'static,
+{
+    transcript.append(&payment_amount);
+    transcript.append(&note);
+    transcript.append(&payment_id);
+
+    // Send the payment amount, note, and ID to the merchant
+    let chan = chan
+        .send(payment_amount)
+        .await
+        .context("Failed to send payment amount")?
+        .send(note)
+        .await
+        .context("Failed to send payment note")?
+        .send(payment_id)
+        .await
+        .context("Failed to send payment ID")?;
+
+    // Allow the merchant to accept or reject the payment and note
+    offer_abort!(in chan as Customer);
+
+    Ok(chan)
+}
+
+/// Receive the paid-for service from the merchant, returning the response note if there is one
+/// and closing the communication channel.
+///
+/// If `payment_id` is given, the merchant must echo it back, to show the service is for this
+/// payment.
+#[Transmitter(Tx)]
+#[Receiver(Rx for Option<pay::PaymentId>, Option<String>)]
+pub async fn receive_service<Tx, Rx>(
+    chan: Chan<pay::MerchantProvideService, Tx, Rx>,
+    payment_id: Option<pay::PaymentId>,
+) -> Result<Option<String>, anyhow::Error>
+where
+    Rx::Error: std::error::Error + Send + Sync + 'static,
+{
+    let (echoed, chan) = chan.recv().await.context("Failed to receive payment ID")?;
+    if let (Some(expected), Some(echoed)) = (payment_id, echoed) {
+        if echoed != expected {
+            return Err(anyhow::anyhow!(
+                "Merchant concluded payment {} instead of payment {}",
+                echoed,
+                expected
+            ));
+        }
+    }
+
+    // Receive the response note (i.e. the fulfillment of the service)
+    let (response_note, chan) = chan
+        .recv()
+        .await
+        .context("Failed to receive response note")?;
+
+    // Close the communication channel: we are done communicating with the merchant
+    chan.close();
+
+    Ok(response_note)
+}
+
+/// The core zkAbacus.Pay protocol: receive a valid, updated channel state.
+#[allow(clippy::too_many_arguments)]
+#[Transmitter(Tx for Nonce, PayProof, RevocationPair, RevocationLockBlindingFactor, AbortReason)]
+#[Receiver(Rx for AbortReason, ClosingSignature, PayToken)]
+async fn zkabacus_pay<Tx, Rx>(
+    mut rng: StdRng,
+    database: &dyn QueryCustomer,
+    label: &ChannelName,
+    transcript: Transcript,
+    chan: Chan<pay::CustomerStartPayment, Tx, Rx>,
+    payment_amount: PaymentAmount,
+    payment_id: i64,
+    response_timeout: Duration,
+) -> Result<Chan<pay::MerchantProvideService, Tx, Rx>, anyhow::Error>
+where
+    Tx::Error: std::error::Error + Send + Sync + 'static,
+    Rx::Error: std::error::Error + Send + Sync + 'static,
+{
+    // Generate the shared context for proofs from the transcript so far
+    let context = transcript.context();
+
+    // Start the zkAbacus core payment and get fresh proofs and commitments, keeping them so that
+    // the payment can be resumed if the session is interrupted from here on
+    let start: PaymentStart = start_payment(&mut rng, database, label, payment_amount, context)
+        .await?
+        .into();
+    database
+        .record_payment_start(payment_id, &start)
+        .await
+        .with_context(|| format!("Failed to record payment on channel {}", label))?;
+
+    send_payment(
+        database,
+        label,
+        chan,
+        payment_id,
+        start,
+        None,
+        response_timeout,
+    )
+    .await
+}
+
+/// The rest of the zkAbacus.Pay protocol, once the payment has started: send the nonce and pay
+/// proof, lock the payment with the merchant's closing signature, and unlock it with the pay token.
+///
+/// When resuming a payment which was already locked, `lock` is the revocation revealed then, which
+/// is revealed again instead of locking the payment.
+///
+/// If the merchant takes longer than `response_timeout` to send either response, the session is
+/// dropped, leaving the payment to be resumed.
+#[Transmitter(Tx for Nonce, PayProof, RevocationPair, RevocationLockBlindingFactor, AbortReason)]
+#[Receiver(Rx for AbortReason, ClosingSignature, PayToken)]
+pub async fn send_payment<Tx, Rx>(
+    database: &dyn QueryCustomer,
+    label: &ChannelName,
+    chan: Chan<pay::CustomerStartPayment, Tx, Rx>,
+    payment_id: i64,
+    start: PaymentStart,
+    lock: Option<PaymentLock>,
+    response_timeout: Duration,
+) -> Result<Chan<pay::MerchantProvideService, Tx, Rx>, anyhow::Error>
+where
+    Tx::Error: std::error::Error + Send + Sync + 'static,
+    Rx::Error: std::error::Error + Send + Sync + 'static,
+{
+    // Send the initial proofs and commitments to the merchant
+    let chan = chan
+        .send(start.nonce)
+        .await
+        .context("Failed to send nonce")?
+        .send(start.pay_proof)
+        .await
+        .context("Failed to send payment proof")?;
+
+    // Receive a closing signature from the merchant, allowing the merchant to cancel the session
+    // at this point instead, and throw an error if so
+    let (closing_signature, chan) =
+        await_response(response_timeout, "closing signature", async move {
+            offer_abort!(in chan as Customer);
+            chan.recv()
+                .await
+                .context("Failed to receive closing signature")
+        })
+        .await?;
+
+    // Verify the closing signature and transition into a locked state, unless the payment was
+    // already locked
+    let lock = match lock {
+        Some(lock) => lock,
+        None => match lock_payment(database, label, closing_signature).await? {
+            Some(lock_message) => {
+                let lock = PaymentLock::from(lock_message);
+                database
+                    .record_payment_lock(payment_id, &lock)
+                    .await
+                    .with_context(|| format!("Failed to record payment on channel {}", label))?;
+                lock
+            }
+            None => {
+                // If the closing signature does not verify, inform the merchant we are aborting
+                abort!(in chan return pay::Error::InvalidClosingSignature);
+            }
+        },
+    };
+    proceed!(in chan);
+
+    // Reveal our lock, secret, and blinding factor
+    let chan = chan
+        .send(lock.revocation_pair)
+        .await
+        .context("Failed to send revocation pair")?
+        .send(lock.revocation_lock_blinding_factor)
+        .await
+        .context("Failed to send revocation lock blinding factor")?;
+
+    // Receive a pay token from the merchant, which allows us to pay again, allowing the merchant
+    // to cancel the session at this point instead, and throw an error if so
+    let (pay_token, chan) = await_response(response_timeout, "payment token", async move {
+        offer_abort!(in chan as Customer);
+        chan.recv().await.context("Failed to receive payment token")
+    })
+    .await?;
+
+    // Unlock the payment channel using the pay token
+    unlock_payment(database, label, pay_token, payment_id).await?;
+
+    Ok(chan)
+}
+
+/// Attempt to start the payment for the channel of the given label, using the given
+/// [`PaymentAmount`] and [`ProofContext`].
+///
+/// Returns the [`StartMessage`] for broadcast to the merchant if successful.
+async fn start_payment(
+    rng: &mut StdRng,
+    database: &dyn QueryCustomer,
+    label: &ChannelName,
+    payment_amount: PaymentAmount,
+    context: ProofContext,
+) -> Result<StartMessage, anyhow::Error> {
+    let zkabacus_config = database.channel_zkabacus_config(label).await?;
+    // Try to start the payment. If successful, update channel status to `Started`.
+    database
+        .with_channel_state(label, zkchannels_state::Ready, |ready| {
+            // Try to start the payment using the payment amount and proof context
+            match ready.start(rng, payment_amount, &context, &zkabacus_config) {
+                Ok((started, start_message)) => Ok((State::Started(started), start_message)),
+                Err((_, e)) => Err(pay::Error::StartFailed(e)),
+            }
+        })
+        .await
+        .with_context(|| format!("Failed to update channel {} to Started status", &label))?
+        .map_err(|e| e.into())
+}
+
+/// Attempt to lock a started payment for the channel of the given label, using the given
+/// [`ClosingSignature`].
+///
+/// Returns the [`LockMessage`] for broadcast to the merchant if successful, or `None` if the
+/// database operations succeeded but the closing signature was invalid.
+async fn lock_payment(
+    database: &dyn QueryCustomer,
+    label: &ChannelName,
+    closing_signature: ClosingSignature,
+) -> Result<Option<LockMessage>, anyhow::Error> {
+    let zkabacus_config = database.channel_zkabacus_config(label).await?;
+    // Try to continue (lock) the payment. If successful, update channel status to `Locked`.
+    database
+        .with_channel_state(label, zkchannels_state::Started, |started| {
+            // Attempt to lock the state using the closing signature. If it fails, raise a `pay::Error`.
+            match started.lock(closing_signature, &zkabacus_config) {
+                Ok((locked, lock_message)) => Ok((State::Locked(locked), lock_message)),
+                Err(_) => Err(pay::Error::InvalidClosingSignature),
+            }
+        })
+        .await
+        .map(Result::ok)
+        .with_context(|| format!("Failed to update channel {} to Locked status", &label))
+}
+
+/// Attempt to unlock a locked payment for a channel of the given label, using the given
+/// [`PayToken`].
+///
+/// If successful, this updates the state in the database for the channel so that it is ready for
+/// the next payment, and completes the pending payment in the channel's history along with it.
+async fn unlock_payment(
+    database: &dyn QueryCustomer,
+    label: &ChannelName,
+    pay_token: PayToken,
+    payment_id: i64,
+) -> Result<(), anyhow::Error> {
+    let zkabacus_config = database.channel_zkabacus_config(label).await?;
+    // Try to finish (unlock) the payment. If successful, update channel status to `Ready`.
+    database
+        .with_channel_state_paid(label, zkchannels_state::Locked, payment_id, |locked| {
+            // Attempt to unlock the state using the pay token
+            match locked.unlock(pay_token, &zkabacus_config) {
+                Ok(ready) => Ok((State::Ready(ready), ())),
+                Err(_) => Err(pay::Error::InvalidPayToken),
+            }
+        })
+        .await
+        .with_context(|| format!("Failed to update channel {} to Ready status", &label))?
+        .map_err(|e| anyhow::anyhow!(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        customer::{client::ZkChannelAddress, database::PaymentRecord},
+        database::SqlitePoolOptions,
+        escrow::types::{ContractDetails, TezosPublicKey},
+        protocol::{
+            daemon::{request_proxy_pay, serve_proxy_pay, PayRequest, ProxyPay},
+            wire_pair, WireChan,
+        },
+    };
+    use rand::SeedableRng;
+    use sqlx::SqlitePool;
+    use std::sync::Mutex;
+    use zkabacus_crypto::{
+        customer::{Config as ZkAbacusConfig, Requested},
+        merchant, ChannelId, CustomerBalance, CustomerRandomness, MerchantBalance,
+        MerchantRandomness,
+    };
+
+    const SESSION_KEY: &[u8] = b"session key";
+
+    async fn create_migrated_db() -> Result<SqlitePool, anyhow::Error> {
+        let conn = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        conn.migrate().await?;
+        Ok(conn)
+    }
+
+    fn config() -> Config {
+        toml::from_str(r#"tezos_account = "tezos_key.json""#).unwrap()
+    }
+
+    /// Store a channel with the given label, ready to pay on, returning the merchant's side of it.
+    async fn ready_channel(
+        database: &dyn QueryCustomer,
+        label: &ChannelName,
+    ) -> Result<merchant::Config, anyhow::Error> {
+        let mut rng = StdRng::from_entropy();
+        let merchant_config = merchant::Config::new(&mut rng);
+        let (pk, rev_param, range_param) = merchant_config.extract_customer_config_parts();
+        let zkabacus_config = ZkAbacusConfig::from_parts(pk, rev_param, range_param);
+        let channel_id = ChannelId::new(
+            MerchantRandomness::new(&mut rng),
+            CustomerRandomness::new(&mut rng),
+            zkabacus_config.merchant_public_key(),
+            &[],
+            &[],
+        );
+        let merchant_balance = MerchantBalance::try_new(5).unwrap();
+        let customer_balance = CustomerBalance::try_new(5).unwrap();
+        let context = ProofContext::new(b"establish");
+
+        let (requested, proof) = Requested::new(
+            &mut rng,
+            &zkabacus_config,
+            channel_id,
+            merchant_balance,
+            customer_balance,
+            &context,
+        );
+        let (closing_signature, blinded_state) = merchant_config
+            .initialize(
+                &mut rng,
+                &channel_id,
+                customer_balance,
+                merchant_balance,
+                proof,
+                &context,
+            )
+            .unwrap();
+        let inactive = requested
+            .complete(closing_signature, &zkabacus_config)
+            .unwrap();
+        let contract_details = ContractDetails {
+            merchant_tezos_public_key: TezosPublicKey::from_base58check(
+                "edpku5Ei6Dni4qwoJGqXJs13xHfyu4fhUg6zqZkFyiEh1mQhFD3iZE",
+            )
+            .unwrap(),
+            contract_id: None,
+            contract_level: None,
+        };
+        database
+            .new_channel(
+                label,
+                &"zkchannel://localhost".parse::<ZkChannelAddress>()?,
+                inactive,
+                &contract_details,
+                &zkabacus_config,
+            )
+            .await
+            .map_err(|(_, e)| e)?;
+
+        let pay_token = merchant_config.activate(&mut rng, blinded_state);
+        database
+            .with_channel_state(label, zkchannels_state::Inactive, |inactive| match inactive
+                .activate(pay_token, &zkabacus_config)
+            {
+                Ok(ready) => Ok((State::Ready(ready), ())),
+                Err(_) => Err(()),
+            })
+            .await?
+            .unwrap();
+        Ok(merchant_config)
+    }
+
+    /// Accept a payment as the merchant, following the session's transcript as the customer does.
+    async fn accept_payment(
+        chan: WireChan<<pay::Pay as Session>::Dual>,
+        merchant_config: &merchant::Config,
+    ) -> Result<(), anyhow::Error> {
+        let mut rng = StdRng::from_entropy();
+        let mut transcript = Transcript::new(SESSION_KEY);
+        let (payment_amount, chan) = chan.recv().await?;
+        let (note, chan): (String, _) = chan.recv().await?;
+        let (payment_id, chan) = chan.recv().await?;
+        transcript.append(&payment_amount);
+        transcript.append(&note);
+        transcript.append(&payment_id);
+        proceed!(in chan);
+
+        let (nonce, chan) = chan.recv().await?;
+        let (pay_proof, chan) = chan.recv().await?;
+        let (unrevoked, closing_signature) = match merchant_config.allow_payment(
+            &mut rng,
+            payment_amount,
+            &nonce,
+            pay_proof,
+            &transcript.context(),
+        ) {
+            Some(allowed) => allowed,
+            None => abort!(in chan return pay::Error::InvalidPayProof),
+        };
+        proceed!(in chan);
+        let chan = chan.send(closing_signature).await?;
+        offer_abort!(in chan as crate::protocol::Party::Merchant);
+
+        let (revocation_pair, chan) = chan.recv().await?;
+        let (revocation_blinding_factor, chan) = chan.recv().await?;
+        let pay_token = match unrevoked.complete_payment(
+            &mut rng,
+            &revocation_pair,
+            &revocation_blinding_factor,
+        ) {
+            Ok(pay_token) => pay_token,
+            Err(_) => abort!(in chan return pay::Error::InvalidRevocationOpening),
+        };
+        proceed!(in chan);
+        chan.send(pay_token)
+            .await?
+            .send(Some(payment_id))
+            .await?
+            .send(Some(format!("thanks for {}", note)))
+            .await?
+            .close();
+        Ok(())
+    }
+
+    /// Make a payment of 1 on the channel with the given label to the given merchant, reporting
+    /// progress as it is made.
+    async fn pay_one(
+        config: &Config,
+        database: &dyn QueryCustomer,
+        label: &ChannelName,
+        merchant_config: &merchant::Config,
+        progress: impl Fn(PayProgress),
+    ) -> Result<Option<String>, anyhow::Error> {
+        let (customer, merchant) = wire_pair::<pay::Pay>();
+        let (paid, accepted) = tokio::join!(
+            make_payment(
+                StdRng::from_entropy(),
+                config,
+                database,
+                label,
+                Transcript::new(SESSION_KEY),
+                customer,
+                PaymentAmount::pay_merchant(1).unwrap(),
+                "one more second".to_string(),
+                pay::PaymentId::generate(),
+                progress,
+            ),
+            accept_payment(merchant, merchant_config),
+        );
+        accepted?;
+        paid
+    }
+
+    /// Everything recorded about a channel that a payment changes, leaving out what differs
+    /// between any two payments, such as times and payment IDs.
+    async fn recorded(
+        database: &dyn QueryCustomer,
+        label: &ChannelName,
+    ) -> Result<impl PartialEq + std::fmt::Debug, anyhow::Error> {
+        let channel = database.get_channel(label).await?;
+        let payments: Vec<_> = database
+            .channel_payments(label)
+            .await?
+            .into_iter()
+            .map(|payment: PaymentRecord| {
+                (
+                    payment.amount,
+                    payment.note,
+                    payment.outcome,
+                    payment.failure_reason,
+                    payment.response_note,
+                    payment.customer_balance_before,
+                    payment.customer_balance,
+                    payment.merchant_balance,
+                    payment.payment_id.is_some(),
+                )
+            })
+            .collect();
+        let history: Vec<_> = database
+            .channel_history(label)
+            .await?
+            .into_iter()
+            .map(|event| (event.old_state, event.new_state))
+            .collect();
+        Ok((
+            channel.state.state_name(),
+            channel.state.customer_balance().into_inner(),
+            channel.state.merchant_balance().into_inner(),
+            payments,
+            history,
+        ))
+    }
+
+    #[tokio::test]
+    async fn proxied_payment_matches_direct_payment() -> Result<(), anyhow::Error> {
+        let config = config();
+        let database = create_migrated_db().await?;
+        let direct = ChannelName::new("direct".to_string());
+        let proxied = ChannelName::new("proxied".to_string());
+        let direct_merchant = ready_channel(&database, &direct).await?;
+        let proxied_merchant = ready_channel(&database, &proxied).await?;
+
+        let response = pay_one(&config, &database, &direct, &direct_merchant, |_| {}).await?;
+        assert_eq!(response.as_deref(), Some("thanks for one more second"));
+
+        // Make the same payment through the daemon's side of the proxy
+        let (client, daemon) = wire_pair::<ProxyPay>();
+        let forwarded = Mutex::new(Vec::new());
+        let request = PayRequest {
+            label: proxied.clone(),
+            payment_amount: PaymentAmount::pay_merchant(1).unwrap(),
+            note: "one more second".to_string(),
+            payment_id: pay::PaymentId::generate(),
+        };
+        let (proxied_response, served) = tokio::join!(
+            request_proxy_pay(client, request, |progress| forwarded
+                .lock()
+                .unwrap()
+                .push(progress)),
+            serve_proxy_pay(daemon, |request, progress| {
+                let (config, database, merchant_config) = (&config, &database, &proxied_merchant);
+                async move {
+                    pay_one(config, database, &request.label, merchant_config, |paid| {
+                        let _ = progress.send(paid);
+                    })
+                    .await
+                }
+            }),
+        );
+        served?;
+        assert_eq!(proxied_response?, response);
+        assert_eq!(
+            forwarded.into_inner().unwrap(),
+            vec![PayProgress::Approved, PayProgress::Paid]
+        );
+
+        assert_eq!(
+            recorded(&database, &direct).await?,
+            recorded(&database, &proxied).await?
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,544 @@
+//! Dispatching on each of the customer's channels from the chain-watching daemon.
+
+use {
+    async_trait::async_trait,
+    futures::{
+        stream::{self, StreamExt},
+        Future,
+    },
+    std::time::SystemTime,
+    thiserror::Error,
+};
+
+use crate::{
+    database::customer::StateName,
+    escrow::types::{ContractStatus, Entrypoint},
+    protocol::ChannelName,
+};
+
+/// Checks a channel's contract and takes any action it requires, abstracted so that a pass of the
+/// chain watcher can be exercised without a Tezos node.
+#[async_trait]
+pub trait ChannelDispatcher: Send + Sync {
+    /// A channel to dispatch on, along with whatever else is known about it.
+    type Channel: Send + Sync;
+
+    /// Check the channel's contract and take any action it requires, returning the time at which
+    /// the channel will next need attention, if it is waiting on a known deadline.
+    async fn dispatch(&self, channel: &Self::Channel) -> Result<Option<SystemTime>, anyhow::Error>;
+}
+
+/// Dispatch on every channel, at most `max_concurrent` at once, returning the result for each
+/// channel in order.
+///
+/// A channel whose dispatch fails doesn't stop the others from being dispatched on; its error is
+/// returned for the caller to report, and to retry on a later pass.
+pub async fn dispatch_all<D: ChannelDispatcher>(
+    dispatcher: &D,
+    channels: &[D::Channel],
+    max_concurrent: usize,
+) -> Vec<Result<Option<SystemTime>, anyhow::Error>> {
+    stream::iter(channels.iter().map(|channel| dispatcher.dispatch(channel)))
+        .buffered(max_concurrent.max(1))
+        .collect()
+        .await
+}
+
+/// Whether the chain watcher may load the customer's Tezos key to post the operations a channel
+/// needs, which is the only time it needs the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAccess {
+    /// Load the key each time an operation must be posted.
+    OnDemand,
+    /// Never load the key: leave each operation that must be posted for the user to post.
+    WatchOnly,
+}
+
+/// An error when the chain watcher can't get the key to post an operation a channel needs.
+#[derive(Debug, Error)]
+pub enum KeyUnavailable {
+    #[error(
+        "Not posting {action} for {label}, since the daemon is watch-only: \
+        run `customer close {label}` to post it"
+    )]
+    WatchOnly {
+        label: ChannelName,
+        action: Entrypoint,
+    },
+    #[error(
+        "Tezos key unavailable to post {action} for {label}: \
+        run `customer close {label}` manually ({source})"
+    )]
+    Missing {
+        label: ChannelName,
+        action: Entrypoint,
+        source: anyhow::Error,
+    },
+}
+
+/// What the chain watcher does for a channel, given its local state and the status of its contract.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reaction {
+    /// Nothing: the channel is up to date with its contract, or is waiting on the merchant.
+    Nothing,
+    /// Post custClose in reaction to the merchant's expiry.
+    AnswerExpiry,
+    /// Wait for the custClose timeout to expire before claiming the customer's balance.
+    AwaitTimeout,
+    /// Claim the customer's balance with custClaim, now that the custClose timeout has expired.
+    Claim,
+    /// Finalize the channel, now that its contract is closed, according to how it was closed.
+    FinalizeClose,
+    /// The contract's status can't be reached from the channel's state, so the channel needs the
+    /// user's attention.
+    Inconsistent,
+}
+
+impl Reaction {
+    /// The operation this reaction posts on the channel's contract, if any, given whether the
+    /// channel's custClose operation is still waiting to be broadcast.
+    pub fn operation(self, awaiting_broadcast: bool) -> Option<Entrypoint> {
+        match self {
+            Reaction::AnswerExpiry => Some(Entrypoint::CustomerClose),
+            Reaction::Claim if !awaiting_broadcast => Some(Entrypoint::CustomerClaim),
+            _ => None,
+        }
+    }
+}
+
+/// Decide what the chain watcher does for a channel, given the local state of the channel, the
+/// status of its contract, and whether its custClose timeout has expired, if it has one.
+///
+/// Every pairing is covered, so that a contract which moved through several statuses while the
+/// daemon was stopped is handled on the first check after it starts: an expiry is answered from
+/// any open state, and a contract closed by the merchant's claim is finalized even if the expiry
+/// before it was never seen.
+pub fn plan_reaction(
+    state: StateName,
+    status: ContractStatus,
+    timeout_expired: Option<bool>,
+) -> Reaction {
+    use StateName::*;
+
+    match (state, status) {
+        // Establishment finishes or reclaims the funding, not the chain watcher
+        (
+            Inactive | Originated | CustomerFunded | MerchantFunded,
+            ContractStatus::AwaitingCustomerFunding
+            | ContractStatus::AwaitingMerchantFunding
+            | ContractStatus::Open,
+        )
+        | (
+            Inactive | Originated | CustomerFunded | FundingReclaimed,
+            ContractStatus::FundingReclaimed,
+        ) => Reaction::Nothing,
+
+        // The channel is open, or a mutual close is still in progress
+        (Ready | Started | Locked | PendingMutualClose, ContractStatus::Open) => Reaction::Nothing,
+
+        // The merchant posted an expiry, which must be answered whatever the channel was doing,
+        // unless the channel already answered it
+        (
+            CustomerFunded | MerchantFunded | Ready | Started | Locked | PendingMutualClose,
+            ContractStatus::Expiry,
+        ) => Reaction::AnswerExpiry,
+        (PendingClose | PendingExpiry, ContractStatus::Expiry) => Reaction::Nothing,
+
+        // The customer's custClose was posted, and its balance is claimable after the timeout
+        (PendingClose, ContractStatus::CustomerClose) => match timeout_expired {
+            Some(true) => Reaction::Claim,
+            Some(false) => Reaction::AwaitTimeout,
+            None => Reaction::Nothing,
+        },
+        (PendingCustomerClaim | Dispute, ContractStatus::CustomerClose) => Reaction::Nothing,
+
+        // The contract was closed by whichever party, possibly while the daemon was stopped
+        (
+            CustomerFunded | MerchantFunded | Ready | Started | Locked | PendingMutualClose
+            | PendingExpiry | PendingClose | PendingCustomerClaim | Dispute,
+            ContractStatus::Closed,
+        ) => Reaction::FinalizeClose,
+        (Closed, ContractStatus::Closed) => Reaction::Nothing,
+
+        _ => Reaction::Inconsistent,
+    }
+}
+
+/// The operation the customer must post on a channel's contract, if any, given the status of the
+/// contract, whether its custClose timeout has expired, if it has one, the local state of the
+/// channel, and whether the channel's custClose operation is still waiting to be broadcast.
+///
+/// This is either a custClose in reaction to the merchant's expiry, or a custClaim once the
+/// custClose timeout has expired. Monitoring every other contract needs no key.
+pub fn operation_required(
+    status: ContractStatus,
+    timeout_expired: Option<bool>,
+    state: StateName,
+    awaiting_broadcast: bool,
+) -> Option<Entrypoint> {
+    plan_reaction(state, status, timeout_expired).operation(awaiting_broadcast)
+}
+
+/// Load the key needed to post `action` on a channel with `load`, if `access` allows it, at the
+/// moment the action must be posted.
+pub async fn key_for_action<K, Fut: Future<Output = Result<K, anyhow::Error>>>(
+    access: KeyAccess,
+    label: &ChannelName,
+    action: Entrypoint,
+    load: impl FnOnce() -> Fut,
+) -> Result<K, KeyUnavailable> {
+    match access {
+        KeyAccess::WatchOnly => Err(KeyUnavailable::WatchOnly {
+            label: label.clone(),
+            action,
+        }),
+        KeyAccess::OnDemand => load().await.map_err(|source| KeyUnavailable::Missing {
+            label: label.clone(),
+            action,
+            source,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+        time::Duration,
+    };
+
+    /// A chain on which each channel's contract either needs attention at some time, or can't be
+    /// read.
+    #[derive(Default)]
+    struct Chain {
+        dispatched: Mutex<Vec<&'static str>>,
+    }
+
+    #[async_trait]
+    impl ChannelDispatcher for Chain {
+        type Channel = (&'static str, Option<SystemTime>);
+
+        async fn dispatch(
+            &self,
+            channel: &Self::Channel,
+        ) -> Result<Option<SystemTime>, anyhow::Error> {
+            let (label, next_action) = channel;
+            self.dispatched.lock().unwrap().push(*label);
+            match next_action {
+                Some(next_action) => Ok(Some(*next_action)),
+                None => Err(anyhow::anyhow!("contract for {} not found", label)),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_channel_does_not_stop_others() {
+        let deadline = SystemTime::UNIX_EPOCH + Duration::from_secs(1_630_454_400);
+        let channels = vec![("broken", None), ("closing", Some(deadline))];
+        let chain = Chain::default();
+
+        let results = dispatch_all(&chain, &channels, 16).await;
+
+        let mut dispatched = chain.dispatched.lock().unwrap().clone();
+        dispatched.sort_unstable();
+        assert_eq!(dispatched, vec!["broken", "closing"]);
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(
+            results[0].as_ref().unwrap_err().to_string(),
+            "contract for broken not found"
+        );
+        assert_eq!(results[1].as_ref().unwrap(), &Some(deadline));
+    }
+
+    /// A slow chain which records how many channels are being dispatched on at once.
+    #[derive(Default)]
+    struct SlowChain {
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ChannelDispatcher for SlowChain {
+        type Channel = usize;
+
+        async fn dispatch(
+            &self,
+            _channel: &Self::Channel,
+        ) -> Result<Option<SystemTime>, anyhow::Error> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_dispatches_are_bounded() {
+        let channels: Vec<usize> = (0..20).collect();
+        let chain = SlowChain::default();
+
+        let results = dispatch_all(&chain, &channels, 4).await;
+
+        assert_eq!(results.len(), 20);
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(chain.max_in_flight.load(Ordering::SeqCst), 4);
+        assert_eq!(chain.in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    /// A chain on which each channel's contract has some status, dispatched on by a daemon which
+    /// counts how often it loads the key.
+    struct KeyedChain {
+        access: KeyAccess,
+        key_loads: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ChannelDispatcher for KeyedChain {
+        type Channel = (&'static str, ContractStatus, Option<bool>, StateName);
+
+        async fn dispatch(
+            &self,
+            channel: &Self::Channel,
+        ) -> Result<Option<SystemTime>, anyhow::Error> {
+            let (label, status, timeout_expired, state) = *channel;
+            if let Some(action) = operation_required(status, timeout_expired, state, false) {
+                key_for_action(
+                    self.access,
+                    &ChannelName::new(label.to_string()),
+                    action,
+                    || async {
+                        self.key_loads.fetch_add(1, Ordering::SeqCst);
+                        Ok(())
+                    },
+                )
+                .await?;
+            }
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn monitoring_reads_no_key() {
+        let monitored = vec![
+            ("open", ContractStatus::Open, None, StateName::Ready),
+            (
+                "closing",
+                ContractStatus::CustomerClose,
+                Some(false),
+                StateName::PendingClose,
+            ),
+            (
+                "answered",
+                ContractStatus::Expiry,
+                None,
+                StateName::PendingClose,
+            ),
+            ("closed", ContractStatus::Closed, None, StateName::Closed),
+        ];
+        let chain = KeyedChain {
+            access: KeyAccess::OnDemand,
+            key_loads: AtomicUsize::new(0),
+        };
+
+        let results = dispatch_all(&chain, &monitored, 16).await;
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(chain.key_loads.load(Ordering::SeqCst), 0);
+
+        // The key is only loaded when a channel needs an operation posted
+        let expiring = vec![("expiring", ContractStatus::Expiry, None, StateName::Ready)];
+        let results = dispatch_all(&chain, &expiring, 16).await;
+        assert!(results[0].is_ok());
+        assert_eq!(chain.key_loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn watch_only_never_reads_key() {
+        let chain = KeyedChain {
+            access: KeyAccess::WatchOnly,
+            key_loads: AtomicUsize::new(0),
+        };
+        let channels = vec![
+            ("expiring", ContractStatus::Expiry, None, StateName::Ready),
+            (
+                "claimable",
+                ContractStatus::CustomerClose,
+                Some(true),
+                StateName::PendingClose,
+            ),
+        ];
+
+        let results = dispatch_all(&chain, &channels, 16).await;
+
+        assert_eq!(chain.key_loads.load(Ordering::SeqCst), 0);
+        for result in results {
+            assert!(matches!(
+                result.unwrap_err().downcast::<KeyUnavailable>(),
+                Ok(KeyUnavailable::WatchOnly { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn reactions_cover_every_state_and_status() {
+        use Reaction::{
+            AnswerExpiry as Answer, FinalizeClose as Finalize, Inconsistent as Bad, Nothing,
+        };
+        use StateName::*;
+
+        let statuses = [
+            ContractStatus::AwaitingCustomerFunding,
+            ContractStatus::AwaitingMerchantFunding,
+            ContractStatus::Open,
+            ContractStatus::Expiry,
+            ContractStatus::CustomerClose,
+            ContractStatus::Closed,
+            ContractStatus::FundingReclaimed,
+        ];
+
+        // The expected reaction for each state but PendingClose, in the order of `statuses`
+        let expected = [
+            (
+                Inactive,
+                [Nothing, Nothing, Nothing, Bad, Bad, Bad, Nothing],
+            ),
+            (
+                Originated,
+                [Nothing, Nothing, Nothing, Bad, Bad, Bad, Nothing],
+            ),
+            (
+                CustomerFunded,
+                [Nothing, Nothing, Nothing, Answer, Bad, Finalize, Nothing],
+            ),
+            (
+                MerchantFunded,
+                [Nothing, Nothing, Nothing, Answer, Bad, Finalize, Bad],
+            ),
+            (Ready, [Bad, Bad, Nothing, Answer, Bad, Finalize, Bad]),
+            (Started, [Bad, Bad, Nothing, Answer, Bad, Finalize, Bad]),
+            (Locked, [Bad, Bad, Nothing, Answer, Bad, Finalize, Bad]),
+            (
+                PendingMutualClose,
+                [Bad, Bad, Nothing, Answer, Bad, Finalize, Bad],
+            ),
+            (PendingExpiry, [Bad, Bad, Bad, Nothing, Bad, Finalize, Bad]),
+            (
+                PendingCustomerClaim,
+                [Bad, Bad, Bad, Bad, Nothing, Finalize, Bad],
+            ),
+            (Dispute, [Bad, Bad, Bad, Bad, Nothing, Finalize, Bad]),
+            (Closed, [Bad, Bad, Bad, Bad, Bad, Nothing, Bad]),
+            (FundingReclaimed, [Bad, Bad, Bad, Bad, Bad, Bad, Nothing]),
+        ];
+        for (state, reactions) in expected.iter() {
+            for (status, reaction) in statuses.iter().zip(reactions.iter()) {
+                for timeout_expired in [None, Some(false), Some(true)] {
+                    assert_eq!(
+                        plan_reaction(*state, *status, timeout_expired),
+                        *reaction,
+                        "{:?} with {:?} contract (timeout expired: {:?})",
+                        state,
+                        status,
+                        timeout_expired
+                    );
+                }
+            }
+        }
+
+        // Only a channel waiting on its own custClose depends on the timeout
+        for status in statuses
+            .iter()
+            .filter(|s| **s != ContractStatus::CustomerClose)
+        {
+            for timeout_expired in [None, Some(false), Some(true)] {
+                assert_eq!(
+                    plan_reaction(PendingClose, *status, timeout_expired),
+                    match status {
+                        ContractStatus::Expiry => Reaction::Nothing,
+                        ContractStatus::Closed => Reaction::FinalizeClose,
+                        _ => Reaction::Inconsistent,
+                    }
+                );
+            }
+        }
+        let closing = ContractStatus::CustomerClose;
+        assert_eq!(
+            plan_reaction(PendingClose, closing, None),
+            Reaction::Nothing
+        );
+        assert_eq!(
+            plan_reaction(PendingClose, closing, Some(false)),
+            Reaction::AwaitTimeout
+        );
+        assert_eq!(
+            plan_reaction(PendingClose, closing, Some(true)),
+            Reaction::Claim
+        );
+    }
+
+    #[test]
+    fn missed_expiry_is_caught_up() {
+        // The merchant posted an expiry while the daemon was stopped, and the delay has passed
+        // but the merchant hasn't claimed yet: answering is still possible
+        assert_eq!(
+            plan_reaction(StateName::Ready, ContractStatus::Expiry, Some(true)),
+            Reaction::AnswerExpiry
+        );
+        assert_eq!(
+            Reaction::AnswerExpiry.operation(false),
+            Some(Entrypoint::CustomerClose)
+        );
+
+        // The merchant already claimed: there's nothing left to answer, only to finalize
+        assert_eq!(
+            plan_reaction(StateName::Ready, ContractStatus::Closed, None),
+            Reaction::FinalizeClose
+        );
+        assert_eq!(Reaction::FinalizeClose.operation(false), None);
+
+        // A claim waits for the custClose operation to be broadcast
+        assert_eq!(Reaction::Claim.operation(true), None);
+        assert_eq!(
+            Reaction::Claim.operation(false),
+            Some(Entrypoint::CustomerClaim)
+        );
+    }
+
+    #[test]
+    fn operations_required() {
+        use ContractStatus::*;
+
+        // Answer an expiry, unless already closing
+        assert_eq!(
+            operation_required(Expiry, None, StateName::Ready, false),
+            Some(Entrypoint::CustomerClose)
+        );
+        assert_eq!(
+            operation_required(Expiry, None, StateName::PendingExpiry, false),
+            None
+        );
+
+        // Claim once the timeout has expired and the custClose has been broadcast
+        assert_eq!(
+            operation_required(CustomerClose, Some(true), StateName::PendingClose, false),
+            Some(Entrypoint::CustomerClaim)
+        );
+        assert_eq!(
+            operation_required(CustomerClose, Some(true), StateName::PendingClose, true),
+            None
+        );
+        assert_eq!(
+            operation_required(CustomerClose, Some(false), StateName::PendingClose, false),
+            None
+        );
+        assert_eq!(
+            operation_required(CustomerClose, None, StateName::PendingClose, false),
+            None
+        );
+    }
+}
@@ -1,13 +1,31 @@
 pub mod customer;
 pub mod merchant;
-pub use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions, SqliteRow};
+mod postgres;
+pub use sqlx::{
+    postgres::{PgPool, PgPoolOptions},
+    sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions, SqliteRow},
+};
 
-use {anyhow::Context, std::path::Path, std::sync::Arc};
+use {
+    anyhow::Context,
+    sqlx::sqlite::SqliteJournalMode,
+    std::{path::Path, sync::Arc, time::Duration},
+};
 
+/// How long a connection to a SQLite database waits for another connection, perhaps in another
+/// process, to release a lock it needs before giving up.
+pub const SQLITE_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Open a SQLite database, creating it if it doesn't exist.
+///
+/// The database is opened in WAL mode, so that reading it doesn't wait for a writer in another
+/// process, and a connection waits up to [`SQLITE_BUSY_TIMEOUT`] for the write lock.
 pub async fn connect_sqlite<T: AsRef<Path>>(path: T) -> Result<Arc<SqlitePool>, anyhow::Error> {
     let options = SqliteConnectOptions::new()
         .create_if_missing(true)
-        .filename(path.as_ref());
+        .filename(path.as_ref())
+        .journal_mode(SqliteJournalMode::Wal)
+        .busy_timeout(SQLITE_BUSY_TIMEOUT);
 
     let pool = SqlitePoolOptions::new()
         .max_connections(1)
@@ -22,3 +40,20 @@ pub async fn connect_sqlite<T: AsRef<Path>>(path: T) -> Result<Arc<SqlitePool>,
 
     Ok(Arc::new(pool))
 }
+
+/// Connect to a Postgres database, such as one shared by several merchant services, or a managed
+/// database holding a customer's channels.
+pub async fn connect_postgres(uri: &http::Uri) -> Result<Arc<PgPool>, anyhow::Error> {
+    let pool = PgPoolOptions::new()
+        .connect(&uri.to_string())
+        .await
+        .with_context(|| {
+            // The URI may hold a password, so only the host is given
+            format!(
+                "Could not connect to Postgres database on \"{}\"",
+                uri.host().unwrap_or_default()
+            )
+        })?;
+
+    Ok(Arc::new(pool))
+}
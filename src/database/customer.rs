@@ -1,28 +1,44 @@
 use {
     async_trait::async_trait,
-    futures::stream::StreamExt,
+    futures::{
+        stream::{BoxStream, StreamExt},
+        Future,
+    },
     serde::{Deserialize, Serialize},
-    sqlx::SqlitePool,
-    std::any::Any,
+    sqlx::{Sqlite, SqlitePool, Transaction},
+    std::{
+        any::Any,
+        convert::TryFrom,
+        fmt::{self, Display, Formatter},
+        str::FromStr,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
     thiserror::Error,
 };
 
 use zkabacus_crypto::{
-    customer::{ClosingMessage, Inactive},
-    CustomerBalance, MerchantBalance,
+    customer::{ClosingMessage, Inactive, LockMessage, StartMessage},
+    revlock::{RevocationLockBlindingFactor, RevocationPair},
+    ChannelId, CustomerBalance, MerchantBalance, Nonce, PayProof,
 };
 
 use tezedge::crypto::ToBase58Check;
 
 use crate::{
-    customer::{client::ZkChannelAddress, ChannelName},
-    escrow::types::{ContractDetails, ContractId, TezosPublicKey},
+    customer::{client::ZkChannelAddress, ChannelName, InvalidChannelName},
+    escrow::types::{
+        ContractDetails, ContractId, ContractStatus, Entrypoint, KeyHash, TezosPublicKey,
+    },
+    protocol::pay::PaymentId,
 };
 
+mod in_flight;
+mod postgres;
 mod state;
 use self::state::zkchannels_state::ZkChannelState;
 
-pub use super::connect_sqlite;
+pub use super::{connect_postgres, connect_sqlite};
+pub use in_flight::OperationGuard;
 pub use state::{zkchannels_state, State, StateName, UnexpectedState};
 
 type Result<T> = std::result::Result<T, Error>;
@@ -45,9 +61,25 @@ pub enum Error {
     /// A channel which was expected to exist in the database did not exist.
     #[error("There is no channel by the name of \"{0}\"")]
     NoSuchChannel(ChannelName),
+    /// There is no channel with the given ID.
+    #[error("There is no channel with the ID {0}")]
+    NoSuchChannelId(ChannelId),
+    /// There is no channel whose ID starts with the given prefix.
+    #[error("There is no channel with an ID that starts with \"{0}\"")]
+    ChannelNotFoundWithPrefix(String),
+    /// More than one channel has an ID that starts with the given prefix.
+    #[error("More than one channel has an ID that starts with \"{0}\"")]
+    ChannelIdCollision(String),
     /// A channel which was expected *not* to exist in the database *did* exist.
     #[error("There is already a channel by the name of \"{0}\"")]
     ChannelExists(ChannelName),
+    /// A channel can't be given the label.
+    #[error(transparent)]
+    InvalidChannelName(#[from] InvalidChannelName),
+    /// The payment being completed isn't pending on the channel: it is unknown, already
+    /// completed, or recorded as failed.
+    #[error("Payment {0} is not pending on this channel")]
+    PaymentNotPending(i64),
     /// A channel balance update was invalid.
     #[error("Failed to update channel balance to invalid set (merchant: {0:?}, customer: {1:?})")]
     InvalidBalanceUpdate(MerchantBalance, Option<CustomerBalance>),
@@ -57,6 +89,21 @@ pub enum Error {
     /// A channel already holds contract details.
     #[error("The channel \"{0}\" already has contract details set")]
     ContractDetailsExist(ChannelName),
+    /// An operation calling the entrypoint is already being posted for the channel.
+    #[error("An operation calling {0} is already in flight for this channel")]
+    OperationAlreadyInFlight(Entrypoint),
+    /// A channel's record of what the daemon last saw of its contract was invalid.
+    #[error("Error retrieving the last contract observation for \"{0}\": invalid status")]
+    InvalidContractObservation(ChannelName),
+    /// A channel ID stored in the database could not be parsed.
+    #[error("Invalid channel ID in database: {0}")]
+    MalformedChannelId(String),
+    /// A channel which is not yet finished with can't be archived or purged.
+    #[error("Cannot remove \"{0}\": the channel is {1}, not closed")]
+    NotTerminal(ChannelName, StateName),
+    /// Closing balances were given for a channel which is not closing.
+    #[error("Cannot set closing balances for \"{0}\": the channel is {1}, not closing")]
+    NotClosing(ChannelName, StateName),
 }
 
 /// The contents of a row of the database for a particular channel.
@@ -68,7 +115,233 @@ pub struct ChannelDetails {
     pub customer_deposit: CustomerBalance,
     pub address: ZkChannelAddress,
     pub closing_balances: ClosingBalances,
+    /// How the channel was closed, if its closing balances have been set.
+    pub closing_path: Option<ClosingPath>,
     pub contract_details: ContractDetails,
+    /// Whether a custClose operation was produced off chain, and is waiting for the customer to
+    /// broadcast it and confirm that they did.
+    pub awaiting_broadcast: bool,
+}
+
+/// What the chain-watching daemon last saw of a channel's contract, and how it reacted.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ContractObservation {
+    /// The status of the contract.
+    pub status: ContractStatus,
+    /// The level of the head block when the contract was first seen with this status.
+    pub level: u32,
+    /// When the contract was last seen, still with this status.
+    pub observed_at: SystemTime,
+    /// The entrypoint the daemon called in reaction to this status, if it has.
+    pub reaction: Option<Entrypoint>,
+    /// The entrypoint which needs calling in reaction to this status, if a watch-only daemon left
+    /// it for the user to call.
+    pub required_action: Option<Entrypoint>,
+}
+
+/// A change in the state of a channel, from one kind of state to another.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ChannelEvent {
+    pub old_state: StateName,
+    pub new_state: StateName,
+    pub recorded_at: SystemTime,
+}
+
+/// A payment being made on a channel, to record in its payment history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewPayment {
+    /// The amount of the payment, in mutez, which is negative for a refund.
+    pub amount: i64,
+    /// The note the customer sent the merchant with the payment.
+    pub note: String,
+    /// The ID the customer chose for the payment, which the merchant records too.
+    pub payment_id: PaymentId,
+}
+
+/// The nonce and pay proof the customer sent to start a payment, kept so that the payment can be
+/// resumed if its session is interrupted.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PaymentStart {
+    pub nonce: Nonce,
+    pub pay_proof: PayProof,
+}
+
+zkabacus_crypto::impl_sqlx_for_bincode_ty!(PaymentStart);
+
+impl From<StartMessage> for PaymentStart {
+    fn from(start_message: StartMessage) -> Self {
+        Self {
+            nonce: start_message.nonce,
+            pay_proof: start_message.pay_proof,
+        }
+    }
+}
+
+/// The revocation the customer revealed to lock a payment, kept so that it can be revealed again
+/// if the payment is resumed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PaymentLock {
+    pub revocation_pair: RevocationPair,
+    pub revocation_lock_blinding_factor: RevocationLockBlindingFactor,
+}
+
+zkabacus_crypto::impl_sqlx_for_bincode_ty!(PaymentLock);
+
+impl From<LockMessage> for PaymentLock {
+    fn from(lock_message: LockMessage) -> Self {
+        Self {
+            revocation_pair: lock_message.revocation_pair,
+            revocation_lock_blinding_factor: lock_message.revocation_lock_blinding_factor,
+        }
+    }
+}
+
+/// A payment left pending by an interrupted session, with what's needed to resume it.
+#[derive(Debug)]
+pub struct InterruptedPayment {
+    pub payment_id: i64,
+    pub start: PaymentStart,
+    /// The revocation revealed for the payment, if it got as far as locking the channel.
+    pub lock: Option<PaymentLock>,
+}
+
+/// What became of a payment the customer made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(rename_all = "snake_case", type_name = "text")]
+pub enum PaymentOutcome {
+    /// The payment has begun, and has neither completed nor failed yet. A payment which stays
+    /// pending was interrupted.
+    Pending,
+    /// The channel was unlocked with the merchant's new pay token.
+    Completed,
+    /// The payment was refused by the merchant, or the pay session didn't complete.
+    Failed,
+}
+
+impl Display for PaymentOutcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Pending => "pending",
+                Self::Completed => "completed",
+                Self::Failed => "failed",
+            }
+        )
+    }
+}
+
+/// A payment on a channel, as recorded in its payment history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaymentRecord {
+    pub label: ChannelName,
+    /// The amount of the payment, in mutez, which is negative for a refund.
+    pub amount: i64,
+    pub note: String,
+    pub outcome: PaymentOutcome,
+    /// Why the payment failed, if it did.
+    pub failure_reason: Option<String>,
+    /// The note the merchant sent in response to the payment, if any.
+    pub response_note: Option<String>,
+    /// The customer's balance when the payment began, in mutez, if it was recorded.
+    pub customer_balance_before: Option<i64>,
+    /// The customer's balance once the payment completed, in mutez, or when it began if it
+    /// didn't complete.
+    pub customer_balance: i64,
+    /// The merchant's balance once the payment completed, in mutez, or when it began if it
+    /// didn't complete.
+    pub merchant_balance: i64,
+    pub started_at: SystemTime,
+    /// When the payment completed, or when it began if it didn't complete.
+    pub paid_at: SystemTime,
+    /// The ID the customer chose for the payment, which is missing for payments made before
+    /// payments had IDs.
+    pub payment_id: Option<PaymentId>,
+}
+
+/// What is kept of a closed channel once it is archived: its final balances and how it was
+/// closed, without its zkAbacus state.
+#[derive(Debug, Clone)]
+pub struct ArchivedChannel {
+    pub label: ChannelName,
+    pub channel_id: Option<ChannelId>,
+    pub address: ZkChannelAddress,
+    pub merchant_deposit: MerchantBalance,
+    pub customer_deposit: CustomerBalance,
+    /// The state the channel was in when it was archived.
+    pub final_state: StateName,
+    pub closing_balances: ClosingBalances,
+    pub closing_path: Option<ClosingPath>,
+    pub contract_id: Option<ContractId>,
+    pub contract_level: Option<u32>,
+    pub archived_at: SystemTime,
+}
+
+/// Everything stored about a channel which is needed to restore it from a backup: what the
+/// chain-watching daemon saw of its contract is left out, since the daemon checks every channel
+/// again when it starts.
+#[derive(Serialize, Deserialize)]
+pub struct ChannelBackup {
+    pub label: ChannelName,
+    pub address: ZkChannelAddress,
+    pub merchant_deposit: MerchantBalance,
+    pub customer_deposit: CustomerBalance,
+    pub state: State,
+    pub closing_balances: ClosingBalances,
+    pub closing_path: Option<ClosingPath>,
+    pub merchant_tezos_public_key: String,
+    pub contract_id: Option<ContractId>,
+    pub contract_level: Option<u32>,
+    pub awaiting_broadcast: bool,
+    pub zkabacus_config: zkabacus_crypto::customer::Config,
+}
+
+/// What to do when restoring a channel from a backup into a database which already has a channel
+/// by the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Restore nothing at all.
+    Refuse,
+    /// Keep the channel already in the database, and restore the others.
+    Skip,
+    /// Replace the channel already in the database with the one from the backup.
+    Replace,
+}
+
+impl Default for MergeStrategy {
+    fn default() -> Self {
+        MergeStrategy::Refuse
+    }
+}
+
+/// An error parsing a [`MergeStrategy`].
+#[derive(Debug, Error)]
+#[error("Unknown merge strategy \"{0}\": expected \"refuse\", \"skip\", or \"replace\"")]
+pub struct ParseMergeStrategyError(String);
+
+impl FromStr for MergeStrategy {
+    type Err = ParseMergeStrategyError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "refuse" => Ok(MergeStrategy::Refuse),
+            "skip" => Ok(MergeStrategy::Skip),
+            "replace" => Ok(MergeStrategy::Replace),
+            _ => Err(ParseMergeStrategyError(s.to_string())),
+        }
+    }
+}
+
+/// The channels restored from a backup, by what happened to each.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ImportSummary {
+    /// Channels which weren't in the database before.
+    pub imported: Vec<ChannelName>,
+    /// Channels which replaced a channel by the same name.
+    pub replaced: Vec<ChannelName>,
+    /// Channels left out because the database already had a channel by the same name.
+    pub skipped: Vec<ChannelName>,
 }
 
 /// The balances of a channel at closing. These may change during a close flow.
@@ -89,6 +362,34 @@ impl Default for ClosingBalances {
     }
 }
 
+/// How a channel's closing balances were paid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClosingPath {
+    /// Both parties agreed on the balances, which were paid out at once.
+    Mutual,
+    /// The customer posted their balance, either of their own accord or in response to an
+    /// expiry, and it was not disputed.
+    Unilateral,
+    /// The merchant disputed the balance the customer posted, and was paid everything.
+    Dispute,
+    /// The merchant claimed everything after an expiry to which the customer didn't respond.
+    Expiry,
+}
+
+zkabacus_crypto::impl_sqlx_for_bincode_ty!(ClosingPath);
+
+impl Display for ClosingPath {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ClosingPath::Mutual => "mutual close",
+            ClosingPath::Unilateral => "unilateral close",
+            ClosingPath::Dispute => "dispute",
+            ClosingPath::Expiry => "expiry",
+        })
+    }
+}
+
 /// Extension trait augmenting the customer database [`QueryCustomer`] with extra methods.
 ///
 /// These are implemented automatically for any database handle which implements
@@ -124,6 +425,23 @@ pub trait QueryCustomerExt {
         with_zkabacus_state: F,
     ) -> Result<std::result::Result<T, E>>;
 
+    /// Like [`QueryCustomerExt::with_channel_state`], for the change of state which completes a
+    /// payment begun with [`QueryCustomer::begin_payment`]: the payment is recorded as completed,
+    /// with the balances of the new state, only if the new state is stored.
+    async fn with_channel_state_paid<
+        'a,
+        S: ZkChannelState + Send + 'static,
+        F: FnOnce(S::ZkAbacusState) -> std::result::Result<(State, T), E> + Send + 'a,
+        T: Send + 'static,
+        E: Send + 'static,
+    >(
+        &'a self,
+        channel_name: &ChannelName,
+        expected_state: S,
+        payment_id: i64,
+        with_zkabacus_state: F,
+    ) -> Result<std::result::Result<T, E>>;
+
     /// Given a channel's unique name, mutate its state in the database using a provided closure,
     /// that is given the current state and must convert it to [`State::PendingClose`].
     ///
@@ -144,6 +462,29 @@ pub trait QueryCustomerExt {
             + Send
             + 'a,
     ) -> Result<std::result::Result<ClosingMessage, E>>;
+
+    /// Given what the daemon saw of a channel's contract, run `react` to respond to it, unless the
+    /// daemon already reacted to that observation, and record `reaction` once `react` succeeds.
+    ///
+    /// The return type for this function can be interpreted as follows:
+    /// - A successful run returns `Ok(Ok(Some(T)))`, where `T` is returned by `react`.
+    /// - A run where the daemon already reacted returns `Ok(Ok(None))`, without running `react`.
+    /// - A run where `react` fails returns `Ok(Err(E))`. Nothing is recorded, so the reaction is
+    ///   tried again the next time the contract is seen.
+    /// - A run where recording the reaction fails returns `Err(Error)`.
+    async fn react_once<
+        'a,
+        F: FnOnce() -> Fut + Send + 'a,
+        Fut: Future<Output = std::result::Result<T, E>> + Send + 'a,
+        T: Send + 'static,
+        E: Send + 'static,
+    >(
+        &'a self,
+        channel_name: &ChannelName,
+        observation: &ContractObservation,
+        reaction: Entrypoint,
+        react: F,
+    ) -> Result<std::result::Result<Option<T>, E>>;
 }
 
 /// Trait-object safe version of [`QueryCustomer`]: use this type in trait objects and implement it
@@ -179,16 +520,19 @@ pub trait QueryCustomer: Send + Sync {
     /// Get the closing balances of a given channel.
     async fn closing_balances(&self, channel_name: &ChannelName) -> Result<ClosingBalances>;
 
-    /// Update the closing balances for a given channel.
+    /// Update the closing balances for a given channel, recording the [`ClosingPath`] which paid
+    /// them out.
     ///
     /// This should only be called once the balances are finalized on chain and maintains the
     /// following invariants:
     /// - The customer balance can be set at most once.
     /// - The merchant balance can only be increased.
-    /// If either of these invariants are violated, will raise [`Error::InvalidBalanceUpdate`].
+    /// If either of these invariants are violated, will raise [`Error::InvalidBalanceUpdate`]. If
+    /// the channel is not closing or closed, will raise [`Error::NotClosing`].
     async fn update_closing_balances(
         &self,
         channel_name: &ChannelName,
+        closing_path: ClosingPath,
         merchant_balance: MerchantBalance,
         customer_balance: Option<CustomerBalance>,
     ) -> Result<()>;
@@ -196,12 +540,13 @@ pub trait QueryCustomer: Send + Sync {
     /// Get the merchant's Tezos key and details about the originated Tezos contract if it exists.
     async fn contract_details(&self, channel_name: &ChannelName) -> Result<ContractDetails>;
 
-    /// Set contract information for a given channel. Will fail if the contract information has
-    /// previously been set.
-    async fn initialize_contract_details(
+    /// Set the ID of the contract originated for a given channel, and the level of the block in
+    /// which it was originated. Will fail if the contract ID has previously been set.
+    async fn set_contract_id(
         &self,
         channel_name: &ChannelName,
         contract_id: &ContractId,
+        level: u32,
     ) -> Result<()>;
 
     /// Rename an existing channel from a given name to a new one.
@@ -211,6 +556,14 @@ pub trait QueryCustomer: Send + Sync {
         new_label: &ChannelName,
     ) -> Result<()>;
 
+    /// Mark a channel as waiting, or no longer waiting, for the customer to broadcast a custClose
+    /// operation produced off chain.
+    async fn set_awaiting_broadcast(
+        &self,
+        channel_name: &ChannelName,
+        awaiting_broadcast: bool,
+    ) -> Result<()>;
+
     /// Assign a new [`ZkChannelAddress`] to an existing channel.
     async fn readdress_channel(
         &self,
@@ -228,10 +581,172 @@ pub trait QueryCustomer: Send + Sync {
     /// details about the originated contract, and any money that has been paid out.
     async fn get_channel(&self, channel_name: &ChannelName) -> Result<ChannelDetails>;
 
+    /// Get complete [`ChannelDetails`] for the channel with the given [`ChannelId`].
+    async fn get_channel_by_id(&self, channel_id: &ChannelId) -> Result<ChannelDetails>;
+
+    /// Get the label of the channel with the given [`ChannelId`], if there is one.
+    async fn channel_label_for_id(&self, channel_id: &ChannelId) -> Result<Option<ChannelName>>;
+
+    /// Get the label of the only channel whose [`ChannelId`], as it is displayed, starts with the
+    /// given prefix.
+    async fn channel_label_for_prefix(&self, prefix: &str) -> Result<ChannelName>;
+
+    /// Get the labels of the channels with the merchant at the given [`ZkChannelAddress`] which
+    /// aren't closed yet, in order of label.
+    async fn channels_for_address(&self, address: &ZkChannelAddress) -> Result<Vec<ChannelName>>;
+
+    /// Move a closed channel out of the channels in use, keeping only an [`ArchivedChannel`]
+    /// record of it. Fails with [`Error::NotTerminal`] if the channel isn't closed.
+    async fn archive_channel(&self, channel_name: &ChannelName) -> Result<()>;
+
+    /// Delete a closed channel entirely, keeping no record of it. Fails with
+    /// [`Error::NotTerminal`] if the channel isn't closed.
+    async fn purge_channel(&self, channel_name: &ChannelName) -> Result<()>;
+
+    /// Get every archived channel, in the order they were archived.
+    async fn get_archived_channels(&self) -> Result<Vec<ArchivedChannel>>;
+
+    /// Get everything needed to restore _every_ channel from a backup.
+    async fn export_channels(&self) -> Result<Vec<ChannelBackup>>;
+
+    /// Restore channels from a backup in a single transaction, resolving each channel whose name
+    /// is already taken with `strategy`. If the strategy is [`MergeStrategy::Refuse`] and any name
+    /// is taken, nothing is restored.
+    async fn import_channels(
+        &self,
+        channels: Vec<ChannelBackup>,
+        strategy: MergeStrategy,
+    ) -> Result<ImportSummary>;
+
+    /// Record that an operation calling the given [`Entrypoint`] is about to be posted for a
+    /// channel, unless one already is. Returns `false` if one already is.
+    ///
+    /// A record older than `stale_after` is assumed to have been left behind by a process that
+    /// stopped before it could call [`QueryCustomer::end_operation`], and is replaced.
+    async fn begin_operation(
+        &self,
+        channel_name: &ChannelName,
+        entrypoint: Entrypoint,
+        stale_after: Duration,
+    ) -> Result<bool>;
+
+    /// Clear the record of an operation calling the given [`Entrypoint`] for a channel, whether or
+    /// not it succeeded.
+    async fn end_operation(&self, channel_name: &ChannelName, entrypoint: Entrypoint)
+        -> Result<()>;
+
+    /// Get the entrypoints for which operations are being posted for a channel, as recorded by
+    /// [`QueryCustomer::begin_operation`].
+    async fn operations_in_flight(&self, channel_name: &ChannelName) -> Result<Vec<Entrypoint>>;
+
+    /// Get complete [`ChannelDetails`] for every channel which has been stuck partway through
+    /// establishment, with an originated contract but before the merchant's funding, since before
+    /// the given time.
+    async fn get_stalled_establishments(
+        &self,
+        stalled_since: SystemTime,
+    ) -> Result<Vec<ChannelDetails>>;
+
+    /// Get the hash of the keys pinned for the merchant at the given address, if any.
+    async fn trusted_merchant_key_hash(
+        &self,
+        address: &ZkChannelAddress,
+    ) -> Result<Option<KeyHash>>;
+
+    /// Record that the daemon saw a channel's contract with the given status, when the head block
+    /// was at the given level, and return what is now recorded.
+    ///
+    /// If the status differs from the one last seen, the level is recorded along with it, and any
+    /// reaction to the previous status is forgotten; otherwise only the time it was seen changes.
+    async fn observe_contract(
+        &self,
+        channel_name: &ChannelName,
+        status: ContractStatus,
+        level: u32,
+    ) -> Result<ContractObservation>;
+
+    /// Record that the daemon called the given entrypoint in reaction to a channel's contract
+    /// having the given status. Returns `false` if the daemon already reacted, or if the contract
+    /// was last seen with some other status.
+    async fn record_reaction(
+        &self,
+        channel_name: &ChannelName,
+        status: ContractStatus,
+        reaction: Entrypoint,
+    ) -> Result<bool>;
+
+    /// Record that the given entrypoint needs calling in reaction to a channel's contract having
+    /// the given status, but that the daemon left it for the user to call. Returns `false` if the
+    /// contract was last seen with some other status.
+    async fn record_required_action(
+        &self,
+        channel_name: &ChannelName,
+        status: ContractStatus,
+        action: Entrypoint,
+    ) -> Result<bool>;
+
+    /// Get what the daemon last saw of a channel's contract, and how it reacted, if it has seen
+    /// the contract at all.
+    async fn contract_observation(
+        &self,
+        channel_name: &ChannelName,
+    ) -> Result<Option<ContractObservation>>;
+
+    /// Pin the hash of the keys of the merchant at the given address, replacing any previous pin.
+    async fn trust_merchant_key_hash(
+        &self,
+        address: &ZkChannelAddress,
+        key_hash: &KeyHash,
+    ) -> Result<()>;
+
+    /// Get every change in the kind of state of a channel, oldest first.
+    async fn channel_history(&self, channel_name: &ChannelName) -> Result<Vec<ChannelEvent>>;
+
+    /// Record that a payment on the channel has begun, returning its ID. The payment is pending
+    /// until it is completed with [`QueryCustomerExt::with_channel_state_paid`], or failed with
+    /// [`QueryCustomer::fail_payment`].
+    async fn begin_payment(&self, channel_name: &ChannelName, payment: &NewPayment) -> Result<i64>;
+
+    /// Record that a pending payment failed, and why. A payment which already completed is left
+    /// as it is.
+    async fn fail_payment(&self, payment_id: i64, reason: &str) -> Result<()>;
+
+    /// Record the note the merchant sent in response to a payment.
+    async fn set_payment_response_note(&self, payment_id: i64, response_note: &str) -> Result<()>;
+
+    /// Get every payment begun on the channel, whatever became of it, oldest first.
+    async fn channel_payments(&self, channel_name: &ChannelName) -> Result<Vec<PaymentRecord>>;
+
+    /// Keep the messages which started a pending payment, so that it can be resumed.
+    async fn record_payment_start(&self, payment_id: i64, start: &PaymentStart) -> Result<()>;
+
+    /// Keep the revocation revealed to lock a pending payment, so that it can be resumed.
+    async fn record_payment_lock(&self, payment_id: i64, lock: &PaymentLock) -> Result<()>;
+
+    /// Get the latest payment on the channel which is still pending after it started, if any,
+    /// with what's needed to resume it.
+    async fn interrupted_payment(
+        &self,
+        channel_name: &ChannelName,
+    ) -> Result<Option<InterruptedPayment>>;
+
+    /// Stream the payments completed since the given time, on the given channel or else on every
+    /// channel, ordered by channel label and then oldest first.
+    fn payment_history<'a>(
+        &'a self,
+        channel_name: Option<&'a ChannelName>,
+        since: SystemTime,
+    ) -> BoxStream<'a, Result<PaymentRecord>>;
+
+    /// Get every payment made with the given payment ID, on any channel, whatever became of it,
+    /// oldest first.
+    async fn payments_with_id(&self, payment_id: &PaymentId) -> Result<Vec<PaymentRecord>>;
+
     /// **Don't call this function directly:** instead call
     /// [`QueryCustomerExt::with_channel_state`] or [`QueryCustomerExt::with_closeable_channel`].  This
     /// method retrieves the current state from the database, retrieves an updated state by executing
-    /// `with_state` on the current state, and updates the database.  This method uses `Box<dyn Any +
+    /// `with_state` on the current state, and updates the database, completing the pending payment
+    /// `payment_id` (if any) with the balances of the updated state.  This method uses `Box<dyn Any +
     /// Send>` to avoid the use of generic parameters,
     /// which is what allows the trait to be object safe.
     ///
@@ -245,6 +760,7 @@ pub trait QueryCustomer: Send + Sync {
     async fn with_channel_state_erased<'a>(
         &'a self,
         channel_name: &ChannelName,
+        payment_id: Option<i64>,
         with_state: Box<
             dyn for<'s> FnOnce(
                     State,
@@ -257,12 +773,72 @@ pub trait QueryCustomer: Send + Sync {
     ) -> Result<std::result::Result<Box<dyn Any>, Box<dyn Any>>>;
 }
 
+/// The most times a transaction is begun again after waiting out the busy timeout for another
+/// connection to release the write lock.
+const MAX_BUSY_RETRIES: u32 = 3;
+
+/// Whether an error is SQLite reporting that the database is locked by another connection.
+fn is_busy(error: &sqlx::Error) -> bool {
+    const SQLITE_BUSY: i32 = 5;
+    const SQLITE_LOCKED: i32 = 6;
+    error
+        .as_database_error()
+        .and_then(|e| e.code())
+        .and_then(|code| code.parse::<i32>().ok())
+        // Extended result codes carry the primary code in their lowest byte
+        .map_or(false, |code| {
+            matches!(code & 0xff, SQLITE_BUSY | SQLITE_LOCKED)
+        })
+}
+
+/// Begin a transaction which holds the write lock on the database from the start, retrying a
+/// bounded number of times if another connection holds it for too long.
+///
+/// Taking the lock before anything is read means that what the transaction reads can't be changed
+/// by another writer before it is written back. SQLite would otherwise refuse the write without
+/// waiting for the lock, and it couldn't be retried without reading the channel again.
+async fn begin_write(pool: &SqlitePool) -> Result<Transaction<'_, Sqlite>> {
+    let mut retries = 0;
+    loop {
+        let mut transaction = pool.begin().await?;
+        // A write which changes nothing, but which takes the write lock
+        match sqlx::query!("DELETE FROM customer_channels WHERE 0")
+            .execute(&mut transaction)
+            .await
+        {
+            Ok(_) => return Ok(transaction),
+            Err(e) if is_busy(&e) && retries < MAX_BUSY_RETRIES => {
+                tracing::debug!(retries, "Customer database is locked; retrying");
+                retries += 1;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
 #[async_trait]
 impl QueryCustomer for SqlitePool {
     async fn migrate(&self) -> Result<()> {
         sqlx::migrate!("src/database/migrations/customer")
             .run(self)
             .await?;
+
+        // Channels stored before their IDs were recorded get them from their states
+        let unidentified = sqlx::query!(
+            r#"SELECT id, state AS "state: State" FROM customer_channels WHERE channel_id IS NULL"#
+        )
+        .fetch_all(self)
+        .await?;
+        for r in unidentified {
+            sqlx::query!(
+                "UPDATE customer_channels SET channel_id = ? WHERE id = ?",
+                r.state.channel_id(),
+                r.id,
+            )
+            .execute(self)
+            .await?;
+        }
+
         Ok(())
     }
 
@@ -276,9 +852,11 @@ impl QueryCustomer for SqlitePool {
     ) -> std::result::Result<(), (Inactive, Error)> {
         let merchant_deposit = *inactive.merchant_balance();
         let customer_deposit = *inactive.customer_balance();
+        let channel_id = *inactive.channel_id();
         let state = State::Inactive(inactive);
         (|| async {
-            let mut transaction = self.begin().await?;
+            channel_name.validate()?;
+            let mut transaction = begin_write(self).await?;
 
             // Determine if the channel already exists
             let already_exists = sqlx::query!(
@@ -325,9 +903,11 @@ impl QueryCustomer for SqlitePool {
                     closing_balances,
                     merchant_tezos_public_key,
                     contract_id,
-                    config_id
+                    config_id,
+                    state_updated_at,
+                    channel_id
                 )
-                VALUES (?, ?, ?, ?, ?, ?, ?, NULL, ?)
+                VALUES (?, ?, ?, ?, ?, ?, ?, NULL, ?, strftime('%s', 'now'), ?)
             ",
                 channel_name,
                 address,
@@ -336,7 +916,8 @@ impl QueryCustomer for SqlitePool {
                 state,
                 default_balances,
                 merchant_tezos_public_key_string,
-                inserted_config.id
+                inserted_config.id,
+                channel_id,
             )
             .execute(&mut transaction)
             .await
@@ -369,8 +950,9 @@ impl QueryCustomer for SqlitePool {
             "#,
             channel_name
         )
-        .fetch_one(self)
+        .fetch_optional(self)
         .await?
+        .ok_or_else(|| Error::NoSuchChannel(channel_name.clone()))?
         .data)
     }
 
@@ -409,16 +991,19 @@ impl QueryCustomer for SqlitePool {
     async fn update_closing_balances(
         &self,
         channel_name: &ChannelName,
+        closing_path: ClosingPath,
         merchant_balance: MerchantBalance,
         customer_balance: Option<CustomerBalance>,
     ) -> Result<()> {
-        let mut transaction = self.begin().await?;
+        let mut transaction = begin_write(self).await?;
 
         // Ensure that the channel name exists
         // TODO: find a way to do this modularly with `closing_balances()`?
-        let closing_balances = sqlx::query!(
+        let record = sqlx::query!(
             r#"
-            SELECT closing_balances AS "closing_balances: ClosingBalances"
+            SELECT
+                state AS "state: State",
+                closing_balances AS "closing_balances: ClosingBalances"
             FROM customer_channels
             WHERE label = ?"#,
             channel_name,
@@ -426,8 +1011,14 @@ impl QueryCustomer for SqlitePool {
         .fetch(&mut transaction)
         .next()
         .await
-        .ok_or_else(|| Error::NoSuchChannel(channel_name.clone()))?
-        .map(|record| record.closing_balances)?;
+        .ok_or_else(|| Error::NoSuchChannel(channel_name.clone()))??;
+        let closing_balances = record.closing_balances;
+
+        // Make sure the channel is closing: balances are only paid out by a close.
+        let state_name = record.state.state_name();
+        if !state_name.is_closing() {
+            return Err(Error::NotClosing(channel_name.clone(), state_name));
+        }
 
         // Make sure we're not decreasing merchant balance.
         if let Some(original) = closing_balances.merchant_balance {
@@ -458,8 +1049,9 @@ impl QueryCustomer for SqlitePool {
 
         // Update the db with the new balances.
         sqlx::query!(
-            "UPDATE customer_channels SET closing_balances = ? WHERE label = ?",
+            "UPDATE customer_channels SET closing_balances = ?, closing_path = ? WHERE label = ?",
             updated_closing_balances,
+            closing_path,
             channel_name,
         )
         .execute(&mut transaction)
@@ -475,6 +1067,7 @@ impl QueryCustomer for SqlitePool {
             r#"
             SELECT 
                 contract_id AS "contract_id: ContractId",
+                contract_level AS "contract_level: u32",
                 merchant_tezos_public_key AS "merchant_tezos_public_key: String"
             FROM customer_channels
             WHERE label = ?
@@ -494,15 +1087,17 @@ impl QueryCustomer for SqlitePool {
         Ok(ContractDetails {
             merchant_tezos_public_key,
             contract_id: record.contract_id,
+            contract_level: record.contract_level,
         })
     }
 
-    async fn initialize_contract_details(
+    async fn set_contract_id(
         &self,
         channel_name: &ChannelName,
         contract_id: &ContractId,
+        level: u32,
     ) -> Result<()> {
-        let mut transaction = self.begin().await?;
+        let mut transaction = begin_write(self).await?;
 
         // Ensure that channel exists and does not already have contract details.
         // TODO: find a way to do this modularly with `contract_details()`
@@ -526,8 +1121,9 @@ impl QueryCustomer for SqlitePool {
 
         // Update channel with new details.
         sqlx::query!(
-            "UPDATE customer_channels SET contract_id = ? WHERE label = ?",
+            "UPDATE customer_channels SET contract_id = ?, contract_level = ? WHERE label = ?",
             contract_id,
+            level,
             channel_name,
         )
         .execute(&mut transaction)
@@ -543,7 +1139,8 @@ impl QueryCustomer for SqlitePool {
         channel_name: &ChannelName,
         new_channel_name: &ChannelName,
     ) -> Result<()> {
-        let mut transaction = self.begin().await?;
+        new_channel_name.validate()?;
+        let mut transaction = begin_write(self).await?;
 
         // Ensure that the old channel name exists
         let old_exists = sqlx::query!(
@@ -578,7 +1175,7 @@ impl QueryCustomer for SqlitePool {
             new_channel_name,
             channel_name,
         )
-        .execute(self)
+        .execute(&mut transaction)
         .await?;
 
         transaction.commit().await?;
@@ -586,6 +1183,27 @@ impl QueryCustomer for SqlitePool {
         Ok(())
     }
 
+    async fn set_awaiting_broadcast(
+        &self,
+        channel_name: &ChannelName,
+        awaiting_broadcast: bool,
+    ) -> Result<()> {
+        let rows_affected = sqlx::query!(
+            "UPDATE customer_channels SET awaiting_broadcast = ? WHERE label = ?",
+            awaiting_broadcast,
+            channel_name,
+        )
+        .execute(self)
+        .await?
+        .rows_affected();
+
+        if rows_affected == 1 {
+            Ok(())
+        } else {
+            Err(Error::NoSuchChannel(channel_name.clone()))
+        }
+    }
+
     async fn readdress_channel(
         &self,
         channel_name: &ChannelName,
@@ -618,8 +1236,11 @@ impl QueryCustomer for SqlitePool {
                 customer_deposit AS "customer_deposit: CustomerBalance",
                 merchant_deposit AS "merchant_deposit: MerchantBalance",
                 closing_balances AS "closing_balances: ClosingBalances",
+                closing_path AS "closing_path: ClosingPath",
                 merchant_tezos_public_key AS "merchant_tezos_public_key: String",
-                contract_id AS "contract_id: ContractId"
+                contract_id AS "contract_id: ContractId",
+                contract_level AS "contract_level: u32",
+                awaiting_broadcast AS "awaiting_broadcast: bool"
             FROM customer_channels
             "#
         )
@@ -635,13 +1256,16 @@ impl QueryCustomer for SqlitePool {
                 customer_deposit: r.customer_deposit,
                 merchant_deposit: r.merchant_deposit,
                 closing_balances: r.closing_balances,
+                closing_path: r.closing_path,
                 contract_details: ContractDetails {
                     merchant_tezos_public_key: TezosPublicKey::from_base58check(
                         &r.merchant_tezos_public_key,
                     )
                     .map_err(|_| Error::InvalidContractDetails(label_copy))?,
                     contract_id: r.contract_id,
+                    contract_level: r.contract_level,
                 },
+                awaiting_broadcast: r.awaiting_broadcast,
             })
         })
         .collect()
@@ -656,8 +1280,11 @@ impl QueryCustomer for SqlitePool {
                 customer_deposit AS "customer_deposit: CustomerBalance",
                 merchant_deposit AS "merchant_deposit: MerchantBalance",
                 closing_balances AS "closing_balances: ClosingBalances",
+                closing_path AS "closing_path: ClosingPath",
                 merchant_tezos_public_key AS "merchant_tezos_public_key: String",
-                contract_id AS "contract_id: ContractId"
+                contract_id AS "contract_id: ContractId",
+                contract_level AS "contract_level: u32",
+                awaiting_broadcast AS "awaiting_broadcast: bool"
             FROM customer_channels 
             WHERE label = ?
             "#,
@@ -675,310 +1302,3113 @@ impl QueryCustomer for SqlitePool {
                 customer_deposit: r.customer_deposit,
                 merchant_deposit: r.merchant_deposit,
                 closing_balances: r.closing_balances,
+                closing_path: r.closing_path,
                 contract_details: ContractDetails {
                     merchant_tezos_public_key: TezosPublicKey::from_base58check(
                         &r.merchant_tezos_public_key,
                     )
                     .map_err(|_| Error::InvalidContractDetails(channel_name.clone()))?,
                     contract_id: r.contract_id,
+                    contract_level: r.contract_level,
                 },
+                awaiting_broadcast: r.awaiting_broadcast,
             })
         })?
     }
 
-    async fn with_channel_state_erased<'a>(
-        &'a self,
-        channel_name: &ChannelName,
-        with_state: Box<
-            dyn for<'s> FnOnce(
-                    State,
-                ) -> std::result::Result<
-                    (State, Box<dyn Any + Send>),
-                    Box<dyn Any + Send>,
-                > + Send
-                + 'a,
-        >,
-    ) -> Result<std::result::Result<Box<dyn Any>, Box<dyn Any>>> {
-        let mut transaction = self.begin().await?;
-
-        // Retrieve the state so that we can modify it
-        let state: State = sqlx::query!(
-            r#"SELECT state AS "state: State" FROM customer_channels WHERE label = ?"#,
-            channel_name,
+    async fn get_channel_by_id(&self, channel_id: &ChannelId) -> Result<ChannelDetails> {
+        let r = sqlx::query!(
+            r#"
+            SELECT
+                label AS "label: ChannelName",
+                state AS "state: State",
+                address AS "address: ZkChannelAddress",
+                customer_deposit AS "customer_deposit: CustomerBalance",
+                merchant_deposit AS "merchant_deposit: MerchantBalance",
+                closing_balances AS "closing_balances: ClosingBalances",
+                closing_path AS "closing_path: ClosingPath",
+                merchant_tezos_public_key AS "merchant_tezos_public_key: String",
+                contract_id AS "contract_id: ContractId",
+                contract_level AS "contract_level: u32",
+                awaiting_broadcast AS "awaiting_broadcast: bool"
+            FROM customer_channels
+            WHERE channel_id = ?
+            "#,
+            channel_id,
         )
-        .fetch(&mut transaction)
-        .next()
-        .await
-        .ok_or_else(|| Error::NoSuchChannel(channel_name.clone()))??
-        .state;
+        .fetch_optional(self)
+        .await?
+        .ok_or(Error::NoSuchChannelId(*channel_id))?;
 
-        // Perform the operation with the state fetched from the database
-        match with_state(state) {
-            Ok((state, output)) => {
-                // Store the new state to the database
-                sqlx::query!(
-                    "UPDATE customer_channels SET state = ? WHERE label = ?",
-                    state,
-                    channel_name
+        Ok(ChannelDetails {
+            contract_details: ContractDetails {
+                merchant_tezos_public_key: TezosPublicKey::from_base58check(
+                    &r.merchant_tezos_public_key,
                 )
-                .execute(&mut transaction)
-                .await?;
-
-                // Commit the transaction
-                transaction.commit().await?;
-
-                Ok(Ok(output))
-            }
-            Err(error) => Ok(Err(error)),
-        }
+                .map_err(|_| Error::InvalidContractDetails(r.label.clone()))?,
+                contract_id: r.contract_id,
+                contract_level: r.contract_level,
+            },
+            label: r.label,
+            state: r.state,
+            address: r.address,
+            customer_deposit: r.customer_deposit,
+            merchant_deposit: r.merchant_deposit,
+            closing_balances: r.closing_balances,
+            closing_path: r.closing_path,
+            awaiting_broadcast: r.awaiting_broadcast,
+        })
     }
-}
 
-// Blanket implementation of [`QueryCustomerExt`] for all [`QueryCustomer`]
-#[async_trait]
-impl<Q: QueryCustomer + ?Sized> QueryCustomerExt for Q {
-    async fn with_channel_state<
-        'a,
-        S: ZkChannelState + Send + 'static,
-        F: FnOnce(S::ZkAbacusState) -> std::result::Result<(State, T), E> + Send + 'a,
-        T: Send + 'static,
-        E: Send + 'static,
-    >(
-        &'a self,
-        channel_name: &ChannelName,
-        _expected_state: S,
-        with_zkabacus_state: F,
-    ) -> Result<std::result::Result<T, E>> {
-        let result = <Self as QueryCustomer>::with_channel_state_erased(
-            self,
+    async fn channel_label_for_id(&self, channel_id: &ChannelId) -> Result<Option<ChannelName>> {
+        Ok(sqlx::query!(
+            r#"SELECT label AS "label: ChannelName" FROM customer_channels WHERE channel_id = ?"#,
+            channel_id,
+        )
+        .fetch_optional(self)
+        .await?
+        .map(|r| r.label))
+    }
+
+    async fn channel_label_for_prefix(&self, prefix: &str) -> Result<ChannelName> {
+        // Channel IDs are case-sensitive, unlike `LIKE`
+        let prefix_length = prefix.len() as i64;
+        let mut labels = sqlx::query!(
+            r#"
+            SELECT label AS "label: ChannelName"
+            FROM customer_channels
+            WHERE substr(channel_id, 1, ?) = ?
+            LIMIT 2
+            "#,
+            prefix_length,
+            prefix,
+        )
+        .fetch_all(self)
+        .await?
+        .into_iter()
+        .map(|r| r.label);
+
+        match (labels.next(), labels.next()) {
+            (None, _) => Err(Error::ChannelNotFoundWithPrefix(prefix.to_string())),
+            (Some(label), None) => Ok(label),
+            (Some(_), Some(_)) => Err(Error::ChannelIdCollision(prefix.to_string())),
+        }
+    }
+
+    async fn channels_for_address(&self, address: &ZkChannelAddress) -> Result<Vec<ChannelName>> {
+        Ok(sqlx::query!(
+            r#"
+            SELECT
+                label AS "label: ChannelName",
+                state AS "state: State"
+            FROM customer_channels
+            WHERE address = ?
+            ORDER BY label
+            "#,
+            address,
+        )
+        .fetch_all(self)
+        .await?
+        .into_iter()
+        .filter(|r| !r.state.state_name().is_terminal())
+        .map(|r| r.label)
+        .collect())
+    }
+
+    async fn archive_channel(&self, channel_name: &ChannelName) -> Result<()> {
+        let mut transaction = begin_write(self).await?;
+
+        let r = sqlx::query!(
+            r#"SELECT id, state AS "state: State", config_id FROM customer_channels WHERE label = ?"#,
             channel_name,
-            Box::new(
-                // Extract the inner zkAbacus type from the state enum and make sure it matches
-                |state| match S::zkabacus_state(state) {
-                    Ok(zkabacus_state) => match with_zkabacus_state(zkabacus_state) {
-                        Ok((state, t)) => Ok((state, Box::new(t))),
-                        Err(e) => Err(Box::new(Ok::<E, UnexpectedState>(e))),
-                    },
-                    Err(unexpected_state) => {
-                        Err(Box::new(Err::<E, UnexpectedState>(unexpected_state)))
-                    }
-                },
-            ),
         )
+        .fetch_optional(&mut transaction)
+        .await?
+        .ok_or_else(|| Error::NoSuchChannel(channel_name.clone()))?;
+
+        let final_state = r.state.state_name();
+        if !final_state.is_terminal() {
+            return Err(Error::NotTerminal(channel_name.clone(), final_state));
+        }
+
+        sqlx::query!(
+            "INSERT INTO archived_channels (
+                label,
+                channel_id,
+                address,
+                merchant_deposit,
+                customer_deposit,
+                final_state,
+                closing_balances,
+                closing_path,
+                contract_id,
+                contract_level,
+                archived_at
+            )
+            SELECT
+                label,
+                channel_id,
+                address,
+                merchant_deposit,
+                customer_deposit,
+                ?,
+                closing_balances,
+                closing_path,
+                contract_id,
+                contract_level,
+                strftime('%s', 'now')
+            FROM customer_channels
+            WHERE id = ?",
+            final_state,
+            r.id,
+        )
+        .execute(&mut transaction)
         .await?;
 
-        // Cast the result back to its true type
-        match result {
-            // Successful result
-            Ok(t) => {
-                let t: T = *t.downcast().unwrap();
-                Ok(Ok(t))
-            }
-            // Error, which could be one of...
-            Err(error_result) => {
-                let error_result: std::result::Result<E, UnexpectedState> =
-                    *error_result.downcast().unwrap();
-                match error_result {
-                    // Error returned by the closure
-                    Ok(e) => Ok(Err(e)),
-                    // Error returned because the state didn't match the one in the database.
-                    Err(e) => return Err(e.into()),
+        // The channel's history and in-flight operations go with it
+        sqlx::query!("DELETE FROM customer_channels WHERE id = ?", r.id)
+            .execute(&mut transaction)
+            .await?;
+        sqlx::query!("DELETE FROM configs WHERE id = ?", r.config_id)
+            .execute(&mut transaction)
+            .await?;
+
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn purge_channel(&self, channel_name: &ChannelName) -> Result<()> {
+        let mut transaction = begin_write(self).await?;
+
+        let r = sqlx::query!(
+            r#"SELECT id, state AS "state: State", config_id FROM customer_channels WHERE label = ?"#,
+            channel_name,
+        )
+        .fetch_optional(&mut transaction)
+        .await?
+        .ok_or_else(|| Error::NoSuchChannel(channel_name.clone()))?;
+
+        let state_name = r.state.state_name();
+        if !state_name.is_terminal() {
+            return Err(Error::NotTerminal(channel_name.clone(), state_name));
+        }
+
+        sqlx::query!("DELETE FROM customer_channels WHERE id = ?", r.id)
+            .execute(&mut transaction)
+            .await?;
+        sqlx::query!("DELETE FROM configs WHERE id = ?", r.config_id)
+            .execute(&mut transaction)
+            .await?;
+
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn get_archived_channels(&self) -> Result<Vec<ArchivedChannel>> {
+        Ok(sqlx::query!(
+            r#"
+            SELECT
+                label AS "label: ChannelName",
+                channel_id AS "channel_id: ChannelId",
+                address AS "address: ZkChannelAddress",
+                merchant_deposit AS "merchant_deposit: MerchantBalance",
+                customer_deposit AS "customer_deposit: CustomerBalance",
+                final_state AS "final_state: StateName",
+                closing_balances AS "closing_balances: ClosingBalances",
+                closing_path AS "closing_path: ClosingPath",
+                contract_id AS "contract_id: ContractId",
+                contract_level AS "contract_level: u32",
+                archived_at AS "archived_at: i64"
+            FROM archived_channels
+            ORDER BY id
+            "#
+        )
+        .fetch_all(self)
+        .await?
+        .into_iter()
+        .map(|r| ArchivedChannel {
+            label: r.label,
+            channel_id: r.channel_id,
+            address: r.address,
+            merchant_deposit: r.merchant_deposit,
+            customer_deposit: r.customer_deposit,
+            final_state: r.final_state,
+            closing_balances: r.closing_balances,
+            closing_path: r.closing_path,
+            contract_id: r.contract_id,
+            contract_level: r.contract_level,
+            archived_at: UNIX_EPOCH + Duration::from_secs(r.archived_at as u64),
+        })
+        .collect())
+    }
+
+    async fn export_channels(&self) -> Result<Vec<ChannelBackup>> {
+        sqlx::query!(
+            r#"
+            SELECT
+                label AS "label: ChannelName",
+                address AS "address: ZkChannelAddress",
+                merchant_deposit AS "merchant_deposit: MerchantBalance",
+                customer_deposit AS "customer_deposit: CustomerBalance",
+                state AS "state: State",
+                closing_balances AS "closing_balances: ClosingBalances",
+                closing_path AS "closing_path: ClosingPath",
+                merchant_tezos_public_key AS "merchant_tezos_public_key: String",
+                contract_id AS "contract_id: ContractId",
+                contract_level AS "contract_level: u32",
+                awaiting_broadcast AS "awaiting_broadcast: bool",
+                configs.data AS "zkabacus_config: zkabacus_crypto::customer::Config"
+            FROM customer_channels
+            INNER JOIN configs ON configs.id = customer_channels.config_id
+            ORDER BY customer_channels.id
+            "#
+        )
+        .fetch_all(self)
+        .await?
+        .into_iter()
+        .map(|r| -> Result<ChannelBackup> {
+            Ok(ChannelBackup {
+                label: r.label,
+                address: r.address,
+                merchant_deposit: r.merchant_deposit,
+                customer_deposit: r.customer_deposit,
+                state: r.state,
+                closing_balances: r.closing_balances,
+                closing_path: r.closing_path,
+                merchant_tezos_public_key: r.merchant_tezos_public_key,
+                contract_id: r.contract_id,
+                contract_level: r.contract_level,
+                awaiting_broadcast: r.awaiting_broadcast,
+                zkabacus_config: r.zkabacus_config,
+            })
+        })
+        .collect()
+    }
+
+    async fn import_channels(
+        &self,
+        channels: Vec<ChannelBackup>,
+        strategy: MergeStrategy,
+    ) -> Result<ImportSummary> {
+        let mut transaction = begin_write(self).await?;
+        let mut summary = ImportSummary::default();
+
+        for channel in channels {
+            let existing = sqlx::query!(
+                r#"
+                SELECT config_id AS "config_id: i64"
+                FROM customer_channels
+                WHERE label = ?
+                "#,
+                channel.label
+            )
+            .fetch_optional(&mut transaction)
+            .await?;
+            let replacing = existing.is_some();
+
+            if let Some(existing) = existing {
+                match strategy {
+                    // Dropping the transaction rolls back every channel restored so far
+                    MergeStrategy::Refuse => return Err(Error::ChannelExists(channel.label)),
+                    MergeStrategy::Skip => {
+                        summary.skipped.push(channel.label);
+                        continue;
+                    }
+                    MergeStrategy::Replace => {
+                        sqlx::query!(
+                            "DELETE FROM customer_channels WHERE label = ?",
+                            channel.label
+                        )
+                        .execute(&mut transaction)
+                        .await?;
+                        sqlx::query!("DELETE FROM configs WHERE id = ?", existing.config_id)
+                            .execute(&mut transaction)
+                            .await?;
+                    }
                 }
             }
+
+            let channel_id = *channel.state.channel_id();
+            let inserted_config = sqlx::query!(
+                r#"
+                INSERT INTO configs (data)
+                VALUES (?)
+                RETURNING id AS "id: i32"
+                "#,
+                channel.zkabacus_config
+            )
+            .fetch_one(&mut transaction)
+            .await?;
+
+            sqlx::query!(
+                "INSERT INTO customer_channels (
+                    label,
+                    address,
+                    merchant_deposit,
+                    customer_deposit,
+                    state,
+                    closing_balances,
+                    closing_path,
+                    merchant_tezos_public_key,
+                    contract_id,
+                    contract_level,
+                    awaiting_broadcast,
+                    config_id,
+                    state_updated_at,
+                    channel_id
+                )
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, strftime('%s', 'now'), ?)
+            ",
+                channel.label,
+                channel.address,
+                channel.merchant_deposit,
+                channel.customer_deposit,
+                channel.state,
+                channel.closing_balances,
+                channel.closing_path,
+                channel.merchant_tezos_public_key,
+                channel.contract_id,
+                channel.contract_level,
+                channel.awaiting_broadcast,
+                inserted_config.id,
+                channel_id,
+            )
+            .execute(&mut transaction)
+            .await?;
+
+            if replacing {
+                summary.replaced.push(channel.label);
+            } else {
+                summary.imported.push(channel.label);
+            }
         }
+
+        transaction.commit().await?;
+        Ok(summary)
     }
 
-    async fn with_closeable_channel<'a, E: Send + 'static>(
+    async fn begin_operation(
         &self,
         channel_name: &ChannelName,
-        with_closeable_state: impl FnOnce(State) -> std::result::Result<(State, ClosingMessage), E>
-            + Send
-            + 'a,
-    ) -> Result<std::result::Result<ClosingMessage, E>> {
-        let result = <Self as QueryCustomer>::with_channel_state_erased(
-            self,
+        entrypoint: Entrypoint,
+        stale_after: Duration,
+    ) -> Result<bool> {
+        let mut transaction = begin_write(self).await?;
+
+        let channel_id = sqlx::query!(
+            "SELECT id FROM customer_channels WHERE label = ?",
             channel_name,
-            Box::new(|state| match with_closeable_state(state) {
-                Ok((state, t)) => {
-                    // Only allow updates that result in the PendingClose status.
-                    if let State::PendingClose(_) = state {
-                        Ok((state, Box::new(t)))
-                    } else {
-                        Err(Box::new(Err::<E, Error>(Error::CloseFailure)))
-                    }
-                }
-                // Closure function failed somehow
-                Err(e) => Err(Box::new(Ok::<E, Error>(e))),
-            }),
         )
+        .fetch(&mut transaction)
+        .next()
+        .await
+        .ok_or_else(|| Error::NoSuchChannel(channel_name.clone()))??
+        .id;
+
+        // Clear out any record of this operation that is too old to still be in flight
+        let stale_before = (SystemTime::now() - stale_after)
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs() as i64)
+            .unwrap_or(0);
+        sqlx::query!(
+            "DELETE FROM operations_in_flight
+            WHERE channel_id = ? AND entrypoint = ? AND started_at <= ?",
+            channel_id,
+            entrypoint,
+            stale_before,
+        )
+        .execute(&mut transaction)
         .await?;
 
-        // Cast the result back to its true type
-        match result {
-            // Successful result: get the `ClosingMessage` out of the box.
-            Ok(t) => Ok(Ok(*t.downcast().unwrap())),
-            // Error, which could be one of...
-            Err(error_result) => {
-                let err: Result<E> = *error_result.downcast().unwrap();
-                match err {
-                    // Error returned by the closure
-                    Ok(e) => Ok(Err(e)),
-                    // Error returned because the closure didn't return a PendingClose status.
-                    Err(e) => Err(e),
-                }
-            }
-        }
+        let rows_affected = sqlx::query!(
+            "INSERT OR IGNORE INTO operations_in_flight (channel_id, entrypoint, started_at)
+            VALUES (?, ?, strftime('%s', 'now'))",
+            channel_id,
+            entrypoint,
+        )
+        .execute(&mut transaction)
+        .await?
+        .rows_affected();
+
+        transaction.commit().await?;
+
+        Ok(rows_affected == 1)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::database::SqlitePoolOptions;
-    use {
-        rand::{rngs::StdRng, SeedableRng},
-        std::str::FromStr,
-    };
+    async fn end_operation(
+        &self,
+        channel_name: &ChannelName,
+        entrypoint: Entrypoint,
+    ) -> Result<()> {
+        sqlx::query!(
+            "DELETE FROM operations_in_flight
+            WHERE channel_id = (SELECT id FROM customer_channels WHERE label = ?)
+            AND entrypoint = ?",
+            channel_name,
+            entrypoint,
+        )
+        .execute(self)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn operations_in_flight(&self, channel_name: &ChannelName) -> Result<Vec<Entrypoint>> {
+        Ok(sqlx::query!(
+            r#"
+            SELECT entrypoint AS "entrypoint: Entrypoint"
+            FROM operations_in_flight
+            WHERE channel_id = (SELECT id FROM customer_channels WHERE label = ?)
+            ORDER BY started_at
+            "#,
+            channel_name,
+        )
+        .fetch_all(self)
+        .await?
+        .into_iter()
+        .map(|r| r.entrypoint)
+        .collect())
+    }
+
+    async fn get_stalled_establishments(
+        &self,
+        stalled_since: SystemTime,
+    ) -> Result<Vec<ChannelDetails>> {
+        let stalled_since = stalled_since
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs() as i64);
+
+        let labels = sqlx::query!(
+            r#"
+            SELECT label AS "label: ChannelName"
+            FROM customer_channels
+            WHERE state_updated_at <= ?
+            "#,
+            stalled_since,
+        )
+        .fetch_all(self)
+        .await?;
+
+        // The state is stored opaquely, so it has to be checked here rather than in the query
+        let mut stalled = Vec::new();
+        for r in labels {
+            let channel = self.get_channel(&r.label).await?;
+            if matches!(
+                channel.state.state_name(),
+                StateName::Originated | StateName::CustomerFunded
+            ) {
+                stalled.push(channel);
+            }
+        }
+        Ok(stalled)
+    }
+
+    async fn trusted_merchant_key_hash(
+        &self,
+        address: &ZkChannelAddress,
+    ) -> Result<Option<KeyHash>> {
+        Ok(sqlx::query!(
+            r#"SELECT key_hash AS "key_hash: KeyHash" FROM trusted_merchants WHERE address = ?"#,
+            address,
+        )
+        .fetch_optional(self)
+        .await?
+        .map(|r| r.key_hash))
+    }
+
+    async fn trust_merchant_key_hash(
+        &self,
+        address: &ZkChannelAddress,
+        key_hash: &KeyHash,
+    ) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO trusted_merchants (address, key_hash) VALUES (?, ?)
+            ON CONFLICT (address) DO UPDATE SET key_hash = excluded.key_hash",
+            address,
+            key_hash,
+        )
+        .execute(self)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn channel_history(&self, channel_name: &ChannelName) -> Result<Vec<ChannelEvent>> {
+        let mut transaction = self.begin().await?;
+
+        let channel_id = sqlx::query!(
+            "SELECT id FROM customer_channels WHERE label = ?",
+            channel_name,
+        )
+        .fetch_optional(&mut transaction)
+        .await?
+        .ok_or_else(|| Error::NoSuchChannel(channel_name.clone()))?
+        .id;
+
+        let events = sqlx::query!(
+            r#"
+            SELECT
+                old_state AS "old_state: StateName",
+                new_state AS "new_state: StateName",
+                recorded_at AS "recorded_at: i64"
+            FROM channel_events
+            WHERE channel_id = ?
+            ORDER BY id
+            "#,
+            channel_id,
+        )
+        .fetch_all(&mut transaction)
+        .await?
+        .into_iter()
+        .map(|r| ChannelEvent {
+            old_state: r.old_state,
+            new_state: r.new_state,
+            recorded_at: UNIX_EPOCH + Duration::from_secs(r.recorded_at as u64),
+        })
+        .collect();
+
+        transaction.commit().await?;
+        Ok(events)
+    }
+
+    async fn begin_payment(&self, channel_name: &ChannelName, payment: &NewPayment) -> Result<i64> {
+        let mut transaction = begin_write(self).await?;
+        let channel = sqlx::query!(
+            r#"SELECT id, state AS "state: State" FROM customer_channels WHERE label = ?"#,
+            channel_name,
+        )
+        .fetch_optional(&mut transaction)
+        .await?
+        .ok_or_else(|| Error::NoSuchChannel(channel_name.clone()))?;
+
+        // Until the payment completes, its balances are those it began with
+        let customer_balance = channel.state.customer_balance().into_inner() as i64;
+        let merchant_balance = channel.state.merchant_balance().into_inner() as i64;
+        let payment_uuid = payment.payment_id.to_string();
+        let payment_id = sqlx::query!(
+            r#"
+            INSERT INTO payments (
+                channel_id,
+                amount,
+                note,
+                outcome,
+                customer_balance_before,
+                customer_balance,
+                merchant_balance,
+                started_at,
+                paid_at,
+                payment_id
+            )
+            VALUES (?, ?, ?, 'pending', ?, ?, ?, strftime('%s', 'now'), strftime('%s', 'now'), ?)
+            RETURNING id AS "id!: i64"
+            "#,
+            channel.id,
+            payment.amount,
+            payment.note,
+            customer_balance,
+            customer_balance,
+            merchant_balance,
+            payment_uuid,
+        )
+        .fetch_one(&mut transaction)
+        .await?
+        .id;
+
+        transaction.commit().await?;
+        Ok(payment_id)
+    }
+
+    async fn fail_payment(&self, payment_id: i64, reason: &str) -> Result<()> {
+        sqlx::query!(
+            "UPDATE payments
+            SET outcome = 'failed', failure_reason = ?
+            WHERE id = ? AND outcome = 'pending'",
+            reason,
+            payment_id,
+        )
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+
+    async fn set_payment_response_note(&self, payment_id: i64, response_note: &str) -> Result<()> {
+        sqlx::query!(
+            "UPDATE payments SET response_note = ? WHERE id = ?",
+            response_note,
+            payment_id,
+        )
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+
+    async fn record_payment_start(&self, payment_id: i64, start: &PaymentStart) -> Result<()> {
+        sqlx::query!(
+            "UPDATE payments SET start_message = ? WHERE id = ? AND outcome = 'pending'",
+            start,
+            payment_id,
+        )
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+
+    async fn record_payment_lock(&self, payment_id: i64, lock: &PaymentLock) -> Result<()> {
+        sqlx::query!(
+            "UPDATE payments SET lock_message = ? WHERE id = ? AND outcome = 'pending'",
+            lock,
+            payment_id,
+        )
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+
+    async fn interrupted_payment(
+        &self,
+        channel_name: &ChannelName,
+    ) -> Result<Option<InterruptedPayment>> {
+        let mut transaction = self.begin().await?;
+        let channel_id = sqlx::query!(
+            "SELECT id FROM customer_channels WHERE label = ?",
+            channel_name
+        )
+        .fetch_optional(&mut transaction)
+        .await?
+        .ok_or_else(|| Error::NoSuchChannel(channel_name.clone()))?
+        .id;
+
+        Ok(sqlx::query!(
+            r#"
+            SELECT
+                id AS "id!: i64",
+                start_message AS "start_message!: PaymentStart",
+                lock_message AS "lock_message: PaymentLock"
+            FROM payments
+            WHERE channel_id = ? AND outcome = 'pending' AND start_message IS NOT NULL
+            ORDER BY id DESC
+            LIMIT 1
+            "#,
+            channel_id,
+        )
+        .fetch_optional(&mut transaction)
+        .await?
+        .map(|r| InterruptedPayment {
+            payment_id: r.id,
+            start: r.start_message,
+            lock: r.lock_message,
+        }))
+    }
+
+    async fn channel_payments(&self, channel_name: &ChannelName) -> Result<Vec<PaymentRecord>> {
+        let mut transaction = self.begin().await?;
+        let channel_id = sqlx::query!(
+            "SELECT id FROM customer_channels WHERE label = ?",
+            channel_name
+        )
+        .fetch_optional(&mut transaction)
+        .await?
+        .ok_or_else(|| Error::NoSuchChannel(channel_name.clone()))?
+        .id;
+
+        let payments = sqlx::query!(
+            r#"
+            SELECT
+                amount,
+                note,
+                outcome AS "outcome: PaymentOutcome",
+                failure_reason,
+                response_note,
+                customer_balance_before,
+                customer_balance,
+                merchant_balance,
+                COALESCE(started_at, paid_at) AS "started_at!: i64",
+                paid_at,
+                payment_id
+            FROM payments
+            WHERE channel_id = ?
+            ORDER BY id
+            "#,
+            channel_id,
+        )
+        .fetch_all(&mut transaction)
+        .await?
+        .into_iter()
+        .map(|r| PaymentRecord {
+            label: channel_name.clone(),
+            amount: r.amount,
+            note: r.note,
+            outcome: r.outcome,
+            failure_reason: r.failure_reason,
+            response_note: r.response_note,
+            customer_balance_before: r.customer_balance_before,
+            customer_balance: r.customer_balance,
+            merchant_balance: r.merchant_balance,
+            started_at: UNIX_EPOCH + Duration::from_secs(r.started_at as u64),
+            paid_at: UNIX_EPOCH + Duration::from_secs(r.paid_at as u64),
+            payment_id: r.payment_id.and_then(|id| id.parse().ok()),
+        })
+        .collect();
+
+        Ok(payments)
+    }
+
+    fn payment_history<'a>(
+        &'a self,
+        channel_name: Option<&'a ChannelName>,
+        since: SystemTime,
+    ) -> BoxStream<'a, Result<PaymentRecord>> {
+        let since = since
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs() as i64);
+
+        sqlx::query!(
+            r#"
+            SELECT
+                customer_channels.label AS "label: ChannelName",
+                payments.amount,
+                payments.note,
+                payments.outcome AS "outcome: PaymentOutcome",
+                payments.failure_reason,
+                payments.response_note,
+                payments.customer_balance_before,
+                payments.customer_balance,
+                payments.merchant_balance,
+                COALESCE(payments.started_at, payments.paid_at) AS "started_at!: i64",
+                payments.paid_at,
+                payments.payment_id
+            FROM payments
+            JOIN customer_channels ON customer_channels.id = payments.channel_id
+            WHERE
+                (? IS NULL OR customer_channels.label = ?)
+                AND payments.paid_at >= ?
+                AND payments.outcome = 'completed'
+            ORDER BY customer_channels.label, payments.id
+            "#,
+            channel_name,
+            channel_name,
+            since,
+        )
+        .fetch(self)
+        .map(|r| {
+            let r = r?;
+            Ok(PaymentRecord {
+                label: r.label,
+                amount: r.amount,
+                note: r.note,
+                outcome: r.outcome,
+                failure_reason: r.failure_reason,
+                response_note: r.response_note,
+                customer_balance_before: r.customer_balance_before,
+                customer_balance: r.customer_balance,
+                merchant_balance: r.merchant_balance,
+                started_at: UNIX_EPOCH + Duration::from_secs(r.started_at as u64),
+                paid_at: UNIX_EPOCH + Duration::from_secs(r.paid_at as u64),
+                payment_id: r.payment_id.and_then(|id| id.parse().ok()),
+            })
+        })
+        .boxed()
+    }
+
+    async fn payments_with_id(&self, payment_id: &PaymentId) -> Result<Vec<PaymentRecord>> {
+        let payment_id = payment_id.to_string();
+        let payments = sqlx::query!(
+            r#"
+            SELECT
+                customer_channels.label AS "label: ChannelName",
+                payments.amount,
+                payments.note,
+                payments.outcome AS "outcome: PaymentOutcome",
+                payments.failure_reason,
+                payments.response_note,
+                payments.customer_balance_before,
+                payments.customer_balance,
+                payments.merchant_balance,
+                COALESCE(payments.started_at, payments.paid_at) AS "started_at!: i64",
+                payments.paid_at,
+                payments.payment_id
+            FROM payments
+            JOIN customer_channels ON customer_channels.id = payments.channel_id
+            WHERE payments.payment_id = ?
+            ORDER BY payments.id
+            "#,
+            payment_id,
+        )
+        .fetch_all(self)
+        .await?
+        .into_iter()
+        .map(|r| PaymentRecord {
+            label: r.label,
+            amount: r.amount,
+            note: r.note,
+            outcome: r.outcome,
+            failure_reason: r.failure_reason,
+            response_note: r.response_note,
+            customer_balance_before: r.customer_balance_before,
+            customer_balance: r.customer_balance,
+            merchant_balance: r.merchant_balance,
+            started_at: UNIX_EPOCH + Duration::from_secs(r.started_at as u64),
+            paid_at: UNIX_EPOCH + Duration::from_secs(r.paid_at as u64),
+            payment_id: r.payment_id.and_then(|id| id.parse().ok()),
+        })
+        .collect();
+
+        Ok(payments)
+    }
+
+    async fn observe_contract(
+        &self,
+        channel_name: &ChannelName,
+        status: ContractStatus,
+        level: u32,
+    ) -> Result<ContractObservation> {
+        let status = status as i32;
+        let rows_affected = sqlx::query!(
+            "UPDATE customer_channels
+            SET
+                last_observed_level = CASE
+                    WHEN last_observed_status IS ? THEN last_observed_level ELSE ?
+                END,
+                last_reaction = CASE
+                    WHEN last_observed_status IS ? THEN last_reaction ELSE NULL
+                END,
+                required_action = CASE
+                    WHEN last_observed_status IS ? THEN required_action ELSE NULL
+                END,
+                last_observed_status = ?,
+                last_observed_at = strftime('%s', 'now')
+            WHERE label = ?",
+            status,
+            level,
+            status,
+            status,
+            status,
+            channel_name,
+        )
+        .execute(self)
+        .await?
+        .rows_affected();
+
+        if rows_affected != 1 {
+            return Err(Error::NoSuchChannel(channel_name.clone()));
+        }
+
+        self.contract_observation(channel_name)
+            .await?
+            .ok_or_else(|| Error::NoSuchChannel(channel_name.clone()))
+    }
+
+    async fn record_reaction(
+        &self,
+        channel_name: &ChannelName,
+        status: ContractStatus,
+        reaction: Entrypoint,
+    ) -> Result<bool> {
+        let status = status as i32;
+        let rows_affected = sqlx::query!(
+            "UPDATE customer_channels SET last_reaction = ?
+            WHERE label = ? AND last_observed_status = ? AND last_reaction IS NULL",
+            reaction,
+            channel_name,
+            status,
+        )
+        .execute(self)
+        .await?
+        .rows_affected();
+
+        Ok(rows_affected == 1)
+    }
+
+    async fn record_required_action(
+        &self,
+        channel_name: &ChannelName,
+        status: ContractStatus,
+        action: Entrypoint,
+    ) -> Result<bool> {
+        let status = status as i32;
+        let rows_affected = sqlx::query!(
+            "UPDATE customer_channels SET required_action = ?
+            WHERE label = ? AND last_observed_status = ?",
+            action,
+            channel_name,
+            status,
+        )
+        .execute(self)
+        .await?
+        .rows_affected();
+
+        Ok(rows_affected == 1)
+    }
+
+    async fn contract_observation(
+        &self,
+        channel_name: &ChannelName,
+    ) -> Result<Option<ContractObservation>> {
+        let r = sqlx::query!(
+            r#"
+            SELECT
+                last_observed_status AS "last_observed_status: i32",
+                last_observed_level AS "last_observed_level: u32",
+                last_observed_at AS "last_observed_at: i64",
+                last_reaction AS "last_reaction: Entrypoint",
+                required_action AS "required_action: Entrypoint"
+            FROM customer_channels
+            WHERE label = ?
+            "#,
+            channel_name,
+        )
+        .fetch_optional(self)
+        .await?
+        .ok_or_else(|| Error::NoSuchChannel(channel_name.clone()))?;
+
+        match (
+            r.last_observed_status,
+            r.last_observed_level,
+            r.last_observed_at,
+        ) {
+            (Some(status), Some(level), Some(observed_at)) => Ok(Some(ContractObservation {
+                status: ContractStatus::try_from(status)
+                    .map_err(|_| Error::InvalidContractObservation(channel_name.clone()))?,
+                level,
+                observed_at: UNIX_EPOCH + Duration::from_secs(observed_at as u64),
+                reaction: r.last_reaction,
+                required_action: r.required_action,
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    async fn with_channel_state_erased<'a>(
+        &'a self,
+        channel_name: &ChannelName,
+        payment_id: Option<i64>,
+        with_state: Box<
+            dyn for<'s> FnOnce(
+                    State,
+                ) -> std::result::Result<
+                    (State, Box<dyn Any + Send>),
+                    Box<dyn Any + Send>,
+                > + Send
+                + 'a,
+        >,
+    ) -> Result<std::result::Result<Box<dyn Any>, Box<dyn Any>>> {
+        let mut transaction = begin_write(self).await?;
+
+        // Retrieve the state so that we can modify it
+        let channel = sqlx::query!(
+            r#"SELECT id, state AS "state: State" FROM customer_channels WHERE label = ?"#,
+            channel_name,
+        )
+        .fetch(&mut transaction)
+        .next()
+        .await
+        .ok_or_else(|| Error::NoSuchChannel(channel_name.clone()))??;
+        let old_state = channel.state.state_name();
+
+        // Perform the operation with the state fetched from the database
+        match with_state(channel.state) {
+            Ok((state, output)) => {
+                // Store the new state to the database
+                sqlx::query!(
+                    "UPDATE customer_channels
+                    SET state = ?, state_updated_at = strftime('%s', 'now')
+                    WHERE label = ?",
+                    state,
+                    channel_name
+                )
+                .execute(&mut transaction)
+                .await?;
+
+                // Record the change if the channel moved to a different kind of state
+                let new_state = state.state_name();
+                if new_state != old_state {
+                    sqlx::query!(
+                        "INSERT INTO channel_events (channel_id, old_state, new_state, recorded_at)
+                        VALUES (?, ?, ?, strftime('%s', 'now'))",
+                        channel.id,
+                        old_state,
+                        new_state,
+                    )
+                    .execute(&mut transaction)
+                    .await?;
+                }
+
+                // Complete the payment this change completes, if any
+                if let Some(payment_id) = payment_id {
+                    let customer_balance = state.customer_balance().into_inner() as i64;
+                    let merchant_balance = state.merchant_balance().into_inner() as i64;
+                    let rows_affected = sqlx::query!(
+                        "UPDATE payments
+                        SET
+                            outcome = 'completed',
+                            customer_balance = ?,
+                            merchant_balance = ?,
+                            paid_at = strftime('%s', 'now')
+                        WHERE id = ? AND channel_id = ? AND outcome = 'pending'",
+                        customer_balance,
+                        merchant_balance,
+                        payment_id,
+                        channel.id,
+                    )
+                    .execute(&mut transaction)
+                    .await?
+                    .rows_affected();
+                    if rows_affected == 0 {
+                        return Err(Error::PaymentNotPending(payment_id));
+                    }
+                }
+
+                // Commit the transaction
+                transaction.commit().await?;
+
+                Ok(Ok(output))
+            }
+            Err(error) => Ok(Err(error)),
+        }
+    }
+}
+
+/// Mutate a channel's state as [`QueryCustomerExt::with_channel_state`] does, completing the
+/// pending payment `payment_id` (if any) along with the new state.
+async fn with_expected_state<
+    'a,
+    Q: QueryCustomer + ?Sized,
+    S: ZkChannelState + Send + 'static,
+    F: FnOnce(S::ZkAbacusState) -> std::result::Result<(State, T), E> + Send + 'a,
+    T: Send + 'static,
+    E: Send + 'static,
+>(
+    database: &'a Q,
+    channel_name: &ChannelName,
+    _expected_state: S,
+    payment_id: Option<i64>,
+    with_zkabacus_state: F,
+) -> Result<std::result::Result<T, E>> {
+    let result = database
+        .with_channel_state_erased(
+            channel_name,
+            payment_id,
+            Box::new(
+                // Extract the inner zkAbacus type from the state enum and make sure it matches
+                |state| match S::zkabacus_state(state) {
+                    Ok(zkabacus_state) => match with_zkabacus_state(zkabacus_state) {
+                        Ok((state, t)) => Ok((state, Box::new(t))),
+                        Err(e) => Err(Box::new(Ok::<E, UnexpectedState>(e))),
+                    },
+                    Err(unexpected_state) => {
+                        Err(Box::new(Err::<E, UnexpectedState>(unexpected_state)))
+                    }
+                },
+            ),
+        )
+        .await?;
+
+    // Cast the result back to its true type
+    match result {
+        // Successful result
+        Ok(t) => {
+            let t: T = *t.downcast().unwrap();
+            Ok(Ok(t))
+        }
+        // Error, which could be one of...
+        Err(error_result) => {
+            let error_result: std::result::Result<E, UnexpectedState> =
+                *error_result.downcast().unwrap();
+            match error_result {
+                // Error returned by the closure
+                Ok(e) => Ok(Err(e)),
+                // Error returned because the state didn't match the one in the database.
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+}
+
+// Blanket implementation of [`QueryCustomerExt`] for all [`QueryCustomer`]
+#[async_trait]
+impl<Q: QueryCustomer + ?Sized> QueryCustomerExt for Q {
+    async fn with_channel_state<
+        'a,
+        S: ZkChannelState + Send + 'static,
+        F: FnOnce(S::ZkAbacusState) -> std::result::Result<(State, T), E> + Send + 'a,
+        T: Send + 'static,
+        E: Send + 'static,
+    >(
+        &'a self,
+        channel_name: &ChannelName,
+        expected_state: S,
+        with_zkabacus_state: F,
+    ) -> Result<std::result::Result<T, E>> {
+        with_expected_state(
+            self,
+            channel_name,
+            expected_state,
+            None,
+            with_zkabacus_state,
+        )
+        .await
+    }
+
+    async fn with_channel_state_paid<
+        'a,
+        S: ZkChannelState + Send + 'static,
+        F: FnOnce(S::ZkAbacusState) -> std::result::Result<(State, T), E> + Send + 'a,
+        T: Send + 'static,
+        E: Send + 'static,
+    >(
+        &'a self,
+        channel_name: &ChannelName,
+        expected_state: S,
+        payment_id: i64,
+        with_zkabacus_state: F,
+    ) -> Result<std::result::Result<T, E>> {
+        with_expected_state(
+            self,
+            channel_name,
+            expected_state,
+            Some(payment_id),
+            with_zkabacus_state,
+        )
+        .await
+    }
+
+    async fn with_closeable_channel<'a, E: Send + 'static>(
+        &self,
+        channel_name: &ChannelName,
+        with_closeable_state: impl FnOnce(State) -> std::result::Result<(State, ClosingMessage), E>
+            + Send
+            + 'a,
+    ) -> Result<std::result::Result<ClosingMessage, E>> {
+        let result = <Self as QueryCustomer>::with_channel_state_erased(
+            self,
+            channel_name,
+            None,
+            Box::new(|state| match with_closeable_state(state) {
+                Ok((state, t)) => {
+                    // Only allow updates that result in the PendingClose status.
+                    if let State::PendingClose(_) = state {
+                        Ok((state, Box::new(t)))
+                    } else {
+                        Err(Box::new(Err::<E, Error>(Error::CloseFailure)))
+                    }
+                }
+                // Closure function failed somehow
+                Err(e) => Err(Box::new(Ok::<E, Error>(e))),
+            }),
+        )
+        .await?;
+
+        // Cast the result back to its true type
+        match result {
+            // Successful result: get the `ClosingMessage` out of the box.
+            Ok(t) => Ok(Ok(*t.downcast().unwrap())),
+            // Error, which could be one of...
+            Err(error_result) => {
+                let err: Result<E> = *error_result.downcast().unwrap();
+                match err {
+                    // Error returned by the closure
+                    Ok(e) => Ok(Err(e)),
+                    // Error returned because the closure didn't return a PendingClose status.
+                    Err(e) => Err(e),
+                }
+            }
+        }
+    }
+
+    async fn react_once<
+        'a,
+        F: FnOnce() -> Fut + Send + 'a,
+        Fut: Future<Output = std::result::Result<T, E>> + Send + 'a,
+        T: Send + 'static,
+        E: Send + 'static,
+    >(
+        &'a self,
+        channel_name: &ChannelName,
+        observation: &ContractObservation,
+        reaction: Entrypoint,
+        react: F,
+    ) -> Result<std::result::Result<Option<T>, E>> {
+        if observation.reaction.is_some() {
+            return Ok(Ok(None));
+        }
+
+        let output = match react().await {
+            Ok(output) => output,
+            Err(e) => return Ok(Err(e)),
+        };
+        self.record_reaction(channel_name, observation.status, reaction)
+            .await?;
+        Ok(Ok(Some(output)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{PgPool, SqlitePoolOptions};
+    use {
+        rand::{rngs::StdRng, SeedableRng},
+        std::str::FromStr,
+    };
+
+    use tezedge::OriginatedAddress;
+    use zkabacus_crypto::{customer::*, merchant, *};
+
+    async fn create_migrated_db() -> Result<SqlitePool> {
+        let conn = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        conn.migrate().await?;
+        Ok(conn)
+    }
+
+    /// Create and migrate a fresh Postgres database on the server given by
+    /// `ZEEKOE_TEST_POSTGRES_URL`, whose user must be able to create databases, or `None` if the
+    /// variable isn't set.
+    async fn create_migrated_postgres_db() -> Result<Option<PgPool>> {
+        let url = match std::env::var("ZEEKOE_TEST_POSTGRES_URL") {
+            Ok(url) => url,
+            Err(_) => return Ok(None),
+        };
+
+        // Each test gets a database of its own
+        let name = format!("zeekoe_test_{}", uuid::Uuid::new_v4().to_simple());
+        let server = PgPool::connect(&url).await?;
+        sqlx::query(&format!("CREATE DATABASE {}", name))
+            .execute(&server)
+            .await?;
+        server.close().await;
+
+        let mut url = url::Url::parse(&url).expect("invalid ZEEKOE_TEST_POSTGRES_URL");
+        url.set_path(&name);
+        let conn = PgPool::connect(url.as_str()).await?;
+        conn.migrate().await?;
+        Ok(Some(conn))
+    }
+
+    /// Run each of the given tests against SQLite, and against Postgres if
+    /// `ZEEKOE_TEST_POSTGRES_URL` is set.
+    macro_rules! backend_tests {
+        ($($test:ident),* $(,)?) => {
+            mod sqlite {
+                $(
+                    #[tokio::test(flavor = "multi_thread")]
+                    async fn $test() -> super::Result<()> {
+                        super::$test(&super::create_migrated_db().await?).await
+                    }
+                )*
+            }
+
+            mod postgres {
+                $(
+                    #[tokio::test(flavor = "multi_thread")]
+                    async fn $test() -> super::Result<()> {
+                        match super::create_migrated_postgres_db().await? {
+                            Some(conn) => super::$test(&conn).await,
+                            None => Ok(()),
+                        }
+                    }
+                )*
+            }
+        };
+    }
+
+    backend_tests!(
+        test_migrate,
+        insert_customer_channel,
+        labels_are_unique,
+        invalid_labels_are_rejected,
+        missing_channel_is_reported,
+        wrong_state_is_rejected,
+        find_channel_by_id,
+        find_channels_by_address,
+        insert_contract_details,
+        mark_awaiting_broadcast,
+        disputed_close_pays_merchant_everything,
+        closing_balances_set_once,
+        closing_balances_need_closing_channel,
+        stalled_establishment_reclaimed,
+        operation_in_flight_posts_once,
+        operation_in_flight_across_processes,
+        pin_merchant_key_hash,
+        same_observation_reacts_once,
+        watch_only_records_required_action,
+        channel_history_records_payment_cycle,
+        payment_recorded_with_state,
+        payment_outcomes_are_recorded,
+        interrupted_payment_can_be_resumed,
+        archive_closed_channel,
+        archive_needs_closed_channel,
+        purge_reclaimed_channel,
+    );
+
+    async fn test_migrate(conn: &dyn QueryCustomer) -> Result<()> {
+        // Migrating an already migrated database does nothing
+        conn.migrate().await?;
+        Ok(())
+    }
+
+    async fn insert_channel(channel_name: &ChannelName, conn: &dyn QueryCustomer) -> Result<()> {
+        establish_channel(channel_name, conn).await?;
+        Ok(())
+    }
+
+    /// Insert a new inactive channel, returning the merchant's config and the blinded state with
+    /// which the merchant can activate it.
+    async fn establish_channel(
+        channel_name: &ChannelName,
+        conn: &dyn QueryCustomer,
+    ) -> Result<(merchant::Config, VerifiedBlindedState)> {
+        let address = ZkChannelAddress::from_str("zkchannel://localhost").unwrap();
+        establish_channel_at(channel_name, &address, conn).await
+    }
+
+    /// Insert a new inactive channel with the merchant at the given address.
+    async fn establish_channel_at(
+        channel_name: &ChannelName,
+        address: &ZkChannelAddress,
+        conn: &dyn QueryCustomer,
+    ) -> Result<(merchant::Config, VerifiedBlindedState)> {
+        // set up zkchannel details
+        let mut rng = StdRng::from_entropy();
+
+        // set up keys
+        let merchant_config = merchant::Config::new(&mut rng);
+        let (pk, rev_param, range_param) = merchant_config.extract_customer_config_parts();
+        let zkabacus_config = Config::from_parts(pk, rev_param, range_param);
+
+        // build a channel id
+        let cid_m = MerchantRandomness::new(&mut rng);
+        let cid_c = CustomerRandomness::new(&mut rng);
+        let channel_id = ChannelId::new(
+            cid_m,
+            cid_c,
+            zkabacus_config.merchant_public_key(),
+            &[],
+            &[],
+        );
+
+        // set up deposit info
+        let merchant_balance = MerchantBalance::try_new(5).unwrap();
+        let customer_balance = CustomerBalance::try_new(5).unwrap();
+        let context = Context::new(b"here is some fake context");
+
+        // simulate establish to get zkabacus objects
+        let (requested, proof) = Requested::new(
+            &mut rng,
+            &zkabacus_config,
+            channel_id,
+            merchant_balance,
+            customer_balance,
+            &context,
+        );
+
+        let (closing_signature, blinded_state) = merchant_config
+            .initialize(
+                &mut rng,
+                &channel_id,
+                customer_balance,
+                merchant_balance,
+                proof,
+                &context,
+            )
+            .unwrap();
+
+        let inactive = requested
+            .complete(closing_signature, &zkabacus_config)
+            .unwrap();
+        let contract_details = ContractDetails {
+            merchant_tezos_public_key: TezosPublicKey::from_base58check(
+                "edpku5Ei6Dni4qwoJGqXJs13xHfyu4fhUg6zqZkFyiEh1mQhFD3iZE",
+            )
+            .unwrap(),
+            contract_id: None,
+            contract_level: None,
+        };
+
+        conn.new_channel(
+            channel_name,
+            address,
+            inactive,
+            &contract_details,
+            &zkabacus_config,
+        )
+        .await
+        .map_err(|(_, e)| e)?;
+
+        Ok((merchant_config, blinded_state))
+    }
+
+    async fn insert_customer_channel(conn: &dyn QueryCustomer) -> Result<()> {
+        let channel_name = ChannelName::new("test channel".to_string());
+        insert_channel(&channel_name, conn).await?;
+        Ok(())
+    }
+
+    async fn labels_are_unique(conn: &dyn QueryCustomer) -> Result<()> {
+        let first = ChannelName::new("first channel".to_string());
+        let second = ChannelName::new("second channel".to_string());
+        insert_channel(&first, conn).await?;
+        insert_channel(&second, conn).await?;
+
+        // A label can't be reused, whether by a new channel or by renaming another one
+        assert!(matches!(
+            insert_channel(&first, conn).await,
+            Err(Error::ChannelExists(name)) if name == first
+        ));
+        assert!(matches!(
+            conn.rename_channel(&second, &first).await,
+            Err(Error::ChannelExists(name)) if name == first
+        ));
+        let unknown = ChannelName::new("no such channel".to_string());
+        assert!(matches!(
+            conn.rename_channel(&unknown, &ChannelName::new("renamed".to_string()))
+                .await,
+            Err(Error::NoSuchChannel(name)) if name == unknown
+        ));
+
+        // Of two channels established at once under the same label, only one is stored
+        let third = ChannelName::new("third channel".to_string());
+        let (a, b) = tokio::join!(insert_channel(&third, conn), insert_channel(&third, conn));
+        assert!(matches!(
+            (a, b),
+            (Ok(()), Err(Error::ChannelExists(_))) | (Err(Error::ChannelExists(_)), Ok(()))
+        ));
+
+        // Renaming frees the old label
+        conn.rename_channel(&third, &ChannelName::new("renamed".to_string()))
+            .await?;
+        insert_channel(&third, conn).await?;
+        assert_eq!(conn.get_channels().await?.len(), 4);
+
+        Ok(())
+    }
+
+    async fn invalid_labels_are_rejected(conn: &dyn QueryCustomer) -> Result<()> {
+        let channel_name = ChannelName::new("valid channel".to_string());
+        insert_channel(&channel_name, conn).await?;
+
+        // Neither a new channel nor a renamed one can have an invalid label
+        assert!(matches!(
+            insert_channel(&ChannelName::new(String::new()), conn).await,
+            Err(Error::InvalidChannelName(InvalidChannelName::Empty))
+        ));
+        assert!(matches!(
+            insert_channel(&ChannelName::new("tab\there".to_string()), conn).await,
+            Err(Error::InvalidChannelName(
+                InvalidChannelName::ControlCharacter('\t')
+            ))
+        ));
+        let too_long = ChannelName::new("x".repeat(crate::customer::MAX_CHANNEL_NAME_LENGTH + 1));
+        assert!(matches!(
+            conn.rename_channel(&channel_name, &too_long).await,
+            Err(Error::InvalidChannelName(InvalidChannelName::TooLong(_)))
+        ));
+        assert_eq!(conn.get_channels().await?.len(), 1);
+
+        // A label suffixed because it was taken can be stored, though it can't be chosen
+        let suffixed = channel_name.suffixed(1);
+        conn.rename_channel(&channel_name, &suffixed).await?;
+        insert_channel(&channel_name, conn).await?;
+
+        Ok(())
+    }
+
+    /// Assert that an operation failed because there is no channel by the given name.
+    fn assert_no_such_channel<T>(result: Result<T>, label: &ChannelName) {
+        match result {
+            Err(Error::NoSuchChannel(name)) => assert_eq!(&name, label),
+            Err(e) => panic!("expected no channel named {}, but got: {}", label, e),
+            Ok(_) => panic!("expected no channel named {}, but succeeded", label),
+        }
+    }
+
+    async fn missing_channel_is_reported(conn: &dyn QueryCustomer) -> Result<()> {
+        let missing = ChannelName::new("no such channel".to_string());
+        insert_channel(&ChannelName::new("some channel".to_string()), conn).await?;
+
+        // The steps of a payment
+        assert_no_such_channel(conn.channel_zkabacus_config(&missing).await, &missing);
+        assert_no_such_channel(
+            conn.with_channel_state(&missing, zkchannels_state::Ready, |_| {
+                Err::<(State, ()), ()>(())
+            })
+            .await,
+            &missing,
+        );
+        assert_no_such_channel(
+            conn.with_channel_state(&missing, zkchannels_state::Started, |_| {
+                Err::<(State, ()), ()>(())
+            })
+            .await,
+            &missing,
+        );
+        assert_no_such_channel(
+            conn.with_channel_state(&missing, zkchannels_state::Locked, |_| {
+                Err::<(State, ()), ()>(())
+            })
+            .await,
+            &missing,
+        );
+
+        // Activating a channel, and closing it
+        assert_no_such_channel(
+            conn.with_channel_state(&missing, zkchannels_state::MerchantFunded, |_| {
+                Err::<(State, ()), ()>(())
+            })
+            .await,
+            &missing,
+        );
+        assert_no_such_channel(
+            conn.with_closeable_channel(&missing, |_| Err::<(State, ClosingMessage), ()>(()))
+                .await,
+            &missing,
+        );
+
+        // Everything else that looks a channel up by name
+        assert_no_such_channel(conn.channel_address(&missing).await, &missing);
+        assert_no_such_channel(conn.closing_balances(&missing).await, &missing);
+        assert_no_such_channel(conn.contract_details(&missing).await, &missing);
+        assert_no_such_channel(conn.get_channel(&missing).await, &missing);
+        assert_no_such_channel(conn.contract_observation(&missing).await, &missing);
+        assert_no_such_channel(
+            conn.update_closing_balances(
+                &missing,
+                ClosingPath::Unilateral,
+                MerchantBalance::try_new(5).unwrap(),
+                None,
+            )
+            .await,
+            &missing,
+        );
+        assert_no_such_channel(
+            conn.begin_operation(&missing, Entrypoint::CustomerClose, Duration::from_secs(60))
+                .await,
+            &missing,
+        );
+        assert_no_such_channel(
+            conn.observe_contract(&missing, ContractStatus::Expiry, 100)
+                .await,
+            &missing,
+        );
+
+        Ok(())
+    }
+
+    async fn find_channel_by_id(conn: &dyn QueryCustomer) -> Result<()> {
+        let first = ChannelName::new("first channel".to_string());
+        let second = ChannelName::new("second channel".to_string());
+        insert_channel(&first, conn).await?;
+        insert_channel(&second, conn).await?;
+        let first_id = *conn.get_channel(&first).await?.state.channel_id();
+        let second_id = *conn.get_channel(&second).await?.state.channel_id();
+
+        // Each channel is found by its whole ID
+        assert_eq!(
+            conn.channel_label_for_id(&first_id).await?,
+            Some(first.clone())
+        );
+        assert_eq!(conn.get_channel_by_id(&second_id).await?.label, second);
+        let (first_id, second_id) = (first_id.to_string(), second_id.to_string());
+        assert_eq!(conn.channel_label_for_prefix(&first_id).await?, first);
+        assert_eq!(
+            conn.channel_label_for_prefix(&second_id[..8]).await?,
+            second
+        );
+
+        // A prefix that both IDs start with is ambiguous
+        let common_length = first_id
+            .chars()
+            .zip(second_id.chars())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let common = &first_id[..common_length];
+        assert!(matches!(
+            conn.channel_label_for_prefix(common).await,
+            Err(Error::ChannelIdCollision(prefix)) if prefix == common
+        ));
+
+        // Prefixes are matched exactly, including their case
+        let swapped: String = first_id
+            .chars()
+            .map(|c| {
+                if c.is_ascii_uppercase() {
+                    c.to_ascii_lowercase()
+                } else {
+                    c.to_ascii_uppercase()
+                }
+            })
+            .collect();
+        for prefix in [swapped.as_str(), "%", "_"] {
+            assert!(matches!(
+                conn.channel_label_for_prefix(prefix).await,
+                Err(Error::ChannelNotFoundWithPrefix(_))
+            ));
+        }
+
+        // A channel which was never stored isn't found
+        let missing = ChannelId::new(
+            MerchantRandomness::new(&mut StdRng::from_entropy()),
+            CustomerRandomness::new(&mut StdRng::from_entropy()),
+            conn.channel_zkabacus_config(&second)
+                .await?
+                .merchant_public_key(),
+            &[],
+            &[],
+        );
+        assert_eq!(conn.channel_label_for_id(&missing).await?, None);
+        assert!(matches!(
+            conn.get_channel_by_id(&missing).await,
+            Err(Error::NoSuchChannelId(id)) if id == missing
+        ));
+
+        Ok(())
+    }
+
+    async fn wrong_state_is_rejected(conn: &dyn QueryCustomer) -> Result<()> {
+        let channel_name = ChannelName::new("inactive channel".to_string());
+        insert_channel(&channel_name, conn).await?;
+
+        // A channel which isn't in the expected state isn't given to the closure
+        let mut ran = false;
+        let result = conn
+            .with_channel_state(
+                &channel_name,
+                zkchannels_state::Ready,
+                |ready| -> std::result::Result<_, ()> {
+                    ran = true;
+                    Ok((State::Ready(ready), ()))
+                },
+            )
+            .await;
+        assert!(matches!(result, Err(Error::UnexpectedState(_))));
+        assert!(!ran);
+
+        // ...and is left as it was
+        let channel = conn.get_channel(&channel_name).await?;
+        assert_eq!(channel.state.state_name(), StateName::Inactive);
+        assert!(conn.channel_history(&channel_name).await?.is_empty());
+
+        // The same goes for a closure which fails
+        let result = conn
+            .with_channel_state(&channel_name, zkchannels_state::Inactive, |_| {
+                Err::<(State, ()), _>("failed")
+            })
+            .await?;
+        assert_eq!(result, Err("failed"));
+        let channel = conn.get_channel(&channel_name).await?;
+        assert_eq!(channel.state.state_name(), StateName::Inactive);
+        assert!(conn.channel_history(&channel_name).await?.is_empty());
+
+        Ok(())
+    }
+
+    async fn insert_contract_details(conn: &dyn QueryCustomer) -> Result<()> {
+        let channel_name = ChannelName::new("test contract details channel".to_string());
+        insert_channel(&channel_name, conn).await?;
+
+        // make sure contract details are not set initially
+        if conn
+            .contract_details(&channel_name)
+            .await?
+            .contract_id
+            .is_some()
+        {
+            panic!("Contract details should not be set yet.")
+        }
+
+        // pick contract details
+        let contract_id = ContractId::new(
+            OriginatedAddress::from_base58check("KT1Mjjcb6tmSsLm7Cb3DSQszePjfchPM4Uxm").unwrap(),
+        );
+
+        // set contract details
+        conn.set_contract_id(&channel_name, &contract_id, 1234)
+            .await?;
+
+        // make sure saved details match expected values
+        let details = conn.contract_details(&channel_name).await?;
+        match details.contract_id {
+            Some(saved_id) => {
+                assert!(saved_id == contract_id)
+            }
+            None => panic!("Contract details did not get set when they should"),
+        }
+        assert_eq!(details.contract_level, Some(1234));
+        assert_eq!(
+            conn.get_channel(&channel_name)
+                .await?
+                .contract_details
+                .contract_level,
+            Some(1234)
+        );
+
+        // make sure we cannot overwrite saved contact details
+        match conn
+            .set_contract_id(&channel_name, &contract_id, 1235)
+            .await
+        {
+            Ok(()) => panic!("Allowed overwrite of contract details"),
+            Err(super::Error::ContractDetailsExist(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn mark_awaiting_broadcast(conn: &dyn QueryCustomer) -> Result<()> {
+        let channel_name = ChannelName::new("off chain close channel".to_string());
+        insert_channel(&channel_name, conn).await?;
+
+        assert!(!conn.get_channel(&channel_name).await?.awaiting_broadcast);
+
+        conn.set_awaiting_broadcast(&channel_name, true).await?;
+        assert!(conn.get_channel(&channel_name).await?.awaiting_broadcast);
+        assert!(conn.get_channels().await?[0].awaiting_broadcast);
+
+        conn.set_awaiting_broadcast(&channel_name, false).await?;
+        assert!(!conn.get_channel(&channel_name).await?.awaiting_broadcast);
+
+        // Marking a channel that doesn't exist fails
+        let unknown = ChannelName::new("no such channel".to_string());
+        assert!(matches!(
+            conn.set_awaiting_broadcast(&unknown, true).await,
+            Err(Error::NoSuchChannel(_))
+        ));
+
+        Ok(())
+    }
+
+    async fn disputed_close_pays_merchant_everything(conn: &dyn QueryCustomer) -> Result<()> {
+        use crate::{customer::finalize, protocol::close::CustomerCloseEnding};
+
+        // The customer posts custClose, which pays out the merchant balance at once
+        let post_cust_close = |channel_name: ChannelName| async move {
+            insert_channel(&channel_name, conn).await?;
+            let closing_message = conn
+                .with_closeable_channel(&channel_name, |state| match state {
+                    State::Inactive(inactive) => {
+                        let closing_message = inactive.close(&mut StdRng::from_entropy());
+                        Ok((
+                            State::PendingClose(closing_message.clone()),
+                            closing_message,
+                        ))
+                    }
+                    _ => Err(()),
+                })
+                .await?
+                .unwrap();
+            conn.update_closing_balances(
+                &channel_name,
+                ClosingPath::Unilateral,
+                *closing_message.merchant_balance(),
+                None,
+            )
+            .await?;
+            Ok::<_, Error>(channel_name)
+        };
+        let assert_merchant_paid_everything = |channel_name: ChannelName| async move {
+            let channel = conn.get_channel(&channel_name).await?;
+            assert_eq!(channel.state.state_name(), StateName::Closed);
+            assert_eq!(channel.closing_path, Some(ClosingPath::Dispute));
+            let closing_balances = conn.closing_balances(&channel_name).await?;
+            assert_eq!(
+                closing_balances.merchant_balance.map(|b| b.into_inner()),
+                Some(10)
+            );
+            assert_eq!(
+                closing_balances.customer_balance.map(|b| b.into_inner()),
+                Some(0)
+            );
+            Ok::<_, Error>(())
+        };
+
+        // The merchant disputes the posted balances, and the watcher sees the dispute confirmed
+        let channel_name =
+            post_cust_close(ChannelName::new("disputed channel".to_string())).await?;
+        finalize::process_dispute(conn, &channel_name)
+            .await
+            .unwrap();
+        assert_eq!(
+            conn.get_channel(&channel_name).await?.state.state_name(),
+            StateName::Dispute
+        );
+        finalize::finalize_dispute(conn, &channel_name)
+            .await
+            .unwrap();
+        assert_merchant_paid_everything(channel_name).await?;
+
+        // The watcher only sees the contract closed by the dispute, as when it was stopped
+        let channel_name =
+            post_cust_close(ChannelName::new("closed by dispute".to_string())).await?;
+        finalize::finalize_closed_customer_close(
+            conn,
+            &channel_name,
+            CustomerCloseEnding::Disputed,
+        )
+        .await
+        .unwrap();
+        assert_merchant_paid_everything(channel_name).await?;
+
+        Ok(())
+    }
+
+    async fn closing_balances_set_once(conn: &dyn QueryCustomer) -> Result<()> {
+        let channel_name = ChannelName::new("mutually closed channel".to_string());
+        insert_channel(&channel_name, conn).await?;
+        let mut rng = StdRng::from_entropy();
+
+        let closing_message = conn
+            .with_closeable_channel(&channel_name, |state| match state {
+                State::Inactive(inactive) => {
+                    let closing_message = inactive.close(&mut rng);
+                    Ok((
+                        State::PendingMutualClose(closing_message.clone()),
+                        closing_message,
+                    ))
+                }
+                _ => Err(()),
+            })
+            .await?
+            .unwrap();
+        let (customer_balance, merchant_balance) = (
+            *closing_message.customer_balance(),
+            *closing_message.merchant_balance(),
+        );
+        conn.update_closing_balances(
+            &channel_name,
+            ClosingPath::Mutual,
+            merchant_balance,
+            Some(customer_balance),
+        )
+        .await?;
+
+        // Finalizing the close again is refused, whatever the path
+        for path in [ClosingPath::Mutual, ClosingPath::Expiry] {
+            assert!(matches!(
+                conn.update_closing_balances(
+                    &channel_name,
+                    path,
+                    merchant_balance,
+                    Some(customer_balance),
+                )
+                .await,
+                Err(Error::InvalidBalanceUpdate(..))
+            ));
+        }
+
+        // As is decreasing the merchant balance
+        assert!(matches!(
+            conn.update_closing_balances(
+                &channel_name,
+                ClosingPath::Unilateral,
+                MerchantBalance::try_new(merchant_balance.into_inner() - 1).unwrap(),
+                None,
+            )
+            .await,
+            Err(Error::InvalidBalanceUpdate(..))
+        ));
+
+        // The balances and path from the first close are kept
+        let channel = conn.get_channel(&channel_name).await?;
+        assert_eq!(channel.closing_path, Some(ClosingPath::Mutual));
+        assert_eq!(
+            channel
+                .closing_balances
+                .merchant_balance
+                .map(|b| b.into_inner()),
+            Some(merchant_balance.into_inner())
+        );
+        assert_eq!(
+            channel
+                .closing_balances
+                .customer_balance
+                .map(|b| b.into_inner()),
+            Some(customer_balance.into_inner())
+        );
+
+        Ok(())
+    }
+
+    async fn closing_balances_need_closing_channel(conn: &dyn QueryCustomer) -> Result<()> {
+        let channel_name = ChannelName::new("open channel".to_string());
+        insert_channel(&channel_name, conn).await?;
+
+        match conn
+            .update_closing_balances(
+                &channel_name,
+                ClosingPath::Unilateral,
+                MerchantBalance::try_new(5).unwrap(),
+                None,
+            )
+            .await
+        {
+            Err(Error::NotClosing(name, StateName::Inactive)) => assert_eq!(name, channel_name),
+            Err(e) => panic!("expected the channel not to be closing, but got: {}", e),
+            Ok(()) => panic!("expected the channel not to be closing, but succeeded"),
+        }
+
+        let channel = conn.get_channel(&channel_name).await?;
+        assert_eq!(channel.closing_path, None);
+        assert!(channel.closing_balances.merchant_balance.is_none());
+        assert!(channel.closing_balances.customer_balance.is_none());
+
+        Ok(())
+    }
+
+    async fn find_channels_by_address(conn: &dyn QueryCustomer) -> Result<()> {
+        let address = |s: &str| ZkChannelAddress::from_str(s).unwrap();
+        let only = address("zkchannel://only.example.com");
+        let shared = address("zkchannel://shared.example.com");
+        let only_channel = ChannelName::new("only channel".to_string());
+        let first_shared = ChannelName::new("first shared channel".to_string());
+        let second_shared = ChannelName::new("second shared channel".to_string());
+        establish_channel_at(&only_channel, &only, conn).await?;
+        establish_channel_at(&second_shared, &shared, conn).await?;
+        establish_channel_at(&first_shared, &shared, conn).await?;
+
+        // No channel, one channel, or several channels can be with the merchant at an address
+        assert!(conn
+            .channels_for_address(&address("zkchannel://nobody.example.com"))
+            .await?
+            .is_empty());
+        assert_eq!(conn.channels_for_address(&only).await?, [only_channel]);
+        assert_eq!(
+            conn.channels_for_address(&shared).await?,
+            [first_shared.clone(), second_shared.clone()]
+        );
+
+        // A closed channel no longer counts
+        let mut rng = StdRng::from_entropy();
+        conn.with_closeable_channel(&second_shared, |state| match state {
+            State::Inactive(inactive) => {
+                Ok((State::PendingMutualClose(inactive.close(&mut rng)), ()))
+            }
+            _ => Err(()),
+        })
+        .await?
+        .unwrap();
+        conn.with_channel_state(
+            &second_shared,
+            zkchannels_state::PendingMutualClose,
+            |closing_message| -> std::result::Result<_, ()> {
+                Ok((State::Closed(closing_message), ()))
+            },
+        )
+        .await?
+        .unwrap();
+        assert_eq!(conn.channels_for_address(&shared).await?, [first_shared]);
+
+        Ok(())
+    }
+
+    async fn archive_closed_channel(conn: &dyn QueryCustomer) -> Result<()> {
+        let channel_name = ChannelName::new("closed channel".to_string());
+        insert_channel(&channel_name, conn).await?;
+        let other_channel_name = ChannelName::new("open channel".to_string());
+        insert_channel(&other_channel_name, conn).await?;
+        let mut rng = StdRng::from_entropy();
+
+        let closing_message = conn
+            .with_closeable_channel(&channel_name, |state| match state {
+                State::Inactive(inactive) => {
+                    let closing_message = inactive.close(&mut rng);
+                    Ok((
+                        State::PendingMutualClose(closing_message.clone()),
+                        closing_message,
+                    ))
+                }
+                _ => Err(()),
+            })
+            .await?
+            .unwrap();
+        conn.with_channel_state(
+            &channel_name,
+            zkchannels_state::PendingMutualClose,
+            |closing_message| -> std::result::Result<_, ()> {
+                Ok((State::Closed(closing_message), ()))
+            },
+        )
+        .await?
+        .unwrap();
+        conn.update_closing_balances(
+            &channel_name,
+            ClosingPath::Mutual,
+            *closing_message.merchant_balance(),
+            Some(*closing_message.customer_balance()),
+        )
+        .await?;
+        let closed = conn.get_channel(&channel_name).await?;
+
+        conn.archive_channel(&channel_name).await?;
+
+        // The daemon no longer sees the channel, but the other channel is untouched
+        let channels = conn.get_channels().await?;
+        assert_eq!(channels.len(), 1);
+        assert_eq!(channels[0].label, other_channel_name);
+        assert_no_such_channel(conn.get_channel(&channel_name).await, &channel_name);
+
+        // Its final balances are kept
+        let archived = conn.get_archived_channels().await?;
+        assert_eq!(archived.len(), 1);
+        assert_eq!(archived[0].label, channel_name);
+        assert_eq!(archived[0].final_state, StateName::Closed);
+        assert_eq!(archived[0].closing_path, Some(ClosingPath::Mutual));
+        assert_eq!(
+            archived[0]
+                .closing_balances
+                .merchant_balance
+                .map(|b| b.into_inner()),
+            Some(closing_message.merchant_balance().into_inner())
+        );
+        assert_eq!(
+            archived[0]
+                .closing_balances
+                .customer_balance
+                .map(|b| b.into_inner()),
+            Some(closing_message.customer_balance().into_inner())
+        );
+        assert_eq!(
+            archived[0].customer_deposit.into_inner(),
+            closed.customer_deposit.into_inner()
+        );
+
+        // The label is free to be used again
+        insert_channel(&channel_name, conn).await?;
+
+        Ok(())
+    }
+
+    async fn archive_needs_closed_channel(conn: &dyn QueryCustomer) -> Result<()> {
+        let channel_name = ChannelName::new("open channel".to_string());
+        insert_channel(&channel_name, conn).await?;
+
+        for result in [
+            conn.archive_channel(&channel_name).await,
+            conn.purge_channel(&channel_name).await,
+        ] {
+            match result {
+                Err(Error::NotTerminal(name, StateName::Inactive)) => {
+                    assert_eq!(name, channel_name)
+                }
+                Err(e) => panic!("expected the channel not to be closed, but got: {}", e),
+                Ok(()) => panic!("expected the channel not to be closed, but succeeded"),
+            }
+        }
+
+        // The channel is left as it was
+        let channel = conn.get_channel(&channel_name).await?;
+        assert_eq!(channel.state.state_name(), StateName::Inactive);
+        assert!(conn.get_archived_channels().await?.is_empty());
+
+        Ok(())
+    }
+
+    async fn purge_reclaimed_channel(conn: &dyn QueryCustomer) -> Result<()> {
+        let channel_name = ChannelName::new("reclaimed channel".to_string());
+        insert_channel(&channel_name, conn).await?;
+        conn.with_channel_state(
+            &channel_name,
+            zkchannels_state::Inactive,
+            |inactive| -> std::result::Result<_, ()> {
+                Ok((State::FundingReclaimed(inactive), ()))
+            },
+        )
+        .await?
+        .unwrap();
+
+        conn.purge_channel(&channel_name).await?;
+
+        // Nothing is kept of it
+        assert!(conn.get_channels().await?.is_empty());
+        assert_no_such_channel(conn.get_channel(&channel_name).await, &channel_name);
+        assert!(conn.get_archived_channels().await?.is_empty());
+
+        Ok(())
+    }
+
+    async fn stalled_establishment_reclaimed(conn: &dyn QueryCustomer) -> Result<()> {
+        let an_hour = Duration::from_secs(60 * 60);
+
+        // A channel which the customer funded, but the merchant didn't, and one which was never
+        // originated
+        let stalled_channel_name = ChannelName::new("stalled channel".to_string());
+        insert_channel(&stalled_channel_name, conn).await?;
+        conn.with_channel_state(
+            &stalled_channel_name,
+            zkchannels_state::Inactive,
+            |inactive| -> std::result::Result<_, ()> { Ok((State::Originated(inactive), ())) },
+        )
+        .await?
+        .unwrap();
+        conn.with_channel_state(
+            &stalled_channel_name,
+            zkchannels_state::Originated,
+            |inactive| -> std::result::Result<_, ()> { Ok((State::CustomerFunded(inactive), ())) },
+        )
+        .await?
+        .unwrap();
+        insert_channel(&ChannelName::new("inactive channel".to_string()), conn).await?;
+
+        // Nothing has been stuck for an hour yet
+        let stalled = conn
+            .get_stalled_establishments(SystemTime::now() - an_hour)
+            .await?;
+        assert!(stalled.is_empty());
+
+        // Fast-forward the clock by an hour: only the funded channel is stuck
+        let stalled = conn
+            .get_stalled_establishments(SystemTime::now() + an_hour)
+            .await?;
+        assert_eq!(stalled.len(), 1);
+        assert_eq!(stalled[0].label, stalled_channel_name);
+
+        // Once its funding is reclaimed, it isn't stuck any more
+        conn.with_channel_state(
+            &stalled_channel_name,
+            zkchannels_state::CustomerFunded,
+            |inactive| -> std::result::Result<_, ()> {
+                Ok((State::FundingReclaimed(inactive), ()))
+            },
+        )
+        .await?
+        .unwrap();
+        let channel = conn.get_channel(&stalled_channel_name).await?;
+        assert_eq!(channel.state.state_name(), StateName::FundingReclaimed);
+        assert!(conn
+            .get_stalled_establishments(SystemTime::now() + an_hour)
+            .await?
+            .is_empty());
+
+        Ok(())
+    }
+
+    async fn operation_in_flight_posts_once(conn: &dyn QueryCustomer) -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let channel_name = ChannelName::new("in flight channel".to_string());
+        insert_channel(&channel_name, conn).await?;
+
+        // Two concurrent attempts to post custClose, each standing in for a chain call that takes
+        // a while to confirm
+        let chain_calls = AtomicUsize::new(0);
+        let (conn_ref, channel_name_ref, chain_calls_ref) = (conn, &channel_name, &chain_calls);
+        let close = || async move {
+            let guard = OperationGuard::acquire(
+                conn_ref,
+                channel_name_ref,
+                Entrypoint::CustomerClose,
+                Duration::from_secs(60),
+            )
+            .await?;
+            chain_calls_ref.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            guard.release(conn_ref).await
+        };
+        let (first, second) = tokio::join!(close(), close());
+
+        assert_eq!(chain_calls.load(Ordering::SeqCst), 1);
+        assert!(matches!(
+            (first, second),
+            (
+                Ok(()),
+                Err(Error::OperationAlreadyInFlight(Entrypoint::CustomerClose))
+            ) | (
+                Err(Error::OperationAlreadyInFlight(Entrypoint::CustomerClose)),
+                Ok(())
+            )
+        ));
+
+        // Once released, the operation can be posted again, and other entrypoints are unaffected
+        let claim = OperationGuard::acquire(
+            conn,
+            &channel_name,
+            Entrypoint::CustomerClaim,
+            Duration::from_secs(60),
+        )
+        .await?;
+        assert_eq!(
+            conn.operations_in_flight(&channel_name).await?,
+            vec![Entrypoint::CustomerClaim]
+        );
+        OperationGuard::acquire(
+            conn,
+            &channel_name,
+            Entrypoint::CustomerClose,
+            Duration::from_secs(60),
+        )
+        .await?
+        .release(conn)
+        .await?;
+        claim.release(conn).await?;
+        assert!(conn.operations_in_flight(&channel_name).await?.is_empty());
+
+        Ok(())
+    }
+
+    async fn operation_in_flight_across_processes(conn: &dyn QueryCustomer) -> Result<()> {
+        let channel_name = ChannelName::new("other process channel".to_string());
+        insert_channel(&channel_name, conn).await?;
+
+        // Another process has recorded that it's posting custClaim
+        assert!(
+            conn.begin_operation(
+                &channel_name,
+                Entrypoint::CustomerClaim,
+                Duration::from_secs(60)
+            )
+            .await?
+        );
+        assert!(matches!(
+            OperationGuard::acquire(
+                conn,
+                &channel_name,
+                Entrypoint::CustomerClaim,
+                Duration::from_secs(60)
+            )
+            .await,
+            Err(Error::OperationAlreadyInFlight(Entrypoint::CustomerClaim))
+        ));
+
+        // A record that's stale is assumed to be left over from a process that stopped
+        OperationGuard::acquire(
+            conn,
+            &channel_name,
+            Entrypoint::CustomerClaim,
+            Duration::ZERO,
+        )
+        .await?
+        .release(conn)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn pin_merchant_key_hash(conn: &dyn QueryCustomer) -> Result<()> {
+        let address = ZkChannelAddress::from_str("zkchannel://localhost").unwrap();
+        let other = ZkChannelAddress::from_str("zkchannel://example.com").unwrap();
+        let first: KeyHash = "11".repeat(32).parse().unwrap();
+        let second: KeyHash = "22".repeat(32).parse().unwrap();
+
+        // Nothing is pinned before first use
+        assert_eq!(conn.trusted_merchant_key_hash(&address).await?, None);
+
+        conn.trust_merchant_key_hash(&address, &first).await?;
+        assert_eq!(conn.trusted_merchant_key_hash(&address).await?, Some(first));
+        assert_eq!(conn.trusted_merchant_key_hash(&other).await?, None);
+
+        // Pinning again replaces the previous pin
+        conn.trust_merchant_key_hash(&address, &second).await?;
+        assert_eq!(
+            conn.trusted_merchant_key_hash(&address).await?,
+            Some(second)
+        );
+
+        Ok(())
+    }
+
+    async fn same_observation_reacts_once(conn: &dyn QueryCustomer) -> Result<()> {
+        let channel_name = ChannelName::new("watched channel".to_string());
+        insert_channel(&channel_name, conn).await?;
+
+        // The daemon hasn't seen the contract yet
+        assert_eq!(conn.contract_observation(&channel_name).await?, None);
+
+        // Seeing the contract in expiry at the same level over several passes closes only once
+        let mut closes = 0;
+        for _ in 0..3 {
+            let observation = conn
+                .observe_contract(&channel_name, ContractStatus::Expiry, 100)
+                .await?;
+            conn.react_once(
+                &channel_name,
+                &observation,
+                Entrypoint::CustomerClose,
+                || {
+                    closes += 1;
+                    futures::future::ready(Ok::<_, ()>(()))
+                },
+            )
+            .await?
+            .unwrap();
+        }
+        assert_eq!(closes, 1);
+
+        // Seeing the same status later keeps the level at which it was first seen
+        let observation = conn
+            .observe_contract(&channel_name, ContractStatus::Expiry, 105)
+            .await?;
+        assert_eq!(observation.level, 100);
+        assert_eq!(observation.reaction, Some(Entrypoint::CustomerClose));
+        assert_eq!(
+            conn.contract_observation(&channel_name).await?,
+            Some(observation)
+        );
+
+        // A new status forgets the reaction to the old one, and a failed reaction is tried again
+        let observation = conn
+            .observe_contract(&channel_name, ContractStatus::CustomerClose, 110)
+            .await?;
+        assert_eq!(observation.level, 110);
+        assert_eq!(observation.reaction, None);
+        let failed = conn
+            .react_once(
+                &channel_name,
+                &observation,
+                Entrypoint::CustomerClaim,
+                || futures::future::ready(Err::<(), _>("node unavailable")),
+            )
+            .await?;
+        assert_eq!(failed, Err("node unavailable"));
+        let retried = conn
+            .react_once(
+                &channel_name,
+                &observation,
+                Entrypoint::CustomerClaim,
+                || futures::future::ready(Ok::<_, ()>(())),
+            )
+            .await?;
+        assert_eq!(retried, Ok(Some(())));
+
+        // A reaction to a status the contract no longer has isn't recorded
+        assert!(
+            !conn
+                .record_reaction(
+                    &channel_name,
+                    ContractStatus::Expiry,
+                    Entrypoint::CustomerClose
+                )
+                .await?
+        );
+
+        Ok(())
+    }
 
-    use tezedge::OriginatedAddress;
-    use zkabacus_crypto::{customer::*, merchant, *};
+    async fn watch_only_records_required_action(conn: &dyn QueryCustomer) -> Result<()> {
+        let channel_name = ChannelName::new("watched channel".to_string());
+        insert_channel(&channel_name, conn).await?;
 
-    async fn create_migrated_db() -> Result<SqlitePool> {
-        let conn = SqlitePoolOptions::new()
-            .max_connections(1)
-            .connect("sqlite::memory:")
+        // A watch-only daemon leaves the close to the user, and remembers that it's needed
+        conn.observe_contract(&channel_name, ContractStatus::Expiry, 100)
             .await?;
-        conn.migrate().await?;
-        Ok(conn)
-    }
+        assert!(
+            conn.record_required_action(
+                &channel_name,
+                ContractStatus::Expiry,
+                Entrypoint::CustomerClose
+            )
+            .await?
+        );
+        let observation = conn
+            .observe_contract(&channel_name, ContractStatus::Expiry, 105)
+            .await?;
+        assert_eq!(observation.reaction, None);
+        assert_eq!(observation.required_action, Some(Entrypoint::CustomerClose));
+
+        // Once the user has closed, the action is no longer required
+        let observation = conn
+            .observe_contract(&channel_name, ContractStatus::CustomerClose, 110)
+            .await?;
+        assert_eq!(observation.required_action, None);
+
+        // An action for a status the contract no longer has isn't recorded
+        assert!(
+            !conn
+                .record_required_action(
+                    &channel_name,
+                    ContractStatus::Expiry,
+                    Entrypoint::CustomerClose
+                )
+                .await?
+        );
+        assert_eq!(
+            conn.contract_observation(&channel_name)
+                .await?
+                .and_then(|observation| observation.required_action),
+            None
+        );
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn test_migrate() -> Result<()> {
-        create_migrated_db().await?;
         Ok(())
     }
 
-    async fn insert_channel(channel_name: &ChannelName, conn: &SqlitePool) -> Result<()> {
-        // set up zkchannel details
+    async fn channel_history_records_payment_cycle(conn: &dyn QueryCustomer) -> Result<()> {
+        let channel_name = ChannelName::new("history channel".to_string());
+        let (merchant_config, blinded_state) = establish_channel(&channel_name, conn).await?;
+        let config = conn.channel_zkabacus_config(&channel_name).await?;
         let mut rng = StdRng::from_entropy();
-        let address = ZkChannelAddress::from_str("zkchannel://localhost").unwrap();
+        let context = Context::new(b"here is some fake context");
+        assert!(conn.channel_history(&channel_name).await?.is_empty());
 
-        // set up keys
-        let merchant_config = merchant::Config::new(&mut rng);
-        let (pk, rev_param, range_param) = merchant_config.extract_customer_config_parts();
-        let zkabacus_config = Config::from_parts(pk, rev_param, range_param);
+        // Activate the channel
+        let pay_token = merchant_config.activate(&mut rng, blinded_state);
+        conn.with_channel_state(
+            &channel_name,
+            zkchannels_state::Inactive,
+            |inactive| match inactive.activate(pay_token, &config) {
+                Ok(ready) => Ok((State::Ready(ready), ())),
+                Err(_) => Err(()),
+            },
+        )
+        .await?
+        .unwrap();
 
-        // build a channel id
-        let cid_m = MerchantRandomness::new(&mut rng);
-        let cid_c = CustomerRandomness::new(&mut rng);
-        let channel_id = ChannelId::new(
-            cid_m,
-            cid_c,
-            zkabacus_config.merchant_public_key(),
-            &[],
-            &[],
+        // Writing back a state of the same kind isn't a change
+        conn.with_channel_state(
+            &channel_name,
+            zkchannels_state::Ready,
+            |ready| -> std::result::Result<_, ()> { Ok((State::Ready(ready), ())) },
+        )
+        .await?
+        .unwrap();
+
+        // Make a payment
+        let payment_amount = PaymentAmount::pay_merchant(1).unwrap();
+        let start_message =
+            conn.with_channel_state(&channel_name, zkchannels_state::Ready, |ready| match ready
+                .start(&mut rng, payment_amount, &context, &config)
+            {
+                Ok((started, start_message)) => Ok((State::Started(started), start_message)),
+                Err(_) => Err(()),
+            })
+            .await?
+            .unwrap();
+        let (unrevoked, closing_signature) = merchant_config
+            .allow_payment(
+                &mut rng,
+                payment_amount,
+                &start_message.nonce,
+                start_message.pay_proof,
+                &context,
+            )
+            .unwrap();
+        let lock_message =
+            conn.with_channel_state(&channel_name, zkchannels_state::Started, |started| {
+                match started.lock(closing_signature, &config) {
+                    Ok((locked, lock_message)) => Ok((State::Locked(locked), lock_message)),
+                    Err(_) => Err(()),
+                }
+            })
+            .await?
+            .unwrap();
+        let pay_token = unrevoked
+            .complete_payment(
+                &mut rng,
+                &lock_message.revocation_pair,
+                &lock_message.revocation_lock_blinding_factor,
+            )
+            .unwrap();
+        conn.with_channel_state(
+            &channel_name,
+            zkchannels_state::Locked,
+            |locked| match locked.unlock(pay_token, &config) {
+                Ok(ready) => Ok((State::Ready(ready), ())),
+                Err(_) => Err(()),
+            },
+        )
+        .await?
+        .unwrap();
+
+        // Each change of state was recorded, in order
+        let history = conn.channel_history(&channel_name).await?;
+        let changes: Vec<_> = history
+            .iter()
+            .map(|event| (event.old_state, event.new_state))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (StateName::Inactive, StateName::Ready),
+                (StateName::Ready, StateName::Started),
+                (StateName::Started, StateName::Locked),
+                (StateName::Locked, StateName::Ready),
+            ]
         );
+        assert!(history
+            .windows(2)
+            .all(|events| events[0].recorded_at <= events[1].recorded_at));
 
-        // set up deposit info
-        let merchant_balance = MerchantBalance::try_new(5).unwrap();
-        let customer_balance = CustomerBalance::try_new(5).unwrap();
-        let context = Context::new(b"here is some fake context");
+        // The history follows the channel when it is renamed
+        let new_name = ChannelName::new("renamed history channel".to_string());
+        conn.rename_channel(&channel_name, &new_name).await?;
+        assert_eq!(conn.channel_history(&new_name).await?, history);
+        assert_no_such_channel(conn.channel_history(&channel_name).await, &channel_name);
 
-        // simulate establish to get zkabacus objects
-        let (requested, proof) = Requested::new(
-            &mut rng,
-            &zkabacus_config,
-            channel_id,
-            merchant_balance,
-            customer_balance,
-            &context,
+        Ok(())
+    }
+
+    async fn payment_recorded_with_state(conn: &dyn QueryCustomer) -> Result<()> {
+        let channel_name = ChannelName::new("paying channel".to_string());
+        insert_channel(&channel_name, conn).await?;
+        let other_channel_name = ChannelName::new("other channel".to_string());
+        insert_channel(&other_channel_name, conn).await?;
+        let payment = NewPayment {
+            amount: 5,
+            note: "two coffees, \"to go\"\nplease".to_string(),
+            payment_id: PaymentId::generate(),
+        };
+        let payment_id = conn.begin_payment(&channel_name, &payment).await?;
+
+        // Nothing is completed if the state isn't changed
+        assert!(conn
+            .with_channel_state_paid(
+                &channel_name,
+                zkchannels_state::Inactive,
+                payment_id,
+                |_| -> std::result::Result<(State, ()), ()> { Err(()) },
+            )
+            .await?
+            .is_err());
+        assert!(matches!(
+            conn.with_channel_state_paid(
+                &channel_name,
+                zkchannels_state::Ready,
+                payment_id,
+                |ready| -> std::result::Result<_, ()> { Ok((State::Ready(ready), ())) },
+            )
+            .await,
+            Err(Error::UnexpectedState(_))
+        ));
+        let history: Vec<_> = conn.payment_history(None, UNIX_EPOCH).collect().await;
+        assert!(history.is_empty());
+
+        // Otherwise the payment is completed with the balances of the new state
+        let before = SystemTime::now() - Duration::from_secs(1);
+        conn.with_channel_state_paid(
+            &channel_name,
+            zkchannels_state::Inactive,
+            payment_id,
+            |inactive| -> std::result::Result<_, ()> { Ok((State::Inactive(inactive), ())) },
+        )
+        .await?
+        .unwrap();
+        let channel = conn.get_channel(&channel_name).await?;
+        let history = conn
+            .payment_history(Some(&channel_name), UNIX_EPOCH)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].label, channel_name);
+        assert_eq!(history[0].amount, payment.amount);
+        assert_eq!(history[0].note, payment.note);
+        assert_eq!(history[0].payment_id, Some(payment.payment_id));
+        assert_eq!(
+            history[0].customer_balance as u64,
+            channel.state.customer_balance().into_inner()
         );
+        assert_eq!(
+            history[0].merchant_balance as u64,
+            channel.state.merchant_balance().into_inner()
+        );
+        assert!(history[0].paid_at >= before);
 
-        let (closing_signature, _blinded_state) = merchant_config
-            .initialize(
+        // It only appears in the history of its own channel, and since a time before it was made
+        for (label, since, expected) in [
+            (None, before, 1),
+            (Some(&other_channel_name), before, 0),
+            (None, SystemTime::now() + Duration::from_secs(60), 0),
+        ] {
+            let history: Vec<_> = conn.payment_history(label, since).collect().await;
+            assert_eq!(history.len(), expected);
+        }
+
+        Ok(())
+    }
+
+    async fn interrupted_payment_can_be_resumed(conn: &dyn QueryCustomer) -> Result<()> {
+        let channel_name = ChannelName::new("interrupted channel".to_string());
+        let (merchant_config, blinded_state) = establish_channel(&channel_name, conn).await?;
+        let config = conn.channel_zkabacus_config(&channel_name).await?;
+        let mut rng = StdRng::from_entropy();
+        let context = Context::new(b"here is some fake context");
+        let payment_amount = PaymentAmount::pay_merchant(1).unwrap();
+
+        let pay_token = merchant_config.activate(&mut rng, blinded_state);
+        conn.with_channel_state(
+            &channel_name,
+            zkchannels_state::Inactive,
+            |inactive| match inactive.activate(pay_token, &config) {
+                Ok(ready) => Ok((State::Ready(ready), ())),
+                Err(_) => Err(()),
+            },
+        )
+        .await?
+        .unwrap();
+        assert!(conn.interrupted_payment(&channel_name).await?.is_none());
+
+        // Start a payment, and lose the session before the closing signature arrives
+        let payment_id = conn
+            .begin_payment(
+                &channel_name,
+                &NewPayment {
+                    amount: 1,
+                    note: "interrupted".to_string(),
+                    payment_id: PaymentId::generate(),
+                },
+            )
+            .await?;
+        let start_message =
+            conn.with_channel_state(&channel_name, zkchannels_state::Ready, |ready| match ready
+                .start(&mut rng, payment_amount, &context, &config)
+            {
+                Ok((started, start_message)) => Ok((State::Started(started), start_message)),
+                Err(_) => Err(()),
+            })
+            .await?
+            .unwrap();
+        conn.record_payment_start(payment_id, &start_message.into())
+            .await?;
+
+        // The merchant replays the closing signature for the stored pay proof
+        let interrupted = conn.interrupted_payment(&channel_name).await?.unwrap();
+        assert_eq!(interrupted.payment_id, payment_id);
+        assert!(interrupted.lock.is_none());
+        let (_, closing_signature) = merchant_config
+            .allow_payment(
                 &mut rng,
-                &channel_id,
-                customer_balance,
-                merchant_balance,
-                proof,
+                payment_amount,
+                &interrupted.start.nonce,
+                interrupted.start.pay_proof,
                 &context,
             )
             .unwrap();
+        let lock_message =
+            conn.with_channel_state(&channel_name, zkchannels_state::Started, |started| {
+                match started.lock(closing_signature, &config) {
+                    Ok((locked, lock_message)) => Ok((State::Locked(locked), lock_message)),
+                    Err(_) => Err(()),
+                }
+            })
+            .await?
+            .unwrap();
+        conn.record_payment_lock(payment_id, &lock_message.into())
+            .await?;
 
-        let inactive = requested
-            .complete(closing_signature, &zkabacus_config)
+        // Lose the session again before the pay token arrives; the merchant replays the payment
+        // once more, and the same revocation completes it
+        let interrupted = conn.interrupted_payment(&channel_name).await?.unwrap();
+        let lock = interrupted.lock.unwrap();
+        let (unrevoked, _) = merchant_config
+            .allow_payment(
+                &mut rng,
+                payment_amount,
+                &interrupted.start.nonce,
+                interrupted.start.pay_proof,
+                &context,
+            )
             .unwrap();
-        let contract_details = ContractDetails {
-            merchant_tezos_public_key: TezosPublicKey::from_base58check(
-                "edpku5Ei6Dni4qwoJGqXJs13xHfyu4fhUg6zqZkFyiEh1mQhFD3iZE",
+        let pay_token = unrevoked
+            .complete_payment(
+                &mut rng,
+                &lock.revocation_pair,
+                &lock.revocation_lock_blinding_factor,
             )
-            .unwrap(),
-            contract_id: None,
+            .unwrap();
+        conn.with_channel_state_paid(
+            &channel_name,
+            zkchannels_state::Locked,
+            payment_id,
+            |locked| match locked.unlock(pay_token, &config) {
+                Ok(ready) => Ok((State::Ready(ready), ())),
+                Err(_) => Err(()),
+            },
+        )
+        .await?
+        .unwrap();
+
+        // Once completed, there is nothing left to resume
+        assert!(conn.interrupted_payment(&channel_name).await?.is_none());
+        let payments = conn.channel_payments(&channel_name).await?;
+        assert_eq!(payments.len(), 1);
+        assert_eq!(payments[0].outcome, PaymentOutcome::Completed);
+
+        Ok(())
+    }
+
+    async fn payment_outcomes_are_recorded(conn: &dyn QueryCustomer) -> Result<()> {
+        let channel_name = ChannelName::new("outcomes channel".to_string());
+        insert_channel(&channel_name, conn).await?;
+        let balance_before = conn
+            .get_channel(&channel_name)
+            .await?
+            .state
+            .customer_balance()
+            .into_inner() as i64;
+        let payment = |note: &str| NewPayment {
+            amount: 1,
+            note: note.to_string(),
+            payment_id: PaymentId::generate(),
+        };
+        let unlock = |inactive: Inactive| -> std::result::Result<_, ()> {
+            Ok((State::Inactive(inactive), ()))
         };
 
-        conn.new_channel(
-            channel_name,
-            &address,
-            inactive,
-            &contract_details,
-            &zkabacus_config,
-        )
-        .await
-        .map_err(|(_, e)| e)?;
+        // A payment which completes is recorded with the merchant's response
+        let completed = conn.begin_payment(&channel_name, &payment("paid")).await?;
+        conn.with_channel_state_paid(&channel_name, zkchannels_state::Inactive, completed, unlock)
+            .await?
+            .unwrap();
+        conn.set_payment_response_note(completed, "enjoy").await?;
+        // ...and stays completed, even if the session fails afterwards
+        conn.fail_payment(completed, "connection reset").await?;
+
+        // A payment the merchant aborts never changes the state, and is recorded as failed
+        let aborted = conn
+            .begin_payment(&channel_name, &payment("aborted"))
+            .await?;
+        conn.fail_payment(aborted, "Merchant aborted the payment")
+            .await?;
+
+        // A payment whose pay token doesn't unlock the channel is left pending by the failed
+        // unlock, then recorded as failed, after which it can no longer be completed
+        let invalid = conn
+            .begin_payment(&channel_name, &payment("invalid"))
+            .await?;
+        assert!(conn
+            .with_channel_state_paid(
+                &channel_name,
+                zkchannels_state::Inactive,
+                invalid,
+                |_| -> std::result::Result<(State, ()), ()> { Err(()) },
+            )
+            .await?
+            .is_err());
+        assert_eq!(
+            conn.channel_payments(&channel_name).await?[2].outcome,
+            PaymentOutcome::Pending
+        );
+        conn.fail_payment(invalid, "Invalid pay token").await?;
+        assert!(matches!(
+            conn.with_channel_state_paid(
+                &channel_name,
+                zkchannels_state::Inactive,
+                invalid,
+                unlock
+            )
+            .await,
+            Err(Error::PaymentNotPending(id)) if id == invalid
+        ));
+
+        let payments = conn.channel_payments(&channel_name).await?;
+        let outcomes: Vec<_> = payments
+            .iter()
+            .map(|payment| {
+                (
+                    payment.note.as_str(),
+                    payment.outcome,
+                    payment.failure_reason.as_deref(),
+                    payment.response_note.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                ("paid", PaymentOutcome::Completed, None, Some("enjoy")),
+                (
+                    "aborted",
+                    PaymentOutcome::Failed,
+                    Some("Merchant aborted the payment"),
+                    None
+                ),
+                (
+                    "invalid",
+                    PaymentOutcome::Failed,
+                    Some("Invalid pay token"),
+                    None
+                ),
+            ]
+        );
+        assert!(payments
+            .iter()
+            .all(|payment| payment.customer_balance_before == Some(balance_before)));
+
+        // Only the completed payment is exported in the payment history
+        let history = conn
+            .payment_history(Some(&channel_name), UNIX_EPOCH)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(history, payments[..1]);
+
+        // ...but every payment can be looked up by its ID, whatever its outcome
+        for payment in &payments {
+            assert_eq!(
+                conn.payments_with_id(&payment.payment_id.unwrap()).await?,
+                [payment.clone()]
+            );
+        }
+        assert!(conn
+            .payments_with_id(&PaymentId::generate())
+            .await?
+            .is_empty());
 
         Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn insert_customer_channel() -> Result<()> {
+    async fn operation_locks_are_forgotten() -> Result<()> {
         let conn = create_migrated_db().await?;
-        let channel_name = ChannelName::new("test channel".to_string());
+        let channel_name = ChannelName::new("forgotten lock channel".to_string());
         insert_channel(&channel_name, &conn).await?;
+        let has_lock = || in_flight::has_lock_in_process(&channel_name, Entrypoint::CustomerClose);
+
+        // The lock is kept only while a guard holds it, whether the guard is released or dropped
+        let guard = OperationGuard::acquire(
+            &conn,
+            &channel_name,
+            Entrypoint::CustomerClose,
+            Duration::from_secs(60),
+        )
+        .await?;
+        assert!(has_lock());
+        guard.release(&conn).await?;
+        assert!(!has_lock());
+
+        let guard = OperationGuard::acquire(
+            &conn,
+            &channel_name,
+            Entrypoint::CustomerClose,
+            Duration::from_secs(60),
+        )
+        .await?;
+        drop(guard);
+        assert!(!has_lock());
+
+        // Nor is it kept when another process is already posting the operation
+        conn.begin_operation(
+            &channel_name,
+            Entrypoint::CustomerClose,
+            Duration::from_secs(60),
+        )
+        .await?;
+        assert!(matches!(
+            OperationGuard::acquire(
+                &conn,
+                &channel_name,
+                Entrypoint::CustomerClose,
+                Duration::from_secs(60)
+            )
+            .await,
+            Err(Error::OperationAlreadyInFlight(Entrypoint::CustomerClose))
+        ));
+        assert!(!has_lock());
+
         Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn insert_contract_details() -> Result<()> {
+    async fn channel_ids_are_backfilled() -> Result<()> {
         let conn = create_migrated_db().await?;
-        let channel_name = ChannelName::new("test contract details channel".to_string());
+        let channel_name = ChannelName::new("old channel".to_string());
         insert_channel(&channel_name, &conn).await?;
+        let channel_id = *conn.get_channel(&channel_name).await?.state.channel_id();
 
-        // make sure contract details are not set initially
-        if conn
-            .contract_details(&channel_name)
+        // A channel stored before IDs were recorded gets its ID when the database is migrated
+        sqlx::query("UPDATE customer_channels SET channel_id = NULL")
+            .execute(&conn)
+            .await?;
+        assert_eq!(conn.channel_label_for_id(&channel_id).await?, None);
+        conn.migrate().await?;
+        assert_eq!(
+            conn.channel_label_for_id(&channel_id).await?,
+            Some(channel_name)
+        );
+
+        Ok(())
+    }
+
+    /// Several processes changing the same channel at once all succeed, and none of their changes
+    /// is lost.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_writers_wait_for_lock() -> Result<()> {
+        const PROCESSES: usize = 4;
+        const TASKS: usize = 8;
+        const FLIPS: usize = 5;
+
+        let path = std::env::temp_dir().join(format!("customer-busy-{}.db", std::process::id()));
+        let remove = || {
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+            }
+        };
+        remove();
+
+        // Each process has its own pool, so they contend for the database's lock
+        let mut pools = Vec::new();
+        for _ in 0..PROCESSES {
+            pools.push(connect_sqlite(&path).await.unwrap());
+        }
+        pools[0].migrate().await?;
+        let channel_name = ChannelName::new("contended channel".to_string());
+        insert_channel(&channel_name, pools[0].as_ref()).await?;
+        pools[0]
+            .with_channel_state(
+                &channel_name,
+                zkchannels_state::Inactive,
+                |inactive| -> std::result::Result<_, ()> { Ok((State::Originated(inactive), ())) },
+            )
             .await?
-            .contract_id
-            .is_some()
-        {
-            panic!("Contract details should not be set yet.")
+            .unwrap();
+
+        // Every task flips the channel between two states a few times
+        let mut tasks = Vec::new();
+        for pool in &pools {
+            for _ in 0..TASKS {
+                let pool = pool.clone();
+                let channel_name = channel_name.clone();
+                tasks.push(tokio::spawn(async move {
+                    for _ in 0..FLIPS {
+                        pool.with_channel_state_erased(
+                            &channel_name,
+                            None,
+                            Box::new(|state| {
+                                let flipped = match state {
+                                    State::Originated(inactive) => State::CustomerFunded(inactive),
+                                    State::CustomerFunded(inactive) => State::Originated(inactive),
+                                    _ => return Err(Box::new(()) as Box<dyn Any + Send>),
+                                };
+                                Ok((flipped, Box::new(()) as Box<dyn Any + Send>))
+                            }),
+                        )
+                        .await?
+                        .expect("channel must be in one of the flipped states");
+                    }
+                    Ok::<_, Error>(())
+                }));
+            }
+        }
+        for task in tasks {
+            task.await.unwrap()?;
         }
 
-        // pick contract details
-        let contract_id = ContractId::new(
-            OriginatedAddress::from_base58check("KT1Mjjcb6tmSsLm7Cb3DSQszePjfchPM4Uxm").unwrap(),
+        // Each flip read the state left by the one before
+        let flips = PROCESSES * TASKS * FLIPS;
+        let channel = pools[0].get_channel(&channel_name).await?;
+        assert_eq!(channel.state.state_name(), StateName::Originated);
+        assert_eq!(
+            pools[0].channel_history(&channel_name).await?.len(),
+            flips + 1
         );
 
-        // set contract details
-        conn.initialize_contract_details(&channel_name, &contract_id)
-            .await?;
+        for pool in pools {
+            pool.close().await;
+        }
+        remove();
+        Ok(())
+    }
 
-        // make sure saved details match expected values
-        let details = conn.contract_details(&channel_name).await?;
-        match details.contract_id {
-            Some(saved_id) => {
-                assert!(saved_id == contract_id)
-            }
-            None => panic!("Contract details did not get set when they should"),
+    #[tokio::test(flavor = "multi_thread")]
+    async fn backup_round_trip() -> Result<()> {
+        round_trip_backup(&create_migrated_db().await?, &create_migrated_db().await?).await
+    }
+
+    /// A backup of a SQLite database can be restored into Postgres, and vice versa, which is how a
+    /// customer moves their channels from one to the other.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn backup_moves_between_backends() -> Result<()> {
+        if let Some(postgres) = create_migrated_postgres_db().await? {
+            round_trip_backup(&create_migrated_db().await?, &postgres).await?;
+        }
+        if let Some(postgres) = create_migrated_postgres_db().await? {
+            round_trip_backup(&postgres, &create_migrated_db().await?).await?;
         }
+        Ok(())
+    }
 
-        // make sure we cannot overwrite saved contact details
-        match conn
-            .initialize_contract_details(&channel_name, &contract_id)
-            .await
-        {
-            Ok(()) => panic!("Allowed overwrite of contract details"),
-            Err(super::Error::ContractDetailsExist(_)) => Ok(()),
-            Err(e) => Err(e),
+    async fn round_trip_backup(
+        original: &dyn QueryCustomer,
+        restored: &dyn QueryCustomer,
+    ) -> Result<()> {
+        use crate::customer::backup;
+
+        let first = ChannelName::new("first channel".to_string());
+        let second = ChannelName::new("second channel".to_string());
+        insert_channel(&first, original).await?;
+        insert_channel(&second, original).await?;
+        original.set_awaiting_broadcast(&second, true).await?;
+
+        // How a closed channel was closed is restored along with it
+        let mut rng = StdRng::from_entropy();
+        let closing_message = original
+            .with_closeable_channel(&second, |state| match state {
+                State::Inactive(inactive) => {
+                    let closing_message = inactive.close(&mut rng);
+                    Ok((
+                        State::PendingMutualClose(closing_message.clone()),
+                        closing_message,
+                    ))
+                }
+                _ => Err(()),
+            })
+            .await?
+            .unwrap();
+        original
+            .update_closing_balances(
+                &second,
+                ClosingPath::Mutual,
+                *closing_message.merchant_balance(),
+                Some(*closing_message.customer_balance()),
+            )
+            .await?;
+
+        let sealed = backup::seal(&mut rng, &original.export_channels().await?, "passphrase")
+            .expect("sealing a backup succeeds");
+
+        // The wrong passphrase is caught before anything is restored
+        assert!(backup::open(&sealed, "wrong passphrase").is_err());
+
+        // Every channel is restored into a fresh database as it was
+        let channels = backup::open(&sealed, "passphrase").expect("opening a backup succeeds");
+        let summary = restored
+            .import_channels(channels, MergeStrategy::Refuse)
+            .await?;
+        assert_eq!(summary.imported, vec![first.clone(), second.clone()]);
+
+        let (original_channels, restored_channels) = (
+            original.get_channels().await?,
+            restored.get_channels().await?,
+        );
+        assert_eq!(original_channels.len(), restored_channels.len());
+        for (original, restored) in original_channels.iter().zip(&restored_channels) {
+            assert_eq!(original.label, restored.label);
+            assert_eq!(original.state.state_name(), restored.state.state_name());
+            assert_eq!(original.state.channel_id(), restored.state.channel_id());
+            assert_eq!(original.address.to_string(), restored.address.to_string());
+            assert_eq!(original.awaiting_broadcast, restored.awaiting_broadcast);
+            assert_eq!(original.closing_path, restored.closing_path);
         }
+        assert_eq!(
+            restored.get_channel(&second).await?.closing_path,
+            Some(ClosingPath::Mutual)
+        );
+        assert_eq!(
+            bincode::serialize(&original.channel_zkabacus_config(&first).await?).unwrap(),
+            bincode::serialize(&restored.channel_zkabacus_config(&first).await?).unwrap()
+        );
+
+        // Restoring over existing channels is refused outright, unless a strategy says otherwise
+        let channels = || backup::open(&sealed, "passphrase").unwrap();
+        assert!(matches!(
+            restored.import_channels(channels(), MergeStrategy::Refuse).await,
+            Err(Error::ChannelExists(name)) if name == first
+        ));
+        assert_eq!(restored.get_channels().await?.len(), 2);
+
+        let summary = restored
+            .import_channels(channels(), MergeStrategy::Skip)
+            .await?;
+        assert_eq!(summary.skipped, vec![first.clone(), second.clone()]);
+
+        restored.set_awaiting_broadcast(&second, false).await?;
+        let summary = restored
+            .import_channels(channels(), MergeStrategy::Replace)
+            .await?;
+        assert_eq!(summary.replaced, vec![first, second.clone()]);
+        assert!(restored.get_channel(&second).await?.awaiting_broadcast);
+        assert_eq!(restored.get_channels().await?.len(), 2);
+
+        Ok(())
     }
 }
@@ -0,0 +1,118 @@
+use {
+    std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+    tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard},
+};
+
+use super::{Error, QueryCustomer, Result};
+use crate::{customer::ChannelName, escrow::types::Entrypoint};
+
+lazy_static::lazy_static! {
+    /// A lock for each channel and entrypoint for which this process is posting an operation.
+    static ref IN_PROCESS: Mutex<HashMap<(ChannelName, Entrypoint), Arc<AsyncMutex<()>>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// The exclusive right to post an operation calling some entrypoint for a channel.
+///
+/// The customer CLI and the daemon's polling service can both decide to call the same entrypoint
+/// (e.g. `custClose` or `custClaim`) at once; whichever is second would fail on chain with an error
+/// that hides the success of the first. Holding one of these guards while posting rules that out,
+/// both within this process and, through a record in the database, across processes.
+#[must_use = "an operation guard should be released once the operation is done"]
+pub struct OperationGuard {
+    channel_name: ChannelName,
+    entrypoint: Entrypoint,
+    /// This process's lock for the channel and entrypoint, which is only `None` once dropped.
+    in_process: Option<OwnedMutexGuard<()>>,
+}
+
+impl OperationGuard {
+    /// Acquire the right to post an operation calling the entrypoint for the channel, or fail
+    /// with [`Error::OperationAlreadyInFlight`] if one is already being posted.
+    ///
+    /// A record in the database older than `stale_after` is assumed to have been left behind by a
+    /// process that stopped partway through, and doesn't prevent acquiring the guard.
+    pub async fn acquire(
+        database: &dyn QueryCustomer,
+        channel_name: &ChannelName,
+        entrypoint: Entrypoint,
+        stale_after: Duration,
+    ) -> Result<Self> {
+        // Only ever take the lock while holding the map, so that a guard being dropped can tell
+        // whether it holds the last reference to the lock
+        let in_process = IN_PROCESS
+            .lock()
+            .unwrap()
+            .entry((channel_name.clone(), entrypoint))
+            .or_default()
+            .clone()
+            .try_lock_owned()
+            .map_err(|_| Error::OperationAlreadyInFlight(entrypoint))?;
+        let guard = Self {
+            channel_name: channel_name.clone(),
+            entrypoint,
+            in_process: Some(in_process),
+        };
+
+        if !database
+            .begin_operation(channel_name, entrypoint, stale_after)
+            .await?
+        {
+            return Err(Error::OperationAlreadyInFlight(entrypoint));
+        }
+
+        Ok(guard)
+    }
+
+    /// Give up the right to post the operation, whether or not it succeeded.
+    ///
+    /// If this is never called, the database keeps a record of the operation until it is stale.
+    pub async fn release(self, database: &dyn QueryCustomer) -> Result<()> {
+        database
+            .end_operation(&self.channel_name, self.entrypoint)
+            .await
+    }
+
+    /// Give up the right to post the operation once it is done, passing on its `outcome`.
+    ///
+    /// A failure to release the guard is logged rather than returned, so that it doesn't hide the
+    /// outcome of the operation; the record in the database is then left until it is stale.
+    pub async fn release_after<T>(self, database: &dyn QueryCustomer, outcome: T) -> T {
+        let (channel_name, entrypoint) = (self.channel_name.clone(), self.entrypoint);
+        if let Err(e) = self.release(database).await {
+            tracing::warn!(
+                label = %channel_name,
+                %entrypoint,
+                error = %e,
+                "Failed to record that the operation is no longer in flight"
+            );
+        }
+        outcome
+    }
+}
+
+impl Drop for OperationGuard {
+    fn drop(&mut self) {
+        // Forget the lock once no guard holds it, so that locks don't pile up for every channel
+        // and entrypoint this process ever posted an operation for
+        let key = (self.channel_name.clone(), self.entrypoint);
+        let mut in_process = IN_PROCESS.lock().unwrap();
+        drop(self.in_process.take());
+        if matches!(in_process.get(&key), Some(lock) if Arc::strong_count(lock) == 1) {
+            in_process.remove(&key);
+        }
+    }
+}
+
+/// Whether this process holds a lock for the channel and entrypoint, or has one left over.
+#[cfg(test)]
+pub(super) fn has_lock_in_process(channel_name: &ChannelName, entrypoint: Entrypoint) -> bool {
+    IN_PROCESS
+        .lock()
+        .unwrap()
+        .contains_key(&(channel_name.clone(), entrypoint))
+}
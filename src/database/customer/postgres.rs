@@ -0,0 +1,1374 @@
+//! The customer database on PostgreSQL, for a daemon whose channels should live in a managed
+//! database rather than a local file.
+//!
+//! The schema and queries mirror those for SQLite. Values which SQLite stores using their own
+//! `sqlx` encodings are stored here as `BYTEA`, encoded with bincode.
+//!
+//! SQLite serializes every write, which the checks that a channel exists (or doesn't) before
+//! changing it rely on. Postgres doesn't, so channels are locked for the length of those checks,
+//! and a label taken by another connection in the meantime is reported as it would be by the check.
+
+use {
+    async_trait::async_trait,
+    futures::stream::{BoxStream, StreamExt},
+    sqlx::{
+        postgres::{PgConnection, PgRow},
+        PgPool, Row,
+    },
+    std::{
+        any::Any,
+        convert::TryFrom,
+        str::FromStr,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+};
+
+use zkabacus_crypto::{customer::Inactive, ChannelId, CustomerBalance, MerchantBalance};
+
+use super::{
+    state::zkchannels_state::{self, ZkChannelState},
+    ArchivedChannel, ChannelBackup, ChannelDetails, ChannelEvent, ClosingBalances, ClosingPath,
+    ContractObservation, Error, ImportSummary, InterruptedPayment, MergeStrategy, NewPayment,
+    PaymentLock, PaymentOutcome, PaymentRecord, PaymentStart, QueryCustomer, Result, State,
+    StateName,
+};
+use crate::{
+    customer::{client::ZkChannelAddress, ChannelName},
+    database::postgres::{get_bincode, get_optional_bincode, Bincode},
+    escrow::types::{
+        ContractDetails, ContractId, ContractStatus, Entrypoint, KeyHash, TezosPublicKey,
+    },
+    protocol::pay::PaymentId,
+};
+use tezedge::crypto::ToBase58Check;
+
+/// The code Postgres gives for a violated unique constraint.
+const UNIQUE_VIOLATION: &str = "23505";
+
+/// Convert a database error into an [`Error`], reporting a violation of the uniqueness of labels
+/// as [`Error::ChannelExists`]: another connection took the label after it was checked.
+fn label_taken(label: &ChannelName) -> impl FnOnce(sqlx::Error) -> Error + '_ {
+    move |e| {
+        let unique_violation = e
+            .as_database_error()
+            .and_then(|e| e.code())
+            .map_or(false, |code| code == UNIQUE_VIOLATION);
+        if unique_violation {
+            Error::ChannelExists(label.clone())
+        } else {
+            e.into()
+        }
+    }
+}
+
+/// Convert a time to seconds since the Unix epoch, as times are stored.
+fn seconds(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs() as i64)
+}
+
+/// Get a block level, which is stored as a `BIGINT`.
+fn get_level(row: &PgRow, column: &str) -> Result<Option<u32>> {
+    Ok(row
+        .try_get::<Option<i64>, _>(column)?
+        .map(|level| level as u32))
+}
+
+/// Parse the merchant's Tezos public key, which is stored in base58check.
+fn get_merchant_tezos_public_key(row: &PgRow, label: &ChannelName) -> Result<TezosPublicKey> {
+    let key: String = row.try_get("merchant_tezos_public_key")?;
+    TezosPublicKey::from_base58check(&key).map_err(|_| Error::InvalidContractDetails(label.clone()))
+}
+
+/// The columns of `customer_channels` which make up [`ChannelDetails`].
+const CHANNEL_DETAILS: &str = "
+    label,
+    state,
+    address,
+    customer_deposit,
+    merchant_deposit,
+    closing_balances,
+    closing_path,
+    merchant_tezos_public_key,
+    contract_id,
+    contract_level,
+    awaiting_broadcast";
+
+fn channel_details(row: &PgRow) -> Result<ChannelDetails> {
+    let label: ChannelName = row.try_get("label")?;
+    Ok(ChannelDetails {
+        state: get_bincode(row, "state")?,
+        address: get_bincode(row, "address")?,
+        customer_deposit: get_bincode(row, "customer_deposit")?,
+        merchant_deposit: get_bincode(row, "merchant_deposit")?,
+        closing_balances: get_bincode(row, "closing_balances")?,
+        closing_path: get_optional_bincode(row, "closing_path")?,
+        contract_details: ContractDetails {
+            merchant_tezos_public_key: get_merchant_tezos_public_key(row, &label)?,
+            contract_id: get_optional_bincode(row, "contract_id")?,
+            contract_level: get_level(row, "contract_level")?,
+        },
+        awaiting_broadcast: row.try_get("awaiting_broadcast")?,
+        label,
+    })
+}
+
+/// Get the given columns of a channel, or fail if there is no such channel.
+async fn channel_row(pool: &PgPool, channel_name: &ChannelName, columns: &str) -> Result<PgRow> {
+    sqlx::query(&format!(
+        "SELECT {} FROM customer_channels WHERE label = $1",
+        columns
+    ))
+    .bind(channel_name)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| Error::NoSuchChannel(channel_name.clone()))
+}
+
+/// Get the given columns of a channel, holding the channel until the transaction ends, or fail if
+/// there is no such channel.
+async fn locked_channel_row(
+    connection: &mut PgConnection,
+    channel_name: &ChannelName,
+    columns: &str,
+) -> Result<PgRow> {
+    sqlx::query(&format!(
+        "SELECT {} FROM customer_channels WHERE label = $1 FOR UPDATE",
+        columns
+    ))
+    .bind(channel_name)
+    .fetch_optional(connection)
+    .await?
+    .ok_or_else(|| Error::NoSuchChannel(channel_name.clone()))
+}
+
+/// Read a payment on the channel with the given label from a row of `payments`, whose
+/// `started_at` falls back to its `paid_at`.
+fn payment_record(label: ChannelName, row: &PgRow) -> Result<PaymentRecord> {
+    Ok(PaymentRecord {
+        label,
+        amount: row.try_get("amount")?,
+        note: row.try_get("note")?,
+        outcome: row.try_get::<PaymentOutcome, _>("outcome")?,
+        failure_reason: row.try_get("failure_reason")?,
+        response_note: row.try_get("response_note")?,
+        customer_balance_before: row.try_get("customer_balance_before")?,
+        customer_balance: row.try_get("customer_balance")?,
+        merchant_balance: row.try_get("merchant_balance")?,
+        started_at: UNIX_EPOCH + Duration::from_secs(row.try_get::<i64, _>("started_at")? as u64),
+        paid_at: UNIX_EPOCH + Duration::from_secs(row.try_get::<i64, _>("paid_at")? as u64),
+        payment_id: row
+            .try_get::<Option<String>, _>("payment_id")?
+            .and_then(|id| id.parse().ok()),
+    })
+}
+
+/// Delete a channel which is finished with, returning its `id` and the given columns of it as they
+/// were, or fail if it isn't finished with.
+async fn remove_terminal_channel(
+    connection: &mut PgConnection,
+    channel_name: &ChannelName,
+    columns: &str,
+) -> Result<PgRow> {
+    let row = locked_channel_row(
+        connection,
+        channel_name,
+        &format!("id, config_id, state, {}", columns),
+    )
+    .await?;
+    let state: State = get_bincode(&row, "state")?;
+    let state_name = state.state_name();
+    if !state_name.is_terminal() {
+        return Err(Error::NotTerminal(channel_name.clone(), state_name));
+    }
+
+    // The channel's history and in-flight operations go with it
+    sqlx::query("DELETE FROM customer_channels WHERE id = $1")
+        .bind(row.try_get::<i64, _>("id")?)
+        .execute(&mut *connection)
+        .await?;
+    sqlx::query("DELETE FROM configs WHERE id = $1")
+        .bind(row.try_get::<i64, _>("config_id")?)
+        .execute(&mut *connection)
+        .await?;
+    Ok(row)
+}
+
+/// Whether there is a channel by the given name.
+async fn channel_exists(connection: &mut PgConnection, channel_name: &ChannelName) -> Result<bool> {
+    Ok(
+        sqlx::query("SELECT label FROM customer_channels WHERE label = $1")
+            .bind(channel_name)
+            .fetch_optional(connection)
+            .await?
+            .is_some(),
+    )
+}
+
+#[async_trait]
+impl QueryCustomer for PgPool {
+    async fn migrate(&self) -> Result<()> {
+        sqlx::migrate!("src/database/migrations/customer_postgres")
+            .run(self)
+            .await?;
+
+        // Channels stored before their IDs were recorded get them from their states
+        let unidentified =
+            sqlx::query("SELECT id, state FROM customer_channels WHERE channel_id IS NULL")
+                .fetch_all(self)
+                .await?;
+        for row in unidentified {
+            let state: State = get_bincode(&row, "state")?;
+            sqlx::query("UPDATE customer_channels SET channel_id = $1 WHERE id = $2")
+                .bind(state.channel_id().to_string())
+                .bind(row.try_get::<i64, _>("id")?)
+                .execute(self)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn new_channel(
+        &self,
+        channel_name: &ChannelName,
+        address: &ZkChannelAddress,
+        inactive: Inactive,
+        contract_details: &ContractDetails,
+        zkabacus_config: &zkabacus_crypto::customer::Config,
+    ) -> std::result::Result<(), (Inactive, Error)> {
+        let merchant_deposit = *inactive.merchant_balance();
+        let customer_deposit = *inactive.customer_balance();
+        let state = State::Inactive(inactive);
+        let result = async {
+            channel_name.validate()?;
+            let mut transaction = self.begin().await?;
+
+            // Return an error if the channel already exists
+            if channel_exists(&mut transaction, channel_name).await? {
+                return Err(Error::ChannelExists(channel_name.clone()));
+            }
+
+            // Return an error if contract details are already originated
+            if contract_details.contract_id.is_some() {
+                return Err(Error::InvalidContractDetails(channel_name.clone()));
+            }
+
+            let config_id: i64 = sqlx::query("INSERT INTO configs (data) VALUES ($1) RETURNING id")
+                .bind(Bincode(zkabacus_config))
+                .fetch_one(&mut transaction)
+                .await?
+                .try_get("id")?;
+
+            sqlx::query(
+                "INSERT INTO customer_channels (
+                    label,
+                    address,
+                    merchant_deposit,
+                    customer_deposit,
+                    state,
+                    closing_balances,
+                    merchant_tezos_public_key,
+                    contract_id,
+                    config_id,
+                    state_updated_at,
+                    channel_id
+                )
+                VALUES (
+                    $1, $2, $3, $4, $5, $6, $7, NULL, $8,
+                    EXTRACT(EPOCH FROM NOW())::BIGINT,
+                    $9
+                )",
+            )
+            .bind(channel_name)
+            .bind(Bincode(address))
+            .bind(Bincode(merchant_deposit))
+            .bind(Bincode(customer_deposit))
+            .bind(Bincode(&state))
+            .bind(Bincode(ClosingBalances::default()))
+            .bind(contract_details.merchant_tezos_public_key.to_base58check())
+            .bind(config_id)
+            .bind(state.channel_id().to_string())
+            .execute(&mut transaction)
+            .await
+            .map_err(label_taken(channel_name))?;
+
+            transaction.commit().await?;
+            Ok(())
+        }
+        .await;
+
+        result.map_err(|e| {
+            (
+                zkchannels_state::Inactive::zkabacus_state(state).unwrap(),
+                e,
+            )
+        })
+    }
+
+    async fn channel_zkabacus_config(
+        &self,
+        channel_name: &ChannelName,
+    ) -> Result<zkabacus_crypto::customer::Config> {
+        let row = sqlx::query(
+            "SELECT data
+            FROM configs
+            INNER JOIN customer_channels ON configs.id = customer_channels.config_id
+            WHERE customer_channels.label = $1
+            LIMIT 1",
+        )
+        .bind(channel_name)
+        .fetch_optional(self)
+        .await?
+        .ok_or_else(|| Error::NoSuchChannel(channel_name.clone()))?;
+        Ok(get_bincode(&row, "data")?)
+    }
+
+    async fn channel_address(&self, channel_name: &ChannelName) -> Result<ZkChannelAddress> {
+        let row = channel_row(self, channel_name, "address").await?;
+        Ok(get_bincode(&row, "address")?)
+    }
+
+    async fn closing_balances(&self, channel_name: &ChannelName) -> Result<ClosingBalances> {
+        let row = channel_row(self, channel_name, "closing_balances").await?;
+        Ok(get_bincode(&row, "closing_balances")?)
+    }
+
+    async fn update_closing_balances(
+        &self,
+        channel_name: &ChannelName,
+        closing_path: ClosingPath,
+        merchant_balance: MerchantBalance,
+        customer_balance: Option<CustomerBalance>,
+    ) -> Result<()> {
+        let mut transaction = self.begin().await?;
+
+        // Find the current balances, holding the channel until they're updated
+        let row =
+            locked_channel_row(&mut transaction, channel_name, "state, closing_balances").await?;
+        let state: State = get_bincode(&row, "state")?;
+        let closing_balances: ClosingBalances = get_bincode(&row, "closing_balances")?;
+
+        // Make sure the channel is closing: balances are only paid out by a close.
+        let state_name = state.state_name();
+        if !state_name.is_closing() {
+            return Err(Error::NotClosing(channel_name.clone(), state_name));
+        }
+
+        // Make sure we're not decreasing merchant balance.
+        if let Some(original) = closing_balances.merchant_balance {
+            if original.into_inner() > merchant_balance.into_inner() {
+                return Err(Error::InvalidBalanceUpdate(
+                    merchant_balance,
+                    customer_balance,
+                ));
+            }
+        }
+
+        // Make sure we don't update customer balance more than once.
+        if closing_balances.customer_balance.is_some() {
+            return Err(Error::InvalidBalanceUpdate(
+                merchant_balance,
+                customer_balance,
+            ));
+        }
+
+        // If everything was ok, set the new balances.
+        let updated_closing_balances = ClosingBalances {
+            merchant_balance: Some(merchant_balance),
+            customer_balance,
+        };
+
+        sqlx::query(
+            "UPDATE customer_channels SET closing_balances = $1, closing_path = $2 WHERE label = $3",
+        )
+        .bind(Bincode(updated_closing_balances))
+        .bind(Bincode(closing_path))
+        .bind(channel_name)
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn contract_details(&self, channel_name: &ChannelName) -> Result<ContractDetails> {
+        let row = channel_row(
+            self,
+            channel_name,
+            "contract_id, contract_level, merchant_tezos_public_key",
+        )
+        .await?;
+
+        Ok(ContractDetails {
+            merchant_tezos_public_key: get_merchant_tezos_public_key(&row, channel_name)?,
+            contract_id: get_optional_bincode(&row, "contract_id")?,
+            contract_level: get_level(&row, "contract_level")?,
+        })
+    }
+
+    async fn set_contract_id(
+        &self,
+        channel_name: &ChannelName,
+        contract_id: &ContractId,
+        level: u32,
+    ) -> Result<()> {
+        let mut transaction = self.begin().await?;
+
+        // Ensure that channel exists and does not already have contract details, holding it until
+        // they're set
+        let row = locked_channel_row(&mut transaction, channel_name, "contract_id").await?;
+        if get_optional_bincode::<ContractId>(&row, "contract_id")?.is_some() {
+            return Err(Error::ContractDetailsExist(channel_name.clone()));
+        }
+
+        sqlx::query(
+            "UPDATE customer_channels SET contract_id = $1, contract_level = $2 WHERE label = $3",
+        )
+        .bind(Bincode(contract_id))
+        .bind(i64::from(level))
+        .bind(channel_name)
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn rename_channel(
+        &self,
+        channel_name: &ChannelName,
+        new_channel_name: &ChannelName,
+    ) -> Result<()> {
+        new_channel_name.validate()?;
+        let mut transaction = self.begin().await?;
+
+        // Ensure that the old channel name exists, holding it until it's renamed
+        locked_channel_row(&mut transaction, channel_name, "label").await?;
+
+        // Ensure that the new channel name *does not* exist
+        if channel_exists(&mut transaction, new_channel_name).await? {
+            return Err(Error::ChannelExists(new_channel_name.clone()));
+        }
+
+        sqlx::query("UPDATE customer_channels SET label = $1 WHERE label = $2")
+            .bind(new_channel_name)
+            .bind(channel_name)
+            .execute(&mut transaction)
+            .await
+            .map_err(label_taken(new_channel_name))?;
+
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn set_awaiting_broadcast(
+        &self,
+        channel_name: &ChannelName,
+        awaiting_broadcast: bool,
+    ) -> Result<()> {
+        let rows_affected =
+            sqlx::query("UPDATE customer_channels SET awaiting_broadcast = $1 WHERE label = $2")
+                .bind(awaiting_broadcast)
+                .bind(channel_name)
+                .execute(self)
+                .await?
+                .rows_affected();
+
+        if rows_affected == 1 {
+            Ok(())
+        } else {
+            Err(Error::NoSuchChannel(channel_name.clone()))
+        }
+    }
+
+    async fn readdress_channel(
+        &self,
+        channel_name: &ChannelName,
+        new_address: &ZkChannelAddress,
+    ) -> Result<()> {
+        let rows_affected =
+            sqlx::query("UPDATE customer_channels SET address = $1 WHERE label = $2")
+                .bind(Bincode(new_address))
+                .bind(channel_name)
+                .execute(self)
+                .await?
+                .rows_affected();
+
+        if rows_affected == 1 {
+            Ok(())
+        } else {
+            Err(Error::NoSuchChannel(channel_name.clone()))
+        }
+    }
+
+    async fn get_channels(&self) -> Result<Vec<ChannelDetails>> {
+        sqlx::query(&format!(
+            "SELECT {} FROM customer_channels ORDER BY id",
+            CHANNEL_DETAILS
+        ))
+        .fetch_all(self)
+        .await?
+        .iter()
+        .map(channel_details)
+        .collect()
+    }
+
+    async fn get_channel(&self, channel_name: &ChannelName) -> Result<ChannelDetails> {
+        channel_details(&channel_row(self, channel_name, CHANNEL_DETAILS).await?)
+    }
+
+    async fn get_channel_by_id(&self, channel_id: &ChannelId) -> Result<ChannelDetails> {
+        let row = sqlx::query(&format!(
+            "SELECT {} FROM customer_channels WHERE channel_id = $1",
+            CHANNEL_DETAILS
+        ))
+        .bind(channel_id.to_string())
+        .fetch_optional(self)
+        .await?
+        .ok_or(Error::NoSuchChannelId(*channel_id))?;
+        channel_details(&row)
+    }
+
+    async fn channel_label_for_id(&self, channel_id: &ChannelId) -> Result<Option<ChannelName>> {
+        Ok(
+            sqlx::query("SELECT label FROM customer_channels WHERE channel_id = $1")
+                .bind(channel_id.to_string())
+                .fetch_optional(self)
+                .await?
+                .map(|row| row.try_get("label"))
+                .transpose()?,
+        )
+    }
+
+    async fn channel_label_for_prefix(&self, prefix: &str) -> Result<ChannelName> {
+        // Match the prefix literally, rather than with `LIKE`, which would treat `_` as a wildcard
+        let mut labels = sqlx::query(
+            "SELECT label FROM customer_channels
+            WHERE left(channel_id, length($1)) = $1
+            LIMIT 2",
+        )
+        .bind(prefix)
+        .fetch_all(self)
+        .await?
+        .into_iter();
+
+        match (labels.next(), labels.next()) {
+            (None, _) => Err(Error::ChannelNotFoundWithPrefix(prefix.to_string())),
+            (Some(row), None) => Ok(row.try_get("label")?),
+            (Some(_), Some(_)) => Err(Error::ChannelIdCollision(prefix.to_string())),
+        }
+    }
+
+    async fn channels_for_address(&self, address: &ZkChannelAddress) -> Result<Vec<ChannelName>> {
+        let mut labels = Vec::new();
+        for row in sqlx::query(
+            "SELECT label, state FROM customer_channels
+            WHERE address = $1
+            ORDER BY label",
+        )
+        .bind(Bincode(address))
+        .fetch_all(self)
+        .await?
+        {
+            let state: State = get_bincode(&row, "state")?;
+            if !state.state_name().is_terminal() {
+                labels.push(row.try_get("label")?);
+            }
+        }
+        Ok(labels)
+    }
+
+    async fn archive_channel(&self, channel_name: &ChannelName) -> Result<()> {
+        let mut transaction = self.begin().await?;
+
+        let row = remove_terminal_channel(
+            &mut transaction,
+            channel_name,
+            "channel_id,
+            address,
+            merchant_deposit,
+            customer_deposit,
+            closing_balances,
+            closing_path,
+            contract_id,
+            contract_level",
+        )
+        .await?;
+        let state: State = get_bincode(&row, "state")?;
+
+        sqlx::query(
+            "INSERT INTO archived_channels (
+                label,
+                channel_id,
+                address,
+                merchant_deposit,
+                customer_deposit,
+                final_state,
+                closing_balances,
+                closing_path,
+                contract_id,
+                contract_level,
+                archived_at
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, EXTRACT(EPOCH FROM NOW())::BIGINT)",
+        )
+        .bind(channel_name)
+        .bind(row.try_get::<Option<String>, _>("channel_id")?)
+        .bind(row.try_get::<Vec<u8>, _>("address")?)
+        .bind(row.try_get::<Vec<u8>, _>("merchant_deposit")?)
+        .bind(row.try_get::<Vec<u8>, _>("customer_deposit")?)
+        .bind(Bincode(state.state_name()))
+        .bind(row.try_get::<Vec<u8>, _>("closing_balances")?)
+        .bind(row.try_get::<Option<Vec<u8>>, _>("closing_path")?)
+        .bind(row.try_get::<Option<Vec<u8>>, _>("contract_id")?)
+        .bind(row.try_get::<Option<i64>, _>("contract_level")?)
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn purge_channel(&self, channel_name: &ChannelName) -> Result<()> {
+        let mut transaction = self.begin().await?;
+        remove_terminal_channel(&mut transaction, channel_name, "label").await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn get_archived_channels(&self) -> Result<Vec<ArchivedChannel>> {
+        sqlx::query(
+            "SELECT
+                label,
+                channel_id,
+                address,
+                merchant_deposit,
+                customer_deposit,
+                final_state,
+                closing_balances,
+                closing_path,
+                contract_id,
+                contract_level,
+                archived_at
+            FROM archived_channels
+            ORDER BY id",
+        )
+        .fetch_all(self)
+        .await?
+        .iter()
+        .map(|row| -> Result<ArchivedChannel> {
+            Ok(ArchivedChannel {
+                label: row.try_get("label")?,
+                channel_id: row
+                    .try_get::<Option<String>, _>("channel_id")?
+                    .map(|id| ChannelId::from_str(&id).map_err(|_| Error::MalformedChannelId(id)))
+                    .transpose()?,
+                address: get_bincode(row, "address")?,
+                merchant_deposit: get_bincode(row, "merchant_deposit")?,
+                customer_deposit: get_bincode(row, "customer_deposit")?,
+                final_state: get_bincode(row, "final_state")?,
+                closing_balances: get_bincode(row, "closing_balances")?,
+                closing_path: get_optional_bincode(row, "closing_path")?,
+                contract_id: get_optional_bincode(row, "contract_id")?,
+                contract_level: get_level(row, "contract_level")?,
+                archived_at: UNIX_EPOCH
+                    + Duration::from_secs(row.try_get::<i64, _>("archived_at")? as u64),
+            })
+        })
+        .collect()
+    }
+
+    async fn export_channels(&self) -> Result<Vec<ChannelBackup>> {
+        sqlx::query(
+            "SELECT
+                label,
+                address,
+                merchant_deposit,
+                customer_deposit,
+                state,
+                closing_balances,
+                closing_path,
+                merchant_tezos_public_key,
+                contract_id,
+                contract_level,
+                awaiting_broadcast,
+                configs.data AS zkabacus_config
+            FROM customer_channels
+            INNER JOIN configs ON configs.id = customer_channels.config_id
+            ORDER BY customer_channels.id",
+        )
+        .fetch_all(self)
+        .await?
+        .iter()
+        .map(|row| -> Result<ChannelBackup> {
+            Ok(ChannelBackup {
+                label: row.try_get("label")?,
+                address: get_bincode(row, "address")?,
+                merchant_deposit: get_bincode(row, "merchant_deposit")?,
+                customer_deposit: get_bincode(row, "customer_deposit")?,
+                state: get_bincode(row, "state")?,
+                closing_balances: get_bincode(row, "closing_balances")?,
+                closing_path: get_optional_bincode(row, "closing_path")?,
+                merchant_tezos_public_key: row.try_get("merchant_tezos_public_key")?,
+                contract_id: get_optional_bincode(row, "contract_id")?,
+                contract_level: get_level(row, "contract_level")?,
+                awaiting_broadcast: row.try_get("awaiting_broadcast")?,
+                zkabacus_config: get_bincode(row, "zkabacus_config")?,
+            })
+        })
+        .collect()
+    }
+
+    async fn import_channels(
+        &self,
+        channels: Vec<ChannelBackup>,
+        strategy: MergeStrategy,
+    ) -> Result<ImportSummary> {
+        let mut transaction = self.begin().await?;
+        let mut summary = ImportSummary::default();
+
+        for channel in channels {
+            let existing =
+                sqlx::query("SELECT config_id FROM customer_channels WHERE label = $1 FOR UPDATE")
+                    .bind(&channel.label)
+                    .fetch_optional(&mut transaction)
+                    .await?
+                    .map(|row| row.try_get::<i64, _>("config_id"))
+                    .transpose()?;
+            let replacing = existing.is_some();
+
+            if let Some(config_id) = existing {
+                match strategy {
+                    // Dropping the transaction rolls back every channel restored so far
+                    MergeStrategy::Refuse => return Err(Error::ChannelExists(channel.label)),
+                    MergeStrategy::Skip => {
+                        summary.skipped.push(channel.label);
+                        continue;
+                    }
+                    MergeStrategy::Replace => {
+                        sqlx::query("DELETE FROM customer_channels WHERE label = $1")
+                            .bind(&channel.label)
+                            .execute(&mut transaction)
+                            .await?;
+                        sqlx::query("DELETE FROM configs WHERE id = $1")
+                            .bind(config_id)
+                            .execute(&mut transaction)
+                            .await?;
+                    }
+                }
+            }
+
+            let config_id: i64 = sqlx::query("INSERT INTO configs (data) VALUES ($1) RETURNING id")
+                .bind(Bincode(&channel.zkabacus_config))
+                .fetch_one(&mut transaction)
+                .await?
+                .try_get("id")?;
+
+            sqlx::query(
+                "INSERT INTO customer_channels (
+                    label,
+                    address,
+                    merchant_deposit,
+                    customer_deposit,
+                    state,
+                    closing_balances,
+                    closing_path,
+                    merchant_tezos_public_key,
+                    contract_id,
+                    contract_level,
+                    awaiting_broadcast,
+                    config_id,
+                    state_updated_at,
+                    channel_id
+                )
+                VALUES (
+                    $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12,
+                    EXTRACT(EPOCH FROM NOW())::BIGINT,
+                    $13
+                )",
+            )
+            .bind(&channel.label)
+            .bind(Bincode(&channel.address))
+            .bind(Bincode(channel.merchant_deposit))
+            .bind(Bincode(channel.customer_deposit))
+            .bind(Bincode(&channel.state))
+            .bind(Bincode(channel.closing_balances))
+            .bind(channel.closing_path.map(Bincode))
+            .bind(&channel.merchant_tezos_public_key)
+            .bind(channel.contract_id.as_ref().map(Bincode))
+            .bind(channel.contract_level.map(i64::from))
+            .bind(channel.awaiting_broadcast)
+            .bind(config_id)
+            .bind(channel.state.channel_id().to_string())
+            .execute(&mut transaction)
+            .await
+            .map_err(label_taken(&channel.label))?;
+
+            if replacing {
+                summary.replaced.push(channel.label);
+            } else {
+                summary.imported.push(channel.label);
+            }
+        }
+
+        transaction.commit().await?;
+        Ok(summary)
+    }
+
+    async fn begin_operation(
+        &self,
+        channel_name: &ChannelName,
+        entrypoint: Entrypoint,
+        stale_after: Duration,
+    ) -> Result<bool> {
+        let mut transaction = self.begin().await?;
+
+        let channel_id: i64 = sqlx::query("SELECT id FROM customer_channels WHERE label = $1")
+            .bind(channel_name)
+            .fetch_optional(&mut transaction)
+            .await?
+            .ok_or_else(|| Error::NoSuchChannel(channel_name.clone()))?
+            .try_get("id")?;
+
+        // Clear out any record of this operation that is too old to still be in flight
+        let stale_before = SystemTime::now()
+            .checked_sub(stale_after)
+            .map_or(0, seconds);
+        sqlx::query(
+            "DELETE FROM operations_in_flight
+            WHERE channel_id = $1 AND entrypoint = $2 AND started_at <= $3",
+        )
+        .bind(channel_id)
+        .bind(Bincode(entrypoint))
+        .bind(stale_before)
+        .execute(&mut transaction)
+        .await?;
+
+        // A concurrent insert of the same operation waits for this one, then inserts nothing
+        let rows_affected = sqlx::query(
+            "INSERT INTO operations_in_flight (channel_id, entrypoint, started_at)
+            VALUES ($1, $2, EXTRACT(EPOCH FROM NOW())::BIGINT)
+            ON CONFLICT (channel_id, entrypoint) DO NOTHING",
+        )
+        .bind(channel_id)
+        .bind(Bincode(entrypoint))
+        .execute(&mut transaction)
+        .await?
+        .rows_affected();
+
+        transaction.commit().await?;
+
+        Ok(rows_affected == 1)
+    }
+
+    async fn end_operation(
+        &self,
+        channel_name: &ChannelName,
+        entrypoint: Entrypoint,
+    ) -> Result<()> {
+        sqlx::query(
+            "DELETE FROM operations_in_flight
+            WHERE channel_id = (SELECT id FROM customer_channels WHERE label = $1)
+            AND entrypoint = $2",
+        )
+        .bind(channel_name)
+        .bind(Bincode(entrypoint))
+        .execute(self)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn operations_in_flight(&self, channel_name: &ChannelName) -> Result<Vec<Entrypoint>> {
+        sqlx::query(
+            "SELECT entrypoint
+            FROM operations_in_flight
+            WHERE channel_id = (SELECT id FROM customer_channels WHERE label = $1)
+            ORDER BY started_at",
+        )
+        .bind(channel_name)
+        .fetch_all(self)
+        .await?
+        .iter()
+        .map(|row| -> Result<Entrypoint> { Ok(get_bincode(row, "entrypoint")?) })
+        .collect()
+    }
+
+    async fn get_stalled_establishments(
+        &self,
+        stalled_since: SystemTime,
+    ) -> Result<Vec<ChannelDetails>> {
+        let channels = sqlx::query(&format!(
+            "SELECT {} FROM customer_channels WHERE state_updated_at <= $1 ORDER BY id",
+            CHANNEL_DETAILS
+        ))
+        .bind(seconds(stalled_since))
+        .fetch_all(self)
+        .await?;
+
+        // The state is stored opaquely, so it has to be checked here rather than in the query
+        let mut stalled = Vec::new();
+        for row in &channels {
+            let channel = channel_details(row)?;
+            if matches!(
+                channel.state.state_name(),
+                StateName::Originated | StateName::CustomerFunded
+            ) {
+                stalled.push(channel);
+            }
+        }
+        Ok(stalled)
+    }
+
+    async fn trusted_merchant_key_hash(
+        &self,
+        address: &ZkChannelAddress,
+    ) -> Result<Option<KeyHash>> {
+        sqlx::query("SELECT key_hash FROM trusted_merchants WHERE address = $1")
+            .bind(Bincode(address))
+            .fetch_optional(self)
+            .await?
+            .map(|row| -> Result<KeyHash> { Ok(get_bincode(&row, "key_hash")?) })
+            .transpose()
+    }
+
+    async fn trust_merchant_key_hash(
+        &self,
+        address: &ZkChannelAddress,
+        key_hash: &KeyHash,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO trusted_merchants (address, key_hash) VALUES ($1, $2)
+            ON CONFLICT (address) DO UPDATE SET key_hash = excluded.key_hash",
+        )
+        .bind(Bincode(address))
+        .bind(Bincode(key_hash))
+        .execute(self)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn channel_history(&self, channel_name: &ChannelName) -> Result<Vec<ChannelEvent>> {
+        let channel_id: i64 = channel_row(self, channel_name, "id").await?.try_get("id")?;
+
+        sqlx::query(
+            "SELECT old_state, new_state, recorded_at
+            FROM channel_events
+            WHERE channel_id = $1
+            ORDER BY id",
+        )
+        .bind(channel_id)
+        .fetch_all(self)
+        .await?
+        .iter()
+        .map(|row| -> Result<ChannelEvent> {
+            Ok(ChannelEvent {
+                old_state: get_bincode(row, "old_state")?,
+                new_state: get_bincode(row, "new_state")?,
+                recorded_at: UNIX_EPOCH
+                    + Duration::from_secs(row.try_get::<i64, _>("recorded_at")? as u64),
+            })
+        })
+        .collect()
+    }
+
+    async fn begin_payment(&self, channel_name: &ChannelName, payment: &NewPayment) -> Result<i64> {
+        let mut transaction = self.begin().await?;
+        let row = locked_channel_row(&mut transaction, channel_name, "id, state").await?;
+        let channel_id: i64 = row.try_get("id")?;
+        let state: State = get_bincode(&row, "state")?;
+
+        // Until the payment completes, its balances are those it began with
+        let customer_balance = state.customer_balance().into_inner() as i64;
+        let merchant_balance = state.merchant_balance().into_inner() as i64;
+        let payment_id: i64 = sqlx::query(
+            "INSERT INTO payments (
+                channel_id,
+                amount,
+                note,
+                outcome,
+                customer_balance_before,
+                customer_balance,
+                merchant_balance,
+                started_at,
+                paid_at,
+                payment_id
+            )
+            VALUES (
+                $1, $2, $3, 'pending', $4, $4, $5,
+                EXTRACT(EPOCH FROM NOW())::BIGINT, EXTRACT(EPOCH FROM NOW())::BIGINT, $6
+            )
+            RETURNING id",
+        )
+        .bind(channel_id)
+        .bind(payment.amount)
+        .bind(&payment.note)
+        .bind(customer_balance)
+        .bind(merchant_balance)
+        .bind(payment.payment_id.to_string())
+        .fetch_one(&mut transaction)
+        .await?
+        .try_get("id")?;
+
+        transaction.commit().await?;
+        Ok(payment_id)
+    }
+
+    async fn fail_payment(&self, payment_id: i64, reason: &str) -> Result<()> {
+        sqlx::query(
+            "UPDATE payments
+            SET outcome = 'failed', failure_reason = $1
+            WHERE id = $2 AND outcome = 'pending'",
+        )
+        .bind(reason)
+        .bind(payment_id)
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+
+    async fn set_payment_response_note(&self, payment_id: i64, response_note: &str) -> Result<()> {
+        sqlx::query("UPDATE payments SET response_note = $1 WHERE id = $2")
+            .bind(response_note)
+            .bind(payment_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn record_payment_start(&self, payment_id: i64, start: &PaymentStart) -> Result<()> {
+        sqlx::query("UPDATE payments SET start_message = $1 WHERE id = $2 AND outcome = 'pending'")
+            .bind(Bincode(start))
+            .bind(payment_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn record_payment_lock(&self, payment_id: i64, lock: &PaymentLock) -> Result<()> {
+        sqlx::query("UPDATE payments SET lock_message = $1 WHERE id = $2 AND outcome = 'pending'")
+            .bind(Bincode(lock))
+            .bind(payment_id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn interrupted_payment(
+        &self,
+        channel_name: &ChannelName,
+    ) -> Result<Option<InterruptedPayment>> {
+        let channel_id: i64 = channel_row(self, channel_name, "id").await?.try_get("id")?;
+
+        sqlx::query(
+            "SELECT id, start_message, lock_message
+            FROM payments
+            WHERE channel_id = $1 AND outcome = 'pending' AND start_message IS NOT NULL
+            ORDER BY id DESC
+            LIMIT 1",
+        )
+        .bind(channel_id)
+        .fetch_optional(self)
+        .await?
+        .map(|row| -> Result<InterruptedPayment> {
+            Ok(InterruptedPayment {
+                payment_id: row.try_get("id")?,
+                start: get_bincode(&row, "start_message")?,
+                lock: get_optional_bincode(&row, "lock_message")?,
+            })
+        })
+        .transpose()
+    }
+
+    async fn channel_payments(&self, channel_name: &ChannelName) -> Result<Vec<PaymentRecord>> {
+        let channel_id: i64 = channel_row(self, channel_name, "id").await?.try_get("id")?;
+
+        sqlx::query(
+            "SELECT
+                amount,
+                note,
+                outcome,
+                failure_reason,
+                response_note,
+                customer_balance_before,
+                customer_balance,
+                merchant_balance,
+                COALESCE(started_at, paid_at) AS started_at,
+                paid_at,
+                payment_id
+            FROM payments
+            WHERE channel_id = $1
+            ORDER BY id",
+        )
+        .bind(channel_id)
+        .fetch_all(self)
+        .await?
+        .iter()
+        .map(|row| payment_record(channel_name.clone(), row))
+        .collect()
+    }
+
+    fn payment_history<'a>(
+        &'a self,
+        channel_name: Option<&'a ChannelName>,
+        since: SystemTime,
+    ) -> BoxStream<'a, Result<PaymentRecord>> {
+        sqlx::query(
+            "SELECT
+                customer_channels.label,
+                payments.amount,
+                payments.note,
+                payments.outcome,
+                payments.failure_reason,
+                payments.response_note,
+                payments.customer_balance_before,
+                payments.customer_balance,
+                payments.merchant_balance,
+                COALESCE(payments.started_at, payments.paid_at) AS started_at,
+                payments.paid_at,
+                payments.payment_id
+            FROM payments
+            JOIN customer_channels ON customer_channels.id = payments.channel_id
+            WHERE
+                ($1 IS NULL OR customer_channels.label = $1)
+                AND payments.paid_at >= $2
+                AND payments.outcome = 'completed'
+            ORDER BY customer_channels.label, payments.id",
+        )
+        .bind(channel_name)
+        .bind(seconds(since))
+        .fetch(self)
+        .map(|row| -> Result<PaymentRecord> {
+            let row = row?;
+            payment_record(row.try_get("label")?, &row)
+        })
+        .boxed()
+    }
+
+    async fn payments_with_id(&self, payment_id: &PaymentId) -> Result<Vec<PaymentRecord>> {
+        sqlx::query(
+            "SELECT
+                customer_channels.label,
+                payments.amount,
+                payments.note,
+                payments.outcome,
+                payments.failure_reason,
+                payments.response_note,
+                payments.customer_balance_before,
+                payments.customer_balance,
+                payments.merchant_balance,
+                COALESCE(payments.started_at, payments.paid_at) AS started_at,
+                payments.paid_at,
+                payments.payment_id
+            FROM payments
+            JOIN customer_channels ON customer_channels.id = payments.channel_id
+            WHERE payments.payment_id = $1
+            ORDER BY payments.id",
+        )
+        .bind(payment_id.to_string())
+        .fetch_all(self)
+        .await?
+        .iter()
+        .map(|row| payment_record(row.try_get("label")?, row))
+        .collect()
+    }
+
+    async fn observe_contract(
+        &self,
+        channel_name: &ChannelName,
+        status: ContractStatus,
+        level: u32,
+    ) -> Result<ContractObservation> {
+        let rows_affected = sqlx::query(
+            "UPDATE customer_channels
+            SET
+                last_observed_level = CASE
+                    WHEN last_observed_status IS NOT DISTINCT FROM $1
+                    THEN last_observed_level ELSE $2
+                END,
+                last_reaction = CASE
+                    WHEN last_observed_status IS NOT DISTINCT FROM $1 THEN last_reaction ELSE NULL
+                END,
+                required_action = CASE
+                    WHEN last_observed_status IS NOT DISTINCT FROM $1
+                    THEN required_action ELSE NULL
+                END,
+                last_observed_status = $1,
+                last_observed_at = EXTRACT(EPOCH FROM NOW())::BIGINT
+            WHERE label = $3",
+        )
+        .bind(status as i32)
+        .bind(i64::from(level))
+        .bind(channel_name)
+        .execute(self)
+        .await?
+        .rows_affected();
+
+        if rows_affected != 1 {
+            return Err(Error::NoSuchChannel(channel_name.clone()));
+        }
+
+        self.contract_observation(channel_name)
+            .await?
+            .ok_or_else(|| Error::NoSuchChannel(channel_name.clone()))
+    }
+
+    async fn record_reaction(
+        &self,
+        channel_name: &ChannelName,
+        status: ContractStatus,
+        reaction: Entrypoint,
+    ) -> Result<bool> {
+        let rows_affected = sqlx::query(
+            "UPDATE customer_channels SET last_reaction = $1
+            WHERE label = $2 AND last_observed_status = $3 AND last_reaction IS NULL",
+        )
+        .bind(Bincode(reaction))
+        .bind(channel_name)
+        .bind(status as i32)
+        .execute(self)
+        .await?
+        .rows_affected();
+
+        Ok(rows_affected == 1)
+    }
+
+    async fn record_required_action(
+        &self,
+        channel_name: &ChannelName,
+        status: ContractStatus,
+        action: Entrypoint,
+    ) -> Result<bool> {
+        let rows_affected = sqlx::query(
+            "UPDATE customer_channels SET required_action = $1
+            WHERE label = $2 AND last_observed_status = $3",
+        )
+        .bind(Bincode(action))
+        .bind(channel_name)
+        .bind(status as i32)
+        .execute(self)
+        .await?
+        .rows_affected();
+
+        Ok(rows_affected == 1)
+    }
+
+    async fn contract_observation(
+        &self,
+        channel_name: &ChannelName,
+    ) -> Result<Option<ContractObservation>> {
+        let row = channel_row(
+            self,
+            channel_name,
+            "last_observed_status,
+            last_observed_level,
+            last_observed_at,
+            last_reaction,
+            required_action",
+        )
+        .await?;
+
+        match (
+            row.try_get::<Option<i32>, _>("last_observed_status")?,
+            get_level(&row, "last_observed_level")?,
+            row.try_get::<Option<i64>, _>("last_observed_at")?,
+        ) {
+            (Some(status), Some(level), Some(observed_at)) => Ok(Some(ContractObservation {
+                status: ContractStatus::try_from(status)
+                    .map_err(|_| Error::InvalidContractObservation(channel_name.clone()))?,
+                level,
+                observed_at: UNIX_EPOCH + Duration::from_secs(observed_at as u64),
+                reaction: get_optional_bincode(&row, "last_reaction")?,
+                required_action: get_optional_bincode(&row, "required_action")?,
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    async fn with_channel_state_erased<'a>(
+        &'a self,
+        channel_name: &ChannelName,
+        payment_id: Option<i64>,
+        with_state: Box<
+            dyn for<'s> FnOnce(
+                    State,
+                ) -> std::result::Result<
+                    (State, Box<dyn Any + Send>),
+                    Box<dyn Any + Send>,
+                > + Send
+                + 'a,
+        >,
+    ) -> Result<std::result::Result<Box<dyn Any>, Box<dyn Any>>> {
+        let mut transaction = self.begin().await?;
+
+        // Retrieve the state so that we can modify it, holding the channel until it's stored
+        let row = locked_channel_row(&mut transaction, channel_name, "id, state").await?;
+        let channel_id: i64 = row.try_get("id")?;
+        let state: State = get_bincode(&row, "state")?;
+        let old_state = state.state_name();
+
+        // Perform the operation with the state fetched from the database
+        match with_state(state) {
+            Ok((state, output)) => {
+                let new_state = state.state_name();
+                let customer_balance = state.customer_balance().into_inner() as i64;
+                let merchant_balance = state.merchant_balance().into_inner() as i64;
+
+                // Store the new state to the database
+                sqlx::query(
+                    "UPDATE customer_channels
+                    SET state = $1, state_updated_at = EXTRACT(EPOCH FROM NOW())::BIGINT
+                    WHERE label = $2",
+                )
+                .bind(Bincode(state))
+                .bind(channel_name)
+                .execute(&mut transaction)
+                .await?;
+
+                // Record the change if the channel moved to a different kind of state
+                if new_state != old_state {
+                    sqlx::query(
+                        "INSERT INTO channel_events (channel_id, old_state, new_state, recorded_at)
+                        VALUES ($1, $2, $3, EXTRACT(EPOCH FROM NOW())::BIGINT)",
+                    )
+                    .bind(channel_id)
+                    .bind(Bincode(old_state))
+                    .bind(Bincode(new_state))
+                    .execute(&mut transaction)
+                    .await?;
+                }
+
+                // Complete the payment this change completes, if any
+                if let Some(payment_id) = payment_id {
+                    let rows_affected = sqlx::query(
+                        "UPDATE payments
+                        SET
+                            outcome = 'completed',
+                            customer_balance = $1,
+                            merchant_balance = $2,
+                            paid_at = EXTRACT(EPOCH FROM NOW())::BIGINT
+                        WHERE id = $3 AND channel_id = $4 AND outcome = 'pending'",
+                    )
+                    .bind(customer_balance)
+                    .bind(merchant_balance)
+                    .bind(payment_id)
+                    .bind(channel_id)
+                    .execute(&mut transaction)
+                    .await?
+                    .rows_affected();
+                    if rows_affected == 0 {
+                        return Err(Error::PaymentNotPending(payment_id));
+                    }
+                }
+
+                // Commit the transaction
+                transaction.commit().await?;
+
+                Ok(Ok(output))
+            }
+            Err(error) => Ok(Err(error)),
+        }
+    }
+}
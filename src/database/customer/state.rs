@@ -51,6 +51,9 @@ pub enum State {
     /// Note: this [`ClosingMessage`](zkabacus::ClosingMessage) indicates the channel state as
     /// proposed by the customer, which may be different from the final balances.
     Closed(zkabacus::ClosingMessage),
+    /// Establishment was abandoned before the merchant funded the contract, and the customer's
+    /// deposit, if any, has been reclaimed from it.
+    FundingReclaimed(zkabacus::Inactive),
 }
 
 /// The set of zkAbacus states that are associated with at least one channel status.
@@ -63,6 +66,7 @@ impl IsZkAbacusState for zkabacus::Locked {}
 impl IsZkAbacusState for zkabacus::ClosingMessage {}
 
 impl_sqlx_for_bincode_ty!(State);
+impl_sqlx_for_bincode_ty!(StateName);
 
 pub mod zkchannels_state {
     //! Individual structs that compose the ZkChannel statuses and conversion functions to
@@ -128,10 +132,12 @@ pub mod zkchannels_state {
     impl_zkchannel_state!(PendingCustomerClaim, ClosingMessage);
     impl_zkchannel_state!(Dispute, ClosingMessage);
     impl_zkchannel_state!(Closed, ClosingMessage);
+    impl_zkchannel_state!(FundingReclaimed, Inactive);
 }
 
 /// The names of the different states a channel can be in (does not contain actual state).
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(strum_macros::EnumIter))]
 pub enum StateName {
     Inactive,
     Originated,
@@ -146,6 +152,47 @@ pub enum StateName {
     PendingCustomerClaim,
     Dispute,
     Closed,
+    FundingReclaimed,
+}
+
+impl StateName {
+    /// Whether a channel in this state is open: funded, and not yet closing or closed.
+    pub fn is_open(self) -> bool {
+        matches!(
+            self,
+            StateName::CustomerFunded
+                | StateName::MerchantFunded
+                | StateName::Ready
+                | StateName::Started
+                | StateName::Locked
+        )
+    }
+
+    /// Whether a channel in this state can be closed mutually. The merchant only agrees to close a
+    /// channel which is fully funded and has no payment underway.
+    pub fn can_close_mutually(self) -> bool {
+        self == StateName::Ready
+    }
+
+    /// Whether a channel in this state is finished with: closed, or abandoned with its funding
+    /// reclaimed. Nothing more will happen to it.
+    pub fn is_terminal(self) -> bool {
+        matches!(self, StateName::Closed | StateName::FundingReclaimed)
+    }
+
+    /// Whether a channel in this state is closing or closed, so that it may have closing
+    /// balances.
+    pub fn is_closing(self) -> bool {
+        matches!(
+            self,
+            StateName::PendingMutualClose
+                | StateName::PendingExpiry
+                | StateName::PendingClose
+                | StateName::PendingCustomerClaim
+                | StateName::Dispute
+                | StateName::Closed
+        )
+    }
 }
 
 impl Display for StateName {
@@ -164,6 +211,7 @@ impl Display for StateName {
             StateName::PendingCustomerClaim => "pending customer claim",
             StateName::Dispute => "disputed",
             StateName::Closed => "closed",
+            StateName::FundingReclaimed => "funding reclaimed",
         }
         .fmt(f)
     }
@@ -186,6 +234,7 @@ impl State {
             State::PendingCustomerClaim(_) => StateName::PendingCustomerClaim,
             State::Dispute(_) => StateName::Dispute,
             State::Closed(_) => StateName::Closed,
+            State::FundingReclaimed(_) => StateName::FundingReclaimed,
         }
     }
 
@@ -205,6 +254,7 @@ impl State {
             State::PendingCustomerClaim(closing_message) => closing_message.customer_balance(),
             State::Dispute(closing_message) => closing_message.customer_balance(),
             State::Closed(closed) => closed.customer_balance(),
+            State::FundingReclaimed(inactive) => inactive.customer_balance(),
         }
     }
 
@@ -223,6 +273,7 @@ impl State {
             State::PendingCustomerClaim(closing_message) => closing_message.merchant_balance(),
             State::Dispute(closing_message) => closing_message.merchant_balance(),
             State::Closed(closed) => closed.merchant_balance(),
+            State::FundingReclaimed(inactive) => inactive.merchant_balance(),
         }
     }
 
@@ -241,6 +292,7 @@ impl State {
             State::PendingCustomerClaim(closing_message) => closing_message.channel_id(),
             State::Dispute(closing_message) => closing_message.channel_id(),
             State::Closed(closed) => closed.channel_id(),
+            State::FundingReclaimed(inactive) => inactive.channel_id(),
         }
     }
 }
@@ -253,3 +305,21 @@ pub struct UnexpectedState {
     expected_state: StateName,
     actual_state: StateName,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use strum::IntoEnumIterator;
+
+    #[test]
+    fn only_ready_channels_close_mutually() {
+        let open: Vec<StateName> = StateName::iter().filter(|state| state.is_open()).collect();
+        assert!(open.contains(&StateName::Ready));
+
+        // An open channel which isn't ready can only be closed unilaterally
+        for state in open {
+            assert_eq!(state.can_close_mutually(), state == StateName::Ready);
+        }
+        assert!(!StateName::PendingMutualClose.can_close_mutually());
+    }
+}
@@ -1,17 +1,35 @@
-use {async_trait::async_trait, futures::StreamExt, rand::rngs::StdRng, thiserror::Error};
+use {
+    async_trait::async_trait,
+    futures::StreamExt,
+    rand::rngs::StdRng,
+    std::{
+        fmt::{self, Display, Formatter},
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+    thiserror::Error,
+};
 
-pub use super::connect_sqlite;
+pub use super::{connect_postgres, connect_sqlite};
 use crate::database::SqlitePool;
-use crate::{escrow::types::ContractId, protocol::ChannelStatus};
+use crate::{
+    escrow::types::{ContractId, FundingOperation, TezosFundingAddress, TezosPublicKey},
+    protocol::{
+        pay::{self, LimitExceeded, PaymentId, PaymentLimits, RecentPayments},
+        ChannelStatus, Transcript,
+    },
+};
 use serde::{Deserialize, Serialize};
+use tezedge::crypto::ToBase58Check;
 use zkabacus_crypto::{
     revlock::{RevocationLock, RevocationPair, RevocationSecret},
-    ChannelId, CommitmentParameters, CustomerBalance, KeyPair, MerchantBalance, Nonce,
-    RangeConstraintParameters,
+    ChannelId, CommitmentParameters, Context, CustomerBalance, KeyPair, MerchantBalance, Nonce,
+    PayProof, PaymentAmount, RangeConstraintParameters,
 };
 
 type Result<T> = std::result::Result<T, Error>;
 
+mod postgres;
+
 #[async_trait]
 pub trait QueryMerchant: Send + Sync {
     /// Perform all the DB migrations defined in src/database/migrations/merchant/*.sql
@@ -21,18 +39,81 @@ pub trait QueryMerchant: Send + Sync {
     /// and `false` if it already exists.
     async fn insert_nonce(&self, nonce: &Nonce) -> Result<bool>;
 
+    /// Atomically insert a nonce along with what's needed to resume the pay session spending it,
+    /// returning `true` if it was added successfully and `false` if it already exists.
+    ///
+    /// Sessions which started more than [`pay::RESUME_WINDOW`] before this one are forgotten.
+    async fn insert_nonce_for_session(&self, nonce: &Nonce, session: &PaySession) -> Result<bool>;
+
+    /// Get what's needed to resume the pay session which spent the nonce, if it started within
+    /// [`pay::RESUME_WINDOW`] of `now`.
+    async fn pay_session(&self, nonce: &Nonce, now: SystemTime) -> Result<Option<PaySession>>;
+
     /// Don't use this function! Use the more descriptive
     /// [`QueryMerchantExt::insert_revocation_lock()`] and
     /// [`QueryMerchantExt::insert_revocation_pair()`] functions instead!
     ///
     /// Insert a revocation lock and optional secret, returning all revocations
     /// that existed prior.
+    ///
+    /// If a payment is given, it is recorded in the same transaction, but only if there were no
+    /// prior revocations.
     async fn insert_revocation(
         &self,
         revocation: &RevocationLock,
         secret: Option<&RevocationSecret>,
+        payment: Option<&PaymentRecord>,
     ) -> Result<Vec<Option<RevocationSecret>>>;
 
+    /// Look up the revocation secret for a revocation lock, if the customer has revealed it.
+    ///
+    /// A customer who posts a lock with a known secret on chain is closing on a revoked state,
+    /// which the merchant can dispute with the secret.
+    async fn revocation_secret_for(
+        &self,
+        revocation_lock: &RevocationLock,
+    ) -> Result<Option<RevocationSecret>>;
+
+    /// Record a payment of the given amount (negative for a refund) made at the given time, only
+    /// if it fits within the rate and volume limits given the payments already recorded.
+    ///
+    /// The check and the insert happen atomically. If the payment doesn't fit, this fails with
+    /// [`Error::PaymentLimitExceeded`], giving the limit it would have exceeded.
+    async fn insert_payment_event(
+        &self,
+        amount: i64,
+        now: SystemTime,
+        limits: &PaymentLimits,
+    ) -> Result<()>;
+
+    /// Summarize the payments recorded within the rate and volume windows ending at the given
+    /// time.
+    async fn recent_payments(&self, now: SystemTime) -> Result<RecentPayments>;
+
+    /// Record a payment in the payment history.
+    ///
+    /// Completed payments should instead be recorded along with their revocation, by
+    /// [`QueryMerchantExt::insert_revocation_pair_for_payment()`]. A completed payment which was
+    /// already recorded as failed, because its session was interrupted before it was resumed,
+    /// updates that record rather than adding another.
+    async fn record_payment(&self, payment: &PaymentRecord) -> Result<()>;
+
+    /// Get up to `limit` entries of the payment history, most recent first, skipping the most
+    /// recent `offset`.
+    async fn payment_history(&self, limit: u32, offset: u32) -> Result<Vec<PaymentRecord>>;
+
+    /// Get every payment in the payment history made with the given payment ID, oldest first.
+    async fn payments_with_id(&self, payment_id: &PaymentId) -> Result<Vec<PaymentRecord>>;
+
+    /// Keep a webhook event until it is delivered, returning its ID.
+    async fn insert_webhook_event(&self, payload: &str) -> Result<i64>;
+
+    /// Forget a webhook event once it has been delivered.
+    async fn delete_webhook_event(&self, id: i64) -> Result<()>;
+
+    /// Get the ID and payload of every webhook event which hasn't been delivered, oldest first.
+    async fn undelivered_webhook_events(&self) -> Result<Vec<(i64, String)>>;
+
     /// Fetch a singleton merchant config, creating it if it doesn't already exist.
     async fn fetch_or_create_config(
         &self,
@@ -48,8 +129,27 @@ pub trait QueryMerchant: Send + Sync {
         customer_deposit: &CustomerBalance,
     ) -> Result<()>;
 
+    /// Store a new channel whose contract has been verified during establishment, along with the
+    /// Tezos account the customer established it with, so that the account can be checked
+    /// against the contract when the channel closes.
+    ///
+    /// The channel is stored with the customer's account in a single write, so that it is never
+    /// stored without it.
+    async fn record_verified_contract(
+        &self,
+        channel_id: &ChannelId,
+        contract_id: &ContractId,
+        merchant_deposit: &MerchantBalance,
+        customer_deposit: &CustomerBalance,
+        customer_funding_address: &TezosFundingAddress,
+        customer_public_key: &TezosPublicKey,
+    ) -> Result<()>;
+
     /// Update an existing merchant channel's status to a new state, only if it is currently in the
     /// expected state.
+    ///
+    /// The check and the update happen atomically. If the channel is in any other state, this fails
+    /// with [`Error::UnexpectedChannelStatus`], giving the state it was actually in.
     async fn compare_and_swap_channel_status(
         &self,
         channel_id: &ChannelId,
@@ -57,6 +157,65 @@ pub trait QueryMerchant: Send + Sync {
         new: &ChannelStatus,
     ) -> Result<()>;
 
+    /// Record the on-chain operation by which the customer funded the channel's contract.
+    async fn record_customer_funding(
+        &self,
+        channel_id: &ChannelId,
+        funding_operation: &FundingOperation,
+    ) -> Result<()>;
+
+    /// Get the on-chain operation by which the customer funded the channel's contract, if it has
+    /// been recorded.
+    async fn customer_funding(&self, channel_id: &ChannelId) -> Result<Option<FundingOperation>>;
+
+    /// Record the Tezos address from which the customer said they would fund the channel's
+    /// contract.
+    async fn record_customer_funding_address(
+        &self,
+        channel_id: &ChannelId,
+        funding_address: &TezosFundingAddress,
+    ) -> Result<()>;
+
+    /// Get the Tezos address from which the customer said they would fund the channel's contract,
+    /// if it has been recorded.
+    async fn customer_funding_address(
+        &self,
+        channel_id: &ChannelId,
+    ) -> Result<Option<TezosFundingAddress>>;
+
+    /// Record the Tezos public key the customer gave when establishing the channel.
+    async fn record_customer_public_key(
+        &self,
+        channel_id: &ChannelId,
+        public_key: &TezosPublicKey,
+    ) -> Result<()>;
+
+    /// Get the Tezos public key the customer gave when establishing the channel, if it has been
+    /// recorded.
+    async fn customer_public_key(&self, channel_id: &ChannelId) -> Result<Option<TezosPublicKey>>;
+
+    /// Record the hash of the merchDispute operation with which the merchant disputed the
+    /// channel's close.
+    async fn record_dispute_operation(&self, channel_id: &ChannelId, hash: &str) -> Result<()>;
+
+    /// Get the hash of the merchDispute operation with which the merchant disputed the channel's
+    /// close, if it has been recorded.
+    async fn dispute_operation(&self, channel_id: &ChannelId) -> Result<Option<String>>;
+
+    /// Mark a channel as blocked, or no longer blocked, by the merchant.
+    async fn set_channel_blocked(&self, channel_id: &ChannelId, blocked: bool) -> Result<()>;
+
+    /// Flag a channel as needing an operator's attention for the given reason, or clear the flag
+    /// if the reason is `None`.
+    async fn set_attention_reason(
+        &self,
+        channel_id: &ChannelId,
+        reason: Option<&str>,
+    ) -> Result<()>;
+
+    /// Get the reason a channel needs an operator's attention, if it has been flagged.
+    async fn attention_reason(&self, channel_id: &ChannelId) -> Result<Option<String>>;
+
     /// Update an existing merchant channel's status to PendingClose, if it is in a state that can
     /// do so allowably (e.g. not already in a close flow).
     async fn update_status_to_pending_close(&self, channel_id: &ChannelId) -> Result<()>;
@@ -79,6 +238,29 @@ pub trait QueryMerchant: Send + Sync {
     /// Get information about every channel in the database.
     async fn get_channels(&self) -> Result<Vec<ChannelDetails>>;
 
+    /// Get information about a particular channel based on its [`ChannelId`].
+    async fn get_channel(&self, channel_id: &ChannelId) -> Result<ChannelDetails>;
+
+    /// Get information about every channel currently in the given status.
+    async fn channels_with_status(&self, status: &ChannelStatus) -> Result<Vec<ChannelDetails>>;
+
+    /// Get information about every channel which has been stuck partway through establishment,
+    /// after the customer funded the contract but before the channel was activated, since before
+    /// the given time.
+    async fn get_stalled_establishments(
+        &self,
+        stalled_since: SystemTime,
+    ) -> Result<Vec<ChannelDetails>>;
+
+    /// Get information about every channel which has been active since before the given time.
+    ///
+    /// Payments are unlinkable, so the merchant can't tell when a particular channel was last
+    /// used; the time it was activated is the last activity the merchant can attribute to it.
+    async fn get_long_active_channels(
+        &self,
+        active_since: SystemTime,
+    ) -> Result<Vec<ChannelDetails>>;
+
     /// Get channel status for a particular channel based on its [`ChannelId`].
     async fn channel_status(&self, channel_id: &ChannelId) -> Result<ChannelStatus>;
 
@@ -112,6 +294,14 @@ pub trait QueryMerchantExt: QueryMerchant {
         &self,
         revocation_pair: &RevocationPair,
     ) -> Result<Vec<Option<RevocationSecret>>>;
+
+    /// Insert a revocation pair revealed by a payment, returning all revocations that existed
+    /// prior. If there were none, the payment is recorded along with the revocation.
+    async fn insert_revocation_pair_for_payment(
+        &self,
+        revocation_pair: &RevocationPair,
+        payment: &PaymentRecord,
+    ) -> Result<Vec<Option<RevocationSecret>>>;
 }
 
 /// An error when accessing the merchant database.
@@ -139,6 +329,15 @@ pub enum Error {
     /// A channel balance update was invalid.
     #[error("Failed to update channel balance to invalid set (merchant: {0:?}, customer: {1:?})")]
     InvalidBalanceUpdate(MerchantBalance, Option<CustomerBalance>),
+    /// A stored Tezos address could not be parsed.
+    #[error("Invalid customer funding address stored for channel {0}")]
+    InvalidFundingAddress(ChannelId),
+    /// A stored Tezos public key could not be parsed.
+    #[error("Invalid customer public key stored for channel {0}")]
+    InvalidPublicKey(ChannelId),
+    /// A payment would have exceeded one of the merchant's limits.
+    #[error("Payment limit exceeded: {0}")]
+    PaymentLimitExceeded(LimitExceeded),
     /// An underlying database error occurred.
     #[error(transparent)]
     Database(#[from] sqlx::Error),
@@ -147,6 +346,119 @@ pub enum Error {
     Migration(#[from] sqlx::migrate::MigrateError),
 }
 
+/// What became of a payment the merchant was asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(rename_all = "snake_case", type_name = "text")]
+pub enum PaymentOutcome {
+    /// The customer received a new pay token.
+    Completed,
+    /// The merchant's limits or approver refused the payment.
+    Rejected,
+    /// The payment was approved, but the pay session didn't complete.
+    Failed,
+}
+
+impl Display for PaymentOutcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Completed => "completed",
+                Self::Rejected => "rejected",
+                Self::Failed => "failed",
+            }
+        )
+    }
+}
+
+/// A payment the merchant was asked for, as recorded in its payment history.
+///
+/// Payments are unlinkable, so the history can't say which channel a payment came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaymentRecord {
+    /// The amount of the payment, in mutez, which is negative for a refund.
+    pub amount: i64,
+    /// The note the customer attached to the payment.
+    pub note: String,
+    /// The approver the payment was sent to: `automatic`, or its URL.
+    pub approver: String,
+    /// The response note the approver gave for the customer, if any.
+    pub response_note: Option<String>,
+    /// Where the approver said the result of the payment could be found, if anywhere.
+    pub response_url: Option<String>,
+    pub outcome: PaymentOutcome,
+    /// Why the payment wasn't completed, if it wasn't.
+    pub failure_reason: Option<String>,
+    /// When the merchant received the payment request.
+    pub created_at: SystemTime,
+    /// The ID the customer chose for the payment, which is missing for payments made before
+    /// customers sent one.
+    pub payment_id: Option<PaymentId>,
+}
+
+/// What the merchant needs to resume a pay session which was interrupted after the customer
+/// started the payment, kept for the nonce the payment spends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaySession {
+    /// The digest of the session's transcript, from which the context of the pay proof was made.
+    pub transcript_digest: Vec<u8>,
+    /// The amount of the payment, in mutez, which is negative for a refund.
+    pub amount: i64,
+    pub note: String,
+    /// The pay proof the customer sent, encoded with bincode.
+    pub pay_proof: Vec<u8>,
+    /// When the payment started, as recorded in the payment history.
+    pub created_at: SystemTime,
+    /// The ID the customer chose for the payment, which is missing for sessions started before
+    /// customers sent one.
+    pub payment_id: Option<PaymentId>,
+    /// The approver's response note, as when it approved the payment.
+    pub response_note: Option<String>,
+    /// Where the result of the payment may be located, as when the approver approved it.
+    pub response_url: Option<String>,
+}
+
+impl PaySession {
+    /// The session for a payment the approver approved, as recorded in the payment history.
+    pub fn new(
+        transcript: &Transcript,
+        payment_amount: PaymentAmount,
+        payment: &PaymentRecord,
+        pay_proof: &PayProof,
+    ) -> Self {
+        Self {
+            transcript_digest: transcript.digest().to_vec(),
+            amount: payment_amount.to_i64(),
+            note: payment.note.clone(),
+            pay_proof: bincode::serialize(pay_proof).expect("Pay proofs must be serializable"),
+            created_at: payment.created_at,
+            payment_id: payment.payment_id,
+            response_note: payment.response_note.clone(),
+            response_url: payment.response_url.clone(),
+        }
+    }
+
+    /// Whether the pay proof is the one the customer sent when the session started.
+    pub fn is_for(&self, pay_proof: &PayProof) -> bool {
+        bincode::serialize(pay_proof).map_or(false, |pay_proof| pay_proof == self.pay_proof)
+    }
+
+    /// The context against which the pay proof was made.
+    pub fn context(&self) -> Context {
+        Context::new(&self.transcript_digest)
+    }
+
+    /// The amount of the payment, if it is still a valid payment amount.
+    pub fn payment_amount(&self) -> Option<PaymentAmount> {
+        if self.amount < 0 {
+            PaymentAmount::pay_customer(self.amount.unsigned_abs()).ok()
+        } else {
+            PaymentAmount::pay_merchant(self.amount as u64).ok()
+        }
+    }
+}
+
 /// The contents of a row of the database for a particular channel.
 pub struct ChannelDetails {
     pub channel_id: ChannelId,
@@ -155,6 +467,11 @@ pub struct ChannelDetails {
     pub merchant_deposit: MerchantBalance,
     pub customer_deposit: CustomerBalance,
     pub closing_balances: ClosingBalances,
+    /// Whether the merchant has blocked the channel.
+    pub blocked: bool,
+    /// When the channel's status last changed, which is the last activity the merchant can
+    /// attribute to it, since payments are unlinkable.
+    pub status_updated_at: SystemTime,
 }
 
 /// The balances of a channel at closing. These may change during a close flow.
@@ -195,10 +512,108 @@ impl QueryMerchant for SqlitePool {
         Ok(res.rows_affected() > 0)
     }
 
+    async fn insert_nonce_for_session(&self, nonce: &Nonce, session: &PaySession) -> Result<bool> {
+        let created_at = session
+            .created_at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+        let window_start = pay::window_start(created_at, pay::RESUME_WINDOW) as i64;
+        let payment_id = session.payment_id.map(|id| id.to_string());
+        let created_at = created_at as i64;
+        let mut transaction = self.begin().await?;
+
+        let inserted = sqlx::query!(
+            "INSERT INTO nonces (data) VALUES (?) ON CONFLICT (data) DO NOTHING",
+            nonce
+        )
+        .execute(&mut transaction)
+        .await?
+        .rows_affected()
+            > 0;
+
+        // Only a fresh nonce starts a session which can be resumed
+        if inserted {
+            sqlx::query!(
+                "DELETE FROM pay_sessions WHERE created_at <= ?",
+                window_start
+            )
+            .execute(&mut transaction)
+            .await?;
+            sqlx::query!(
+                r#"
+                INSERT INTO pay_sessions (
+                    nonce,
+                    transcript_digest,
+                    amount,
+                    note,
+                    pay_proof,
+                    created_at,
+                    payment_id,
+                    response_note,
+                    response_url
+                )
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+                "#,
+                nonce,
+                session.transcript_digest,
+                session.amount,
+                session.note,
+                session.pay_proof,
+                created_at,
+                payment_id,
+                session.response_note,
+                session.response_url,
+            )
+            .execute(&mut transaction)
+            .await?;
+        }
+
+        transaction.commit().await?;
+        Ok(inserted)
+    }
+
+    async fn pay_session(&self, nonce: &Nonce, now: SystemTime) -> Result<Option<PaySession>> {
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+        let window_start = pay::window_start(now, pay::RESUME_WINDOW) as i64;
+
+        Ok(sqlx::query!(
+            r#"
+            SELECT
+                transcript_digest,
+                amount,
+                note,
+                pay_proof,
+                created_at,
+                payment_id,
+                response_note,
+                response_url
+            FROM pay_sessions
+            WHERE nonce = ? AND created_at > ?
+            "#,
+            nonce,
+            window_start,
+        )
+        .fetch_optional(self)
+        .await?
+        .map(|r| PaySession {
+            transcript_digest: r.transcript_digest,
+            amount: r.amount,
+            note: r.note,
+            pay_proof: r.pay_proof,
+            created_at: UNIX_EPOCH + Duration::from_secs(r.created_at as u64),
+            payment_id: r.payment_id.and_then(|id| id.parse().ok()),
+            response_note: r.response_note,
+            response_url: r.response_url,
+        }))
+    }
+
     async fn insert_revocation(
         &self,
         lock: &RevocationLock,
         secret: Option<&RevocationSecret>,
+        payment: Option<&PaymentRecord>,
     ) -> Result<Vec<Option<RevocationSecret>>> {
         let mut transaction = self.begin().await?;
         let existing_pairs = sqlx::query!(
@@ -223,10 +638,237 @@ impl QueryMerchant for SqlitePool {
         .execute(&mut transaction)
         .await?;
 
+        // A payment whose revocation lock was reused didn't complete, so isn't recorded here
+        if let (Some(payment), true) = (payment, existing_pairs.is_empty()) {
+            insert_payment(&mut transaction, payment).await?;
+        }
+
         transaction.commit().await?;
         Ok(existing_pairs)
     }
 
+    async fn revocation_secret_for(
+        &self,
+        revocation_lock: &RevocationLock,
+    ) -> Result<Option<RevocationSecret>> {
+        Ok(sqlx::query!(
+            r#"
+            SELECT secret AS "secret!: RevocationSecret"
+            FROM revocations
+            WHERE lock = ? AND secret IS NOT NULL
+            LIMIT 1
+            "#,
+            revocation_lock,
+        )
+        .fetch_optional(self)
+        .await?
+        .map(|r| r.secret))
+    }
+
+    async fn insert_payment_event(
+        &self,
+        amount: i64,
+        now: SystemTime,
+        limits: &PaymentLimits,
+    ) -> Result<()> {
+        let created_at = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+        let rate_window_start = pay::window_start(created_at, pay::RATE_WINDOW) as i64;
+        let volume_window_start = pay::window_start(created_at, pay::VOLUME_WINDOW) as i64;
+        let created_at = created_at as i64;
+        let volume_amount = amount.max(0);
+        let max_payments = limits
+            .max_payments_per_minute
+            .map_or(i64::MAX, |max| max as i64);
+        let max_volume = limits
+            .max_volume_per_day
+            .map_or(i64::MAX, |max| max.min(i64::MAX as u64) as i64);
+
+        loop {
+            // Insert the event only if the recent events leave room for it, in one statement so
+            // that concurrent payments can't both fit in the last of the room
+            let inserted = sqlx::query!(
+                r#"
+                INSERT INTO payment_events (amount, created_at)
+                SELECT ?, ?
+                WHERE
+                    (SELECT COUNT(*) FROM payment_events WHERE created_at > ?) < ?
+                    AND (
+                        SELECT COALESCE(SUM(amount), 0)
+                        FROM payment_events
+                        WHERE amount > 0 AND created_at > ?
+                    ) + ? <= ?
+                "#,
+                amount,
+                created_at,
+                rate_window_start,
+                max_payments,
+                volume_window_start,
+                volume_amount,
+                max_volume,
+            )
+            .execute(self)
+            .await?
+            .rows_affected()
+                > 0;
+
+            if inserted {
+                return Ok(());
+            }
+
+            // Work out which limit was exceeded; since events are only ever added, one must have
+            // been, but if not, try again
+            let recent = self.recent_payments(now).await?;
+            limits
+                .check_recent(&recent, amount)
+                .map_err(Error::PaymentLimitExceeded)?;
+        }
+    }
+
+    async fn record_payment(&self, payment: &PaymentRecord) -> Result<()> {
+        let mut connection = self.acquire().await?;
+        insert_payment(&mut connection, payment).await
+    }
+
+    async fn payment_history(&self, limit: u32, offset: u32) -> Result<Vec<PaymentRecord>> {
+        let payments = sqlx::query!(
+            r#"
+            SELECT
+                amount AS "amount!",
+                note AS "note!",
+                approver AS "approver!",
+                response_note AS "response_note?",
+                response_url AS "response_url?",
+                outcome AS "outcome!: PaymentOutcome",
+                failure_reason AS "failure_reason?",
+                created_at AS "created_at!",
+                payment_id AS "payment_id?"
+            FROM payments
+            ORDER BY id DESC
+            LIMIT ? OFFSET ?
+            "#,
+            limit,
+            offset,
+        )
+        .fetch_all(self)
+        .await?
+        .into_iter()
+        .map(|r| PaymentRecord {
+            amount: r.amount,
+            note: r.note,
+            approver: r.approver,
+            response_note: r.response_note,
+            response_url: r.response_url,
+            outcome: r.outcome,
+            failure_reason: r.failure_reason,
+            created_at: UNIX_EPOCH + Duration::from_secs(r.created_at as u64),
+            payment_id: r.payment_id.and_then(|id| id.parse().ok()),
+        })
+        .collect();
+
+        Ok(payments)
+    }
+
+    async fn payments_with_id(&self, payment_id: &PaymentId) -> Result<Vec<PaymentRecord>> {
+        let payment_id = payment_id.to_string();
+        let payments = sqlx::query!(
+            r#"
+            SELECT
+                amount AS "amount!",
+                note AS "note!",
+                approver AS "approver!",
+                response_note AS "response_note?",
+                response_url AS "response_url?",
+                outcome AS "outcome!: PaymentOutcome",
+                failure_reason AS "failure_reason?",
+                created_at AS "created_at!",
+                payment_id AS "payment_id?"
+            FROM payments
+            WHERE payment_id = ?
+            ORDER BY id
+            "#,
+            payment_id,
+        )
+        .fetch_all(self)
+        .await?
+        .into_iter()
+        .map(|r| PaymentRecord {
+            amount: r.amount,
+            note: r.note,
+            approver: r.approver,
+            response_note: r.response_note,
+            response_url: r.response_url,
+            outcome: r.outcome,
+            failure_reason: r.failure_reason,
+            created_at: UNIX_EPOCH + Duration::from_secs(r.created_at as u64),
+            payment_id: r.payment_id.and_then(|id| id.parse().ok()),
+        })
+        .collect();
+
+        Ok(payments)
+    }
+
+    async fn insert_webhook_event(&self, payload: &str) -> Result<i64> {
+        Ok(sqlx::query!(
+            r#"
+            INSERT INTO webhook_events (payload)
+            VALUES (?)
+            RETURNING id AS "id!: i64"
+            "#,
+            payload
+        )
+        .fetch_one(self)
+        .await?
+        .id)
+    }
+
+    async fn delete_webhook_event(&self, id: i64) -> Result<()> {
+        sqlx::query!("DELETE FROM webhook_events WHERE id = ?", id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn undelivered_webhook_events(&self) -> Result<Vec<(i64, String)>> {
+        Ok(sqlx::query!(
+            r#"SELECT id AS "id!", payload AS "payload!" FROM webhook_events ORDER BY id"#
+        )
+        .fetch_all(self)
+        .await?
+        .into_iter()
+        .map(|r| (r.id, r.payload))
+        .collect())
+    }
+
+    async fn recent_payments(&self, now: SystemTime) -> Result<RecentPayments> {
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+        let rate_window_start = pay::window_start(now, pay::RATE_WINDOW) as i64;
+        let volume_window_start = pay::window_start(now, pay::VOLUME_WINDOW) as i64;
+
+        // The rate window lies within the volume window, so both are counted from the latter
+        let recent = sqlx::query!(
+            r#"
+            SELECT
+                COALESCE(SUM(created_at > ?), 0) AS "count!: i64",
+                COALESCE(SUM(MAX(amount, 0)), 0) AS "volume!: i64"
+            FROM payment_events
+            WHERE created_at > ?
+            "#,
+            rate_window_start,
+            volume_window_start,
+        )
+        .fetch_one(self)
+        .await?;
+
+        Ok(RecentPayments {
+            count: recent.count as u64,
+            volume: recent.volume as u64,
+        })
+    }
+
     async fn fetch_or_create_config(
         &self,
         rng: &mut StdRng,
@@ -302,15 +944,56 @@ impl QueryMerchant for SqlitePool {
                 merchant_deposit,
                 customer_deposit,
                 status,
-                closing_balances
+                closing_balances,
+                status_updated_at
+            )
+            VALUES (?, ?, ?, ?, ?, ?, strftime('%s', 'now'))",
+            channel_id,
+            contract_id,
+            merchant_deposit,
+            customer_deposit,
+            ChannelStatus::Originated,
+            default_balances,
+        )
+        .execute(self)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn record_verified_contract(
+        &self,
+        channel_id: &ChannelId,
+        contract_id: &ContractId,
+        merchant_deposit: &MerchantBalance,
+        customer_deposit: &CustomerBalance,
+        customer_funding_address: &TezosFundingAddress,
+        customer_public_key: &TezosPublicKey,
+    ) -> Result<()> {
+        let default_balances = ClosingBalances::default();
+        let customer_funding_address = customer_funding_address.to_base58check();
+        let customer_public_key = customer_public_key.to_base58check();
+        sqlx::query!(
+            "INSERT INTO merchant_channels (
+                channel_id,
+                contract_id,
+                merchant_deposit,
+                customer_deposit,
+                status,
+                closing_balances,
+                customer_funding_address,
+                customer_public_key,
+                status_updated_at
             )
-            VALUES (?, ?, ?, ?, ?, ?)",
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, strftime('%s', 'now'))",
             channel_id,
             contract_id,
             merchant_deposit,
             customer_deposit,
             ChannelStatus::Originated,
             default_balances,
+            customer_funding_address,
+            customer_public_key,
         )
         .execute(self)
         .await?;
@@ -324,49 +1007,273 @@ impl QueryMerchant for SqlitePool {
         expected: &ChannelStatus,
         new: &ChannelStatus,
     ) -> Result<()> {
-        // TODO: This should return a different error when the CAS fails
-        let mut transaction = self.begin().await?;
-
-        // Find out the current status
-        let result: Option<ChannelStatus> = sqlx::query!(
-            r#"
-            SELECT status AS "status: Option<ChannelStatus>"
-            FROM merchant_channels
-            WHERE channel_id = ?
-            "#,
+        // Update the status only if it is currently the expected status, in a single statement so
+        // that concurrent updates can't both succeed
+        let updated = sqlx::query!(
+            "UPDATE merchant_channels
+            SET status = ?, status_updated_at = strftime('%s', 'now')
+            WHERE channel_id = ? AND status = ?",
+            new,
             channel_id,
+            expected,
         )
-        .fetch_one(&mut transaction)
+        .execute(self)
         .await?
-        .status;
-
-        // Only if the current status is what was expected, update the status to the new status
-        match result {
-            None => Err(Error::ChannelNotFound(*channel_id)),
-            Some(ref current) if current == expected => {
-                sqlx::query!(
-                    "UPDATE merchant_channels
-                    SET status = ?
-                    WHERE channel_id = ?",
-                    new,
-                    channel_id
-                )
-                .execute(&mut transaction)
-                .await?;
+        .rows_affected();
 
-                transaction.commit().await?;
-                Ok(())
-            }
-            Some(unexpected_status) => Err(Error::UnexpectedChannelStatus {
+        if updated == 0 {
+            // Either the channel doesn't exist, or it's in some other status
+            return Err(Error::UnexpectedChannelStatus {
                 channel_id: *channel_id,
                 expected: vec![*expected],
-                found: unexpected_status,
-            }),
+                found: self.channel_status(channel_id).await?,
+            });
         }
+
+        Ok(())
     }
 
-    async fn update_status_to_pending_close(&self, channel_id: &ChannelId) -> Result<()> {
-        let mut transaction = self.begin().await?;
+    async fn record_customer_funding(
+        &self,
+        channel_id: &ChannelId,
+        funding_operation: &FundingOperation,
+    ) -> Result<()> {
+        let result = sqlx::query!(
+            "UPDATE merchant_channels
+            SET customer_funding_operation = ?
+            WHERE channel_id = ?",
+            funding_operation,
+            channel_id,
+        )
+        .execute(self)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::ChannelNotFound(*channel_id));
+        }
+
+        Ok(())
+    }
+
+    async fn record_dispute_operation(&self, channel_id: &ChannelId, hash: &str) -> Result<()> {
+        let result = sqlx::query!(
+            "UPDATE merchant_channels
+            SET dispute_operation = ?
+            WHERE channel_id = ?",
+            hash,
+            channel_id,
+        )
+        .execute(self)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::ChannelNotFound(*channel_id));
+        }
+
+        Ok(())
+    }
+
+    async fn dispute_operation(&self, channel_id: &ChannelId) -> Result<Option<String>> {
+        Ok(sqlx::query!(
+            "SELECT dispute_operation FROM merchant_channels WHERE channel_id = ?",
+            channel_id
+        )
+        .fetch_optional(self)
+        .await?
+        .ok_or(Error::ChannelNotFound(*channel_id))?
+        .dispute_operation)
+    }
+
+    async fn record_customer_funding_address(
+        &self,
+        channel_id: &ChannelId,
+        funding_address: &TezosFundingAddress,
+    ) -> Result<()> {
+        let funding_address = funding_address.to_base58check();
+        let result = sqlx::query!(
+            "UPDATE merchant_channels
+            SET customer_funding_address = ?
+            WHERE channel_id = ?",
+            funding_address,
+            channel_id,
+        )
+        .execute(self)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::ChannelNotFound(*channel_id));
+        }
+
+        Ok(())
+    }
+
+    async fn customer_funding_address(
+        &self,
+        channel_id: &ChannelId,
+    ) -> Result<Option<TezosFundingAddress>> {
+        let mut results = sqlx::query!(
+            r#"
+            SELECT customer_funding_address
+            FROM merchant_channels
+            WHERE channel_id = ?
+            LIMIT 2
+            "#,
+            channel_id
+        )
+        .fetch_all(self)
+        .await?
+        .into_iter();
+
+        let funding_address = match results.next() {
+            None => return Err(Error::ChannelNotFound(*channel_id)),
+            Some(record) => record.customer_funding_address,
+        };
+
+        if results.next().is_some() {
+            return Err(Error::ChannelIdCollision(channel_id.to_string()));
+        }
+
+        funding_address
+            .map(|address| {
+                TezosFundingAddress::from_base58check(&address)
+                    .map_err(|_| Error::InvalidFundingAddress(*channel_id))
+            })
+            .transpose()
+    }
+
+    async fn record_customer_public_key(
+        &self,
+        channel_id: &ChannelId,
+        public_key: &TezosPublicKey,
+    ) -> Result<()> {
+        let public_key = public_key.to_base58check();
+        let result = sqlx::query!(
+            "UPDATE merchant_channels
+            SET customer_public_key = ?
+            WHERE channel_id = ?",
+            public_key,
+            channel_id,
+        )
+        .execute(self)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::ChannelNotFound(*channel_id));
+        }
+
+        Ok(())
+    }
+
+    async fn customer_public_key(&self, channel_id: &ChannelId) -> Result<Option<TezosPublicKey>> {
+        let mut results = sqlx::query!(
+            r#"
+            SELECT customer_public_key
+            FROM merchant_channels
+            WHERE channel_id = ?
+            LIMIT 2
+            "#,
+            channel_id
+        )
+        .fetch_all(self)
+        .await?
+        .into_iter();
+
+        let public_key = match results.next() {
+            None => return Err(Error::ChannelNotFound(*channel_id)),
+            Some(record) => record.customer_public_key,
+        };
+
+        if results.next().is_some() {
+            return Err(Error::ChannelIdCollision(channel_id.to_string()));
+        }
+
+        public_key
+            .map(|public_key| {
+                TezosPublicKey::from_base58check(&public_key)
+                    .map_err(|_| Error::InvalidPublicKey(*channel_id))
+            })
+            .transpose()
+    }
+
+    async fn set_channel_blocked(&self, channel_id: &ChannelId, blocked: bool) -> Result<()> {
+        let result = sqlx::query!(
+            "UPDATE merchant_channels
+            SET blocked = ?
+            WHERE channel_id = ?",
+            blocked,
+            channel_id,
+        )
+        .execute(self)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::ChannelNotFound(*channel_id));
+        }
+
+        Ok(())
+    }
+
+    async fn set_attention_reason(
+        &self,
+        channel_id: &ChannelId,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        let result = sqlx::query!(
+            "UPDATE merchant_channels
+            SET attention_reason = ?
+            WHERE channel_id = ?",
+            reason,
+            channel_id,
+        )
+        .execute(self)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::ChannelNotFound(*channel_id));
+        }
+
+        Ok(())
+    }
+
+    async fn attention_reason(&self, channel_id: &ChannelId) -> Result<Option<String>> {
+        Ok(sqlx::query!(
+            "SELECT attention_reason FROM merchant_channels WHERE channel_id = ?",
+            channel_id
+        )
+        .fetch_optional(self)
+        .await?
+        .ok_or(Error::ChannelNotFound(*channel_id))?
+        .attention_reason)
+    }
+
+    async fn customer_funding(&self, channel_id: &ChannelId) -> Result<Option<FundingOperation>> {
+        let mut results = sqlx::query!(
+            r#"
+            SELECT customer_funding_operation AS "customer_funding_operation: FundingOperation"
+            FROM merchant_channels
+            WHERE channel_id = ?
+            LIMIT 2
+            "#,
+            channel_id
+        )
+        .fetch_all(self)
+        .await?
+        .into_iter();
+
+        let funding_operation = match results.next() {
+            None => return Err(Error::ChannelNotFound(*channel_id)),
+            Some(record) => record.customer_funding_operation,
+        };
+
+        if results.next().is_some() {
+            return Err(Error::ChannelIdCollision(channel_id.to_string()));
+        }
+
+        Ok(funding_operation)
+    }
+
+    async fn update_status_to_pending_close(&self, channel_id: &ChannelId) -> Result<()> {
+        let mut transaction = self.begin().await?;
 
         // Find out current status
         let result: Option<ChannelStatus> = sqlx::query!(
@@ -390,7 +1297,7 @@ impl QueryMerchant for SqlitePool {
             | Some(ChannelStatus::PendingMutualClose) => {
                 sqlx::query!(
                     "UPDATE merchant_channels
-                    SET status = ?
+                    SET status = ?, status_updated_at = strftime('%s', 'now')
                     WHERE channel_id = ?",
                     ChannelStatus::PendingClose,
                     channel_id
@@ -503,7 +1410,9 @@ impl QueryMerchant for SqlitePool {
                 contract_id AS "contract_id: ContractId",
                 merchant_deposit AS "merchant_deposit: MerchantBalance",
                 customer_deposit AS "customer_deposit: CustomerBalance",
-                closing_balances AS "closing_balances: ClosingBalances"
+                closing_balances AS "closing_balances: ClosingBalances",
+                blocked AS "blocked: bool",
+                status_updated_at AS "status_updated_at!"
             FROM merchant_channels
             "#
         )
@@ -517,6 +1426,163 @@ impl QueryMerchant for SqlitePool {
             merchant_deposit: r.merchant_deposit,
             customer_deposit: r.customer_deposit,
             closing_balances: r.closing_balances,
+            blocked: r.blocked,
+            status_updated_at: UNIX_EPOCH + Duration::from_secs(r.status_updated_at as u64),
+        })
+        .collect();
+
+        Ok(channels)
+    }
+
+    async fn get_channel(&self, channel_id: &ChannelId) -> Result<ChannelDetails> {
+        let channel = sqlx::query!(
+            r#"
+            SELECT
+                channel_id AS "channel_id: ChannelId",
+                status as "status: ChannelStatus",
+                contract_id AS "contract_id: ContractId",
+                merchant_deposit AS "merchant_deposit: MerchantBalance",
+                customer_deposit AS "customer_deposit: CustomerBalance",
+                closing_balances AS "closing_balances: ClosingBalances",
+                blocked AS "blocked: bool",
+                status_updated_at AS "status_updated_at!"
+            FROM merchant_channels
+            WHERE channel_id = ?
+            "#,
+            channel_id
+        )
+        .fetch_optional(self)
+        .await?
+        .ok_or(Error::ChannelNotFound(*channel_id))?;
+
+        Ok(ChannelDetails {
+            channel_id: channel.channel_id,
+            status: channel.status,
+            contract_id: channel.contract_id,
+            merchant_deposit: channel.merchant_deposit,
+            customer_deposit: channel.customer_deposit,
+            closing_balances: channel.closing_balances,
+            blocked: channel.blocked,
+            status_updated_at: UNIX_EPOCH + Duration::from_secs(channel.status_updated_at as u64),
+        })
+    }
+
+    async fn channels_with_status(&self, status: &ChannelStatus) -> Result<Vec<ChannelDetails>> {
+        let channels = sqlx::query!(
+            r#"
+            SELECT
+                channel_id AS "channel_id: ChannelId",
+                status as "status: ChannelStatus",
+                contract_id AS "contract_id: ContractId",
+                merchant_deposit AS "merchant_deposit: MerchantBalance",
+                customer_deposit AS "customer_deposit: CustomerBalance",
+                closing_balances AS "closing_balances: ClosingBalances",
+                blocked AS "blocked: bool",
+                status_updated_at AS "status_updated_at!"
+            FROM merchant_channels
+            WHERE status = ?
+            "#,
+            status
+        )
+        .fetch_all(self)
+        .await?
+        .into_iter()
+        .map(|r| ChannelDetails {
+            channel_id: r.channel_id,
+            status: r.status,
+            contract_id: r.contract_id,
+            merchant_deposit: r.merchant_deposit,
+            customer_deposit: r.customer_deposit,
+            closing_balances: r.closing_balances,
+            blocked: r.blocked,
+            status_updated_at: UNIX_EPOCH + Duration::from_secs(r.status_updated_at as u64),
+        })
+        .collect();
+
+        Ok(channels)
+    }
+
+    async fn get_stalled_establishments(
+        &self,
+        stalled_since: SystemTime,
+    ) -> Result<Vec<ChannelDetails>> {
+        let stalled_since = stalled_since
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs() as i64);
+
+        let channels = sqlx::query!(
+            r#"
+            SELECT
+                channel_id AS "channel_id: ChannelId",
+                status as "status: ChannelStatus",
+                contract_id AS "contract_id: ContractId",
+                merchant_deposit AS "merchant_deposit: MerchantBalance",
+                customer_deposit AS "customer_deposit: CustomerBalance",
+                closing_balances AS "closing_balances: ClosingBalances",
+                blocked AS "blocked: bool",
+                status_updated_at AS "status_updated_at!"
+            FROM merchant_channels
+            WHERE (status = ? OR status = ?) AND status_updated_at <= ?
+            "#,
+            ChannelStatus::CustomerFunded,
+            ChannelStatus::MerchantFunded,
+            stalled_since,
+        )
+        .fetch_all(self)
+        .await?
+        .into_iter()
+        .map(|r| ChannelDetails {
+            channel_id: r.channel_id,
+            status: r.status,
+            contract_id: r.contract_id,
+            merchant_deposit: r.merchant_deposit,
+            customer_deposit: r.customer_deposit,
+            closing_balances: r.closing_balances,
+            blocked: r.blocked,
+            status_updated_at: UNIX_EPOCH + Duration::from_secs(r.status_updated_at as u64),
+        })
+        .collect();
+
+        Ok(channels)
+    }
+
+    async fn get_long_active_channels(
+        &self,
+        active_since: SystemTime,
+    ) -> Result<Vec<ChannelDetails>> {
+        let active_since = active_since
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs() as i64);
+
+        let channels = sqlx::query!(
+            r#"
+            SELECT
+                channel_id AS "channel_id: ChannelId",
+                status as "status: ChannelStatus",
+                contract_id AS "contract_id: ContractId",
+                merchant_deposit AS "merchant_deposit: MerchantBalance",
+                customer_deposit AS "customer_deposit: CustomerBalance",
+                closing_balances AS "closing_balances: ClosingBalances",
+                blocked AS "blocked: bool",
+                status_updated_at AS "status_updated_at!"
+            FROM merchant_channels
+            WHERE status = ? AND status_updated_at <= ?
+            "#,
+            ChannelStatus::Active,
+            active_since,
+        )
+        .fetch_all(self)
+        .await?
+        .into_iter()
+        .map(|r| ChannelDetails {
+            channel_id: r.channel_id,
+            status: r.status,
+            contract_id: r.contract_id,
+            merchant_deposit: r.merchant_deposit,
+            customer_deposit: r.customer_deposit,
+            closing_balances: r.closing_balances,
+            blocked: r.blocked,
+            status_updated_at: UNIX_EPOCH + Duration::from_secs(r.status_updated_at as u64),
         })
         .collect();
 
@@ -642,7 +1708,9 @@ impl QueryMerchant for SqlitePool {
                 contract_id AS "contract_id: ContractId",
                 merchant_deposit AS "merchant_deposit: MerchantBalance",
                 customer_deposit AS "customer_deposit: CustomerBalance",
-                closing_balances AS "closing_balances: ClosingBalances"
+                closing_balances AS "closing_balances: ClosingBalances",
+                blocked AS "blocked: bool",
+                status_updated_at AS "status_updated_at!"
             FROM merchant_channels
             WHERE channel_id LIKE ?
             LIMIT 2
@@ -662,6 +1730,9 @@ impl QueryMerchant for SqlitePool {
                 merchant_deposit: channel.merchant_deposit,
                 customer_deposit: channel.customer_deposit,
                 closing_balances: channel.closing_balances,
+                blocked: channel.blocked,
+                status_updated_at: UNIX_EPOCH
+                    + Duration::from_secs(channel.status_updated_at as u64),
             },
         };
 
@@ -680,7 +1751,7 @@ impl<Q: QueryMerchant + ?Sized> QueryMerchantExt for Q {
         revocation: &RevocationLock,
     ) -> Result<Vec<Option<RevocationSecret>>> {
         // Call insert_revocation with None
-        self.insert_revocation(revocation, None).await
+        self.insert_revocation(revocation, None, None).await
     }
 
     async fn insert_revocation_pair(
@@ -691,16 +1762,105 @@ impl<Q: QueryMerchant + ?Sized> QueryMerchantExt for Q {
         self.insert_revocation(
             &revocation_pair.revocation_lock(),
             Some(&revocation_pair.revocation_secret()),
+            None,
         )
         .await
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::database::SqlitePoolOptions;
-    use {rand::SeedableRng, strum::IntoEnumIterator, tezedge::OriginatedAddress};
+
+    async fn insert_revocation_pair_for_payment(
+        &self,
+        revocation_pair: &RevocationPair,
+        payment: &PaymentRecord,
+    ) -> Result<Vec<Option<RevocationSecret>>> {
+        self.insert_revocation(
+            &revocation_pair.revocation_lock(),
+            Some(&revocation_pair.revocation_secret()),
+            Some(payment),
+        )
+        .await
+    }
+}
+
+/// Insert a payment into the payment history, on the given connection or transaction.
+async fn insert_payment(
+    connection: &mut sqlx::SqliteConnection,
+    payment: &PaymentRecord,
+) -> Result<()> {
+    let created_at = payment
+        .created_at
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs() as i64);
+    let payment_id = payment.payment_id.map(|id| id.to_string());
+
+    // A resumed payment completes the attempt recorded as failed when its session was
+    // interrupted, which started at the same time
+    if payment.outcome == PaymentOutcome::Completed {
+        let failed = PaymentOutcome::Failed;
+        let updated = sqlx::query!(
+            r#"
+            UPDATE payments
+            SET outcome = ?, response_note = ?, response_url = ?, failure_reason = NULL
+            WHERE id = (
+                SELECT id
+                FROM payments
+                WHERE payment_id = ? AND created_at = ? AND amount = ? AND outcome = ?
+                ORDER BY id DESC
+                LIMIT 1
+            )
+            "#,
+            payment.outcome,
+            payment.response_note,
+            payment.response_url,
+            payment_id,
+            created_at,
+            payment.amount,
+            failed,
+        )
+        .execute(&mut *connection)
+        .await?
+        .rows_affected()
+            > 0;
+        if updated {
+            return Ok(());
+        }
+    }
+
+    sqlx::query!(
+        r#"
+        INSERT INTO payments (
+            amount,
+            note,
+            approver,
+            response_note,
+            response_url,
+            outcome,
+            failure_reason,
+            created_at,
+            payment_id
+        )
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
+        payment.amount,
+        payment.note,
+        payment.approver,
+        payment.response_note,
+        payment.response_url,
+        payment.outcome,
+        payment.failure_reason,
+        created_at,
+        payment_id,
+    )
+    .execute(connection)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{PgPool, SqlitePoolOptions};
+    use {rand::SeedableRng, strum::IntoEnumIterator, tezedge::OriginatedAddress};
 
     use zkabacus_crypto::internal::{test_new_nonce, test_new_revocation_pair};
     use zkabacus_crypto::{CustomerRandomness, MerchantRandomness};
@@ -717,15 +1877,92 @@ mod tests {
         Ok(conn)
     }
 
-    #[tokio::test]
-    async fn test_migrate() -> Result<()> {
-        create_migrated_db().await?;
+    /// Create and migrate a fresh Postgres database on the server given by
+    /// `ZEEKOE_TEST_POSTGRES_URL`, whose user must be able to create databases, or `None` if the
+    /// variable isn't set.
+    async fn create_migrated_postgres_db() -> Result<Option<PgPool>> {
+        let url = match std::env::var("ZEEKOE_TEST_POSTGRES_URL") {
+            Ok(url) => url,
+            Err(_) => return Ok(None),
+        };
+
+        // Each test gets a database of its own
+        let name = format!("zeekoe_test_{}", uuid::Uuid::new_v4().to_simple());
+        let server = PgPool::connect(&url).await?;
+        sqlx::query(&format!("CREATE DATABASE {}", name))
+            .execute(&server)
+            .await?;
+        server.close().await;
+
+        let mut url = url::Url::parse(&url).expect("invalid ZEEKOE_TEST_POSTGRES_URL");
+        url.set_path(&name);
+        let conn = PgPool::connect(url.as_str()).await?;
+        conn.migrate().await?;
+        Ok(Some(conn))
+    }
+
+    /// Run each of the given tests against SQLite, and against Postgres if
+    /// `ZEEKOE_TEST_POSTGRES_URL` is set.
+    macro_rules! backend_tests {
+        ($($test:ident),* $(,)?) => {
+            mod sqlite {
+                $(
+                    #[tokio::test]
+                    async fn $test() -> super::Result<()> {
+                        super::$test(&super::create_migrated_db().await?).await
+                    }
+                )*
+            }
+
+            mod postgres {
+                $(
+                    #[tokio::test]
+                    async fn $test() -> super::Result<()> {
+                        match super::create_migrated_postgres_db().await? {
+                            Some(conn) => super::$test(&conn).await,
+                            None => Ok(()),
+                        }
+                    }
+                )*
+            }
+        };
+    }
+
+    backend_tests!(
+        test_migrate,
+        test_insert_nonce,
+        test_insert_revocation,
+        test_payment_history,
+        test_payments_with_id,
+        test_resumed_payment_updates_record,
+        test_dispute_revoked_lock,
+        test_record_dispute_operation,
+        test_attention_reason,
+        test_merchant_statuses,
+        test_merchant_config,
+        test_merchant_channels,
+        test_failed_compare_and_swap,
+        test_concurrent_compare_and_swap,
+        test_payment_event_limits,
+        test_block_channel,
+        test_record_customer_funding,
+        test_record_customer_funding_address,
+        test_record_customer_public_key,
+        test_establish_before_channel_exists,
+        test_stalled_establishments,
+        test_long_active_channels,
+        test_closing_balance_update,
+        test_webhook_events,
+        test_pay_sessions,
+    );
+
+    async fn test_migrate(conn: &dyn QueryMerchant) -> Result<()> {
+        // Migrating an already migrated database does nothing
+        conn.migrate().await?;
         Ok(())
     }
 
-    #[tokio::test]
-    async fn test_insert_nonce() -> Result<()> {
-        let conn = create_migrated_db().await?;
+    async fn test_insert_nonce(conn: &dyn QueryMerchant) -> Result<()> {
         let mut rng = rand::thread_rng();
 
         let nonce = test_new_nonce(&mut rng);
@@ -737,9 +1974,62 @@ mod tests {
         Ok(())
     }
 
-    #[tokio::test]
-    async fn test_insert_revocation() -> Result<()> {
-        let conn = create_migrated_db().await?;
+    async fn test_pay_sessions(conn: &dyn QueryMerchant) -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let started = UNIX_EPOCH + Duration::from_secs(1_634_600_000);
+        let payment_id = PaymentId::generate();
+        let session = |created_at| PaySession {
+            transcript_digest: vec![7; 32],
+            amount: -5,
+            note: "refund".to_string(),
+            pay_proof: vec![1, 2, 3],
+            created_at,
+            payment_id: Some(payment_id),
+            response_note: None,
+            response_url: Some("https://example.com/result".to_string()),
+        };
+
+        // A session is kept for a fresh nonce only
+        let nonce = test_new_nonce(&mut rng);
+        assert!(
+            conn.insert_nonce_for_session(&nonce, &session(started))
+                .await?
+        );
+        assert!(
+            !conn
+                .insert_nonce_for_session(&nonce, &session(started + Duration::from_secs(1)))
+                .await?
+        );
+        assert!(!conn.insert_nonce(&nonce).await?);
+        assert_eq!(
+            conn.pay_session(&nonce, started + Duration::from_secs(60))
+                .await?,
+            Some(session(started))
+        );
+        assert_eq!(
+            conn.pay_session(&test_new_nonce(&mut rng), started).await?,
+            None
+        );
+
+        // It can't be resumed once the window has passed, and is forgotten when a later session
+        // starts
+        let later = started + pay::RESUME_WINDOW;
+        assert_eq!(conn.pay_session(&nonce, later).await?, None);
+        let later_nonce = test_new_nonce(&mut rng);
+        assert!(
+            conn.insert_nonce_for_session(&later_nonce, &session(later))
+                .await?
+        );
+        assert_eq!(conn.pay_session(&nonce, started).await?, None);
+        assert_eq!(
+            conn.pay_session(&later_nonce, later).await?,
+            Some(session(later))
+        );
+
+        Ok(())
+    }
+
+    async fn test_insert_revocation(conn: &dyn QueryMerchant) -> Result<()> {
         let mut rng = rand::thread_rng();
 
         // Each time we insert a lock (& optional secret), it returns all previously
@@ -767,12 +2057,228 @@ mod tests {
         Ok(())
     }
 
-    #[tokio::test]
-    async fn test_merchant_statuses() -> Result<()> {
-        let conn = create_migrated_db().await?;
+    fn payment_record(amount: i64, outcome: PaymentOutcome) -> PaymentRecord {
+        PaymentRecord {
+            amount,
+            note: format!("payment of {}", amount),
+            approver: "automatic".into(),
+            response_note: None,
+            response_url: None,
+            outcome,
+            failure_reason: match outcome {
+                PaymentOutcome::Completed => None,
+                _ => Some("no reason".into()),
+            },
+            created_at: UNIX_EPOCH + Duration::from_secs(1_634_600_000 + amount as u64),
+            payment_id: Some(PaymentId::generate()),
+        }
+    }
+
+    async fn test_payment_history(conn: &dyn QueryMerchant) -> Result<()> {
+        let mut rng = rand::thread_rng();
+
+        let rejected = payment_record(1, PaymentOutcome::Rejected);
+        conn.record_payment(&rejected).await?;
+
+        // A completed payment is recorded along with its revocation...
+        let pair = test_new_revocation_pair(&mut rng);
+        let completed = payment_record(2, PaymentOutcome::Completed);
+        conn.insert_revocation_pair_for_payment(&pair, &completed)
+            .await?;
+
+        // ...unless its revocation lock was reused
+        let reused = payment_record(3, PaymentOutcome::Completed);
+        let prior = conn
+            .insert_revocation_pair_for_payment(&pair, &reused)
+            .await?;
+        assert_eq!(prior.len(), 1);
+
+        let failed = payment_record(4, PaymentOutcome::Failed);
+        conn.record_payment(&failed).await?;
+
+        // The history is most recent first, and can be paged through
+        assert_eq!(
+            conn.payment_history(10, 0).await?,
+            vec![failed.clone(), completed.clone(), rejected.clone()]
+        );
+        assert_eq!(conn.payment_history(1, 0).await?, vec![failed]);
+        assert_eq!(
+            conn.payment_history(10, 1).await?,
+            vec![completed, rejected]
+        );
+        assert!(conn.payment_history(10, 3).await?.is_empty());
+
+        Ok(())
+    }
+
+    async fn test_payments_with_id(conn: &dyn QueryMerchant) -> Result<()> {
+        let mut rng = rand::thread_rng();
+
+        // The customer retried a rejected payment with the same ID, and it completed
+        let rejected = payment_record(1, PaymentOutcome::Rejected);
+        let payment_id = rejected.payment_id.unwrap();
+        conn.record_payment(&rejected).await?;
+        let completed = PaymentRecord {
+            payment_id: Some(payment_id),
+            ..payment_record(2, PaymentOutcome::Completed)
+        };
+        conn.insert_revocation_pair_for_payment(&test_new_revocation_pair(&mut rng), &completed)
+            .await?;
+
+        // Payments with other IDs, or none at all, aren't found
+        conn.record_payment(&payment_record(3, PaymentOutcome::Completed))
+            .await?;
+        conn.record_payment(&PaymentRecord {
+            payment_id: None,
+            ..payment_record(4, PaymentOutcome::Failed)
+        })
+        .await?;
+
+        assert_eq!(
+            conn.payments_with_id(&payment_id).await?,
+            vec![rejected, completed]
+        );
+        assert!(conn
+            .payments_with_id(&PaymentId::generate())
+            .await?
+            .is_empty());
+
+        Ok(())
+    }
+
+    async fn test_resumed_payment_updates_record(conn: &dyn QueryMerchant) -> Result<()> {
+        let mut rng = rand::thread_rng();
+
+        // The session was interrupted, so the payment was recorded as failed, as was an earlier
+        // attempt with the same ID
+        let earlier = payment_record(1, PaymentOutcome::Failed);
+        let payment_id = earlier.payment_id.unwrap();
+        conn.record_payment(&earlier).await?;
+        let interrupted = PaymentRecord {
+            amount: 1,
+            payment_id: Some(payment_id),
+            response_url: Some("https://example.com/result".into()),
+            ..payment_record(2, PaymentOutcome::Failed)
+        };
+        conn.record_payment(&interrupted).await?;
+
+        // Resuming it completes the attempt which was interrupted, rather than adding another
+        let resumed = PaymentRecord {
+            outcome: PaymentOutcome::Completed,
+            failure_reason: None,
+            ..interrupted.clone()
+        };
+        let prior = conn
+            .insert_revocation_pair_for_payment(&test_new_revocation_pair(&mut rng), &resumed)
+            .await?;
+        assert!(prior.is_empty());
+        assert_eq!(
+            conn.payments_with_id(&payment_id).await?,
+            vec![earlier, resumed]
+        );
+
+        // A payment completed in its original session is recorded as usual
+        let completed = payment_record(3, PaymentOutcome::Completed);
+        conn.insert_revocation_pair_for_payment(&test_new_revocation_pair(&mut rng), &completed)
+            .await?;
+        assert_eq!(conn.payment_history(1, 0).await?, vec![completed]);
+
+        Ok(())
+    }
 
+    async fn test_dispute_revoked_lock(conn: &dyn QueryMerchant) -> Result<()> {
+        let mut rng = rand::thread_rng();
+
+        // The customer revoked this state when they paid
+        let revoked = test_new_revocation_pair(&mut rng);
+        conn.insert_revocation_pair(&revoked).await?;
+
+        // ...then posted it in custClose anyway, so the merchant disputes with the secret it holds
+        let secret = conn
+            .revocation_secret_for(&revoked.revocation_lock())
+            .await?
+            .expect("revoked lock must be disputed");
+        assert_eq!(
+            bincode::serialize(&secret).unwrap(),
+            bincode::serialize(&revoked.revocation_secret()).unwrap()
+        );
+
+        // A lock the customer never revoked is an honest close, which can't be disputed, even if
+        // the merchant has seen the lock before
+        let latest = test_new_revocation_pair(&mut rng);
+        assert!(conn
+            .revocation_secret_for(&latest.revocation_lock())
+            .await?
+            .is_none());
+        conn.insert_revocation_lock(&latest.revocation_lock())
+            .await?;
+        assert!(conn
+            .revocation_secret_for(&latest.revocation_lock())
+            .await?
+            .is_none());
+
+        Ok(())
+    }
+
+    async fn test_record_dispute_operation(conn: &dyn QueryMerchant) -> Result<()> {
+        let channel_id = insert_new_channel(conn).await?;
+
+        assert_eq!(conn.dispute_operation(&channel_id).await?, None);
+
+        let hash = "ooFakeOperationHash";
+        conn.record_dispute_operation(&channel_id, hash).await?;
+        assert_eq!(
+            conn.dispute_operation(&channel_id).await?,
+            Some(hash.to_string())
+        );
+
+        Ok(())
+    }
+
+    async fn test_attention_reason(conn: &dyn QueryMerchant) -> Result<()> {
+        let channel_id = insert_new_channel(conn).await?;
+
+        assert_eq!(conn.attention_reason(&channel_id).await?, None);
+
+        let reason = "contract not found";
+        conn.set_attention_reason(&channel_id, Some(reason)).await?;
+        assert_eq!(
+            conn.attention_reason(&channel_id).await?,
+            Some(reason.to_string())
+        );
+
+        conn.set_attention_reason(&channel_id, None).await?;
+        assert_eq!(conn.attention_reason(&channel_id).await?, None);
+
+        Ok(())
+    }
+
+    async fn test_webhook_events(conn: &dyn QueryMerchant) -> Result<()> {
+        assert!(conn.undelivered_webhook_events().await?.is_empty());
+
+        let first = conn.insert_webhook_event("{\"event\":\"first\"}").await?;
+        let second = conn.insert_webhook_event("{\"event\":\"second\"}").await?;
+        assert_eq!(
+            conn.undelivered_webhook_events().await?,
+            vec![
+                (first, "{\"event\":\"first\"}".to_string()),
+                (second, "{\"event\":\"second\"}".to_string()),
+            ]
+        );
+
+        // Delivered events are forgotten
+        conn.delete_webhook_event(first).await?;
+        assert_eq!(
+            conn.undelivered_webhook_events().await?,
+            vec![(second, "{\"event\":\"second\"}".to_string())]
+        );
+
+        Ok(())
+    }
+
+    async fn test_merchant_statuses(conn: &dyn QueryMerchant) -> Result<()> {
         // Create channel and set its initial status.
-        let channel_id = insert_new_channel(&conn).await?;
+        let channel_id = insert_new_channel(conn).await?;
 
         // Get a list of every possible status, assuming that the first one is what channels
         // are inserted with
@@ -790,9 +2296,7 @@ mod tests {
         Ok(())
     }
 
-    #[tokio::test]
-    async fn test_merchant_config() -> Result<()> {
-        let conn = create_migrated_db().await?;
+    async fn test_merchant_config(conn: &dyn QueryMerchant) -> Result<()> {
         let mut rng = StdRng::from_entropy();
 
         let config1 = conn.fetch_or_create_config(&mut rng).await?;
@@ -815,7 +2319,7 @@ mod tests {
         Ok(())
     }
 
-    async fn insert_new_channel(conn: &SqlitePool) -> Result<ChannelId> {
+    async fn insert_new_channel(conn: &dyn QueryMerchant) -> Result<ChannelId> {
         let mut rng = StdRng::from_entropy();
 
         let cid_m = MerchantRandomness::new(&mut rng);
@@ -838,10 +2342,8 @@ mod tests {
         Ok(channel_id)
     }
 
-    #[tokio::test]
-    async fn test_merchant_channels() -> Result<()> {
-        let conn = create_migrated_db().await?;
-        let channel_id = insert_new_channel(&conn).await?;
+    async fn test_merchant_channels(conn: &dyn QueryMerchant) -> Result<()> {
+        let channel_id = insert_new_channel(conn).await?;
         conn.compare_and_swap_channel_status(
             &channel_id,
             &ChannelStatus::Originated,
@@ -849,16 +2351,416 @@ mod tests {
         )
         .await?;
 
+        let channel = conn.get_channel(&channel_id).await?;
+        assert_eq!(channel.status, ChannelStatus::CustomerFunded);
+        assert_eq!(channel.merchant_deposit.into_inner(), 5);
+
+        let funded = conn
+            .channels_with_status(&ChannelStatus::CustomerFunded)
+            .await?;
+        assert_eq!(funded.len(), 1);
+        assert_eq!(funded[0].channel_id, channel_id);
+        assert!(conn
+            .channels_with_status(&ChannelStatus::Originated)
+            .await?
+            .is_empty());
+
+        Ok(())
+    }
+
+    async fn test_failed_compare_and_swap(conn: &dyn QueryMerchant) -> Result<()> {
+        let channel_id = insert_new_channel(conn).await?;
+
+        // The channel isn't active yet, so it can't be closed
+        match conn
+            .compare_and_swap_channel_status(
+                &channel_id,
+                &ChannelStatus::Active,
+                &ChannelStatus::PendingClose,
+            )
+            .await
+        {
+            Err(Error::UnexpectedChannelStatus {
+                expected, found, ..
+            }) => {
+                assert_eq!(expected, vec![ChannelStatus::Active]);
+                assert_eq!(found, ChannelStatus::Originated);
+            }
+            other => panic!("expected failed compare-and-swap, got {:?}", other.err()),
+        }
+
+        // ...and its status is unchanged
+        assert_eq!(
+            conn.channel_status(&channel_id).await?,
+            ChannelStatus::Originated
+        );
+
+        Ok(())
+    }
+
+    async fn test_concurrent_compare_and_swap(conn: &dyn QueryMerchant) -> Result<()> {
+        let channel_id = insert_new_channel(conn).await?;
+
+        // Race several updates away from the same status: exactly one of them can win
+        let attempts = [
+            ChannelStatus::CustomerFunded,
+            ChannelStatus::MerchantFunded,
+            ChannelStatus::Active,
+            ChannelStatus::Closed,
+        ];
+        let results = futures::future::join_all(attempts.iter().map(|new| {
+            conn.compare_and_swap_channel_status(&channel_id, &ChannelStatus::Originated, new)
+        }))
+        .await;
+
+        let winners: Vec<_> = attempts
+            .iter()
+            .zip(&results)
+            .filter(|(_, result)| result.is_ok())
+            .map(|(new, _)| *new)
+            .collect();
+        assert_eq!(winners.len(), 1);
+
+        // Every loser saw the winner's status
+        for result in results.into_iter().filter(Result::is_err) {
+            match result {
+                Err(Error::UnexpectedChannelStatus { found, .. }) => assert_eq!(found, winners[0]),
+                other => panic!("unexpected result {:?}", other.err()),
+            }
+        }
+        assert_eq!(conn.channel_status(&channel_id).await?, winners[0]);
+
         Ok(())
     }
 
-    #[tokio::test]
-    async fn test_closing_balance_update() -> Result<()> {
-        // set up new db
-        let conn = create_migrated_db().await?;
+    async fn test_payment_event_limits(conn: &dyn QueryMerchant) -> Result<()> {
+        let now = SystemTime::now();
+        let limits = PaymentLimits {
+            max_payments_per_minute: Some(3),
+            max_volume_per_day: Some(10),
+            ..PaymentLimits::default()
+        };
+
+        // Race several payments for the last two places in the rate limit: exactly two can fit
+        conn.insert_payment_event(1, now - Duration::from_secs(30), &limits)
+            .await?;
+        let results =
+            futures::future::join_all((0..4).map(|_| conn.insert_payment_event(1, now, &limits)))
+                .await;
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 2);
+        for result in results.into_iter().filter(Result::is_err) {
+            match result {
+                Err(Error::PaymentLimitExceeded(LimitExceeded::PaymentRate(3))) => {}
+                other => panic!("unexpected result {:?}", other.err()),
+            }
+        }
+        assert_eq!(
+            conn.recent_payments(now).await?,
+            RecentPayments {
+                count: 3,
+                volume: 3
+            }
+        );
+
+        // A minute later, the rate window has moved on, but the volume window hasn't
+        let later = now + Duration::from_secs(61);
+        assert_eq!(
+            conn.recent_payments(later).await?,
+            RecentPayments {
+                count: 0,
+                volume: 3
+            }
+        );
+        match conn.insert_payment_event(8, later, &limits).await {
+            Err(Error::PaymentLimitExceeded(LimitExceeded::DailyVolume(10))) => {}
+            other => panic!("unexpected result {:?}", other.err()),
+        }
+
+        // Refunds don't add to the volume
+        conn.insert_payment_event(-5, later, &limits).await?;
+        conn.insert_payment_event(7, later, &limits).await?;
+        assert_eq!(
+            conn.recent_payments(later).await?,
+            RecentPayments {
+                count: 2,
+                volume: 10
+            }
+        );
+
+        Ok(())
+    }
+
+    async fn test_block_channel(conn: &dyn QueryMerchant) -> Result<()> {
+        let channel_id = insert_new_channel(conn).await?;
+
+        let blocked = |channels: Vec<ChannelDetails>| channels.iter().any(|c| c.blocked);
+        assert!(!blocked(conn.get_channels().await?));
+
+        conn.set_channel_blocked(&channel_id, true).await?;
+        assert!(blocked(conn.get_channels().await?));
+
+        conn.set_channel_blocked(&channel_id, false).await?;
+        assert!(!blocked(conn.get_channels().await?));
+
+        // Blocking a channel that doesn't exist fails
+        let mut rng = StdRng::from_entropy();
+        let pk = KeyPair::new(&mut rng).public_key().clone();
+        let unknown = ChannelId::new(
+            MerchantRandomness::new(&mut rng),
+            CustomerRandomness::new(&mut rng),
+            &pk,
+            &[],
+            &[],
+        );
+        assert!(matches!(
+            conn.set_channel_blocked(&unknown, true).await,
+            Err(Error::ChannelNotFound(_))
+        ));
+
+        Ok(())
+    }
+
+    async fn test_record_customer_funding(conn: &dyn QueryMerchant) -> Result<()> {
+        let channel_id = insert_new_channel(conn).await?;
+
+        // A new channel has no record of its funding
+        assert_eq!(conn.customer_funding(&channel_id).await?, None);
+
+        let funding_operation = FundingOperation::new(
+            "ooYDbsZhDgQ8gRpj8DZ4ETD8wzUiCdbzDG2hjjBR6PpR1BWBVvy".to_string(),
+            123,
+        );
+        conn.record_customer_funding(&channel_id, &funding_operation)
+            .await?;
+        assert_eq!(
+            conn.customer_funding(&channel_id).await?,
+            Some(funding_operation.clone())
+        );
+
+        // Recording funding for a channel that doesn't exist fails
+        let mut rng = StdRng::from_entropy();
+        let pk = KeyPair::new(&mut rng).public_key().clone();
+        let unknown_channel_id = ChannelId::new(
+            MerchantRandomness::new(&mut rng),
+            CustomerRandomness::new(&mut rng),
+            &pk,
+            &[],
+            &[],
+        );
+        assert!(matches!(
+            conn.record_customer_funding(&unknown_channel_id, &funding_operation)
+                .await,
+            Err(Error::ChannelNotFound(_))
+        ));
+
+        Ok(())
+    }
+
+    async fn test_record_customer_funding_address(conn: &dyn QueryMerchant) -> Result<()> {
+        let channel_id = insert_new_channel(conn).await?;
+
+        assert_eq!(conn.customer_funding_address(&channel_id).await?, None);
+
+        let funding_address =
+            TezosFundingAddress::from_base58check("tz1iKxZpa5x1grZyN2Uw9gERXJJPMyG22Sqp").unwrap();
+        conn.record_customer_funding_address(&channel_id, &funding_address)
+            .await?;
+        assert_eq!(
+            conn.customer_funding_address(&channel_id).await?,
+            Some(funding_address)
+        );
+
+        Ok(())
+    }
+
+    async fn test_record_customer_public_key(conn: &dyn QueryMerchant) -> Result<()> {
+        let channel_id = insert_new_channel(conn).await?;
+
+        assert!(conn.customer_public_key(&channel_id).await?.is_none());
+
+        let public_key = TezosPublicKey::from_base58check(
+            "edpku5Ei6Dni4qwoJGqXJs13xHfyu4fhUg6zqZkFyiEh1mQhFD3iZE",
+        )
+        .unwrap();
+        conn.record_customer_public_key(&channel_id, &public_key)
+            .await?;
+        assert_eq!(
+            conn.customer_public_key(&channel_id)
+                .await?
+                .map(|public_key| public_key.to_base58check()),
+            Some(public_key.to_base58check())
+        );
+
+        Ok(())
+    }
+
+    async fn test_establish_before_channel_exists(conn: &dyn QueryMerchant) -> Result<()> {
+        let mut rng = StdRng::from_entropy();
+        let pk = KeyPair::new(&mut rng).public_key().clone();
+        let channel_id = ChannelId::new(
+            MerchantRandomness::new(&mut rng),
+            CustomerRandomness::new(&mut rng),
+            &pk,
+            &[],
+            &[],
+        );
+        let contract_id =
+            ContractId::new(OriginatedAddress::from_base58check(DEFAULT_ADDR).unwrap());
+
+        // Until its contract is verified the channel isn't stored, so establishment can't look up
+        // its contract, and must use the one the customer sent
+        assert!(matches!(
+            conn.contract_details(&channel_id).await,
+            Err(Error::ChannelNotFound(_))
+        ));
+
+        // Every step establishment takes once the contract is verified succeeds from there
+        let funding_address =
+            TezosFundingAddress::from_base58check("tz1iKxZpa5x1grZyN2Uw9gERXJJPMyG22Sqp").unwrap();
+        let public_key = TezosPublicKey::from_base58check(
+            "edpku5Ei6Dni4qwoJGqXJs13xHfyu4fhUg6zqZkFyiEh1mQhFD3iZE",
+        )
+        .unwrap();
+        conn.record_verified_contract(
+            &channel_id,
+            &contract_id,
+            &MerchantBalance::try_new(5).unwrap(),
+            &CustomerBalance::try_new(5).unwrap(),
+            &funding_address,
+            &public_key,
+        )
+        .await?;
+        conn.record_customer_funding(
+            &channel_id,
+            &FundingOperation::new(
+                "ooYDbsZhDgQ8gRpj8DZ4ETD8wzUiCdbzDG2hjjBR6PpR1BWBVvy".to_string(),
+                123,
+            ),
+        )
+        .await?;
+        for (from, to) in [
+            (ChannelStatus::Originated, ChannelStatus::CustomerFunded),
+            (ChannelStatus::CustomerFunded, ChannelStatus::MerchantFunded),
+            (ChannelStatus::MerchantFunded, ChannelStatus::Active),
+        ] {
+            conn.compare_and_swap_channel_status(&channel_id, &from, &to)
+                .await?;
+        }
+
+        assert_eq!(conn.contract_details(&channel_id).await?, contract_id);
+        assert_eq!(
+            conn.customer_funding_address(&channel_id).await?,
+            Some(funding_address)
+        );
+        assert_eq!(
+            conn.customer_public_key(&channel_id).await?,
+            Some(public_key)
+        );
+        assert_eq!(
+            conn.channel_status(&channel_id).await?,
+            ChannelStatus::Active
+        );
+
+        Ok(())
+    }
+
+    async fn test_stalled_establishments(conn: &dyn QueryMerchant) -> Result<()> {
+        let an_hour = Duration::from_secs(60 * 60);
+
+        // A channel which was funded by both parties, but never activated
+        let stalled_channel_id = insert_new_channel(conn).await?;
+        conn.compare_and_swap_channel_status(
+            &stalled_channel_id,
+            &ChannelStatus::Originated,
+            &ChannelStatus::CustomerFunded,
+        )
+        .await?;
+        conn.compare_and_swap_channel_status(
+            &stalled_channel_id,
+            &ChannelStatus::CustomerFunded,
+            &ChannelStatus::MerchantFunded,
+        )
+        .await?;
+
+        // A channel which was activated, and one which was never funded
+        let active_channel_id = insert_new_channel(conn).await?;
+        for (current, next) in [
+            (ChannelStatus::Originated, ChannelStatus::CustomerFunded),
+            (ChannelStatus::CustomerFunded, ChannelStatus::MerchantFunded),
+            (ChannelStatus::MerchantFunded, ChannelStatus::Active),
+        ]
+        .iter()
+        {
+            conn.compare_and_swap_channel_status(&active_channel_id, current, next)
+                .await?;
+        }
+        insert_new_channel(conn).await?;
+
+        // Nothing has been stuck for an hour yet
+        let stalled = conn
+            .get_stalled_establishments(SystemTime::now() - an_hour)
+            .await?;
+        assert!(stalled.is_empty());
+
+        // Fast-forward the clock by an hour: only the funded, inactive channel is stuck
+        let stalled = conn
+            .get_stalled_establishments(SystemTime::now() + an_hour)
+            .await?;
+        assert_eq!(stalled.len(), 1);
+        assert_eq!(stalled[0].channel_id, stalled_channel_id);
+        assert_eq!(stalled[0].status, ChannelStatus::MerchantFunded);
+
+        Ok(())
+    }
+
+    async fn test_long_active_channels(conn: &dyn QueryMerchant) -> Result<()> {
+        let a_day = Duration::from_secs(24 * 60 * 60);
+
+        // An active channel, and one which is still being established
+        let active_channel_id = insert_new_channel(conn).await?;
+        for (current, next) in [
+            (ChannelStatus::Originated, ChannelStatus::CustomerFunded),
+            (ChannelStatus::CustomerFunded, ChannelStatus::MerchantFunded),
+            (ChannelStatus::MerchantFunded, ChannelStatus::Active),
+        ]
+        .iter()
+        {
+            conn.compare_and_swap_channel_status(&active_channel_id, current, next)
+                .await?;
+        }
+        insert_new_channel(conn).await?;
+
+        // The channel hasn't been active for a day yet
+        let long_active = conn
+            .get_long_active_channels(SystemTime::now() - a_day)
+            .await?;
+        assert!(long_active.is_empty());
+
+        // Fast-forward the clock by a day: only the active channel has been active that long
+        let long_active = conn
+            .get_long_active_channels(SystemTime::now() + a_day)
+            .await?;
+        assert_eq!(long_active.len(), 1);
+        assert_eq!(long_active[0].channel_id, active_channel_id);
+
+        // Once the merchant starts closing it, it's no longer considered
+        conn.compare_and_swap_channel_status(
+            &active_channel_id,
+            &ChannelStatus::Active,
+            &ChannelStatus::PendingExpiry,
+        )
+        .await?;
+        assert!(conn
+            .get_long_active_channels(SystemTime::now() + a_day)
+            .await?
+            .is_empty());
+
+        Ok(())
+    }
 
+    async fn test_closing_balance_update(conn: &dyn QueryMerchant) -> Result<()> {
         // Make a new random channel.
-        let channel_id = insert_new_channel(&conn).await?;
+        let channel_id = insert_new_channel(conn).await?;
 
         // make sure the initial closing balances are not set
         let mut closing_balances = conn.closing_balances(&channel_id).await?;
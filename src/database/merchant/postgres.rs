@@ -0,0 +1,1020 @@
+//! The merchant database on PostgreSQL, which services running on separate hosts can share.
+//!
+//! The schema and queries mirror those for SQLite. Values which SQLite stores using their own
+//! `sqlx` encodings are stored here as `BYTEA`, encoded with bincode, and channel IDs as text.
+//!
+//! SQLite serializes every write, which the revocation and payment limit checks rely on. Postgres
+//! doesn't, so those lock their tables for the length of the check instead.
+
+use {
+    async_trait::async_trait,
+    rand::rngs::StdRng,
+    sqlx::{
+        postgres::{PgConnection, PgRow},
+        Encode, PgPool, Postgres, Row, Type,
+    },
+    std::{
+        str::FromStr,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+};
+
+use super::{
+    ChannelDetails, ClosingBalances, Error, PaySession, PaymentOutcome, PaymentRecord,
+    QueryMerchant,
+};
+use crate::{
+    database::postgres::{get_bincode, Bincode},
+    escrow::types::{ContractId, FundingOperation, TezosFundingAddress, TezosPublicKey},
+    protocol::{
+        pay::{self, PaymentId, PaymentLimits, RecentPayments},
+        ChannelStatus,
+    },
+};
+use tezedge::crypto::ToBase58Check;
+use zkabacus_crypto::{
+    revlock::{RevocationLock, RevocationSecret},
+    ChannelId, CommitmentParameters, CustomerBalance, KeyPair, MerchantBalance, Nonce,
+    RangeConstraintParameters,
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Get a channel ID, which is stored as text so that it can be searched by prefix.
+fn get_channel_id(row: &PgRow) -> Result<ChannelId> {
+    let channel_id: String = row.try_get("channel_id")?;
+    ChannelId::from_str(&channel_id).map_err(|_| Error::MalformedChannelId(channel_id))
+}
+
+/// Convert a time to seconds since the Unix epoch, as times are stored.
+fn seconds(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs() as i64)
+}
+
+/// The columns of `merchant_channels` which make up [`ChannelDetails`].
+const CHANNEL_DETAILS: &str = "
+    channel_id,
+    status,
+    contract_id,
+    merchant_deposit,
+    customer_deposit,
+    closing_balances,
+    blocked,
+    status_updated_at";
+
+fn channel_details(row: &PgRow) -> Result<ChannelDetails> {
+    Ok(ChannelDetails {
+        channel_id: get_channel_id(row)?,
+        status: row.try_get("status")?,
+        contract_id: get_bincode(row, "contract_id")?,
+        merchant_deposit: get_bincode(row, "merchant_deposit")?,
+        customer_deposit: get_bincode(row, "customer_deposit")?,
+        closing_balances: get_bincode(row, "closing_balances")?,
+        blocked: row.try_get("blocked")?,
+        status_updated_at: UNIX_EPOCH
+            + Duration::from_secs(row.try_get::<i64, _>("status_updated_at")? as u64),
+    })
+}
+
+/// Get the single row for a channel, or fail if there isn't exactly one.
+async fn channel_row(pool: &PgPool, channel_id: &ChannelId, columns: &str) -> Result<PgRow> {
+    let mut rows = sqlx::query(&format!(
+        "SELECT {} FROM merchant_channels WHERE channel_id = $1 LIMIT 2",
+        columns
+    ))
+    .bind(channel_id.to_string())
+    .fetch_all(pool)
+    .await?
+    .into_iter();
+
+    let row = rows.next().ok_or(Error::ChannelNotFound(*channel_id))?;
+    if rows.next().is_some() {
+        return Err(Error::ChannelIdCollision(channel_id.to_string()));
+    }
+    Ok(row)
+}
+
+/// Set a single column of a channel, or fail if there is no such channel.
+async fn set_channel_column<T>(
+    pool: &PgPool,
+    channel_id: &ChannelId,
+    column: &str,
+    value: T,
+) -> Result<()>
+where
+    T: 'static + Send + for<'q> Encode<'q, Postgres> + Type<Postgres>,
+{
+    let query = format!(
+        "UPDATE merchant_channels SET {} = $1 WHERE channel_id = $2",
+        column
+    );
+    let result = sqlx::query(&query)
+        .bind(value)
+        .bind(channel_id.to_string())
+        .execute(pool)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::ChannelNotFound(*channel_id));
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl QueryMerchant for PgPool {
+    async fn migrate(&self) -> Result<()> {
+        sqlx::migrate!("src/database/migrations/merchant_postgres")
+            .run(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_nonce(&self, nonce: &Nonce) -> Result<bool> {
+        let res =
+            sqlx::query("INSERT INTO nonces (data) VALUES ($1) ON CONFLICT (data) DO NOTHING")
+                .bind(Bincode(nonce))
+                .execute(self)
+                .await?;
+
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn insert_nonce_for_session(&self, nonce: &Nonce, session: &PaySession) -> Result<bool> {
+        let created_at = seconds(session.created_at);
+        let window_start = pay::window_start(created_at as u64, pay::RESUME_WINDOW) as i64;
+        let mut transaction = self.begin().await?;
+
+        let inserted =
+            sqlx::query("INSERT INTO nonces (data) VALUES ($1) ON CONFLICT (data) DO NOTHING")
+                .bind(Bincode(nonce))
+                .execute(&mut transaction)
+                .await?
+                .rows_affected()
+                > 0;
+
+        // Only a fresh nonce starts a session which can be resumed
+        if inserted {
+            sqlx::query("DELETE FROM pay_sessions WHERE created_at <= $1")
+                .bind(window_start)
+                .execute(&mut transaction)
+                .await?;
+            sqlx::query(
+                "INSERT INTO pay_sessions (
+                    nonce,
+                    transcript_digest,
+                    amount,
+                    note,
+                    pay_proof,
+                    created_at,
+                    payment_id,
+                    response_note,
+                    response_url
+                )
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+            )
+            .bind(Bincode(nonce))
+            .bind(&session.transcript_digest)
+            .bind(session.amount)
+            .bind(&session.note)
+            .bind(&session.pay_proof)
+            .bind(created_at)
+            .bind(session.payment_id.map(|id| id.to_string()))
+            .bind(&session.response_note)
+            .bind(&session.response_url)
+            .execute(&mut transaction)
+            .await?;
+        }
+
+        transaction.commit().await?;
+        Ok(inserted)
+    }
+
+    async fn pay_session(&self, nonce: &Nonce, now: SystemTime) -> Result<Option<PaySession>> {
+        let window_start = pay::window_start(seconds(now) as u64, pay::RESUME_WINDOW) as i64;
+
+        sqlx::query(
+            "SELECT
+                transcript_digest,
+                amount,
+                note,
+                pay_proof,
+                created_at,
+                payment_id,
+                response_note,
+                response_url
+            FROM pay_sessions
+            WHERE nonce = $1 AND created_at > $2",
+        )
+        .bind(Bincode(nonce))
+        .bind(window_start)
+        .fetch_optional(self)
+        .await?
+        .map(|row| -> Result<PaySession> {
+            Ok(PaySession {
+                transcript_digest: row.try_get("transcript_digest")?,
+                amount: row.try_get("amount")?,
+                note: row.try_get("note")?,
+                pay_proof: row.try_get("pay_proof")?,
+                created_at: UNIX_EPOCH
+                    + Duration::from_secs(row.try_get::<i64, _>("created_at")? as u64),
+                payment_id: get_payment_id(&row)?,
+                response_note: row.try_get("response_note")?,
+                response_url: row.try_get("response_url")?,
+            })
+        })
+        .transpose()
+    }
+
+    async fn insert_revocation(
+        &self,
+        lock: &RevocationLock,
+        secret: Option<&RevocationSecret>,
+        payment: Option<&PaymentRecord>,
+    ) -> Result<Vec<Option<RevocationSecret>>> {
+        let mut transaction = self.begin().await?;
+
+        // Two payments revealing the same lock at once must not both see it as unused
+        sqlx::query("LOCK TABLE revocations IN SHARE ROW EXCLUSIVE MODE")
+            .execute(&mut transaction)
+            .await?;
+
+        let existing_pairs = sqlx::query("SELECT secret FROM revocations WHERE lock = $1")
+            .bind(Bincode(lock))
+            .fetch_all(&mut transaction)
+            .await?
+            .iter()
+            .map(|row| {
+                Ok(row
+                    .try_get::<Option<Bincode<RevocationSecret>>, _>("secret")?
+                    .map(|secret| secret.0))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        sqlx::query("INSERT INTO revocations (lock, secret) VALUES ($1, $2)")
+            .bind(Bincode(lock))
+            .bind(secret.map(Bincode))
+            .execute(&mut transaction)
+            .await?;
+
+        // A payment whose revocation lock was reused didn't complete, so isn't recorded here
+        if let (Some(payment), true) = (payment, existing_pairs.is_empty()) {
+            insert_payment(&mut transaction, payment).await?;
+        }
+
+        transaction.commit().await?;
+        Ok(existing_pairs)
+    }
+
+    async fn revocation_secret_for(
+        &self,
+        revocation_lock: &RevocationLock,
+    ) -> Result<Option<RevocationSecret>> {
+        Ok(sqlx::query(
+            "SELECT secret FROM revocations
+            WHERE lock = $1 AND secret IS NOT NULL
+            LIMIT 1",
+        )
+        .bind(Bincode(revocation_lock))
+        .fetch_optional(self)
+        .await?
+        .map(|row| get_bincode(&row, "secret"))
+        .transpose()?)
+    }
+
+    async fn insert_payment_event(
+        &self,
+        amount: i64,
+        now: SystemTime,
+        limits: &PaymentLimits,
+    ) -> Result<()> {
+        let created_at = seconds(now);
+        let rate_window_start = pay::window_start(created_at as u64, pay::RATE_WINDOW) as i64;
+        let volume_window_start = pay::window_start(created_at as u64, pay::VOLUME_WINDOW) as i64;
+        let volume_amount = amount.max(0);
+        let max_payments = limits
+            .max_payments_per_minute
+            .map_or(i64::MAX, |max| max as i64);
+        let max_volume = limits
+            .max_volume_per_day
+            .map_or(i64::MAX, |max| max.min(i64::MAX as u64) as i64);
+
+        loop {
+            let mut transaction = self.begin().await?;
+
+            // Concurrent payments must not both fit in the last of the room
+            sqlx::query("LOCK TABLE payment_events IN SHARE ROW EXCLUSIVE MODE")
+                .execute(&mut transaction)
+                .await?;
+
+            let inserted = sqlx::query(
+                "INSERT INTO payment_events (amount, created_at)
+                SELECT $1, $2
+                WHERE
+                    (SELECT COUNT(*) FROM payment_events WHERE created_at > $3) < $4
+                    AND (
+                        SELECT COALESCE(SUM(amount), 0)
+                        FROM payment_events
+                        WHERE amount > 0 AND created_at > $5
+                    ) + $6 <= $7",
+            )
+            .bind(amount)
+            .bind(created_at)
+            .bind(rate_window_start)
+            .bind(max_payments)
+            .bind(volume_window_start)
+            .bind(volume_amount)
+            .bind(max_volume)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected()
+                > 0;
+
+            transaction.commit().await?;
+            if inserted {
+                return Ok(());
+            }
+
+            // Work out which limit was exceeded; since events are only ever added, one must have
+            // been, but if not, try again
+            let recent = self.recent_payments(now).await?;
+            limits
+                .check_recent(&recent, amount)
+                .map_err(Error::PaymentLimitExceeded)?;
+        }
+    }
+
+    async fn recent_payments(&self, now: SystemTime) -> Result<RecentPayments> {
+        let now = seconds(now) as u64;
+        let rate_window_start = pay::window_start(now, pay::RATE_WINDOW) as i64;
+        let volume_window_start = pay::window_start(now, pay::VOLUME_WINDOW) as i64;
+
+        // The rate window lies within the volume window, so both are counted from the latter
+        let recent = sqlx::query(
+            "SELECT
+                COUNT(*) FILTER (WHERE created_at > $1) AS count,
+                COALESCE(SUM(GREATEST(amount, 0)), 0)::BIGINT AS volume
+            FROM payment_events
+            WHERE created_at > $2",
+        )
+        .bind(rate_window_start)
+        .bind(volume_window_start)
+        .fetch_one(self)
+        .await?;
+
+        Ok(RecentPayments {
+            count: recent.try_get::<i64, _>("count")? as u64,
+            volume: recent.try_get::<i64, _>("volume")? as u64,
+        })
+    }
+
+    async fn record_payment(&self, payment: &PaymentRecord) -> Result<()> {
+        let mut connection = self.acquire().await?;
+        insert_payment(&mut connection, payment).await
+    }
+
+    async fn payment_history(&self, limit: u32, offset: u32) -> Result<Vec<PaymentRecord>> {
+        sqlx::query(
+            "SELECT
+                amount,
+                note,
+                approver,
+                response_note,
+                response_url,
+                outcome,
+                failure_reason,
+                created_at,
+                payment_id
+            FROM payments
+            ORDER BY id DESC
+            LIMIT $1 OFFSET $2",
+        )
+        .bind(limit as i64)
+        .bind(offset as i64)
+        .fetch_all(self)
+        .await?
+        .iter()
+        .map(payment_record)
+        .collect()
+    }
+
+    async fn payments_with_id(&self, payment_id: &PaymentId) -> Result<Vec<PaymentRecord>> {
+        sqlx::query(
+            "SELECT
+                amount,
+                note,
+                approver,
+                response_note,
+                response_url,
+                outcome,
+                failure_reason,
+                created_at,
+                payment_id
+            FROM payments
+            WHERE payment_id = $1
+            ORDER BY id",
+        )
+        .bind(payment_id.to_string())
+        .fetch_all(self)
+        .await?
+        .iter()
+        .map(payment_record)
+        .collect()
+    }
+
+    async fn insert_webhook_event(&self, payload: &str) -> Result<i64> {
+        Ok(
+            sqlx::query("INSERT INTO webhook_events (payload) VALUES ($1) RETURNING id")
+                .bind(payload)
+                .fetch_one(self)
+                .await?
+                .try_get("id")?,
+        )
+    }
+
+    async fn delete_webhook_event(&self, id: i64) -> Result<()> {
+        sqlx::query("DELETE FROM webhook_events WHERE id = $1")
+            .bind(id)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn undelivered_webhook_events(&self) -> Result<Vec<(i64, String)>> {
+        sqlx::query("SELECT id, payload FROM webhook_events ORDER BY id")
+            .fetch_all(self)
+            .await?
+            .iter()
+            .map(|row| Ok((row.try_get("id")?, row.try_get("payload")?)))
+            .collect()
+    }
+
+    async fn fetch_or_create_config(
+        &self,
+        rng: &mut StdRng,
+    ) -> Result<zkabacus_crypto::merchant::Config> {
+        let fetch = || {
+            sqlx::query(
+                "SELECT
+                    signing_keypair,
+                    revocation_commitment_parameters,
+                    range_constraint_parameters
+                FROM merchant_config",
+            )
+            .fetch_optional(self)
+        };
+
+        if fetch().await?.is_none() {
+            let new_config = zkabacus_crypto::merchant::Config::new(rng);
+
+            // Another service may have created the config in the meantime, in which case its
+            // config is kept, and used by this one too
+            sqlx::query(
+                "INSERT INTO merchant_config (
+                    signing_keypair,
+                    revocation_commitment_parameters,
+                    range_constraint_parameters
+                )
+                VALUES ($1, $2, $3)
+                ON CONFLICT (id) DO NOTHING",
+            )
+            .bind(Bincode(new_config.signing_keypair()))
+            .bind(Bincode(new_config.revocation_commitment_parameters()))
+            .bind(Bincode(new_config.range_constraint_parameters()))
+            .execute(self)
+            .await?;
+        }
+
+        let existing = fetch().await?.ok_or(sqlx::Error::RowNotFound)?;
+        Ok(zkabacus_crypto::merchant::Config::from_parts(
+            get_bincode::<KeyPair>(&existing, "signing_keypair")?,
+            get_bincode::<CommitmentParameters>(&existing, "revocation_commitment_parameters")?,
+            get_bincode::<RangeConstraintParameters>(&existing, "range_constraint_parameters")?,
+        ))
+    }
+
+    async fn new_channel(
+        &self,
+        channel_id: &ChannelId,
+        contract_id: &ContractId,
+        merchant_deposit: &MerchantBalance,
+        customer_deposit: &CustomerBalance,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO merchant_channels (
+                channel_id,
+                contract_id,
+                merchant_deposit,
+                customer_deposit,
+                status,
+                closing_balances,
+                status_updated_at
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, EXTRACT(EPOCH FROM NOW())::BIGINT)",
+        )
+        .bind(channel_id.to_string())
+        .bind(Bincode(contract_id))
+        .bind(Bincode(merchant_deposit))
+        .bind(Bincode(customer_deposit))
+        .bind(ChannelStatus::Originated)
+        .bind(Bincode(ClosingBalances::default()))
+        .execute(self)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn record_verified_contract(
+        &self,
+        channel_id: &ChannelId,
+        contract_id: &ContractId,
+        merchant_deposit: &MerchantBalance,
+        customer_deposit: &CustomerBalance,
+        customer_funding_address: &TezosFundingAddress,
+        customer_public_key: &TezosPublicKey,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO merchant_channels (
+                channel_id,
+                contract_id,
+                merchant_deposit,
+                customer_deposit,
+                status,
+                closing_balances,
+                customer_funding_address,
+                customer_public_key,
+                status_updated_at
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, EXTRACT(EPOCH FROM NOW())::BIGINT)",
+        )
+        .bind(channel_id.to_string())
+        .bind(Bincode(contract_id))
+        .bind(Bincode(merchant_deposit))
+        .bind(Bincode(customer_deposit))
+        .bind(ChannelStatus::Originated)
+        .bind(Bincode(ClosingBalances::default()))
+        .bind(customer_funding_address.to_base58check())
+        .bind(customer_public_key.to_base58check())
+        .execute(self)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn compare_and_swap_channel_status(
+        &self,
+        channel_id: &ChannelId,
+        expected: &ChannelStatus,
+        new: &ChannelStatus,
+    ) -> Result<()> {
+        // Update the status only if it is currently the expected status, in a single statement so
+        // that concurrent updates can't both succeed
+        let updated = sqlx::query(
+            "UPDATE merchant_channels
+            SET status = $1, status_updated_at = EXTRACT(EPOCH FROM NOW())::BIGINT
+            WHERE channel_id = $2 AND status = $3",
+        )
+        .bind(*new)
+        .bind(channel_id.to_string())
+        .bind(*expected)
+        .execute(self)
+        .await?
+        .rows_affected();
+
+        if updated == 0 {
+            // Either the channel doesn't exist, or it's in some other status
+            return Err(Error::UnexpectedChannelStatus {
+                channel_id: *channel_id,
+                expected: vec![*expected],
+                found: self.channel_status(channel_id).await?,
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn record_customer_funding(
+        &self,
+        channel_id: &ChannelId,
+        funding_operation: &FundingOperation,
+    ) -> Result<()> {
+        set_channel_column(
+            self,
+            channel_id,
+            "customer_funding_operation",
+            Bincode(funding_operation.clone()),
+        )
+        .await
+    }
+
+    async fn customer_funding(&self, channel_id: &ChannelId) -> Result<Option<FundingOperation>> {
+        let row = channel_row(self, channel_id, "customer_funding_operation").await?;
+        Ok(row
+            .try_get::<Option<Bincode<FundingOperation>>, _>("customer_funding_operation")?
+            .map(|funding_operation| funding_operation.0))
+    }
+
+    async fn record_customer_funding_address(
+        &self,
+        channel_id: &ChannelId,
+        funding_address: &TezosFundingAddress,
+    ) -> Result<()> {
+        set_channel_column(
+            self,
+            channel_id,
+            "customer_funding_address",
+            funding_address.to_base58check(),
+        )
+        .await
+    }
+
+    async fn customer_funding_address(
+        &self,
+        channel_id: &ChannelId,
+    ) -> Result<Option<TezosFundingAddress>> {
+        let row = channel_row(self, channel_id, "customer_funding_address").await?;
+        row.try_get::<Option<String>, _>("customer_funding_address")?
+            .map(|address| {
+                TezosFundingAddress::from_base58check(&address)
+                    .map_err(|_| Error::InvalidFundingAddress(*channel_id))
+            })
+            .transpose()
+    }
+
+    async fn record_customer_public_key(
+        &self,
+        channel_id: &ChannelId,
+        public_key: &TezosPublicKey,
+    ) -> Result<()> {
+        set_channel_column(
+            self,
+            channel_id,
+            "customer_public_key",
+            public_key.to_base58check(),
+        )
+        .await
+    }
+
+    async fn customer_public_key(&self, channel_id: &ChannelId) -> Result<Option<TezosPublicKey>> {
+        let row = channel_row(self, channel_id, "customer_public_key").await?;
+        row.try_get::<Option<String>, _>("customer_public_key")?
+            .map(|public_key| {
+                TezosPublicKey::from_base58check(&public_key)
+                    .map_err(|_| Error::InvalidPublicKey(*channel_id))
+            })
+            .transpose()
+    }
+
+    async fn record_dispute_operation(&self, channel_id: &ChannelId, hash: &str) -> Result<()> {
+        set_channel_column(self, channel_id, "dispute_operation", hash.to_string()).await
+    }
+
+    async fn dispute_operation(&self, channel_id: &ChannelId) -> Result<Option<String>> {
+        let row = channel_row(self, channel_id, "dispute_operation").await?;
+        Ok(row.try_get("dispute_operation")?)
+    }
+
+    async fn set_channel_blocked(&self, channel_id: &ChannelId, blocked: bool) -> Result<()> {
+        set_channel_column(self, channel_id, "blocked", blocked).await
+    }
+
+    async fn set_attention_reason(
+        &self,
+        channel_id: &ChannelId,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        set_channel_column(
+            self,
+            channel_id,
+            "attention_reason",
+            reason.map(str::to_string),
+        )
+        .await
+    }
+
+    async fn attention_reason(&self, channel_id: &ChannelId) -> Result<Option<String>> {
+        let row = channel_row(self, channel_id, "attention_reason").await?;
+        Ok(row.try_get("attention_reason")?)
+    }
+
+    async fn update_status_to_pending_close(&self, channel_id: &ChannelId) -> Result<()> {
+        let mut transaction = self.begin().await?;
+
+        // Find out current status, holding the channel until it's updated
+        let status: ChannelStatus = sqlx::query(
+            "SELECT status FROM merchant_channels
+            WHERE channel_id = $1
+            FOR UPDATE",
+        )
+        .bind(channel_id.to_string())
+        .fetch_optional(&mut transaction)
+        .await?
+        .ok_or(Error::ChannelNotFound(*channel_id))?
+        .try_get("status")?;
+
+        // Only update status if it is an allowable value.
+        match status {
+            ChannelStatus::MerchantFunded
+            | ChannelStatus::Active
+            | ChannelStatus::PendingExpiry
+            | ChannelStatus::PendingMutualClose => {
+                sqlx::query(
+                    "UPDATE merchant_channels
+                    SET status = $1, status_updated_at = EXTRACT(EPOCH FROM NOW())::BIGINT
+                    WHERE channel_id = $2",
+                )
+                .bind(ChannelStatus::PendingClose)
+                .bind(channel_id.to_string())
+                .execute(&mut transaction)
+                .await?;
+
+                transaction.commit().await?;
+                Ok(())
+            }
+            unexpected_status => Err(Error::UnexpectedChannelStatus {
+                channel_id: *channel_id,
+                expected: vec![
+                    ChannelStatus::Originated,
+                    ChannelStatus::CustomerFunded,
+                    ChannelStatus::MerchantFunded,
+                    ChannelStatus::Active,
+                    ChannelStatus::PendingExpiry,
+                    ChannelStatus::PendingMutualClose,
+                ],
+                found: unexpected_status,
+            }),
+        }
+    }
+
+    async fn update_closing_balances(
+        &self,
+        channel_id: &ChannelId,
+        expected_status: &ChannelStatus,
+        merchant_balance: MerchantBalance,
+        customer_balance: Option<CustomerBalance>,
+    ) -> Result<()> {
+        let mut transaction = self.begin().await?;
+
+        // Find out the current status, holding the channel until it's updated
+        let row = sqlx::query(
+            "SELECT status, closing_balances FROM merchant_channels
+            WHERE channel_id = $1
+            FOR UPDATE",
+        )
+        .bind(channel_id.to_string())
+        .fetch_optional(&mut transaction)
+        .await?
+        .ok_or(Error::ChannelNotFound(*channel_id))?;
+        let status: ChannelStatus = row.try_get("status")?;
+
+        // Only if the current status is what was expected, update the channel balances.
+        if status != *expected_status {
+            return Err(Error::UnexpectedChannelStatus {
+                channel_id: *channel_id,
+                expected: vec![*expected_status],
+                found: status,
+            });
+        }
+        let closing_balances: ClosingBalances = get_bincode(&row, "closing_balances")?;
+
+        // Make sure we're not decreasing merchant balance.
+        if let Some(original) = closing_balances.merchant_balance {
+            if original.into_inner() > merchant_balance.into_inner() {
+                return Err(Error::InvalidBalanceUpdate(
+                    merchant_balance,
+                    customer_balance,
+                ));
+            }
+        }
+
+        // Make sure we don't update customer balance more than once.
+        if closing_balances.customer_balance.is_some() {
+            return Err(Error::InvalidBalanceUpdate(
+                merchant_balance,
+                customer_balance,
+            ));
+        }
+
+        // If everything was ok, set the new balances.
+        let updated_closing_balances = ClosingBalances {
+            merchant_balance: Some(merchant_balance),
+            customer_balance,
+        };
+
+        sqlx::query("UPDATE merchant_channels SET closing_balances = $1 WHERE channel_id = $2")
+            .bind(Bincode(updated_closing_balances))
+            .bind(channel_id.to_string())
+            .execute(&mut transaction)
+            .await?;
+
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn get_channels(&self) -> Result<Vec<ChannelDetails>> {
+        sqlx::query(&format!(
+            "SELECT {} FROM merchant_channels",
+            CHANNEL_DETAILS
+        ))
+        .fetch_all(self)
+        .await?
+        .iter()
+        .map(channel_details)
+        .collect()
+    }
+
+    async fn get_channel(&self, channel_id: &ChannelId) -> Result<ChannelDetails> {
+        let row = sqlx::query(&format!(
+            "SELECT {} FROM merchant_channels WHERE channel_id = $1",
+            CHANNEL_DETAILS
+        ))
+        .bind(channel_id.to_string())
+        .fetch_optional(self)
+        .await?
+        .ok_or(Error::ChannelNotFound(*channel_id))?;
+
+        channel_details(&row)
+    }
+
+    async fn channels_with_status(&self, status: &ChannelStatus) -> Result<Vec<ChannelDetails>> {
+        sqlx::query(&format!(
+            "SELECT {} FROM merchant_channels WHERE status = $1",
+            CHANNEL_DETAILS
+        ))
+        .bind(*status)
+        .fetch_all(self)
+        .await?
+        .iter()
+        .map(channel_details)
+        .collect()
+    }
+
+    async fn get_stalled_establishments(
+        &self,
+        stalled_since: SystemTime,
+    ) -> Result<Vec<ChannelDetails>> {
+        sqlx::query(&format!(
+            "SELECT {} FROM merchant_channels
+            WHERE (status = $1 OR status = $2) AND status_updated_at <= $3",
+            CHANNEL_DETAILS
+        ))
+        .bind(ChannelStatus::CustomerFunded)
+        .bind(ChannelStatus::MerchantFunded)
+        .bind(seconds(stalled_since))
+        .fetch_all(self)
+        .await?
+        .iter()
+        .map(channel_details)
+        .collect()
+    }
+
+    async fn get_long_active_channels(
+        &self,
+        active_since: SystemTime,
+    ) -> Result<Vec<ChannelDetails>> {
+        sqlx::query(&format!(
+            "SELECT {} FROM merchant_channels
+            WHERE status = $1 AND status_updated_at <= $2",
+            CHANNEL_DETAILS
+        ))
+        .bind(ChannelStatus::Active)
+        .bind(seconds(active_since))
+        .fetch_all(self)
+        .await?
+        .iter()
+        .map(channel_details)
+        .collect()
+    }
+
+    async fn channel_status(&self, channel_id: &ChannelId) -> Result<ChannelStatus> {
+        let row = channel_row(self, channel_id, "status").await?;
+        Ok(row.try_get("status")?)
+    }
+
+    async fn closing_balances(&self, channel_id: &ChannelId) -> Result<ClosingBalances> {
+        let row = channel_row(self, channel_id, "closing_balances").await?;
+        Ok(get_bincode(&row, "closing_balances")?)
+    }
+
+    async fn initial_balances(
+        &self,
+        channel_id: &ChannelId,
+    ) -> Result<(MerchantBalance, CustomerBalance)> {
+        let row = channel_row(self, channel_id, "merchant_deposit, customer_deposit").await?;
+        Ok((
+            get_bincode(&row, "merchant_deposit")?,
+            get_bincode(&row, "customer_deposit")?,
+        ))
+    }
+
+    async fn contract_details(&self, channel_id: &ChannelId) -> Result<ContractId> {
+        let row = channel_row(self, channel_id, "contract_id").await?;
+        Ok(get_bincode(&row, "contract_id")?)
+    }
+
+    async fn get_channel_details_by_prefix(&self, prefix: &str) -> Result<ChannelDetails> {
+        let mut rows = sqlx::query(&format!(
+            "SELECT {} FROM merchant_channels WHERE channel_id LIKE $1 LIMIT 2",
+            CHANNEL_DETAILS
+        ))
+        .bind(format!("{}%", prefix))
+        .fetch_all(self)
+        .await?
+        .into_iter();
+
+        let details = match rows.next() {
+            None => return Err(Error::ChannelNotFoundWithPrefix(prefix.to_string())),
+            Some(row) => channel_details(&row)?,
+        };
+
+        if rows.next().is_some() {
+            return Err(Error::ChannelIdCollision(prefix.to_string()));
+        }
+
+        Ok(details)
+    }
+}
+
+/// Read a payment in the payment history from a row of the `payments` table.
+fn payment_record(row: &PgRow) -> Result<PaymentRecord> {
+    Ok(PaymentRecord {
+        amount: row.try_get("amount")?,
+        note: row.try_get("note")?,
+        approver: row.try_get("approver")?,
+        response_note: row.try_get("response_note")?,
+        response_url: row.try_get("response_url")?,
+        outcome: row.try_get::<PaymentOutcome, _>("outcome")?,
+        failure_reason: row.try_get("failure_reason")?,
+        created_at: UNIX_EPOCH + Duration::from_secs(row.try_get::<i64, _>("created_at")? as u64),
+        payment_id: get_payment_id(row)?,
+    })
+}
+
+/// Read the payment ID from a row, ignoring one which isn't a valid payment ID.
+fn get_payment_id(row: &PgRow) -> Result<Option<PaymentId>> {
+    Ok(row
+        .try_get::<Option<String>, _>("payment_id")?
+        .and_then(|id| id.parse().ok()))
+}
+
+/// Insert a payment into the payment history, on the given connection or transaction.
+async fn insert_payment(connection: &mut PgConnection, payment: &PaymentRecord) -> Result<()> {
+    // A resumed payment completes the attempt recorded as failed when its session was
+    // interrupted, which started at the same time
+    if payment.outcome == PaymentOutcome::Completed {
+        let updated = sqlx::query(
+            "UPDATE payments
+            SET outcome = $1, response_note = $2, response_url = $3, failure_reason = NULL
+            WHERE id = (
+                SELECT id
+                FROM payments
+                WHERE payment_id = $4 AND created_at = $5 AND amount = $6 AND outcome = $7
+                ORDER BY id DESC
+                LIMIT 1
+            )",
+        )
+        .bind(payment.outcome)
+        .bind(&payment.response_note)
+        .bind(&payment.response_url)
+        .bind(payment.payment_id.map(|id| id.to_string()))
+        .bind(seconds(payment.created_at))
+        .bind(payment.amount)
+        .bind(PaymentOutcome::Failed)
+        .execute(&mut *connection)
+        .await?
+        .rows_affected()
+            > 0;
+        if updated {
+            return Ok(());
+        }
+    }
+
+    sqlx::query(
+        "INSERT INTO payments (
+            amount,
+            note,
+            approver,
+            response_note,
+            response_url,
+            outcome,
+            failure_reason,
+            created_at,
+            payment_id
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+    )
+    .bind(payment.amount)
+    .bind(&payment.note)
+    .bind(&payment.approver)
+    .bind(&payment.response_note)
+    .bind(&payment.response_url)
+    .bind(payment.outcome)
+    .bind(&payment.failure_reason)
+    .bind(seconds(payment.created_at))
+    .bind(payment.payment_id.map(|id| id.to_string()))
+    .execute(connection)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,9 @@
+CREATE TABLE operations_in_flight (
+  channel_id INTEGER NOT NULL,
+  entrypoint BLOB NOT NULL,
+  started_at INTEGER NOT NULL,
+  PRIMARY KEY (channel_id, entrypoint),
+  FOREIGN KEY (channel_id)
+    REFERENCES customer_channels (id)
+    ON DELETE CASCADE
+);
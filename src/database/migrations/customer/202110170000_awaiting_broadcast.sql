@@ -0,0 +1 @@
+ALTER TABLE customer_channels ADD COLUMN awaiting_broadcast BOOLEAN NOT NULL DEFAULT FALSE;
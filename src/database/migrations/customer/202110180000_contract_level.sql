@@ -0,0 +1 @@
+ALTER TABLE customer_channels ADD COLUMN contract_level INTEGER;
@@ -0,0 +1,4 @@
+CREATE TABLE trusted_merchants (
+  address BLOB PRIMARY KEY NOT NULL,
+  key_hash BLOB NOT NULL
+);
@@ -0,0 +1,2 @@
+ALTER TABLE customer_channels ADD COLUMN state_updated_at INTEGER NOT NULL DEFAULT 0;
+UPDATE customer_channels SET state_updated_at = CAST(strftime('%s', 'now') AS INTEGER);
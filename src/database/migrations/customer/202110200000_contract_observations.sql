@@ -0,0 +1,4 @@
+ALTER TABLE customer_channels ADD COLUMN last_observed_status INTEGER;
+ALTER TABLE customer_channels ADD COLUMN last_observed_level INTEGER;
+ALTER TABLE customer_channels ADD COLUMN last_observed_at INTEGER;
+ALTER TABLE customer_channels ADD COLUMN last_reaction BLOB;
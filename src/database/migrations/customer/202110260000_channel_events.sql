@@ -0,0 +1,12 @@
+CREATE TABLE channel_events (
+  id INTEGER PRIMARY KEY,
+  channel_id INTEGER NOT NULL,
+  old_state BLOB NOT NULL,
+  new_state BLOB NOT NULL,
+  recorded_at INTEGER NOT NULL,
+  FOREIGN KEY (channel_id)
+    REFERENCES customer_channels (id)
+    ON DELETE CASCADE
+);
+
+CREATE INDEX channel_events_channel_id ON channel_events (channel_id);
@@ -0,0 +1,14 @@
+CREATE TABLE archived_channels (
+  id INTEGER PRIMARY KEY,
+  label TEXT NOT NULL,
+  channel_id TEXT,
+  address BLOB NOT NULL,
+  merchant_deposit BLOB NOT NULL,
+  customer_deposit BLOB NOT NULL,
+  final_state BLOB NOT NULL,
+  closing_balances BLOB NOT NULL,
+  closing_path BLOB,
+  contract_id BLOB,
+  contract_level INTEGER,
+  archived_at INTEGER NOT NULL
+);
@@ -0,0 +1,14 @@
+CREATE TABLE payments (
+  id INTEGER PRIMARY KEY,
+  channel_id INTEGER NOT NULL,
+  amount INTEGER NOT NULL,
+  note TEXT NOT NULL,
+  customer_balance INTEGER NOT NULL,
+  merchant_balance INTEGER NOT NULL,
+  paid_at INTEGER NOT NULL,
+  FOREIGN KEY (channel_id)
+    REFERENCES customer_channels (id)
+    ON DELETE CASCADE
+);
+
+CREATE INDEX payments_channel_id ON payments (channel_id);
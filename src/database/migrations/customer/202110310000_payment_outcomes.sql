@@ -0,0 +1,10 @@
+ALTER TABLE payments ADD COLUMN outcome TEXT NOT NULL DEFAULT "completed"
+  CHECK (outcome IN (
+    "pending",
+    "completed",
+    "failed"
+  ));
+ALTER TABLE payments ADD COLUMN failure_reason TEXT;
+ALTER TABLE payments ADD COLUMN response_note TEXT;
+ALTER TABLE payments ADD COLUMN customer_balance_before INTEGER;
+ALTER TABLE payments ADD COLUMN started_at INTEGER;
@@ -0,0 +1,2 @@
+ALTER TABLE payments ADD COLUMN start_message BLOB;
+ALTER TABLE payments ADD COLUMN lock_message BLOB;
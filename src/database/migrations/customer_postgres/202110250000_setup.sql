@@ -0,0 +1,40 @@
+CREATE TABLE configs (
+  id BIGSERIAL PRIMARY KEY,
+  data BYTEA NOT NULL
+);
+
+CREATE TABLE customer_channels (
+  id BIGSERIAL PRIMARY KEY,
+  label TEXT NOT NULL UNIQUE,
+  address BYTEA NOT NULL,
+  merchant_deposit BYTEA NOT NULL,
+  customer_deposit BYTEA NOT NULL,
+  state BYTEA NOT NULL,
+  closing_balances BYTEA NOT NULL,
+  merchant_tezos_public_key TEXT NOT NULL,
+  contract_id BYTEA,
+  contract_level BIGINT,
+  config_id BIGINT NOT NULL
+    REFERENCES configs (id),
+  awaiting_broadcast BOOLEAN NOT NULL DEFAULT FALSE,
+  state_updated_at BIGINT NOT NULL DEFAULT 0,
+  last_observed_status INTEGER,
+  last_observed_level BIGINT,
+  last_observed_at BIGINT,
+  last_reaction BYTEA,
+  required_action BYTEA
+);
+
+CREATE TABLE operations_in_flight (
+  channel_id BIGINT NOT NULL
+    REFERENCES customer_channels (id)
+    ON DELETE CASCADE,
+  entrypoint BYTEA NOT NULL,
+  started_at BIGINT NOT NULL,
+  PRIMARY KEY (channel_id, entrypoint)
+);
+
+CREATE TABLE trusted_merchants (
+  address BYTEA PRIMARY KEY,
+  key_hash BYTEA NOT NULL
+);
@@ -0,0 +1,11 @@
+CREATE TABLE channel_events (
+  id BIGSERIAL PRIMARY KEY,
+  channel_id BIGINT NOT NULL
+    REFERENCES customer_channels (id)
+    ON DELETE CASCADE,
+  old_state BYTEA NOT NULL,
+  new_state BYTEA NOT NULL,
+  recorded_at BIGINT NOT NULL
+);
+
+CREATE INDEX channel_events_channel_id ON channel_events (channel_id);
@@ -0,0 +1 @@
+ALTER TABLE customer_channels ADD COLUMN closing_path BYTEA;
@@ -0,0 +1,2 @@
+ALTER TABLE customer_channels ADD COLUMN channel_id TEXT;
+CREATE INDEX customer_channels_channel_id ON customer_channels (channel_id);
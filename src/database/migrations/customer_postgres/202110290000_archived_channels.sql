@@ -0,0 +1,14 @@
+CREATE TABLE archived_channels (
+  id BIGSERIAL PRIMARY KEY,
+  label TEXT NOT NULL,
+  channel_id TEXT,
+  address BYTEA NOT NULL,
+  merchant_deposit BYTEA NOT NULL,
+  customer_deposit BYTEA NOT NULL,
+  final_state BYTEA NOT NULL,
+  closing_balances BYTEA NOT NULL,
+  closing_path BYTEA,
+  contract_id BYTEA,
+  contract_level BIGINT,
+  archived_at BIGINT NOT NULL
+);
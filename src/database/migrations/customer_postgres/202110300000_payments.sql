@@ -0,0 +1,13 @@
+CREATE TABLE payments (
+  id BIGSERIAL PRIMARY KEY,
+  channel_id BIGINT NOT NULL
+    REFERENCES customer_channels (id)
+    ON DELETE CASCADE,
+  amount BIGINT NOT NULL,
+  note TEXT NOT NULL,
+  customer_balance BIGINT NOT NULL,
+  merchant_balance BIGINT NOT NULL,
+  paid_at BIGINT NOT NULL
+);
+
+CREATE INDEX payments_channel_id ON payments (channel_id);
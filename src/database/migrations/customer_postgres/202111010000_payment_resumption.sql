@@ -0,0 +1,2 @@
+ALTER TABLE payments ADD COLUMN start_message BYTEA;
+ALTER TABLE payments ADD COLUMN lock_message BYTEA;
@@ -0,0 +1,2 @@
+ALTER TABLE merchant_channels ADD COLUMN status_updated_at INTEGER NOT NULL DEFAULT 0;
+UPDATE merchant_channels SET status_updated_at = CAST(strftime('%s', 'now') AS INTEGER);
@@ -0,0 +1 @@
+ALTER TABLE merchant_channels ADD COLUMN blocked BOOLEAN NOT NULL DEFAULT FALSE;
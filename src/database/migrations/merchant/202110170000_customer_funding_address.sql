@@ -0,0 +1 @@
+ALTER TABLE merchant_channels ADD COLUMN customer_funding_address TEXT;
@@ -0,0 +1 @@
+ALTER TABLE merchant_channels ADD COLUMN dispute_operation TEXT;
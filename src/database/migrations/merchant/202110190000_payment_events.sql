@@ -0,0 +1,6 @@
+CREATE TABLE payment_events (
+  id INTEGER PRIMARY KEY,
+  amount INTEGER NOT NULL,
+  created_at INTEGER NOT NULL
+);
+CREATE INDEX payment_events_created_at ON payment_events (created_at);
@@ -0,0 +1,16 @@
+CREATE TABLE payments (
+  id INTEGER PRIMARY KEY,
+  amount INTEGER NOT NULL,
+  note TEXT NOT NULL,
+  approver TEXT NOT NULL,
+  response_note TEXT,
+  response_url TEXT,
+  outcome TEXT NOT NULL
+    CHECK (outcome IN (
+      "completed",
+      "rejected",
+      "failed"
+    )),
+  failure_reason TEXT,
+  created_at INTEGER NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE merchant_channels ADD COLUMN attention_reason TEXT;
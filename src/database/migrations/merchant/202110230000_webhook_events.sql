@@ -0,0 +1,4 @@
+CREATE TABLE webhook_events (
+  id INTEGER PRIMARY KEY,
+  payload TEXT NOT NULL
+);
@@ -0,0 +1,10 @@
+CREATE TABLE pay_sessions (
+  id INTEGER PRIMARY KEY,
+  nonce BLOB NOT NULL UNIQUE,
+  transcript_digest BLOB NOT NULL,
+  amount INTEGER NOT NULL,
+  note TEXT NOT NULL,
+  pay_proof BLOB NOT NULL,
+  created_at INTEGER NOT NULL
+);
+CREATE INDEX pay_sessions_created_at ON pay_sessions (created_at);
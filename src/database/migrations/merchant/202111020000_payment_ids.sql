@@ -0,0 +1,3 @@
+ALTER TABLE payments ADD COLUMN payment_id TEXT;
+CREATE INDEX payments_payment_id ON payments (payment_id);
+ALTER TABLE pay_sessions ADD COLUMN payment_id TEXT;
@@ -0,0 +1,72 @@
+CREATE TABLE nonces (
+  id BIGSERIAL PRIMARY KEY,
+  data BYTEA NOT NULL
+);
+CREATE UNIQUE INDEX nonces_data ON nonces (data);
+
+CREATE TABLE revocations (
+  id BIGSERIAL PRIMARY KEY,
+  lock BYTEA NOT NULL,
+  secret BYTEA
+);
+CREATE INDEX revocations_lock ON revocations (lock);
+
+CREATE TABLE merchant_config (
+  id INTEGER PRIMARY KEY DEFAULT 1 CHECK (id = 1),
+  signing_keypair BYTEA NOT NULL,
+  revocation_commitment_parameters BYTEA NOT NULL,
+  range_constraint_parameters BYTEA NOT NULL
+);
+
+CREATE TABLE merchant_channels (
+  id BIGSERIAL PRIMARY KEY,
+  channel_id TEXT NOT NULL,
+  contract_id BYTEA NOT NULL,
+  merchant_deposit BYTEA NOT NULL,
+  customer_deposit BYTEA NOT NULL,
+  status TEXT NOT NULL
+    CHECK (status IN (
+      'originated',
+      'customer_funded',
+      'merchant_funded',
+      'active',
+      'pending_expiry',
+      'pending_close',
+      'pending_mutual_close',
+      'pending_merchant_claim',
+      'dispute',
+      'closed'
+    )),
+  closing_balances BYTEA NOT NULL,
+  customer_funding_operation BYTEA,
+  status_updated_at BIGINT NOT NULL DEFAULT 0,
+  blocked BOOLEAN NOT NULL DEFAULT FALSE,
+  customer_funding_address TEXT,
+  dispute_operation TEXT,
+  attention_reason TEXT
+);
+CREATE INDEX merchant_channels_channel_id ON merchant_channels (channel_id);
+
+CREATE TABLE payment_events (
+  id BIGSERIAL PRIMARY KEY,
+  amount BIGINT NOT NULL,
+  created_at BIGINT NOT NULL
+);
+CREATE INDEX payment_events_created_at ON payment_events (created_at);
+
+CREATE TABLE payments (
+  id BIGSERIAL PRIMARY KEY,
+  amount BIGINT NOT NULL,
+  note TEXT NOT NULL,
+  approver TEXT NOT NULL,
+  response_note TEXT,
+  response_url TEXT,
+  outcome TEXT NOT NULL
+    CHECK (outcome IN (
+      'completed',
+      'rejected',
+      'failed'
+    )),
+  failure_reason TEXT,
+  created_at BIGINT NOT NULL
+);
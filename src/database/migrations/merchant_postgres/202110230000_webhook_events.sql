@@ -0,0 +1,4 @@
+CREATE TABLE webhook_events (
+  id BIGSERIAL PRIMARY KEY,
+  payload TEXT NOT NULL
+);
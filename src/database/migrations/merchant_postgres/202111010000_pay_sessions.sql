@@ -0,0 +1,10 @@
+CREATE TABLE pay_sessions (
+  id BIGSERIAL PRIMARY KEY,
+  nonce BYTEA NOT NULL UNIQUE,
+  transcript_digest BYTEA NOT NULL,
+  amount BIGINT NOT NULL,
+  note TEXT NOT NULL,
+  pay_proof BYTEA NOT NULL,
+  created_at BIGINT NOT NULL
+);
+CREATE INDEX pay_sessions_created_at ON pay_sessions (created_at);
@@ -0,0 +1,2 @@
+ALTER TABLE pay_sessions ADD COLUMN response_note TEXT;
+ALTER TABLE pay_sessions ADD COLUMN response_url TEXT;
@@ -0,0 +1,52 @@
+//! Storing values in Postgres which SQLite stores using their own `sqlx` encodings.
+
+use {
+    serde::{de::DeserializeOwned, Serialize},
+    sqlx::{
+        encode::IsNull,
+        error::BoxDynError,
+        postgres::{PgArgumentBuffer, PgRow, PgTypeInfo, PgValueRef},
+        Decode, Encode, Postgres, Row, Type,
+    },
+};
+
+/// A value stored as `BYTEA`, encoded with bincode.
+pub(crate) struct Bincode<T>(pub T);
+
+impl<T> Type<Postgres> for Bincode<T> {
+    fn type_info() -> PgTypeInfo {
+        <Vec<u8> as Type<Postgres>>::type_info()
+    }
+}
+
+impl<T: Serialize> Encode<'_, Postgres> for Bincode<T> {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+        let bytes = bincode::serialize(&self.0).expect("bincode serialization can't fail");
+        <Vec<u8> as Encode<Postgres>>::encode(bytes, buf)
+    }
+}
+
+impl<'r, T: DeserializeOwned> Decode<'r, Postgres> for Bincode<T> {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        let bytes = <&[u8] as Decode<Postgres>>::decode(value)?;
+        Ok(Bincode(bincode::deserialize(bytes)?))
+    }
+}
+
+/// Get a column which was stored as `BYTEA` by [`Bincode`].
+pub(crate) fn get_bincode<T: DeserializeOwned>(
+    row: &PgRow,
+    column: &str,
+) -> Result<T, sqlx::Error> {
+    Ok(row.try_get::<Bincode<T>, _>(column)?.0)
+}
+
+/// Get a nullable column which was stored as `BYTEA` by [`Bincode`].
+pub(crate) fn get_optional_bincode<T: DeserializeOwned>(
+    row: &PgRow,
+    column: &str,
+) -> Result<Option<T>, sqlx::Error> {
+    Ok(row
+        .try_get::<Option<Bincode<T>>, _>(column)?
+        .map(|value| value.0))
+}
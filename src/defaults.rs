@@ -1,5 +1,4 @@
 use {
-    dialectic_reconnect::Backoff,
     directories::ProjectDirs,
     std::{
         net::{IpAddr, Ipv4Addr},
@@ -28,6 +27,10 @@ pub(crate) mod shared {
         1024 * 16
     }
 
+    pub const fn max_note_length() -> u64 {
+        1024 * 8
+    }
+
     pub const fn port() -> u16 {
         2611
     }
@@ -70,6 +73,54 @@ pub mod merchant {
 
     pub const CONFIG_FILE: &str = "Merchant.toml";
 
+    /// How often to check channel contracts for on-chain activity. In production the self-delay
+    /// should be long (at least 48h) so this is the interval used.
+    pub const fn polling_interval() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    pub const fn max_concurrent_dispatches() -> usize {
+        16
+    }
+
+    /// Number of blocks back from the head to search for a customer's funding operation: about a
+    /// day of blocks, far longer than the establish protocol waits for the customer.
+    pub const fn funding_search_depth() -> u64 {
+        2880
+    }
+
+    pub const fn approver_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    /// Length of time a customer making repeated payments may wait between them.
+    pub const fn max_repeat_interval() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    /// Length of time to keep a connection open after a session, in case the customer starts
+    /// another session over it.
+    pub const fn idle_connection_timeout() -> Option<Duration> {
+        Some(Duration::from_secs(10))
+    }
+
+    /// How long to let sessions in progress finish when shutting down.
+    pub const fn shutdown_grace_period() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    /// Whether to reclaim merchant funds from channels which stall after being funded but before
+    /// being activated.
+    pub const fn reclaim_stalled_establish() -> bool {
+        true
+    }
+
+    /// Length of time that a channel may stay funded but not activated before it is considered
+    /// stalled. This is much longer than the establish protocol's own timeouts.
+    pub const fn stalled_establish_timeout() -> Duration {
+        Duration::from_secs(24 * 60 * 60)
+    }
+
     pub fn config_path() -> Result<PathBuf, anyhow::Error> {
         Ok(project_dirs()?.config_dir().join(CONFIG_FILE))
     }
@@ -81,14 +132,52 @@ pub mod customer {
 
     pub use super::shared::*;
 
-    pub fn backoff() -> Backoff {
-        Backoff::with_delay(Duration::from_secs(1))
+    /// How long to wait before the first attempt to reconnect to a merchant.
+    pub const fn reconnect_delay() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    /// How much longer to wait before each further attempt to reconnect: by default, the delay
+    /// stays the same.
+    pub const fn reconnect_multiplier() -> f64 {
+        1.0
     }
 
     pub const fn connection_timeout() -> Option<Duration> {
         Some(Duration::from_secs(60))
     }
 
+    /// Length of time to keep a connection to a merchant open after a session, in case another
+    /// session with the merchant follows. This is shorter than a merchant keeps it open by default.
+    pub const fn idle_connection_timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    pub const fn max_idle_connections() -> usize {
+        4
+    }
+
+    /// Length of time to wait for the merchant's funding to appear on chain during establishment.
+    pub const fn merchant_funding_timeout() -> Duration {
+        transaction_timeout()
+    }
+
+    /// How often the daemon checks channel contracts for on-chain activity. In production the
+    /// self-delay should be long (at least 48h) so this is the interval used.
+    pub const fn polling_interval() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    pub const fn max_concurrent_polls() -> usize {
+        16
+    }
+
+    /// Longest self-delay, in seconds, that a customer accepts from a merchant.
+    pub const fn max_accepted_self_delay() -> u64 {
+        // 7 days, in seconds.
+        7 * 24 * 60 * 60
+    }
+
     pub const CONFIG_FILE: &str = "Customer.toml";
 
     pub const DATABASE_FILE: &str = "customer.db";
@@ -97,10 +186,17 @@ pub mod customer {
         Ok(project_dirs()?.config_dir().join(CONFIG_FILE))
     }
 
+    pub const DAEMON_TOKEN_FILE: &str = "daemon.token";
+
+    pub const DAEMON_SOCKET_FILE: &str = "daemon.sock";
+
+    pub fn data_dir() -> Result<PathBuf, anyhow::Error> {
+        Ok(project_dirs()?.data_dir().to_path_buf())
+    }
+
     pub fn database_location() -> Result<DatabaseLocation, anyhow::Error> {
         Ok(DatabaseLocation::Sqlite(
-            project_dirs()?
-                .data_dir()
+            data_dir()?
                 .join(DATABASE_FILE)
                 .to_str()
                 .ok_or_else(|| anyhow::anyhow!("Invalid UTF-8 in database location path"))?
@@ -108,13 +204,20 @@ pub mod customer {
         ))
     }
 
-    pub const fn max_note_length() -> u64 {
-        1024 * 8
+    pub const fn max_label_suffix() -> u32 {
+        100
+    }
+
+    /// Length of time that the daemon keeps an idle connection to a merchant open, in case a
+    /// payment is proxied through it.
+    pub const fn daemon_idle_timeout() -> Duration {
+        Duration::from_secs(30)
     }
 
-    pub const fn daemon_port() -> u16 {
-        // ZKD :3
-        26114
+    /// Length of time that the daemon lets the notification command run for each event before
+    /// killing it.
+    pub const fn notification_timeout() -> Duration {
+        Duration::from_secs(10)
     }
 
     /// Length of time (seconds) that a customer waits for the merchant to approve a new channel
@@ -122,4 +225,10 @@ pub mod customer {
     pub const fn approval_timeout() -> Duration {
         Duration::from_secs(360)
     }
+
+    /// Length of time that a customer waits for each of the merchant's responses while a payment
+    /// is updating the channel's state.
+    pub const fn payment_response_timeout() -> Duration {
+        Duration::from_secs(120)
+    }
 }
@@ -1,4 +1,5 @@
 pub mod notify;
+pub mod signer;
 pub mod tezos;
 
 pub mod types {
@@ -10,6 +11,7 @@ pub mod types {
     };
     use zkabacus_crypto::PublicKey as ZkAbacusPublicKey;
     use {
+        async_trait::async_trait,
         serde::{Deserialize, Serialize},
         sha3::{Digest, Sha3_256},
         std::{
@@ -17,6 +19,7 @@ pub mod types {
             path::Path,
         },
         thiserror::Error,
+        url::Url,
     };
 
     /// ID for a zkChannels contract originated on Tezos.
@@ -46,10 +49,65 @@ pub mod types {
     /// Tezos public key.
     pub type TezosPublicKey = tezedge::PublicKey;
 
+    /// The canonical bytes of a [`TezosPublicKey`], as bound into a channel ID: the key's raw
+    /// bytes, rather than its base58check encoding.
+    pub fn tezos_public_key_bytes(public_key: &TezosPublicKey) -> &[u8] {
+        public_key.as_ref()
+    }
+
     /// Tezos implicit address; the address of a Tezos account that can fund a zkChannels contract.
     /// An address is the hash of a [`TezosPublicKey`].
     pub type TezosFundingAddress = tezedge::ImplicitAddress;
 
+    /// A Tezos network, either one known by name or one reached at an arbitrary node URI.
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum TezosNetwork {
+        Mainnet,
+        Granadanet,
+        /// A local sandbox node, at `http://localhost:20000`.
+        Sandbox,
+        #[serde(with = "http_serde::uri")]
+        Custom(http::Uri),
+    }
+
+    impl TezosNetwork {
+        /// The URI of a node on this network.
+        pub fn uri(&self) -> http::Uri {
+            match self {
+                TezosNetwork::Mainnet => http::Uri::from_static("https://rpc.tzkt.io/mainnet/"),
+                TezosNetwork::Granadanet => {
+                    http::Uri::from_static("https://rpc.tzkt.io/granadanet/")
+                }
+                TezosNetwork::Sandbox => http::Uri::from_static("http://localhost:20000"),
+                TezosNetwork::Custom(uri) => uri.clone(),
+            }
+        }
+
+        /// The chain ID of this network, if it is a public network with a known chain ID.
+        pub fn chain_id(&self) -> Option<&'static str> {
+            match self {
+                TezosNetwork::Mainnet => Some(MAINNET_CHAIN_ID),
+                TezosNetwork::Granadanet => Some("NetXz969SFaFn8k"),
+                TezosNetwork::Sandbox | TezosNetwork::Custom(_) => None,
+            }
+        }
+    }
+
+    /// The chain ID of the Tezos mainnet.
+    pub const MAINNET_CHAIN_ID: &str = "NetXdQprcVkpaWU";
+
+    impl Display for TezosNetwork {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            match self {
+                TezosNetwork::Mainnet => f.write_str("mainnet"),
+                TezosNetwork::Granadanet => f.write_str("granadanet"),
+                TezosNetwork::Sandbox => f.write_str("sandbox"),
+                TezosNetwork::Custom(uri) => write!(f, "custom network at {}", uri),
+            }
+        }
+    }
+
     /// Set of methods to specify a key in the config file, specified in order of preference.
     ///
     /// Rearranging these is a breaking change due to the untagged serialization.
@@ -57,7 +115,15 @@ pub mod types {
     #[serde(untagged)]
     pub enum KeySpecifier {
         Path(PathBuf),
-        Alias { alias: String },
+        Alias {
+            alias: String,
+        },
+        /// A key held by a remote signer speaking the standard Tezos remote signer protocol, which
+        /// never leaves the signer.
+        Remote {
+            signer_url: Url,
+            address: String,
+        },
     }
 
     impl KeySpecifier {
@@ -75,10 +141,52 @@ pub mod types {
             match self {
                 KeySpecifier::Path(path) => path.to_string_lossy(),
                 KeySpecifier::Alias { alias } => Cow::from(alias.clone()),
+                KeySpecifier::Remote { address, .. } => Cow::from(address.clone()),
             }
         }
     }
 
+    /// Something which can sign Tezos operations on behalf of an account.
+    ///
+    /// This is either key material held in memory, or something holding the key elsewhere, such as
+    /// a remote signer (see [`RemoteSigner`](crate::escrow::signer::RemoteSigner)).
+    #[async_trait]
+    pub trait TezosSigner: Send + Sync + 'static {
+        /// Get the public key of the account.
+        fn public_key(&self) -> &TezosPublicKey;
+
+        /// Get the address of the account.
+        fn funding_address(&self) -> TezosFundingAddress {
+            self.public_key().hash()
+        }
+
+        /// Sign a forged operation, returning the base58check-encoded signature.
+        async fn sign_operation(&self, forged_operation: &[u8]) -> Result<String, SignerError>;
+
+        /// Get the key material for the account, if it is held in memory.
+        ///
+        /// Operations for an account with key material in memory are signed directly by pytezos
+        /// rather than through [`TezosSigner::sign_operation`].
+        fn key_material(&self) -> Option<&TezosKeyMaterial> {
+            None
+        }
+    }
+
+    /// An error while signing a Tezos operation.
+    #[derive(Debug, Error)]
+    pub enum SignerError {
+        #[error("Could not sign with key held in memory: {0}")]
+        Local(String),
+        #[error("Could not reach remote signer: {0}")]
+        Unreachable(String),
+        #[error("Remote signer refused request: {0}")]
+        Refused(String),
+        #[error("Remote signer holds key {0}, which does not match the configured address")]
+        WrongKey(String),
+        #[error("Invalid configured address for remote signer: {0}")]
+        InvalidAddress(String),
+    }
+
     /// Tezos key material, with public key and contents of key file.
     #[derive(Clone)]
     pub struct TezosKeyMaterial {
@@ -92,6 +200,9 @@ pub mod types {
         /// The file should use the key file json formatting that is also used by faucet:
         /// <https://faucet.tzalpha.net/>
         pub fn read_key_pair(key_specifier: &KeySpecifier) -> Result<TezosKeyMaterial, Error> {
+            if let KeySpecifier::Remote { .. } = key_specifier {
+                return Err(Error::KeyNotInMemory);
+            }
             let path = key_specifier.into_python_type();
 
             // Use pytezos parsing functions to parse account config file.
@@ -142,6 +253,8 @@ pub mod types {
         pub merchant_tezos_public_key: TezosPublicKey,
         /// ID of Tezos contract originated on chain.
         pub contract_id: Option<ContractId>,
+        /// Level of the block in which the contract was originated.
+        pub contract_level: Option<u32>,
     }
 
     impl ContractDetails {
@@ -150,6 +263,32 @@ pub mod types {
         }
     }
 
+    /// The on-chain record of an operation which funded a zkChannels contract.
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct FundingOperation {
+        /// Hash of the operation, encoded with base58check.
+        hash: String,
+        /// Level of the block in which the operation was included.
+        level: u32,
+    }
+    zkabacus_crypto::impl_sqlx_for_bincode_ty!(FundingOperation);
+
+    impl FundingOperation {
+        pub fn new(hash: String, level: u32) -> Self {
+            Self { hash, level }
+        }
+
+        /// Get the hash of the operation.
+        pub fn hash(&self) -> &str {
+            &self.hash
+        }
+
+        /// Get the level of the block in which the operation was included.
+        pub fn level(&self) -> u32 {
+            self.level
+        }
+    }
+
     /// A SHA3-256 hash of the contract's Micheline JSON encoding.
     #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
     pub struct ContractHash([u8; 32]);
@@ -165,11 +304,38 @@ pub mod types {
             digested.copy_from_slice(hasher.finalize().as_ref());
             Self(digested)
         }
+
+        /// Get the bytes of the hash.
+        pub fn as_bytes(&self) -> &[u8; 32] {
+            &self.0
+        }
     }
 
     /// A SHA3-256 hash of the merchant's public keys.
     #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
     pub struct KeyHash([u8; 32]);
+    zkabacus_crypto::impl_sqlx_for_bincode_ty!(KeyHash);
+
+    /// A key hash is displayed, and parsed, as hex.
+    impl Display for KeyHash {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", hex::encode(self.0))
+        }
+    }
+
+    #[derive(Debug, Clone, Error)]
+    #[error("Invalid key hash: expected 64 hex digits")]
+    pub struct ParseKeyHashError;
+
+    impl std::str::FromStr for KeyHash {
+        type Err = ParseKeyHashError;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let mut bytes = [0; 32];
+            hex::decode_to_slice(s, &mut bytes).map_err(|_| ParseKeyHashError)?;
+            Ok(Self(bytes))
+        }
+    }
 
     impl KeyHash {
         /// Compute the SHA3-256 hash of the merchant's Pointcheval-Sanders [`ZkAbacusPublicKey`],
@@ -196,7 +362,7 @@ pub mod types {
     }
 
     /// The set of entrypoints on the zkChannels Tezos smart contract.
-    #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
     pub enum Entrypoint {
         Originate,
         AddMerchantFunding,
@@ -211,6 +377,8 @@ pub mod types {
         MutualClose,
     }
 
+    zkabacus_crypto::impl_sqlx_for_bincode_ty!(Entrypoint);
+
     impl Display for Entrypoint {
         fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
             f.write_str(match self {
@@ -230,7 +398,7 @@ pub mod types {
     }
 
     /// The set of statuses that a zkChannels contract can enter.
-    #[derive(Debug, Clone, Copy, PartialEq)]
+    #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
     pub enum ContractStatus {
         AwaitingCustomerFunding = 0,
         AwaitingMerchantFunding = 1,
@@ -289,6 +457,8 @@ pub mod types {
         SigningFailed(ContractId),
         #[error("Key file was invalid: {0}")]
         KeyFileInvalid(String),
+        #[error("Key is held by a remote signer, not in memory")]
+        KeyNotInMemory,
     }
 
     #[cfg(test)]
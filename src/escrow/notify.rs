@@ -1,89 +1,329 @@
 use {
+    async_trait::async_trait,
     serde::{Deserialize, Serialize},
-    std::{
-        pin::Pin,
-        task::{Context, Poll},
-    },
+    std::time::Duration,
+    thiserror::Error,
+    tokio::sync::{broadcast, Mutex},
 };
 
 use tezedge::OperationHash;
 
-use super::types::ContractId;
-use {
-    futures::stream::Stream,
-    std::{
-        cmp::Reverse,
-        future::Future,
-        hash::Hash,
-        ops::{Add, Sub},
-    },
+use super::{
+    tezos::{self, BlockHashError, ContractCallsError, ContractStatusError, HeadLevelError},
+    types::{ContractId, ContractStatus, Entrypoint},
+};
+use std::{
+    cmp::Reverse,
+    future::Future,
+    hash::Hash,
+    ops::{Add, Sub},
 };
 
 pub struct Notifications {}
 
-pub struct ContractEventStream {}
+/// The number of events a subscriber to a [`ContractNotifier`] may fall behind by before it
+/// misses some.
+const EVENT_CAPACITY: usize = 256;
 
-#[allow(unused)]
+/// The most blocks a [`ContractNotifier`] will look through one by one after falling behind; beyond
+/// this, it checks the status of every watched contract at the head instead.
+const MAX_CATCH_UP: u32 = 64;
+
+/// A change in the on-chain status of a watched contract.
+#[derive(Debug, Clone, PartialEq)]
 pub struct ContractEvent {
-    contract: ContractId,
-    operation: OperationHash,
-    event: ZkChannelEvent,
+    /// The contract whose status changed.
+    pub contract_id: ContractId,
+    /// The status of the contract after the change.
+    pub new_status: ContractStatus,
+    /// The level of the block at which the change was observed.
+    pub level: Level,
+    /// The entrypoint whose call changed the status, if it could be determined.
+    pub entrypoint: Option<Entrypoint>,
 }
 
-pub enum ZkChannelEvent {
-    // TODO: zkchannels domain specific event types
+/// A call to an entrypoint of an originated contract, as found in a block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContractCall {
+    pub contract_id: ContractId,
+    /// The name of the entrypoint called, as it appears in the contract (e.g. `custClose`).
+    pub entrypoint: String,
 }
 
-pub enum Error {
-    Reorg,
-    Io(std::io::Error),
-    // TODO: maybe other kinds of errors, add them here
+/// A source of blocks and contract storage for a [`ContractNotifier`] to watch.
+#[async_trait]
+pub trait ChainSource: Send + Sync {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// The level of the head block.
+    async fn head_level(&self) -> Result<Level, Self::Error>;
+
+    /// The hash of the block at the given level.
+    async fn block_hash(&self, level: Level) -> Result<String, Self::Error>;
+
+    /// The calls to originated contracts in the block at the given level, in block order.
+    async fn contract_calls(&self, level: Level) -> Result<Vec<ContractCall>, Self::Error>;
+
+    /// The status of a contract as of the block at the given level.
+    async fn contract_status(
+        &self,
+        contract_id: &ContractId,
+        level: Level,
+    ) -> Result<ContractStatus, Self::Error>;
+
+    /// The status of a contract as of the head block, without waiting for it to be confirmed at
+    /// any depth.
+    async fn head_contract_status(
+        &self,
+        contract_id: &ContractId,
+    ) -> Result<ContractStatus, Self::Error> {
+        let head = self.head_level().await?;
+        self.contract_status(contract_id, head).await
+    }
 }
 
-#[allow(unused)]
-impl Stream for ContractEventStream {
-    type Item = Result<ContractEvent, std::io::Error>;
+/// An error while watching a Tezos node for changes to contracts.
+#[derive(Debug, Error)]
+pub enum NotifierError {
+    #[error(transparent)]
+    HeadLevel(#[from] HeadLevelError),
+    #[error(transparent)]
+    BlockHash(#[from] BlockHashError),
+    #[error(transparent)]
+    ContractCalls(#[from] ContractCallsError),
+    #[error(transparent)]
+    ContractStatus(#[from] ContractStatusError),
+}
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        todo!()
+/// A Tezos node, reached at some URI, as a [`ChainSource`].
+#[derive(Debug, Clone)]
+pub struct TezosChain {
+    uri: http::Uri,
+}
+
+impl TezosChain {
+    pub fn new(uri: http::Uri) -> Self {
+        Self { uri }
     }
 }
 
-pub struct ContractEventStreamHandle {}
+#[async_trait]
+impl ChainSource for TezosChain {
+    type Error = NotifierError;
 
-#[allow(unused)]
-impl ContractEventStreamHandle {
-    /// Add the given [`ContractId`] to the set of streamed contracts.
-    /// The [`Level`] parameter indicates the level at which the [`ContractId`] was originated
-    /// on chain.
-    ///
-    /// This will stream all events for the [`ContractId`] that have occurred on chain between
-    /// [`Level`] and the current chain height.
-    pub async fn add_contract(&self, contract_hash: &ContractId, originated: Level) {
-        todo!()
+    async fn head_level(&self) -> Result<Level, Self::Error> {
+        Ok(tezos::head_level(&self.uri).await?.into())
     }
 
-    /// Remove the given [`ContractId`] from the set of streamed contracts.
-    pub async fn remove_contract(&self, contract_hash: &ContractId) {
-        todo!()
+    async fn block_hash(&self, level: Level) -> Result<String, Self::Error> {
+        Ok(tezos::block_hash(&self.uri, level.into()).await?)
     }
 
-    /// Replace the set of streamed contracts with the given `contract_hashes`.
-    /// The [`Level`] parameters indicate the level at which the [`ContractId`]s are originated
-    /// on chain.
-    ///
-    /// This will not cause duplicated events for [`ContractId`]s that were already
-    /// in the set of streamed contracts.
-    /// This will stream all events for each _new_ [`ContractId`] that have occurred on chain
-    /// between [`Level`] and the current chain height.
-    pub async fn set_contracts(
+    async fn contract_calls(&self, level: Level) -> Result<Vec<ContractCall>, Self::Error> {
+        Ok(tezos::contract_calls(&self.uri, level.into())
+            .await?
+            .into_iter()
+            .map(|(contract_id, entrypoint)| ContractCall {
+                contract_id,
+                entrypoint,
+            })
+            .collect())
+    }
+
+    async fn contract_status(
         &self,
-        contract_hashes: impl IntoIterator<Item = &(ContractId, Level)>,
-    ) {
-        todo!()
+        contract_id: &ContractId,
+        level: Level,
+    ) -> Result<ContractStatus, Self::Error> {
+        Ok(tezos::contract_status_at(&self.uri, contract_id, level.into()).await?)
+    }
+}
+
+/// Watches a set of contracts for changes to their status, and publishes a [`ContractEvent`] to
+/// every subscriber whenever one changes.
+///
+/// Only contracts called in a new block have their storage checked, so watching many contracts
+/// costs little more than watching for new blocks.
+pub struct ContractNotifier<S> {
+    source: S,
+    sender: broadcast::Sender<ContractEvent>,
+    state: Mutex<WatchState>,
+}
+
+/// What a [`ContractNotifier`] knows of the chain.
+struct WatchState {
+    /// The level and hash of the last block checked, if any.
+    last_block: Option<(Level, String)>,
+    /// The watched contracts, each with its status as of `level`, if known.
+    contracts: Vec<(ContractId, Option<ContractStatus>)>,
+}
+
+impl ContractNotifier<TezosChain> {
+    /// Watch the given contracts on the Tezos node at the URI.
+    pub fn tezos(uri: http::Uri, contract_ids: impl IntoIterator<Item = ContractId>) -> Self {
+        Self::new(TezosChain::new(uri), contract_ids)
     }
 }
 
+impl<S: ChainSource> ContractNotifier<S> {
+    /// Watch the given contracts on the chain provided by the source.
+    pub fn new(source: S, contract_ids: impl IntoIterator<Item = ContractId>) -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        Self {
+            source,
+            sender,
+            state: Mutex::new(WatchState {
+                last_block: None,
+                contracts: contract_ids.into_iter().map(|id| (id, None)).collect(),
+            }),
+        }
+    }
+
+    /// Receive every [`ContractEvent`] published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ContractEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Replace the set of watched contracts.
+    ///
+    /// Contracts which were already watched keep their known status, so this doesn't cause
+    /// duplicate events; the status of newly watched contracts is recorded at the next poll
+    /// without publishing an event.
+    pub async fn set_contracts(&self, contract_ids: impl IntoIterator<Item = ContractId>) {
+        let mut state = self.state.lock().await;
+        let mut previous = std::mem::take(&mut state.contracts);
+        state.contracts = contract_ids
+            .into_iter()
+            .map(|id| {
+                let status = previous
+                    .iter()
+                    .position(|(watched, _)| *watched == id)
+                    .and_then(|i| previous.swap_remove(i).1);
+                (id, status)
+            })
+            .collect();
+    }
+
+    /// Check every block since the last poll for changes to watched contracts, publishing and
+    /// returning an event for each change.
+    ///
+    /// If this fails, nothing is published, and the next poll starts from the same place.
+    pub async fn poll(&self) -> Result<Vec<ContractEvent>, S::Error> {
+        let mut state = self.state.lock().await;
+        let head = self.source.head_level().await?;
+        let mut contracts = state.contracts.clone();
+        let mut events = Vec::new();
+
+        // Only walk forward from the last block checked if it is still on the chain. A
+        // reorganization may have replaced it whether the head is now lower, at the same level,
+        // or higher, so compare its hash rather than the head's level
+        let last = match &state.last_block {
+            Some((last, hash)) if *last <= head => {
+                if self.source.block_hash(*last).await? == *hash {
+                    Some(*last)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        match last {
+            // Look through each new block for calls to watched contracts
+            Some(last) if last < head && u32::from(head) - u32::from(last) <= MAX_CATCH_UP => {
+                for level in u32::from(last) + 1..=u32::from(head) {
+                    let level = Level::from(level);
+                    let calls = self.source.contract_calls(level).await?;
+                    for (contract_id, status) in contracts.iter_mut() {
+                        // Only the last call in the block determines the status at its end
+                        let call = match calls
+                            .iter()
+                            .rev()
+                            .find(|call| call.contract_id == *contract_id)
+                        {
+                            Some(call) => call,
+                            None => continue,
+                        };
+                        let new_status = self.source.contract_status(contract_id, level).await?;
+                        if status.map_or(false, |status| status != new_status) {
+                            events.push(ContractEvent {
+                                contract_id: contract_id.clone(),
+                                new_status,
+                                level,
+                                entrypoint: entrypoint_named(&call.entrypoint),
+                            });
+                        }
+                        *status = Some(new_status);
+                    }
+                }
+            }
+            // No new blocks
+            Some(last) if last == head => {}
+            // On the first poll, after a reorganization replaced the last block checked, or after
+            // falling far behind, compare every watched contract's status at the head
+            _ => {
+                for (contract_id, status) in contracts.iter_mut() {
+                    let new_status = self.source.contract_status(contract_id, head).await?;
+                    if status.map_or(false, |status| status != new_status) {
+                        events.push(ContractEvent {
+                            contract_id: contract_id.clone(),
+                            new_status,
+                            level: head,
+                            entrypoint: None,
+                        });
+                    }
+                    *status = Some(new_status);
+                }
+            }
+        }
+
+        // Record the status of contracts which weren't watched before, without reporting a change
+        for (contract_id, status) in contracts.iter_mut().filter(|(_, status)| status.is_none()) {
+            *status = Some(self.source.contract_status(contract_id, head).await?);
+        }
+
+        state.last_block = Some((head, self.source.block_hash(head).await?));
+        state.contracts = contracts;
+
+        for event in &events {
+            // It's fine for there to be no subscribers
+            let _ = self.sender.send(event.clone());
+        }
+        Ok(events)
+    }
+
+    /// Poll for changes at the given interval, until polling fails.
+    pub async fn run(&self, interval: Duration) -> Result<(), S::Error> {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            self.poll().await?;
+        }
+    }
+}
+
+/// The [`Entrypoint`] with the given name in the contract, if it identifies one.
+fn entrypoint_named(name: &str) -> Option<Entrypoint> {
+    Some(match name {
+        "addCustFunding" => Entrypoint::AddCustomerFunding,
+        "addMerchFunding" => Entrypoint::AddMerchantFunding,
+        "expiry" => Entrypoint::Expiry,
+        "custClose" => Entrypoint::CustomerClose,
+        "merchDispute" => Entrypoint::MerchantDispute,
+        "custClaim" => Entrypoint::CustomerClaim,
+        "merchClaim" => Entrypoint::MerchantClaim,
+        "mutualClose" => Entrypoint::MutualClose,
+        // Either party may call `reclaimFunding`, and its name doesn't say which
+        _ => return None,
+    })
+}
+
+pub enum Error {
+    Reorg,
+    Io(std::io::Error),
+    // TODO: maybe other kinds of errors, add them here
+}
+
 #[allow(unused)]
 impl Notifications {
     /// Wait for confirmation that the specified operation is confirmed at the given [`Depth`].
@@ -97,12 +337,6 @@ impl Notifications {
     ) -> Result<(), Error> {
         todo!()
     }
-
-    /// Get a stream of events and a linked handle that allows the contents of the stream to be
-    /// updated by another task.
-    pub async fn contract_events(&self) -> (ContractEventStreamHandle, ContractEventStream) {
-        todo!()
-    }
 }
 
 #[derive(
@@ -188,3 +422,384 @@ pub trait Fetch {
 
     fn fetch_head(&mut self) -> Self::Future;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::establish;
+    use {std::collections::HashMap, tezedge::OriginatedAddress};
+
+    #[derive(Debug, Error)]
+    #[error("Mock chain is unavailable")]
+    struct Unavailable;
+
+    /// A chain whose blocks contain exactly the calls made through [`MockChain::call`].
+    #[derive(Default)]
+    struct MockChain {
+        inner: std::sync::Mutex<MockState>,
+    }
+
+    #[derive(Default)]
+    struct MockState {
+        head: u32,
+        calls: HashMap<u32, Vec<ContractCall>>,
+        /// Each contract's status from some level onwards.
+        statuses: Vec<(ContractId, u32, ContractStatus)>,
+        /// The levels from which blocks were replaced in each reorganization.
+        reorganizations: Vec<u32>,
+        status_queries: usize,
+        unavailable: bool,
+    }
+
+    impl MockChain {
+        fn originate(&self, contract_id: &ContractId, status: ContractStatus) {
+            let mut state = self.inner.lock().unwrap();
+            state.statuses.push((contract_id.clone(), 0, status));
+        }
+
+        fn call(
+            &self,
+            level: u32,
+            contract_id: &ContractId,
+            entrypoint: &str,
+            status: ContractStatus,
+        ) {
+            let mut state = self.inner.lock().unwrap();
+            state.calls.entry(level).or_default().push(ContractCall {
+                contract_id: contract_id.clone(),
+                entrypoint: entrypoint.to_string(),
+            });
+            state.statuses.push((contract_id.clone(), level, status));
+            state.head = state.head.max(level);
+        }
+
+        fn set_head(&self, level: u32) {
+            self.inner.lock().unwrap().head = level;
+        }
+
+        /// Replace every block from the given level onwards with a different one.
+        fn reorganize(&self, level: u32) {
+            self.inner.lock().unwrap().reorganizations.push(level);
+        }
+
+        fn set_unavailable(&self, unavailable: bool) {
+            self.inner.lock().unwrap().unavailable = unavailable;
+        }
+
+        fn status_queries(&self) -> usize {
+            self.inner.lock().unwrap().status_queries
+        }
+    }
+
+    #[async_trait]
+    impl ChainSource for MockChain {
+        type Error = Unavailable;
+
+        async fn head_level(&self) -> Result<Level, Self::Error> {
+            Ok(self.inner.lock().unwrap().head.into())
+        }
+
+        async fn block_hash(&self, level: Level) -> Result<String, Self::Error> {
+            let state = self.inner.lock().unwrap();
+            let level = u32::from(level);
+            let replaced = state
+                .reorganizations
+                .iter()
+                .filter(|from| **from <= level)
+                .count();
+            Ok(format!("{}/{}", level, replaced))
+        }
+
+        async fn contract_calls(&self, level: Level) -> Result<Vec<ContractCall>, Self::Error> {
+            let state = self.inner.lock().unwrap();
+            Ok(state
+                .calls
+                .get(&u32::from(level))
+                .cloned()
+                .unwrap_or_default())
+        }
+
+        async fn contract_status(
+            &self,
+            contract_id: &ContractId,
+            level: Level,
+        ) -> Result<ContractStatus, Self::Error> {
+            let mut state = self.inner.lock().unwrap();
+            if state.unavailable {
+                return Err(Unavailable);
+            }
+            state.status_queries += 1;
+            let level = u32::from(level);
+            Ok(state
+                .statuses
+                .iter()
+                .filter(|(id, from, _)| id == contract_id && *from <= level)
+                .max_by_key(|(_, from, _)| *from)
+                .map(|(_, _, status)| *status)
+                .expect("Mock contract must be originated before its status is queried"))
+        }
+    }
+
+    fn contract_id(address: &str) -> ContractId {
+        ContractId::new(OriginatedAddress::from_base58check(address).unwrap())
+    }
+
+    fn contracts() -> (ContractId, ContractId) {
+        (
+            contract_id("KT1Mjjcb6tmSsLm7Cb3DSQszePjfchPM4Uxm"),
+            contract_id("KT1PWx2mnDueood7fEmfbBDKx1D9BAnnXitn"),
+        )
+    }
+
+    #[tokio::test]
+    async fn notifier_publishes_status_changes() {
+        let (first, second) = contracts();
+        let chain = MockChain::default();
+        chain.originate(&first, ContractStatus::Open);
+        chain.originate(&second, ContractStatus::Open);
+        chain.set_head(10);
+
+        let notifier = ContractNotifier::new(chain, vec![first.clone(), second.clone()]);
+        let mut events = notifier.subscribe();
+
+        // The first poll only records the current statuses
+        assert!(notifier.poll().await.unwrap().is_empty());
+
+        // A call which doesn't change the status isn't reported
+        notifier
+            .source
+            .call(11, &second, "addMerchFunding", ContractStatus::Open);
+        notifier
+            .source
+            .call(12, &first, "custClose", ContractStatus::CustomerClose);
+        notifier.source.set_head(13);
+        let queries = notifier.source.status_queries();
+
+        let expected = ContractEvent {
+            contract_id: first.clone(),
+            new_status: ContractStatus::CustomerClose,
+            level: 12.into(),
+            entrypoint: Some(Entrypoint::CustomerClose),
+        };
+        assert_eq!(notifier.poll().await.unwrap(), vec![expected.clone()]);
+        assert_eq!(events.recv().await.unwrap(), expected);
+
+        // Only the contracts called in new blocks had their storage checked
+        assert_eq!(notifier.source.status_queries(), queries + 2);
+
+        // Polling again without new blocks reports nothing and checks no storage
+        assert!(notifier.poll().await.unwrap().is_empty());
+        assert_eq!(notifier.source.status_queries(), queries + 2);
+    }
+
+    #[tokio::test]
+    async fn notifier_rechecks_every_contract_after_falling_behind() {
+        let (first, second) = contracts();
+        let chain = MockChain::default();
+        chain.originate(&first, ContractStatus::Open);
+        chain.originate(&second, ContractStatus::Open);
+        chain.set_head(10);
+
+        let notifier = ContractNotifier::new(chain, vec![first.clone(), second.clone()]);
+        notifier.poll().await.unwrap();
+
+        notifier.source.call(
+            11,
+            &second,
+            "reclaimFunding",
+            ContractStatus::FundingReclaimed,
+        );
+        notifier.source.set_head(11 + MAX_CATCH_UP);
+
+        assert_eq!(
+            notifier.poll().await.unwrap(),
+            vec![ContractEvent {
+                contract_id: second,
+                new_status: ContractStatus::FundingReclaimed,
+                level: (11 + MAX_CATCH_UP).into(),
+                entrypoint: None,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn notifier_rechecks_every_contract_after_a_reorganization() {
+        let (first, second) = contracts();
+        let chain = MockChain::default();
+        chain.originate(&first, ContractStatus::Open);
+        chain.originate(&second, ContractStatus::Open);
+        chain.set_head(10);
+
+        let notifier = ContractNotifier::new(chain, vec![first.clone(), second.clone()]);
+        notifier.poll().await.unwrap();
+
+        // The last block checked is replaced by one at the same level
+        notifier.source.reorganize(10);
+        notifier
+            .source
+            .call(10, &first, "custClose", ContractStatus::CustomerClose);
+        assert_eq!(
+            notifier.poll().await.unwrap(),
+            vec![ContractEvent {
+                contract_id: first,
+                new_status: ContractStatus::CustomerClose,
+                level: 10.into(),
+                entrypoint: None,
+            }]
+        );
+
+        // The last block checked is replaced, and the head moves past it
+        notifier.source.reorganize(10);
+        notifier
+            .source
+            .call(10, &second, "expiry", ContractStatus::Expiry);
+        notifier.source.set_head(11);
+        assert_eq!(
+            notifier.poll().await.unwrap(),
+            vec![ContractEvent {
+                contract_id: second,
+                new_status: ContractStatus::Expiry,
+                level: 11.into(),
+                entrypoint: None,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn notifier_publishes_nothing_from_a_failed_poll() {
+        let (first, second) = contracts();
+        let chain = MockChain::default();
+        chain.originate(&first, ContractStatus::Open);
+        chain.originate(&second, ContractStatus::AwaitingCustomerFunding);
+        chain.set_head(10);
+
+        let notifier = ContractNotifier::new(chain, vec![first.clone()]);
+        let mut events = notifier.subscribe();
+        notifier.poll().await.unwrap();
+
+        notifier
+            .source
+            .call(11, &first, "expiry", ContractStatus::Expiry);
+        notifier.source.set_unavailable(true);
+        assert!(notifier.poll().await.is_err());
+        assert!(events.try_recv().is_err());
+
+        // The next poll picks up where the failed one started
+        notifier.source.set_unavailable(false);
+        let events_published = notifier.poll().await.unwrap();
+        assert_eq!(events_published.len(), 1);
+        assert_eq!(events.try_recv().unwrap(), events_published[0]);
+
+        // A newly watched contract's status is recorded without being reported, while the
+        // status of contracts still watched is kept
+        notifier
+            .set_contracts(vec![first.clone(), second.clone()])
+            .await;
+        notifier.source.set_head(12);
+        assert!(notifier.poll().await.unwrap().is_empty());
+        notifier.source.call(
+            13,
+            &second,
+            "addCustFunding",
+            ContractStatus::AwaitingMerchantFunding,
+        );
+        assert_eq!(
+            notifier.poll().await.unwrap(),
+            vec![ContractEvent {
+                contract_id: second,
+                new_status: ContractStatus::AwaitingMerchantFunding,
+                level: 13.into(),
+                entrypoint: Some(Entrypoint::AddCustomerFunding),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn reclaim_at_head_stops_merchant_funding() {
+        let (contract, _) = contracts();
+        let chain = MockChain::default();
+        chain.originate(&contract, ContractStatus::AwaitingCustomerFunding);
+        chain.call(
+            10,
+            &contract,
+            "addCustFunding",
+            ContractStatus::AwaitingMerchantFunding,
+        );
+        chain.set_head(12);
+        assert!(establish::check_not_reclaimed(
+            chain.head_contract_status(&contract).await.unwrap()
+        )
+        .is_ok());
+
+        // The customer reclaims their funding after it was verified at the confirmation depth
+        chain.call(
+            13,
+            &contract,
+            "reclaimFunding",
+            ContractStatus::FundingReclaimed,
+        );
+        assert_eq!(
+            chain.contract_status(&contract, 12.into()).await.unwrap(),
+            ContractStatus::AwaitingMerchantFunding
+        );
+        assert!(matches!(
+            establish::check_not_reclaimed(chain.head_contract_status(&contract).await.unwrap()),
+            Err(establish::Error::FundingReclaimed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn stalled_establishment_is_reclaimed_once_merchant_funded() {
+        use crate::protocol::ChannelStatus;
+        use establish::{stalled_establish_action, StalledEstablishAction};
+
+        let (funded, reclaimed) = contracts();
+        let chain = MockChain::default();
+        for contract in [&funded, &reclaimed] {
+            chain.originate(contract, ContractStatus::AwaitingCustomerFunding);
+            chain.call(
+                10,
+                contract,
+                "addCustFunding",
+                ContractStatus::AwaitingMerchantFunding,
+            );
+        }
+
+        // Until the merchant funds the contract, they have nothing in it to reclaim
+        assert_eq!(
+            stalled_establish_action(
+                chain.head_contract_status(&funded).await.unwrap(),
+                ChannelStatus::CustomerFunded
+            ),
+            None
+        );
+
+        // Once it is funded, the merchant reclaims their funds, recording the funding if the
+        // channel missed it
+        chain.call(11, &funded, "addMerchFunding", ContractStatus::Open);
+        let status = chain.head_contract_status(&funded).await.unwrap();
+        assert_eq!(
+            stalled_establish_action(status, ChannelStatus::CustomerFunded),
+            Some(StalledEstablishAction::RecordFundingAndExpire)
+        );
+        assert_eq!(
+            stalled_establish_action(status, ChannelStatus::MerchantFunded),
+            Some(StalledEstablishAction::Expire)
+        );
+
+        // A contract the customer reclaimed their funding from is left alone
+        chain.call(
+            12,
+            &reclaimed,
+            "reclaimFunding",
+            ContractStatus::FundingReclaimed,
+        );
+        assert_eq!(
+            stalled_establish_action(
+                chain.head_contract_status(&reclaimed).await.unwrap(),
+                ChannelStatus::CustomerFunded
+            ),
+            None
+        );
+    }
+}
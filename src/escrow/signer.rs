@@ -0,0 +1,231 @@
+//! Signers for Tezos operations: key material held in memory, and remote signers speaking the
+//! standard Tezos remote signer protocol over HTTP.
+
+use {
+    async_trait::async_trait, inline_python::python, serde::Deserialize, tezedge::ToBase58Check,
+    url::Url,
+};
+
+use crate::escrow::types::{
+    SignerError, TezosFundingAddress, TezosKeyMaterial, TezosPublicKey, TezosSigner,
+};
+
+/// The watermark prefixed to an operation before signing, marking it as a generic operation.
+const GENERIC_OPERATION_WATERMARK: u8 = 0x03;
+
+#[async_trait]
+impl TezosSigner for TezosKeyMaterial {
+    fn public_key(&self) -> &TezosPublicKey {
+        TezosKeyMaterial::public_key(self)
+    }
+
+    async fn sign_operation(&self, forged_operation: &[u8]) -> Result<String, SignerError> {
+        let secret_key = self.private_key().to_base58check();
+        let forged_operation = hex::encode(forged_operation);
+
+        tokio::task::spawn_blocking(move || {
+            let context: inline_python::Context = python! {
+                from pytezos import Key
+                key = Key.from_encoded_key('secret_key)
+                signature = key.sign(bytes.fromhex('forged_operation), generic=True)
+            };
+            context.get::<String>("signature")
+        })
+        .await
+        .map_err(|e| SignerError::Local(e.to_string()))
+    }
+
+    fn key_material(&self) -> Option<&TezosKeyMaterial> {
+        Some(self)
+    }
+}
+
+/// A key held by a remote signer, such as `tezos-signer` or a signer in front of a hardware
+/// wallet, which is asked to sign each operation over HTTP.
+#[derive(Debug, Clone)]
+pub struct RemoteSigner {
+    client: reqwest::Client,
+    key_url: Url,
+    public_key: TezosPublicKey,
+}
+
+#[derive(Deserialize)]
+struct PublicKeyResponse {
+    public_key: String,
+}
+
+#[derive(Deserialize)]
+struct SignatureResponse {
+    signature: String,
+}
+
+impl RemoteSigner {
+    /// Connect to the remote signer at the URL, retrieving the public key for the address and
+    /// checking that it matches.
+    pub async fn connect(signer_url: &Url, address: &str) -> Result<Self, SignerError> {
+        let funding_address = TezosFundingAddress::from_base58check(address)
+            .map_err(|_| SignerError::InvalidAddress(address.to_string()))?;
+
+        let mut key_url = signer_url.clone();
+        key_url
+            .path_segments_mut()
+            .map_err(|_| SignerError::Unreachable(format!("invalid signer URL {}", signer_url)))?
+            .pop_if_empty()
+            .extend(&["keys", address]);
+
+        let client = reqwest::Client::new();
+        let response = client
+            .get(key_url.clone())
+            .send()
+            .await
+            .map_err(|e| SignerError::Unreachable(e.to_string()))?;
+        let PublicKeyResponse { public_key } = parse_response(response).await?;
+
+        let public_key = TezosPublicKey::from_base58check(&public_key)
+            .map_err(|_| SignerError::WrongKey(public_key.clone()))?;
+        if public_key.hash() != funding_address {
+            return Err(SignerError::WrongKey(public_key.to_base58check()));
+        }
+
+        Ok(Self {
+            client,
+            key_url,
+            public_key,
+        })
+    }
+}
+
+#[async_trait]
+impl TezosSigner for RemoteSigner {
+    fn public_key(&self) -> &TezosPublicKey {
+        &self.public_key
+    }
+
+    async fn sign_operation(&self, forged_operation: &[u8]) -> Result<String, SignerError> {
+        let mut watermarked = vec![GENERIC_OPERATION_WATERMARK];
+        watermarked.extend_from_slice(forged_operation);
+
+        let response = self
+            .client
+            .post(self.key_url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(format!("\"{}\"", hex::encode(watermarked)))
+            .send()
+            .await
+            .map_err(|e| SignerError::Unreachable(e.to_string()))?;
+        let SignatureResponse { signature } = parse_response(response).await?;
+        Ok(signature)
+    }
+}
+
+/// Parse a JSON response from a remote signer, treating any unsuccessful status as a refusal.
+async fn parse_response<T: for<'de> Deserialize<'de>>(
+    response: reqwest::Response,
+) -> Result<T, SignerError> {
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .map_err(|e| SignerError::Unreachable(e.to_string()))?;
+    if !status.is_success() {
+        return Err(SignerError::Refused(format!("{}: {}", status, body)));
+    }
+    serde_json::from_str(&body).map_err(|e| SignerError::Refused(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    const PUBLIC_KEY: &str = "edpku5Ei6Dni4qwoJGqXJs13xHfyu4fhUg6zqZkFyiEh1mQhFD3iZE";
+    const SIGNATURE: &str = "edsigtXomBKi5CTRf5cjATJWSyaRvhfYNHqSUGrn4SdbYRcGwQrUGjzEfQDTuqHhuA8b2d8NarZjz8TRf65WkpQmo423BtomS8Q";
+
+    /// Run a mock remote signer for the public key, returning its URL and a record of the bodies
+    /// of the signing requests it receives.
+    async fn mock_signer() -> (Url, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let signed = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+
+                let mut request_line = String::new();
+                stream.read_line(&mut request_line).await.unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    stream.read_line(&mut header).await.unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                stream.read_exact(&mut body).await.unwrap();
+
+                let response = if request_line.starts_with("GET") {
+                    format!(r#"{{"public_key":"{}"}}"#, PUBLIC_KEY)
+                } else {
+                    signed
+                        .lock()
+                        .unwrap()
+                        .push(String::from_utf8(body).unwrap());
+                    format!(r#"{{"signature":"{}"}}"#, SIGNATURE)
+                };
+                stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            response.len(),
+                            response
+                        )
+                        .as_bytes(),
+                    )
+                    .await
+                    .unwrap();
+            }
+        });
+
+        (Url::parse(&url).unwrap(), requests)
+    }
+
+    #[tokio::test]
+    async fn remote_signer_signs_watermarked_operation() {
+        let (url, requests) = mock_signer().await;
+        let address = TezosPublicKey::from_base58check(PUBLIC_KEY)
+            .unwrap()
+            .hash()
+            .to_base58check();
+
+        let signer = RemoteSigner::connect(&url, &address).await.unwrap();
+        assert_eq!(signer.public_key().to_base58check(), PUBLIC_KEY);
+        assert_eq!(signer.funding_address().to_base58check(), address);
+        assert!(signer.key_material().is_none());
+
+        let signature = signer.sign_operation(&[0xab, 0xcd]).await.unwrap();
+        assert_eq!(signature, SIGNATURE);
+        assert_eq!(*requests.lock().unwrap(), vec!["\"03abcd\"".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn remote_signer_with_wrong_key() {
+        let (url, _) = mock_signer().await;
+        assert!(matches!(
+            RemoteSigner::connect(&url, "tz1iKxZpa5x1grZyN2Uw9gERXJJPMyG22Sqp").await,
+            Err(SignerError::WrongKey(_))
+        ));
+    }
+}
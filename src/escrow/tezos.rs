@@ -1,16 +1,30 @@
 use {
-    crate::escrow::types::*,
+    crate::{
+        amount::{Amount, XTZ},
+        escrow::types::*,
+        metrics,
+        protocol::Party,
+    },
     canonicalize_json_micheline::{canonicalize_json_micheline, CanonicalizeError},
     futures::Future,
-    inline_python::{pyo3, pyo3::conversion::FromPyObject, python},
+    inline_python::{
+        pyo3,
+        pyo3::{
+            conversion::FromPyObject,
+            exceptions::{PyRuntimeError, PyValueError},
+            prelude::*,
+        },
+        python,
+    },
     serde::{Deserialize, Serialize},
     std::{
         convert::{TryFrom, TryInto},
         str::FromStr,
+        sync::Arc,
         time::{Duration, SystemTime},
     },
     tezedge::{OriginatedAddress, ToBase58Check},
-    tokio::task::JoinError,
+    tokio::{runtime::Handle, task::JoinError},
     zkabacus_crypto::{
         customer::ClosingMessage, revlock::RevocationSecret, ChannelId, CloseState,
         CustomerBalance, MerchantBalance, PublicKey, RevocationLock,
@@ -24,6 +38,11 @@ lazy_static::lazy_static! {
     static ref CONTRACT_CODE_HASH: ContractHash = ContractHash::new(&*CONTRACT_CODE);
 }
 
+/// The hash of the canonicalized Micheline JSON of the zkChannels contract bundled with this build.
+pub fn contract_hash() -> ContractHash {
+    *CONTRACT_CODE_HASH
+}
+
 /// The default `revocation_lock`: a hex-encoded string which pytezos reads as a scalar 0.
 const DEFAULT_REVOCATION_LOCK: &str = "0x00";
 
@@ -37,17 +56,33 @@ fn python_context() -> inline_python::Context {
         // Producing and verifying the signature for mutual close uses the methods
         // listed in https://pytezos.org/crypto.html
         import json
-        from pytezos import pytezos, Contract, ContractInterface
+        from pytezos import pytezos, Contract, ContractInterface, Key
         from pytezos.michelson.types import MichelsonType
         from pytezos.michelson.parse import michelson_to_micheline
 
         main_code = ContractInterface.from_micheline(json.loads('CONTRACT_CODE))
 
+        // Sign and inject an operation, waiting until it is confirmed at depth. If the account's
+        // secret key isn't held in memory, the operation is forged here, signed by the external
+        // `signer`, and only then injected.
+        def send(operation, signer, min_confirmations):
+            if signer is None:
+                return operation.send(min_confirmations=min_confirmations)
+
+            if hasattr(operation, "as_transaction"):
+                operation = operation.as_transaction()
+            operation = operation.autofill()
+            signature = signer.sign(operation.forge())
+            operation = operation._spawn(signature=signature)
+            operation.inject(min_confirmations=min_confirmations)
+            return operation
+
         // Originate a contract on chain
         def originate(
             uri,
             cust_addr, merch_addr,
             cust_acc,
+            signer,
             merch_pubkey,
             channel_id,
             merch_g2, merch_y2s, merch_x2,
@@ -77,7 +112,7 @@ fn python_context() -> inline_python::Context {
             "status": 0}
 
             // Originate main zkchannel contract
-            out = cust_py.origination(script=main_code.script(initial_storage=initial_storage)).autofill().sign().send(min_confirmations=min_confirmations)
+            out = send(cust_py.origination(script=main_code.script(initial_storage=initial_storage)), signer, min_confirmations)
 
             // Get address, status of main zkchannel contract
             search_depth = 2 * min_confirmations
@@ -85,14 +120,23 @@ fn python_context() -> inline_python::Context {
             contents = op_info["contents"][0]
             contract_id = contents["metadata"]["operation_result"]["originated_contracts"][0]
             status = contents["metadata"]["operation_result"]["status"]
-            block = op_info["branch"]
 
-            return (contract_id, status)
+            // Find the level of the block in which the contract was originated
+            shell = pytezos.using(shell=uri).shell
+            head_level = shell.head.header()["level"]
+            level = head_level
+            for block_level in range(head_level, max(head_level - search_depth, 0), -1):
+                if any(op["hash"] == out.hash() for op in shell.blocks[block_level].operations.managers()):
+                    level = block_level
+                    break
+
+            return (contract_id, status, level)
 
         // Call the `addCustFunding` entrypoint of an extant contract
         def add_customer_funding(
             uri,
             cust_acc,
+            signer,
             contract_id,
             cust_funding,
             min_confirmations
@@ -104,7 +148,7 @@ fn python_context() -> inline_python::Context {
             cust_ci = cust_py.contract(contract_id)
 
             // Call the addCustFunding entrypoint
-            out = cust_ci.addCustFunding().with_amount(cust_funding).send(min_confirmations=min_confirmations)
+            out = send(cust_ci.addCustFunding().with_amount(cust_funding), signer, min_confirmations)
 
             // Get status of the addCustFunding operation
             search_depth = 2 * min_confirmations
@@ -115,15 +159,67 @@ fn python_context() -> inline_python::Context {
 
             return status
 
+        // Find the most recent `addCustFunding` operation on a contract, searching backwards
+        // from the head block through `search_depth` blocks, and return its hash, level, source
+        // address, and status
+        def find_customer_funding(
+            uri,
+            contract_id,
+            search_depth
+        ):
+            shell = pytezos.using(shell=uri).shell
+            head_level = shell.head.header()["level"]
+
+            for level in range(head_level, max(head_level - search_depth, 0), -1):
+                for op in shell.blocks[level].operations.managers():
+                    for contents in op["contents"]:
+                        parameters = contents.get("parameters", {})
+                        if contents.get("destination") == contract_id and parameters.get("entrypoint") == "addCustFunding":
+                            status = contents["metadata"]["operation_result"]["status"]
+                            return (op["hash"], level, contents["source"], status)
+
+            return None
+
+        // Get the chain ID of the network that a node is on
+        def chain_id(uri):
+            return pytezos.using(shell=uri).shell.chains.main.chain_id()
+
+        // Get the balance, in mutez, of an account
+        def get_balance(uri, address):
+            return int(pytezos.using(shell=uri).shell.contracts[address].balance())
+
+        // Get the level of the head block
+        def head_level(uri):
+            return pytezos.using(shell=uri).shell.head.header()["level"]
+
+        // Get the hash of the block at a level
+        def block_hash(uri, level):
+            return pytezos.using(shell=uri).shell.blocks[level].hash()
+
+        // List the calls to originated contracts in the block at a level, as pairs of the
+        // contract address and the name of the entrypoint called
+        def contract_calls(uri, level):
+            calls = []
+            for op in pytezos.using(shell=uri).shell.blocks[level].operations.managers():
+                for contents in op["contents"]:
+                    destination = contents.get("destination", "")
+                    if contents.get("kind") == "transaction" and destination.startswith("KT1"):
+                        parameters = contents.get("parameters", {})
+                        calls.append((destination, parameters.get("entrypoint", "default")))
+            return calls
+
+        // Get the status of a contract as of the block at a level
+        def contract_status_at(uri, contract_id, level):
+            contract = pytezos.using(shell=uri).contract(contract_id).using(block_id=level)
+            return contract.storage()["status"]
+
         // Get the state of a contract.
         def contract_state(
             uri,
-            cust_acc,
             contract_id,
             min_confirmations
         ):
-            cust_py = pytezos.using(key=cust_acc, shell=uri)
-            cust_ci = cust_py.contract(contract_id)
+            cust_ci = pytezos.using(shell=uri).contract(contract_id)
 
             if min_confirmations > 1:
                 block_id = "head~{}".format(min_confirmations-1)
@@ -141,6 +237,7 @@ fn python_context() -> inline_python::Context {
         def add_merchant_funding(
             uri,
             merch_acc,
+            signer,
             contract_id,
             merch_funding,
             min_confirmations
@@ -152,7 +249,7 @@ fn python_context() -> inline_python::Context {
             merch_ci = merch_py.contract(contract_id)
 
             // Call the addMerchFunding entrypoint
-            out = merch_ci.addMerchFunding().with_amount(merch_funding).send(min_confirmations=min_confirmations)
+            out = send(merch_ci.addMerchFunding().with_amount(merch_funding), signer, min_confirmations)
 
             // Get status of the addMerchFunding operation
             search_depth = 2 * min_confirmations
@@ -165,6 +262,7 @@ fn python_context() -> inline_python::Context {
         def cust_close(
             uri,
             cust_acc,
+            signer,
             contract_id,
             customer_balance, merchant_balance,
             sigma1, sigma2,
@@ -187,7 +285,7 @@ fn python_context() -> inline_python::Context {
             }
 
             // Call the custClose entrypoint
-            out = cust_ci.custClose(close_storage).send(min_confirmations=min_confirmations)
+            out = send(cust_ci.custClose(close_storage), signer, min_confirmations)
 
             // Get status of the operation
             search_depth = 2 * min_confirmations
@@ -195,11 +293,12 @@ fn python_context() -> inline_python::Context {
             contents = op_info["contents"][0]
             status = contents["metadata"]["operation_result"]["status"]
 
-            return status
+            return (status, out.hash())
 
         def cust_claim(
             uri,
             cust_acc,
+            signer,
             contract_id,
             min_confirmations,
         ):
@@ -210,7 +309,7 @@ fn python_context() -> inline_python::Context {
             cust_ci = cust_py.contract(contract_id)
 
             // Call the custClaim entrypoint
-            out = cust_ci.custClaim().send(min_confirmations=min_confirmations)
+            out = send(cust_ci.custClaim(), signer, min_confirmations)
 
             // Get status of the operation
             search_depth = 2 * min_confirmations
@@ -223,6 +322,7 @@ fn python_context() -> inline_python::Context {
         def reclaim_funding(
             uri,
             cust_acc,
+            signer,
             contract_id,
             min_confirmations,
         ):
@@ -233,7 +333,7 @@ fn python_context() -> inline_python::Context {
             cust_ci = cust_py.contract(contract_id)
 
             // Call the reclaimFunding entrypoint
-            out = cust_ci.reclaimFunding().send(min_confirmations=min_confirmations)
+            out = send(cust_ci.reclaimFunding(), signer, min_confirmations)
 
             // Get status of the operation
             search_depth = 2 * min_confirmations
@@ -246,6 +346,7 @@ fn python_context() -> inline_python::Context {
         def expiry(
             uri,
             merch_acc,
+            signer,
             contract_id,
             min_confirmations,
         ):
@@ -256,7 +357,7 @@ fn python_context() -> inline_python::Context {
             merch_ci = merch_py.contract(contract_id)
 
             // Call the expiry entrypoint
-            out = merch_ci.expiry().send(min_confirmations=min_confirmations)
+            out = send(merch_ci.expiry(), signer, min_confirmations)
 
             // Get status of the operation
             search_depth = 2 * min_confirmations
@@ -269,6 +370,7 @@ fn python_context() -> inline_python::Context {
         def merch_claim(
             uri,
             merch_acc,
+            signer,
             contract_id,
             min_confirmations,
         ):
@@ -279,7 +381,7 @@ fn python_context() -> inline_python::Context {
             merch_ci = merch_py.contract(contract_id)
 
             // Call the merchClaim entrypoint
-            out = merch_ci.merchClaim().send(min_confirmations=min_confirmations)
+            out = send(merch_ci.merchClaim(), signer, min_confirmations)
 
             // Get status of the operation
             search_depth = 2 * min_confirmations
@@ -292,6 +394,7 @@ fn python_context() -> inline_python::Context {
         def merch_dispute(
             uri,
             merch_acc,
+            signer,
             contract_id,
             revocation_secret,
             min_confirmations,
@@ -303,7 +406,7 @@ fn python_context() -> inline_python::Context {
             merch_ci = merch_py.contract(contract_id)
 
             // Call the merchDispute entrypoint
-            out = merch_ci.merchDispute(revocation_secret).send(min_confirmations=min_confirmations)
+            out = send(merch_ci.merchDispute(revocation_secret), signer, min_confirmations)
 
             // Get status of the operation
             search_depth = 2 * min_confirmations
@@ -311,7 +414,7 @@ fn python_context() -> inline_python::Context {
             contents = op_info["contents"][0]
             status = contents["metadata"]["operation_result"]["status"]
 
-            return status
+            return (status, out.hash())
 
         def sign_mutual_close(
             uri,
@@ -353,9 +456,21 @@ fn python_context() -> inline_python::Context {
 
             return
 
+        def sign_parameters(merch_acc, parameters):
+            // The parameters are already serialized, together with their context string, so they
+            // are signed as they are.
+            return Key.from_encoded_key(merch_acc).sign(bytes.fromhex(parameters))
+
+        def verify_parameters_signature(merch_pubkey, parameters, parameters_signature):
+            // Key.verify() throws an error if the signature is invalid
+            Key.from_encoded_key(merch_pubkey).verify(parameters_signature, bytes.fromhex(parameters))
+
+            return
+
         def mutual_close(
             uri,
             cust_acc,
+            signer,
             contract_id,
             customer_balance, merchant_balance,
             authorization_signature,
@@ -375,7 +490,7 @@ fn python_context() -> inline_python::Context {
             }
 
             // Call the mutualClose entrypoint
-            out = cust_ci.mutualClose(mutual_close_storage).send(min_confirmations=min_confirmations)
+            out = send(cust_ci.mutualClose(mutual_close_storage), signer, min_confirmations)
 
             // Get status of the operation
             search_depth = 2 * min_confirmations
@@ -383,7 +498,7 @@ fn python_context() -> inline_python::Context {
             contents = op_info["contents"][0]
             status = contents["metadata"]["operation_result"]["status"]
 
-            return status
+            return (status, out.hash())
     };
     context
 }
@@ -446,7 +561,7 @@ pub enum VerificationError {
     #[error("Expected customer contract's self_delay to be {expected:?}, but was {actual:?}")]
     UnexpectedSelfDelay { expected: u64, actual: u64 },
     #[error("Expected customer contract's delay_expiry to be to 0, but was {actual:?}")]
-    UnexpectedDelayExpiry { actual: u32 },
+    UnexpectedDelayExpiry { actual: u64 },
     #[error("Expected contract's merchant_balance to be {expected:?}, but was {actual:?}")]
     UnexpectedMerchantBalance {
         expected: MerchantBalance,
@@ -463,6 +578,16 @@ pub enum VerificationError {
     ZkAbacus(#[from] zkabacus_crypto::Error),
     #[error("Contract's MerchantPublicKey did not match the merchant's public key")]
     UnexpectedMerchantKey,
+    #[error(transparent)]
+    FindFunding(#[from] FindFundingError),
+    #[error("Could not find the customer's addCustFunding operation on the contract")]
+    FundingOperationNotFound,
+    #[error("Customer's addCustFunding operation {hash} was not applied")]
+    FundingOperationNotApplied { hash: String },
+    #[error("Expected customer funding to come from {expected}, but it came from {actual}")]
+    UnexpectedFundingSource { expected: String, actual: String },
+    #[error("Expected contract's customer_address to be {expected}, but was {actual}")]
+    UnexpectedCustomerAddress { expected: String, actual: String },
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -482,6 +607,7 @@ pub enum ContractStateError {
 /// State of a zkChannels contract at a point in time.
 #[derive(Debug)]
 pub struct ContractState {
+    customer_address_base58: String,
     merchant_address_base58: String,
     merchant_tezos_public_key_base58: String,
     customer_amount: u64,
@@ -489,7 +615,7 @@ pub struct ContractState {
     status: i32,
     revocation_lock_bytes: Vec<u8>,
     self_delay: u64,
-    delay_expiry: u32,
+    delay_expiry: u64,
     merchant_public_key: (Vec<u8>, [Vec<u8>; 5], Vec<u8>),
     contract_code: String,
 }
@@ -500,18 +626,20 @@ impl ContractState {
         Ok(ContractStatus::try_from(self.status)?)
     }
 
-    /// Get the indicator to whether the timeout was set and, if so, whether it has expired.
-    pub fn timeout_expired(&self) -> Option<bool> {
+    /// Get the time at which the timeout expires, if it has been set.
+    pub fn delay_expiry(&self) -> Option<SystemTime> {
         match self.delay_expiry {
             0 => None,
-            n => {
-                let timeout_expired =
-                    SystemTime::UNIX_EPOCH + Duration::from_secs(n.into()) < SystemTime::now();
-                Some(timeout_expired)
-            }
+            n => Some(SystemTime::UNIX_EPOCH + Duration::from_secs(n)),
         }
     }
 
+    /// Get the indicator to whether the timeout was set and, if so, whether it has expired.
+    pub fn timeout_expired(&self) -> Option<bool> {
+        self.delay_expiry()
+            .map(|delay_expiry| delay_expiry < SystemTime::now())
+    }
+
     pub fn customer_balance(&self) -> Result<CustomerBalance, zkabacus_crypto::Error> {
         CustomerBalance::try_new(self.customer_amount)
     }
@@ -549,6 +677,22 @@ impl ContractState {
         })
     }
 
+    /// Check that the contract pays the customer's balance to the given address, which should be
+    /// the one the customer gave when establishing the channel.
+    pub fn verify_customer_address(
+        &self,
+        expected: &TezosFundingAddress,
+    ) -> Result<(), VerificationError> {
+        let expected = expected.to_base58check();
+        if self.customer_address_base58 != expected {
+            return Err(VerificationError::UnexpectedCustomerAddress {
+                expected,
+                actual: self.customer_address_base58.clone(),
+            });
+        }
+        Ok(())
+    }
+
     /// The merchant's Pointcheval Sanders public key: (g2, y2s, x2)
     pub fn merchant_public_key(&self) -> &(Vec<u8>, [Vec<u8>; 5], Vec<u8>) {
         &self.merchant_public_key
@@ -577,6 +721,7 @@ impl<'source> FromPyObject<'source> for ContractState {
         let contract_code = obj.get_item(1)?.extract()?;
 
         Ok(ContractState {
+            customer_address_base58: storage.get_item("customer_address")?.extract()?,
             merchant_address_base58: storage.get_item("merchant_address")?.extract()?,
             merchant_tezos_public_key_base58: storage.get_item("merchant_public_key")?.extract()?,
             customer_amount: storage.get_item("customer_balance")?.extract()?,
@@ -601,6 +746,14 @@ impl<'source> FromPyObject<'source> for ContractState {
     }
 }
 
+/// An operation that was posted, and the result of attempting it.
+pub struct PostedOperation {
+    /// The result of attempting the operation.
+    pub status: OperationStatus,
+    /// The hash of the operation.
+    pub hash: String,
+}
+
 /// The result of attempting an operation.
 pub enum OperationStatus {
     /// The operation successfully was applied and included in the head block.
@@ -613,6 +766,69 @@ pub enum OperationStatus {
     Skipped,
 }
 
+impl OperationStatus {
+    /// The name of the status, as reported by the Tezos node.
+    pub fn as_str(&self) -> &'static str {
+        use OperationStatus::*;
+        match self {
+            Applied => "applied",
+            Failed => "failed",
+            Backtracked => "backtracked",
+            Skipped => "skipped",
+        }
+    }
+}
+
+/// The result of a contract call which posts an operation, carrying the operation's status.
+trait PostedStatus {
+    fn operation_status(&self) -> &OperationStatus;
+
+    /// The hash of the operation, if it is known.
+    fn operation_hash(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl PostedStatus for OperationStatus {
+    fn operation_status(&self) -> &OperationStatus {
+        self
+    }
+}
+
+impl PostedStatus for PostedOperation {
+    fn operation_status(&self) -> &OperationStatus {
+        &self.status
+    }
+
+    fn operation_hash(&self) -> Option<&str> {
+        Some(&self.hash)
+    }
+}
+
+impl PostedStatus for (ContractId, u32, OperationStatus) {
+    fn operation_status(&self) -> &OperationStatus {
+        &self.2
+    }
+}
+
+/// Record the outcome of posting an operation in the merchant's metrics and the log.
+trait RecordOperation {
+    /// Record this outcome of posting an operation to the given entrypoint, passing it through.
+    fn record_operation(self, entrypoint: &str) -> Self;
+}
+
+impl<T: PostedStatus, E> RecordOperation for Result<T, E> {
+    fn record_operation(self, entrypoint: &str) -> Self {
+        let (outcome, operation_hash) = match &self {
+            Ok(posted) => (posted.operation_status().as_str(), posted.operation_hash()),
+            Err(_) => ("error", None),
+        };
+        tracing::info!(entrypoint, outcome, operation_hash, "Posted operation");
+        metrics::tezos_operation(entrypoint, outcome);
+        self
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 #[error("Could not parse `OperationStatus` {0}")]
 pub struct OperationStatusParseError(String);
@@ -668,6 +884,11 @@ pub struct OriginateError(#[from] JoinError);
 #[error("Could not fund contract: {0}")]
 pub struct CustomerFundError(#[from] JoinError);
 
+/// An error while attempting to locate the customer's funding operation on chain.
+#[derive(Debug, thiserror::Error)]
+#[error("Could not search for customer funding operation: {0}")]
+pub struct FindFundingError(#[from] JoinError);
+
 /// An error while attempting to fund the contract.
 #[derive(Debug, thiserror::Error)]
 #[error("Could not reclaim funding from contract: {0}")]
@@ -697,6 +918,14 @@ pub struct AuthorizeMutualCloseError(#[from] JoinError);
 #[error("Invalid authorization signature for mutual close: {0}")]
 pub struct InvalidAuthorizationSignatureError(#[from] JoinError);
 
+#[derive(Debug, thiserror::Error)]
+#[error("Could not sign merchant parameters: {0}")]
+pub struct SignParametersError(#[from] JoinError);
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid signature on merchant parameters: {0}")]
+pub struct InvalidParametersSignatureError(#[from] JoinError);
+
 #[derive(Debug, thiserror::Error)]
 #[error("Could not issue merchant dispute: {0}")]
 pub struct MerchantDisputeError(#[from] JoinError);
@@ -722,12 +951,84 @@ impl MutualCloseAuthorizationSignature {
     }
 }
 
+/// Merchant signature over its published parameters, binding its zkAbacus parameters to its Tezos
+/// identity.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParametersSignature {
+    /// signature encoded with base58check with prefix (`Prefix::edsig`).
+    signature: String,
+}
+
+impl ParametersSignature {
+    /// Get the signature by itself.
+    pub fn signature(&self) -> &String {
+        &self.signature
+    }
+}
+
+/// Sign the merchant's serialized parameters (see
+/// [`parameters::signed_message`](crate::protocol::parameters::signed_message)) with an EdDSA
+/// signature under its Tezos key, as for the authorization signature for mutual close.
+///
+/// This is called by the merchant.
+pub fn sign_parameters(
+    key_material: &TezosKeyMaterial,
+    parameters: &[u8],
+) -> impl Future<Output = Result<ParametersSignature, SignParametersError>> + Send + 'static {
+    let merchant_account = key_material.private_key().to_base58check();
+    let parameters = hex::encode(parameters);
+
+    async move {
+        tokio::task::spawn_blocking(move || {
+            let context = python_context();
+            context.run(python! {
+                out = sign_parameters('merchant_account, 'parameters)
+            });
+
+            ParametersSignature {
+                signature: context.get::<String>("out"),
+            }
+        })
+        .await
+        .map_err(SignParametersError)
+    }
+}
+
+/// Verify the signature the merchant provided over its serialized parameters. The signature must
+/// be a valid EdDSA signature over the parameters under the merchant's Tezos public key.
+///
+/// This is called by the customer.
+pub fn verify_parameters_signature(
+    merchant_pubkey: &TezosPublicKey,
+    parameters: &[u8],
+    parameters_signature: &ParametersSignature,
+) -> impl Future<Output = Result<(), InvalidParametersSignatureError>> + Send + 'static {
+    let merchant_pubkey = merchant_pubkey.to_base58check();
+    let parameters = hex::encode(parameters);
+    let parameters_signature = parameters_signature.signature.clone();
+
+    async move {
+        tokio::task::spawn_blocking(move || {
+            let context = python_context();
+            context.run(python! {
+                verify_parameters_signature(
+                    'merchant_pubkey,
+                    'parameters,
+                    'parameters_signature
+                )
+            });
+        })
+        .await
+        .map_err(InvalidParametersSignatureError)
+    }
+}
+
 /// Originate a contract on chain.
 ///
 /// This call will wait until the contract is confirmed at depth. It returns the new
-/// [`ContractId`].
+/// [`ContractId`] and the level of the block in which it was originated.
 ///
-/// The `originator_key_pair` should belong to whichever party originates the contract.
+/// The `originator` should sign for whichever party originates the contract.
 /// Currently, this must be called by the customer. Its public key must be the same as the one
 /// in the provided [`CustomerFundingInformation`].
 ///
@@ -739,17 +1040,18 @@ pub fn originate(
     merchant_funding_info: &MerchantFundingInformation,
     customer_funding_info: &CustomerFundingInformation,
     merchant_public_key: &PublicKey,
-    originator_key_pair: &TezosKeyMaterial,
+    originator: &Arc<dyn TezosSigner>,
     channel_id: &ChannelId,
     confirmation_depth: u64,
     self_delay: u64,
-) -> impl Future<Output = Result<(ContractId, OperationStatus), OriginateError>> + Send + 'static {
+) -> impl Future<Output = Result<(ContractId, u32, OperationStatus), OriginateError>> + Send + 'static
+{
     let (g2, y2s, x2) = pointcheval_sanders_public_key_to_python_input(merchant_public_key);
     let merchant_funding = merchant_funding_info.balance.into_inner();
     let merchant_address = merchant_funding_info.address.to_base58check();
     let merchant_pubkey = merchant_funding_info.public_key.to_base58check();
 
-    let customer_account_key = originator_key_pair.private_key().to_base58check();
+    let (customer_account, signer) = python_account(originator);
     let customer_funding = customer_funding_info.balance.into_inner();
     let customer_address = customer_funding_info.address.to_base58check();
     let channel_id = hex_string(&channel_id.to_bytes());
@@ -762,7 +1064,8 @@ pub fn originate(
                 out = originate(
                     'uri,
                     'customer_address, 'merchant_address,
-                    'customer_account_key,
+                    'customer_account,
+                    'signer,
                     'merchant_pubkey,
                     'channel_id,
                     'g2, 'y2s, 'x2,
@@ -772,15 +1075,412 @@ pub fn originate(
                 )
             });
 
-            let (contract_id, status) = context.get::<(String, String)>("out");
+            let (contract_id, status, level) = context.get::<(String, String, u32)>("out");
             let contract_id = ContractId::new(
                 OriginatedAddress::from_base58check(&contract_id)
                     .expect("Contract id returned from pytezos must be valid base58"),
             );
-            (contract_id, status.parse().unwrap())
+            (contract_id, level, status.parse().unwrap())
         })
         .await
         .map_err(OriginateError)
+        .record_operation("originate")
+    }
+}
+
+/// An error while checking which network a Tezos node is on.
+#[derive(Debug, thiserror::Error)]
+pub enum NetworkCheckError {
+    #[error("Could not retrieve chain ID from Tezos node: {0}")]
+    ChainId(#[from] JoinError),
+    #[error(
+        "Tezos node at {uri} is on chain {actual}, but {network} has chain {expected}; \
+        check the configured network"
+    )]
+    UnexpectedChainId {
+        uri: String,
+        network: TezosNetwork,
+        expected: &'static str,
+        actual: String,
+    },
+}
+
+/// Check that the node for the given [`TezosNetwork`] is actually on that network, by comparing
+/// its chain ID to the one expected for the network.
+///
+/// Sandbox and custom networks have no expected chain ID, so for these, this only warns if the node
+/// turns out to be on mainnet.
+pub async fn check_network(network: &TezosNetwork) -> Result<(), NetworkCheckError> {
+    let uri = network.uri().to_string();
+    let python_uri = uri.clone();
+
+    let actual = tokio::task::spawn_blocking(move || {
+        let context = python_context();
+        context.run(python! {
+            out = chain_id('python_uri)
+        });
+
+        context.get::<String>("out")
+    })
+    .await?;
+
+    match network.chain_id() {
+        Some(expected) if expected != actual => Err(NetworkCheckError::UnexpectedChainId {
+            uri,
+            network: network.clone(),
+            expected,
+            actual,
+        }),
+        Some(_) => Ok(()),
+        None => {
+            if actual == MAINNET_CHAIN_ID {
+                tracing::warn!(%uri, %network, "The Tezos node for this network is on mainnet");
+            }
+            Ok(())
+        }
+    }
+}
+
+/// A generous estimate, in mutez, of the cost of originating the contract: the burn for its
+/// storage plus the baker fee.
+pub const ORIGINATION_COST_ESTIMATE: u64 = 5_000_000;
+
+/// A generous estimate, in mutez, of the baker fee for a single contract call.
+pub const OPERATION_FEE_ESTIMATE: u64 = 100_000;
+
+/// The balance, in mutez, that a party's funding address needs in order to establish a channel:
+/// their deposit plus the estimated costs of the operations they post.
+///
+/// The customer originates the contract and funds it; the merchant only funds it.
+pub fn required_funding_balance(party: Party, deposit: u64) -> u64 {
+    let costs = match party {
+        Party::Customer => ORIGINATION_COST_ESTIMATE + OPERATION_FEE_ESTIMATE,
+        Party::Merchant => OPERATION_FEE_ESTIMATE,
+    };
+    deposit.saturating_add(costs)
+}
+
+/// An error while retrieving the balance of an account.
+#[derive(Debug, thiserror::Error)]
+#[error("Could not retrieve account balance: {0}")]
+pub struct GetBalanceError(#[from] JoinError);
+
+/// Get the balance, in mutez, of the account at the given address.
+pub fn get_balance(
+    uri: &http::Uri,
+    address: &TezosFundingAddress,
+) -> impl Future<Output = Result<u64, GetBalanceError>> + Send + 'static {
+    let uri = uri.to_string();
+    let address = address.to_base58check();
+
+    async move {
+        tokio::task::spawn_blocking(move || {
+            let context = python_context();
+            context.run(python! {
+                out = get_balance('uri, 'address)
+            });
+
+            context.get::<u64>("out")
+        })
+        .await
+        .map_err(GetBalanceError)
+    }
+}
+
+/// An error while retrieving the level of the head block.
+#[derive(Debug, thiserror::Error)]
+#[error("Could not retrieve head block level: {0}")]
+pub struct HeadLevelError(#[from] JoinError);
+
+/// Get the level of the head block of the chain.
+pub fn head_level(
+    uri: &http::Uri,
+) -> impl Future<Output = Result<u32, HeadLevelError>> + Send + 'static {
+    let uri = uri.to_string();
+
+    async move {
+        tokio::task::spawn_blocking(move || {
+            let context = python_context();
+            context.run(python! {
+                out = head_level('uri)
+            });
+
+            context.get::<u32>("out")
+        })
+        .await
+        .map_err(HeadLevelError)
+    }
+}
+
+/// An error while retrieving the hash of a block.
+#[derive(Debug, thiserror::Error)]
+#[error("Could not retrieve block hash: {0}")]
+pub struct BlockHashError(#[from] JoinError);
+
+/// Get the hash of the block at the given level of the chain.
+pub fn block_hash(
+    uri: &http::Uri,
+    level: u32,
+) -> impl Future<Output = Result<String, BlockHashError>> + Send + 'static {
+    let uri = uri.to_string();
+
+    async move {
+        tokio::task::spawn_blocking(move || {
+            let context = python_context();
+            context.run(python! {
+                out = block_hash('uri, 'level)
+            });
+
+            context.get::<String>("out")
+        })
+        .await
+        .map_err(BlockHashError)
+    }
+}
+
+/// An error while listing the contract calls in a block.
+#[derive(Debug, thiserror::Error)]
+#[error("Could not retrieve contract calls: {0}")]
+pub struct ContractCallsError(#[from] JoinError);
+
+/// List the calls to originated contracts in the block at the given level, in the order they
+/// appear in the block, as pairs of the contract ID and the name of the entrypoint called.
+pub fn contract_calls(
+    uri: &http::Uri,
+    level: u32,
+) -> impl Future<Output = Result<Vec<(ContractId, String)>, ContractCallsError>> + Send + 'static {
+    let uri = uri.to_string();
+
+    async move {
+        tokio::task::spawn_blocking(move || {
+            let context = python_context();
+            context.run(python! {
+                out = contract_calls('uri, 'level)
+            });
+
+            context
+                .get::<Vec<(String, String)>>("out")
+                .into_iter()
+                .map(|(contract_id, entrypoint)| {
+                    let contract_id = ContractId::new(
+                        OriginatedAddress::from_base58check(&contract_id)
+                            .expect("Contract id returned from pytezos must be valid base58"),
+                    );
+                    (contract_id, entrypoint)
+                })
+                .collect()
+        })
+        .await
+        .map_err(ContractCallsError)
+    }
+}
+
+/// An error while retrieving the status of a contract.
+#[derive(Debug, thiserror::Error)]
+pub enum ContractStatusError {
+    #[error("Could not retrieve contract status: {0}")]
+    PythonError(#[from] JoinError),
+    #[error(transparent)]
+    ParseContractStatus(#[from] ParseContractStatusError),
+}
+
+/// Get the status of a contract as of the block at the given level.
+pub fn contract_status_at(
+    uri: &http::Uri,
+    contract_id: &ContractId,
+    level: u32,
+) -> impl Future<Output = Result<ContractStatus, ContractStatusError>> + Send + 'static {
+    let uri = uri.to_string();
+    let contract_id = contract_id.to_string();
+
+    async move {
+        let status = tokio::task::spawn_blocking(move || {
+            let context = python_context();
+            context.run(python! {
+                out = contract_status_at('uri, 'contract_id, 'level)
+            });
+
+            context.get::<i32>("out")
+        })
+        .await?;
+
+        Ok(ContractStatus::try_from(status)?)
+    }
+}
+
+/// Query the chain to retrieve the state of the contract with the given [`ContractId`], as of the
+/// given confirmation depth.
+///
+/// Unlike [`TezosClient::get_contract_state`], this needs no key material, so it can be used to
+/// monitor a contract on a machine which can't post operations to it.
+pub fn get_contract_state(
+    uri: &http::Uri,
+    contract_id: &ContractId,
+    confirmation_depth: u64,
+) -> impl Future<Output = Result<ContractState, ContractStateError>> + Send + 'static {
+    contract_state(
+        Some(uri.to_string()),
+        contract_id.clone().to_originated_address().to_base58check(),
+        confirmation_depth,
+    )
+}
+
+/// Retrieve the state of a contract, given the types expected by Python.
+fn contract_state(
+    uri: Option<String>,
+    contract_id: String,
+    confirmation_depth: u64,
+) -> impl Future<Output = Result<ContractState, ContractStateError>> + Send + 'static {
+    async move {
+        tokio::task::spawn_blocking(move || {
+            let context = python_context();
+            context.run(python! {
+                out = contract_state(
+                    'uri,
+                    'contract_id,
+                    'confirmation_depth
+                )
+            });
+
+            context.get::<ContractState>("out")
+        })
+        .await
+        .map_err(ContractStateError::PythonError)
+    }
+}
+
+/// An error when a funding address can't cover a party's side of a new channel.
+#[derive(Debug, thiserror::Error)]
+pub enum FundingBalanceError {
+    #[error(transparent)]
+    GetBalance(#[from] GetBalanceError),
+    #[error(
+        "Funding address {address} holds {balance}, but the {party} needs at least {required} \
+        to cover their deposit and estimated fees"
+    )]
+    Insufficient {
+        address: String,
+        party: Party,
+        balance: Amount,
+        required: Amount,
+    },
+}
+
+/// Check that a balance, in mutez, covers the party's deposit and estimated fees, as computed by
+/// [`required_funding_balance`].
+pub fn check_funding_balance(
+    address: &TezosFundingAddress,
+    party: Party,
+    balance: u64,
+    deposit: u64,
+) -> Result<(), FundingBalanceError> {
+    let required = required_funding_balance(party, deposit);
+    if balance >= required {
+        Ok(())
+    } else {
+        Err(FundingBalanceError::Insufficient {
+            address: address.to_base58check(),
+            party,
+            balance: Amount::from_minor_units_of_currency(balance as i64, XTZ),
+            required: Amount::from_minor_units_of_currency(required as i64, XTZ),
+        })
+    }
+}
+
+/// Check that the funding address holds enough to cover the party's deposit and estimated fees,
+/// so that establishment can fail before any operations are posted.
+pub async fn verify_funding_balance(
+    network: &TezosNetwork,
+    address: &TezosFundingAddress,
+    party: Party,
+    deposit: u64,
+) -> Result<(), FundingBalanceError> {
+    let balance = get_balance(&network.uri(), address).await?;
+    check_funding_balance(address, party, balance, deposit)
+}
+
+/// A [`TezosSigner`] made available to Python, to sign operations which are forged in Python.
+#[pyclass]
+#[derive(Clone)]
+struct PythonSigner {
+    signer: Arc<dyn TezosSigner>,
+    runtime: Handle,
+}
+
+#[pymethods]
+impl PythonSigner {
+    /// Sign the hex-encoded forged operation, returning the base58check-encoded signature.
+    fn sign(&self, forged_operation: &str) -> PyResult<String> {
+        let forged_operation =
+            hex::decode(forged_operation).map_err(|e| PyValueError::new_err(e.to_string()))?;
+        self.runtime
+            .block_on(self.signer.sign_operation(&forged_operation))
+            .map_err(|e| PyRuntimeError::new_err(e.to_string()))
+    }
+}
+
+impl ToPyObject for PythonSigner {
+    fn to_object(&self, py: Python) -> PyObject {
+        Py::new(py, self.clone())
+            .expect("Signer must be convertible to a Python object")
+            .into_py(py)
+    }
+}
+
+/// The account to pass to a Python operation, given by its secret key if that is held in memory,
+/// and otherwise by its public key together with a [`PythonSigner`] to sign its operations.
+///
+/// This must be called from within the Tokio runtime.
+fn python_account(signer: &Arc<dyn TezosSigner>) -> (String, Option<PythonSigner>) {
+    match signer.key_material() {
+        Some(key_material) => (key_material.private_key().to_base58check(), None),
+        None => (
+            signer.public_key().to_base58check(),
+            Some(PythonSigner {
+                signer: signer.clone(),
+                runtime: Handle::current(),
+            }),
+        ),
+    }
+}
+
+/// Check the status of a contract which the customer says they have funded, and the latest
+/// `addCustFunding` operation on it, given as `(operation hash, level, source address, status)`.
+fn check_customer_funding(
+    customer_balance: &CustomerBalance,
+    merchant_balance: &MerchantBalance,
+    customer_funding_address: &TezosFundingAddress,
+    actual: ContractStatus,
+    funding: Option<(String, u32, String, String)>,
+) -> Result<Option<FundingOperation>, VerificationError> {
+    let expected = if merchant_balance.into_inner() > 0 {
+        ContractStatus::AwaitingMerchantFunding
+    } else {
+        ContractStatus::Open
+    };
+
+    if expected != actual {
+        return Err(VerificationError::UnexpectedContractStatus { expected, actual });
+    }
+
+    let (hash, level, source, status) = match funding {
+        Some(funding) => funding,
+        // A zero deposit can move the contract on without the customer calling it
+        None if customer_balance.into_inner() == 0 => return Ok(None),
+        None => return Err(VerificationError::FundingOperationNotFound),
+    };
+
+    let expected_source = customer_funding_address.to_base58check();
+    if source != expected_source {
+        return Err(VerificationError::UnexpectedFundingSource {
+            expected: expected_source,
+            actual: source,
+        });
+    }
+
+    match status.parse() {
+        Ok(OperationStatus::Applied) => Ok(Some(FundingOperation::new(hash, level))),
+        _ => Err(VerificationError::FundingOperationNotApplied { hash }),
     }
 }
 
@@ -790,8 +1490,8 @@ pub struct TezosClient {
     pub uri: Option<http::Uri>,
     /// ID of the contract for which the client will post an operation.
     pub contract_id: ContractId,
-    /// Key material for the client.
-    pub client_key_pair: TezosKeyMaterial,
+    /// Signer for the client's operations.
+    pub client_signer: Arc<dyn TezosSigner>,
     /// Block depth for which the client will wait for their operation to reach.
     pub confirmation_depth: u64,
     /// Mutually-agreed delay period for which a client must wait before claiming funds.
@@ -802,9 +1502,10 @@ impl TezosClient {
     /// Transform the Tezos client fields into the correct Python representations, for use in
     /// inline-python calls to the PyTezos API.
     ///
-    /// Returns tuple of `(URI, private key, contract_id)`
+    /// Returns tuple of `(URI, account, contract_id)`, where the account is given by its secret key
+    /// if that is held in memory, and otherwise by its public key.
     fn as_python_types(&self) -> (Option<String>, String, String) {
-        let private_key = self.client_key_pair.private_key().to_base58check();
+        let (account, _) = python_account(&self.client_signer);
         let contract_id = self
             .contract_id
             .clone()
@@ -812,7 +1513,13 @@ impl TezosClient {
             .to_base58check();
         let uri = self.uri.as_ref().map(|uri| uri.to_string());
 
-        (uri, private_key, contract_id)
+        (uri, account, contract_id)
+    }
+
+    /// The external signer to pass to a Python operation, if the client's secret key isn't held in
+    /// memory.
+    fn python_signer(&self) -> Option<PythonSigner> {
+        python_account(&self.client_signer).1
     }
 
     /// Query the chain to retrieve the confirmed state of the contract with the given [`ContractId`].
@@ -822,26 +1529,8 @@ impl TezosClient {
     pub fn get_contract_state(
         &self,
     ) -> impl Future<Output = Result<ContractState, ContractStateError>> + Send + 'static {
-        let (uri, client_private_key, contract_id) = self.as_python_types();
-        let confirmation_depth = self.confirmation_depth;
-
-        async move {
-            tokio::task::spawn_blocking(move || {
-                let context = python_context();
-                context.run(python! {
-                    out = contract_state(
-                        'uri,
-                        'client_private_key,
-                        'contract_id,
-                        'confirmation_depth
-                    )
-                });
-
-                context.get::<ContractState>("out")
-            })
-            .await
-            .map_err(ContractStateError::PythonError)
-        }
+        let (uri, _, contract_id) = self.as_python_types();
+        contract_state(uri, contract_id, self.confirmation_depth)
     }
 
     /// Call the `addFunding` entrypoint with the [`CustomerFundingInformation`].
@@ -859,7 +1548,8 @@ impl TezosClient {
         customer_funding_info: &CustomerFundingInformation,
     ) -> impl Future<Output = Result<OperationStatus, CustomerFundError>> + Send + 'static {
         let customer_funding = customer_funding_info.balance.into_inner();
-        let (uri, customer_private_key, contract_id) = self.as_python_types();
+        let (uri, customer_account, contract_id) = self.as_python_types();
+        let signer = self.python_signer();
         let confirmation_depth = self.confirmation_depth;
 
         async move {
@@ -868,7 +1558,8 @@ impl TezosClient {
                 context.run(python! {
                     out = add_customer_funding(
                         'uri,
-                        'customer_private_key,
+                        'customer_account,
+                        'signer,
                         'contract_id,
                         'customer_funding,
                         'confirmation_depth
@@ -880,6 +1571,7 @@ impl TezosClient {
             })
             .await
             .map_err(CustomerFundError)
+            .record_operation("addFunding")
         }
     }
 
@@ -976,26 +1668,63 @@ impl TezosClient {
     /// Correct funding requires that:
     /// - The `addFunding` operation is the latest operation to be applied to the contract
     /// - The `addFunding` operation is confirmed on chain to the expected depth
+    /// - The `addFunding` operation was sent from the customer's declared funding address
     ///
     /// This function will wait until the customer's funding operation is confirmed at depth
-    /// and is called by the merchant.
+    /// and is called by the merchant. It returns the [`FundingOperation`] that funded the
+    /// contract, so that the merchant can keep a record of it.
+    ///
+    /// The funding operation is searched for in the `search_depth` blocks up to the head, which
+    /// must reach back far enough to cover however long the customer waited to report it.
+    ///
+    /// If the customer's deposit is zero, the contract may not need a funding operation from the
+    /// customer at all, in which case there is no operation to return.
     pub async fn verify_customer_funding(
         &self,
+        customer_balance: &CustomerBalance,
         merchant_balance: &MerchantBalance,
-    ) -> Result<(), VerificationError> {
-        let expected = if merchant_balance.into_inner() > 0 {
-            ContractStatus::AwaitingMerchantFunding
-        } else {
-            ContractStatus::Open
-        };
-
+        customer_funding_address: &TezosFundingAddress,
+        search_depth: u64,
+    ) -> Result<Option<FundingOperation>, VerificationError> {
         let contract_state = self.get_contract_state().await?;
-        let actual = contract_state.status()?;
+        let status = contract_state.status()?;
+
+        // The status alone doesn't say who funded the contract, so find the funding operation
+        let funding = self.find_customer_funding(search_depth).await?;
+
+        check_customer_funding(
+            customer_balance,
+            merchant_balance,
+            customer_funding_address,
+            status,
+            funding,
+        )
+    }
 
-        if expected == actual {
-            Ok(())
-        } else {
-            Err(VerificationError::UnexpectedContractStatus { expected, actual })
+    /// Search the `search_depth` most recent blocks for the latest `addCustFunding` operation on
+    /// the contract.
+    ///
+    /// Returns a tuple of `(operation hash, level, source address, status)` if one was found.
+    #[allow(clippy::type_complexity)]
+    fn find_customer_funding(
+        &self,
+        search_depth: u64,
+    ) -> impl Future<Output = Result<Option<(String, u32, String, String)>, FindFundingError>>
+           + Send
+           + 'static {
+        let (uri, _, contract_id) = self.as_python_types();
+
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let context = python_context();
+                context.run(python! {
+                    out = find_customer_funding('uri, 'contract_id, 'search_depth)
+                });
+
+                context.get::<Option<(String, u32, String, String)>>("out")
+            })
+            .await
+            .map_err(FindFundingError)
         }
     }
 
@@ -1034,7 +1763,8 @@ impl TezosClient {
         merchant_funding_info: &MerchantFundingInformation,
     ) -> impl Future<Output = Result<OperationStatus, CustomerFundError>> + Send + 'static {
         let merchant_funding = merchant_funding_info.balance.into_inner();
-        let (uri, merchant_private_key, contract_id) = self.as_python_types();
+        let (uri, merchant_account, contract_id) = self.as_python_types();
+        let signer = self.python_signer();
         let confirmation_depth = self.confirmation_depth;
 
         async move {
@@ -1043,7 +1773,8 @@ impl TezosClient {
                 context.run(python! {
                     out = add_merchant_funding(
                         'uri,
-                        'merchant_private_key,
+                        'merchant_account,
+                        'signer,
                         'contract_id,
                         'merchant_funding,
                         'confirmation_depth
@@ -1055,6 +1786,7 @@ impl TezosClient {
             })
             .await
             .map_err(CustomerFundError)
+            .record_operation("addFunding")
         }
     }
 
@@ -1066,11 +1798,11 @@ impl TezosClient {
     /// The operation is invalid if:
     /// - the contract status is not AWAITING_FUNDING.
     /// - the `addFunding` entrypoint has not been called by the customer address
-    #[allow(unused)]
     pub fn reclaim_customer_funding(
         &self,
     ) -> impl Future<Output = Result<OperationStatus, ReclaimFundingError>> + Send + 'static {
-        let (uri, customer_private_key, contract_id) = self.as_python_types();
+        let (uri, customer_account, contract_id) = self.as_python_types();
+        let signer = self.python_signer();
         let confirmation_depth = self.confirmation_depth;
 
         async move {
@@ -1079,7 +1811,8 @@ impl TezosClient {
                 context.run(python! {
                     out = reclaim_funding(
                         'uri,
-                        'customer_private_key,
+                        'customer_account,
+                        'signer,
                         'contract_id,
                         'confirmation_depth
                     )
@@ -1090,6 +1823,7 @@ impl TezosClient {
             })
             .await
             .map_err(ReclaimFundingError)
+            .record_operation("reclaimFunding")
         }
     }
 
@@ -1105,14 +1839,15 @@ impl TezosClient {
     pub fn expiry(
         &self,
     ) -> impl Future<Output = Result<OperationStatus, ExpiryError>> + Send + 'static {
-        let (uri, merchant_private_key, contract_id) = self.as_python_types();
+        let (uri, merchant_account, contract_id) = self.as_python_types();
+        let signer = self.python_signer();
         let confirmation_depth = self.confirmation_depth;
 
         async move {
             tokio::task::spawn_blocking(move || {
                 let context = python_context();
                 context.run(python! {
-                    out = expiry('uri, 'merchant_private_key, 'contract_id, 'confirmation_depth)
+                    out = expiry('uri, 'merchant_account, 'signer, 'contract_id, 'confirmation_depth)
                 });
 
                 let status = context.get::<String>("out");
@@ -1120,6 +1855,7 @@ impl TezosClient {
             })
             .await
             .map_err(ExpiryError)
+        .record_operation("expiry")
         }
     }
 
@@ -1137,7 +1873,8 @@ impl TezosClient {
     pub fn merch_claim(
         &self,
     ) -> impl Future<Output = Result<OperationStatus, MerchantClaimError>> + Send + 'static {
-        let (uri, merchant_private_key, contract_id) = self.as_python_types();
+        let (uri, merchant_account, contract_id) = self.as_python_types();
+        let signer = self.python_signer();
         let confirmation_depth = self.confirmation_depth;
 
         async move {
@@ -1146,7 +1883,8 @@ impl TezosClient {
                 context.run(python! {
                     out = merch_claim(
                         'uri,
-                        'merchant_private_key,
+                        'merchant_account,
+                        'signer,
                         'contract_id,
                         'confirmation_depth
                     )
@@ -1157,6 +1895,7 @@ impl TezosClient {
             })
             .await
             .map_err(MerchantClaimError)
+            .record_operation("merchClaim")
         }
     }
 
@@ -1176,8 +1915,9 @@ impl TezosClient {
     pub fn cust_close(
         &self,
         close_message: &ClosingMessage,
-    ) -> impl Future<Output = Result<OperationStatus, CustomerCloseError>> + Send + 'static {
-        let (uri, customer_private_key, contract_id) = self.as_python_types();
+    ) -> impl Future<Output = Result<PostedOperation, CustomerCloseError>> + Send + 'static {
+        let (uri, customer_account, contract_id) = self.as_python_types();
+        let signer = self.python_signer();
         let confirmation_depth = self.confirmation_depth;
 
         let customer_balance = close_message.customer_balance().into_inner();
@@ -1193,7 +1933,8 @@ impl TezosClient {
                 context.run(python! {
                     out = cust_close(
                         'uri,
-                        'customer_private_key,
+                        'customer_account,
+                        'signer,
                         'contract_id,
                         'customer_balance,
                         'merchant_balance,
@@ -1203,11 +1944,15 @@ impl TezosClient {
                     )
                 });
 
-                let status = context.get::<String>("out");
-                status.parse().unwrap()
+                let (status, hash) = context.get::<(String, String)>("out");
+                PostedOperation {
+                    status: status.parse().unwrap(),
+                    hash,
+                }
             })
             .await
             .map_err(CustomerCloseError)
+            .record_operation("custClose")
         }
     }
 
@@ -1224,8 +1969,9 @@ impl TezosClient {
     pub fn merch_dispute(
         &self,
         revocation_secret: &RevocationSecret,
-    ) -> impl Future<Output = Result<OperationStatus, MerchantDisputeError>> + Send + 'static {
-        let (uri, merchant_private_key, contract_id) = self.as_python_types();
+    ) -> impl Future<Output = Result<PostedOperation, MerchantDisputeError>> + Send + 'static {
+        let (uri, merchant_account, contract_id) = self.as_python_types();
+        let signer = self.python_signer();
         let confirmation_depth = self.confirmation_depth;
 
         let revocation_secret = hex_string(&revocation_secret.as_bytes());
@@ -1236,18 +1982,23 @@ impl TezosClient {
                 context.run(python! {
                     out = merch_dispute(
                         'uri,
-                        'merchant_private_key,
+                        'merchant_account,
+                        'signer,
                         'contract_id,
                         'revocation_secret,
                         'confirmation_depth
                     )
                 });
 
-                let status = context.get::<String>("out");
-                status.parse().unwrap()
+                let (status, hash) = context.get::<(String, String)>("out");
+                PostedOperation {
+                    status: status.parse().unwrap(),
+                    hash,
+                }
             })
             .await
             .map_err(MerchantDisputeError)
+            .record_operation("merchDispute")
         }
     }
 
@@ -1264,7 +2015,8 @@ impl TezosClient {
     pub fn cust_claim(
         &self,
     ) -> impl Future<Output = Result<OperationStatus, CustomerClaimError>> + Send + 'static {
-        let (uri, customer_private_key, contract_id) = self.as_python_types();
+        let (uri, customer_account, contract_id) = self.as_python_types();
+        let signer = self.python_signer();
         let confirmation_depth = self.confirmation_depth;
 
         async move {
@@ -1273,7 +2025,8 @@ impl TezosClient {
                 context.run(python! {
                     out = cust_claim(
                         'uri,
-                        'customer_private_key,
+                        'customer_account,
+                        'signer,
                         'contract_id,
                         'confirmation_depth
                     )
@@ -1284,6 +2037,7 @@ impl TezosClient {
             })
             .await
             .map_err(CustomerClaimError)
+            .record_operation("custClaim")
         }
     }
 
@@ -1298,11 +2052,27 @@ impl TezosClient {
     ) -> impl Future<Output = Result<MutualCloseAuthorizationSignature, AuthorizeMutualCloseError>>
            + Send
            + 'static {
-        let (uri, merchant_private_key, contract_id) = self.as_python_types();
-        let channel_id = close_state.channel_id();
+        self.authorize_balances(
+            close_state.channel_id(),
+            close_state.customer_balance(),
+            close_state.merchant_balance(),
+        )
+    }
+
+    /// Sign the mutual close tuple for the given channel and balances, as
+    /// [`authorize_mutual_close`](Self::authorize_mutual_close) does for a [`CloseState`].
+    fn authorize_balances(
+        &self,
+        channel_id: &ChannelId,
+        customer_balance: &CustomerBalance,
+        merchant_balance: &MerchantBalance,
+    ) -> impl Future<Output = Result<MutualCloseAuthorizationSignature, AuthorizeMutualCloseError>>
+           + Send
+           + 'static {
+        let (uri, merchant_account, contract_id) = self.as_python_types();
         let channel_id = hex_string(&channel_id.to_bytes());
-        let customer_balance = close_state.customer_balance().into_inner();
-        let merchant_balance = close_state.merchant_balance().into_inner();
+        let customer_balance = customer_balance.into_inner();
+        let merchant_balance = merchant_balance.into_inner();
 
         async move {
             tokio::task::spawn_blocking(move || {
@@ -1310,7 +2080,7 @@ impl TezosClient {
                 context.run(python! {
                     out = sign_mutual_close(
                         'uri,
-                        'merchant_private_key,
+                        'merchant_account,
                         'channel_id,
                         'contract_id,
                         'customer_balance,
@@ -1383,8 +2153,9 @@ impl TezosClient {
         customer_balance: &CustomerBalance,
         merchant_balance: &MerchantBalance,
         authorization_signature: &MutualCloseAuthorizationSignature,
-    ) -> impl Future<Output = Result<OperationStatus, MutualCloseError>> + Send + 'static {
-        let (uri, customer_private_key, contract_id) = self.as_python_types();
+    ) -> impl Future<Output = Result<PostedOperation, MutualCloseError>> + Send + 'static {
+        let (uri, customer_account, contract_id) = self.as_python_types();
+        let signer = self.python_signer();
         let customer_balance = customer_balance.into_inner();
         let merchant_balance = merchant_balance.into_inner();
         let confirmation_depth = self.confirmation_depth;
@@ -1395,7 +2166,8 @@ impl TezosClient {
                 context.run(python! {
                     out = mutual_close(
                         'uri,
-                        'customer_private_key,
+                        'customer_account,
+                        'signer,
                         'contract_id,
                         'customer_balance,
                         'merchant_balance,
@@ -1404,11 +2176,15 @@ impl TezosClient {
                     )
                 });
 
-                let status = context.get::<String>("out");
-                status.parse().unwrap()
+                let (status, hash) = context.get::<(String, String)>("out");
+                PostedOperation {
+                    status: status.parse().unwrap(),
+                    hash,
+                }
             })
             .await
             .map_err(MutualCloseError)
+            .record_operation("mutualClose")
         }
     }
 
@@ -1429,3 +2205,253 @@ fn is_zero(buf: &[u8]) -> bool {
         && suffix.iter().all(|&x| x == 0)
         && aligned.iter().all(|&x| x == 0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contract_state_with_delay_expiry(delay_expiry: u64) -> ContractState {
+        ContractState {
+            customer_address_base58: "tz1iKxZpa5x1grZyN2Uw9gERXJJPMyG22Sqp".into(),
+            merchant_address_base58: String::new(),
+            merchant_tezos_public_key_base58: String::new(),
+            customer_amount: 0,
+            merchant_amount: 0,
+            status: ContractStatus::CustomerClose as i32,
+            revocation_lock_bytes: vec![0; 32],
+            self_delay: 172_800,
+            delay_expiry,
+            merchant_public_key: (Vec::new(), Default::default(), Vec::new()),
+            contract_code: String::new(),
+        }
+    }
+
+    #[test]
+    fn funding_balance_covers_deposit_and_fees() {
+        let address =
+            TezosFundingAddress::from_base58check("tz1iKxZpa5x1grZyN2Uw9gERXJJPMyG22Sqp").unwrap();
+        let deposit = 10_000_000;
+        let customer_required = deposit + ORIGINATION_COST_ESTIMATE + OPERATION_FEE_ESTIMATE;
+        let merchant_required = deposit + OPERATION_FEE_ESTIMATE;
+
+        assert!(
+            check_funding_balance(&address, Party::Customer, customer_required, deposit).is_ok()
+        );
+        assert!(matches!(
+            check_funding_balance(&address, Party::Customer, customer_required - 1, deposit),
+            Err(FundingBalanceError::Insufficient {
+                party: Party::Customer,
+                ..
+            })
+        ));
+        // A customer balance that covers only the deposit is not enough
+        assert!(check_funding_balance(&address, Party::Customer, deposit, deposit).is_err());
+
+        assert!(
+            check_funding_balance(&address, Party::Merchant, merchant_required, deposit).is_ok()
+        );
+        assert!(
+            check_funding_balance(&address, Party::Merchant, merchant_required - 1, deposit)
+                .is_err()
+        );
+
+        assert_eq!(
+            required_funding_balance(Party::Merchant, u64::MAX),
+            u64::MAX
+        );
+    }
+
+    #[test]
+    fn customer_funding_with_either_deposit_zero() {
+        const ADDRESS: &str = "tz1iKxZpa5x1grZyN2Uw9gERXJJPMyG22Sqp";
+        let address = TezosFundingAddress::from_base58check(ADDRESS).unwrap();
+        let funding = Some((
+            "opHash".to_string(),
+            100,
+            ADDRESS.to_string(),
+            "applied".to_string(),
+        ));
+        let check = |customer, merchant, status, funding| {
+            check_customer_funding(
+                &CustomerBalance::try_new(customer).unwrap(),
+                &MerchantBalance::try_new(merchant).unwrap(),
+                &address,
+                status,
+                funding,
+            )
+        };
+        let operation = Some(FundingOperation::new("opHash".to_string(), 100));
+
+        // Both parties deposit: the merchant has yet to fund
+        assert_eq!(
+            check(
+                5,
+                5,
+                ContractStatus::AwaitingMerchantFunding,
+                funding.clone()
+            )
+            .unwrap(),
+            operation
+        );
+        assert!(matches!(
+            check(5, 5, ContractStatus::Open, funding.clone()),
+            Err(VerificationError::UnexpectedContractStatus { .. })
+        ));
+
+        // Only the customer deposits: the contract is open once they fund it
+        assert_eq!(
+            check(5, 0, ContractStatus::Open, funding.clone()).unwrap(),
+            operation
+        );
+        assert!(matches!(
+            check(5, 0, ContractStatus::Open, None),
+            Err(VerificationError::FundingOperationNotFound)
+        ));
+
+        // Only the merchant deposits: the customer may not have called the contract at all, but
+        // if they did, it must have been from their address
+        assert_eq!(
+            check(0, 5, ContractStatus::AwaitingMerchantFunding, None).unwrap(),
+            None
+        );
+        assert_eq!(
+            check(0, 5, ContractStatus::AwaitingMerchantFunding, funding).unwrap(),
+            operation
+        );
+        assert!(matches!(
+            check(
+                0,
+                5,
+                ContractStatus::AwaitingMerchantFunding,
+                Some((
+                    "opHash".to_string(),
+                    100,
+                    "tz1someoneelse".to_string(),
+                    "applied".to_string()
+                ))
+            ),
+            Err(VerificationError::UnexpectedFundingSource { .. })
+        ));
+        assert!(matches!(
+            check(0, 5, ContractStatus::AwaitingCustomerFunding, None),
+            Err(VerificationError::UnexpectedContractStatus { .. })
+        ));
+    }
+
+    #[test]
+    fn customer_address_must_match() {
+        let contract_state = contract_state_with_delay_expiry(0);
+        let recorded =
+            TezosFundingAddress::from_base58check("tz1iKxZpa5x1grZyN2Uw9gERXJJPMyG22Sqp").unwrap();
+        assert!(contract_state.verify_customer_address(&recorded).is_ok());
+
+        let other =
+            TezosFundingAddress::from_base58check("tz1VSUr8wwNhLAzempoch5d6hLRiTh8Cjcjb").unwrap();
+        assert!(matches!(
+            contract_state.verify_customer_address(&other),
+            Err(VerificationError::UnexpectedCustomerAddress { expected, actual })
+                if expected == "tz1VSUr8wwNhLAzempoch5d6hLRiTh8Cjcjb"
+                    && actual == "tz1iKxZpa5x1grZyN2Uw9gERXJJPMyG22Sqp"
+        ));
+    }
+
+    #[test]
+    fn unset_delay_expiry() {
+        let contract_state = contract_state_with_delay_expiry(0);
+        assert_eq!(contract_state.delay_expiry(), None);
+        assert_eq!(contract_state.timeout_expired(), None);
+    }
+
+    #[test]
+    fn past_delay_expiry() {
+        // 2021-09-01T00:00:00Z
+        let contract_state = contract_state_with_delay_expiry(1_630_454_400);
+        assert_eq!(
+            contract_state.delay_expiry(),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_630_454_400))
+        );
+        assert_eq!(contract_state.timeout_expired(), Some(true));
+    }
+
+    #[test]
+    fn delay_expiry_after_2038() {
+        // Past the end of signed and unsigned 32-bit timestamps, respectively
+        for &seconds in &[i32::MAX as u64 + 1, u32::MAX as u64 + 1] {
+            let contract_state = contract_state_with_delay_expiry(seconds);
+            assert_eq!(
+                contract_state
+                    .delay_expiry()
+                    .unwrap()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap(),
+                Duration::from_secs(seconds)
+            );
+        }
+
+        // 2128-06-11T08:53:20Z
+        let contract_state = contract_state_with_delay_expiry(5_000_000_000);
+        assert_eq!(contract_state.timeout_expired(), Some(false));
+    }
+
+    #[tokio::test]
+    async fn mutual_close_authorization_is_verified() {
+        use rand::{rngs::StdRng, SeedableRng};
+        use zkabacus_crypto::{merchant, CustomerRandomness, MerchantRandomness};
+
+        let key_material = TezosKeyMaterial::read_key_pair(&KeySpecifier::Alias {
+            alias: "edsk2pfUZ7NAbo7ekr5RHW6Dni2GYKS935mqXXcrbXtTn8dCfTfViZ".into(),
+        })
+        .unwrap();
+        let merchant_pubkey = key_material.public_key().clone();
+        let client = TezosClient {
+            uri: None,
+            contract_id: ContractId::new(
+                OriginatedAddress::from_base58check("KT1Mjjcb6tmSsLm7Cb3DSQszePjfchPM4Uxm")
+                    .unwrap(),
+            ),
+            client_signer: Arc::new(key_material),
+            confirmation_depth: 1,
+            self_delay: 120,
+        };
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let (merchant_public_key, _, _) =
+            merchant::Config::new(&mut rng).extract_customer_config_parts();
+        let channel_id = ChannelId::new(
+            MerchantRandomness::new(&mut rng),
+            CustomerRandomness::new(&mut rng),
+            &merchant_public_key,
+            &[],
+            &[],
+        );
+        let customer_balance = CustomerBalance::try_new(6).unwrap();
+        let merchant_balance = MerchantBalance::try_new(4).unwrap();
+        let verify = |signature: &MutualCloseAuthorizationSignature, customer, merchant| {
+            client.verify_authorization_signature(
+                &channel_id,
+                &merchant_pubkey,
+                &CustomerBalance::try_new(customer).unwrap(),
+                &MerchantBalance::try_new(merchant).unwrap(),
+                signature,
+            )
+        };
+
+        let signature = client
+            .authorize_balances(&channel_id, &customer_balance, &merchant_balance)
+            .await
+            .unwrap();
+        verify(&signature, 6, 4).await.unwrap();
+
+        // The signature doesn't carry over to a close paying the customer more
+        assert!(verify(&signature, 7, 3).await.is_err());
+
+        // Nor is a signature corrupted in transit accepted
+        let mut corrupted = signature.signature.clone().into_bytes();
+        let last = corrupted.len() - 1;
+        corrupted[last] = if corrupted[last] == b'1' { b'2' } else { b'1' };
+        let corrupted = MutualCloseAuthorizationSignature {
+            signature: String::from_utf8(corrupted).unwrap(),
+        };
+        assert!(verify(&corrupted, 6, 4).await.is_err());
+    }
+}
@@ -1,8 +1,11 @@
 pub mod amount;
 pub mod arbiter;
+pub mod build_info;
 pub mod customer;
 pub mod escrow;
+pub mod logging;
 pub mod merchant;
+pub mod metrics;
 pub mod protocol;
 pub mod timeout;
 
@@ -0,0 +1,53 @@
+//! Logging for both the customer and the merchant, through [`tracing`].
+//!
+//! Which events are logged is set by the `--log-level` flag, or failing that by the `ZEEKOE_LOG`
+//! environment variable, in the same syntax as `RUST_LOG`: for instance, `debug`, or
+//! `zeekoe=debug,sqlx=warn`. By default, events at the `info` level and above are logged.
+
+use tracing_subscriber::{filter::ParseError, EnvFilter};
+
+/// The environment variable from which the log filter is read, if `--log-level` isn't given.
+pub const LOG_ENV_VAR: &str = "ZEEKOE_LOG";
+
+/// The log filter used if neither `--log-level` nor [`LOG_ENV_VAR`] is given.
+const DEFAULT_FILTER: &str = "info";
+
+/// The filter for log events: the given `--log-level`, if any, or else the one in the environment.
+pub fn filter(log_level: Option<&str>) -> Result<EnvFilter, ParseError> {
+    match log_level {
+        Some(directives) => EnvFilter::try_new(directives),
+        None => match std::env::var(LOG_ENV_VAR) {
+            Ok(directives) => EnvFilter::try_new(directives),
+            Err(_) => EnvFilter::try_new(DEFAULT_FILTER),
+        },
+    }
+}
+
+/// Log events to standard error, filtered according to [`filter`].
+///
+/// If logging has already been set up, this does nothing.
+pub fn init(log_level: Option<&str>) -> Result<(), anyhow::Error> {
+    let filter = filter(log_level)?;
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_target(false)
+        .try_init();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_levels() {
+        // Levels can be given overall, or per module as with `RUST_LOG`
+        assert_eq!(filter(Some("debug")).unwrap().to_string(), "debug");
+        assert_eq!(
+            filter(Some("zeekoe=debug,sqlx=warn")).unwrap().to_string(),
+            "zeekoe=debug,sqlx=warn"
+        );
+        assert!(filter(Some("zeekoe=loud")).is_err());
+    }
+}
@@ -1,5 +1,10 @@
+pub mod approve;
+pub mod webhook;
+
 pub use crate::cli::{merchant as cli, merchant::Cli};
 pub use crate::config::{merchant as config, merchant::Config};
 pub use crate::database::merchant as database;
 pub use crate::defaults::merchant as defaults;
+pub use crate::transport::client_auth::ClientAuth;
+pub use crate::transport::message_length;
 pub use crate::transport::server::{self as server, Chan, Server};
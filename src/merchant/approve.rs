@@ -0,0 +1,971 @@
+//! Asking the merchant's approver whether to accept payments and new channels, and reporting
+//! their outcomes to it.
+
+use {
+    anyhow::Context,
+    ring::hmac,
+    rusty_money::FormattableCurrency,
+    serde::{Deserialize, Serialize},
+    std::time::{Duration, Instant, SystemTime},
+    thiserror::Error,
+    url::Url,
+    uuid::Uuid,
+};
+
+use zkabacus_crypto::{CustomerBalance, MerchantBalance, PaymentAmount};
+
+use crate::{
+    amount::{Amount, XTZ},
+    merchant::config::{Approver, ApproverAuth, DepositBounds, EstablishApprover, Service},
+    metrics,
+    protocol::establish,
+};
+
+/// The header in which requests to an approver carry their HMAC signature, if the approver is
+/// configured to authenticate requests that way.
+pub const SIGNATURE_HEADER: &str = "X-Zeekoe-Signature";
+
+/// How long to wait before retrying a request to an approver that couldn't be reached.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// The secret with which to authenticate requests to an approver, loaded from the file given by
+/// the service's [`ApproverAuth`].
+#[derive(Clone)]
+pub enum ApproverCredentials {
+    /// Send the token in an `Authorization: Bearer` header.
+    Bearer(String),
+    /// Sign the body of each request with HMAC-SHA256, sending the signature in the
+    /// [`SIGNATURE_HEADER`].
+    Hmac(hmac::Key),
+}
+
+impl ApproverCredentials {
+    /// Load the secret from its file. Surrounding whitespace, such as a trailing newline, is not
+    /// part of the secret.
+    pub async fn load(auth: &ApproverAuth) -> Result<Self, std::io::Error> {
+        Ok(match auth {
+            ApproverAuth::Bearer { token_file } => Self::Bearer(
+                tokio::fs::read_to_string(token_file)
+                    .await?
+                    .trim()
+                    .to_string(),
+            ),
+            ApproverAuth::Hmac { secret_file } => Self::hmac(
+                tokio::fs::read_to_string(secret_file)
+                    .await?
+                    .trim()
+                    .as_bytes(),
+            ),
+        })
+    }
+
+    /// Credentials which sign requests with the given HMAC-SHA256 secret.
+    pub fn hmac(secret: &[u8]) -> Self {
+        Self::Hmac(hmac::Key::new(hmac::HMAC_SHA256, secret))
+    }
+}
+
+/// The hex-encoded HMAC-SHA256 signature of a request body, as sent in the [`SIGNATURE_HEADER`].
+pub fn signature(key: &hmac::Key, body: &[u8]) -> String {
+    hex::encode(hmac::sign(key, body).as_ref())
+}
+
+/// An error in reaching an approver.
+#[derive(Debug, Error)]
+pub enum ApproverError {
+    #[error("Approver did not respond within {}", humantime::format_duration(*.0))]
+    Timeout(Duration),
+    #[error(transparent)]
+    Request(#[from] reqwest::Error),
+}
+
+impl ApproverError {
+    /// The reason for rejecting a payment or channel because of this error, to forward to the
+    /// customer. A timeout is a rejection like any other, but any other error is internal.
+    fn into_rejection(self) -> Option<String> {
+        match self {
+            Self::Timeout(_) => Some("approver did not respond in time".into()),
+            Self::Request(_) => None,
+        }
+    }
+}
+
+/// A client for a merchant service's approver, which authenticates its requests and gives up on
+/// them after a timeout.
+#[derive(Clone)]
+pub struct ApproverClient {
+    client: reqwest::Client,
+    credentials: Option<ApproverCredentials>,
+    timeout: Duration,
+}
+
+impl ApproverClient {
+    pub fn new(
+        client: reqwest::Client,
+        credentials: Option<ApproverCredentials>,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            client,
+            credentials,
+            timeout,
+        }
+    }
+
+    /// Make a client for the service's approver, loading its credentials if it has any.
+    pub async fn for_service(
+        client: reqwest::Client,
+        service: &Service,
+    ) -> Result<Self, anyhow::Error> {
+        let credentials = match &service.approver_auth {
+            None => None,
+            Some(auth) => Some(
+                ApproverCredentials::load(auth)
+                    .await
+                    .context("Failed to load approver credentials")?,
+            ),
+        };
+        Ok(Self::new(client, credentials, service.approver_timeout))
+    }
+
+    /// Send the request made by `request`, with the given body, authenticating it.
+    ///
+    /// If the approver can't be reached, the request is retried until the timeout elapses.
+    async fn send(
+        &self,
+        request: impl Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response, ApproverError> {
+        let attempts = async {
+            loop {
+                let request = match &self.credentials {
+                    None => request(&self.client),
+                    Some(ApproverCredentials::Bearer(token)) => {
+                        request(&self.client).bearer_auth(token)
+                    }
+                    Some(ApproverCredentials::Hmac(key)) => {
+                        request(&self.client).header(SIGNATURE_HEADER, signature(key, &body))
+                    }
+                };
+                match request.body(body.clone()).send().await {
+                    Err(e) if e.is_connect() => tokio::time::sleep(RETRY_DELAY).await,
+                    result => return Ok(result?),
+                }
+            }
+        };
+
+        let started = Instant::now();
+        let result = tokio::time::timeout(self.timeout, attempts)
+            .await
+            .unwrap_or(Err(ApproverError::Timeout(self.timeout)));
+        metrics::approver_latency(started.elapsed());
+        result
+    }
+}
+
+/// The description of a payment sent to a URL-based approver, as the JSON body of a `POST` request
+/// to `/pay` or `/refund`.
+///
+/// Payments are unlinkable, so the merchant can't tell which channel a payment comes from, and
+/// there is no channel ID to include.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PaymentRequest {
+    /// A unique identifier for this payment, generated by the merchant.
+    pub payment_id: Uuid,
+    /// The magnitude of the payment, in the minor units of its currency.
+    pub amount: u64,
+    /// The currency code of the payment.
+    pub currency: String,
+    /// The note the customer attached to the payment.
+    pub note: String,
+    /// When the merchant received the payment request, in RFC 3339 format.
+    pub timestamp: String,
+}
+
+impl PaymentRequest {
+    fn new(payment_amount: &PaymentAmount, note: String) -> Self {
+        Self {
+            payment_id: Uuid::new_v4(),
+            amount: payment_amount.to_i64().unsigned_abs(),
+            currency: XTZ.code().to_string(),
+            note,
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        }
+    }
+}
+
+/// An approver's approval of a payment.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PaymentApproval {
+    /// Where the *result* of the payment may be located, once the pay session completes
+    /// successfully.
+    pub response_url: Option<Url>,
+    /// The body of the approver's response, to send to the customer as the response note if there
+    /// is no `response_url`.
+    pub response_note: Option<String>,
+}
+
+/// Ask the specified approver to approve the payment amount and note (or not), returning either
+/// `Ok` if it is approved, and `Err` if it is not approved.
+///
+/// A URL-based approver is sent a [`PaymentRequest`] as JSON, unless `legacy_request` is set, in
+/// which case the amount is sent in the query string and the note as the body of the request.
+///
+/// Rejected payments may provide an `Option<String>` indicating the reason for the payment's
+/// rejection, where `None` indicates that it was rejected due to an internal error in the approver
+/// service. This information is forwarded directly to the customer, so we do not provide further
+/// information about the nature of the internal error, to prevent internal state leakage.
+pub async fn payment(
+    client: &ApproverClient,
+    approver: &Approver,
+    legacy_request: bool,
+    payment_amount: &PaymentAmount,
+    payment_note: String,
+) -> Result<PaymentApproval, Option<String>> {
+    match approver {
+        // The automatic approver approves all non-negative payments
+        Approver::Automatic => {
+            if payment_amount > &PaymentAmount::zero() {
+                Ok(PaymentApproval::default())
+            } else {
+                Err(Some("amount must be non-negative".into()))
+            }
+        }
+        // A URL-based approver approves a payment iff it returns a success code
+        Approver::Url(approver_url) => {
+            let url = approver_url
+                .join(if payment_amount > &PaymentAmount::zero() {
+                    "pay"
+                } else {
+                    "refund"
+                })
+                .map_err(|_| None)?;
+            let response = if legacy_request {
+                // POST /pay?amount=<amount>
+                // body: payment_note
+                client
+                    .send(
+                        |client| {
+                            client
+                                .post(url.clone())
+                                .query(&[("amount", payment_amount.to_i64().abs())])
+                        },
+                        payment_note.into_bytes(),
+                    )
+                    .await
+            } else {
+                // POST /pay
+                // body: PaymentRequest
+                let body = serde_json::to_vec(&PaymentRequest::new(payment_amount, payment_note))
+                    .map_err(|_| None)?;
+                client
+                    .send(
+                        |client| {
+                            client
+                                .post(url.clone())
+                                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                        },
+                        body,
+                    )
+                    .await
+            }
+            .map_err(ApproverError::into_rejection)?;
+
+            if response.status().is_success() {
+                if let Some(response_location) = response.headers().get(reqwest::header::LOCATION) {
+                    // An error converting a `Location` header into a URL is an internal error
+                    // (represented as `Err(None)`)
+                    let response_location_str = response_location.to_str().map_err(|_| None)?;
+                    let response_url = Url::parse(response_location_str).map_err(|_| None)?;
+
+                    // Valid URL in `Location` header, so pingback after payment
+                    Ok(PaymentApproval {
+                        response_url: Some(response_url),
+                        response_note: None,
+                    })
+                } else if legacy_request {
+                    // No `Location` header, so don't pingback after payment
+                    Ok(PaymentApproval::default())
+                } else {
+                    // No `Location` header, so the body of the response is the result
+                    Ok(PaymentApproval {
+                        response_url: None,
+                        response_note: Some(response.text().await.map_err(|_| None)?),
+                    })
+                }
+            } else {
+                // Return the non-success body response to the customer
+                Err(response.text().await.map(Some).unwrap_or(None))
+            }
+        }
+    }
+}
+
+/// The description of a proposed channel sent to a URL-based establish approver, as the JSON body
+/// of a `POST` request to `/establish`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EstablishRequest {
+    /// The customer's proposed deposit, in the minor units of the currency.
+    pub customer_deposit: u64,
+    /// The merchant deposit the customer asked for, in the minor units of the currency.
+    pub merchant_deposit: u64,
+    /// The currency code of the deposits.
+    pub currency: String,
+    /// The note the customer attached to the request.
+    pub note: String,
+}
+
+/// A URL-based establish approver's decision about a proposed channel, as the JSON body of its
+/// response.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "decision", rename_all = "snake_case")]
+pub enum EstablishDecision {
+    /// Approve the channel, optionally reducing the merchant's contribution to the given number
+    /// of minor units.
+    Approve {
+        #[serde(default)]
+        merchant_deposit: Option<u64>,
+    },
+    /// Reject the channel, optionally with a reason to forward to the customer.
+    Reject {
+        #[serde(default)]
+        reason: Option<String>,
+    },
+}
+
+/// An approver's approval of a new channel.
+#[derive(Debug, Clone)]
+pub struct EstablishApproval {
+    /// What the merchant contributes to the channel, which may be less than the customer asked.
+    pub merchant_deposit: MerchantBalance,
+    /// Where the success or failure of the establishment may be reported.
+    pub response_url: Option<Url>,
+}
+
+/// Ask the service's establish approver (or its payment approver, if it has no establish
+/// approver) to approve the new channel balances and note (or not), returning either `Ok` if it
+/// is approved, and `Err` if it is not approved.
+///
+/// Rejected channels may provide an `Option<String>` indicating the reason for the channel's
+/// rejection, where `None` indicates that it was rejected due to an internal error in the approver
+/// service. This information is forwarded directly to the customer, so we do not provide further
+/// information about the nature of the internal error, to prevent internal state leakage.
+pub async fn establish(
+    client: &ApproverClient,
+    service: &Service,
+    customer_balance: &CustomerBalance,
+    merchant_balance: &MerchantBalance,
+    establish_note: String,
+) -> Result<EstablishApproval, Option<String>> {
+    match &service.establish_approver {
+        None => establish_with_payment_approver(
+            client,
+            &service.approve,
+            customer_balance,
+            merchant_balance,
+            establish_note,
+        )
+        .await
+        .map(|response_url| EstablishApproval {
+            merchant_deposit: *merchant_balance,
+            response_url,
+        }),
+        Some(EstablishApprover::Automatic(bounds)) => {
+            establish_within_bounds(bounds, customer_balance, merchant_balance).map_err(Some)?;
+            Ok(EstablishApproval {
+                merchant_deposit: *merchant_balance,
+                response_url: None,
+            })
+        }
+        Some(EstablishApprover::Url(approver_url)) => {
+            establish_with_url(
+                client,
+                approver_url,
+                customer_balance,
+                merchant_balance,
+                establish_note,
+            )
+            .await
+        }
+    }
+}
+
+/// Check a proposed channel against the bounds of an automatic establish approver, returning the
+/// reason for rejecting it if it is out of bounds.
+fn establish_within_bounds(
+    bounds: &DepositBounds,
+    customer_balance: &CustomerBalance,
+    merchant_balance: &MerchantBalance,
+) -> Result<(), String> {
+    let xtz = |amount: u64| Amount::from_minor_units_of_currency(amount as i64, XTZ);
+    let customer_deposit = customer_balance.into_inner();
+    let merchant_deposit = merchant_balance.into_inner();
+
+    match bounds.min_deposit {
+        Some(min_deposit) if customer_deposit < min_deposit => {
+            return Err(format!(
+                "customer deposit must be at least {}",
+                xtz(min_deposit)
+            ))
+        }
+        _ => {}
+    }
+    match bounds.max_deposit {
+        Some(max_deposit) if customer_deposit > max_deposit => {
+            return Err(format!(
+                "customer deposit must be at most {}",
+                xtz(max_deposit)
+            ))
+        }
+        _ => {}
+    }
+    if merchant_deposit > bounds.max_merchant_contribution {
+        return Err(if bounds.max_merchant_contribution == 0 {
+            "merchant declined to contribute to initial channel balance".into()
+        } else {
+            format!(
+                "merchant contributes at most {} to a channel",
+                xtz(bounds.max_merchant_contribution)
+            )
+        });
+    }
+    Ok(())
+}
+
+/// Ask an external establish approver at the URL to decide on the proposed channel.
+async fn establish_with_url(
+    client: &ApproverClient,
+    approver_url: &Url,
+    customer_balance: &CustomerBalance,
+    merchant_balance: &MerchantBalance,
+    establish_note: String,
+) -> Result<EstablishApproval, Option<String>> {
+    // POST /establish
+    // body: EstablishRequest
+    let url = approver_url.join("establish").map_err(|_| None)?;
+    let body = serde_json::to_vec(&EstablishRequest {
+        customer_deposit: customer_balance.into_inner(),
+        merchant_deposit: merchant_balance.into_inner(),
+        currency: XTZ.code().to_string(),
+        note: establish_note,
+    })
+    .map_err(|_| None)?;
+    let response = client
+        .send(
+            |client| {
+                client
+                    .post(url.clone())
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+            },
+            body,
+        )
+        .await
+        .map_err(ApproverError::into_rejection)?;
+
+    if !response.status().is_success() {
+        // Return the non-success body response to the customer
+        return Err(response.text().await.map(Some).unwrap_or(None));
+    }
+
+    // A `Location` header gives a URL at which to report the result of the establishment; an
+    // error converting it into a URL is an internal error
+    let response_url = match response.headers().get(reqwest::header::LOCATION) {
+        Some(response_location) => {
+            let response_location_str = response_location.to_str().map_err(|_| None)?;
+            Some(Url::parse(response_location_str).map_err(|_| None)?)
+        }
+        None => None,
+    };
+
+    // An approver which responds with anything but a valid decision is in error
+    let decision: EstablishDecision =
+        serde_json::from_str(&response.text().await.map_err(|_| None)?).map_err(|_| None)?;
+    match decision {
+        EstablishDecision::Approve {
+            merchant_deposit: None,
+        } => Ok(EstablishApproval {
+            merchant_deposit: *merchant_balance,
+            response_url,
+        }),
+        EstablishDecision::Approve {
+            merchant_deposit: Some(merchant_deposit),
+        } => {
+            // The approver may reduce the merchant's contribution, but not increase it
+            let merchant_deposit = MerchantBalance::try_new(merchant_deposit).map_err(|_| None)?;
+            let merchant_deposit =
+                establish::check_merchant_contribution(*merchant_balance, merchant_deposit)
+                    .map_err(|_| None)?;
+            Ok(EstablishApproval {
+                merchant_deposit,
+                response_url,
+            })
+        }
+        EstablishDecision::Reject { reason } => {
+            Err(Some(reason.unwrap_or_else(|| {
+                "merchant declined to open the channel".into()
+            })))
+        }
+    }
+}
+
+/// Ask the payment approver to approve the new channel balances and note, for services which don't
+/// configure an establish approver. Approved channels may refer to an `Option<Url>`, where the
+/// success or failure of the establishment may be reported.
+async fn establish_with_payment_approver(
+    client: &ApproverClient,
+    approver: &Approver,
+    customer_balance: &CustomerBalance,
+    merchant_balance: &MerchantBalance,
+    establish_note: String,
+) -> Result<Option<Url>, Option<String>> {
+    match approver {
+        // The automatic approver approves all establishment requests
+        Approver::Automatic => {
+            if merchant_balance.into_inner() == 0 {
+                Ok(None)
+            } else {
+                Err(Some(
+                    "merchant declined to contribute to initial channel balance".into(),
+                ))
+            }
+        }
+
+        // A URL-based approver approves a payment iff it returns a success code
+        Approver::Url(approver_url) => {
+            let customer_balance = customer_balance.into_inner();
+            let merchant_balance = merchant_balance.into_inner();
+
+            // POST /establish?customer-amount=<customer_balance>&merchant-amount=<merchant_balance>
+            // body: establish_note
+            let url = approver_url.join("establish").map_err(|_| None)?;
+            let response = client
+                .send(
+                    |client| {
+                        client.post(url.clone()).query(&[
+                            ("customer-amount", customer_balance),
+                            ("merchant-amount", merchant_balance),
+                        ])
+                    },
+                    establish_note.into_bytes(),
+                )
+                .await
+                .map_err(ApproverError::into_rejection)?;
+
+            if response.status().is_success() {
+                if let Some(response_location) = response.headers().get(reqwest::header::LOCATION) {
+                    // An error converting a `Location` header into a URL is an internal error
+                    let response_location_str = response_location.to_str().map_err(|_| None)?;
+                    let response_url = Url::parse(response_location_str).map_err(|_| None)?;
+
+                    // Valid URL in `Location` header, so pingback after establishment
+                    Ok(Some(response_url))
+                } else {
+                    // No `Location` header, so don't pingback after establishment
+                    Ok(None)
+                }
+            } else {
+                // Return the non-success body response to the customer
+                Err(response.text().await.map(Some).unwrap_or(None))
+            }
+        }
+    }
+}
+
+/// Notify the confirmer, if any, of a payment success, and fetch a payment result, if any, to
+/// return directly to the customer.
+pub async fn payment_success(
+    client: &ApproverClient,
+    approval: PaymentApproval,
+) -> Result<Option<String>, anyhow::Error> {
+    if let Some(response_url) = approval.response_url {
+        // Request the good/service at the url
+        let response = client
+            .send(|client| client.get(response_url.clone()), Vec::new())
+            .await
+            .with_context(|| format!("Failed to get resource at {}", response_url.clone()))?;
+
+        // If success, delete the resource and return it
+        if response.status().is_success() {
+            let body = response.text().await?;
+            delete_resource(client, response_url, true).await;
+            Ok(Some(body))
+        } else {
+            Ok(None)
+        }
+    } else {
+        Ok(Some(approval.response_note.unwrap_or_default()))
+    }
+}
+
+/// Notify the confirmer, if any, of a failure (of payment or establishment).
+pub async fn failure(client: &ApproverClient, response_url: Option<Url>) {
+    if let Some(response_url) = response_url {
+        delete_resource(client, response_url, false).await;
+    }
+}
+
+/// Notify the confirmer, if any, of a successful establishment.
+pub async fn establish_success(client: &ApproverClient, response_url: Option<Url>) {
+    if let Some(response_url) = response_url {
+        delete_resource(client, response_url, true).await;
+    }
+}
+
+/// Send a `DELETE` request to a resource at the specified `url`, with the query parameter
+/// `?success=true` or `?success=false`, depending on the value of `success`.
+///
+/// This is common functionality between [`payment_success`] and [`failure`].
+pub async fn delete_resource(client: &ApproverClient, url: Url, success: bool) {
+    client
+        .send(
+            |client| client.delete(url.clone()).query(&[("success", success)]),
+            Vec::new(),
+        )
+        .await
+        .map(|_| ())
+        .unwrap_or(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    /// A request received by the mock approver.
+    #[derive(Debug, Clone)]
+    struct Request {
+        line: String,
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl Request {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    fn client() -> ApproverClient {
+        ApproverClient::new(reqwest::Client::new(), None, Duration::from_secs(5))
+    }
+
+    /// Run a mock approver which answers every request with the given status and body, returning
+    /// its URL and a record of the requests it receives.
+    async fn mock_approver(
+        status: &'static str,
+        body: &'static str,
+    ) -> (Url, Arc<Mutex<Vec<Request>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let received = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+
+                let mut request_line = String::new();
+                stream.read_line(&mut request_line).await.unwrap();
+                let mut headers = Vec::new();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    stream.read_line(&mut header).await.unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                        headers.push((name.trim().to_string(), value.trim().to_string()));
+                    }
+                }
+                let mut request_body = vec![0; content_length];
+                stream.read_exact(&mut request_body).await.unwrap();
+                received.lock().unwrap().push(Request {
+                    line: request_line.trim().to_string(),
+                    headers,
+                    body: String::from_utf8(request_body).unwrap(),
+                });
+
+                stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            status,
+                            body.len(),
+                            body
+                        )
+                        .as_bytes(),
+                    )
+                    .await
+                    .unwrap();
+            }
+        });
+
+        (Url::parse(&url).unwrap(), requests)
+    }
+
+    #[tokio::test]
+    async fn approver_receives_payment_as_json() {
+        let (url, requests) = mock_approver("200 OK", "your receipt").await;
+        let client = client();
+
+        let approval = payment(
+            &client,
+            &Approver::Url(url),
+            false,
+            &PaymentAmount::pay_merchant(5).unwrap(),
+            "a coffee".into(),
+        )
+        .await
+        .unwrap();
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert!(request.line.starts_with("POST /pay "));
+        let request: PaymentRequest = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(request.amount, 5);
+        assert_eq!(request.currency, "XTZ");
+        assert_eq!(request.note, "a coffee");
+        assert!(humantime::parse_rfc3339(&request.timestamp).is_ok());
+
+        // The body of the approval is what the customer gets once the payment completes
+        assert_eq!(
+            payment_success(&client, approval).await.unwrap(),
+            Some("your receipt".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn approver_rejects_payment_with_reason() {
+        let (url, _) = mock_approver("403 Forbidden", "over your limit").await;
+        let client = client();
+
+        let rejection = payment(
+            &client,
+            &Approver::Url(url),
+            false,
+            &PaymentAmount::pay_merchant(5).unwrap(),
+            "a yacht".into(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(rejection, Some("over your limit".to_string()));
+    }
+
+    #[tokio::test]
+    async fn legacy_approver_request() {
+        let (url, requests) = mock_approver("200 OK", "ignored").await;
+        let client = client();
+
+        let approval = payment(
+            &client,
+            &Approver::Url(url),
+            true,
+            &PaymentAmount::pay_customer(7).unwrap(),
+            "a refund".into(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(approval, PaymentApproval::default());
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert!(request.line.starts_with("POST /refund?amount=7 "));
+        assert_eq!(request.body, "a refund");
+    }
+
+    fn deposits(customer: u64, merchant: u64) -> (CustomerBalance, MerchantBalance) {
+        (
+            CustomerBalance::try_new(customer).unwrap(),
+            MerchantBalance::try_new(merchant).unwrap(),
+        )
+    }
+
+    #[test]
+    fn automatic_establish_bounds() {
+        let bounds = DepositBounds {
+            min_deposit: Some(10),
+            max_deposit: Some(100),
+            max_merchant_contribution: 50,
+        };
+        let check = |customer, merchant| {
+            let (customer, merchant) = deposits(customer, merchant);
+            establish_within_bounds(&bounds, &customer, &merchant)
+        };
+
+        assert_eq!(check(10, 0), Ok(()));
+        assert_eq!(check(100, 50), Ok(()));
+        assert!(check(9, 0).unwrap_err().contains("at least"));
+        assert!(check(101, 0).unwrap_err().contains("at most"));
+        assert!(check(50, 51).unwrap_err().contains("contributes at most"));
+
+        // By default, any customer deposit is accepted, but the merchant contributes nothing
+        let (customer, merchant) = deposits(1, 1);
+        assert_eq!(
+            establish_within_bounds(&DepositBounds::default(), &customer, &merchant),
+            Err("merchant declined to contribute to initial channel balance".to_string())
+        );
+    }
+
+    async fn ask_establish_approver(
+        status: &'static str,
+        body: &'static str,
+    ) -> (Result<EstablishApproval, Option<String>>, Vec<Request>) {
+        let (url, requests) = mock_approver(status, body).await;
+        let (customer, merchant) = deposits(20, 10);
+        let result = establish_with_url(
+            &client(),
+            &url,
+            &customer,
+            &merchant,
+            "a new channel".into(),
+        )
+        .await;
+        let requests = requests.lock().unwrap().clone();
+        (result, requests)
+    }
+
+    #[tokio::test]
+    async fn establish_approver_approves() {
+        let (result, requests) =
+            ask_establish_approver("200 OK", r#"{"decision":"approve"}"#).await;
+        assert_eq!(result.unwrap().merchant_deposit.into_inner(), 10);
+
+        assert!(requests[0].line.starts_with("POST /establish "));
+        assert_eq!(
+            serde_json::from_str::<EstablishRequest>(&requests[0].body).unwrap(),
+            EstablishRequest {
+                customer_deposit: 20,
+                merchant_deposit: 10,
+                currency: "XTZ".into(),
+                note: "a new channel".into(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn establish_approver_reduces_contribution() {
+        let (result, _) =
+            ask_establish_approver("200 OK", r#"{"decision":"approve","merchant_deposit":4}"#)
+                .await;
+        assert_eq!(result.unwrap().merchant_deposit.into_inner(), 4);
+
+        // An approver can't make the merchant contribute more than the customer asked for
+        let (result, _) =
+            ask_establish_approver("200 OK", r#"{"decision":"approve","merchant_deposit":11}"#)
+                .await;
+        assert_eq!(result.unwrap_err(), None);
+    }
+
+    #[tokio::test]
+    async fn establish_approver_rejects() {
+        let (result, _) = ask_establish_approver(
+            "200 OK",
+            r#"{"decision":"reject","reason":"too many channels"}"#,
+        )
+        .await;
+        assert_eq!(result.unwrap_err(), Some("too many channels".to_string()));
+
+        let (result, _) = ask_establish_approver("403 Forbidden", "go away").await;
+        assert_eq!(result.unwrap_err(), Some("go away".to_string()));
+
+        // A response that isn't a decision is an internal error
+        let (result, _) = ask_establish_approver("200 OK", "sure, why not").await;
+        assert_eq!(result.unwrap_err(), None);
+    }
+
+    #[test]
+    fn hmac_signature_of_known_body() {
+        // Test case 2 of RFC 4231
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"Jefe");
+        assert_eq!(
+            signature(&key, b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn approver_requests_are_authenticated() {
+        let (url, requests) = mock_approver("200 OK", "").await;
+        let amount = PaymentAmount::pay_merchant(5).unwrap();
+
+        // A bearer token goes in the `Authorization` header
+        let client = ApproverClient::new(
+            reqwest::Client::new(),
+            Some(ApproverCredentials::Bearer("hunter2".into())),
+            Duration::from_secs(5),
+        );
+        payment(
+            &client,
+            &Approver::Url(url.clone()),
+            false,
+            &amount,
+            "".into(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            requests.lock().unwrap()[0].header("authorization"),
+            Some("Bearer hunter2")
+        );
+
+        // An HMAC signature of the body goes in its own header
+        let client = ApproverClient::new(
+            reqwest::Client::new(),
+            Some(ApproverCredentials::hmac(b"Jefe")),
+            Duration::from_secs(5),
+        );
+        payment(&client, &Approver::Url(url), false, &amount, "".into())
+            .await
+            .unwrap();
+        let request = requests.lock().unwrap()[1].clone();
+        assert_eq!(
+            request.header(SIGNATURE_HEADER),
+            Some(
+                signature(
+                    &hmac::Key::new(hmac::HMAC_SHA256, b"Jefe"),
+                    request.body.as_bytes()
+                )
+                .as_str()
+            )
+        );
+        assert_eq!(request.header("authorization"), None);
+    }
+
+    #[tokio::test]
+    async fn unresponsive_approver_rejects() {
+        // An approver which accepts connections, but never responds
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            loop {
+                connections.push(listener.accept().await.unwrap());
+            }
+        });
+
+        let client = ApproverClient::new(reqwest::Client::new(), None, Duration::from_millis(200));
+        let rejection = payment(
+            &client,
+            &Approver::Url(url),
+            false,
+            &PaymentAmount::pay_merchant(5).unwrap(),
+            "".into(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            rejection,
+            Some("approver did not respond in time".to_string())
+        );
+    }
+}
@@ -0,0 +1,499 @@
+//! Notifying the merchant's storefront of channel lifecycle events, by sending signed JSON `POST`
+//! requests to a webhook.
+//!
+//! Events are queued in memory and delivered by a background task, so that sessions never wait on
+//! the webhook. Each event is stored in the database until it is delivered, so that events which
+//! couldn't be delivered are tried again when the merchant next starts.
+
+use {
+    futures::stream::{FuturesUnordered, StreamExt},
+    ring::hmac,
+    serde::{Deserialize, Serialize},
+    std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    },
+    tokio::{sync::mpsc, task::JoinHandle},
+    url::Url,
+    uuid::Uuid,
+};
+
+use zkabacus_crypto::{ChannelId, CustomerBalance, MerchantBalance};
+
+use crate::merchant::{
+    approve::{signature, SIGNATURE_HEADER},
+    config::Webhooks as WebhookConfig,
+    database::QueryMerchant,
+};
+
+/// How many times to try delivering an event before leaving it until the merchant restarts.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// How long to wait before the first retry of an event; each later retry waits twice as long.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The kinds of event a webhook can be sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventType {
+    /// A channel was established and activated.
+    ChannelOpened,
+    /// A payment or refund completed.
+    PaymentReceived,
+    /// A close began, either mutually or by the customer posting balances on chain.
+    CloseStarted,
+    /// The merchant disputed a customer's close on a revoked balance.
+    DisputeFiled,
+}
+
+/// An event sent to the webhook, as the JSON body of a `POST` request.
+///
+/// Payments are unlinkable, so a `payment_received` event has no channel ID.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookEvent {
+    /// A unique identifier for this event, so that the webhook can ignore repeated deliveries.
+    pub id: Uuid,
+    pub event: WebhookEventType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_id: Option<String>,
+    /// The customer's balance in the channel, in mutez, if relevant to the event.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub customer_balance: Option<u64>,
+    /// The merchant's balance in the channel, in mutez, if relevant to the event.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merchant_balance: Option<u64>,
+    /// The amount of a payment, in mutez, which is negative for a refund.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount: Option<i64>,
+    /// The hash of the Tezos operation behind the event, if there is one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operation_hash: Option<String>,
+    /// When the event happened, in RFC 3339 format.
+    pub timestamp: String,
+}
+
+impl WebhookEvent {
+    fn new(event: WebhookEventType) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            event,
+            channel_id: None,
+            customer_balance: None,
+            merchant_balance: None,
+            amount: None,
+            operation_hash: None,
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        }
+    }
+
+    fn with_balances(
+        mut self,
+        channel_id: &ChannelId,
+        customer_balance: CustomerBalance,
+        merchant_balance: MerchantBalance,
+    ) -> Self {
+        self.channel_id = Some(channel_id.to_string());
+        self.customer_balance = Some(customer_balance.into_inner());
+        self.merchant_balance = Some(merchant_balance.into_inner());
+        self
+    }
+
+    /// A channel was activated with the given initial balances.
+    pub fn channel_opened(
+        channel_id: &ChannelId,
+        customer_balance: CustomerBalance,
+        merchant_balance: MerchantBalance,
+    ) -> Self {
+        Self::new(WebhookEventType::ChannelOpened).with_balances(
+            channel_id,
+            customer_balance,
+            merchant_balance,
+        )
+    }
+
+    /// A payment of the given amount, in mutez, completed.
+    pub fn payment_received(amount: i64) -> Self {
+        Self {
+            amount: Some(amount),
+            ..Self::new(WebhookEventType::PaymentReceived)
+        }
+    }
+
+    /// A channel began closing on the given final balances.
+    pub fn close_started(
+        channel_id: &ChannelId,
+        customer_balance: CustomerBalance,
+        merchant_balance: MerchantBalance,
+    ) -> Self {
+        Self::new(WebhookEventType::CloseStarted).with_balances(
+            channel_id,
+            customer_balance,
+            merchant_balance,
+        )
+    }
+
+    /// The merchant posted the given dispute operation against a customer's close.
+    pub fn dispute_filed(channel_id: &ChannelId, operation_hash: &str) -> Self {
+        Self {
+            channel_id: Some(channel_id.to_string()),
+            operation_hash: Some(operation_hash.to_string()),
+            ..Self::new(WebhookEventType::DisputeFiled)
+        }
+    }
+}
+
+/// A handle for sending events to the merchant's webhook, which does nothing if no webhook is
+/// configured.
+#[derive(Debug, Clone, Default)]
+pub struct Webhooks {
+    queue: Option<mpsc::UnboundedSender<WebhookEvent>>,
+    /// The events to send, or `None` to send all of them.
+    events: Option<Vec<WebhookEventType>>,
+}
+
+/// The background task delivering events sent through [`Webhooks`].
+#[derive(Debug)]
+pub struct WebhookDelivery(Option<JoinHandle<()>>);
+
+impl Webhooks {
+    /// Start delivering events to the configured webhook, if there is one, first retrying any
+    /// events that weren't delivered before.
+    pub async fn start(
+        config: Option<&WebhookConfig>,
+        database: Arc<dyn QueryMerchant>,
+        client: reqwest::Client,
+    ) -> Result<(Self, WebhookDelivery), std::io::Error> {
+        let config = match config {
+            None => return Ok((Self::default(), WebhookDelivery(None))),
+            Some(config) => config,
+        };
+        let secret = tokio::fs::read_to_string(&config.secret_file).await?;
+        let sender = Arc::new(Sender {
+            url: config.url.clone(),
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.trim().as_bytes()),
+            client,
+            database,
+            retry_delay: RETRY_DELAY,
+        });
+
+        let (queue, events) = mpsc::unbounded_channel();
+        let delivery = tokio::spawn(deliver(sender, events));
+        Ok((
+            Self {
+                queue: Some(queue),
+                events: config.events.clone(),
+            },
+            WebhookDelivery(Some(delivery)),
+        ))
+    }
+
+    /// Queue an event to be sent, if the webhook wants events of its type. This never waits for
+    /// the event to be delivered.
+    pub fn notify(&self, event: WebhookEvent) {
+        let wanted = match &self.events {
+            None => true,
+            Some(events) => events.contains(&event.event),
+        };
+        if let (Some(queue), true) = (&self.queue, wanted) {
+            queue.send(event).unwrap_or(());
+        }
+    }
+}
+
+impl WebhookDelivery {
+    /// Wait for every queued event to be delivered or given up on, once every [`Webhooks`] handle
+    /// has been dropped.
+    pub async fn finish(self) {
+        if let Some(delivery) = self.0 {
+            delivery.await.unwrap_or(());
+        }
+    }
+}
+
+/// Everything needed to deliver events to the webhook.
+struct Sender {
+    url: Url,
+    key: hmac::Key,
+    client: reqwest::Client,
+    database: Arc<dyn QueryMerchant>,
+    retry_delay: Duration,
+}
+
+impl Sender {
+    /// Deliver the event with the given payload, retrying a bounded number of times, and forget
+    /// it once it is delivered.
+    async fn deliver(self: Arc<Self>, id: Option<i64>, payload: String) {
+        let mut delay = self.retry_delay;
+        for attempt in 1..=MAX_ATTEMPTS {
+            match self.post(&payload).await {
+                Ok(()) => {
+                    if let Some(id) = id {
+                        if let Err(e) = self.database.delete_webhook_event(id).await {
+                            tracing::warn!(id, error = %e, "Failed to forget delivered webhook event");
+                        }
+                    }
+                    return;
+                }
+                Err(e) if attempt == MAX_ATTEMPTS => tracing::error!(
+                    attempts = MAX_ATTEMPTS,
+                    error = %e,
+                    "Giving up on webhook event until the merchant restarts"
+                ),
+                Err(_) => {
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+            }
+        }
+    }
+
+    /// Send the payload to the webhook once, signing it with the shared secret.
+    async fn post(&self, payload: &str) -> Result<(), anyhow::Error> {
+        let response = self
+            .client
+            .post(self.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, signature(&self.key, payload.as_bytes()))
+            .body(payload.to_string())
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "webhook responded with {}",
+                response.status()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Deliver events which weren't delivered before, then each event from the queue as it arrives,
+/// until the queue is closed and every event has been delivered or given up on.
+async fn deliver(sender: Arc<Sender>, mut events: mpsc::UnboundedReceiver<WebhookEvent>) {
+    let mut deliveries = FuturesUnordered::new();
+    match sender.database.undelivered_webhook_events().await {
+        Ok(undelivered) => {
+            for (id, payload) in undelivered {
+                deliveries.push(sender.clone().deliver(Some(id), payload));
+            }
+        }
+        Err(e) => tracing::warn!(error = %e, "Failed to load undelivered webhook events"),
+    }
+
+    let mut receiving = true;
+    loop {
+        tokio::select! {
+            event = events.recv(), if receiving => match event {
+                Some(event) => {
+                    let payload = serde_json::to_string(&event)
+                        .expect("Webhook events must be serializable");
+
+                    // Keep the event until it's delivered, but deliver it even if it can't be kept
+                    let id = match sender.database.insert_webhook_event(&payload).await {
+                        Ok(id) => Some(id),
+                        Err(e) => {
+                            tracing::warn!(error = %e, "Failed to store webhook event");
+                            None
+                        }
+                    };
+                    deliveries.push(sender.clone().deliver(id, payload));
+                }
+                None => receiving = false,
+            },
+            Some(()) = deliveries.next() => {},
+            else => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::SqlitePoolOptions;
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::sync::Mutex;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+    use zkabacus_crypto::{CustomerRandomness, KeyPair, MerchantRandomness};
+
+    /// Run a webhook sink which fails the first `failures` requests with a server error, then
+    /// accepts the rest, returning its URL and the signature and body of each request it accepts.
+    async fn webhook_sink(failures: usize) -> (Url, Arc<Mutex<Vec<(String, String)>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let accepted = Arc::new(Mutex::new(Vec::new()));
+
+        let received = accepted.clone();
+        tokio::spawn(async move {
+            for request in 0.. {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                let (mut signature, mut content_length) = (String::new(), 0);
+                loop {
+                    let mut header = String::new();
+                    stream.read_line(&mut header).await.unwrap();
+                    match header.split_once(':') {
+                        None => break,
+                        Some((name, value)) if name.eq_ignore_ascii_case(SIGNATURE_HEADER) => {
+                            signature = value.trim().to_string()
+                        }
+                        Some((name, value)) if name.eq_ignore_ascii_case("content-length") => {
+                            content_length = value.trim().parse().unwrap()
+                        }
+                        Some(_) => {}
+                    }
+                }
+                let mut body = vec![0; content_length];
+                stream.read_exact(&mut body).await.unwrap();
+
+                let status = if request < failures {
+                    "500 Internal Server Error"
+                } else {
+                    received
+                        .lock()
+                        .unwrap()
+                        .push((signature, String::from_utf8(body).unwrap()));
+                    "200 OK"
+                };
+                stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                            status
+                        )
+                        .as_bytes(),
+                    )
+                    .await
+                    .unwrap();
+            }
+        });
+
+        (Url::parse(&url).unwrap(), accepted)
+    }
+
+    async fn database() -> Arc<dyn QueryMerchant> {
+        let database = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        database.migrate().await.unwrap();
+        Arc::new(database)
+    }
+
+    /// Start delivering to the given URL, with a short retry delay.
+    fn start(
+        url: Url,
+        database: Arc<dyn QueryMerchant>,
+        events: Option<Vec<WebhookEventType>>,
+    ) -> (Webhooks, WebhookDelivery) {
+        let sender = Arc::new(Sender {
+            url,
+            key: hmac::Key::new(hmac::HMAC_SHA256, b"secret"),
+            client: reqwest::Client::new(),
+            database,
+            retry_delay: Duration::from_millis(10),
+        });
+        let (queue, receiver) = mpsc::unbounded_channel();
+        let delivery = tokio::spawn(deliver(sender, receiver));
+        (
+            Webhooks {
+                queue: Some(queue),
+                events,
+            },
+            WebhookDelivery(Some(delivery)),
+        )
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_events() {
+        let (url, accepted) = webhook_sink(0).await;
+        let database = database().await;
+        let (webhooks, delivery) = start(
+            url,
+            database.clone(),
+            Some(vec![WebhookEventType::PaymentReceived]),
+        );
+
+        // Only the events the webhook asked for are sent
+        webhooks.notify(WebhookEvent::payment_received(-5));
+        let mut rng = StdRng::from_entropy();
+        let channel_id = ChannelId::new(
+            MerchantRandomness::new(&mut rng),
+            CustomerRandomness::new(&mut rng),
+            KeyPair::new(&mut rng).public_key(),
+            &[],
+            &[],
+        );
+        webhooks.notify(WebhookEvent::dispute_filed(&channel_id, "ooHash"));
+        drop(webhooks);
+        delivery.finish().await;
+
+        let accepted = accepted.lock().unwrap();
+        assert_eq!(accepted.len(), 1);
+        let (signed, body) = &accepted[0];
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+        assert_eq!(*signed, signature(&key, body.as_bytes()));
+
+        let event: WebhookEvent = serde_json::from_str(body).unwrap();
+        assert_eq!(event.event, WebhookEventType::PaymentReceived);
+        assert_eq!(event.amount, Some(-5));
+        assert_eq!(event.channel_id, None);
+
+        // Delivered events aren't kept
+        assert!(database
+            .undelivered_webhook_events()
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn retries_failed_deliveries() {
+        let (url, accepted) = webhook_sink(2).await;
+        let (webhooks, delivery) = start(url, database().await, None);
+
+        webhooks.notify(WebhookEvent::payment_received(5));
+        drop(webhooks);
+        delivery.finish().await;
+
+        assert_eq!(accepted.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn undelivered_events_survive_restart() {
+        let database = database().await;
+
+        // A webhook which never accepts anything leaves the event stored...
+        let (url, _) = webhook_sink(usize::MAX).await;
+        let (webhooks, delivery) = start(url, database.clone(), None);
+        webhooks.notify(WebhookEvent::payment_received(5));
+        drop(webhooks);
+        delivery.finish().await;
+        assert_eq!(
+            database.undelivered_webhook_events().await.unwrap().len(),
+            1
+        );
+
+        // ...which is delivered when delivery starts again
+        let (url, accepted) = webhook_sink(0).await;
+        let (webhooks, delivery) = start(url, database.clone(), None);
+        drop(webhooks);
+        delivery.finish().await;
+
+        let accepted = accepted.lock().unwrap();
+        assert_eq!(accepted.len(), 1);
+        let event: WebhookEvent = serde_json::from_str(&accepted[0].1).unwrap();
+        assert_eq!(event.amount, Some(5));
+        assert!(database
+            .undelivered_webhook_events()
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,335 @@
+//! Operational metrics for the merchant, served over HTTP in the Prometheus text format.
+//!
+//! Metrics are only collected if the crate is built with the `metrics` feature. Otherwise,
+//! recording them does nothing, and [`serve`] fails immediately.
+
+use {
+    futures::Future,
+    std::{net::SocketAddr, time::Duration},
+    thiserror::Error,
+};
+
+use crate::{database::merchant::PaymentRecord, protocol::ChannelStatus};
+
+#[cfg(feature = "metrics")]
+use {
+    crate::database::merchant::PaymentOutcome,
+    hyper::{
+        header::CONTENT_TYPE,
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, StatusCode,
+    },
+    prometheus::{
+        exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec,
+        IntGaugeVec, Opts, Registry, TextEncoder,
+    },
+    std::{collections::HashMap, convert::Infallible},
+};
+
+/// The path at which metrics are served.
+pub const METRICS_PATH: &str = "/metrics";
+
+#[derive(Debug, Error)]
+pub enum MetricsError {
+    #[error("Cannot serve metrics: this build does not include the `metrics` feature")]
+    Disabled,
+    #[cfg(feature = "metrics")]
+    #[error("Metrics server failed: {0}")]
+    Server(#[from] hyper::Error),
+}
+
+#[cfg(feature = "metrics")]
+struct Metrics {
+    registry: Registry,
+    payments: IntCounterVec,
+    payment_amounts: HistogramVec,
+    approver_latency: Histogram,
+    active_sessions: IntGaugeVec,
+    sessions: IntCounterVec,
+    channels: IntGaugeVec,
+    tezos_operations: IntCounterVec,
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    fn new() -> Result<Self, prometheus::Error> {
+        let metrics = Self {
+            registry: Registry::new_custom(Some("zeekoe".into()), None)?,
+            payments: IntCounterVec::new(
+                Opts::new("payments_total", "Payments requested, by outcome"),
+                &["outcome"],
+            )?,
+            payment_amounts: HistogramVec::new(
+                HistogramOpts::new(
+                    "payment_amount_mutez",
+                    "Amounts of completed payments and refunds, in mutez",
+                )
+                // From 0.001 tez to 10,000 tez
+                .buckets(exponential_buckets(1_000.0, 10.0, 8)?),
+                &["kind"],
+            )?,
+            approver_latency: Histogram::with_opts(HistogramOpts::new(
+                "approver_request_duration_seconds",
+                "Time taken for the approver to respond to each request, including retries",
+            ))?,
+            active_sessions: IntGaugeVec::new(
+                Opts::new("active_sessions", "Sessions in progress, by method"),
+                &["method"],
+            )?,
+            sessions: IntCounterVec::new(
+                Opts::new("sessions_total", "Sessions finished, by method and outcome"),
+                &["method", "outcome"],
+            )?,
+            channels: IntGaugeVec::new(Opts::new("channels", "Channels, by status"), &["status"])?,
+            tezos_operations: IntCounterVec::new(
+                Opts::new(
+                    "tezos_operations_total",
+                    "Tezos operations posted, by entrypoint and outcome",
+                ),
+                &["entrypoint", "outcome"],
+            )?,
+        };
+
+        metrics
+            .registry
+            .register(Box::new(metrics.payments.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.payment_amounts.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.approver_latency.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.active_sessions.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.sessions.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.channels.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.tezos_operations.clone()))?;
+        Ok(metrics)
+    }
+}
+
+#[cfg(feature = "metrics")]
+lazy_static::lazy_static! {
+    static ref METRICS: Metrics = Metrics::new().expect("Metrics must be well-formed");
+}
+
+/// Record a payment which was completed, rejected, or failed, as it is recorded in the payment
+/// history.
+pub fn payment(record: &PaymentRecord) {
+    #[cfg(feature = "metrics")]
+    {
+        METRICS
+            .payments
+            .with_label_values(&[&record.outcome.to_string()])
+            .inc();
+        if record.outcome == PaymentOutcome::Completed {
+            let kind = if record.amount < 0 {
+                "refund"
+            } else {
+                "payment"
+            };
+            METRICS
+                .payment_amounts
+                .with_label_values(&[kind])
+                .observe(record.amount.unsigned_abs() as f64);
+        }
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = record;
+}
+
+/// Record how long the approver took to respond to a request.
+pub fn approver_latency(latency: Duration) {
+    #[cfg(feature = "metrics")]
+    METRICS.approver_latency.observe(latency.as_secs_f64());
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = latency;
+}
+
+/// Run a session of the given method (such as `"pay"`), counting it as active while it runs and
+/// recording whether it succeeded.
+///
+/// A session which is dropped before it finishes stops being counted as active, but isn't
+/// recorded as either succeeding or failing.
+pub async fn session<T, E>(
+    method: &'static str,
+    session: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    #[cfg(feature = "metrics")]
+    {
+        /// Stops counting a session as active when dropped, however it ends.
+        struct Active(&'static str);
+
+        impl Drop for Active {
+            fn drop(&mut self) {
+                METRICS.active_sessions.with_label_values(&[self.0]).dec();
+            }
+        }
+
+        METRICS.active_sessions.with_label_values(&[method]).inc();
+        let _active = Active(method);
+        let result = session.await;
+        let outcome = if result.is_ok() {
+            "succeeded"
+        } else {
+            "failed"
+        };
+        METRICS.sessions.with_label_values(&[method, outcome]).inc();
+        result
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    {
+        let _ = method;
+        session.await
+    }
+}
+
+/// Record the number of channels with each status, given the status of every channel.
+pub fn channels(statuses: impl IntoIterator<Item = ChannelStatus>) {
+    #[cfg(feature = "metrics")]
+    {
+        let mut counts = HashMap::new();
+        for status in statuses {
+            *counts.entry(status.to_string()).or_insert(0) += 1;
+        }
+
+        // Statuses no channel has any more shouldn't keep their last count
+        METRICS.channels.reset();
+        for (status, count) in counts {
+            METRICS.channels.with_label_values(&[&status]).set(count);
+        }
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = statuses;
+}
+
+/// Record the outcome of posting an operation to a contract entrypoint: the status of the
+/// operation, or `"error"` if it couldn't be posted.
+pub fn tezos_operation(entrypoint: &str, outcome: &str) {
+    #[cfg(feature = "metrics")]
+    METRICS
+        .tezos_operations
+        .with_label_values(&[entrypoint, outcome])
+        .inc();
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = (entrypoint, outcome);
+}
+
+/// Serve metrics over plain HTTP at [`METRICS_PATH`] on the given address, until an error occurs.
+///
+/// There is no TLS or authentication, so this should only be exposed to a local Prometheus.
+pub async fn serve(address: SocketAddr) -> Result<(), MetricsError> {
+    #[cfg(feature = "metrics")]
+    {
+        let make_service =
+            make_service_fn(|_connection| async { Ok::<_, Infallible>(service_fn(respond)) });
+        hyper::Server::try_bind(&address)?
+            .serve(make_service)
+            .await?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    {
+        let _ = address;
+        Err(MetricsError::Disabled)
+    }
+}
+
+/// Respond to a request for the metrics, or for anything else.
+#[cfg(feature = "metrics")]
+async fn respond(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if request.uri().path() != METRICS_PATH {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    let response = match encoder.encode(&METRICS.registry.gather(), &mut buffer) {
+        Ok(()) => Response::builder()
+            .header(CONTENT_TYPE, encoder.format_type())
+            .body(Body::from(buffer)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(e.to_string())),
+    };
+    Ok(response.expect("Metrics response must be well-formed"))
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use crate::database::merchant::PaymentOutcome;
+    use std::time::SystemTime;
+
+    #[tokio::test]
+    async fn scrape_after_payment() {
+        // Drive a payment through the same instrumentation as the pay session
+        let record = PaymentRecord {
+            amount: 5_000,
+            note: "coffee".into(),
+            approver: "automatic".into(),
+            response_note: None,
+            response_url: None,
+            outcome: PaymentOutcome::Completed,
+            failure_reason: None,
+            created_at: SystemTime::now(),
+            payment_id: None,
+        };
+        session("pay", async {
+            payment(&record);
+            Ok::<_, ()>(())
+        })
+        .await
+        .unwrap();
+        tezos_operation("expiry", "applied");
+        channels(vec![ChannelStatus::Active, ChannelStatus::Active]);
+
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        tokio::spawn(serve(address));
+
+        // Wait for the server to start listening
+        let url = format!("http://{}{}", address, METRICS_PATH);
+        let mut response = reqwest::get(&url).await;
+        for _ in 0..100 {
+            if response.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            response = reqwest::get(&url).await;
+        }
+        let body = response.unwrap().text().await.unwrap();
+
+        for line in &[
+            r#"zeekoe_payments_total{outcome="completed"} 1"#,
+            r#"zeekoe_payment_amount_mutez_count{kind="payment"} 1"#,
+            r#"zeekoe_sessions_total{method="pay",outcome="succeeded"} 1"#,
+            r#"zeekoe_active_sessions{method="pay"} 0"#,
+            r#"zeekoe_channels{status="active"} 2"#,
+            r#"zeekoe_tezos_operations_total{entrypoint="expiry",outcome="applied"} 1"#,
+        ] {
+            assert!(body.lines().any(|l| l == *line), "missing {}", line);
+        }
+
+        // Nothing else is served
+        let not_found = reqwest::get(&format!("http://{}/", address)).await.unwrap();
+        assert_eq!(not_found.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+}
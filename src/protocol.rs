@@ -4,16 +4,19 @@ use zkabacus_crypto::{
 use {
     dialectic::prelude::*,
     serde::{Deserialize, Serialize},
-    std::fmt::{self, Display, Formatter},
+    std::{
+        fmt::{self, Display, Formatter},
+        str::FromStr,
+    },
     thiserror::Error,
 };
 
 #[cfg(test)]
 use strum_macros::EnumIter;
 
-type OfferAbort<Next, Err> = Session! {
+type OfferAbort<Next> = Session! {
     offer {
-        0 => recv Err,
+        0 => recv AbortReason,
         1 => Next,
     }
 };
@@ -24,23 +27,26 @@ macro_rules! offer_abort {
         let $chan = ::anyhow::Context::context(dialectic::offer!(in $chan {
             0 => {
                 let party_ctx = || format!("{:?} chose to abort the session", $party.opposite());
-                let (err, $chan) = ::anyhow::Context::with_context(
+                let (reason, $chan) = ::anyhow::Context::with_context(
                     ::anyhow::Context::context(
                         $chan.recv().await,
-                        "Failed to receive error after receiving abort"
+                        "Failed to receive reason after receiving abort"
                     ),
                     party_ctx)?;
                 $chan.close();
-                return ::anyhow::Context::with_context(Err(err), party_ctx);
+                return ::anyhow::Context::with_context(
+                    Err::<_, $crate::protocol::AbortReason>(reason),
+                    party_ctx,
+                );
             }
             1 => $chan,
         }), "Failure while receiving choice of continue/abort")?;
     }
 }
 
-type ChooseAbort<Next, Err> = Session! {
+type ChooseAbort<Next> = Session! {
     choose {
-        0 => send Err,
+        0 => send AbortReason,
         1 => Next,
     }
 };
@@ -54,8 +60,8 @@ macro_rules! abort {
         )?;
         let err = $err;
         let $chan = ::anyhow::Context::context(
-            $chan.send(err.clone()).await,
-            "Failed to send error after choosing to abort",
+            $chan.send($crate::protocol::AbortReason::new(&err)).await,
+            "Failed to send reason after choosing to abort",
         )?;
         $chan.close();
         return ::anyhow::Context::context(Err(err), "Protocol aborted");
@@ -72,6 +78,49 @@ macro_rules! proceed {
     };
 }
 
+/// Why a party aborted a session, which it sends to the other party before ending the session.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Error)]
+#[error("{message}")]
+pub struct AbortReason {
+    /// The kind of error the party aborted with, as given by [`AbortCode::code`].
+    pub code: String,
+    /// The error, as the aborting party described it.
+    pub message: String,
+}
+
+impl AbortReason {
+    pub fn new(error: &impl AbortCode) -> Self {
+        Self {
+            code: error.code().to_string(),
+            message: error.to_string(),
+        }
+    }
+}
+
+/// An error a party can abort a session with.
+pub trait AbortCode: std::error::Error {
+    /// A code for the kind of error, which stays the same whatever the details of the error,
+    /// prefixed with the protocol it belongs to (e.g. `pay.rejected`).
+    fn code(&self) -> &'static str;
+}
+
+/// A note longer than the merchant accepts with a channel or payment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Error)]
+#[error("note is {length} bytes long, but the merchant accepts notes of at most {max} bytes")]
+pub struct NoteTooLong {
+    pub length: u64,
+    pub max: u64,
+}
+
+/// Check the length of a note the customer sent against the merchant's limit, in bytes.
+pub fn check_note_length(note: &str, max: u64) -> Result<(), NoteTooLong> {
+    let length = note.len() as u64;
+    if length > max {
+        return Err(NoteTooLong { length, max });
+    }
+    Ok(())
+}
+
 /// The two parties in the protocol.
 #[derive(Debug, Clone, Copy, Eq, Ord, PartialEq, PartialOrd, Hash, Serialize, Deserialize)]
 pub enum Party {
@@ -132,6 +181,47 @@ impl Display for ChannelStatus {
     }
 }
 
+/// The error when a string doesn't name a [`ChannelStatus`].
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Unknown channel status: {0}")]
+pub struct UnknownChannelStatus(String);
+
+impl FromStr for ChannelStatus {
+    type Err = UnknownChannelStatus;
+
+    /// Parse a channel status from its name, ignoring case, underscores, and hyphens.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use zeekoe::protocol::ChannelStatus;
+    ///
+    /// assert_eq!("pendingclose".parse(), Ok(ChannelStatus::PendingClose));
+    /// assert_eq!("pending_close".parse(), Ok(ChannelStatus::PendingClose));
+    /// assert!("pending".parse::<ChannelStatus>().is_err());
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name: String = s
+            .chars()
+            .filter(|c| *c != '_' && *c != '-')
+            .collect::<String>()
+            .to_lowercase();
+        Ok(match name.as_str() {
+            "originated" => Self::Originated,
+            "customerfunded" => Self::CustomerFunded,
+            "merchantfunded" => Self::MerchantFunded,
+            "active" => Self::Active,
+            "pendingexpiry" => Self::PendingExpiry,
+            "pendingclose" => Self::PendingClose,
+            "pendingmutualclose" => Self::PendingMutualClose,
+            "pendingmerchantclaim" => Self::PendingMerchantClaim,
+            "dispute" => Self::Dispute,
+            "closed" => Self::Closed,
+            _ => return Err(UnknownChannelStatus(s.to_string())),
+        })
+    }
+}
+
 impl Party {
     /// Get the other party.
     ///
@@ -152,6 +242,79 @@ impl Party {
     }
 }
 
+/// A running digest of the messages exchanged in a session, from which the context for its
+/// zero-knowledge proofs is formed.
+///
+/// Binding a proof to the transcript, rather than just to the session key, binds it to everything
+/// negotiated before it. Both parties must append the same messages in the same order, whether
+/// they sent or received them.
+#[derive(Debug, Clone)]
+pub struct Transcript(sha3::Sha3_256);
+
+impl Transcript {
+    /// Start a transcript for the session with the given session key bytes.
+    pub fn new(session_key: &[u8]) -> Self {
+        use sha3::Digest;
+        let mut transcript = Self(sha3::Sha3_256::new());
+        transcript.append(&session_key);
+        transcript
+    }
+
+    /// Append a message which was sent or received.
+    pub fn append<T: Serialize + ?Sized>(&mut self, message: &T) {
+        use sha3::Digest;
+        let bytes = bincode::serialize(message).expect("protocol messages are serializable");
+        // Prefix each message with its length, so that message boundaries are unambiguous
+        self.0.update(&(bytes.len() as u64).to_le_bytes());
+        self.0.update(&bytes);
+    }
+
+    /// The digest of the messages appended so far.
+    pub fn digest(&self) -> [u8; 32] {
+        use sha3::Digest;
+        let mut digest = [0; 32];
+        digest.copy_from_slice(self.0.clone().finalize().as_ref());
+        digest
+    }
+
+    /// The proof context for the messages appended so far.
+    pub fn context(&self) -> zkabacus_crypto::Context {
+        zkabacus_crypto::Context::new(&self.digest())
+    }
+}
+
+/// A channel for a session which encodes messages with bincode, as they would be on the wire.
+#[cfg(test)]
+pub(crate) type WireChan<S> = Chan<
+    S,
+    dialectic_tokio_serde::Sender<
+        dialectic_tokio_serde_bincode::Bincode,
+        dialectic_tokio_serde::codec::LengthDelimitedCodec,
+        tokio::io::WriteHalf<tokio::io::DuplexStream>,
+    >,
+    dialectic_tokio_serde::Receiver<
+        dialectic_tokio_serde_bincode::Bincode,
+        dialectic_tokio_serde::codec::LengthDelimitedCodec,
+        tokio::io::ReadHalf<tokio::io::DuplexStream>,
+    >,
+>;
+
+/// A connected pair of channels for a session over an in-memory connection, for the customer and
+/// the merchant, with which to test both sides of the session.
+#[cfg(test)]
+pub(crate) fn wire_pair<S: Session>() -> (WireChan<S>, WireChan<S::Dual>) {
+    use dialectic_tokio_serde_bincode::length_delimited;
+
+    let (customer_io, merchant_io) = tokio::io::duplex(1024);
+    let (rx, tx) = tokio::io::split(customer_io);
+    let (tx, rx) = length_delimited(tx, rx, 4, 1024);
+    let customer = S::wrap(tx, rx);
+    let (rx, tx) = tokio::io::split(merchant_io);
+    let (tx, rx) = length_delimited(tx, rx, 4, 1024);
+    let merchant = S::Dual::wrap(tx, rx);
+    (customer, merchant)
+}
+
 // All protocols are from the perspective of the customer.
 
 pub use close::Close;
@@ -159,36 +322,770 @@ pub use establish::Establish;
 pub use parameters::Parameters;
 pub use pay::Pay;
 
-pub type ZkChannels = Session! {
+/// The whole protocol: agree on a protocol version, then run one of the sessions.
+pub type ZkChannels = version::Negotiate;
+
+/// The sessions the customer can choose from, once both parties agree on a protocol version.
+pub type Sessions = Session! {
     choose {
         0 => Parameters,
         1 => Establish,
         2 => Pay,
         3 => Close,
+        4 => pay::Resume,
+        5 => pay::Repeated,
     }
 };
 
+/// Agreeing on the version of the protocol at the start of every connection, so that parties
+/// running incompatible versions of zeekoe find out before anything else is sent.
+///
+/// Both parties send the version they speak and the optional features they support. The merchant
+/// decides whether it serves the customer's version, and if not, tells the customer which versions
+/// it does serve. Only the features both parties support are used, and each party checks that
+/// those include every feature a session needs before running it.
+pub mod version {
+    use super::*;
+    use anyhow::Context;
+
+    /// The version of the protocol spoken by this build of zeekoe.
+    ///
+    /// Any change to the messages of a session must bump this: the major version for a change an
+    /// older party can't follow, and the minor version otherwise.
+    pub const CURRENT: ProtocolVersion = ProtocolVersion::new(4, 0);
+
+    /// The oldest version of the protocol which a merchant on this build of zeekoe still serves.
+    pub const OLDEST_SUPPORTED: ProtocolVersion = CURRENT;
+
+    /// A version of the protocol.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+    pub struct ProtocolVersion {
+        pub major: u16,
+        pub minor: u16,
+    }
+
+    impl ProtocolVersion {
+        pub const fn new(major: u16, minor: u16) -> Self {
+            Self { major, minor }
+        }
+    }
+
+    impl Display for ProtocolVersion {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            write!(f, "{}.{}", self.major, self.minor)
+        }
+    }
+
+    /// A set of optional protocol features.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+    #[serde(transparent)]
+    pub struct Features(u64);
+
+    impl Features {
+        pub const NONE: Self = Self(0);
+        /// Resuming a payment whose session was interrupted after it started.
+        pub const RESUME_PAYMENT: Self = Self(1 << 0);
+        /// Making many payments over a single session.
+        pub const REPEATED_PAYMENTS: Self = Self(1 << 1);
+        /// Sending an ID with each payment, which the merchant echoes back.
+        pub const PAYMENT_IDS: Self = Self(1 << 2);
+
+        /// Every feature this build of zeekoe supports.
+        pub const SUPPORTED: Self =
+            Self(Self::RESUME_PAYMENT.0 | Self::REPEATED_PAYMENTS.0 | Self::PAYMENT_IDS.0);
+
+        /// Whether every feature in `other` is in this set.
+        pub const fn contains(self, other: Self) -> bool {
+            self.0 & other.0 == other.0
+        }
+
+        /// The features in either this set or `other`.
+        pub const fn union(self, other: Self) -> Self {
+            Self(self.0 | other.0)
+        }
+
+        /// The features in both this set and `other`: those two parties can use with each other.
+        pub const fn intersection(self, other: Self) -> Self {
+            Self(self.0 & other.0)
+        }
+
+        /// Check that every feature in `required` is in this set, naming any which aren't.
+        pub fn require(self, required: Self) -> Result<(), MissingFeatures> {
+            let missing = Self(required.0 & !self.0);
+            if missing == Self::NONE {
+                Ok(())
+            } else {
+                Err(MissingFeatures(missing))
+            }
+        }
+    }
+
+    impl Display for Features {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            let names: Vec<&str> = [
+                (Self::RESUME_PAYMENT, "resuming payments"),
+                (Self::REPEATED_PAYMENTS, "repeated payments"),
+                (Self::PAYMENT_IDS, "payment IDs"),
+            ]
+            .iter()
+            .filter(|(feature, _)| self.contains(*feature))
+            .map(|(_, name)| *name)
+            .collect();
+            if names.is_empty() {
+                write!(f, "no features")
+            } else {
+                write!(f, "{}", names.join(", "))
+            }
+        }
+    }
+
+    /// Features a session needs which aren't supported by both parties.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+    #[error("The other party does not support {0}")]
+    pub struct MissingFeatures(pub Features);
+
+    /// What each party says about itself at the start of a connection.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct Hello {
+        pub version: ProtocolVersion,
+        pub features: Features,
+    }
+
+    impl Hello {
+        /// What this build of zeekoe says about itself.
+        pub const fn current() -> Self {
+            Self {
+                version: CURRENT,
+                features: Features::SUPPORTED,
+            }
+        }
+    }
+
+    /// The range of protocol versions a merchant serves, oldest and newest included.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct SupportedVersions {
+        pub oldest: ProtocolVersion,
+        pub newest: ProtocolVersion,
+    }
+
+    impl SupportedVersions {
+        /// The versions a merchant on this build of zeekoe serves.
+        pub const CURRENT: Self = Self {
+            oldest: OLDEST_SUPPORTED,
+            newest: CURRENT,
+        };
+
+        pub fn contains(&self, version: ProtocolVersion) -> bool {
+            self.oldest <= version && version <= self.newest
+        }
+    }
+
+    impl Display for SupportedVersions {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            if self.oldest == self.newest {
+                write!(f, "{}", self.newest)
+            } else {
+                write!(f, "{} to {}", self.oldest, self.newest)
+            }
+        }
+    }
+
+    /// The merchant's refusal to serve the customer's version of the protocol.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Error)]
+    #[error(
+        "Merchant serves zkChannels protocol version {supported}, but this customer speaks \
+        version {customer}; use a version of zeekoe which speaks a version the merchant serves"
+    )]
+    pub struct Incompatible {
+        pub customer: ProtocolVersion,
+        pub supported: SupportedVersions,
+    }
+
+    /// Check whether a merchant serving the `supported` versions can serve the customer.
+    pub fn check(customer: &Hello, supported: &SupportedVersions) -> Result<(), Incompatible> {
+        if supported.contains(customer.version) {
+            Ok(())
+        } else {
+            Err(Incompatible {
+                customer: customer.version,
+                supported: *supported,
+            })
+        }
+    }
+
+    /// Unlike every later session, the rejection is sent as [`Incompatible`] itself rather than as
+    /// an [`AbortReason`], so that it can be read by customers on any version of the protocol.
+    pub type Negotiate = Session! {
+        send Hello;
+        recv Hello;
+        offer {
+            0 => recv Incompatible,
+            1 => Sessions,
+        }
+    };
+
+    /// Agree on the protocol version as the customer, returning what the merchant said about
+    /// itself, the features both parties support, and the channel on which to choose a session.
+    ///
+    /// If the merchant doesn't serve the customer's version, the error says which versions it
+    /// does serve. If the features both parties support don't include every feature in
+    /// `required`, the error names those which are missing.
+    #[Transmitter(Tx for Hello)]
+    #[Receiver(Rx for Hello, Incompatible)]
+    pub async fn customer<Tx, Rx>(
+        chan: Chan<Negotiate, Tx, Rx>,
+        hello: Hello,
+        required: Features,
+    ) -> Result<(Hello, Features, Chan<Sessions, Tx, Rx>), anyhow::Error>
+    where
+        Tx::Error: std::error::Error + Send + Sync + 'static,
+        Rx::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let chan = chan
+            .send(hello)
+            .await
+            .context("Failed to send protocol version")?;
+        let (merchant, chan) = chan
+            .recv()
+            .await
+            .context("Failed to receive merchant's protocol version")?;
+        let chan = offer!(in chan {
+            0 => {
+                let (incompatible, chan) = chan
+                    .recv()
+                    .await
+                    .context("Failed to receive merchant's supported protocol versions")?;
+                chan.close();
+                return Err(incompatible.into());
+            }
+            1 => chan,
+        })
+        .context("Failed to receive whether merchant serves this protocol version")?;
+        let features = hello.features.intersection(merchant.features);
+        features.require(required)?;
+        Ok((merchant, features, chan))
+    }
+
+    /// Agree on the protocol version as the merchant, serving the customer only if its version is
+    /// one of the `supported` versions, and returning what the customer said about itself, the
+    /// features both parties support, and the channel on which the customer chooses a session.
+    ///
+    /// The merchant should [`require`](Features::require) the features a session needs of the
+    /// returned features before serving it.
+    #[Transmitter(Tx for Hello, Incompatible)]
+    #[Receiver(Rx for Hello)]
+    pub async fn merchant<Tx, Rx>(
+        chan: Chan<<Negotiate as Session>::Dual, Tx, Rx>,
+        hello: Hello,
+        supported: SupportedVersions,
+    ) -> Result<(Hello, Features, Chan<<Sessions as Session>::Dual, Tx, Rx>), anyhow::Error>
+    where
+        Tx::Error: std::error::Error + Send + Sync + 'static,
+        Rx::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let (customer, chan) = chan
+            .recv()
+            .await
+            .context("Failed to receive customer's protocol version")?;
+        let chan = chan
+            .send(hello)
+            .await
+            .context("Failed to send protocol version")?;
+        if let Err(incompatible) = check(&customer, &supported) {
+            chan.choose::<0>()
+                .await
+                .context("Failed to reject customer's protocol version")?
+                .send(incompatible)
+                .await
+                .context("Failed to send supported protocol versions")?
+                .close();
+            return Err(incompatible.into());
+        }
+        let chan = chan
+            .choose::<1>()
+            .await
+            .context("Failed to accept customer's protocol version")?;
+        Ok((
+            customer,
+            hello.features.intersection(customer.features),
+            chan,
+        ))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::protocol::wire_pair;
+
+        /// Run both sides of the negotiation over an in-memory connection, encoded as it would be
+        /// on the wire, returning what each side heard from the other.
+        async fn negotiate(
+            customer_hello: Hello,
+            supported: SupportedVersions,
+        ) -> (Result<Hello, anyhow::Error>, Result<Hello, anyhow::Error>) {
+            let (customer_result, merchant_result) =
+                agree(customer_hello, Features::NONE, Hello::current(), supported).await;
+            (
+                customer_result.map(|(hello, _)| hello),
+                merchant_result.map(|(hello, _)| hello),
+            )
+        }
+
+        /// Run both sides of the negotiation, the customer requiring the `required` features,
+        /// returning what each side heard from the other and the features each agreed on.
+        #[allow(clippy::type_complexity)]
+        async fn agree(
+            customer_hello: Hello,
+            required: Features,
+            merchant_hello: Hello,
+            supported: SupportedVersions,
+        ) -> (
+            Result<(Hello, Features), anyhow::Error>,
+            Result<(Hello, Features), anyhow::Error>,
+        ) {
+            let (customer_chan, merchant_chan) = wire_pair::<Negotiate>();
+
+            let (customer_result, merchant_result) = tokio::join!(
+                customer(customer_chan, customer_hello, required),
+                merchant(merchant_chan, merchant_hello, supported),
+            );
+            (
+                customer_result.map(|(hello, features, _)| (hello, features)),
+                merchant_result.map(|(hello, features, _)| (hello, features)),
+            )
+        }
+
+        #[tokio::test]
+        async fn matching_versions_proceed() {
+            let (heard_by_customer, heard_by_merchant) =
+                negotiate(Hello::current(), SupportedVersions::CURRENT).await;
+            assert_eq!(heard_by_customer.unwrap(), Hello::current());
+            assert_eq!(heard_by_merchant.unwrap(), Hello::current());
+            assert!(Hello::current()
+                .features
+                .contains(Features::REPEATED_PAYMENTS));
+        }
+
+        #[tokio::test]
+        async fn only_common_features_are_used() {
+            let older = |features| Hello {
+                version: CURRENT,
+                features,
+            };
+            let repeated = Features::REPEATED_PAYMENTS.union(Features::PAYMENT_IDS);
+
+            // Both parties use only the features they both support
+            let (heard_by_customer, heard_by_merchant) = agree(
+                older(Features::RESUME_PAYMENT.union(Features::PAYMENT_IDS)),
+                Features::PAYMENT_IDS,
+                Hello::current(),
+                SupportedVersions::CURRENT,
+            )
+            .await;
+            let (_, customer_features) = heard_by_customer.unwrap();
+            let (_, merchant_features) = heard_by_merchant.unwrap();
+            assert_eq!(customer_features, merchant_features);
+            assert!(merchant_features.require(Features::PAYMENT_IDS).is_ok());
+            assert_eq!(
+                merchant_features.require(repeated),
+                Err(MissingFeatures(Features::REPEATED_PAYMENTS))
+            );
+
+            // A customer needing a feature the merchant lacks finds out before choosing a session
+            let (heard_by_customer, _) = agree(
+                Hello::current(),
+                repeated,
+                older(Features::RESUME_PAYMENT),
+                SupportedVersions::CURRENT,
+            )
+            .await;
+            let error = heard_by_customer.unwrap_err();
+            assert_eq!(
+                error.downcast_ref::<MissingFeatures>(),
+                Some(&MissingFeatures(repeated))
+            );
+            assert!(error.to_string().contains("repeated payments, payment IDs"));
+        }
+
+        #[tokio::test]
+        async fn mismatched_versions_are_rejected() {
+            let supported = SupportedVersions {
+                oldest: ProtocolVersion::new(2, 1),
+                newest: ProtocolVersion::new(2, 3),
+            };
+
+            for version in [
+                ProtocolVersion::new(1, 7),
+                ProtocolVersion::new(2, 0),
+                ProtocolVersion::new(2, 4),
+                ProtocolVersion::new(3, 0),
+            ] {
+                let customer_hello = Hello {
+                    version,
+                    features: Features::NONE,
+                };
+                let (heard_by_customer, heard_by_merchant) =
+                    negotiate(customer_hello, supported).await;
+
+                // The customer gets the merchant's rejection, naming the versions it serves,
+                // rather than failing to read whatever the merchant sent next
+                let expected = Incompatible {
+                    customer: version,
+                    supported,
+                };
+                let error = heard_by_customer.unwrap_err();
+                assert_eq!(error.downcast_ref::<Incompatible>(), Some(&expected));
+                assert!(format!("{:#}", error).contains("2.1 to 2.3"));
+                assert_eq!(
+                    heard_by_merchant
+                        .unwrap_err()
+                        .downcast_ref::<Incompatible>(),
+                    Some(&expected)
+                );
+            }
+        }
+    }
+}
+
 pub mod parameters {
-    use crate::escrow::types::{TezosFundingAddress, TezosPublicKey};
+    use crate::{
+        amount::{Amount, XTZ},
+        escrow::{
+            tezos::ParametersSignature,
+            types::{TezosFundingAddress, TezosPublicKey},
+        },
+    };
+    use rusty_money::FormattableCurrency;
     use zkabacus_crypto::{CommitmentParameters, PublicKey, RangeConstraintParameters};
 
     use super::*;
 
-    /// Get the public parameters for the merchant.
+    /// Get the public parameters for the merchant, followed by the merchant's signature over all
+    /// of them (see [`signed_message`]).
     pub type Parameters = Session! {
         recv PublicKey;
         recv CommitmentParameters;
         recv RangeConstraintParameters;
         recv TezosFundingAddress;
         recv TezosPublicKey;
+        recv ChainParameters;
+        recv MerchantPolicy;
+        recv ParametersSignature;
     };
+
+    /// The context string signed along with the merchant's parameters, so that the signature can't
+    /// be passed off as a signature over anything else.
+    pub const SIGNATURE_CONTEXT: &str = "zkChannels merchant parameters";
+
+    /// The message the merchant signs with its Tezos key when publishing its parameters: the
+    /// serialized tuple of [`SIGNATURE_CONTEXT`] and every parameter, in the order they are sent.
+    ///
+    /// This binds the merchant's zkAbacus parameters to its Tezos identity, so none of them can be
+    /// swapped for another merchant's in transit.
+    pub fn signed_message(
+        public_key: &PublicKey,
+        commitment_parameters: &CommitmentParameters,
+        range_constraint_parameters: &RangeConstraintParameters,
+        funding_address: &TezosFundingAddress,
+        tezos_public_key: &TezosPublicKey,
+        chain_parameters: &ChainParameters,
+        policy: &MerchantPolicy,
+    ) -> Vec<u8> {
+        bincode::serialize(&(
+            SIGNATURE_CONTEXT,
+            public_key,
+            commitment_parameters,
+            range_constraint_parameters,
+            funding_address,
+            tezos_public_key,
+            chain_parameters,
+            policy,
+        ))
+        .expect("Merchant parameters must be serializable")
+    }
+
+    /// The parameters of a channel's on-chain contract, as required by the merchant.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct ChainParameters {
+        /// The length of time, in seconds, during which a close operation can be contested.
+        pub self_delay: u64,
+        /// The depth at which the merchant considers an on-chain operation final.
+        pub confirmation_depth: u64,
+    }
+
+    /// The limits and policies the merchant applies to channels and payments, advertised so that
+    /// the customer can check a request against them before opening a session for it.
+    ///
+    /// Amounts are in the minor units of the `currency`. A limit of `None` is not advertised: the
+    /// merchant may still reject a request that exceeds it, for instance if an external approver
+    /// decides.
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct MerchantPolicy {
+        /// The smallest customer deposit the merchant accepts.
+        pub min_customer_deposit: Option<u64>,
+        /// The largest customer deposit the merchant accepts.
+        pub max_customer_deposit: Option<u64>,
+        /// The most the merchant contributes to a channel.
+        pub max_merchant_contribution: Option<u64>,
+        /// The largest payment the merchant accepts.
+        pub max_payment_amount: Option<u64>,
+        /// The longest note, in bytes, the merchant accepts with a channel or payment.
+        pub max_note_length: Option<u64>,
+        /// The self-delay, in seconds, the merchant requires of every channel's contract.
+        pub self_delay: u64,
+        /// The depth at which the merchant considers an on-chain operation final.
+        pub confirmation_depth: u64,
+        /// The code of the currency in which the merchant's channels are denominated.
+        pub currency: String,
+    }
+
+    /// A request which the merchant's advertised [`MerchantPolicy`] rules out.
+    #[derive(Debug, Clone, PartialEq, Eq, Error)]
+    pub enum PolicyViolation {
+        #[error("The merchant requires a customer deposit of at least {}", xtz(*.0))]
+        DepositTooSmall(u64),
+        #[error("The merchant accepts a customer deposit of at most {}", xtz(*.0))]
+        DepositTooLarge(u64),
+        #[error("The merchant does not contribute to channels")]
+        NoMerchantContribution,
+        #[error("The merchant contributes at most {} to a channel", xtz(*.0))]
+        MerchantContribution(u64),
+        #[error("The merchant accepts payments of at most {}", xtz(*.0))]
+        PaymentAmount(u64),
+        #[error("The merchant accepts notes of at most {0} bytes")]
+        NoteLength(u64),
+        #[error("The merchant's channels are denominated in {0}, which is not supported")]
+        Currency(String),
+    }
+
+    fn xtz(amount: u64) -> Amount {
+        Amount::from_minor_units_of_currency(amount as i64, XTZ)
+    }
+
+    impl MerchantPolicy {
+        /// Check that the merchant's channels are denominated in a currency the customer supports.
+        pub fn check_currency(&self) -> Result<(), PolicyViolation> {
+            if self.currency != XTZ.code() {
+                return Err(PolicyViolation::Currency(self.currency.clone()));
+            }
+            Ok(())
+        }
+
+        /// Check the customer's deposit and the merchant contribution they ask for against the
+        /// merchant's bounds.
+        pub fn check_deposits(
+            &self,
+            customer_deposit: u64,
+            merchant_deposit: u64,
+        ) -> Result<(), PolicyViolation> {
+            match self.min_customer_deposit {
+                Some(min) if customer_deposit < min => {
+                    return Err(PolicyViolation::DepositTooSmall(min))
+                }
+                _ => {}
+            }
+            match self.max_customer_deposit {
+                Some(max) if customer_deposit > max => {
+                    return Err(PolicyViolation::DepositTooLarge(max))
+                }
+                _ => {}
+            }
+            match self.max_merchant_contribution {
+                Some(0) if merchant_deposit > 0 => Err(PolicyViolation::NoMerchantContribution),
+                Some(max) if merchant_deposit > max => {
+                    Err(PolicyViolation::MerchantContribution(max))
+                }
+                _ => Ok(()),
+            }
+        }
+
+        /// Check the amount of a payment against the merchant's cap. Refunds, with a negative
+        /// amount, are not checked.
+        pub fn check_payment(&self, amount: i64) -> Result<(), PolicyViolation> {
+            match self.max_payment_amount {
+                Some(max) if amount > 0 && amount.unsigned_abs() > max => {
+                    Err(PolicyViolation::PaymentAmount(max))
+                }
+                _ => Ok(()),
+            }
+        }
+
+        /// Check the length of a note against the merchant's cap.
+        pub fn check_note(&self, note: &str) -> Result<(), PolicyViolation> {
+            match self.max_note_length {
+                Some(max) if note.len() as u64 > max => Err(PolicyViolation::NoteLength(max)),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::escrow::{
+            tezos,
+            types::{KeySpecifier, TezosKeyMaterial},
+        };
+        use rand::{rngs::StdRng, SeedableRng};
+
+        fn policy() -> MerchantPolicy {
+            MerchantPolicy {
+                min_customer_deposit: Some(1_000_000),
+                max_customer_deposit: Some(100_000_000),
+                max_merchant_contribution: Some(5_000_000),
+                max_payment_amount: Some(10_000_000),
+                max_note_length: Some(64),
+                self_delay: 172_800,
+                confirmation_depth: 20,
+                currency: "XTZ".into(),
+            }
+        }
+
+        #[test]
+        fn deposit_outside_bounds_is_rejected() {
+            let policy = policy();
+            assert_eq!(policy.check_currency(), Ok(()));
+            assert_eq!(policy.check_deposits(1_000_000, 0), Ok(()));
+            assert_eq!(policy.check_deposits(100_000_000, 5_000_000), Ok(()));
+
+            assert_eq!(
+                policy.check_deposits(999_999, 0),
+                Err(PolicyViolation::DepositTooSmall(1_000_000))
+            );
+            assert_eq!(
+                policy.check_deposits(100_000_001, 0),
+                Err(PolicyViolation::DepositTooLarge(100_000_000))
+            );
+            assert_eq!(
+                policy.check_deposits(1_000_000, 5_000_001),
+                Err(PolicyViolation::MerchantContribution(5_000_000))
+            );
+            assert!(PolicyViolation::DepositTooSmall(1_000_000)
+                .to_string()
+                .starts_with("The merchant requires a customer deposit of at least 1"));
+
+            // A merchant which doesn't contribute says so, rather than capping it at nothing
+            let no_contribution = MerchantPolicy {
+                max_merchant_contribution: Some(0),
+                ..policy.clone()
+            };
+            assert_eq!(
+                no_contribution.check_deposits(1_000_000, 1),
+                Err(PolicyViolation::NoMerchantContribution)
+            );
+
+            // Limits which aren't advertised aren't checked
+            let unbounded = MerchantPolicy {
+                min_customer_deposit: None,
+                max_customer_deposit: None,
+                max_merchant_contribution: None,
+                ..policy
+            };
+            assert_eq!(unbounded.check_deposits(0, u64::MAX), Ok(()));
+        }
+
+        #[test]
+        fn payment_outside_limits_is_rejected() {
+            let policy = policy();
+            assert_eq!(policy.check_payment(10_000_000), Ok(()));
+            assert_eq!(
+                policy.check_payment(10_000_001),
+                Err(PolicyViolation::PaymentAmount(10_000_000))
+            );
+            // Refunds aren't capped by the payment limit
+            assert_eq!(policy.check_payment(-10_000_001), Ok(()));
+
+            assert_eq!(policy.check_note(&"a".repeat(64)), Ok(()));
+            assert_eq!(
+                policy.check_note(&"a".repeat(65)),
+                Err(PolicyViolation::NoteLength(64))
+            );
+
+            let other_currency = MerchantPolicy {
+                currency: "BTC".into(),
+                ..policy
+            };
+            assert_eq!(
+                other_currency.check_currency(),
+                Err(PolicyViolation::Currency("BTC".into()))
+            );
+        }
+
+        #[tokio::test]
+        async fn tampered_parameters_are_rejected() {
+            let key_material = TezosKeyMaterial::read_key_pair(&KeySpecifier::Alias {
+                alias: "edsk2pfUZ7NAbo7ekr5RHW6Dni2GYKS935mqXXcrbXtTn8dCfTfViZ".into(),
+            })
+            .unwrap();
+            let mut rng = StdRng::seed_from_u64(0);
+            let (public_key, commitment_parameters, range_constraint_parameters) =
+                zkabacus_crypto::merchant::Config::new(&mut rng).extract_customer_config_parts();
+            let chain_parameters = ChainParameters {
+                self_delay: 172_800,
+                confirmation_depth: 20,
+            };
+            let message = |commitment_parameters: &CommitmentParameters| {
+                signed_message(
+                    &public_key,
+                    commitment_parameters,
+                    &range_constraint_parameters,
+                    &key_material.funding_address(),
+                    key_material.public_key(),
+                    &chain_parameters,
+                    &policy(),
+                )
+            };
+
+            let signed = message(&commitment_parameters);
+            let signature = tezos::sign_parameters(&key_material, &signed)
+                .await
+                .unwrap();
+            tezos::verify_parameters_signature(key_material.public_key(), &signed, &signature)
+                .await
+                .unwrap();
+
+            // A commitment-parameters blob modified in transit no longer matches the signature
+            let blob = bincode::serialize(&commitment_parameters).unwrap();
+            let start = signed
+                .windows(blob.len())
+                .position(|window| window == &blob[..])
+                .unwrap();
+            let mut tampered = signed.clone();
+            tampered[start + blob.len() / 2] ^= 1;
+            assert!(tezos::verify_parameters_signature(
+                key_material.public_key(),
+                &tampered,
+                &signature
+            )
+            .await
+            .is_err());
+
+            // Nor do another merchant's commitment parameters spliced in next to this merchant's
+            // Tezos identity
+            let (_, other_commitment_parameters, _) =
+                zkabacus_crypto::merchant::Config::new(&mut rng).extract_customer_config_parts();
+            assert!(tezos::verify_parameters_signature(
+                key_material.public_key(),
+                &message(&other_commitment_parameters),
+                &signature
+            )
+            .await
+            .is_err());
+        }
+    }
 }
 
 pub mod establish {
     use super::*;
-    use crate::escrow::types::*;
+    use crate::{
+        amount::{Amount, XTZ},
+        database::customer::StateName,
+        escrow::{tezos, types::*},
+        protocol::parameters::ChainParameters,
+    };
     use zkabacus_crypto::{
-        ClosingSignature, CustomerBalance, EstablishProof, MerchantBalance, PayToken,
+        ChannelId, ClosingSignature, CustomerBalance, EstablishProof, MerchantBalance, PayToken,
+        PublicKey,
     };
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -198,10 +1095,16 @@ pub mod establish {
     pub enum Error {
         #[error("Received invalid parameters from merchant")]
         InvalidParameters,
+        #[error("Merchant's signature on its parameters is invalid")]
+        InvalidParametersSignature,
+        #[error("Channel funding request rejected: {0}")]
+        NoteTooLong(NoteTooLong),
         #[error("Invalid {0} deposit amount")]
         InvalidDeposit(Party),
         #[error("Channel funding request rejected: {0}")]
         Rejected(String),
+        #[error("Customer's hash of the merchant's keys does not match the merchant's keys")]
+        KeyHashMismatch,
         #[error("Invalid channel establish proof")]
         InvalidEstablishProof,
         #[error("Invalid closing signature")]
@@ -210,10 +1113,350 @@ pub mod establish {
         InvalidPayToken,
         #[error("Merchant funding not received")]
         FailedMerchantFunding,
+        #[error("Customer reclaimed their funding before the merchant funded the contract")]
+        FundingReclaimed,
         #[error("Could not verify contract's origination on chain")]
         FailedVerifyOrigination,
         #[error("Could not verify contract was funded correctly on chain")]
         FailedVerifyCustomerFunding,
+        #[error("Merchant rejected the contract: {0}")]
+        ContractRejected(ContractRejection),
+    }
+
+    impl AbortCode for Error {
+        fn code(&self) -> &'static str {
+            match self {
+                Self::InvalidParameters => "establish.invalid_parameters",
+                Self::InvalidParametersSignature => "establish.invalid_parameters_signature",
+                Self::NoteTooLong(_) => "establish.note_too_long",
+                Self::InvalidDeposit(_) => "establish.invalid_deposit",
+                Self::Rejected(_) => "establish.rejected",
+                Self::KeyHashMismatch => "establish.key_hash_mismatch",
+                Self::InvalidEstablishProof => "establish.invalid_establish_proof",
+                Self::InvalidClosingSignature => "establish.invalid_closing_signature",
+                Self::InvalidPayToken => "establish.invalid_pay_token",
+                Self::FailedMerchantFunding => "establish.failed_merchant_funding",
+                Self::FundingReclaimed => "establish.funding_reclaimed",
+                Self::FailedVerifyOrigination => "establish.failed_verify_origination",
+                Self::FailedVerifyCustomerFunding => "establish.failed_verify_customer_funding",
+                Self::ContractRejected(_) => "establish.contract_rejected",
+            }
+        }
+    }
+
+    /// Why the merchant rejected the contract the customer originated and funded, as sent to the
+    /// customer when aborting establishment.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Error, Serialize, Deserialize)]
+    pub enum ContractRejection {
+        #[error("the contract's status was not as expected")]
+        ContractStatus,
+        #[error("the contract's code was not the zkChannels contract")]
+        ContractHash,
+        #[error("the contract's self delay was not as agreed")]
+        SelfDelay,
+        #[error("the contract's delay expiry was already set")]
+        DelayExpiry,
+        #[error("the contract's merchant balance was not as agreed")]
+        MerchantBalance,
+        #[error("the contract's customer balance was not as agreed")]
+        CustomerBalance,
+        #[error("the contract's revocation lock was already set")]
+        RevocationLock,
+        #[error("the contract's merchant public key was not the merchant's")]
+        MerchantKey,
+        #[error("the customer's funding operation was not found on chain")]
+        FundingNotFound,
+        #[error("the customer's funding operation was not applied")]
+        FundingNotApplied,
+        #[error("the customer's funding came from an unexpected address")]
+        FundingSource,
+        #[error("the merchant could not read the contract")]
+        Unreadable,
+    }
+
+    impl From<&tezos::VerificationError> for ContractRejection {
+        fn from(error: &tezos::VerificationError) -> Self {
+            use tezos::VerificationError::*;
+            match error {
+                UnexpectedContractStatus { .. } => Self::ContractStatus,
+                UnexpectedContractHash => Self::ContractHash,
+                UnexpectedSelfDelay { .. } => Self::SelfDelay,
+                UnexpectedDelayExpiry { .. } => Self::DelayExpiry,
+                UnexpectedMerchantBalance { .. } => Self::MerchantBalance,
+                UnexpectedCustomerBalance { .. } => Self::CustomerBalance,
+                UnexpectedRevocationLock { .. } => Self::RevocationLock,
+                UnexpectedMerchantKey => Self::MerchantKey,
+                FundingOperationNotFound => Self::FundingNotFound,
+                FundingOperationNotApplied { .. } => Self::FundingNotApplied,
+                UnexpectedFundingSource { .. } => Self::FundingSource,
+                ContractState(_) | ZkAbacus(_) | FindFunding(_) => Self::Unreadable,
+            }
+        }
+    }
+
+    /// The merchant's keys didn't match the hash the customer pinned for them.
+    #[derive(Debug, Clone, Copy, Error, PartialEq, Eq)]
+    #[error(
+        "Merchant's keys do not match the pinned key hash: expected {expected}, but received \
+        keys with hash {received}. Refusing to establish a channel"
+    )]
+    pub struct PinnedKeyHashMismatch {
+        pub expected: KeyHash,
+        pub received: KeyHash,
+    }
+
+    /// The merchant's required self-delay is outside the range the customer accepts.
+    #[derive(Debug, Clone, Copy, Error, PartialEq, Eq)]
+    pub enum SelfDelayMismatch {
+        #[error(
+            "Merchant requires a self-delay of {required} seconds, but the longest accepted is \
+            {max_accepted} seconds (see `max_accepted_self_delay`)"
+        )]
+        TooLong { required: u64, max_accepted: u64 },
+        #[error(
+            "Merchant requires a self-delay of {required} seconds, but the shortest accepted is \
+            {min_accepted} seconds (see `self_delay`)"
+        )]
+        TooShort { required: u64, min_accepted: u64 },
+    }
+
+    /// Agree on the parameters of a channel's contract, given those the merchant requires.
+    ///
+    /// The merchant's self-delay is accepted if it lies between `min_self_delay` and
+    /// `max_self_delay`, inclusive: too short a delay leaves no time to contest a close, and too
+    /// long a delay locks up the customer's funds. The agreed confirmation depth is the greater of
+    /// the two parties' depths, so that neither party acts on an operation the other doesn't yet
+    /// consider final.
+    pub fn negotiate_chain_parameters(
+        required: ChainParameters,
+        min_self_delay: u64,
+        max_self_delay: u64,
+        confirmation_depth: u64,
+    ) -> Result<ChainParameters, SelfDelayMismatch> {
+        if required.self_delay > max_self_delay {
+            return Err(SelfDelayMismatch::TooLong {
+                required: required.self_delay,
+                max_accepted: max_self_delay,
+            });
+        }
+        if required.self_delay < min_self_delay {
+            return Err(SelfDelayMismatch::TooShort {
+                required: required.self_delay,
+                min_accepted: min_self_delay,
+            });
+        }
+        Ok(ChainParameters {
+            self_delay: required.self_delay,
+            confirmation_depth: required.confirmation_depth.max(confirmation_depth),
+        })
+    }
+
+    /// The merchant offered to contribute more to a channel than the customer asked it to.
+    #[derive(Debug, Clone, Copy, Error, PartialEq, Eq)]
+    #[error(
+        "Merchant offered to deposit {}, more than the {} requested",
+        Amount::from_minor_units_of_currency(*offered as i64, XTZ),
+        Amount::from_minor_units_of_currency(*requested as i64, XTZ)
+    )]
+    pub struct ExcessMerchantContribution {
+        /// The merchant deposit the customer asked for, in mutez.
+        pub requested: u64,
+        /// The merchant deposit the merchant offered, in mutez.
+        pub offered: u64,
+    }
+
+    /// Check the merchant's contribution to a channel once it approves the channel, which may be
+    /// less than the customer asked for, but never more.
+    pub fn check_merchant_contribution(
+        requested: MerchantBalance,
+        offered: MerchantBalance,
+    ) -> Result<MerchantBalance, ExcessMerchantContribution> {
+        if offered.into_inner() > requested.into_inner() {
+            Err(ExcessMerchantContribution {
+                requested: requested.into_inner(),
+                offered: offered.into_inner(),
+            })
+        } else {
+            Ok(offered)
+        }
+    }
+
+    /// Whether the hash of the merchant's keys needs to be pinned after checking it.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum KeyHashPin {
+        /// The keys match the existing pin.
+        Trusted,
+        /// The keys should be pinned: either this is the first contact with the merchant, or the
+        /// customer gave the hash explicitly.
+        Pin,
+    }
+
+    /// Check the hash of the keys received from the merchant against the hash the customer gave
+    /// explicitly, if any, or else the hash pinned on first contact with the merchant, if any.
+    pub fn check_merchant_key_hash(
+        received: KeyHash,
+        given: Option<KeyHash>,
+        pinned: Option<KeyHash>,
+    ) -> Result<KeyHashPin, PinnedKeyHashMismatch> {
+        match given.or(pinned) {
+            Some(expected) if expected != received => {
+                Err(PinnedKeyHashMismatch { expected, received })
+            }
+            Some(_) if given.is_none() || given == pinned => Ok(KeyHashPin::Trusted),
+            _ => Ok(KeyHashPin::Pin),
+        }
+    }
+
+    /// Whether the customer should check, with [`tezos::verify_funding_balance`], that the
+    /// merchant's funding address covers their deposit and estimated fees before originating the
+    /// contract.
+    ///
+    /// There's nothing to check for a channel established off chain or one the merchant deposits
+    /// nothing into, and the customer may choose to skip the check, since it doesn't account for
+    /// pending operations on the merchant's account and so may refuse a merchant who would in fact
+    /// be able to fund the channel.
+    pub fn should_check_merchant_balance(
+        off_chain: bool,
+        skip_check: bool,
+        merchant_deposit: MerchantBalance,
+    ) -> bool {
+        !off_chain && !skip_check && merchant_deposit.into_inner() > 0
+    }
+
+    /// Check, immediately before the merchant funds a contract, that the customer hasn't reclaimed
+    /// their funding from it since it was verified, given the contract's status at the head block.
+    pub fn check_not_reclaimed(contract_status: ContractStatus) -> Result<(), Error> {
+        match contract_status {
+            ContractStatus::FundingReclaimed => Err(Error::FundingReclaimed),
+            _ => Ok(()),
+        }
+    }
+
+    /// What the merchant has to do for a channel whose establishment stalled after the customer
+    /// funded it.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum StalledEstablishAction {
+        /// The merchant funded the contract: initiate expiry to reclaim the merchant's funds.
+        Expire,
+        /// The merchant funded the contract, but the channel wasn't updated to match: record the
+        /// funding, then initiate expiry.
+        RecordFundingAndExpire,
+    }
+
+    /// Decide what the merchant has to do for a channel whose establishment stalled, from the
+    /// status of its contract and the channel's stored status.
+    ///
+    /// Until the contract is open, the merchant has no funds in it to reclaim. Returns `None` if
+    /// there's nothing to do.
+    pub fn stalled_establish_action(
+        contract_status: ContractStatus,
+        channel_status: ChannelStatus,
+    ) -> Option<StalledEstablishAction> {
+        match (contract_status, channel_status) {
+            (ContractStatus::Open, ChannelStatus::CustomerFunded) => {
+                Some(StalledEstablishAction::RecordFundingAndExpire)
+            }
+            (ContractStatus::Open, ChannelStatus::MerchantFunded) => {
+                Some(StalledEstablishAction::Expire)
+            }
+            _ => None,
+        }
+    }
+
+    /// Form the ID of a channel from both parties' random contributions, the merchant's zkAbacus
+    /// public key, and both parties' Tezos public keys, binding the channel to their on-chain
+    /// identities.
+    ///
+    /// The customer and the merchant both call this, so that they hash byte-identical input.
+    pub fn channel_id(
+        merchant_randomness: MerchantRandomness,
+        customer_randomness: CustomerRandomness,
+        merchant_public_key: &PublicKey,
+        merchant_tezos_public_key: &TezosPublicKey,
+        customer_tezos_public_key: &TezosPublicKey,
+    ) -> ChannelId {
+        ChannelId::new(
+            merchant_randomness,
+            customer_randomness,
+            merchant_public_key,
+            tezos_public_key_bytes(merchant_tezos_public_key),
+            tezos_public_key_bytes(customer_tezos_public_key),
+        )
+    }
+
+    /// The next on-chain step the customer has to take to establish a channel.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ChainStep {
+        /// Originate the contract.
+        Originate,
+        /// Add the customer's deposit to the originated contract.
+        FundContract,
+        /// Wait for the merchant to add their deposit.
+        AwaitMerchantFunding,
+    }
+
+    /// Determine which on-chain step of establishment a channel is up to from its stored state,
+    /// so that an interrupted establishment picks up where it left off.
+    ///
+    /// The contract ID is stored before a channel moves to `Originated`, so the
+    /// [`ChainStep::Originate`] step must check for a stored contract before originating one.
+    ///
+    /// Returns `None` if the channel is already past the on-chain steps of establishment.
+    pub fn next_chain_step(state: StateName) -> Option<ChainStep> {
+        match state {
+            StateName::Inactive => Some(ChainStep::Originate),
+            StateName::Originated => Some(ChainStep::FundContract),
+            StateName::CustomerFunded => Some(ChainStep::AwaitMerchantFunding),
+            _ => None,
+        }
+    }
+
+    /// What it takes to reclaim the customer's deposit from the contract of a channel whose
+    /// establishment was abandoned.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Reclaim {
+        /// Call the contract's `reclaimFunding` entrypoint to return the customer's deposit.
+        CallReclaimFunding,
+        /// The contract doesn't hold the customer's deposit, so there's nothing to call.
+        NothingToReclaim,
+    }
+
+    /// The customer's deposit can't be reclaimed from the contract of a channel.
+    #[derive(Debug, Clone, Copy, Error, PartialEq, Eq)]
+    pub enum CannotReclaim {
+        #[error(
+            "Channel is {0}, but only a channel that is partway through establishment can have \
+            its funding reclaimed"
+        )]
+        State(StateName),
+        #[error("The merchant has funded the contract, so the channel must be closed instead")]
+        Open,
+        #[error("Can't reclaim funding from a contract with status {0:?}")]
+        ContractStatus(ContractStatus),
+    }
+
+    /// Determine how to reclaim the customer's deposit from a channel's contract, given the stored
+    /// state of the channel and the status of its contract on chain.
+    ///
+    /// Only a channel with an originated contract that the merchant hasn't yet funded can be
+    /// reclaimed from; an open contract never is.
+    pub fn reclaim_step(
+        state: StateName,
+        status: ContractStatus,
+    ) -> Result<Reclaim, CannotReclaim> {
+        if !matches!(state, StateName::Originated | StateName::CustomerFunded) {
+            return Err(CannotReclaim::State(state));
+        }
+        match status {
+            ContractStatus::AwaitingMerchantFunding => Ok(Reclaim::CallReclaimFunding),
+            // Either the customer never funded the contract, or their funding was already
+            // reclaimed but the channel wasn't updated to match
+            ContractStatus::AwaitingCustomerFunding | ContractStatus::FundingReclaimed => {
+                Ok(Reclaim::NothingToReclaim)
+            }
+            ContractStatus::Open => Err(CannotReclaim::Open),
+            status => Err(CannotReclaim::ContractStatus(status)),
+        }
     }
 
     pub type Establish = CustomerSupplyInfo;
@@ -242,10 +1485,12 @@ pub mod establish {
 
     pub type MerchantApproveEstablish = Session! {
         // Merchant decides if they want to open the channel as described
-        OfferAbort<MerchantSupplyInfo, Error>;
+        OfferAbort<MerchantSupplyInfo>;
     };
 
     pub type MerchantSupplyInfo = Session! {
+        // The merchant's contribution to the channel, which may be less than the customer asked
+        recv MerchantBalance;
         recv MerchantRandomness;
         Initialize;
     };
@@ -255,63 +1500,775 @@ pub mod establish {
     pub type CustomerSupplyProof = Session! {
         send EstablishProof;
         // Merchant verifies the proof
-        OfferAbort<MerchantSupplyClosingSignature, Error>;
+        OfferAbort<MerchantSupplyClosingSignature>;
     };
 
     pub type MerchantSupplyClosingSignature = Session! {
         recv ClosingSignature;
         // Customer verifies the signature
-        ChooseAbort<CustomerSupplyContractInfo, Error>;
+        ChooseAbort<CustomerSupplyContractInfo>;
     };
 
     pub type CustomerSupplyContractInfo = Session! {
         send ContractId;
         // Merchant ensures the contract was correctly originated
-        OfferAbort<MerchantVerifyCustomerFunding, Error>;
+        OfferAbort<MerchantVerifyCustomerFunding>;
     };
 
     pub type MerchantVerifyCustomerFunding = Session! {
         // Notify the merchant that the customer has funded the contract.
         send ContractFunded;
         // Merchant ensures the contract was correctly funded
-        OfferAbort<CustomerVerifyMerchantFunding, Error>;
+        OfferAbort<CustomerVerifyMerchantFunding>;
     };
 
     pub type CustomerVerifyMerchantFunding = Session! {
         // Notify the customer that the merchant has funded the contract.
         recv ContractFunded;
         // Customer ensures the merchant funded the contract
-        ChooseAbort<Activate, Error>;
+        ChooseAbort<Activate>;
     };
 
     pub type Activate = Session! {
         recv PayToken;
     };
-}
-pub mod close {
-    use {
-        dialectic::types::Done,
-        zkabacus_crypto::{CloseState, CloseStateSignature},
-    };
 
-    use crate::{database::customer::StateName, escrow::tezos::MutualCloseAuthorizationSignature};
+    #[cfg(test)]
+    mod tests {
+        use super::*;
 
-    use super::*;
+        #[test]
+        fn merchant_balance_check_is_skipped() {
+            let deposit = MerchantBalance::try_new(10_000_000).unwrap();
+            let nothing = MerchantBalance::try_new(0).unwrap();
 
-    #[derive(Debug, Clone, Serialize, Deserialize, Error)]
-    pub enum Error {
-        #[error("Customer tried to close on an uncloseable state \"{0}\"")]
-        UncloseableState(StateName),
-        #[error("Customer sent an invalid signature")]
-        InvalidCloseStateSignature,
-        #[error("Customer sent a close state that has already been seen")]
-        KnownRevocationLock,
-        #[error("Merchant send an invalid authorization signature")]
-        InvalidMerchantAuthorizationSignature,
+            assert!(should_check_merchant_balance(false, false, deposit));
+
+            // Off chain, on request, or when the merchant has nothing to fund
+            assert!(!should_check_merchant_balance(true, false, deposit));
+            assert!(!should_check_merchant_balance(false, true, deposit));
+            assert!(!should_check_merchant_balance(false, false, nothing));
+        }
+
+        #[test]
+        fn merchant_contribution_never_exceeds_request() {
+            let balance = |amount| MerchantBalance::try_new(amount).unwrap();
+
+            // The merchant may contribute what was asked, or less
+            assert_eq!(
+                check_merchant_contribution(balance(10), balance(10)).map(|b| b.into_inner()),
+                Ok(10)
+            );
+            assert_eq!(
+                check_merchant_contribution(balance(10), balance(4)).map(|b| b.into_inner()),
+                Ok(4)
+            );
+            assert_eq!(
+                check_merchant_contribution(balance(10), balance(0)).map(|b| b.into_inner()),
+                Ok(0)
+            );
+
+            // ...but not more
+            assert_eq!(
+                check_merchant_contribution(balance(10), balance(11)).map(|b| b.into_inner()),
+                Err(ExcessMerchantContribution {
+                    requested: 10,
+                    offered: 11
+                })
+            );
+        }
+
+        #[test]
+        fn both_parties_derive_same_channel_id() {
+            use rand::{rngs::StdRng, SeedableRng};
+            use zkabacus_crypto::KeyPair;
+
+            let merchant_tezos_key = TezosPublicKey::from_base58check(
+                "edpku5Ei6Dni4qwoJGqXJs13xHfyu4fhUg6zqZkFyiEh1mQhFD3iZE",
+            )
+            .unwrap();
+            let customer_tezos_key = TezosPublicKey::from_base58check(
+                "edpkuBknW28nW72KG6RoHtYW7p12T6GKc7nAbwYX5m8Wd9sDVC9yav",
+            )
+            .unwrap();
+
+            let mut rng = StdRng::seed_from_u64(0);
+            let merchant_public_key = KeyPair::new(&mut rng).public_key().clone();
+            let merchant_randomness = MerchantRandomness::new(&mut rng);
+            let customer_randomness = CustomerRandomness::new(&mut rng);
+
+            let id = |merchant_tezos_key: &TezosPublicKey, customer_tezos_key: &TezosPublicKey| {
+                channel_id(
+                    merchant_randomness,
+                    customer_randomness,
+                    &merchant_public_key,
+                    merchant_tezos_key,
+                    customer_tezos_key,
+                )
+                .to_bytes()
+            };
+
+            // The merchant's view, with its own key material and the customer's contributions,
+            // matches the customer's view
+            let merchant_view = id(&merchant_tezos_key, &customer_tezos_key);
+            let customer_view = id(&merchant_tezos_key.clone(), &customer_tezos_key.clone());
+            assert_eq!(merchant_view, customer_view);
+
+            // Binding different Tezos keys gives a different channel
+            assert_ne!(merchant_view, id(&customer_tezos_key, &merchant_tezos_key));
+            assert_ne!(merchant_view, id(&merchant_tezos_key, &merchant_tezos_key));
+        }
+
+        #[test]
+        fn transcript_binds_note() {
+            let transcript = |note: &str| {
+                let mut transcript = Transcript::new(&[7; 32]);
+                transcript.append(&CustomerBalance::try_new(5).unwrap());
+                transcript.append(&MerchantBalance::try_new(0).unwrap());
+                transcript.append(note);
+                transcript.digest()
+            };
+
+            // Both parties appending the same messages agree
+            assert_eq!(transcript("channel note"), transcript("channel note"));
+
+            // A single differing byte in the note changes the context
+            assert_ne!(transcript("channel note"), transcript("channel notf"));
+
+            // Moving a byte across a message boundary changes the context
+            let mut split = Transcript::new(&[7; 32]);
+            split.append("ab");
+            split.append("c");
+            let mut moved = Transcript::new(&[7; 32]);
+            moved.append("a");
+            moved.append("bc");
+            assert_ne!(split.digest(), moved.digest());
+        }
+
+        #[test]
+        fn pinned_key_hash() {
+            let hash = |byte: &str| byte.repeat(32).parse::<KeyHash>().unwrap();
+            let (ours, theirs) = (hash("11"), hash("22"));
+
+            // Trust on first use
+            assert_eq!(
+                check_merchant_key_hash(ours, None, None),
+                Ok(KeyHashPin::Pin)
+            );
+
+            // Matching the pin
+            assert_eq!(
+                check_merchant_key_hash(ours, None, Some(ours)),
+                Ok(KeyHashPin::Trusted)
+            );
+            assert_eq!(
+                check_merchant_key_hash(ours, Some(ours), Some(ours)),
+                Ok(KeyHashPin::Trusted)
+            );
+
+            // Mismatching the pin
+            assert_eq!(
+                check_merchant_key_hash(theirs, None, Some(ours)),
+                Err(PinnedKeyHashMismatch {
+                    expected: ours,
+                    received: theirs
+                })
+            );
+
+            // A hash given explicitly takes precedence over the pin, and replaces it
+            assert_eq!(
+                check_merchant_key_hash(theirs, Some(theirs), Some(ours)),
+                Ok(KeyHashPin::Pin)
+            );
+            assert_eq!(
+                check_merchant_key_hash(ours, Some(theirs), Some(ours)),
+                Err(PinnedKeyHashMismatch {
+                    expected: theirs,
+                    received: ours
+                })
+            );
+            assert_eq!(
+                check_merchant_key_hash(ours, Some(ours), None),
+                Ok(KeyHashPin::Pin)
+            );
+        }
+
+        #[test]
+        fn negotiate_self_delay() {
+            let required = |self_delay| ChainParameters {
+                self_delay,
+                confirmation_depth: 20,
+            };
+
+            // Within the accepted range, including its ends
+            for self_delay in &[100, 150, 200] {
+                assert_eq!(
+                    negotiate_chain_parameters(required(*self_delay), 100, 200, 1),
+                    Ok(required(*self_delay))
+                );
+            }
+
+            // Outside it, with both values reported
+            assert_eq!(
+                negotiate_chain_parameters(required(201), 100, 200, 1),
+                Err(SelfDelayMismatch::TooLong {
+                    required: 201,
+                    max_accepted: 200
+                })
+            );
+            assert_eq!(
+                negotiate_chain_parameters(required(99), 100, 200, 1),
+                Err(SelfDelayMismatch::TooShort {
+                    required: 99,
+                    min_accepted: 100
+                })
+            );
+
+            // The deeper confirmation depth wins
+            assert_eq!(
+                negotiate_chain_parameters(required(150), 100, 200, 30)
+                    .map(|p| p.confirmation_depth),
+                Ok(30)
+            );
+        }
+
+        #[test]
+        fn contract_rejection_reasons() {
+            let rejection =
+                ContractRejection::from(&tezos::VerificationError::UnexpectedMerchantBalance {
+                    expected: MerchantBalance::try_new(5).unwrap(),
+                    actual: MerchantBalance::try_new(0).unwrap(),
+                });
+            assert_eq!(rejection, ContractRejection::MerchantBalance);
+
+            // The reason survives being sent to the customer
+            let error = Error::ContractRejected(rejection);
+            let sent: Error = bincode::deserialize(&bincode::serialize(&error).unwrap()).unwrap();
+            assert!(matches!(
+                sent,
+                Error::ContractRejected(ContractRejection::MerchantBalance)
+            ));
+            assert_eq!(
+                sent.to_string(),
+                "Merchant rejected the contract: the contract's merchant balance was not as agreed"
+            );
+
+            assert_eq!(
+                ContractRejection::from(&tezos::VerificationError::UnexpectedContractHash),
+                ContractRejection::ContractHash
+            );
+        }
+
+        #[test]
+        fn resume_chain_steps() {
+            assert_eq!(
+                next_chain_step(StateName::Inactive),
+                Some(ChainStep::Originate)
+            );
+            assert_eq!(
+                next_chain_step(StateName::Originated),
+                Some(ChainStep::FundContract)
+            );
+            assert_eq!(
+                next_chain_step(StateName::CustomerFunded),
+                Some(ChainStep::AwaitMerchantFunding)
+            );
+
+            // Once funded, there's nothing left to do on chain
+            for state in &[
+                StateName::MerchantFunded,
+                StateName::Ready,
+                StateName::PendingClose,
+                StateName::Closed,
+            ] {
+                assert_eq!(next_chain_step(*state), None);
+            }
+        }
+
+        #[test]
+        fn reclaim_abandoned_funding() {
+            for state in &[StateName::Originated, StateName::CustomerFunded] {
+                assert_eq!(
+                    reclaim_step(*state, ContractStatus::AwaitingMerchantFunding),
+                    Ok(Reclaim::CallReclaimFunding)
+                );
+                assert_eq!(
+                    reclaim_step(*state, ContractStatus::AwaitingCustomerFunding),
+                    Ok(Reclaim::NothingToReclaim)
+                );
+                assert_eq!(
+                    reclaim_step(*state, ContractStatus::FundingReclaimed),
+                    Ok(Reclaim::NothingToReclaim)
+                );
+
+                // An open contract is never reclaimed from
+                assert_eq!(
+                    reclaim_step(*state, ContractStatus::Open),
+                    Err(CannotReclaim::Open)
+                );
+                assert_eq!(
+                    reclaim_step(*state, ContractStatus::Expiry),
+                    Err(CannotReclaim::ContractStatus(ContractStatus::Expiry))
+                );
+            }
+
+            // Nor is a channel that has moved past establishment, whatever its contract says
+            for state in &[
+                StateName::Inactive,
+                StateName::MerchantFunded,
+                StateName::Ready,
+                StateName::FundingReclaimed,
+            ] {
+                assert_eq!(
+                    reclaim_step(*state, ContractStatus::AwaitingMerchantFunding),
+                    Err(CannotReclaim::State(*state))
+                );
+            }
+        }
+    }
+}
+pub mod close {
+    use {
+        dialectic::types::Done,
+        std::time::SystemTime,
+        zkabacus_crypto::{CloseState, CloseStateSignature, CustomerBalance, MerchantBalance},
+    };
+
+    use crate::{
+        database::customer::StateName,
+        escrow::{
+            tezos::MutualCloseAuthorizationSignature,
+            types::{ContractStatus, Entrypoint},
+        },
+    };
+
+    use super::*;
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Error)]
+    pub enum Error {
+        #[error("Customer tried to close on an uncloseable state \"{0}\"")]
+        UncloseableState(StateName),
+        #[error("Customer sent an invalid signature")]
+        InvalidCloseStateSignature,
+        #[error("Customer sent a close state that has already been seen")]
+        KnownRevocationLock,
+        #[error("Merchant send an invalid authorization signature")]
+        InvalidMerchantAuthorizationSignature,
         #[error("Arbiter failed to accept mutual close")]
         ArbiterRejectedMutualClose,
     }
 
+    impl AbortCode for Error {
+        fn code(&self) -> &'static str {
+            match self {
+                Self::UncloseableState(_) => "close.uncloseable_state",
+                Self::InvalidCloseStateSignature => "close.invalid_close_state_signature",
+                Self::KnownRevocationLock => "close.known_revocation_lock",
+                Self::InvalidMerchantAuthorizationSignature => {
+                    "close.invalid_merchant_authorization_signature"
+                }
+                Self::ArbiterRejectedMutualClose => "close.arbiter_rejected_mutual_close",
+            }
+        }
+    }
+
+    /// Which party started a unilateral close.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum UnilateralCloseKind {
+        /// The merchant posted an expiry operation, to which the customer is responding.
+        MerchantInitiated,
+        /// The customer is closing of their own accord.
+        CustomerInitiated,
+    }
+
+    /// What the customer does on chain to close a channel unilaterally.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum UnilateralClosePlan {
+        /// Post custClose with the current balances, which pays the merchant balance out
+        /// immediately, then claim the customer balance, if any, once the delay has passed.
+        PostCustomerClose,
+        /// Post nothing, and wait for the merchant to claim the whole channel balance after their
+        /// expiry.
+        AwaitMerchantClaim,
+    }
+
+    /// Decide how the customer should close a channel unilaterally.
+    ///
+    /// The customer only has something to gain from posting custClose if they have a balance to
+    /// claim, or if the merchant hasn't started closing: otherwise the merchant's expiry already
+    /// pays the merchant everything they're owed, and posting custClose would only cost the
+    /// customer fees. When the customer starts the close, they post custClose even with a zero
+    /// balance, because nothing else would pay the merchant out and close the contract.
+    pub fn unilateral_close_plan(
+        kind: UnilateralCloseKind,
+        customer_balance: CustomerBalance,
+    ) -> UnilateralClosePlan {
+        match kind {
+            UnilateralCloseKind::MerchantInitiated if customer_balance.into_inner() == 0 => {
+                UnilateralClosePlan::AwaitMerchantClaim
+            }
+            _ => UnilateralClosePlan::PostCustomerClose,
+        }
+    }
+
+    /// The reason a channel can't be closed unilaterally again.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+    pub enum AlreadyClosingError {
+        #[error(
+            "The closing data for this channel was already written; broadcast its custClose \
+            operation and then run `confirm-close`"
+        )]
+        AwaitingBroadcast,
+        #[error(
+            "This channel is already closing (it is {0}); run `list` to follow its progress, and \
+            `watch` to finish closing it"
+        )]
+        Closing(StateName),
+    }
+
+    /// Check that a unilateral close hasn't already produced a custClose operation for a channel,
+    /// so that running the close twice doesn't post (or write out) the operation twice.
+    pub fn check_not_closing(
+        state: StateName,
+        awaiting_broadcast: bool,
+    ) -> Result<(), AlreadyClosingError> {
+        match state {
+            StateName::PendingClose if awaiting_broadcast => {
+                Err(AlreadyClosingError::AwaitingBroadcast)
+            }
+            StateName::PendingClose
+            | StateName::PendingExpiry
+            | StateName::PendingCustomerClaim
+            | StateName::Dispute
+            | StateName::Closed => Err(AlreadyClosingError::Closing(state)),
+            _ => Ok(()),
+        }
+    }
+
+    /// A receipt for a custClose operation which the customer broadcast themselves, after closing
+    /// off chain.
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct CloseReceipt {
+        /// The hash of the custClose operation.
+        pub operation_hash: String,
+        /// The level of the block in which the operation was included.
+        pub level: u32,
+    }
+
+    /// The reason a custClose operation broadcast off chain can't be confirmed.
+    #[derive(Debug, Clone, PartialEq, Error)]
+    pub enum ConfirmCloseError {
+        #[error("Contract has status {0:?}, not CustomerClose")]
+        UnexpectedStatus(ContractStatus),
+        #[error(
+            "Balances on chain (customer {posted_customer}, merchant {posted_merchant}) don't match \
+            the closing message (customer {expected_customer}, merchant {expected_merchant})"
+        )]
+        BalanceMismatch {
+            posted_customer: u64,
+            posted_merchant: u64,
+            expected_customer: u64,
+            expected_merchant: u64,
+        },
+    }
+
+    /// Check that a contract reflects the custClose operation for a closing message: that it is in
+    /// the CustomerClose status, with the balances of the closing message.
+    pub fn check_posted_close(
+        status: ContractStatus,
+        posted: (CustomerBalance, MerchantBalance),
+        expected: (CustomerBalance, MerchantBalance),
+    ) -> Result<(), ConfirmCloseError> {
+        if status != ContractStatus::CustomerClose {
+            return Err(ConfirmCloseError::UnexpectedStatus(status));
+        }
+
+        let (posted_customer, posted_merchant) = (posted.0.into_inner(), posted.1.into_inner());
+        let (expected_customer, expected_merchant) =
+            (expected.0.into_inner(), expected.1.into_inner());
+        if (posted_customer, posted_merchant) != (expected_customer, expected_merchant) {
+            return Err(ConfirmCloseError::BalanceMismatch {
+                posted_customer,
+                posted_merchant,
+                expected_customer,
+                expected_merchant,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// How a contract on which custClose was posted came to be closed.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum CustomerCloseEnding {
+        /// The merchant posted merchDispute with the revocation secret for the posted balances,
+        /// and was paid the whole channel balance.
+        Disputed,
+        /// The customer claimed their balance with custClaim.
+        Claimed,
+    }
+
+    /// Decide how a closed contract, on which custClose was posted, came to be closed, from the
+    /// local state of the channel and the entrypoint whose call closed the contract, if known.
+    ///
+    /// Without the entrypoint, a channel still in PendingClose is taken to have been disputed,
+    /// since the customer moves to PendingCustomerClaim before posting custClaim. Returns `None`
+    /// if the channel isn't waiting on the end of a customer close.
+    pub fn customer_close_ending(
+        state: StateName,
+        closed_by: Option<Entrypoint>,
+    ) -> Option<CustomerCloseEnding> {
+        match (state, closed_by) {
+            // A dispute that was processed but not finalized
+            (StateName::Dispute, _) => Some(CustomerCloseEnding::Disputed),
+            (StateName::PendingClose, Some(Entrypoint::MerchantDispute))
+            | (StateName::PendingClose, None) => Some(CustomerCloseEnding::Disputed),
+            // A claim which appeared to fail, but was applied anyway
+            (StateName::PendingClose, Some(Entrypoint::CustomerClaim))
+            | (StateName::PendingCustomerClaim, Some(Entrypoint::CustomerClaim))
+            | (StateName::PendingCustomerClaim, None) => Some(CustomerCloseEnding::Claimed),
+            _ => None,
+        }
+    }
+
+    /// How a contract came to be closed, when it wasn't by a customer close.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ClosedContractEnding {
+        /// The merchant claimed the whole channel balance with merchClaim, after the customer
+        /// didn't answer an expiry with custClose.
+        MerchantClaimed,
+        /// The mutualClose operation posted by the customer was applied.
+        MutuallyClosed,
+    }
+
+    /// Decide how a closed contract came to be closed, from the local state of the channel, the
+    /// entrypoint whose call closed the contract, if known, and the status the contract was seen
+    /// with before it closed, if known.
+    ///
+    /// Without the entrypoint, a channel in PendingMutualClose is only taken to have been closed
+    /// mutually if its contract was last seen open, since only mutualClose closes an open contract.
+    /// An open channel whose contract closed can only have missed the merchant's expiry and the
+    /// claim after it. Returns `None` if the contract was closed some other way, such as by a
+    /// customer close (see [`customer_close_ending`]).
+    pub fn closed_contract_ending(
+        state: StateName,
+        closed_by: Option<Entrypoint>,
+        previous_status: Option<ContractStatus>,
+    ) -> Option<ClosedContractEnding> {
+        match (state, closed_by, previous_status) {
+            // The customer had nothing to claim, so left the expiry for the merchant to claim
+            (StateName::PendingExpiry, _, _) => Some(ClosedContractEnding::MerchantClaimed),
+            // The customer's custClose never landed, and the merchant claimed everything instead
+            (StateName::PendingClose, Some(Entrypoint::MerchantClaim), _) => {
+                Some(ClosedContractEnding::MerchantClaimed)
+            }
+            // The whole expiry went by unanswered, such as while the customer's daemon was stopped
+            (state, None | Some(Entrypoint::MerchantClaim), _) if state.is_open() => {
+                Some(ClosedContractEnding::MerchantClaimed)
+            }
+            (StateName::PendingMutualClose, Some(Entrypoint::MerchantClaim), _) => {
+                Some(ClosedContractEnding::MerchantClaimed)
+            }
+            (StateName::PendingMutualClose, Some(Entrypoint::MutualClose), _)
+            | (StateName::PendingMutualClose, None, Some(ContractStatus::Open)) => {
+                Some(ClosedContractEnding::MutuallyClosed)
+            }
+            _ => None,
+        }
+    }
+
+    /// What the merchant's chain watcher has to do for a channel, given the status of its contract.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum MerchantChainAction {
+        /// The customer posted custClose: record the posted revocation lock, and dispute the close
+        /// if its secret is known.
+        ProcessCustomerClose,
+        /// An expiry was posted on chain, but the channel wasn't updated to match.
+        RecordExpiry,
+        /// The customer didn't respond to an expiry in time: claim the whole balance with
+        /// merchClaim.
+        ClaimExpiry,
+        /// A merchClaim closed the contract, but the channel wasn't updated to match.
+        FinalizeExpiry,
+        /// A merchDispute closed the contract, but the channel wasn't updated to match.
+        FinalizeDispute,
+        /// The customer's close was processed, but the contract was closed before the channel was
+        /// updated to match.
+        FinalizeCustomerClose,
+    }
+
+    impl Display for MerchantChainAction {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            f.write_str(match self {
+                Self::ProcessCustomerClose => {
+                    "process the customer's close, disputing it if it used a revoked balance"
+                }
+                Self::RecordExpiry => "record the expiry posted on chain",
+                Self::ClaimExpiry => {
+                    "claim the balance after the customer didn't answer the expiry"
+                }
+                Self::FinalizeExpiry => "finalize the merchant's claim",
+                Self::FinalizeDispute => "finalize the merchant's dispute",
+                Self::FinalizeCustomerClose => "finalize the customer's close",
+            })
+        }
+    }
+
+    /// Decide what the merchant has to do for a channel, from the status of its contract, whether
+    /// the contract's timeout has expired (if one is set), and the channel's stored status.
+    ///
+    /// A channel already in PendingClose on a contract in CustomerClose is processed again, in
+    /// case the merchant stopped before checking the posted revocation lock. Likewise, an expiry
+    /// that reached the chain without the channel's status being updated is recorded, so that it
+    /// can be claimed. Returns `None` if there's nothing to do.
+    pub fn merchant_chain_action(
+        contract_status: ContractStatus,
+        timeout_expired: Option<bool>,
+        channel_status: ChannelStatus,
+    ) -> Option<MerchantChainAction> {
+        match (contract_status, channel_status) {
+            (
+                ContractStatus::CustomerClose,
+                ChannelStatus::Active | ChannelStatus::PendingExpiry | ChannelStatus::PendingClose,
+            ) => Some(MerchantChainAction::ProcessCustomerClose),
+            (ContractStatus::Expiry, ChannelStatus::MerchantFunded | ChannelStatus::Active) => {
+                Some(MerchantChainAction::RecordExpiry)
+            }
+            (ContractStatus::Expiry, ChannelStatus::PendingExpiry)
+                if timeout_expired == Some(true) =>
+            {
+                Some(MerchantChainAction::ClaimExpiry)
+            }
+            (ContractStatus::Closed, ChannelStatus::PendingMerchantClaim) => {
+                Some(MerchantChainAction::FinalizeExpiry)
+            }
+            (ContractStatus::Closed, ChannelStatus::Dispute) => {
+                Some(MerchantChainAction::FinalizeDispute)
+            }
+            (ContractStatus::Closed, ChannelStatus::PendingClose) => {
+                Some(MerchantChainAction::FinalizeCustomerClose)
+            }
+            _ => None,
+        }
+    }
+
+    /// What reconciling a channel's stored status against its contract found to be needed.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Reconciliation {
+        /// The merchant has to act on the state of the contract.
+        Act(MerchantChainAction),
+        /// The contract couldn't be found on chain, so an operator needs to look into the channel.
+        ContractNotFound,
+    }
+
+    impl Display for Reconciliation {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::Act(action) => write!(f, "{}", action),
+                Self::ContractNotFound => {
+                    f.write_str("flag the channel for attention, since its contract wasn't found")
+                }
+            }
+        }
+    }
+
+    /// Decide what reconciling a channel needs, from the status of its contract and whether its
+    /// timeout has expired, or `None` if the contract couldn't be found, and the channel's stored
+    /// status. Returns `None` if the channel is consistent with its contract.
+    ///
+    /// A closed channel is never reconciled, even if its contract has gone missing.
+    pub fn reconcile(
+        contract: Option<(ContractStatus, Option<bool>)>,
+        channel_status: ChannelStatus,
+    ) -> Option<Reconciliation> {
+        match contract {
+            _ if channel_status == ChannelStatus::Closed => None,
+            None => Some(Reconciliation::ContractNotFound),
+            Some((contract_status, timeout_expired)) => {
+                merchant_chain_action(contract_status, timeout_expired, channel_status)
+                    .map(Reconciliation::Act)
+            }
+        }
+    }
+
+    /// What an operator's manual close of a channel does, given the state of its contract.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ManualCloseAction {
+        /// Initiate expiry with the expiry entrypoint.
+        Expiry,
+        /// Claim the whole balance with merchClaim.
+        MerchantClaim,
+        /// Dispute the customer's close with merchDispute.
+        Dispute,
+    }
+
+    impl Display for ManualCloseAction {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            f.write_str(match self {
+                Self::Expiry => {
+                    "initiate expiry, because the contract is open; the customer then has the \
+                    self-delay to close on their latest balance"
+                }
+                Self::MerchantClaim => {
+                    "claim the whole balance with merchClaim, because the customer didn't respond \
+                    to the expiry within the self-delay"
+                }
+                Self::Dispute => {
+                    "dispute the customer's close with merchDispute, because the customer closed \
+                    on a revoked balance"
+                }
+            })
+        }
+    }
+
+    /// The reason a channel can't be closed manually right now.
+    #[derive(Debug, Clone, Copy, PartialEq, Error)]
+    pub enum ManualCloseError {
+        #[error("Contract is already closed (status {0:?})")]
+        AlreadyClosed(ContractStatus),
+        #[error("Contract isn't open yet (status {0:?}), so can't be expired")]
+        NotOpen(ContractStatus),
+        #[error(
+            "Expiry is in progress, but the customer can respond until {}",
+            .0.map_or_else(
+                || "an unknown time".into(),
+                |t| humantime::format_rfc3339_seconds(t).to_string()
+            )
+        )]
+        ExpiryPending(Option<SystemTime>),
+        #[error(
+            "Customer closed on their latest balance, so there is nothing to dispute; the \
+            channel is finalized once the close is processed"
+        )]
+        HonestCustomerClose,
+    }
+
+    /// Decide how to force the close of a channel right now, from the status of its contract,
+    /// when its timeout expires (if one is set), and whether the revocation lock the customer
+    /// posted on chain (if they did) has a known secret.
+    pub fn manual_close_action(
+        contract_status: ContractStatus,
+        delay_expiry: Option<SystemTime>,
+        now: SystemTime,
+        revoked: bool,
+    ) -> Result<ManualCloseAction, ManualCloseError> {
+        match contract_status {
+            ContractStatus::Open => Ok(ManualCloseAction::Expiry),
+            ContractStatus::Expiry => match delay_expiry {
+                Some(delay_expiry) if delay_expiry < now => Ok(ManualCloseAction::MerchantClaim),
+                _ => Err(ManualCloseError::ExpiryPending(delay_expiry)),
+            },
+            ContractStatus::CustomerClose if revoked => Ok(ManualCloseAction::Dispute),
+            ContractStatus::CustomerClose => Err(ManualCloseError::HonestCustomerClose),
+            ContractStatus::Closed | ContractStatus::FundingReclaimed => {
+                Err(ManualCloseError::AlreadyClosed(contract_status))
+            }
+            ContractStatus::AwaitingCustomerFunding | ContractStatus::AwaitingMerchantFunding => {
+                Err(ManualCloseError::NotOpen(contract_status))
+            }
+        }
+    }
+
     /// Mutual close session.
     pub type Close = CustomerSendSignature;
 
@@ -319,25 +2276,465 @@ pub mod close {
         send CloseStateSignature;
         send CloseState;
         // Merchant checks whether the `CloseState` is outdated
-        OfferAbort<MerchantSendAuthorization, Error>
+        OfferAbort<MerchantSendAuthorization>
     };
 
     pub type MerchantSendAuthorization = Session! {
         // Tezos authorization signature
         recv MutualCloseAuthorizationSignature;
         // Merchant verifies the signature
-        ChooseAbort<Done, Error>
+        ChooseAbort<Done>
     };
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::protocol::wire_pair;
+
+        /// Abort as the merchant does on receiving a close state it has already seen.
+        #[Transmitter(Tx for AbortReason)]
+        #[Receiver(Rx)]
+        async fn reject_known_lock<Tx, Rx>(
+            chan: Chan<<OfferAbort<Done> as Session>::Dual, Tx, Rx>,
+        ) -> Result<(), anyhow::Error>
+        where
+            Tx::Error: std::error::Error + Send + Sync + 'static,
+        {
+            abort!(in chan return Error::KnownRevocationLock)
+        }
+
+        #[Transmitter(Tx)]
+        #[Receiver(Rx for AbortReason)]
+        async fn await_authorization<Tx, Rx>(
+            chan: Chan<OfferAbort<Done>, Tx, Rx>,
+        ) -> Result<(), anyhow::Error>
+        where
+            Rx::Error: std::error::Error + Send + Sync + 'static,
+        {
+            offer_abort!(in chan as Party::Customer);
+            chan.close();
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn abort_reason_reaches_customer() {
+            let (customer, merchant) = wire_pair::<OfferAbort<Done>>();
+            let (customer, merchant) =
+                tokio::join!(await_authorization(customer), reject_known_lock(merchant));
+
+            // The merchant has the error it aborted with
+            assert!(matches!(
+                merchant.unwrap_err().downcast_ref::<Error>(),
+                Some(Error::KnownRevocationLock)
+            ));
+
+            // The customer gets the merchant's reason, with its message intact
+            let error = customer.unwrap_err();
+            assert_eq!(
+                error.downcast_ref::<AbortReason>(),
+                Some(&AbortReason {
+                    code: "close.known_revocation_lock".to_string(),
+                    message: "Customer sent a close state that has already been seen".to_string(),
+                })
+            );
+            assert_eq!(
+                format!("{:#}", error),
+                "Merchant chose to abort the session: \
+                Customer sent a close state that has already been seen"
+            );
+        }
+
+        #[test]
+        fn merchant_chain_actions() {
+            use ContractStatus::*;
+
+            // A custClose on an open channel, or one the merchant was expiring
+            for channel_status in &[
+                ChannelStatus::Active,
+                ChannelStatus::PendingExpiry,
+                ChannelStatus::PendingClose,
+            ] {
+                assert_eq!(
+                    merchant_chain_action(CustomerClose, None, *channel_status),
+                    Some(MerchantChainAction::ProcessCustomerClose)
+                );
+            }
+            assert_eq!(
+                merchant_chain_action(CustomerClose, Some(false), ChannelStatus::Closed),
+                None
+            );
+
+            // An expiry whose status update was lost is recorded before anything else
+            for channel_status in &[ChannelStatus::MerchantFunded, ChannelStatus::Active] {
+                assert_eq!(
+                    merchant_chain_action(Expiry, Some(true), *channel_status),
+                    Some(MerchantChainAction::RecordExpiry)
+                );
+            }
+
+            // An expiry is only claimed once its timeout has passed
+            assert_eq!(
+                merchant_chain_action(Expiry, Some(true), ChannelStatus::PendingExpiry),
+                Some(MerchantChainAction::ClaimExpiry)
+            );
+            assert_eq!(
+                merchant_chain_action(Expiry, Some(false), ChannelStatus::PendingExpiry),
+                None
+            );
+            assert_eq!(
+                merchant_chain_action(Expiry, None, ChannelStatus::PendingExpiry),
+                None
+            );
+
+            // Claims and disputes that closed the contract are finalized
+            assert_eq!(
+                merchant_chain_action(Closed, None, ChannelStatus::PendingMerchantClaim),
+                Some(MerchantChainAction::FinalizeExpiry)
+            );
+            assert_eq!(
+                merchant_chain_action(Closed, None, ChannelStatus::Dispute),
+                Some(MerchantChainAction::FinalizeDispute)
+            );
+            assert_eq!(
+                merchant_chain_action(Closed, None, ChannelStatus::Closed),
+                None
+            );
+            assert_eq!(
+                merchant_chain_action(Open, None, ChannelStatus::Active),
+                None
+            );
+
+            // A customer's close that was processed but never finalized is finalized
+            assert_eq!(
+                merchant_chain_action(Closed, None, ChannelStatus::PendingClose),
+                Some(MerchantChainAction::FinalizeCustomerClose)
+            );
+        }
+
+        #[test]
+        fn reconciliations() {
+            use ContractStatus::*;
+
+            // Consistent channels need nothing
+            assert_eq!(reconcile(Some((Open, None)), ChannelStatus::Active), None);
+            assert_eq!(reconcile(Some((Closed, None)), ChannelStatus::Closed), None);
+
+            // A closed contract whose channel is still pending close is finalized
+            assert_eq!(
+                reconcile(Some((Closed, None)), ChannelStatus::PendingClose),
+                Some(Reconciliation::Act(
+                    MerchantChainAction::FinalizeCustomerClose
+                ))
+            );
+
+            // A customer's close which the channel missed is processed, to dispute it if need be
+            assert_eq!(
+                reconcile(Some((CustomerClose, None)), ChannelStatus::Active),
+                Some(Reconciliation::Act(
+                    MerchantChainAction::ProcessCustomerClose
+                ))
+            );
+
+            // A missing contract is flagged, unless the channel is closed anyway
+            for channel_status in &[
+                ChannelStatus::Originated,
+                ChannelStatus::Active,
+                ChannelStatus::PendingClose,
+            ] {
+                assert_eq!(
+                    reconcile(None, *channel_status),
+                    Some(Reconciliation::ContractNotFound)
+                );
+            }
+            assert_eq!(reconcile(None, ChannelStatus::Closed), None);
+        }
+
+        #[test]
+        fn zero_customer_balance_close_plan() {
+            let zero = CustomerBalance::try_new(0).unwrap();
+            let some = CustomerBalance::try_new(1).unwrap();
+
+            // Responding to an expiry with nothing to claim: let the merchant claim everything
+            assert_eq!(
+                unilateral_close_plan(UnilateralCloseKind::MerchantInitiated, zero),
+                UnilateralClosePlan::AwaitMerchantClaim
+            );
+            assert_eq!(
+                unilateral_close_plan(UnilateralCloseKind::MerchantInitiated, some),
+                UnilateralClosePlan::PostCustomerClose
+            );
+
+            // Closing of the customer's own accord always closes the contract
+            assert_eq!(
+                unilateral_close_plan(UnilateralCloseKind::CustomerInitiated, zero),
+                UnilateralClosePlan::PostCustomerClose
+            );
+            assert_eq!(
+                unilateral_close_plan(UnilateralCloseKind::CustomerInitiated, some),
+                UnilateralClosePlan::PostCustomerClose
+            );
+        }
+
+        #[test]
+        fn close_twice() {
+            // The first close is allowed, from any open state or a failed mutual close
+            for state in [
+                StateName::Ready,
+                StateName::Locked,
+                StateName::PendingMutualClose,
+            ] {
+                assert_eq!(check_not_closing(state, false), Ok(()));
+            }
+
+            // After the first close, whether it posted or wrote out custClose, the second isn't
+            assert_eq!(
+                check_not_closing(StateName::PendingClose, false),
+                Err(AlreadyClosingError::Closing(StateName::PendingClose))
+            );
+            assert_eq!(
+                check_not_closing(StateName::PendingClose, true),
+                Err(AlreadyClosingError::AwaitingBroadcast)
+            );
+            assert_eq!(
+                check_not_closing(StateName::Closed, false),
+                Err(AlreadyClosingError::Closing(StateName::Closed))
+            );
+        }
+
+        #[test]
+        fn dispute_or_claim() {
+            use CustomerCloseEnding::*;
+
+            // A dispute is recognized whether or not the entrypoint is known
+            assert_eq!(
+                customer_close_ending(StateName::PendingClose, Some(Entrypoint::MerchantDispute)),
+                Some(Disputed)
+            );
+            assert_eq!(
+                customer_close_ending(StateName::PendingClose, None),
+                Some(Disputed)
+            );
+            assert_eq!(
+                customer_close_ending(StateName::Dispute, None),
+                Some(Disputed)
+            );
+
+            // A custClaim that landed isn't mistaken for a dispute
+            assert_eq!(
+                customer_close_ending(StateName::PendingClose, Some(Entrypoint::CustomerClaim)),
+                Some(Claimed)
+            );
+            assert_eq!(
+                customer_close_ending(StateName::PendingCustomerClaim, None),
+                Some(Claimed)
+            );
+
+            // Channels that aren't in a customer close are left alone
+            assert_eq!(customer_close_ending(StateName::Closed, None), None);
+            assert_eq!(
+                customer_close_ending(StateName::PendingExpiry, Some(Entrypoint::MerchantClaim)),
+                None
+            );
+        }
+
+        #[test]
+        fn merchant_claim_or_mutual_close() {
+            use ClosedContractEnding::*;
+            use ContractStatus::*;
+
+            // A merchClaim finishes an expiry the customer didn't answer, whether or not the
+            // customer tried to
+            assert_eq!(
+                closed_contract_ending(StateName::PendingExpiry, None, Some(Expiry)),
+                Some(MerchantClaimed)
+            );
+            assert_eq!(
+                closed_contract_ending(
+                    StateName::PendingExpiry,
+                    Some(Entrypoint::MerchantClaim),
+                    None
+                ),
+                Some(MerchantClaimed)
+            );
+            assert_eq!(
+                closed_contract_ending(
+                    StateName::PendingClose,
+                    Some(Entrypoint::MerchantClaim),
+                    Some(Expiry)
+                ),
+                Some(MerchantClaimed)
+            );
+
+            // An open channel can only have missed the expiry and the claim after it
+            assert_eq!(
+                closed_contract_ending(StateName::Ready, None, None),
+                Some(MerchantClaimed)
+            );
+            assert_eq!(
+                closed_contract_ending(
+                    StateName::Locked,
+                    Some(Entrypoint::MerchantClaim),
+                    Some(Open)
+                ),
+                Some(MerchantClaimed)
+            );
+            assert_eq!(
+                closed_contract_ending(
+                    StateName::PendingMutualClose,
+                    Some(Entrypoint::MerchantClaim),
+                    Some(Expiry)
+                ),
+                Some(MerchantClaimed)
+            );
+
+            // A mutual close is recognized by its entrypoint, or by the contract closing while open
+            assert_eq!(
+                closed_contract_ending(
+                    StateName::PendingMutualClose,
+                    Some(Entrypoint::MutualClose),
+                    None
+                ),
+                Some(MutuallyClosed)
+            );
+            assert_eq!(
+                closed_contract_ending(StateName::PendingMutualClose, None, Some(Open)),
+                Some(MutuallyClosed)
+            );
+
+            // A contract that went through expiry wasn't closed mutually, even if the customer
+            // had started to
+            assert_eq!(
+                closed_contract_ending(StateName::PendingMutualClose, None, Some(Expiry)),
+                None
+            );
+            assert_eq!(
+                closed_contract_ending(StateName::PendingMutualClose, None, None),
+                None
+            );
+
+            // Customer closes, and channels that are already closed, are left alone
+            assert_eq!(
+                closed_contract_ending(
+                    StateName::PendingClose,
+                    Some(Entrypoint::MerchantDispute),
+                    Some(CustomerClose)
+                ),
+                None
+            );
+            assert_eq!(
+                closed_contract_ending(StateName::Closed, Some(Entrypoint::MutualClose), None),
+                None
+            );
+        }
+
+        #[test]
+        fn manual_close_actions() {
+            use std::time::Duration;
+            use ContractStatus::*;
+
+            let now = SystemTime::now();
+            let an_hour = Duration::from_secs(60 * 60);
+
+            assert_eq!(
+                manual_close_action(Open, None, now, false),
+                Ok(ManualCloseAction::Expiry)
+            );
+
+            // An expiry can only be claimed once the customer's chance to respond has passed
+            assert_eq!(
+                manual_close_action(Expiry, Some(now - an_hour), now, false),
+                Ok(ManualCloseAction::MerchantClaim)
+            );
+            assert_eq!(
+                manual_close_action(Expiry, Some(now + an_hour), now, false),
+                Err(ManualCloseError::ExpiryPending(Some(now + an_hour)))
+            );
+            assert_eq!(
+                manual_close_action(Expiry, None, now, false),
+                Err(ManualCloseError::ExpiryPending(None))
+            );
+
+            // Only a close on a revoked balance can be disputed
+            assert_eq!(
+                manual_close_action(CustomerClose, None, now, true),
+                Ok(ManualCloseAction::Dispute)
+            );
+            assert_eq!(
+                manual_close_action(CustomerClose, None, now, false),
+                Err(ManualCloseError::HonestCustomerClose)
+            );
+
+            for status in &[Closed, FundingReclaimed] {
+                assert_eq!(
+                    manual_close_action(*status, None, now, true),
+                    Err(ManualCloseError::AlreadyClosed(*status))
+                );
+            }
+            for status in &[AwaitingCustomerFunding, AwaitingMerchantFunding] {
+                assert_eq!(
+                    manual_close_action(*status, None, now, false),
+                    Err(ManualCloseError::NotOpen(*status))
+                );
+            }
+        }
+
+        #[test]
+        fn check_off_chain_close() {
+            let balances = |customer, merchant| {
+                (
+                    CustomerBalance::try_new(customer).unwrap(),
+                    MerchantBalance::try_new(merchant).unwrap(),
+                )
+            };
+
+            assert_eq!(
+                check_posted_close(
+                    ContractStatus::CustomerClose,
+                    balances(5, 5),
+                    balances(5, 5)
+                ),
+                Ok(())
+            );
+
+            // The operation hasn't been applied, or something else closed the contract
+            assert_eq!(
+                check_posted_close(ContractStatus::Open, balances(5, 5), balances(5, 5)),
+                Err(ConfirmCloseError::UnexpectedStatus(ContractStatus::Open))
+            );
+
+            // Balances posted on chain don't match the stored closing message
+            assert_eq!(
+                check_posted_close(
+                    ContractStatus::CustomerClose,
+                    balances(7, 3),
+                    balances(5, 5)
+                ),
+                Err(ConfirmCloseError::BalanceMismatch {
+                    posted_customer: 7,
+                    posted_merchant: 3,
+                    expected_customer: 5,
+                    expected_merchant: 5,
+                })
+            );
+        }
+    }
 }
 
 pub mod pay {
     use super::*;
+    use crate::timeout::await_response;
+    use anyhow::Context;
+    use std::{future::Future, time::Duration};
     use zkabacus_crypto::{self, PaymentAmount};
 
     #[derive(Debug, Clone, Serialize, Deserialize, Error)]
     pub enum Error {
         #[error("Payment rejected: {0}")]
         Rejected(String),
+        #[error("Payment rejected: {0}")]
+        LimitExceeded(LimitExceeded),
+        #[error("Payment rejected: {0}")]
+        NoteTooLong(NoteTooLong),
         #[error("Customer failed to generate nonce and pay proof: {0}")]
         StartFailed(#[from] zkabacus_crypto::Error),
         #[error("Customer submitted reused nonce")]
@@ -352,18 +2749,203 @@ pub mod pay {
         InvalidPayProof,
         #[error("Channel frozen: merchant returned invalid payment token")]
         InvalidPayToken,
+        #[error("Merchant has no interrupted payment to resume for this nonce")]
+        NoPaymentToResume,
+        #[error("Merchant already completed a payment with ID {0}")]
+        DuplicatePayment(PaymentId),
+    }
+
+    impl AbortCode for Error {
+        fn code(&self) -> &'static str {
+            match self {
+                Self::Rejected(_) => "pay.rejected",
+                Self::LimitExceeded(_) => "pay.limit_exceeded",
+                Self::NoteTooLong(_) => "pay.note_too_long",
+                Self::StartFailed(_) => "pay.start_failed",
+                Self::ReusedNonce => "pay.reused_nonce",
+                Self::InvalidClosingSignature => "pay.invalid_closing_signature",
+                Self::ReusedRevocationLock => "pay.reused_revocation_lock",
+                Self::InvalidRevocationOpening => "pay.invalid_revocation_opening",
+                Self::InvalidPayProof => "pay.invalid_pay_proof",
+                Self::InvalidPayToken => "pay.invalid_pay_token",
+                Self::NoPaymentToResume => "pay.no_payment_to_resume",
+                Self::DuplicatePayment(_) => "pay.duplicate_payment",
+            }
+        }
+    }
+
+    /// An ID the customer generates for each payment, which both parties record in their payment
+    /// history and the merchant echoes back at the end of the payment, so that the outcome of a
+    /// payment whose session was lost can be looked up afterwards.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+    #[serde(transparent)]
+    pub struct PaymentId(uuid::Uuid);
+
+    impl PaymentId {
+        /// A fresh, random payment ID.
+        pub fn generate() -> Self {
+            Self(uuid::Uuid::new_v4())
+        }
+    }
+
+    impl Display for PaymentId {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.0.to_hyphenated_ref())
+        }
+    }
+
+    impl FromStr for PaymentId {
+        type Err = uuid::Error;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            s.parse().map(Self)
+        }
+    }
+
+    /// The limit a payment would have exceeded, which the merchant checks before approving it.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Error)]
+    pub enum LimitExceeded {
+        #[error("the merchant accepts payments of at most {0} mutez")]
+        PaymentAmount(u64),
+        #[error("the merchant issues refunds of at most {0} mutez")]
+        RefundAmount(u64),
+        #[error("the merchant accepts at most {0} payments per minute")]
+        PaymentRate(u32),
+        #[error("the merchant accepts at most {0} mutez of payments per day")]
+        DailyVolume(u64),
+    }
+
+    /// The window over which [`PaymentLimits::max_payments_per_minute`] is counted.
+    pub const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+    /// The window over which [`PaymentLimits::max_volume_per_day`] is counted.
+    pub const VOLUME_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// How long after a payment starts the merchant will resume it, if its session is interrupted.
+    pub const RESUME_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// The start of the window of the given length which ends at `now`, both in seconds since the
+    /// Unix epoch. A payment recorded strictly after the start falls within the window.
+    pub fn window_start(now: u64, window: Duration) -> u64 {
+        now.saturating_sub(window.as_secs())
+    }
+
+    /// The payments the merchant has already recorded within each window.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct RecentPayments {
+        /// The number of payments and refunds within the [`RATE_WINDOW`].
+        pub count: u64,
+        /// The total of payments to the merchant, excluding refunds, within the [`VOLUME_WINDOW`].
+        pub volume: u64,
+    }
+
+    /// The limits, in mutez, a merchant places on payments. A limit of `None` is not enforced.
+    ///
+    /// Payments are unlinkable, so the rate and volume limits apply across all channels.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct PaymentLimits {
+        pub max_payment_amount: Option<u64>,
+        pub max_refund_amount: Option<u64>,
+        pub max_payments_per_minute: Option<u32>,
+        pub max_volume_per_day: Option<u64>,
+    }
+
+    impl PaymentLimits {
+        /// Check the amount of a payment, or of a refund if it is negative, against its cap.
+        pub fn check_amount(&self, amount: i64) -> Result<(), LimitExceeded> {
+            match (amount >= 0, self.max_payment_amount, self.max_refund_amount) {
+                (true, Some(max), _) if amount.unsigned_abs() > max => {
+                    Err(LimitExceeded::PaymentAmount(max))
+                }
+                (false, _, Some(max)) if amount.unsigned_abs() > max => {
+                    Err(LimitExceeded::RefundAmount(max))
+                }
+                _ => Ok(()),
+            }
+        }
+
+        /// Check whether one more payment of the given amount fits within the rate and volume
+        /// limits, given the payments already recorded within their windows.
+        pub fn check_recent(
+            &self,
+            recent: &RecentPayments,
+            amount: i64,
+        ) -> Result<(), LimitExceeded> {
+            if let Some(max) = self.max_payments_per_minute {
+                if recent.count >= max as u64 {
+                    return Err(LimitExceeded::PaymentRate(max));
+                }
+            }
+            if let Some(max) = self.max_volume_per_day {
+                if recent.volume.saturating_add(amount.max(0) as u64) > max {
+                    return Err(LimitExceeded::DailyVolume(max));
+                }
+            }
+            Ok(())
+        }
     }
 
     /// The full zkchannels "pay" protocol's session type.
     pub type Pay = Session! {
         send PaymentAmount;
         send String; // Payment note
+        send PaymentId;
         GetPaymentApproval;
     };
 
+    /// Any number of payments, one after another over the same session, for streaming payments.
+    /// Before each one, the customer chooses whether to make another.
+    pub type Repeated = Session! {
+        loop {
+            choose {
+                0 => break,
+                1 => call Pay,
+            }
+        }
+    };
+
+    /// Serve repeated payments as the merchant, running `pay` for each payment the customer
+    /// chooses to make, until the customer chooses to stop.
+    ///
+    /// The customer has `max_wait` to choose whether to make each payment, after which the session
+    /// is given up on, so that a customer can't hold it open indefinitely between payments.
+    #[Transmitter(Tx)]
+    #[Receiver(Rx)]
+    pub async fn serve_repeated<Tx, Rx, F, Fut>(
+        mut chan: Chan<<Repeated as Session>::Dual, Tx, Rx>,
+        max_wait: Duration,
+        mut pay: F,
+    ) -> Result<(), anyhow::Error>
+    where
+        Rx::Error: std::error::Error + Send + Sync + 'static,
+        F: FnMut(Chan<<Pay as Session>::Dual, Tx, Rx>) -> Fut,
+        Fut: Future<Output = Result<(), anyhow::Error>>,
+    {
+        loop {
+            let next = await_response(max_wait, "choice of whether to pay again", async move {
+                Ok(offer!(in chan {
+                    0 => {
+                        chan.close();
+                        None
+                    }
+                    1 => Some(chan),
+                })
+                .context("Failed to receive choice of whether to pay again")?)
+            })
+            .await?;
+            chan = match next {
+                None => return Ok(()),
+                Some(chan) => {
+                    let ((), chan) = chan.call(&mut pay).await?;
+                    chan.map_err(|_| anyhow::anyhow!("Pay session ended before it was complete"))?
+                }
+            };
+        }
+    }
+
     pub type GetPaymentApproval = Session! {
         // Merchant decides if it wants to allow the described payment
-        OfferAbort<CustomerStartPayment, Error>;
+        OfferAbort<CustomerStartPayment>;
     };
 
     /// The start of the zkabacus "pay" protocol.
@@ -371,20 +2953,20 @@ pub mod pay {
         send Nonce;
         send PayProof;
         // Merchant checks that the `PayProof` is valid
-        OfferAbort<MerchantAcceptPayment, Error>;
+        OfferAbort<MerchantAcceptPayment>;
     };
 
     pub type MerchantAcceptPayment = Session! {
         recv ClosingSignature;
         // Customer verifies the signature
-        ChooseAbort<CustomerRevokePreviousPayToken, Error>;
+        ChooseAbort<CustomerRevokePreviousPayToken>;
     };
 
     pub type CustomerRevokePreviousPayToken = Session! {
         send RevocationPair;
         send RevocationLockBlindingFactor;
         // Merchant verifies that the revocation information is valid
-        OfferAbort<MerchantIssueNewPayToken, Error>;
+        OfferAbort<MerchantIssueNewPayToken>;
     };
 
     pub type MerchantIssueNewPayToken = Session! {
@@ -393,18 +2975,848 @@ pub mod pay {
     };
 
     pub type MerchantProvideService = Session! {
+        // Echoed back, so the customer can tell which payment this concludes, unless the payment
+        // was resumed from a session the merchant kept no ID for
+        recv Option<PaymentId>;
         recv Option<String>;
     };
+
+    /// Resume a payment whose session was interrupted after the customer started it, by replaying
+    /// the rest of the pay protocol for the nonce and pay proof the customer sent in that session.
+    ///
+    /// The merchant only resumes a payment for the same pay proof it first received, within the
+    /// [`RESUME_WINDOW`], and checks it against the context of the original session, so resuming
+    /// a payment can only ever produce the state the customer was originally paying towards. A
+    /// customer who already revealed their revocation pair sends it again, and ignores the new
+    /// closing signature. The approver isn't asked again, but the merchant provides the service
+    /// it approved in the original session.
+    pub type Resume = CustomerStartPayment;
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::{
+            protocol::{wire_pair, WireChan},
+            timeout::ResponseTimeout,
+        };
+        use rand::{rngs::StdRng, SeedableRng};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use zkabacus_crypto::{
+            customer::{Config, Ready, Requested},
+            merchant, ChannelId, CustomerBalance, MerchantBalance,
+        };
+
+        const NOW: u64 = 1_634_600_000;
+
+        /// Receive a payment request as the merchant does, refusing an over-long note before
+        /// asking the approver, which is counted rather than called.
+        async fn receive_payment(
+            chan: WireChan<<Pay as Session>::Dual>,
+            max_note_length: u64,
+            approver_calls: &AtomicUsize,
+        ) -> Result<(), anyhow::Error> {
+            let (_amount, chan) = chan.recv().await?;
+            let (note, chan): (String, _) = chan.recv().await?;
+            let (_payment_id, chan) = chan.recv().await?;
+            if let Err(e) = check_note_length(&note, max_note_length) {
+                abort!(in chan return Error::NoteTooLong(e));
+            }
+            approver_calls.fetch_add(1, Ordering::SeqCst);
+            proceed!(in chan);
+            drop(chan);
+            Ok(())
+        }
+
+        async fn request_payment(chan: WireChan<Pay>, note: String) -> Result<(), anyhow::Error> {
+            let chan = chan
+                .send(PaymentAmount::pay_merchant(5).unwrap())
+                .await?
+                .send(note)
+                .await?
+                .send(PaymentId::generate())
+                .await?;
+            offer_abort!(in chan as Party::Customer);
+            drop(chan);
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn over_long_note_is_refused_before_approval() {
+            let approver_calls = AtomicUsize::new(0);
+
+            let (customer, merchant) = wire_pair::<Pay>();
+            let (customer, merchant) = tokio::join!(
+                request_payment(customer, "a".repeat(65)),
+                receive_payment(merchant, 64, &approver_calls),
+            );
+            assert!(matches!(
+                merchant.unwrap_err().downcast_ref::<Error>(),
+                Some(Error::NoteTooLong(NoteTooLong {
+                    length: 65,
+                    max: 64
+                }))
+            ));
+            let reason = customer.unwrap_err();
+            let reason = reason.downcast_ref::<AbortReason>().unwrap();
+            assert_eq!(reason.code, "pay.note_too_long");
+            assert!(reason.message.contains("at most 64 bytes"));
+            assert_eq!(approver_calls.load(Ordering::SeqCst), 0);
+
+            // A note within the limit goes on to the approver
+            let (customer, merchant) = wire_pair::<Pay>();
+            let (customer, merchant) = tokio::join!(
+                request_payment(customer, "a".repeat(64)),
+                receive_payment(merchant, 64, &approver_calls),
+            );
+            customer.unwrap();
+            merchant.unwrap();
+            assert_eq!(approver_calls.load(Ordering::SeqCst), 1);
+        }
+
+        /// The rest of the pay protocol once the customer has received the closing signature.
+        type AfterClosingSignature = ChooseAbort<CustomerRevokePreviousPayToken>;
+
+        /// Abort as the customer does on receiving a closing signature which doesn't verify.
+        async fn refuse_closing_signature(
+            chan: WireChan<AfterClosingSignature>,
+        ) -> Result<(), anyhow::Error> {
+            abort!(in chan return Error::InvalidClosingSignature)
+        }
+
+        /// Wait for the customer's revocation pair as the merchant does after sending the closing
+        /// signature.
+        async fn await_revocation(
+            chan: WireChan<<AfterClosingSignature as Session>::Dual>,
+        ) -> Result<(), anyhow::Error> {
+            offer_abort!(in chan as Party::Merchant);
+            drop(chan);
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn invalid_closing_signature_reason_reaches_merchant() {
+            let (customer, merchant) = wire_pair::<AfterClosingSignature>();
+            let (customer, merchant) = tokio::join!(
+                refuse_closing_signature(customer),
+                await_revocation(merchant)
+            );
+
+            assert!(matches!(
+                customer.unwrap_err().downcast_ref::<Error>(),
+                Some(Error::InvalidClosingSignature)
+            ));
+
+            // The merchant is told the signature was refused, not that its pay token was
+            let error = merchant.unwrap_err();
+            assert_eq!(
+                error.downcast_ref::<AbortReason>(),
+                Some(&AbortReason {
+                    code: "pay.invalid_closing_signature".to_string(),
+                    message: "Merchant returned invalid closing signature".to_string(),
+                })
+            );
+            assert_eq!(
+                format!("{:#}", error),
+                "Customer chose to abort the session: Merchant returned invalid closing signature"
+            );
+        }
+
+        /// The context of every payment in these tests, in place of a session transcript.
+        fn payment_context() -> zkabacus_crypto::Context {
+            zkabacus_crypto::Context::new(b"repeated payments")
+        }
+
+        /// A merchant and a customer with an active channel between them, in which the customer
+        /// has 5 to pay with.
+        fn active_channel() -> (merchant::Config, Config, Ready) {
+            let mut rng = StdRng::from_entropy();
+            let merchant_config = merchant::Config::new(&mut rng);
+            let (pk, rev_param, range_param) = merchant_config.extract_customer_config_parts();
+            let config = Config::from_parts(pk, rev_param, range_param);
+            let channel_id = ChannelId::new(
+                MerchantRandomness::new(&mut rng),
+                CustomerRandomness::new(&mut rng),
+                config.merchant_public_key(),
+                &[],
+                &[],
+            );
+            let merchant_balance = MerchantBalance::try_new(5).unwrap();
+            let customer_balance = CustomerBalance::try_new(5).unwrap();
+            let context = zkabacus_crypto::Context::new(b"establish");
+
+            let (requested, proof) = Requested::new(
+                &mut rng,
+                &config,
+                channel_id,
+                merchant_balance,
+                customer_balance,
+                &context,
+            );
+            let (closing_signature, blinded_state) = merchant_config
+                .initialize(
+                    &mut rng,
+                    &channel_id,
+                    customer_balance,
+                    merchant_balance,
+                    proof,
+                    &context,
+                )
+                .unwrap();
+            let inactive = requested.complete(closing_signature, &config).unwrap();
+            let pay_token = merchant_config.activate(&mut rng, blinded_state);
+            let ready = match inactive.activate(pay_token, &config) {
+                Ok(ready) => ready,
+                Err(_) => panic!("Failed to activate channel"),
+            };
+            (merchant_config, config, ready)
+        }
+
+        /// Make a payment of 1 as the customer, returning the channel's new state and the
+        /// merchant's response note.
+        async fn make_payment(
+            chan: WireChan<Pay>,
+            config: &Config,
+            ready: Ready,
+        ) -> Result<(Ready, Option<String>), anyhow::Error> {
+            let mut rng = StdRng::from_entropy();
+            let payment_amount = PaymentAmount::pay_merchant(1).unwrap();
+            let payment_id = PaymentId::generate();
+            let chan = chan
+                .send(payment_amount)
+                .await?
+                .send("one more second".to_string())
+                .await?
+                .send(payment_id)
+                .await?;
+            offer_abort!(in chan as Party::Customer);
+
+            let (started, start_message) = ready
+                .start(&mut rng, payment_amount, &payment_context(), config)
+                .map_err(|_| anyhow::anyhow!("Failed to start payment"))?;
+            let chan = chan
+                .send(start_message.nonce)
+                .await?
+                .send(start_message.pay_proof)
+                .await?;
+            offer_abort!(in chan as Party::Customer);
+
+            let (closing_signature, chan) = chan.recv().await?;
+            let (locked, lock_message) = started
+                .lock(closing_signature, config)
+                .map_err(|_| anyhow::anyhow!("Failed to lock payment"))?;
+            proceed!(in chan);
+            let chan = chan
+                .send(lock_message.revocation_pair)
+                .await?
+                .send(lock_message.revocation_lock_blinding_factor)
+                .await?;
+            offer_abort!(in chan as Party::Customer);
+
+            let (pay_token, chan) = chan.recv().await?;
+            let (echoed_id, chan) = chan.recv().await?;
+            let (response_note, chan) = chan.recv().await?;
+            chan.close();
+            assert_eq!(echoed_id, Some(payment_id));
+            let ready = locked
+                .unlock(pay_token, config)
+                .map_err(|_| anyhow::anyhow!("Failed to unlock payment"))?;
+            Ok((ready, response_note))
+        }
+
+        /// Accept a payment as the merchant, counting it once it completes.
+        async fn accept_payment(
+            chan: WireChan<<Pay as Session>::Dual>,
+            merchant_config: &merchant::Config,
+            completed: &AtomicUsize,
+        ) -> Result<(), anyhow::Error> {
+            let mut rng = StdRng::from_entropy();
+            let (payment_amount, chan) = chan.recv().await?;
+            let (note, chan): (String, _) = chan.recv().await?;
+            let (payment_id, chan) = chan.recv().await?;
+            proceed!(in chan);
+
+            let (nonce, chan) = chan.recv().await?;
+            let (pay_proof, chan) = chan.recv().await?;
+            let (unrevoked, closing_signature) = match merchant_config.allow_payment(
+                &mut rng,
+                payment_amount,
+                &nonce,
+                pay_proof,
+                &payment_context(),
+            ) {
+                Some(allowed) => allowed,
+                None => abort!(in chan return Error::InvalidPayProof),
+            };
+            proceed!(in chan);
+            let chan = chan.send(closing_signature).await?;
+            offer_abort!(in chan as Party::Merchant);
+
+            let (revocation_pair, chan) = chan.recv().await?;
+            let (revocation_blinding_factor, chan) = chan.recv().await?;
+            let pay_token = match unrevoked.complete_payment(
+                &mut rng,
+                &revocation_pair,
+                &revocation_blinding_factor,
+            ) {
+                Ok(pay_token) => pay_token,
+                Err(_) => abort!(in chan return Error::InvalidRevocationOpening),
+            };
+            completed.fetch_add(1, Ordering::SeqCst);
+            proceed!(in chan);
+            chan.send(pay_token)
+                .await?
+                .send(Some(payment_id))
+                .await?
+                .send(Some(format!("thanks for {}", note)))
+                .await?
+                .close();
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn repeated_payments_over_one_session() {
+            let (merchant_config, config, mut ready) = active_channel();
+            let completed = AtomicUsize::new(0);
+            let (merchant_config, completed_ref) = (&merchant_config, &completed);
+
+            let (customer, merchant) = wire_pair::<Repeated>();
+            let customer = async {
+                let mut chan = customer;
+                let mut response_notes = Vec::new();
+                for _ in 0..3 {
+                    let (paid, next) = chan
+                        .choose::<1>()
+                        .await?
+                        .call(|chan| make_payment(chan, &config, ready))
+                        .await?;
+                    chan = next.map_err(|_| anyhow::anyhow!("Pay session was incomplete"))?;
+                    ready = paid.0;
+                    response_notes.push(paid.1);
+                }
+                chan.choose::<0>().await?.close();
+                Ok::<_, anyhow::Error>(response_notes)
+            };
+            let merchant = serve_repeated(merchant, Duration::from_secs(5), move |chan| {
+                accept_payment(chan, merchant_config, completed_ref)
+            });
+            let (customer, merchant) = tokio::join!(customer, merchant);
+
+            // Each payment completes on the same session, which then ends as the customer chose
+            merchant.unwrap();
+            assert_eq!(
+                customer.unwrap(),
+                vec![Some("thanks for one more second".to_string()); 3]
+            );
+            assert_eq!(completed.load(Ordering::SeqCst), 3);
+        }
+
+        #[tokio::test]
+        async fn repeated_payments_give_up_on_a_stalled_customer() {
+            let (merchant_config, _config, _ready) = active_channel();
+            let completed = AtomicUsize::new(0);
+            let (merchant_config, completed_ref) = (&merchant_config, &completed);
+
+            // The customer never chooses whether to make another payment
+            let (_customer, merchant) = wire_pair::<Repeated>();
+            let error = serve_repeated(merchant, Duration::from_millis(50), move |chan| {
+                accept_payment(chan, merchant_config, completed_ref)
+            })
+            .await
+            .unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<ResponseTimeout>(),
+                Some(ResponseTimeout {
+                    awaiting: "choice of whether to pay again",
+                    ..
+                })
+            ));
+            assert_eq!(completed.load(Ordering::SeqCst), 0);
+        }
+
+        fn limits() -> PaymentLimits {
+            PaymentLimits {
+                max_payment_amount: Some(100),
+                max_refund_amount: Some(10),
+                max_payments_per_minute: Some(3),
+                max_volume_per_day: Some(1000),
+            }
+        }
+
+        #[test]
+        fn window_starts() {
+            assert_eq!(window_start(NOW, RATE_WINDOW), NOW - 60);
+            assert_eq!(window_start(NOW, VOLUME_WINDOW), NOW - 86_400);
+
+            // A window reaching back before the epoch starts at the epoch
+            assert_eq!(window_start(30, RATE_WINDOW), 0);
+
+            // Sub-second windows are empty
+            assert_eq!(window_start(NOW, Duration::from_millis(500)), NOW);
+        }
+
+        #[test]
+        fn payment_amounts() {
+            let limits = limits();
+            assert_eq!(limits.check_amount(100), Ok(()));
+            assert_eq!(
+                limits.check_amount(101),
+                Err(LimitExceeded::PaymentAmount(100))
+            );
+            assert_eq!(limits.check_amount(-10), Ok(()));
+            assert_eq!(
+                limits.check_amount(-11),
+                Err(LimitExceeded::RefundAmount(10))
+            );
+
+            // Refunds are capped independently of payments
+            let refunds_only = PaymentLimits {
+                max_refund_amount: Some(10),
+                ..PaymentLimits::default()
+            };
+            assert_eq!(refunds_only.check_amount(i64::MAX), Ok(()));
+            assert_eq!(
+                refunds_only.check_amount(i64::MIN),
+                Err(LimitExceeded::RefundAmount(10))
+            );
+            assert_eq!(PaymentLimits::default().check_amount(i64::MIN), Ok(()));
+        }
+
+        #[test]
+        fn payment_rate() {
+            let limits = limits();
+            let recent = |count| RecentPayments { count, volume: 0 };
+            assert_eq!(limits.check_recent(&recent(2), 1), Ok(()));
+            assert_eq!(
+                limits.check_recent(&recent(3), 1),
+                Err(LimitExceeded::PaymentRate(3))
+            );
+            // Refunds count towards the rate
+            assert_eq!(
+                limits.check_recent(&recent(3), -1),
+                Err(LimitExceeded::PaymentRate(3))
+            );
+        }
+
+        #[test]
+        fn daily_volume() {
+            let limits = limits();
+            let recent = |volume| RecentPayments { count: 0, volume };
+            assert_eq!(limits.check_recent(&recent(900), 100), Ok(()));
+            assert_eq!(
+                limits.check_recent(&recent(901), 100),
+                Err(LimitExceeded::DailyVolume(1000))
+            );
+            // Refunds don't count towards the volume, even once it has been reached
+            assert_eq!(limits.check_recent(&recent(1000), -5), Ok(()));
+            assert_eq!(
+                limits.check_recent(&recent(u64::MAX), 1),
+                Err(LimitExceeded::DailyVolume(1000))
+            );
+        }
+    }
 }
 
 pub mod daemon {
     use super::*;
+    use anyhow::Context;
     use dialectic::types::Done;
+    use rand::{CryptoRng, Rng};
+    use sha3::{Digest, Sha3_256};
+    use std::{future::Future, path::Path, time::SystemTime};
+    use tokio::sync::mpsc;
+
+    use crate::{
+        config::DatabaseLocation,
+        customer::{
+            database::{ContractObservation, StateName},
+            ChannelName,
+        },
+        escrow::types::{ContractId, Entrypoint},
+    };
+
+    use super::pay::PaymentId;
+    use zkabacus_crypto::{CustomerBalance, MerchantBalance, PaymentAmount};
+
+    /// The first port in the range of dynamic ports, from which daemon ports are derived.
+    const DYNAMIC_PORT_START: u16 = 49152;
+
+    /// A fingerprint of the customer database that a daemon is serving.
+    ///
+    /// The daemon sends this at the start of every control session, so that a client configured
+    /// for one database never sends commands to a daemon watching channels from another.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+    pub struct DaemonIdentity([u8; 32]);
+
+    impl DaemonIdentity {
+        /// Compute the identity of a daemon serving the database at the given location.
+        ///
+        /// The parent directory of a SQLite database is canonicalized if it exists, so that the
+        /// same database reached by different relative paths has the same identity.
+        pub fn new(location: &DatabaseLocation) -> Self {
+            let mut hasher = Sha3_256::new();
+            match location {
+                DatabaseLocation::Ephemeral => hasher.update(b"ephemeral"),
+                DatabaseLocation::Sqlite(path) => {
+                    hasher.update(b"sqlite:");
+                    hasher.update(canonical_database_path(path).to_string_lossy().as_bytes());
+                }
+                DatabaseLocation::Postgres(uri) => {
+                    hasher.update(b"postgres:");
+                    hasher.update(uri.to_string().as_bytes());
+                }
+            }
+
+            let mut digested = [0; 32];
+            digested.copy_from_slice(hasher.finalize().as_ref());
+            Self(digested)
+        }
+
+        /// The default port for a daemon with this identity, in the range of dynamic ports.
+        ///
+        /// Deriving the port from the identity means that customers with several data
+        /// directories on one machine get distinct daemon ports without configuring them.
+        pub fn default_port(&self) -> u16 {
+            let offset =
+                u16::from_be_bytes([self.0[0], self.0[1]]) % (u16::MAX - DYNAMIC_PORT_START);
+            DYNAMIC_PORT_START + offset
+        }
+
+        /// Check that this identity, received from a daemon, is the one the client expected.
+        pub fn verify(&self, expected: &DaemonIdentity) -> Result<(), WrongDaemon> {
+            if self == expected {
+                Ok(())
+            } else {
+                Err(WrongDaemon {
+                    expected: *expected,
+                    actual: *self,
+                })
+            }
+        }
+    }
+
+    impl Display for DaemonIdentity {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", hex::encode(&self.0[..8]))
+        }
+    }
+
+    fn canonical_database_path(path: &Path) -> std::path::PathBuf {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(file_name)) => match parent.canonicalize() {
+                Ok(parent) => parent.join(file_name),
+                Err(_) => path.to_path_buf(),
+            },
+            _ => path.to_path_buf(),
+        }
+    }
+
+    /// The daemon on the other end of a control session is serving a different database.
+    #[derive(Debug, Error)]
+    #[error(
+        "Connected to a daemon serving a different customer database \
+        (expected {expected}, found {actual}); \
+        check that the daemon was started with the same configuration"
+    )]
+    pub struct WrongDaemon {
+        pub expected: DaemonIdentity,
+        pub actual: DaemonIdentity,
+    }
+
+    /// A secret token, stored in a file readable only by the customer, which a client presents to
+    /// the daemon to prove that it is allowed to control it.
+    #[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct DaemonToken([u8; 32]);
+
+    impl DaemonToken {
+        /// Generate a fresh random token.
+        pub fn new(rng: &mut (impl Rng + CryptoRng)) -> Self {
+            Self(rng.gen())
+        }
+
+        /// Read a token from a file, as written by [`DaemonToken::write`].
+        pub fn read(path: &Path) -> Result<Self, anyhow::Error> {
+            let contents = std::fs::read_to_string(path)?;
+            let mut token = [0; 32];
+            hex::decode_to_slice(contents.trim(), &mut token)?;
+            Ok(Self(token))
+        }
+
+        /// Write this token to a file, replacing any existing token.
+        pub fn write(&self, path: &Path) -> Result<(), anyhow::Error> {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, hex::encode(&self.0))?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+            }
+            Ok(())
+        }
+    }
+
+    impl fmt::Debug for DaemonToken {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            f.write_str("DaemonToken(..)")
+        }
+    }
 
+    /// The control protocol of the customer daemon: the daemon identifies itself, then the client
+    /// authenticates and issues a command.
     pub type Daemon = Session! {
+        recv DaemonIdentity;
+        send DaemonToken;
+        DaemonCommand;
+    };
+
+    pub type DaemonCommand = Session! {
         choose {
             // Refresh
             0 => Done,
+            // Pay
+            1 => ProxyPay,
+            // Status
+            2 => DaemonStatus,
+            // Trigger close
+            3 => TriggerClose,
+        }
+    };
+
+    /// A payment for the daemon to make on a client's behalf.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct PayRequest {
+        pub label: ChannelName,
+        pub payment_amount: PaymentAmount,
+        pub note: String,
+        pub payment_id: PaymentId,
+    }
+
+    /// A milestone reached by a payment in progress.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    pub enum PayProgress {
+        /// Connected to the merchant, either over an idle connection that was kept open or over a
+        /// new one.
+        Connected { reused: bool },
+        /// The merchant approved the payment.
+        Approved,
+        /// The channel state has been updated to reflect the payment.
+        Paid,
+    }
+
+    impl Display for PayProgress {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            match self {
+                PayProgress::Connected { reused: true } => {
+                    write!(f, "Connected to merchant (reusing open connection)")
+                }
+                PayProgress::Connected { reused: false } => write!(f, "Connected to merchant"),
+                PayProgress::Approved => write!(f, "Payment approved by merchant"),
+                PayProgress::Paid => write!(f, "Channel updated with payment"),
+            }
+        }
+    }
+
+    /// Make a payment through the daemon, which reports progress until the payment finishes, then
+    /// sends either the merchant's response note or a description of what went wrong.
+    pub type ProxyPay = Session! {
+        send PayRequest;
+        loop {
+            offer {
+                0 => recv PayProgress,
+                1 => {
+                    recv Result<Option<String>, String>;
+                    break;
+                }
+            }
+        }
+    };
+
+    /// Ask the daemon to make a payment, passing each report of its progress to `progress`, and
+    /// return the merchant's response note, if any.
+    #[Transmitter(Tx for PayRequest)]
+    #[Receiver(Rx for PayProgress, Result<Option<String>, String>)]
+    pub async fn request_proxy_pay<Tx, Rx>(
+        chan: Chan<ProxyPay, Tx, Rx>,
+        request: PayRequest,
+        mut progress: impl FnMut(PayProgress),
+    ) -> Result<Option<String>, anyhow::Error>
+    where
+        Tx::Error: std::error::Error + Send + Sync + 'static,
+        Rx::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let mut chan = chan
+            .send(request)
+            .await
+            .context("Failed to send payment request to daemon")?;
+
+        let result = loop {
+            chan = offer!(in chan {
+                0 => {
+                    let (report, chan) = chan
+                        .recv()
+                        .await
+                        .context("Failed to receive payment progress from daemon")?;
+                    progress(report);
+                    chan
+                }
+                1 => {
+                    let (result, chan) = chan
+                        .recv()
+                        .await
+                        .context("Failed to receive payment result from daemon")?;
+                    chan.close();
+                    break result;
+                }
+            })
+            .context("Failed to receive payment progress from daemon")?;
+        };
+
+        result
+            .map_err(anyhow::Error::msg)
+            .context("Daemon failed to make payment")
+    }
+
+    /// Make a payment requested by a client of the daemon, by running `pay` for the request, and
+    /// forward the progress `pay` reports to the client while it runs.
+    #[Transmitter(Tx for PayProgress, Result<Option<String>, String>)]
+    #[Receiver(Rx for PayRequest)]
+    pub async fn serve_proxy_pay<Tx, Rx, F, Fut>(
+        chan: Chan<<ProxyPay as Session>::Dual, Tx, Rx>,
+        pay: F,
+    ) -> Result<(), anyhow::Error>
+    where
+        Tx::Error: std::error::Error + Send + Sync + 'static,
+        Rx::Error: std::error::Error + Send + Sync + 'static,
+        F: FnOnce(PayRequest, mpsc::UnboundedSender<PayProgress>) -> Fut,
+        Fut: Future<Output = Result<Option<String>, anyhow::Error>>,
+    {
+        let (request, mut chan) = chan
+            .recv()
+            .await
+            .context("Failed to receive payment request")?;
+
+        // Progress is reported over a channel so that it can be forwarded to the client while the
+        // payment is still in progress
+        let (progress_sender, mut progress_receiver) = mpsc::unbounded_channel();
+        let payment = pay(request, progress_sender);
+        tokio::pin!(payment);
+
+        let result = loop {
+            tokio::select! {
+                Some(progress) = progress_receiver.recv() => {
+                    chan = chan
+                        .choose::<0>()
+                        .await
+                        .context("Failed to select payment progress")?
+                        .send(progress)
+                        .await
+                        .context("Failed to send payment progress")?;
+                }
+                result = &mut payment => break result,
+            }
+        };
+
+        // Forward any progress reported after the last that was forwarded
+        while let Ok(progress) = progress_receiver.try_recv() {
+            chan = chan
+                .choose::<0>()
+                .await
+                .context("Failed to select payment progress")?
+                .send(progress)
+                .await
+                .context("Failed to send payment progress")?;
         }
+
+        chan.choose::<1>()
+            .await
+            .context("Failed to select payment result")?
+            .send(result.map_err(|e| format!("{:#}", e)))
+            .await
+            .context("Failed to send payment result")?
+            .close();
+
+        Ok(())
+    }
+
+    /// What the daemon knows about a channel.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct ChannelSummary {
+        pub label: ChannelName,
+        pub state: StateName,
+        pub customer_balance: CustomerBalance,
+        pub merchant_balance: MerchantBalance,
+        pub contract_id: Option<ContractId>,
+        /// What the daemon last saw of the channel's contract, if it has checked it yet.
+        pub last_observation: Option<ContractObservation>,
+        /// The entrypoints for which operations are being posted for the channel.
+        pub operations_in_flight: Vec<Entrypoint>,
+    }
+
+    /// Get a summary of every channel from the daemon.
+    pub type DaemonStatus = Session! {
+        recv Vec<ChannelSummary>;
+    };
+
+    /// Ask the daemon to check a channel's contract right away, rather than at its next pass, and
+    /// take whatever action closing the channel requires. The daemon sends back the time at which
+    /// the channel next needs attention, if it is waiting on a deadline, or a description of what
+    /// went wrong.
+    pub type TriggerClose = Session! {
+        send ChannelName;
+        recv Result<Option<SystemTime>, String>;
     };
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use rand::{rngs::StdRng, SeedableRng};
+
+        #[test]
+        fn refuse_wrong_daemon() {
+            let ours = DaemonIdentity::new(&DatabaseLocation::Sqlite("alice/customer.db".into()));
+            let theirs = DaemonIdentity::new(&DatabaseLocation::Sqlite("bob/customer.db".into()));
+
+            assert!(ours.verify(&ours).is_ok());
+            let err = theirs.verify(&ours).unwrap_err();
+            assert_eq!(err.expected, ours);
+            assert_eq!(err.actual, theirs);
+            assert!(err.to_string().contains(&ours.to_string()));
+            assert!(err.to_string().contains(&theirs.to_string()));
+        }
+
+        #[test]
+        fn daemon_identity_ignores_relative_paths() {
+            let dir = std::env::temp_dir();
+            let direct = DaemonIdentity::new(&DatabaseLocation::Sqlite(dir.join("customer.db")));
+            let indirect =
+                DaemonIdentity::new(&DatabaseLocation::Sqlite(dir.join(".").join("customer.db")));
+            assert_eq!(direct, indirect);
+        }
+
+        #[test]
+        fn default_ports_differ_by_database() {
+            let ports: std::collections::HashSet<u16> = (0..16)
+                .map(|i| {
+                    let location = DatabaseLocation::Sqlite(format!("{}/customer.db", i).into());
+                    DaemonIdentity::new(&location).default_port()
+                })
+                .collect();
+
+            assert!(ports.iter().all(|&port| port >= DYNAMIC_PORT_START));
+            assert!(ports.len() > 1);
+        }
+
+        #[test]
+        fn daemon_token_round_trip() {
+            let path = std::env::temp_dir().join(format!("daemon-token-{}", std::process::id()));
+            let token = DaemonToken::new(&mut StdRng::seed_from_u64(0));
+
+            token.write(&path).unwrap();
+            let read = DaemonToken::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert!(token == read);
+        }
+    }
 }
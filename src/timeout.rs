@@ -1,4 +1,7 @@
-use {async_trait::async_trait, futures::Future, std::time::Duration, tokio::time::Timeout};
+use {
+    async_trait::async_trait, futures::Future, std::time::Duration, thiserror::Error,
+    tokio::time::Timeout,
+};
 
 #[async_trait]
 pub trait WithTimeout {
@@ -15,3 +18,64 @@ where
         tokio::time::timeout(duration, self)
     }
 }
+
+/// The other party to a session didn't send a response within the time allowed for it.
+#[derive(Debug, Clone, Copy, Error)]
+#[error("Timed out after {timeout:?} waiting for {awaiting}")]
+pub struct ResponseTimeout {
+    pub awaiting: &'static str,
+    pub timeout: Duration,
+}
+
+/// Wait at most `timeout` for a response from the other party to a session, failing with a
+/// [`ResponseTimeout`] for the response described by `awaiting` if it doesn't arrive in time.
+///
+/// Only the wait for the response is timed, so any computation of our own before or after it
+/// never counts against the timeout.
+pub async fn await_response<T>(
+    timeout: Duration,
+    awaiting: &'static str,
+    response: impl Future<Output = Result<T, anyhow::Error>>,
+) -> Result<T, anyhow::Error> {
+    response
+        .with_timeout(timeout)
+        .await
+        .map_err(|_| ResponseTimeout { awaiting, timeout })?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn stalled_response_times_out() {
+        // A party which stalls at any point in a session is given up on
+        for awaiting in ["closing signature", "payment token"] {
+            let (_stalled, response) = oneshot::channel::<()>();
+            let error = await_response(Duration::from_millis(50), awaiting, async {
+                Ok::<_, anyhow::Error>(response.await?)
+            })
+            .await
+            .unwrap_err();
+            let timeout = error.downcast_ref::<ResponseTimeout>().unwrap();
+            assert_eq!(timeout.awaiting, awaiting);
+        }
+    }
+
+    #[tokio::test]
+    async fn own_computation_is_not_timed() {
+        // The other party responds at once, but it takes us longer than the timeout to get round
+        // to waiting for the response
+        let (respond, response) = oneshot::channel();
+        respond.send(42).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let received = await_response(Duration::from_millis(50), "response", async {
+            Ok::<_, anyhow::Error>(response.await?)
+        })
+        .await
+        .unwrap();
+        assert_eq!(received, 42);
+    }
+}
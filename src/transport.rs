@@ -1,6 +1,9 @@
+mod backoff;
 mod channel;
 pub mod client;
+pub mod client_auth;
 mod handshake;
 pub mod io_stream;
+pub mod message_length;
 pub mod pem;
 pub mod server;
@@ -0,0 +1,93 @@
+//! How long a client waits between attempts to reconnect to a server, and when it gives up.
+
+use {dialectic_reconnect::retry::Recovery, std::convert::TryFrom, std::time::Duration};
+
+/// The longest delay between attempts, however the delay grows.
+const LONGEST_DELAY: Duration = Duration::from_secs(u32::MAX as u64);
+
+/// A strategy for reconnecting to a server: a delay before each attempt which starts at an initial
+/// delay and is multiplied after every attempt, up to a maximum delay, for up to some number of
+/// attempts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    initial_delay: Duration,
+    multiplier: f64,
+    max_delay: Option<Duration>,
+    max_retries: Option<usize>,
+}
+
+impl Backoff {
+    /// Wait the same `delay` before every attempt, making as many attempts as it takes.
+    pub fn with_delay(delay: Duration) -> Self {
+        Self {
+            initial_delay: delay,
+            multiplier: 1.0,
+            max_delay: None,
+            max_retries: None,
+        }
+    }
+
+    /// Multiply the delay by `multiplier` after each attempt. The multiplier is never less than 1,
+    /// which keeps the delay constant.
+    pub fn multiplier(&mut self, multiplier: f64) -> &mut Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// Never wait longer than `max_delay` between attempts (the default is `None`, for no limit).
+    pub fn max_delay(&mut self, max_delay: Option<Duration>) -> &mut Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Give up after `max_retries` attempts to reconnect.
+    pub fn max_retries(&mut self, max_retries: usize) -> &mut Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+
+    /// The delay before the attempt after the given number of retries.
+    pub fn delay(&self, retries: usize) -> Duration {
+        if self.initial_delay == Duration::ZERO {
+            return Duration::ZERO;
+        }
+        let exponent = i32::try_from(retries).unwrap_or(i32::MAX);
+        let delay = self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent);
+        let max_delay = self.max_delay.unwrap_or(LONGEST_DELAY).min(LONGEST_DELAY);
+        Duration::from_secs_f64(delay.min(max_delay.as_secs_f64()))
+    }
+
+    /// How to recover from a failure after the given number of retries.
+    pub(crate) fn recovery(&self, retries: usize) -> Recovery {
+        if self.max_retries.map_or(false, |max| retries >= max) {
+            Recovery::Fail
+        } else {
+            Recovery::ReconnectAfter(self.delay(retries))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_grows_up_to_max_delay() {
+        let mut backoff = Backoff::with_delay(Duration::from_millis(100));
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(10), Duration::from_millis(100));
+
+        backoff
+            .multiplier(2.0)
+            .max_delay(Some(Duration::from_secs(1)));
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(1), Duration::from_millis(200));
+        assert_eq!(backoff.delay(3), Duration::from_millis(800));
+        assert_eq!(backoff.delay(4), Duration::from_secs(1));
+        assert_eq!(backoff.delay(usize::MAX), Duration::from_secs(1));
+
+        // Without a maximum delay, the delay is still bounded
+        backoff.max_delay(None);
+        assert_eq!(backoff.delay(usize::MAX), LONGEST_DELAY);
+    }
+}
@@ -4,13 +4,10 @@ use {
     dialectic_tokio_serde::{codec::LengthDelimitedCodec, Receiver, Sender, SymmetricalError},
     dialectic_tokio_serde_bincode::Bincode,
     std::io,
-    tokio::{
-        io::{ReadHalf, WriteHalf},
-        net::TcpStream,
-    },
-    tokio_rustls::webpki::DNSName,
+    tokio::io::{ReadHalf, WriteHalf},
 };
 
+use super::client::ServerAddress;
 use super::handshake::{Handshake, SessionKey};
 use super::io_stream::IoStream;
 
@@ -25,18 +22,46 @@ pub type ServerChan<S> =
 /// serialization.
 ///
 /// The session type parameter for this channel is the session from **the client's perspective.**
-pub type ClientChan<S> = RetrySplitChan<
-    S,
+pub type ClientChan<S> = Chan<S, ClientSender, ClientReceiver>;
+
+/// The sending end of a [`ClientChan`], which outlives any one session over it.
+pub(crate) type ClientSender = RetrySplitSender<
+    SessionKey,
+    Handshake,
+    ServerAddress,
+    io::Error,
+    SymmetricalError<Bincode, LengthDelimitedCodec>,
+    Bincode,
+    LengthDelimitedCodec,
+    IoStream,
+>;
+
+/// The receiving end of a [`ClientChan`], which outlives any one session over it.
+pub(crate) type ClientReceiver = RetrySplitReceiver<
     SessionKey,
     Handshake,
-    (DNSName, u16),
+    ServerAddress,
     io::Error,
     SymmetricalError<Bincode, LengthDelimitedCodec>,
     Bincode,
     LengthDelimitedCodec,
-    tokio_rustls::client::TlsStream<TcpStream>,
+    IoStream,
 >;
 
+/// Take the sending and receiving ends out of a channel which was just created, so that sessions
+/// can be run over them one after another.
+pub(crate) fn into_ends<S, Tx, Rx>(chan: Chan<S, Tx, Rx>) -> (Tx, Rx)
+where
+    S: Session,
+    Tx: Send + 'static,
+    Rx: Send + 'static,
+{
+    match chan.unwrap() {
+        (Ok(tx), Ok(rx)) => (tx, rx),
+        _ => unreachable!("A new channel always owns both of its ends"),
+    }
+}
+
 /// An error in the underlying non-resuming transport.
 pub type TransportError = SymmetricalError<Bincode, LengthDelimitedCodec>;
 
@@ -52,12 +77,6 @@ pub type TransportError = SymmetricalError<Bincode, LengthDelimitedCodec>;
 type ResumeSplitChan<S, K, F, E, T> =
     Chan<S, ResumeSplitSender<K, F, E, T>, ResumeSplitReceiver<K, F, E, T>>;
 
-type RetrySplitChan<S, K, H, A, CErr, HErr, F, E, T> = Chan<
-    S,
-    RetrySplitSender<K, H, A, CErr, HErr, F, E, T>,
-    RetrySplitReceiver<K, H, A, CErr, HErr, F, E, T>,
->;
-
 type ResumeSplitSender<K, F, E, T> =
     resume::Sender<K, SplitSender<F, E, T>, SplitReceiver<F, E, T>>;
 type ResumeSplitReceiver<K, F, E, T> =
@@ -8,12 +8,15 @@ use {
     dialectic_tokio_serde_bincode::{length_delimited, Bincode},
     http::uri::{InvalidUri, Uri},
     std::{
+        collections::HashMap,
         fmt::{self, Display},
+        future::Future,
         io,
         marker::PhantomData,
+        path::{Path, PathBuf},
         str::FromStr,
-        sync::Arc,
-        time::Duration,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
     },
     thiserror::Error,
     tokio::net::TcpStream,
@@ -21,14 +24,20 @@ use {
     webpki::{DNSNameRef, InvalidDNSNameError},
 };
 
-use super::{channel::TransportError, handshake};
+use super::{
+    channel::{self, ClientReceiver, ClientSender, TransportError},
+    client_auth,
+    handshake::{self, Reuse},
+    io_stream::IoStream,
+    pem,
+};
 use crate::customer;
 
-#[cfg(feature = "allow_explicit_certificate_trust")]
-use {super::pem, std::path::Path};
+#[cfg(unix)]
+use tokio::net::UnixStream;
 
+pub use super::backoff::Backoff;
 pub use super::channel::ClientChan as Chan;
-pub use dialectic_reconnect::Backoff;
 pub use handshake::SessionKey;
 
 /// The type of errors returned during sessions on a client-side channel.
@@ -39,6 +48,13 @@ compile_error!(
     "crate cannot be built for release with the `allow_explicit_certificate_trust` feature enabled"
 );
 
+#[cfg(all(not(debug_assertions), feature = "dev-insecure"))]
+compile_error!("crate cannot be built for release with the `dev-insecure` feature enabled");
+
+/// How long to wait for a server to start another session over a connection taken from a
+/// [`ConnectionCache`], before giving up on that connection and making a new one.
+const REUSE_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// A client for some session-typed `Protocol` which connects over TLS with a parameterizable
 /// [`Backoff`] strategy for retrying lost connections.
 ///
@@ -60,6 +76,10 @@ pub struct Client<Protocol> {
     timeout: Option<Duration>,
     /// Client TLS configuration.
     tls_config: rustls::ClientConfig,
+    /// Whether to wrap connections in TLS.
+    use_tls: bool,
+    /// Where to keep connections between sessions, if they are kept at all.
+    reuse: Option<ReuseConnections>,
     /// Client session type.
     client_session: PhantomData<fn() -> Protocol>,
 }
@@ -82,8 +102,10 @@ where
             max_length: usize::MAX,
             backoff,
             tls_config,
+            use_tls: true,
             max_pending_retries: usize::MAX,
             timeout: None,
+            reuse: None,
             client_session: PhantomData,
         }
     }
@@ -123,6 +145,15 @@ where
         self
     }
 
+    /// Connect over plain TCP, without TLS.
+    ///
+    /// This is only appropriate for connections that never leave the local machine, like those to
+    /// the customer daemon, which authenticates its clients by other means.
+    pub fn disable_tls(&mut self) -> &mut Self {
+        self.use_tls = false;
+        self
+    }
+
     // Only on non-release builds that explicitly request this capability via the
     // `allow_explicit_certificate_trust` feature, add the auxiliary trusted certificate to the set
     // of trusted certificates. In release builds, it is not possible for the client to trust anyone
@@ -139,6 +170,61 @@ where
         Ok(self)
     }
 
+    // Only on non-release builds that explicitly request this capability via the `dev-insecure`
+    // feature, accept any certificate the server presents, without checking it at all. The
+    // connection is then encrypted, but not authenticated.
+    #[cfg(feature = "dev-insecure")]
+    pub fn allow_invalid_certificates(&mut self) -> &mut Self {
+        self.tls_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(AcceptAnyCertificate));
+        self
+    }
+
+    /// Present the certificate chain at `certificate_chain_path`, with the private key at
+    /// `private_key_path`, to servers which authenticate their clients.
+    ///
+    /// If a server refuses the certificate, or requires one when none is set, connecting fails
+    /// with a [`ClientCertificateError`](client_auth::ClientCertificateError).
+    pub fn client_certificate(
+        &mut self,
+        certificate_chain_path: impl AsRef<Path>,
+        private_key_path: impl AsRef<Path>,
+    ) -> Result<&mut Self, io::Error> {
+        let certificate_chain = pem::read_certificates(certificate_chain_path)?;
+        let private_key = pem::read_private_key(private_key_path)?;
+        self.tls_config
+            .set_single_client_cert(certificate_chain, private_key)
+            .map_err(|_error| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid client certificate chain or private key",
+                )
+            })?;
+        Ok(self)
+    }
+
+    /// Keep each connection open in `cache` for up to `idle_timeout` after a session run with
+    /// [`session`](Client::session) finishes, so that the next session with the same server,
+    /// however it is started, can use it rather than making a new connection. At most `max_idle`
+    /// connections are kept, and the one idle the longest makes way for another.
+    ///
+    /// A kept connection which turns out to be dead is replaced by a new one; the server must keep
+    /// connections open for longer than `idle_timeout` for any of them to be used.
+    pub fn reuse_connections(
+        &mut self,
+        cache: ConnectionCache,
+        idle_timeout: Duration,
+        max_idle: usize,
+    ) -> &mut Self {
+        self.reuse = Some(ReuseConnections {
+            cache,
+            idle_timeout,
+            max_idle,
+        });
+        self
+    }
+
     pub async fn connect_zkchannel(
         &self,
         ZkChannelAddress { host, port }: &ZkChannelAddress,
@@ -149,52 +235,212 @@ where
 
     /// Connect to the given [`DNSName`] and port, returning either a connected [`Chan`] or an
     /// error if connection and all re-connection attempts failed.
+    ///
+    /// If connections are [reused](Client::reuse_connections), this takes one which was kept open
+    /// for the server, if there is one.
     pub async fn connect(
         &self,
         host: &DNSName,
         port: u16,
     ) -> Result<(SessionKey, Chan<Protocol>), Error> {
+        let (session_key, tx, rx, _) = self.open(host, port).await?;
+        Ok((session_key, Protocol::wrap(tx, rx)))
+    }
+
+    /// Run a session with the server at the given [`ZkChannelAddress`], as
+    /// [`session`](Client::session) does.
+    pub async fn session_zkchannel<T, E, F, Fut>(
+        &self,
+        ZkChannelAddress { host, port }: &ZkChannelAddress,
+        session: F,
+    ) -> Result<T, E>
+    where
+        F: FnOnce(SessionKey, Chan<Protocol>) -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: From<Error>,
+    {
+        let port = port.unwrap_or_else(customer::defaults::port);
+        self.session(host, port, session).await
+    }
+
+    /// Run a session with the server at the given [`ZkChannelAddress`], as
+    /// [`session_noting_reuse`](Client::session_noting_reuse) does.
+    pub async fn session_zkchannel_noting_reuse<T, E, F, Fut>(
+        &self,
+        ZkChannelAddress { host, port }: &ZkChannelAddress,
+        session: F,
+    ) -> Result<T, E>
+    where
+        F: FnOnce(SessionKey, Chan<Protocol>, bool) -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: From<Error>,
+    {
+        let port = port.unwrap_or_else(customer::defaults::port);
+        self.session_noting_reuse(host, port, session).await
+    }
+
+    /// Run a session with the server at the given [`DNSName`] and port, connecting as
+    /// [`connect`](Client::connect) does.
+    ///
+    /// If connections are [reused](Client::reuse_connections) and the session runs to completion,
+    /// the connection is kept open afterwards for the next session with the same server.
+    pub async fn session<T, E, F, Fut>(&self, host: &DNSName, port: u16, session: F) -> Result<T, E>
+    where
+        F: FnOnce(SessionKey, Chan<Protocol>) -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: From<Error>,
+    {
+        self.session_noting_reuse(host, port, |session_key, chan, _| {
+            session(session_key, chan)
+        })
+        .await
+    }
+
+    /// Run a session with the server at the given [`DNSName`] and port, as
+    /// [`session`](Client::session) does, telling the session whether it runs over a connection
+    /// which was kept open from an earlier session.
+    pub async fn session_noting_reuse<T, E, F, Fut>(
+        &self,
+        host: &DNSName,
+        port: u16,
+        session: F,
+    ) -> Result<T, E>
+    where
+        F: FnOnce(SessionKey, Chan<Protocol>, bool) -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: From<Error>,
+    {
+        let (session_key, tx, rx, reused) = self.open(host, port).await?;
+        let (output, ends) =
+            Protocol::over(tx, rx, |chan| session(session_key, chan, reused)).await?;
+        if let (Some(reuse), Ok((tx, rx))) = (&self.reuse, ends) {
+            reuse.put((host.to_owned(), port), tx, rx);
+        }
+        Ok(output)
+    }
+
+    /// Get the ends of a connection to the given [`DNSName`] and port on which to start a new
+    /// session, reusing a kept connection if there is a live one, and whether it was reused.
+    async fn open(
+        &self,
+        host: &DNSName,
+        port: u16,
+    ) -> Result<(SessionKey, ClientSender, ClientReceiver, bool), Error> {
+        if let Some(reuse) = &self.reuse {
+            if let Some((tx, rx)) = reuse.take(&(host.to_owned(), port)) {
+                let reused =
+                    <Reuse as Session>::over(tx, rx, handshake::client::reuse::<_, _, Error>);
+                match tokio::time::timeout(REUSE_TIMEOUT, reused).await {
+                    Ok(Ok((session_key, Ok((tx, rx))))) => return Ok((session_key, tx, rx, true)),
+                    // The server closed the connection, or it was lost while it was idle
+                    _ => tracing::debug!(
+                        host = AsRef::<str>::as_ref(host),
+                        port,
+                        "Kept connection to server is dead; making a new one"
+                    ),
+                }
+            }
+        }
+        let (session_key, tx, rx) = self
+            .connect_to(ServerAddress::Tcp(host.to_owned(), port))
+            .await?;
+        Ok((session_key, tx, rx, false))
+    }
+
+    /// Connect to the Unix socket at the given path, returning either a connected [`Chan`] or an
+    /// error if connection and all re-connection attempts failed.
+    ///
+    /// The connection is never wrapped in TLS, since it never leaves this machine. Unix sockets are
+    /// only supported on Unix.
+    pub async fn connect_unix(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<(SessionKey, Chan<Protocol>), Error> {
+        let (session_key, tx, rx) = self
+            .connect_to(ServerAddress::Unix(path.as_ref().to_owned()))
+            .await?;
+        Ok((session_key, Protocol::wrap(tx, rx)))
+    }
+
+    /// Make a new connection to the given address, returning the ends of the connection on which
+    /// to start a session.
+    async fn connect_to(
+        &self,
+        address: ServerAddress,
+    ) -> Result<(SessionKey, ClientSender, ClientReceiver), Error> {
         // Share the TLS config between all times we connect
-        let tls_config = Arc::new(self.tls_config.clone());
+        let tls_config = if self.use_tls {
+            Some(Arc::new(self.tls_config.clone()))
+        } else {
+            None
+        };
 
         // Address configuration
         let length_field_bytes = self.length_field_bytes;
         let max_length = self.max_length;
 
         // A closure that connects to the server we want to connect to
-        let connect = move |(domain, port): (DNSName, u16)| {
+        let connect = move |address: ServerAddress| {
             let tls_config = tls_config.clone();
             async move {
-                // Resolve the domain name we wish to connect to
-                let address_str: &str = AsRef::as_ref(&domain);
-                let mut addresses = tokio::net::lookup_host((address_str, port)).await?;
-
-                // Attempt to connect to any of the socket addresses, succeeding on the first
-                let mut connection_error = None;
-                let tcp_stream = loop {
-                    if let Some(address) = addresses.next() {
-                        match TcpStream::connect(address).await {
-                            Ok(tcp_stream) => {
-                                // Session typed messages may be small; send them immediately
-                                tcp_stream.set_nodelay(true)?;
-                                break tcp_stream;
+                let io_stream = match address {
+                    ServerAddress::Tcp(domain, port) => {
+                        // Resolve the domain name we wish to connect to
+                        let address_str: &str = AsRef::as_ref(&domain);
+                        let mut addresses = tokio::net::lookup_host((address_str, port)).await?;
+
+                        // Attempt to connect to any of the socket addresses, succeeding on the
+                        // first
+                        let mut connection_error = None;
+                        let tcp_stream = loop {
+                            if let Some(address) = addresses.next() {
+                                match TcpStream::connect(address).await {
+                                    Ok(tcp_stream) => {
+                                        // Session typed messages may be small; send them
+                                        // immediately
+                                        tcp_stream.set_nodelay(true)?;
+                                        break tcp_stream;
+                                    }
+                                    Err(e) => connection_error = Some(e),
+                                }
+                            } else {
+                                return Err(connection_error.unwrap_or_else(|| {
+                                    io::Error::new(
+                                        io::ErrorKind::NotFound,
+                                        format!("unknown domain: {}", address_str),
+                                    )
+                                }));
+                            }
+                        };
+
+                        // Wrap a TCP stream in a TLS connection
+                        match tls_config {
+                            None => IoStream::from(tcp_stream),
+                            Some(tls_config) => {
+                                let tls_connector = TlsConnector::from(tls_config);
+                                IoStream::from(
+                                    tls_connector
+                                        .connect(domain.as_ref(), tcp_stream)
+                                        .await
+                                        .map_err(client_auth::explain_client_error)?,
+                                )
                             }
-                            Err(e) => connection_error = Some(e),
                         }
-                    } else {
-                        return Err(connection_error.unwrap_or_else(|| {
-                            io::Error::new(
-                                io::ErrorKind::NotFound,
-                                format!("unknown domain: {}", address_str),
-                            )
-                        }));
+                    }
+                    // A Unix socket never leaves this machine, so isn't wrapped in TLS
+                    #[cfg(unix)]
+                    ServerAddress::Unix(path) => IoStream::from(UnixStream::connect(path).await?),
+                    #[cfg(not(unix))]
+                    ServerAddress::Unix(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Unsupported,
+                            "Unix sockets are not supported on this platform",
+                        ))
                     }
                 };
 
-                // Wrap a TCP stream in a TLS connection, then wrap that in a Dialectic channel
-                let tls_connector = TlsConnector::from(tls_config);
-                let tls_stream = tls_connector.connect(domain.as_ref(), tcp_stream).await?;
-                let (rx, tx) = tokio::io::split(tls_stream);
+                // Wrap the stream in a Dialectic channel
+                let (rx, tx) = tokio::io::split(io_stream);
                 let (tx, rx) = length_delimited(tx, rx, length_field_bytes, max_length);
                 Ok((tx, rx))
             }
@@ -212,8 +458,12 @@ where
         .recover_handshake(reconnect_unless(&self.backoff, permanent_handshake_error))
         .timeout(self.timeout)
         .max_pending_retries(self.max_pending_retries)
-        .connect((host.to_owned(), port))
+        .connect(address)
         .await
+        .map(|(session_key, chan)| {
+            let (tx, rx) = channel::into_ends(chan);
+            (session_key, tx, rx)
+        })
         .map_err(|e| {
             // Convert error into general error type
             use retry::RetryError::*;
@@ -230,6 +480,90 @@ where
     }
 }
 
+/// Connections to servers kept open after their sessions finish, for the next session with the
+/// same server. Clones of a cache share the connections kept in it.
+#[derive(Clone, Default)]
+pub struct ConnectionCache {
+    /// At most one idle connection per server, keyed by the server's domain name and port.
+    idle: Arc<Mutex<HashMap<(DNSName, u16), IdleConnection>>>,
+}
+
+impl ConnectionCache {
+    /// Close the kept connections which have been idle for at least the given time.
+    pub fn expire(&self, idle_timeout: Duration) {
+        self.idle
+            .lock()
+            .unwrap()
+            .retain(|_, connection| connection.since.elapsed() < idle_timeout);
+    }
+}
+
+/// A connection whose session finished, with the time it finished.
+struct IdleConnection {
+    since: Instant,
+    tx: ClientSender,
+    rx: ClientReceiver,
+}
+
+/// How a [`Client`] reuses connections.
+#[derive(Clone)]
+struct ReuseConnections {
+    cache: ConnectionCache,
+    idle_timeout: Duration,
+    max_idle: usize,
+}
+
+impl ReuseConnections {
+    /// Take the connection kept for the given server, if it hasn't been idle too long.
+    fn take(&self, server: &(DNSName, u16)) -> Option<(ClientSender, ClientReceiver)> {
+        let connection = self.cache.idle.lock().unwrap().remove(server)?;
+        if connection.since.elapsed() < self.idle_timeout {
+            Some((connection.tx, connection.rx))
+        } else {
+            None
+        }
+    }
+
+    /// Keep a connection for the given server, in place of any connection already kept for it,
+    /// closing those which have been idle too long and, if there are too many, the one which has
+    /// been idle the longest.
+    fn put(&self, server: (DNSName, u16), tx: ClientSender, rx: ClientReceiver) {
+        let mut idle = self.cache.idle.lock().unwrap();
+        idle.remove(&server);
+        idle.retain(|_, connection| connection.since.elapsed() < self.idle_timeout);
+        if idle.len() >= self.max_idle {
+            let longest_idle = idle
+                .iter()
+                .min_by_key(|(_, connection)| connection.since)
+                .map(|(server, _)| server.clone());
+            match longest_idle {
+                Some(longest_idle) => {
+                    idle.remove(&longest_idle);
+                }
+                // No connections are kept at all
+                None => return,
+            }
+        }
+        idle.insert(
+            server,
+            IdleConnection {
+                since: Instant::now(),
+                tx,
+                rx,
+            },
+        );
+    }
+}
+
+/// Where a [`Client`] connects to a server.
+#[derive(Debug, Clone)]
+pub enum ServerAddress {
+    /// A domain name and port, over TCP.
+    Tcp(DNSName, u16),
+    /// The path of a Unix socket. This is only supported on Unix.
+    Unix(PathBuf),
+}
+
 /// The address of a zkChannels merchant: a URI of the form `zkchannel://some.domain.com:2611` with
 /// an optional port number.
 #[derive(Debug, Clone, serde_with::SerializeDisplay, serde_with::DeserializeFromStr)]
@@ -293,18 +627,17 @@ impl Display for ZkChannelAddress {
 }
 
 /// Given a backoff and a predicate on errors, return a reconnection strategy which uses that
-/// backoff unless the predicate returns true. This is marginally more efficient than rebuilding the
-/// backoff every time inside a closure.
+/// backoff unless the predicate returns true.
 fn reconnect_unless<E>(
     backoff: &Backoff,
     unless: impl Fn(&E) -> bool,
 ) -> impl Fn(usize, &E) -> retry::Recovery {
-    let backoff = backoff.build(retry::Recovery::ReconnectAfter);
+    let backoff = *backoff;
     move |retries, error| {
         if unless(error) {
             retry::Recovery::Fail
         } else {
-            backoff(retries, error)
+            backoff.recovery(retries)
         }
     }
 }
@@ -348,6 +681,23 @@ fn permanent_rx_error(error: &RecvError<Bincode, LengthDelimitedCodec>) -> bool
     })
 }
 
+/// A certificate verifier which accepts any certificate, for development only.
+#[cfg(feature = "dev-insecure")]
+struct AcceptAnyCertificate;
+
+#[cfg(feature = "dev-insecure")]
+impl rustls::ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        _presented_certs: &[rustls::Certificate],
+        _dns_name: DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
 /// Determine if a connecting error should be considered permanent.
 fn permanent_connect_error(error: &io::Error) -> bool {
     permanent_error_kind(&error.kind())
@@ -360,3 +710,337 @@ fn permanent_handshake_error(error: &TransportError) -> bool {
         dialectic_tokio_serde::Error::Recv(err) => permanent_rx_error(err),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{
+        client_auth::ClientAuth,
+        server::{self, Server},
+    };
+    use std::{
+        net::SocketAddr,
+        path::{Path, PathBuf},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// A session in which the client sends a number, and the server sends it back.
+    type Echo = Session! {
+        send u32;
+        recv u32;
+    };
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/transport/testdata")
+            .join(name)
+    }
+
+    /// Serve [`Echo`] over TLS with a self-signed certificate for `localhost`, authenticating
+    /// clients as given, for as long as the test runs.
+    fn serve_echo(client_auth: Option<ClientAuth>) -> SocketAddr {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let interact = |_session_key, (), chan: server::Chan<Echo>| async move {
+            let (number, chan) = chan.recv().await.map_err(|e| format!("{:?}", e))?;
+            chan.send(number)
+                .await
+                .map_err(|e| format!("{:?}", e))?
+                .close();
+            Ok::<_, String>(())
+        };
+
+        tokio::spawn(async move {
+            let (certificate, private_key) = (testdata("first.crt"), testdata("first.key"));
+            let mut server: Server<Echo> = Server::new();
+            server
+                .client_auth(client_auth)
+                .serve_while(
+                    address,
+                    Some((&certificate, &private_key)),
+                    || async { Some(()) },
+                    interact,
+                    futures::future::pending(),
+                )
+                .await
+        });
+
+        address
+    }
+
+    fn client() -> Client<Echo> {
+        let mut backoff = Backoff::with_delay(Duration::ZERO);
+        backoff.max_retries(0);
+        Client::new(backoff)
+    }
+
+    /// Echo a number over a new connection to the server at `address`, waiting for it to start
+    /// listening.
+    async fn echo(client: &Client<Echo>, address: SocketAddr) -> Result<u32, Error> {
+        let host = DNSNameRef::try_from_ascii_str("localhost").unwrap().into();
+        for _ in 0..100 {
+            match TcpStream::connect(address).await {
+                Ok(_) => break,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+        let (_, chan) = client.connect(&host, address.port()).await?;
+        let (number, chan) = chan.send(7).await.unwrap().recv().await.unwrap();
+        chan.close();
+        Ok(number)
+    }
+
+    #[tokio::test]
+    async fn self_signed_certificate_is_rejected() {
+        let address = serve_echo(None);
+        assert!(echo(&client(), address).await.is_err());
+    }
+
+    #[cfg(feature = "allow_explicit_certificate_trust")]
+    #[tokio::test]
+    async fn trusted_self_signed_certificate_is_accepted() {
+        let address = serve_echo(None);
+        let mut client = client();
+        client
+            .trust_explicit_certificate(testdata("first.crt"))
+            .unwrap();
+        assert_eq!(echo(&client, address).await.unwrap(), 7);
+
+        // Trusting one certificate doesn't trust others
+        let mut other = self::client();
+        other
+            .trust_explicit_certificate(testdata("second.crt"))
+            .unwrap();
+        assert!(echo(&other, address).await.is_err());
+    }
+
+    #[cfg(feature = "dev-insecure")]
+    #[tokio::test]
+    async fn invalid_certificate_is_accepted_when_allowed() {
+        let address = serve_echo(None);
+        let mut client = client();
+        client.allow_invalid_certificates();
+        assert_eq!(echo(&client, address).await.unwrap(), 7);
+    }
+
+    #[cfg(feature = "allow_explicit_certificate_trust")]
+    #[tokio::test]
+    async fn client_certificate_is_required() {
+        let address = serve_echo(Some(ClientAuth {
+            ca_path: testdata("client-ca.crt"),
+            required: true,
+        }));
+        let trusting_client = |certificate: Option<&str>| {
+            let mut client = client();
+            client
+                .trust_explicit_certificate(testdata("first.crt"))
+                .unwrap();
+            if let Some(name) = certificate {
+                client
+                    .client_certificate(
+                        testdata(&format!("{}.crt", name)),
+                        testdata(&format!("{}.key", name)),
+                    )
+                    .unwrap();
+            }
+            client
+        };
+
+        // A certificate issued by the client CA is accepted
+        assert_eq!(
+            echo(&trusting_client(Some("client")), address)
+                .await
+                .unwrap(),
+            7
+        );
+
+        // Missing and wrongly issued certificates are each reported as such
+        let missing = echo(&trusting_client(None), address).await.unwrap_err();
+        assert!(format!("{:?}", missing).contains(&format!(
+            "{:?}",
+            client_auth::ClientCertificateError::Missing
+        )));
+        let rejected = echo(&trusting_client(Some("other-client")), address)
+            .await
+            .unwrap_err();
+        assert!(format!("{:?}", rejected).contains(&format!(
+            "{:?}",
+            client_auth::ClientCertificateError::Rejected
+        )));
+    }
+
+    /// Serve [`Echo`] over plain TCP, keeping connections open for `idle_timeout` after each
+    /// session, and serving at most `max_sessions` sessions at once, for as long as the test runs.
+    /// Returns the address, and the number of connections the server has accepted once it is
+    /// listening, plus one for the connection it is waiting for.
+    async fn serve_echo_keeping_connections(
+        idle_timeout: Option<Duration>,
+        max_sessions: Option<usize>,
+    ) -> (SocketAddr, Arc<AtomicUsize>) {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let accepts = Arc::new(AtomicUsize::new(0));
+
+        let interact = |_session_key, (), chan: server::Chan<Echo>| async move {
+            let (number, chan) = chan.recv().await.map_err(|e| format!("{:?}", e))?;
+            chan.send(number)
+                .await
+                .map_err(|e| format!("{:?}", e))?
+                .close();
+            Ok::<_, String>(())
+        };
+        let initialize = {
+            let accepts = accepts.clone();
+            move || {
+                accepts.fetch_add(1, Ordering::SeqCst);
+                async { Some(()) }
+            }
+        };
+
+        tokio::spawn(async move {
+            let mut server: Server<Echo> = Server::new();
+            server
+                .idle_timeout(idle_timeout)
+                .max_concurrent_sessions(max_sessions)
+                .max_sessions_per_ip(max_sessions)
+                .serve_while(
+                    address,
+                    None,
+                    initialize,
+                    interact,
+                    futures::future::pending(),
+                )
+                .await
+        });
+
+        // The server waits for its first connection once it is listening
+        while accepts.load(Ordering::SeqCst) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        (address, accepts)
+    }
+
+    /// A client over plain TCP which keeps connections open between sessions.
+    fn reusing_client() -> Client<Echo> {
+        let mut client = client();
+        client.disable_tls().reuse_connections(
+            ConnectionCache::default(),
+            Duration::from_secs(10),
+            1,
+        );
+        client
+    }
+
+    /// Echo a number in a session, keeping the connection if the client reuses connections.
+    async fn echo_session(client: &Client<Echo>, port: u16, number: u32) -> Result<u32, Error> {
+        let host = DNSNameRef::try_from_ascii_str("localhost").unwrap().into();
+        client
+            .session(&host, port, |_session_key, chan| async move {
+                let (number, chan) = chan.send(number).await?.recv().await?;
+                chan.close();
+                Ok(number)
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn sequential_sessions_share_connection() {
+        let (address, accepts) =
+            serve_echo_keeping_connections(Some(Duration::from_secs(60)), None).await;
+        let client = reusing_client();
+
+        assert_eq!(echo_session(&client, address.port(), 1).await.unwrap(), 1);
+        assert_eq!(echo_session(&client, address.port(), 2).await.unwrap(), 2);
+
+        // A session started with `connect` uses the kept connection too
+        let host = DNSNameRef::try_from_ascii_str("localhost").unwrap().into();
+        let (_, chan) = client.connect(&host, address.port()).await.unwrap();
+        let (number, chan) = chan.send(3).await.unwrap().recv().await.unwrap();
+        chan.close();
+        assert_eq!(number, 3);
+
+        // One connection, and the server waiting for the next
+        assert_eq!(accepts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn idle_connection_does_not_hold_session() {
+        // A server which serves one session at a time, to one client address
+        let (address, accepts) =
+            serve_echo_keeping_connections(Some(Duration::from_secs(60)), Some(1)).await;
+        let reusing = reusing_client();
+        assert_eq!(echo_session(&reusing, address.port(), 1).await.unwrap(), 1);
+
+        // Another client is served while the first client's connection is idle...
+        let mut other = client();
+        other.disable_tls();
+        let echoed = tokio::time::timeout(
+            Duration::from_secs(10),
+            echo_session(&other, address.port(), 2),
+        )
+        .await
+        .expect("idle connection kept the next client waiting");
+        assert_eq!(echoed.unwrap(), 2);
+
+        // ...and the first client can still start another session over its connection
+        assert_eq!(echo_session(&reusing, address.port(), 3).await.unwrap(), 3);
+        assert_eq!(accepts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn dead_connection_is_replaced() {
+        // A server which closes each connection once its session finishes
+        let (address, accepts) = serve_echo_keeping_connections(None, None).await;
+        let client = reusing_client();
+
+        assert_eq!(echo_session(&client, address.port(), 1).await.unwrap(), 1);
+        assert_eq!(echo_session(&client, address.port(), 2).await.unwrap(), 2);
+
+        // Two connections, and the server waiting for the next
+        assert_eq!(accepts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn reconnect_gives_up_after_max_retries() {
+        // A server which accepts connections, but closes each one straight away
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let attempts = attempts.clone();
+            async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    attempts.fetch_add(1, Ordering::SeqCst);
+                    drop(stream);
+                }
+            }
+        });
+
+        let config: customer::Config = toml::from_str(
+            r#"
+            database = "ephemeral"
+            tezos_account = { alias = "alice" }
+
+            [reconnect]
+            initial_delay = "1ms"
+            multiplier = 2.0
+            max_retries = 3
+            "#,
+        )
+        .unwrap();
+        let mut client: Client<Echo> = Client::new(config.reconnect.backoff());
+        client.timeout(config.reconnect.timeout);
+
+        let host = DNSNameRef::try_from_ascii_str("localhost").unwrap().into();
+        assert!(client.connect(&host, address.port()).await.is_err());
+
+        // The first attempt, then three more
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+    }
+}
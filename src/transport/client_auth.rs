@@ -0,0 +1,77 @@
+//! Authenticating clients by TLS client certificates, for servers which only serve known clients.
+//!
+//! When a server refuses a client's certificate, the TLS layer on each end only reports a generic
+//! failure; the functions here pick out those failures so that both ends can say what went wrong.
+
+use {
+    std::{io, path::PathBuf},
+    thiserror::Error,
+    tokio_rustls::rustls::{internal::msgs::enums::AlertDescription, TLSError},
+};
+
+/// How a server authenticates its clients by their TLS certificates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientAuth {
+    /// The certificate authorities, in PEM format, which must have issued client certificates.
+    pub ca_path: PathBuf,
+    /// Whether to refuse clients which present no certificate, rather than serving them
+    /// anonymously. Clients which present a certificate not issued by the authorities are always
+    /// refused.
+    pub required: bool,
+}
+
+/// Why a server refused a client's certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum ClientCertificateError {
+    #[error("The server requires a client certificate, but none was presented")]
+    Missing,
+    #[error("The server rejected the client certificate")]
+    Rejected,
+}
+
+impl ClientCertificateError {
+    /// Determine if an error accepting a TLS connection on the server was the client's
+    /// certificate being refused.
+    pub fn from_accept_error(error: &io::Error) -> Option<Self> {
+        match tls_error(error)? {
+            TLSError::NoCertificatesPresented => Some(Self::Missing),
+            // The server verifies no other certificates than the client's
+            TLSError::WebPKIError(_) => Some(Self::Rejected),
+            _ => None,
+        }
+    }
+
+    /// Determine if an error on the client's end of a TLS connection was the server refusing the
+    /// client's certificate, which the client only learns by the alert the server sends.
+    pub fn from_client_error(error: &io::Error) -> Option<Self> {
+        match tls_error(error)? {
+            TLSError::AlertReceived(AlertDescription::CertificateRequired) => Some(Self::Missing),
+            TLSError::AlertReceived(
+                AlertDescription::BadCertificate
+                | AlertDescription::UnsupportedCertificate
+                | AlertDescription::CertificateRevoked
+                | AlertDescription::CertificateExpired
+                | AlertDescription::CertificateUnknown
+                | AlertDescription::UnknownCA,
+            ) => Some(Self::Rejected),
+            _ => None,
+        }
+    }
+}
+
+/// Replace an error on the client's end of a TLS connection which was caused by the server
+/// refusing the client's certificate with one which says so, leaving any other error as it is.
+///
+/// The replacement is a [`io::ErrorKind::PermissionDenied`] error, so that the client doesn't
+/// retry a connection which will only be refused again.
+pub(crate) fn explain_client_error(error: io::Error) -> io::Error {
+    match ClientCertificateError::from_client_error(&error) {
+        Some(refusal) => io::Error::new(io::ErrorKind::PermissionDenied, refusal),
+        None => error,
+    }
+}
+
+/// The TLS error underlying an I/O error from a TLS stream, if there is one.
+fn tls_error(error: &io::Error) -> Option<&TLSError> {
+    error.get_ref()?.downcast_ref::<TLSError>()
+}
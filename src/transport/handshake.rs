@@ -1,10 +1,15 @@
 //! The definitions of the handshake protocol used when starting new connections and resuming broken
 //! ones, and implementations of both the client and server side handshakes.
+//!
+//! A connection whose session finished can carry another session: the [`Reuse`] handshake gives
+//! each further session a fresh key of its own, while the connection keeps the key with which it
+//! is resumed.
 
 use {
     dialectic::prelude::*,
     dialectic_reconnect::resume,
     serde::{Deserialize, Serialize},
+    std::fmt::{self, Display, Formatter},
     uuid::Uuid,
 };
 
@@ -31,6 +36,12 @@ impl SessionKey {
     }
 }
 
+impl Display for SessionKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.to_bytes()))
+    }
+}
+
 pub(crate) type Handshake = Session! {
     choose {
         0 => {
@@ -46,6 +57,14 @@ pub(crate) type Handshake = Session! {
     }
 };
 
+/// Start another session over a connection whose previous session finished.
+pub(crate) type Reuse = Session! {
+    // Send a freshly generated client session ID
+    send Uuid;
+    // Receive a freshly generated server session ID
+    recv Uuid;
+};
+
 pub(crate) mod server {
     use super::*;
 
@@ -71,6 +90,23 @@ pub(crate) mod server {
             }
         })?
     }
+
+    #[Transmitter(Tx for Uuid)]
+    #[Receiver(Rx for Uuid)]
+    pub(crate) async fn reuse<Tx, Rx, E>(
+        chan: Chan<<Reuse as Session>::Dual, Tx, Rx>,
+    ) -> Result<SessionKey, E>
+    where
+        E: From<Tx::Error> + From<Rx::Error>,
+    {
+        let (client_key, chan) = chan.recv().await?;
+        let server_key = Uuid::new_v4();
+        chan.send(server_key).await?.close();
+        Ok(SessionKey {
+            client_key,
+            server_key,
+        })
+    }
 }
 
 pub(super) mod client {
@@ -104,4 +140,19 @@ pub(super) mod client {
         chan.choose::<1>().await?.send(key).await?.close();
         Ok(())
     }
+
+    #[Transmitter(Tx for Uuid)]
+    #[Receiver(Rx for Uuid)]
+    pub(crate) async fn reuse<Tx, Rx, E>(chan: Chan<Reuse, Tx, Rx>) -> Result<SessionKey, E>
+    where
+        E: From<Tx::Error> + From<Rx::Error>,
+    {
+        let client_key = Uuid::new_v4();
+        let (server_key, chan) = chan.send(client_key).await?.recv().await?;
+        chan.close();
+        Ok(SessionKey {
+            client_key,
+            server_key,
+        })
+    }
 }
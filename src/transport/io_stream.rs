@@ -2,11 +2,21 @@ use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
-use tokio_rustls::server::TlsStream;
+use tokio_rustls::{client, server};
 
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+use super::client_auth;
+
+/// A TCP stream which may or may not be wrapped in TLS, from either the client or server side, or
+/// a Unix socket stream, which is never wrapped in TLS.
 pub enum IoStream {
     Tcp(TcpStream),
-    Tls(Box<TlsStream<TcpStream>>),
+    Tls(Box<server::TlsStream<TcpStream>>),
+    ClientTls(Box<client::TlsStream<TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
 }
 
 impl AsyncRead for IoStream {
@@ -18,6 +28,12 @@ impl AsyncRead for IoStream {
         match self.get_mut() {
             IoStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
             IoStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            // The client only learns that the server refused its certificate when reading
+            IoStream::ClientTls(stream) => Pin::new(stream)
+                .poll_read(cx, buf)
+                .map_err(client_auth::explain_client_error),
+            #[cfg(unix)]
+            IoStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
         }
     }
 }
@@ -31,6 +47,9 @@ impl AsyncWrite for IoStream {
         match self.get_mut() {
             IoStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
             IoStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            IoStream::ClientTls(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            IoStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
         }
     }
 
@@ -38,6 +57,9 @@ impl AsyncWrite for IoStream {
         match self.get_mut() {
             IoStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
             IoStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            IoStream::ClientTls(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            IoStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
         }
     }
 
@@ -45,6 +67,9 @@ impl AsyncWrite for IoStream {
         match self.get_mut() {
             IoStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
             IoStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            IoStream::ClientTls(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            IoStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
         }
     }
 }
@@ -55,8 +80,21 @@ impl From<TcpStream> for IoStream {
     }
 }
 
-impl From<TlsStream<TcpStream>> for IoStream {
-    fn from(stream: TlsStream<TcpStream>) -> Self {
+impl From<server::TlsStream<TcpStream>> for IoStream {
+    fn from(stream: server::TlsStream<TcpStream>) -> Self {
         IoStream::Tls(Box::new(stream))
     }
 }
+
+impl From<client::TlsStream<TcpStream>> for IoStream {
+    fn from(stream: client::TlsStream<TcpStream>) -> Self {
+        IoStream::ClientTls(Box::new(stream))
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixStream> for IoStream {
+    fn from(stream: UnixStream) -> Self {
+        IoStream::Unix(stream)
+    }
+}
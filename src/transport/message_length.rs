@@ -0,0 +1,304 @@
+//! Telling when a session failed because a message was larger than the maximum message length.
+//!
+//! The length-delimited framing on each end refuses any message longer than that end's
+//! `max_message_length`, but only reports a generic I/O error from deep in the codec; the functions
+//! here pick out those errors so that each end can say which limit was hit, and in which direction.
+//!
+//! A message too large to receive can't be answered with an abort: the failed receive consumes the
+//! channel, so the receiving end can only close the connection.
+
+use {
+    dialectic_reconnect::retry::RetryError,
+    dialectic_tokio_serde::{RecvError, SendError},
+    std::{
+        fmt::{self, Display},
+        io,
+    },
+    thiserror::Error,
+    tokio_util::codec::LengthDelimitedCodecError,
+};
+
+use super::{channel::TransportError, client};
+
+/// Whether a message was being sent or received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sending,
+    Receiving,
+}
+
+impl Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Direction::Sending => write!(f, "Tried to send"),
+            Direction::Receiving => write!(f, "Received"),
+        }
+    }
+}
+
+/// A message which was larger than the maximum message length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error(
+    "{direction} a message larger than the maximum message length (`max_message_length`) of \
+    {max_length} bytes"
+)]
+pub struct MessageTooLarge {
+    pub direction: Direction,
+    pub max_length: usize,
+}
+
+impl MessageTooLarge {
+    /// Determine if an error in the underlying transport was a message being larger than
+    /// `max_length`.
+    pub fn from_transport_error(error: &TransportError, max_length: usize) -> Option<Self> {
+        let (direction, error) = match error {
+            dialectic_tokio_serde::Error::Send(SendError::Encode(error)) => {
+                (Direction::Sending, error)
+            }
+            dialectic_tokio_serde::Error::Recv(RecvError::Decode(error)) => {
+                (Direction::Receiving, error)
+            }
+            _ => return None,
+        };
+        frame_too_big(error).then(|| Self {
+            direction,
+            max_length,
+        })
+    }
+
+    /// Determine if an I/O error from the codec was a message being larger than `max_length`.
+    ///
+    /// The codec refuses to send a message with [`io::ErrorKind::InvalidInput`], and to receive
+    /// one with [`io::ErrorKind::InvalidData`].
+    pub fn from_io_error(error: &io::Error, max_length: usize) -> Option<Self> {
+        let direction = match error.kind() {
+            io::ErrorKind::InvalidInput => Direction::Sending,
+            io::ErrorKind::InvalidData => Direction::Receiving,
+            _ => return None,
+        };
+        frame_too_big(error).then(|| Self {
+            direction,
+            max_length,
+        })
+    }
+
+    /// Find a message being larger than `max_length` anywhere in the chain of causes of an error.
+    pub fn find(error: &anyhow::Error, max_length: usize) -> Option<Self> {
+        error.chain().find_map(|cause| {
+            if let Some(too_large) = cause.downcast_ref::<Self>() {
+                Some(*too_large)
+            } else if let Some(RetryError::OriginalError(error)) =
+                cause.downcast_ref::<client::Error>()
+            {
+                Self::from_transport_error(error, max_length)
+            } else if let Some(error) = cause.downcast_ref::<TransportError>() {
+                Self::from_transport_error(error, max_length)
+            } else if let Some(error) = cause.downcast_ref::<io::Error>() {
+                Self::from_io_error(error, max_length)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Replace an error from a session which failed because a message was larger than `max_length`
+/// with one which says so and names the limit, leaving any other error as it is.
+pub fn explain(error: anyhow::Error, max_length: usize) -> anyhow::Error {
+    match MessageTooLarge::find(&error, max_length) {
+        // Already explained
+        Some(_) if error.downcast_ref::<MessageTooLarge>().is_some() => error,
+        Some(too_large) => error.context(too_large),
+        None => error,
+    }
+}
+
+/// Whether an I/O error from the codec is its refusal of a frame larger than its maximum length.
+fn frame_too_big(error: &io::Error) -> bool {
+    error
+        .get_ref()
+        .map_or(false, |error| error.is::<LengthDelimitedCodecError>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        abort, offer_abort,
+        protocol::{
+            pay::{self, PaymentId},
+            AbortReason, Party, Pay,
+        },
+        transport::{
+            client::{self, Backoff, Client},
+            server::{self, Server},
+        },
+    };
+    use anyhow::Context;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use webpki::DNSNameRef;
+    use zkabacus_crypto::PaymentAmount;
+
+    /// The longest message either end accepts in these tests.
+    const MAX_LENGTH: usize = 256;
+
+    /// Receive a payment request as the merchant does, then reject it, having received it whole.
+    async fn receive_payment(chan: server::Chan<Pay>) -> Result<(), anyhow::Error> {
+        let (_amount, chan) = chan
+            .recv()
+            .await
+            .context("Failed to receive payment amount")?;
+        let (_note, chan): (String, _) = chan
+            .recv()
+            .await
+            .context("Failed to receive payment note")?;
+        let (_payment_id, chan) = chan.recv().await.context("Failed to receive payment ID")?;
+        abort!(in chan return pay::Error::Rejected("received".into()))
+    }
+
+    /// Serve [`Pay`] over plain TCP, refusing messages longer than `max_length`, for as long as
+    /// the test runs. Every session's error is forwarded to the returned receiver.
+    async fn serve_payments(
+        max_length: usize,
+    ) -> (std::net::SocketAddr, mpsc::UnboundedReceiver<anyhow::Error>) {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (errors_tx, errors) = mpsc::unbounded_channel();
+
+        let interact = move |_session_key, (), chan: server::Chan<Pay>| {
+            let errors_tx = errors_tx.clone();
+            async move {
+                if let Err(e) = receive_payment(chan).await {
+                    errors_tx.send(explain(e, max_length)).unwrap_or(());
+                }
+                Ok::<_, String>(())
+            }
+        };
+
+        tokio::spawn(async move {
+            let mut server: Server<Pay> = Server::new();
+            // Don't wait long for a client whose connection broke to come back
+            server
+                .max_length(max_length)
+                .timeout(Some(Duration::from_millis(100)))
+                .serve_while(
+                    address,
+                    None,
+                    || async { Some(()) },
+                    interact,
+                    futures::future::pending(),
+                )
+                .await
+        });
+
+        (address, errors)
+    }
+
+    /// Request a payment with the given note as the customer does.
+    async fn request_payment(chan: client::Chan<Pay>, note: String) -> Result<(), anyhow::Error> {
+        let chan = chan
+            .send(PaymentAmount::pay_merchant(1).unwrap())
+            .await
+            .context("Failed to send payment amount")?
+            .send(note)
+            .await
+            .context("Failed to send payment note")?
+            .send(PaymentId::generate())
+            .await
+            .context("Failed to send payment ID")?;
+        offer_abort!(in chan as Party::Customer);
+        chan.close();
+        Ok(())
+    }
+
+    /// Request a payment with the given note from the server at `address`, from a client which
+    /// refuses messages longer than `max_length`, explaining any error.
+    async fn send_payment_note(
+        address: std::net::SocketAddr,
+        max_length: usize,
+        note: String,
+    ) -> Result<(), anyhow::Error> {
+        let mut backoff = Backoff::with_delay(Duration::ZERO);
+        backoff.max_retries(0);
+        let mut client: Client<Pay> = Client::new(backoff);
+        client.disable_tls().max_length(max_length);
+
+        let host = DNSNameRef::try_from_ascii_str("localhost").unwrap().into();
+        let mut connected = client.connect(&host, address.port()).await;
+        for _ in 0..100 {
+            if connected.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            connected = client.connect(&host, address.port()).await;
+        }
+        let (_, chan) = connected?;
+
+        request_payment(chan, note)
+            .await
+            .map_err(|e| explain(e, max_length))
+    }
+
+    #[tokio::test]
+    async fn oversized_payment_note_is_refused_by_sender() {
+        let (address, mut errors) = serve_payments(usize::MAX).await;
+
+        let error = send_payment_note(address, MAX_LENGTH, "x".repeat(MAX_LENGTH * 2))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<MessageTooLarge>(),
+            Some(&MessageTooLarge {
+                direction: Direction::Sending,
+                max_length: MAX_LENGTH,
+            })
+        );
+        assert!(error.to_string().contains(&MAX_LENGTH.to_string()));
+        assert!(format!("{:#}", error).contains("Failed to send payment note"));
+
+        // The merchant never receives the note, so can't mistake it for one too large to receive
+        let error = errors.recv().await.unwrap();
+        assert_eq!(MessageTooLarge::find(&error, usize::MAX), None);
+        assert!(format!("{:#}", error).contains("Failed to receive payment note"));
+    }
+
+    #[tokio::test]
+    async fn oversized_payment_note_is_refused_by_receiver() {
+        let (address, mut errors) = serve_payments(MAX_LENGTH).await;
+
+        // A note within the limit is received, and the payment rejected as the merchant chose
+        let error = send_payment_note(address, usize::MAX, "x".repeat(MAX_LENGTH / 2))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error
+                .downcast_ref::<AbortReason>()
+                .map(|reason| &*reason.code),
+            Some("pay.rejected")
+        );
+        errors.recv().await.unwrap();
+
+        // The sender sends the whole note, and can't tell why the merchant then hung up, so
+        // doesn't blame a limit of its own...
+        let error = send_payment_note(address, usize::MAX, "x".repeat(MAX_LENGTH * 2))
+            .await
+            .unwrap_err();
+        assert_eq!(MessageTooLarge::find(&error, usize::MAX), None);
+        assert!(error.downcast_ref::<AbortReason>().is_none());
+        assert!(!format!("{:#}", error).contains("Failed to send payment note"));
+
+        // ...but the receiver can
+        let error = errors.recv().await.unwrap();
+        assert_eq!(
+            error.downcast_ref::<MessageTooLarge>(),
+            Some(&MessageTooLarge {
+                direction: Direction::Receiving,
+                max_length: MAX_LENGTH,
+            })
+        );
+        assert!(format!("{:#}", error).contains("Failed to receive payment note"));
+    }
+}
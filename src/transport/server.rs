@@ -7,14 +7,42 @@ use {
     dialectic_tokio_serde_bincode::{length_delimited, Bincode},
     futures::{stream::FuturesUnordered, Future, StreamExt},
     std::{
-        fmt::Debug, io, marker::PhantomData, net::SocketAddr, path::Path, sync::Arc, time::Duration,
+        collections::HashMap,
+        fmt::{self, Debug, Display},
+        io,
+        marker::PhantomData,
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
     },
     thiserror::Error,
-    tokio::{net::TcpListener, select, sync::mpsc},
+    tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+        select,
+        sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore},
+    },
     tokio_rustls::{rustls, TlsAcceptor},
+    tracing::Instrument,
+};
+
+#[cfg(unix)]
+use {
+    std::os::unix::fs::PermissionsExt,
+    tokio::{
+        net::UnixListener,
+        signal::unix::{signal, Signal, SignalKind},
+    },
 };
 
-use super::{channel::TransportError, handshake, io_stream::IoStream, pem};
+use super::{
+    channel::{self, TransportError},
+    client_auth::{ClientAuth, ClientCertificateError},
+    handshake::{self, Reuse},
+    io_stream::IoStream,
+    pem,
+};
 
 pub use super::channel::ServerChan as Chan;
 pub use handshake::SessionKey;
@@ -33,6 +61,16 @@ pub struct Server<Protocol: Session> {
     max_pending_retries: Option<usize>,
     /// The timeout after which broken connections will be garbage-collected.
     timeout: Option<Duration>,
+    /// How long to let sessions in progress finish after termination, before aborting them.
+    shutdown_grace_period: Option<Duration>,
+    /// The maximum number of sessions to serve at once.
+    max_concurrent_sessions: Option<usize>,
+    /// The maximum number of sessions to serve at once for any one client IP address.
+    max_sessions_per_ip: Option<usize>,
+    /// How to authenticate clients by their TLS certificates, if at all.
+    client_auth: Option<ClientAuth>,
+    /// How long to keep a connection open after its session finishes, for another session.
+    idle_timeout: Option<Duration>,
     /// The session, from the *client's* perspective.
     client_session: PhantomData<fn() -> Protocol>,
 }
@@ -54,6 +92,37 @@ pub enum ServerError<TaskError: 'static + Debug> {
     Accept(AcceptError),
 }
 
+/// Where a [`Server`] listens for connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddress {
+    /// A TCP address, on which connections may be wrapped in TLS.
+    Tcp(SocketAddr),
+    /// The path of a Unix socket, which only the user running the server can connect to. This is
+    /// only supported on Unix.
+    Unix(PathBuf),
+}
+
+impl From<SocketAddr> for ListenAddress {
+    fn from(address: SocketAddr) -> Self {
+        ListenAddress::Tcp(address)
+    }
+}
+
+impl From<(IpAddr, u16)> for ListenAddress {
+    fn from(address: (IpAddr, u16)) -> Self {
+        ListenAddress::Tcp(address.into())
+    }
+}
+
+impl Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(address) => write!(f, "{}", address),
+            ListenAddress::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
 impl<Protocol> Default for Server<Protocol>
 where
     Protocol: Session,
@@ -65,6 +134,11 @@ where
             length_field_bytes: 4,
             max_pending_retries: None,
             timeout: None,
+            shutdown_grace_period: None,
+            max_concurrent_sessions: None,
+            max_sessions_per_ip: None,
+            client_auth: None,
+            idle_timeout: None,
             client_session: PhantomData,
         }
     }
@@ -113,12 +187,69 @@ where
         self
     }
 
+    /// Set how long to let sessions in progress finish once the server is told to terminate.
+    ///
+    /// Once this has elapsed, any sessions still running are aborted, closing their connections so
+    /// that clients see an error rather than waiting indefinitely. If this is `None` (the default),
+    /// the server waits for every session to finish, however long that takes.
+    pub fn shutdown_grace_period(&mut self, shutdown_grace_period: Option<Duration>) -> &mut Self {
+        self.shutdown_grace_period = shutdown_grace_period;
+        self
+    }
+
+    /// Set the maximum number of sessions to serve at once (the default is `None`, for no limit).
+    ///
+    /// Once this many sessions are running, no more connections are accepted until one finishes,
+    /// so further clients wait to connect.
+    pub fn max_concurrent_sessions(&mut self, max_concurrent_sessions: Option<usize>) -> &mut Self {
+        self.max_concurrent_sessions = max_concurrent_sessions;
+        self
+    }
+
+    /// Set the maximum number of sessions to serve at once for any one client IP address (the
+    /// default is `None`, for no limit).
+    ///
+    /// Connections from an address which already has this many sessions are closed as soon as
+    /// they are accepted.
+    pub fn max_sessions_per_ip(&mut self, max_sessions_per_ip: Option<usize>) -> &mut Self {
+        self.max_sessions_per_ip = max_sessions_per_ip;
+        self
+    }
+
+    /// Set how to authenticate clients by their TLS certificates (the default is `None`, for not
+    /// asking clients for certificates at all).
+    ///
+    /// This only applies when serving over TLS. The certificate authorities are reloaded along
+    /// with the server's certificate.
+    pub fn client_auth(&mut self, client_auth: Option<ClientAuth>) -> &mut Self {
+        self.client_auth = client_auth;
+        self
+    }
+
+    /// Set how long to keep a connection open after its session finishes, waiting for the client
+    /// to start another session over it (the default is `None`, for closing it straight away).
+    ///
+    /// Each further session over a connection is given its own [`SessionKey`], and its own
+    /// `Input` cloned from the one the connection was accepted with. A connection counts against
+    /// the session limits for as long as it is open.
+    pub fn idle_timeout(&mut self, idle_timeout: Option<Duration>) -> &mut Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
     /// Accept connections on `address` in a loop, running the `initialize` function when accepting.
     /// If `initialize` returns `None`, stop; otherwise, concurrently serve each connection with
     /// `interact`.
     ///
     /// Note that `initialize` runs sequentially: it can pause the server if desired by
     /// `.await`-ing.
+    ///
+    /// When `terminate` completes, the server stops accepting connections, then waits for the
+    /// sessions in progress to finish, up to the
+    /// [`shutdown_grace_period`](Server::shutdown_grace_period), before returning.
+    ///
+    /// Connections can only be wrapped in TLS over TCP. A Unix socket needs no TLS: only the user
+    /// running the server can connect to it.
     pub async fn serve_while<
         Input,
         Error,
@@ -129,14 +260,14 @@ where
         TerminateFut,
     >(
         &self,
-        address: impl Into<SocketAddr>,
+        address: impl Into<ListenAddress>,
         tls_config: Option<(&Path, &Path)>,
         mut initialize: Init,
         interact: Interaction,
         terminate: TerminateFut,
     ) -> Result<(), io::Error>
     where
-        Input: Send + 'static,
+        Input: Clone + Send + 'static,
         Error: Send + Debug + 'static,
         Init: FnMut() -> InitFut,
         InitFut: Future<Output = Option<Input>>,
@@ -145,26 +276,23 @@ where
         InteractionFut: Future<Output = Result<(), Error>> + Send + 'static,
         TerminateFut: Future<Output = ()> + Send + 'static,
     {
-        let mut server_config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
-
-        // Optionally configure server-side TLS
-        let tls_acceptor = match tls_config {
-            None => None,
-            Some((certificate_chain_path, private_key_path)) => {
-                let certificate_chain = pem::read_certificates(certificate_chain_path)?;
-                let private_key = pem::read_private_key(private_key_path)?;
-
-                server_config
-                    .set_single_cert(certificate_chain, private_key)
-                    .map_err(|_error| {
-                        io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "invalid server certificate chain or private key",
-                        )
-                    })?;
-                Some(TlsAcceptor::from(Arc::new(server_config)))
-            }
-        };
+        // Optionally configure server-side TLS, which connections over a Unix socket never need
+        let address = address.into();
+        if tls_config.is_some() && matches!(address, ListenAddress::Unix(_)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TLS is not supported over a Unix socket",
+            ));
+        }
+        let mut tls_acceptor = tls_config
+            .map(|(certificate_chain_path, private_key_path)| {
+                ReloadingTlsAcceptor::new(
+                    certificate_chain_path,
+                    private_key_path,
+                    self.client_auth.clone(),
+                )
+            })
+            .transpose()?;
 
         // Resume-handling acceptor to be shared between all connections
         let mut acceptor = resume::Acceptor::new(
@@ -179,7 +307,8 @@ where
         // Error handling task awaits the result of each spawned server task and logs any errors
         // that occur, as they occur
         let (result_tx, result_rx) = mpsc::unbounded_channel();
-        let error_join_handle = tokio::spawn(error_handler(result_rx));
+        let (abort_sessions, recv_abort_sessions) = oneshot::channel();
+        let mut error_join_handle = tokio::spawn(error_handler(result_rx, recv_abort_sessions));
 
         // Listen for the termination event and forward it to stop the server
         let (stop_server, mut recv_stop_server) = mpsc::channel(1);
@@ -191,33 +320,89 @@ where
         // Wrap the server function in an `Arc` to share it between threads
         let interact = Arc::new(interact);
 
+        // Count sessions against the limits, releasing them however each session ends
+        let limits = Arc::new(SessionLimits::new(
+            self.max_concurrent_sessions,
+            self.max_sessions_per_ip,
+        ));
+
         // Bind to the address and serve
-        let address = address.into();
-        println!("serving on: {:?}", address);
-        let listener = TcpListener::bind(address).await?;
+        tracing::info!(%address, "Serving");
+        let listener = Listener::bind(&address).await?;
 
-        // Loop over incoming TCP connections until `initialize` returns `None`
+        // Loop over incoming connections until `initialize` returns `None`
         while let Some(input) = initialize().await {
             // If the termination future returns before a new connection, stop
             let accept_result = tokio::select! {
-                result = listener.accept() => result,
+                result = async {
+                    // Leave connections waiting until a session is available, without holding
+                    // one while waiting for a connection, which an idle connection may need
+                    let accepted = listener.accept().await?;
+                    Ok::<_, io::Error>((accepted, limits.acquire().await))
+                } => result,
                 () = async { recv_stop_server.recv().await.unwrap_or(()) } => break,
+                () = ReloadingTlsAcceptor::reload_requested(&mut tls_acceptor) => {
+                    if let Some(ref mut tls_acceptor) = tls_acceptor {
+                        tls_acceptor.reload(true);
+                    }
+                    continue;
+                }
             };
 
             match accept_result {
                 Err(err) => result_tx.send(Err(err.into())).unwrap_or(()),
-                Ok((tcp_stream, addr)) => {
-                    tcp_stream.set_nodelay(true)?;
-
-                    let io_stream = match tls_acceptor {
-                        None => IoStream::from(tcp_stream),
-                        Some(ref acceptor) => match acceptor.accept(tcp_stream).await {
-                            Ok(tls_stream) => IoStream::from(tls_stream),
-                            Err(e) => {
-                                eprintln!("Server TLS initialization error [{}]: {}", addr, e);
-                                continue;
+                Ok((connection, permit)) => {
+                    let (io_stream, permit) = match connection {
+                        Connection::Local(io_stream) => {
+                            // Every connection over a Unix socket is from this machine
+                            match limits.admit(IpAddr::V4(Ipv4Addr::LOCALHOST), permit) {
+                                Some(permit) => (io_stream, permit),
+                                None => {
+                                    tracing::warn!(
+                                        "Refusing local connection: too many sessions from this \
+                                        machine"
+                                    );
+                                    continue;
+                                }
                             }
-                        },
+                        }
+                        Connection::Tcp(tcp_stream, addr) => {
+                            tcp_stream.set_nodelay(true)?;
+
+                            // Refuse clients which already have as many sessions as they are
+                            // allowed
+                            let permit = match limits.admit(addr.ip(), permit) {
+                                Some(permit) => permit,
+                                None => {
+                                    tracing::warn!(
+                                        client = %addr,
+                                        "Refusing connection: too many sessions from this address"
+                                    );
+                                    if let Some(ref mut acceptor) = tls_acceptor {
+                                        if let Ok(mut tls_stream) =
+                                            acceptor.current().accept(tcp_stream).await
+                                        {
+                                            tls_stream.shutdown().await.unwrap_or(());
+                                        }
+                                    }
+                                    continue;
+                                }
+                            };
+
+                            let io_stream = match tls_acceptor {
+                                None => IoStream::from(tcp_stream),
+                                Some(ref mut acceptor) => {
+                                    match acceptor.current().accept(tcp_stream).await {
+                                        Ok(tls_stream) => IoStream::from(tls_stream),
+                                        Err(e) => {
+                                            log_tls_accept_error(addr, &e);
+                                            continue;
+                                        }
+                                    }
+                                }
+                            };
+                            (io_stream, permit)
+                        }
                     };
 
                     // Layer a length-delimmited bincode `Chan` over the TLS stream
@@ -227,12 +412,20 @@ where
 
                     let acceptor = acceptor.clone();
                     let interact = interact.clone();
+                    let idle_timeout = self.idle_timeout;
 
                     // Run the interaction concurrently, or resume it if it's resuming an
                     // existing one
                     let join_handle = tokio::spawn(async move {
                         let result = acceptor.accept(tx, rx).await;
-                        run_interaction::<Protocol, _, _, _, _>(result, input, interact).await
+                        run_interaction::<Protocol, _, _, _, _>(
+                            result,
+                            input,
+                            interact,
+                            idle_timeout,
+                            permit,
+                        )
+                        .await
                     });
 
                     // Keep track of pending server task
@@ -241,18 +434,374 @@ where
             }
         }
 
-        error_join_handle.await?;
+        // Stop accepting connections, and let the error handler finish once every session has
+        drop(listener);
+        drop(result_tx);
+
+        // Wait for the sessions in progress, aborting them if they outlast the grace period
+        match self.shutdown_grace_period {
+            None => error_join_handle.await?,
+            Some(grace_period) => {
+                if tokio::time::timeout(grace_period, &mut error_join_handle)
+                    .await
+                    .is_err()
+                {
+                    abort_sessions.send(()).unwrap_or(());
+                    error_join_handle.await?;
+                }
+            }
+        }
         Ok(())
     }
 }
 
+/// A TLS acceptor for a certificate chain and private key on disk, which is rebuilt when either
+/// file changes, or on SIGHUP, so that certificates can be rotated without restarting the server.
+///
+/// Connections already made keep the certificate they were made with. If the replacement files
+/// are invalid, the previous certificate stays in use.
+struct ReloadingTlsAcceptor {
+    certificate_chain_path: PathBuf,
+    private_key_path: PathBuf,
+    client_auth: Option<ClientAuth>,
+    /// When each of the files was last modified, as of when they were last loaded.
+    modified: [Option<SystemTime>; 2],
+    acceptor: TlsAcceptor,
+    #[cfg(unix)]
+    hangups: Signal,
+}
+
+impl ReloadingTlsAcceptor {
+    fn new(
+        certificate_chain_path: &Path,
+        private_key_path: &Path,
+        client_auth: Option<ClientAuth>,
+    ) -> Result<Self, io::Error> {
+        let modified = modified_times(certificate_chain_path, private_key_path);
+        Ok(Self {
+            acceptor: load_tls_acceptor(
+                certificate_chain_path,
+                private_key_path,
+                client_auth.as_ref(),
+            )?,
+            certificate_chain_path: certificate_chain_path.to_owned(),
+            private_key_path: private_key_path.to_owned(),
+            client_auth,
+            modified,
+            #[cfg(unix)]
+            hangups: signal(SignalKind::hangup())?,
+        })
+    }
+
+    /// Get the acceptor for the current certificate, reloading it first if it changed on disk.
+    fn current(&mut self) -> TlsAcceptor {
+        self.reload(false);
+        self.acceptor.clone()
+    }
+
+    /// Reload the certificate chain and private key if either changed since they were last
+    /// loaded, or regardless if `force` is set.
+    fn reload(&mut self, force: bool) {
+        let modified = modified_times(&self.certificate_chain_path, &self.private_key_path);
+        if !force && modified == self.modified {
+            return;
+        }
+
+        // Whether or not the files are valid, don't try them again until they change
+        self.modified = modified;
+        match load_tls_acceptor(
+            &self.certificate_chain_path,
+            &self.private_key_path,
+            self.client_auth.as_ref(),
+        ) {
+            Ok(acceptor) => {
+                self.acceptor = acceptor;
+                tracing::info!(
+                    path = %self.certificate_chain_path.display(),
+                    "Reloaded TLS certificate"
+                );
+            }
+            Err(e) => tracing::warn!(
+                path = %self.certificate_chain_path.display(),
+                error = %e,
+                "Error reloading TLS certificate; keeping the previous one"
+            ),
+        }
+    }
+
+    /// Wait until a reload is requested by SIGHUP, which never happens if there is no acceptor, or
+    /// off Unix.
+    async fn reload_requested(acceptor: &mut Option<Self>) {
+        #[cfg(unix)]
+        if let Some(acceptor) = acceptor {
+            if acceptor.hangups.recv().await.is_some() {
+                return;
+            }
+        }
+        futures::future::pending().await
+    }
+}
+
+/// Get the last modification times of the certificate chain and private key files, if available.
+fn modified_times(
+    certificate_chain_path: &Path,
+    private_key_path: &Path,
+) -> [Option<SystemTime>; 2] {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    [modified(certificate_chain_path), modified(private_key_path)]
+}
+
+/// Build a TLS acceptor from the certificate chain and private key at the given paths, which
+/// verifies client certificates against the given certificate authorities, if any.
+fn load_tls_acceptor(
+    certificate_chain_path: &Path,
+    private_key_path: &Path,
+    client_auth: Option<&ClientAuth>,
+) -> Result<TlsAcceptor, io::Error> {
+    let certificate_chain = pem::read_certificates(certificate_chain_path)?;
+    let private_key = pem::read_private_key(private_key_path)?;
+
+    let client_cert_verifier = match client_auth {
+        None => rustls::NoClientAuth::new(),
+        Some(ClientAuth { ca_path, required }) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for certificate in pem::read_certificates(ca_path)? {
+                roots.add(&certificate).map_err(|_error| {
+                    io::Error::new(io::ErrorKind::InvalidData, "invalid client CA certificate")
+                })?;
+            }
+            if roots.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "no client CA certificates found",
+                ));
+            }
+            if *required {
+                rustls::AllowAnyAuthenticatedClient::new(roots)
+            } else {
+                rustls::AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+            }
+        }
+    };
+
+    let mut server_config = rustls::ServerConfig::new(client_cert_verifier);
+    server_config
+        .set_single_cert(certificate_chain, private_key)
+        .map_err(|_error| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid server certificate chain or private key",
+            )
+        })?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Log why a TCP connection couldn't be wrapped in TLS, saying so if it was because of the
+/// client's certificate.
+fn log_tls_accept_error(client: SocketAddr, error: &io::Error) {
+    match ClientCertificateError::from_accept_error(error) {
+        Some(ClientCertificateError::Missing) => tracing::warn!(
+            client = %client,
+            "Refusing connection: no client certificate presented"
+        ),
+        Some(ClientCertificateError::Rejected) => tracing::warn!(
+            client = %client,
+            error = %error,
+            "Refusing connection: client certificate rejected"
+        ),
+        None => tracing::warn!(
+            client = %client,
+            error = %error,
+            "Server TLS initialization error"
+        ),
+    }
+}
+
+/// A listener bound to a [`ListenAddress`].
+enum Listener {
+    Tcp(TcpListener),
+    /// A listener on a Unix socket, whose path is removed when the listener is dropped.
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+/// A connection accepted by a [`Listener`].
+enum Connection {
+    /// A TCP connection from the given address, which may be yet to be wrapped in TLS.
+    Tcp(TcpStream, SocketAddr),
+    /// A connection over a Unix socket, which is from this machine.
+    Local(IoStream),
+}
+
+impl Listener {
+    async fn bind(address: &ListenAddress) -> Result<Self, io::Error> {
+        match address {
+            ListenAddress::Tcp(address) => Ok(Listener::Tcp(TcpListener::bind(address).await?)),
+            #[cfg(unix)]
+            ListenAddress::Unix(path) => Ok(Listener::Unix(bind_unix(path)?, path.clone())),
+            #[cfg(not(unix))]
+            ListenAddress::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    async fn accept(&self) -> Result<Connection, io::Error> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, address) = listener.accept().await?;
+                Ok(Connection::Tcp(stream, address))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok(Connection::Local(IoStream::from(stream)))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Bind a Unix socket at `path` which only the current user can connect to, replacing any socket
+/// left there by a server which didn't shut down cleanly.
+///
+/// The socket is bound under a temporary name, and only moved to `path` once its permissions are
+/// restricted, so that no other user can connect to it in between.
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> Result<UnixListener, io::Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let file_name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid Unix socket path: {:?}", path),
+        )
+    })?;
+    let mut staging_name = std::ffi::OsString::from(".");
+    staging_name.push(file_name);
+    staging_name.push(format!(".{}", std::process::id()));
+    let staging_path = path.with_file_name(staging_name);
+
+    let _ = std::fs::remove_file(&staging_path);
+    let listener = UnixListener::bind(&staging_path)?;
+    let restricted =
+        std::fs::set_permissions(&staging_path, std::fs::Permissions::from_mode(0o600))
+            .and_then(|()| std::fs::rename(&staging_path, path));
+    if let Err(e) = restricted {
+        let _ = std::fs::remove_file(&staging_path);
+        return Err(e);
+    }
+    Ok(listener)
+}
+
+/// Limits on how many sessions are served at once, overall and for each client IP address.
+#[derive(Debug)]
+struct SessionLimits {
+    /// Permits for sessions overall, if they are limited.
+    overall: Option<Arc<Semaphore>>,
+    /// The maximum number of sessions for each client IP address, if they are limited.
+    max_per_ip: Option<usize>,
+    /// The number of sessions running for each client IP address with any.
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// A session counted against the [`SessionLimits`], which is released when this is dropped.
+#[derive(Debug)]
+struct SessionPermit {
+    limits: Arc<SessionLimits>,
+    ip: IpAddr,
+    _overall: Option<OwnedSemaphorePermit>,
+}
+
+impl SessionLimits {
+    fn new(max_overall: Option<usize>, max_per_ip: Option<usize>) -> Self {
+        Self {
+            overall: max_overall.map(|max| Arc::new(Semaphore::new(max))),
+            max_per_ip,
+            per_ip: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Wait until a session is available under the overall limit.
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        match &self.overall {
+            None => None,
+            Some(overall) => Some(
+                overall
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("Session semaphore is never closed"),
+            ),
+        }
+    }
+
+    /// Count a session for the given client address, holding the overall permit acquired for it,
+    /// or return `None` if the address already has as many sessions as it is allowed.
+    fn admit(
+        self: &Arc<Self>,
+        ip: IpAddr,
+        overall: Option<OwnedSemaphorePermit>,
+    ) -> Option<SessionPermit> {
+        let mut per_ip = self.per_ip.lock().unwrap();
+        let sessions = per_ip.entry(ip).or_insert(0);
+        if self.max_per_ip.map_or(false, |max| *sessions >= max) {
+            if *sessions == 0 {
+                per_ip.remove(&ip);
+            }
+            return None;
+        }
+        *sessions += 1;
+
+        Some(SessionPermit {
+            limits: self.clone(),
+            ip,
+            _overall: overall,
+        })
+    }
+
+    /// The number of sessions running for the given client address.
+    #[cfg(test)]
+    fn sessions(&self, ip: IpAddr) -> usize {
+        self.per_ip.lock().unwrap().get(&ip).copied().unwrap_or(0)
+    }
+}
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        let mut per_ip = self.limits.per_ip.lock().unwrap();
+        if let Some(sessions) = per_ip.get_mut(&self.ip) {
+            *sessions -= 1;
+            if *sessions == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
 type JoinHandle<T> = tokio::task::JoinHandle<Result<(), ServerError<T>>>;
 
-/// Run the interaction on a single connection.
+/// Run the interaction on a single connection, and then on the same connection again for as long
+/// as the client starts another session within the `idle_timeout` after each one finishes.
+///
+/// The `permit` for the first session is given up while the connection is idle, and each session
+/// after it counts against the limits like one on a new connection.
 async fn run_interaction<Protocol, Interaction, InteractionFut, Error, Input>(
     result: Result<(SessionKey, Option<Chan<Protocol>>), AcceptError>,
     input: Input,
     interact: Arc<Interaction>,
+    idle_timeout: Option<Duration>,
+    mut permit: SessionPermit,
 ) -> Result<(), ServerError<Error>>
 where
     Protocol: Session,
@@ -260,11 +809,58 @@ where
     InteractionFut: Future<Output = Result<(), Error>> + Send + 'static,
     Interaction: Fn(SessionKey, Input, Chan<Protocol>) -> InteractionFut + Send + Sync + 'static,
     Error: Debug + 'static,
+    Input: Clone,
 {
     match result.map_err(ServerError::Accept)? {
-        (session_key, Some(chan)) => interact(session_key, input, chan)
-            .await
-            .map_err(ServerError::Task)?,
+        (mut session_key, Some(chan)) => {
+            let (mut tx, mut rx) = channel::into_ends(chan);
+            loop {
+                // Everything logged during the session can be found by its key
+                let span = tracing::info_span!("session", session_key = %session_key);
+                let ((), ends) = <<Protocol as Session>::Dual as Session>::over(tx, rx, |chan| {
+                    interact(session_key, input.clone(), chan)
+                })
+                .instrument(span)
+                .await
+                .map_err(ServerError::Task)?;
+
+                // Only a connection whose session finished can carry another one
+                let (idle_timeout, (idle_tx, idle_rx)) = match (idle_timeout, ends) {
+                    (Some(idle_timeout), Ok(ends)) => (idle_timeout, ends),
+                    _ => break,
+                };
+
+                // An idle connection isn't a session, so it mustn't keep other clients waiting
+                let (limits, ip) = (permit.limits.clone(), permit.ip);
+                drop(permit);
+
+                let reused = <<Reuse as Session>::Dual as Session>::over(
+                    idle_tx,
+                    idle_rx,
+                    handshake::server::reuse::<_, _, anyhow::Error>,
+                );
+                match tokio::time::timeout(idle_timeout, reused).await {
+                    Ok(Ok((next_session_key, Ok((next_tx, next_rx))))) => {
+                        permit = match limits.admit(ip, limits.acquire().await) {
+                            Some(permit) => permit,
+                            None => {
+                                tracing::warn!(
+                                    client = %ip,
+                                    "Closing reused connection: too many sessions from this \
+                                    address"
+                                );
+                                break;
+                            }
+                        };
+                        session_key = next_session_key;
+                        tx = next_tx;
+                        rx = next_rx;
+                    }
+                    // The client hung up, or left the connection idle for too long
+                    _ => break,
+                }
+            }
+        }
         (_session_key, None) => {
             // reconnected existing channel, nothing more to do
         }
@@ -272,29 +868,420 @@ where
     Ok::<_, ServerError<Error>>(())
 }
 
-/// Handle errors on the provided `Receiver`.
+/// Handle errors on the provided `Receiver`, finishing once it is closed and every session it
+/// delivered has finished.
+///
+/// If `abort` fires, every session still running is aborted.
 async fn error_handler<Error: Debug>(
     mut result_rx: mpsc::UnboundedReceiver<Result<JoinHandle<Error>, ServerError<Error>>>,
+    mut abort: oneshot::Receiver<()>,
 ) {
-    let mut results = FuturesUnordered::new();
+    let mut results: FuturesUnordered<JoinHandle<Error>> = FuturesUnordered::new();
+    let mut receiving = true;
+    let mut aborted = false;
     loop {
         select! {
-            Some(incoming) = result_rx.recv() => {
+            incoming = result_rx.recv(), if receiving => {
                 match incoming {
-                    Ok(join_handle) => results.push(async move {
-                        let join_handle: JoinHandle<Error> = join_handle;
-                        join_handle.await.map_err(ServerError::Join).and_then(|r| r)
-                    }),
-                    Err(err) => eprintln!("{}", err),
+                    Some(Ok(join_handle)) => results.push(join_handle),
+                    Some(Err(err)) => tracing::error!(error = %err, "Session failed"),
+                    None => receiving = false,
                 }
             },
             Some(result) = results.next() => {
-                match result {
+                match result.map_err(ServerError::Join).and_then(|r| r) {
                     Ok(()) => {},
-                    Err(err) => eprintln!("{}", err),
+                    // Sessions are only cancelled when they are aborted below, which is reported
+                    Err(ServerError::Join(err)) if err.is_cancelled() => {},
+                    Err(err) => tracing::error!(error = %err, "Session failed"),
+                }
+            },
+            result = &mut abort, if !aborted => {
+                aborted = true;
+                if result.is_ok() {
+                    tracing::warn!(
+                        sessions = results.len(),
+                        "Aborting sessions still in progress"
+                    );
+                    for join_handle in results.iter() {
+                        join_handle.abort();
+                    }
                 }
             },
             else => break,
         }
+
+        if !receiving && results.is_empty() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{
+        client::{self, Backoff, Client},
+        client_auth,
+    };
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::{io::AsyncReadExt, net::TcpStream};
+    use tokio_rustls::{rustls::Session, TlsConnector};
+    use webpki::DNSNameRef;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/transport/testdata")
+            .join(name)
+    }
+
+    fn certificate(name: &str) -> rustls::Certificate {
+        pem::read_certificates(testdata(&format!("{}.crt", name))).unwrap()[0].clone()
+    }
+
+    /// Make a TLS connection through the given acceptor, and get the certificate the client saw.
+    async fn presented_certificate(acceptor: TlsAcceptor) -> rustls::Certificate {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let mut client_config = rustls::ClientConfig::new();
+        for name in &["first", "second"] {
+            client_config.root_store.add(&certificate(name)).unwrap();
+        }
+        let connector = TlsConnector::from(Arc::new(client_config));
+
+        let accept = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            acceptor.accept(stream).await.unwrap()
+        };
+        let connect = async {
+            let stream = TcpStream::connect(address).await.unwrap();
+            let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+            connector.connect(domain, stream).await.unwrap()
+        };
+        let (_, client) = tokio::join!(accept, connect);
+
+        client.get_ref().1.get_peer_certificates().unwrap()[0].clone()
+    }
+
+    /// Make a TLS connection through an acceptor which requires client certificates issued by
+    /// `client-ca`, presenting the named client certificate if any, and get the result on each end:
+    /// first the server's, then the client's.
+    async fn connect_with_client_certificate(
+        name: Option<&str>,
+    ) -> (Result<(), io::Error>, Result<(), io::Error>) {
+        let client_auth = ClientAuth {
+            ca_path: testdata("client-ca.crt"),
+            required: true,
+        };
+        let acceptor = load_tls_acceptor(
+            &testdata("first.crt"),
+            &testdata("first.key"),
+            Some(&client_auth),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let mut client_config = rustls::ClientConfig::new();
+        client_config.root_store.add(&certificate("first")).unwrap();
+        if let Some(name) = name {
+            client_config
+                .set_single_client_cert(
+                    pem::read_certificates(testdata(&format!("{}.crt", name))).unwrap(),
+                    pem::read_private_key(testdata(&format!("{}.key", name))).unwrap(),
+                )
+                .unwrap();
+        }
+        let connector = TlsConnector::from(Arc::new(client_config));
+
+        // The server sends a byte once it accepts the client, which the client waits for, since
+        // the client may only learn it was refused once it reads
+        let accept = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await?;
+            stream.write_all(&[1]).await?;
+            stream.flush().await?;
+            Ok::<_, io::Error>(stream)
+        };
+        let connect = async {
+            let stream = TcpStream::connect(address).await.unwrap();
+            let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+            let stream = connector
+                .connect(domain, stream)
+                .await
+                .map_err(client_auth::explain_client_error)?;
+            IoStream::from(stream).read_exact(&mut [0]).await?;
+            Ok::<_, io::Error>(())
+        };
+        let (accepted, connected) = tokio::join!(accept, connect);
+        (accepted.map(drop), connected)
+    }
+
+    /// The reason the client was given for its certificate being refused, if any.
+    fn client_refusal(result: Result<(), io::Error>) -> Option<ClientCertificateError> {
+        result
+            .err()?
+            .get_ref()?
+            .downcast_ref::<ClientCertificateError>()
+            .copied()
+    }
+
+    #[tokio::test]
+    async fn client_certificates_are_verified() {
+        // A certificate issued by the client CA is accepted
+        let (accepted, connected) = connect_with_client_certificate(Some("client")).await;
+        accepted.unwrap();
+        connected.unwrap();
+
+        // No certificate at all is refused, and both ends say why
+        let (accepted, connected) = connect_with_client_certificate(None).await;
+        assert_eq!(
+            ClientCertificateError::from_accept_error(&accepted.unwrap_err()),
+            Some(ClientCertificateError::Missing)
+        );
+        assert_eq!(
+            client_refusal(connected),
+            Some(ClientCertificateError::Missing)
+        );
+
+        // A certificate issued by another CA is refused, and both ends say why
+        let (accepted, connected) = connect_with_client_certificate(Some("other-client")).await;
+        assert_eq!(
+            ClientCertificateError::from_accept_error(&accepted.unwrap_err()),
+            Some(ClientCertificateError::Rejected)
+        );
+        assert_eq!(
+            client_refusal(connected),
+            Some(ClientCertificateError::Rejected)
+        );
+    }
+
+    #[tokio::test]
+    async fn reload_changed_certificate() {
+        let dir = std::env::temp_dir().join(format!("zeekoe-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let certificate_path = dir.join("server.crt");
+        let private_key_path = dir.join("server.key");
+        let install = |name: &str| {
+            std::fs::copy(testdata(&format!("{}.crt", name)), &certificate_path).unwrap();
+            std::fs::copy(testdata(&format!("{}.key", name)), &private_key_path).unwrap();
+        };
+
+        install("first");
+        let mut acceptor =
+            ReloadingTlsAcceptor::new(&certificate_path, &private_key_path, None).unwrap();
+        assert_eq!(
+            presented_certificate(acceptor.current()).await,
+            certificate("first")
+        );
+
+        // Leave enough time for the modification times to differ on any file system
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        // Rotating the certificate on disk is picked up by the next connection
+        install("second");
+        assert_eq!(
+            presented_certificate(acceptor.current()).await,
+            certificate("second")
+        );
+
+        // An invalid replacement leaves the current certificate in place
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        std::fs::write(&private_key_path, "not a private key").unwrap();
+        assert_eq!(
+            presented_certificate(acceptor.current()).await,
+            certificate("second")
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A session in which the client sends a number, and the server sends it back.
+    type Echo = Session! {
+        send u32;
+        recv u32;
+    };
+
+    /// Serve [`Echo`] over plain TCP, taking `delay` to answer, until `stop` is sent. `completed`
+    /// is set whenever a session finishes.
+    fn serve_slow_echo(
+        delay: Duration,
+        grace_period: Duration,
+        completed: Arc<AtomicBool>,
+    ) -> (
+        SocketAddr,
+        oneshot::Sender<()>,
+        tokio::task::JoinHandle<Result<(), io::Error>>,
+    ) {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (stop, recv_stop) = oneshot::channel();
+
+        let interact = move |_session_key, (), chan: Chan<Echo>| {
+            let completed = completed.clone();
+            async move {
+                let (number, chan) = chan.recv().await.map_err(|e| format!("{:?}", e))?;
+                tokio::time::sleep(delay).await;
+                chan.send(number)
+                    .await
+                    .map_err(|e| format!("{:?}", e))?
+                    .close();
+                completed.store(true, Ordering::SeqCst);
+                Ok::<_, String>(())
+            }
+        };
+
+        let server = tokio::spawn(async move {
+            let mut server: Server<Echo> = Server::new();
+            server.shutdown_grace_period(Some(grace_period));
+            server
+                .serve_while(address, None, || async { Some(()) }, interact, async move {
+                    recv_stop.await.unwrap_or(())
+                })
+                .await
+        });
+
+        (address, stop, server)
+    }
+
+    /// Connect to the server at `address`, waiting for it to start listening.
+    async fn connect(address: SocketAddr) -> client::Chan<Echo> {
+        let mut backoff = Backoff::with_delay(Duration::ZERO);
+        backoff.max_retries(0);
+        let mut client: Client<Echo> = Client::new(backoff);
+        client.disable_tls();
+
+        let host = DNSNameRef::try_from_ascii_str("localhost").unwrap().into();
+        for _ in 0..100 {
+            if let Ok((_, chan)) = client.connect(&host, address.port()).await {
+                return chan;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("server never started listening on {}", address);
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_sessions() {
+        let completed = Arc::new(AtomicBool::new(false));
+        let (address, stop, server) = serve_slow_echo(
+            Duration::from_millis(500),
+            Duration::from_secs(30),
+            completed.clone(),
+        );
+
+        let chan = connect(address).await.send(7).await.unwrap();
+        stop.send(()).unwrap();
+
+        // No new connections are accepted while the session finishes...
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(TcpStream::connect(address).await.is_err());
+        assert!(!completed.load(Ordering::SeqCst));
+
+        // ...and the session in progress gets its answer before the server stops
+        let (number, chan) = chan.recv().await.unwrap();
+        chan.close();
+        assert_eq!(number, 7);
+
+        server.await.unwrap().unwrap();
+        assert!(completed.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn shutdown_aborts_sessions_after_grace_period() {
+        let completed = Arc::new(AtomicBool::new(false));
+        let (address, stop, server) = serve_slow_echo(
+            Duration::from_secs(60 * 60),
+            Duration::from_millis(100),
+            completed.clone(),
+        );
+
+        let chan = connect(address).await.send(7).await.unwrap();
+        stop.send(()).unwrap();
+
+        // The server gives up on the session rather than waiting for it...
+        tokio::time::timeout(Duration::from_secs(10), server)
+            .await
+            .expect("server did not stop after its grace period")
+            .unwrap()
+            .unwrap();
+        assert!(!completed.load(Ordering::SeqCst));
+
+        // ...and the client sees its connection fail, rather than waiting forever
+        assert!(tokio::time::timeout(Duration::from_secs(10), chan.recv())
+            .await
+            .expect("client was left waiting on an aborted session")
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn session_limits_are_enforced_and_released() {
+        let limits = Arc::new(SessionLimits::new(Some(3), Some(2)));
+        let first: IpAddr = "10.0.0.1".parse().unwrap();
+        let second: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let admit = |ip| {
+            let limits = limits.clone();
+            async move {
+                let permit = limits.acquire().await;
+                limits.admit(ip, permit)
+            }
+        };
+
+        // One address can't have more than its share of sessions...
+        let a = admit(first).await.unwrap();
+        let b = admit(first).await.unwrap();
+        assert!(admit(first).await.is_none());
+        assert_eq!(limits.sessions(first), 2);
+
+        // ...and a refused connection doesn't use up a session overall
+        let c = admit(second).await.unwrap();
+
+        // Once every session is taken, the next connection waits for one to finish
+        let mut waiting = tokio::spawn(admit(second));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), &mut waiting)
+                .await
+                .is_err()
+        );
+        drop(a);
+        let d = waiting.await.unwrap().unwrap();
+        assert_eq!(limits.sessions(first), 1);
+        assert_eq!(limits.sessions(second), 2);
+
+        drop((b, c, d));
+        assert_eq!(limits.sessions(first), 0);
+        assert_eq!(limits.sessions(second), 0);
+        assert_eq!(limits.overall.as_ref().unwrap().available_permits(), 3);
+    }
+
+    #[tokio::test]
+    async fn session_limits_released_when_sessions_fail() {
+        let limits = Arc::new(SessionLimits::new(Some(2), Some(2)));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        // A session that panics...
+        let permit = limits.admit(ip, limits.acquire().await).unwrap();
+        let panicked = tokio::spawn(async move {
+            let _permit = permit;
+            panic!("session failed");
+        });
+        assert!(panicked.await.unwrap_err().is_panic());
+
+        // ...and a session that is aborted
+        let permit = limits.admit(ip, limits.acquire().await).unwrap();
+        let aborted = tokio::spawn(async move {
+            let _permit = permit;
+            futures::future::pending::<()>().await;
+        });
+        aborted.abort();
+        assert!(aborted.await.unwrap_err().is_cancelled());
+
+        // Both give back their sessions
+        assert_eq!(limits.sessions(ip), 0);
+        assert_eq!(limits.overall.as_ref().unwrap().available_permits(), 2);
     }
 }